@@ -0,0 +1,179 @@
+// Copyright 2016 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Detection of what the current GL implementation supports.
+
+use gltrace::{self as gl, GlApi};
+
+/// The features of the current GL implementation that this crate cares about. This is detected
+/// once when the `RenderContext` is created.
+#[derive(Copy, Clone, Debug, RustcEncodable)]
+pub struct Capabilities {
+    /// Whether the context is OpenGL ES rather than desktop OpenGL.
+    pub is_gles: bool,
+
+    /// Whether `GL_TEXTURE_RECTANGLE_ARB` textures can be used.
+    pub texture_rectangle: bool,
+
+    /// Whether textures can be uploaded from BGRA data.
+    pub bgra_upload: bool,
+
+    /// Whether pixel buffer objects are available for asynchronous uploads.
+    pub pixel_buffer_objects: bool,
+
+    /// Whether timer queries are available.
+    pub timer_queries: bool,
+
+    /// Whether anisotropic texture filtering is available.
+    pub anisotropic_filtering: bool,
+
+    /// Whether sRGB framebuffers are available.
+    pub srgb_framebuffer: bool,
+
+    /// Whether fence sync objects are available.
+    pub fence_sync: bool,
+
+    /// The maximum width and height of a texture, in pixels.
+    pub max_texture_size: usize,
+}
+
+#[cfg(feature = "heapsize")]
+known_heap_size!(0, Capabilities);
+
+impl Capabilities {
+    /// Queries the GL context behind `gl` for its capabilities.
+    pub fn detect(gl: &GlApi) -> Capabilities {
+        let version = gl::get_string(gl, gl::VERSION);
+        let extensions = gl::get_string(gl, gl::EXTENSIONS);
+        let max_texture_size = gl::get_integer_v(gl, gl::MAX_TEXTURE_SIZE);
+        Capabilities::from_strings(&version, &extensions, max_texture_size.max(0) as usize)
+    }
+
+    /// Derives the capabilities from a `GL_VERSION` string, a space-separated `GL_EXTENSIONS`
+    /// string, and the value of `GL_MAX_TEXTURE_SIZE`.
+    pub fn from_strings(version: &str, extensions: &str, max_texture_size: usize)
+                        -> Capabilities {
+        let (is_gles, major, minor) = parse_version(version);
+        let at_least = |required_major: u32, required_minor: u32| {
+            (major, minor) >= (required_major, required_minor)
+        };
+        let has = |name: &str| extensions.split_whitespace().any(|extension| extension == name);
+
+        if is_gles {
+            Capabilities {
+                is_gles: true,
+                texture_rectangle: false,
+                bgra_upload: has("GL_EXT_texture_format_BGRA8888"),
+                pixel_buffer_objects: at_least(3, 0) || has("GL_NV_pixel_buffer_object"),
+                timer_queries: has("GL_EXT_disjoint_timer_query"),
+                anisotropic_filtering: has("GL_EXT_texture_filter_anisotropic"),
+                srgb_framebuffer: at_least(3, 0) || has("GL_EXT_sRGB"),
+                fence_sync: at_least(3, 0) || has("GL_APPLE_sync"),
+                max_texture_size: max_texture_size,
+            }
+        } else {
+            Capabilities {
+                is_gles: false,
+                texture_rectangle: has("GL_ARB_texture_rectangle") ||
+                                   has("GL_EXT_texture_rectangle") ||
+                                   has("GL_NV_texture_rectangle"),
+                bgra_upload: true,
+                pixel_buffer_objects: at_least(2, 1) || has("GL_ARB_pixel_buffer_object"),
+                timer_queries: at_least(3, 3) || has("GL_ARB_timer_query"),
+                anisotropic_filtering: has("GL_EXT_texture_filter_anisotropic") ||
+                                       has("GL_ARB_texture_filter_anisotropic"),
+                srgb_framebuffer: at_least(3, 0) || has("GL_ARB_framebuffer_sRGB") ||
+                                  has("GL_EXT_framebuffer_sRGB"),
+                fence_sync: at_least(3, 2) || has("GL_ARB_sync"),
+                max_texture_size: max_texture_size,
+            }
+        }
+    }
+}
+
+/// Parses a `GL_VERSION` string such as "3.3.0 NVIDIA 367.44" or "OpenGL ES 3.0 V@127.0".
+/// Returns whether this is OpenGL ES and the major and minor version. Unparseable versions are
+/// treated as 0.0.
+fn parse_version(version: &str) -> (bool, u32, u32) {
+    let es_prefix = "OpenGL ES";
+    let is_gles = version.starts_with(es_prefix);
+    let version = if is_gles {
+        version[es_prefix.len()..].trim_left_matches(char::is_whitespace)
+    } else {
+        version
+    };
+
+    // Skip any profile marker such as "-CM" on old GLES 1 implementations.
+    let version = version.trim_left_matches(|c: char| !c.is_digit(10));
+    let number = version.split(|c: char| c.is_whitespace()).next().unwrap_or("");
+    let mut parts = number.split('.');
+    let major = parts.next().and_then(|part| part.parse().ok()).unwrap_or(0);
+    let minor = parts.next().and_then(|part| part.parse().ok()).unwrap_or(0);
+    (is_gles, major, minor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Capabilities, parse_version};
+
+    #[test]
+    fn desktop_capabilities_come_from_the_version_and_extensions() {
+        let capabilities = Capabilities::from_strings("2.1 Mesa 11.2.0",
+                                                      "GL_ARB_texture_rectangle GL_ARB_sync",
+                                                      4096);
+        assert!(!capabilities.is_gles);
+        assert!(capabilities.texture_rectangle);
+        assert!(capabilities.bgra_upload);
+        assert!(capabilities.pixel_buffer_objects);
+        assert!(capabilities.fence_sync);
+        assert!(!capabilities.timer_queries);
+        assert!(!capabilities.srgb_framebuffer);
+        assert_eq!(capabilities.max_texture_size, 4096);
+    }
+
+    #[test]
+    fn extensions_match_whole_names_only() {
+        let capabilities = Capabilities::from_strings("2.0", "GL_ARB_texture_rectangle_foo", 0);
+        assert!(!capabilities.texture_rectangle);
+        assert!(!capabilities.pixel_buffer_objects);
+    }
+
+    #[test]
+    fn gles_never_uses_rectangle_textures() {
+        let capabilities = Capabilities::from_strings("OpenGL ES 3.0 V@127.0",
+                                                      "GL_ARB_texture_rectangle",
+                                                      2048);
+        assert!(capabilities.is_gles);
+        assert!(!capabilities.texture_rectangle);
+        assert!(!capabilities.bgra_upload);
+        assert!(capabilities.pixel_buffer_objects);
+        assert!(capabilities.srgb_framebuffer);
+        assert!(capabilities.fence_sync);
+    }
+
+    #[test]
+    fn gles_2_needs_extensions() {
+        let capabilities = Capabilities::from_strings("OpenGL ES 2.0",
+                                                      "GL_EXT_texture_format_BGRA8888",
+                                                      2048);
+        assert!(capabilities.is_gles);
+        assert!(capabilities.bgra_upload);
+        assert!(!capabilities.pixel_buffer_objects);
+        assert!(!capabilities.fence_sync);
+    }
+
+    #[test]
+    fn versions_are_parsed() {
+        assert_eq!(parse_version("3.3.0 NVIDIA 367.44"), (false, 3, 3));
+        assert_eq!(parse_version("OpenGL ES 3.1 Mesa"), (true, 3, 1));
+        assert_eq!(parse_version("OpenGL ES-CM 1.1"), (true, 1, 1));
+        assert_eq!(parse_version("garbage"), (false, 0, 0));
+        assert_eq!(parse_version(""), (false, 0, 0));
+    }
+}
@@ -7,7 +7,12 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-#[derive(Copy, Clone, Debug)]
+//! RGBA colors, and the color matrices layers can be filtered with.
+//!
+//! Colors are stored with straight alpha, each component between 0 and 1. The compositor blends
+//! with premultiplied alpha, so colors must be premultiplied before they are drawn.
+
+#[derive(Copy, Clone, Debug, RustcEncodable)]
 pub struct Color {
     pub r: f32,
     pub g: f32,
@@ -15,5 +20,177 @@ pub struct Color {
     pub a: f32,
 }
 
+impl Color {
+    /// Creates a color from 8-bit sRGB components with straight alpha.
+    pub fn from_rgba8(r: u8, g: u8, b: u8, a: u8) -> Color {
+        Color {
+            r: r as f32 / 255.0,
+            g: g as f32 / 255.0,
+            b: b as f32 / 255.0,
+            a: a as f32 / 255.0,
+        }
+    }
+
+    /// Returns transparent black.
+    pub fn transparent() -> Color {
+        Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 }
+    }
+
+    /// Returns the color with its color components multiplied by its alpha, as the blend
+    /// function of the compositor expects.
+    pub fn premultiply(self) -> Color {
+        Color {
+            r: self.r * self.a,
+            g: self.g * self.a,
+            b: self.b * self.a,
+            a: self.a,
+        }
+    }
+
+    /// Undoes `premultiply`. A fully transparent color carries no color, so transparent black
+    /// is returned for it.
+    pub fn unpremultiply(self) -> Color {
+        if self.a == 0.0 {
+            return Color::transparent();
+        }
+        Color {
+            r: self.r / self.a,
+            g: self.g / self.a,
+            b: self.b / self.a,
+            a: self.a,
+        }
+    }
+
+    /// Returns true if every component of the two colors differs by at most `epsilon`.
+    pub fn approx_eq(&self, other: &Color, epsilon: f32) -> bool {
+        (self.r - other.r).abs() <= epsilon &&
+        (self.g - other.g).abs() <= epsilon &&
+        (self.b - other.b).abs() <= epsilon &&
+        (self.a - other.a).abs() <= epsilon
+    }
+}
+
 #[cfg(feature = "heapsize")]
 known_heap_size!(0, Color);
+
+/// A 4x5 matrix transforming colors with straight alpha, as used by the CSS color filters. Each
+/// row computes one of the red, green, blue and alpha components of the result from the four
+/// components of the input and a constant in the last column. The results are clamped to [0, 1].
+#[derive(Copy, Clone, Debug, PartialEq, RustcEncodable)]
+pub struct ColorMatrix {
+    pub rows: [[f32; 5]; 4],
+}
+
+#[cfg(feature = "heapsize")]
+known_heap_size!(0, ColorMatrix);
+
+impl ColorMatrix {
+    pub fn identity() -> ColorMatrix {
+        ColorMatrix {
+            rows: [[1.0, 0.0, 0.0, 0.0, 0.0],
+                   [0.0, 1.0, 0.0, 0.0, 0.0],
+                   [0.0, 0.0, 1.0, 0.0, 0.0],
+                   [0.0, 0.0, 0.0, 1.0, 0.0]],
+        }
+    }
+
+    /// Returns the matrix of the CSS `grayscale()` filter. `amount` is clamped to [0, 1], 1
+    /// making the color entirely gray.
+    pub fn grayscale(amount: f32) -> ColorMatrix {
+        let s = 1.0 - amount.max(0.0).min(1.0);
+        ColorMatrix {
+            rows: [[0.2126 + 0.7874 * s, 0.7152 - 0.7152 * s, 0.0722 - 0.0722 * s, 0.0, 0.0],
+                   [0.2126 - 0.2126 * s, 0.7152 + 0.2848 * s, 0.0722 - 0.0722 * s, 0.0, 0.0],
+                   [0.2126 - 0.2126 * s, 0.7152 - 0.7152 * s, 0.0722 + 0.9278 * s, 0.0, 0.0],
+                   [0.0, 0.0, 0.0, 1.0, 0.0]],
+        }
+    }
+
+    /// Returns the matrix of the CSS `sepia()` filter. `amount` is clamped to [0, 1].
+    pub fn sepia(amount: f32) -> ColorMatrix {
+        let s = 1.0 - amount.max(0.0).min(1.0);
+        ColorMatrix {
+            rows: [[0.393 + 0.607 * s, 0.769 - 0.769 * s, 0.189 - 0.189 * s, 0.0, 0.0],
+                   [0.349 - 0.349 * s, 0.686 + 0.314 * s, 0.168 - 0.168 * s, 0.0, 0.0],
+                   [0.272 - 0.272 * s, 0.534 - 0.534 * s, 0.131 + 0.869 * s, 0.0, 0.0],
+                   [0.0, 0.0, 0.0, 1.0, 0.0]],
+        }
+    }
+
+    /// Returns the matrix of the CSS `invert()` filter. `amount` is clamped to [0, 1], 1
+    /// inverting the color entirely.
+    pub fn invert(amount: f32) -> ColorMatrix {
+        let amount = amount.max(0.0).min(1.0);
+        let scale = 1.0 - 2.0 * amount;
+        ColorMatrix {
+            rows: [[scale, 0.0, 0.0, 0.0, amount],
+                   [0.0, scale, 0.0, 0.0, amount],
+                   [0.0, 0.0, scale, 0.0, amount],
+                   [0.0, 0.0, 0.0, 1.0, 0.0]],
+        }
+    }
+
+    /// Returns the matrix of the CSS `brightness()` filter, which multiplies the color
+    /// components by `amount`. Negative amounts are treated as 0.
+    pub fn brightness(amount: f32) -> ColorMatrix {
+        let amount = amount.max(0.0);
+        ColorMatrix {
+            rows: [[amount, 0.0, 0.0, 0.0, 0.0],
+                   [0.0, amount, 0.0, 0.0, 0.0],
+                   [0.0, 0.0, amount, 0.0, 0.0],
+                   [0.0, 0.0, 0.0, 1.0, 0.0]],
+        }
+    }
+
+    /// Returns the 4x4 part of the matrix in column-major order, as GL expects it.
+    pub fn to_column_major_array(&self) -> [f32; 16] {
+        let mut array = [0.0; 16];
+        for (row_index, row) in self.rows.iter().enumerate() {
+            for column_index in 0..4 {
+                array[column_index * 4 + row_index] = row[column_index];
+            }
+        }
+        array
+    }
+
+    /// Returns the constant column of the matrix.
+    pub fn offset(&self) -> [f32; 4] {
+        [self.rows[0][4], self.rows[1][4], self.rows[2][4], self.rows[3][4]]
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::Color;
+
+    #[test]
+    fn colors_are_built_from_8_bit_components() {
+        let color = Color::from_rgba8(255, 0, 51, 128);
+        assert!(color.approx_eq(&Color { r: 1.0, g: 0.0, b: 0.2, a: 128.0 / 255.0 }, 1e-6));
+        assert!(Color::from_rgba8(0, 0, 0, 0).approx_eq(&Color::transparent(), 0.0));
+        assert!(!color.approx_eq(&Color { a: 0.5, ..color }, 1e-3));
+        assert!(color.approx_eq(&Color { a: 0.5, ..color }, 0.01));
+    }
+
+    #[test]
+    fn premultiplying_and_unpremultiplying_round_trips() {
+        let color = Color { r: 1.0, g: 0.5, b: 0.25, a: 0.5 };
+        let premultiplied = color.premultiply();
+        assert!(premultiplied.approx_eq(&Color { r: 0.5, g: 0.25, b: 0.125, a: 0.5 }, 1e-6));
+        assert!(premultiplied.unpremultiply().approx_eq(&color, 1e-6));
+
+        // Opaque colors are unchanged.
+        let opaque = Color { a: 1.0, ..color };
+        assert!(opaque.premultiply().approx_eq(&opaque, 0.0));
+        assert!(opaque.unpremultiply().approx_eq(&opaque, 0.0));
+    }
+
+    #[test]
+    fn fully_transparent_colors_unpremultiply_to_transparent_black() {
+        let transparent_red = Color { r: 1.0, g: 0.0, b: 0.0, a: 0.0 };
+        assert!(transparent_red.premultiply().approx_eq(&Color::transparent(), 0.0));
+        let unpremultiplied = Color { r: 0.5, g: 0.5, b: 0.5, a: 0.0 }.unpremultiply();
+        assert!(unpremultiplied.approx_eq(&Color::transparent(), 0.0));
+        assert!(!unpremultiplied.r.is_nan());
+    }
+}
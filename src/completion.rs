@@ -0,0 +1,235 @@
+// Copyright 2016 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Tokens through which the compositor tells the embedder that some asynchronous work is done:
+//! a buffer transaction was committed, an upload finished on the GPU, or an animation ended.
+//!
+//! The compositor keeps the `CompletionSignal` of the work and hands out `CompletionToken`s,
+//! which can be polled with `is_complete` or awaited through the `Future` returned by `future`.
+//! Tokens are `Send`, so the embedder can wait on another thread than the compositor's. Dropping
+//! the signal before completing it cancels the work, and waiting on its tokens then fails with
+//! `Cancelled` rather than hanging forever.
+
+use std::fmt;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+const PENDING: usize = 0;
+const COMPLETE: usize = 1;
+const CANCELLED: usize = 2;
+
+struct TokenState {
+    /// One of `PENDING`, `COMPLETE` or `CANCELLED`. Once it leaves `PENDING` it never changes.
+    state: AtomicUsize,
+
+    /// The wakers of the futures waiting for the work.
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl TokenState {
+    fn finish(&self, state: usize) {
+        if self.state.compare_exchange(PENDING, state, Ordering::AcqRel, Ordering::Acquire)
+               .is_err() {
+            return
+        }
+        let wakers = match self.wakers.lock() {
+            Ok(mut wakers) => mem::replace(&mut *wakers, vec!()),
+            Err(_) => return,
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// Returned by `TokenFuture` when the work was cancelled instead of completed, for instance
+/// because the layer it was for was removed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The work was cancelled before it completed")
+    }
+}
+
+/// The compositor side of a piece of work, which completes its tokens. Dropping it without
+/// calling `complete` cancels them.
+pub struct CompletionSignal {
+    state: Arc<TokenState>,
+}
+
+impl CompletionSignal {
+    /// Creates the signal of a new piece of work.
+    pub fn new() -> CompletionSignal {
+        CompletionSignal {
+            state: Arc::new(TokenState {
+                state: AtomicUsize::new(PENDING),
+                wakers: Mutex::new(vec!()),
+            }),
+        }
+    }
+
+    /// Returns a token that completes along with this signal.
+    pub fn token(&self) -> CompletionToken {
+        CompletionToken {
+            state: self.state.clone(),
+        }
+    }
+
+    /// Completes the tokens of this signal and wakes the futures waiting on them.
+    pub fn complete(self) {
+        self.state.finish(COMPLETE)
+    }
+}
+
+impl Drop for CompletionSignal {
+    fn drop(&mut self) {
+        self.state.finish(CANCELLED)
+    }
+}
+
+/// The embedder side of a piece of work, which tells whether it is done.
+#[derive(Clone)]
+pub struct CompletionToken {
+    state: Arc<TokenState>,
+}
+
+impl CompletionToken {
+    /// Returns a token that is already complete, for work that was done synchronously.
+    pub fn completed() -> CompletionToken {
+        let signal = CompletionSignal::new();
+        let token = signal.token();
+        signal.complete();
+        token
+    }
+
+    /// Returns true if the work completed.
+    pub fn is_complete(&self) -> bool {
+        self.state.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    /// Returns true if the work was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.state.state.load(Ordering::Acquire) == CANCELLED
+    }
+
+    /// Returns a future that resolves when the work completes or is cancelled.
+    pub fn future(&self) -> TokenFuture {
+        TokenFuture {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for CompletionToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self.state.state.load(Ordering::Acquire) {
+            PENDING => "pending",
+            COMPLETE => "complete",
+            _ => "cancelled",
+        };
+        write!(f, "CompletionToken({})", state)
+    }
+}
+
+/// A future that resolves when the work of a `CompletionToken` completes or is cancelled.
+pub struct TokenFuture {
+    state: Arc<TokenState>,
+}
+
+impl TokenFuture {
+    fn poll_state(&self) -> Poll<Result<(), Cancelled>> {
+        match self.state.state.load(Ordering::Acquire) {
+            PENDING => Poll::Pending,
+            COMPLETE => Poll::Ready(Ok(())),
+            _ => Poll::Ready(Err(Cancelled)),
+        }
+    }
+}
+
+impl Future for TokenFuture {
+    type Output = Result<(), Cancelled>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), Cancelled>> {
+        if let Poll::Ready(result) = self.poll_state() {
+            return Poll::Ready(result)
+        }
+
+        // Register before checking again, so that work finishing in between still wakes us.
+        if let Ok(mut wakers) = self.state.wakers.lock() {
+            if !wakers.iter().any(|waker| waker.will_wake(context.waker())) {
+                wakers.push(context.waker().clone());
+            }
+        }
+        self.poll_state()
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::{Cancelled, CompletionSignal, CompletionToken};
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use std::task::{Context, Poll, Waker};
+    use std::thread;
+    use test_util::{ThreadWaker, block_on};
+
+    /// Polls `future` once with a waker that counts its wakes, which are returned.
+    fn poll_once<F: Future + Unpin>(future: &mut F) -> (Poll<F::Output>, Arc<ThreadWaker>) {
+        let counter = Arc::new(ThreadWaker::for_current_thread());
+        let waker = Waker::from(counter.clone());
+        let poll = Pin::new(future).poll(&mut Context::from_waker(&waker));
+        (poll, counter)
+    }
+
+    #[test]
+    fn completing_a_signal_wakes_the_pending_future() {
+        let signal = CompletionSignal::new();
+        let token = signal.token();
+        let mut future = token.future();
+        let (poll, waker) = poll_once(&mut future);
+        assert_eq!(poll, Poll::Pending);
+        assert!(!token.is_complete());
+
+        signal.complete();
+        assert_eq!(waker.wakes.load(Ordering::SeqCst), 1);
+        assert!(token.is_complete());
+        assert_eq!(poll_once(&mut future).0, Poll::Ready(Ok(())));
+    }
+
+    #[test]
+    fn dropping_a_signal_cancels_its_tokens() {
+        let signal = CompletionSignal::new();
+        let token = signal.token();
+        drop(signal);
+        assert!(token.is_cancelled());
+        assert_eq!(block_on(token.future()), Err(Cancelled));
+    }
+
+    #[test]
+    fn completed_tokens_resolve_immediately() {
+        assert_eq!(block_on(CompletionToken::completed().future()), Ok(()));
+    }
+
+    #[test]
+    fn tokens_can_be_awaited_on_another_thread() {
+        let signal = CompletionSignal::new();
+        let token = signal.token();
+        let waiter = thread::spawn(move || block_on(token.future()));
+        signal.complete();
+        assert_eq!(waiter.join().unwrap(), Ok(()));
+    }
+}
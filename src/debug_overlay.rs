@@ -0,0 +1,76 @@
+// Copyright 2016 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! What the debug overlays draw over layers and tiles. The GL compositor and the software
+//! compositor both take their colors and line thicknesses from here, so that the golden images
+//! of the software compositor catch changes to them.
+//!
+//! Overlays are drawn over the content they describe: the borders and heatmap tint of a tile
+//! right after the tile, and the label, border, and bounding box of a layer after all of its
+//! tiles.
+
+use color::Color;
+use layers::ContentAge;
+use tiling::Tile;
+
+/// What is drawn over layers and tiles for debugging.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DebugOverlayMode {
+    /// Nothing, except what the debug options of each layer ask for.
+    None,
+    /// Lines at the borders of layers, their bounding boxes, and tiles.
+    Borders,
+    /// A tint over each tile going from green for tiles showing the current content of their
+    /// layer to red for tiles that are many content ages behind.
+    ContentAgeHeatmap,
+    /// A tint over each tile going from green for tiles painted once to red for tiles that were
+    /// painted many times.
+    PaintCountHeatmap,
+}
+
+pub static TILE_BORDER_COLOR: Color = Color { r: 0., g: 1., b: 1., a: 1.0 };
+pub static TILE_BORDER_THICKNESS: usize = 1;
+pub static LAYER_BORDER_COLOR: Color = Color { r: 1., g: 0.5, b: 0., a: 1.0 };
+pub static LAYER_BORDER_THICKNESS: usize = 2;
+pub static LAYER_AABB_BORDER_COLOR: Color = Color { r: 1., g: 0.0, b: 0., a: 1.0 };
+pub static LAYER_AABB_BORDER_THICKNESS: usize = 1;
+
+/// The opacity of the tints drawn over tiles by the heatmap debug overlays.
+static HEATMAP_ALPHA: f32 = 0.4;
+/// The number of content ages behind its layer at which a tile is drawn fully red by
+/// `DebugOverlayMode::ContentAgeHeatmap`.
+static HEATMAP_MAX_CONTENT_AGES_BEHIND: usize = 8;
+/// The paint count at which a tile is drawn fully red by `DebugOverlayMode::PaintCountHeatmap`.
+static HEATMAP_MAX_PAINT_COUNT: u32 = 16;
+
+/// Returns the premultiplied color of the heatmap tint drawn over the given tile, if `mode` is a
+/// heatmap. `content_age` is the current content age of the layer of the tile.
+pub fn heatmap_color(mode: DebugOverlayMode, tile: &Tile, content_age: ContentAge)
+                     -> Option<Color> {
+    let heat = match mode {
+        DebugOverlayMode::None | DebugOverlayMode::Borders => return None,
+        DebugOverlayMode::ContentAgeHeatmap => {
+            let ages_behind = tile.content_ages_behind(content_age).unwrap_or(0);
+            ages_behind.min(HEATMAP_MAX_CONTENT_AGES_BEHIND) as f32 /
+                HEATMAP_MAX_CONTENT_AGES_BEHIND as f32
+        }
+        DebugOverlayMode::PaintCountHeatmap => {
+            let paint_count = tile.paint_count().saturating_sub(1);
+            paint_count.min(HEATMAP_MAX_PAINT_COUNT) as f32 / HEATMAP_MAX_PAINT_COUNT as f32
+        }
+    };
+
+    // Green through yellow to red.
+    Some(Color {
+        r: (heat * 2.0).min(1.0),
+        g: ((1.0 - heat) * 2.0).min(1.0),
+        b: 0.0,
+        a: HEATMAP_ALPHA,
+    }.premultiply())
+}
@@ -0,0 +1,142 @@
+// Copyright 2016 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Snapshots of the layer tree as plain data, as produced by `scene::dump_layer_tree`, for
+//! debugging and remote inspection.
+//!
+//! A dump copies the state of every layer at the time it is taken, so dumps of successive frames
+//! can be encoded, for instance as JSON with `to_json_string`, and diffed. The transform state is
+//! the one computed by the last transform update.
+
+use color::{Color, ColorMatrix};
+use geometry::{DevicePixel, LayerPixel, ScreenPixel};
+use layers::Layer;
+use tiling::TileGridStatistics;
+
+use euclid::point::TypedPoint2D;
+use euclid::rect::TypedRect;
+use rustc_serialize::json;
+
+/// A snapshot of a scene and its layer tree.
+#[derive(Clone, Debug, RustcEncodable)]
+pub struct LayerTreeDump {
+    /// The viewport of the scene.
+    pub viewport: TypedRect<f32, DevicePixel>,
+
+    /// The scale of the scene.
+    pub scale: f32,
+
+    /// The root layer, if the scene has one.
+    pub root: Option<LayerDump>,
+}
+
+impl LayerTreeDump {
+    /// Encodes the dump as JSON.
+    pub fn to_json_string(&self) -> String {
+        // The dump holds no maps, whose non-string keys are the only thing the encoder rejects.
+        json::encode(self).unwrap()
+    }
+}
+
+/// A snapshot of a layer and its descendants.
+#[derive(Clone, Debug, RustcEncodable)]
+pub struct LayerDump {
+    pub bounds: TypedRect<f32, LayerPixel>,
+
+    /// The transform of the layer, in the order of `Matrix4D::row_major`, as are the other
+    /// matrices of a dump.
+    pub transform: [f32; 16],
+
+    pub perspective: [f32; 16],
+    pub content_offset: TypedPoint2D<f32, LayerPixel>,
+    pub masks_to_bounds: bool,
+    pub opacity: f32,
+    pub visible: bool,
+    pub hides_subtree: bool,
+    pub background_color: Color,
+    pub contents_opaque: bool,
+    pub filter: Option<ColorMatrix>,
+    pub establishes_3d_context: bool,
+    pub stacking_index: u64,
+    pub group_id: Option<u64>,
+    pub tile_size: usize,
+
+    /// The transform state computed by the last transform update.
+    pub transform_state: TransformStateDump,
+
+    /// Counters describing the tiles of the layer.
+    pub tile_statistics: TileGridStatistics,
+
+    /// The extra data of the layer, formatted with `Debug`, if it was asked for.
+    pub extra_data: Option<String>,
+
+    /// The children of the layer, in paint order.
+    pub children: Vec<LayerDump>,
+}
+
+impl LayerDump {
+    /// Takes a snapshot of the layer and its descendants, describing their extra data with
+    /// `describe_extra_data`.
+    pub fn new<T, F>(layer: &Layer<T>, describe_extra_data: &F) -> LayerDump
+                     where F: Fn(&T) -> Option<String> {
+        let transform_state = layer.transform_state.borrow();
+        LayerDump {
+            bounds: layer.bounds(),
+            transform: layer.transform().to_row_major_array(),
+            perspective: layer.perspective().to_row_major_array(),
+            content_offset: layer.content_offset(),
+            masks_to_bounds: layer.masks_to_bounds(),
+            opacity: layer.opacity(),
+            visible: *layer.visible.borrow(),
+            hides_subtree: *layer.hides_subtree.borrow(),
+            background_color: *layer.background_color.borrow(),
+            contents_opaque: *layer.contents_opaque.borrow(),
+            filter: *layer.filter.borrow(),
+            establishes_3d_context: layer.establishes_3d_context,
+            stacking_index: *layer.stacking_index.borrow(),
+            group_id: layer.group_id.get(),
+            tile_size: layer.tile_size.get(),
+            transform_state: TransformStateDump {
+                final_transform: transform_state.final_transform.to_row_major_array(),
+                inverse_transform: transform_state.inverse_transform.map(|inverse| {
+                    inverse.to_row_major_array()
+                }),
+                screen_rect: transform_state.screen_rect.as_ref().map(|rect| rect.rect),
+                z_center: transform_state.screen_rect.as_ref().map(|rect| rect.z_center),
+                world_rect: transform_state.world_rect,
+                has_transform: transform_state.has_transform,
+                crosses_near_plane: transform_state.crosses_near_plane,
+                effective_opacity: transform_state.effective_opacity,
+            },
+            tile_statistics: layer.tile_statistics(),
+            extra_data: describe_extra_data(&*layer.extra_data.borrow()),
+            children: layer.children().iter().map(|child| {
+                LayerDump::new(&**child, describe_extra_data)
+            }).collect(),
+        }
+    }
+}
+
+/// A snapshot of the `TransformState` of a layer.
+#[derive(Clone, Debug, RustcEncodable)]
+pub struct TransformStateDump {
+    pub final_transform: [f32; 16],
+    pub inverse_transform: Option<[f32; 16]>,
+
+    /// The rect of the layer on the screen, or None if it is entirely clipped.
+    pub screen_rect: Option<TypedRect<f32, ScreenPixel>>,
+
+    /// The z of the center of the screen rect, or None if the layer is entirely clipped.
+    pub z_center: Option<f32>,
+
+    pub world_rect: TypedRect<f32, LayerPixel>,
+    pub has_transform: bool,
+    pub crosses_near_plane: bool,
+    pub effective_opacity: f32,
+}
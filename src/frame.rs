@@ -0,0 +1,126 @@
+// Copyright 2016 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Frame counting for the render pass.
+//!
+//! The embedder starts each frame with `RenderContext::begin_frame`, passing the current time
+//! from whatever clock it uses; frame times never come from the system clock, which is only read
+//! to time render passes for `RenderStatistics`. The resulting `FrameInfo` is handed down the
+//! render path to everything that draws during the frame. Frames are ended with
+//! `RenderContext::end_frame`, which restores the GL state of the embedder if it was saved.
+
+/// The index of a frame. Indices wrap around on overflow, so they must be compared with
+/// `is_after` rather than with the ordinary comparison operators.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, RustcEncodable)]
+pub struct FrameIndex(pub u32);
+
+#[cfg(feature = "heapsize")]
+known_heap_size!(0, FrameIndex);
+
+impl FrameIndex {
+    /// Returns the index of the frame after this one.
+    pub fn next(self) -> FrameIndex {
+        FrameIndex(self.0.wrapping_add(1))
+    }
+
+    /// Returns true if this frame comes after `other`. This is correct across wrap-around as
+    /// long as the two frames are less than 2^31 frames apart.
+    pub fn is_after(self, other: FrameIndex) -> bool {
+        (self.0.wrapping_sub(other.0) as i32) > 0
+    }
+}
+
+/// The frame currently being rendered.
+#[derive(Clone, Copy, Debug, RustcEncodable)]
+pub struct FrameInfo {
+    /// The index of this frame.
+    pub index: FrameIndex,
+
+    /// The time at which the embedder began this frame, in whatever units it uses.
+    pub time: f64,
+}
+
+#[cfg(feature = "heapsize")]
+known_heap_size!(0, FrameInfo);
+
+/// Counts frames as they are begun.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameClock {
+    /// The most recently begun frame, if any.
+    last_frame: Option<FrameInfo>,
+}
+
+impl FrameClock {
+    pub fn new() -> FrameClock {
+        FrameClock {
+            last_frame: None,
+        }
+    }
+
+    /// Begins a new frame at the given time and returns it. Times that go backwards are clamped
+    /// to the time of the previous frame, so frame times never decrease.
+    pub fn begin_frame(&mut self, now: f64) -> FrameInfo {
+        let frame = match self.last_frame {
+            Some(last_frame) => {
+                FrameInfo {
+                    index: last_frame.index.next(),
+                    time: now.max(last_frame.time),
+                }
+            }
+            None => {
+                FrameInfo {
+                    index: FrameIndex(0),
+                    time: now,
+                }
+            }
+        };
+        self.last_frame = Some(frame);
+        frame
+    }
+
+    /// Returns the most recently begun frame, or None if no frame has been begun yet.
+    pub fn last_frame(&self) -> Option<FrameInfo> {
+        self.last_frame
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::{FrameClock, FrameIndex};
+    use std::u32;
+
+    #[test]
+    fn frame_indices_stay_ordered_across_wrap_around() {
+        let last = FrameIndex(u32::MAX);
+        let first = last.next();
+        assert_eq!(first, FrameIndex(0));
+        assert!(first.is_after(last));
+        assert!(!last.is_after(first));
+        assert!(FrameIndex(5).is_after(FrameIndex(u32::MAX - 5)));
+        assert!(!FrameIndex(3).is_after(FrameIndex(3)));
+
+        // Frames 2^31 or more apart can't be ordered.
+        assert!(FrameIndex((1 << 31) - 1).is_after(FrameIndex(0)));
+        assert!(!FrameIndex(1 << 31).is_after(FrameIndex(0)));
+    }
+
+    #[test]
+    fn frame_times_never_go_backwards() {
+        let mut clock = FrameClock::new();
+        assert!(clock.last_frame().is_none());
+        let first = clock.begin_frame(10.0);
+        assert_eq!((first.index, first.time), (FrameIndex(0), 10.0));
+
+        let second = clock.begin_frame(4.0);
+        assert_eq!((second.index, second.time), (FrameIndex(1), 10.0));
+        let third = clock.begin_frame(12.5);
+        assert_eq!((third.index, third.time), (FrameIndex(2), 12.5));
+        assert_eq!(clock.last_frame().unwrap().index, FrameIndex(2));
+    }
+}
@@ -8,11 +8,24 @@
 // except according to those terms.
 
 // Units for use with euclid::length and euclid::scale_factor.
+//
+// The coordinate spaces used by this crate are:
+//
+// * `LayerPixel`: layer coordinates. Layer bounds are relative to the parent layer, while the
+//   world rect of a layer is in global coordinates, after content offsets but before any layer
+//   transform.
+// * `ScreenPixel`: the result of applying the layer transforms (and perspective) to world
+//   coordinates, before the scene scale. Projected rects, clip rects, and the dirty and viewport
+//   rects of the buffer request pass are in this space.
+// * `DevicePixel`: screen coordinates multiplied by the scene scale. Tiles live in this space.
+
+use euclid::rect::TypedRect;
+use euclid::scale_factor::ScaleFactor;
 
 /// One hardware pixel.
 ///
 /// This unit corresponds to the smallest addressable element of the display hardware.
-#[derive(Copy, Clone, RustcEncodable, Debug)]
+#[derive(Copy, Clone, RustcDecodable, RustcEncodable, Debug)]
 pub enum DevicePixel {}
 
 #[cfg(feature = "heapsize")]
@@ -22,8 +35,31 @@ known_heap_size!(0, DevicePixel);
 ///
 /// This unit corresponds to a "pixel" in layer coordinate space, which after scaling and
 /// transformation becomes a device pixel.
-#[derive(Copy, Clone, RustcEncodable, Debug)]
+#[derive(Copy, Clone, RustcDecodable, RustcEncodable, Debug)]
 pub enum LayerPixel {}
 
 #[cfg(feature = "heapsize")]
 known_heap_size!(0, LayerPixel);
+
+/// One pixel in screen coordinate space.
+///
+/// This unit corresponds to a layer pixel after all layer transforms have been applied, but
+/// before the scene scale is applied.
+#[derive(Copy, Clone, RustcDecodable, RustcEncodable, Debug)]
+pub enum ScreenPixel {}
+
+#[cfg(feature = "heapsize")]
+known_heap_size!(0, ScreenPixel);
+
+/// Reinterprets a world-space rect as a screen-space rect. This is only correct for rects that
+/// are not affected by any layer transform, such as the bounds of the root layer.
+pub fn untransformed_world_rect_to_screen(rect: &TypedRect<f32, LayerPixel>)
+                                          -> TypedRect<f32, ScreenPixel> {
+    TypedRect::from_untyped(&rect.to_untyped())
+}
+
+/// Converts the scene scale into the scale from screen pixels to device pixels.
+pub fn screen_to_device_scale(scale: ScaleFactor<f32, LayerPixel, DevicePixel>)
+                              -> ScaleFactor<f32, ScreenPixel, DevicePixel> {
+    ScaleFactor::new(scale.get())
+}
@@ -0,0 +1,2160 @@
+// Copyright 2016 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Recording and replaying the GL calls made by this crate, for reproducing driver bugs outside
+//! of the embedder.
+//!
+//! All GL calls of the crate go through this module, which re-exports `gleam::gl` and wraps the
+//! functions we use. While recording is on for the current thread, every wrapped call is
+//! appended to a `GlTrace` before being issued. Uploaded data is recorded as its length and a
+//! digest, unless recording is verbose, in which case the bytes are kept too, so that traces
+//! stay small by default. Calls made by the platform surface code to bind native surfaces go
+//! through platform APIs and are not recorded.
+//!
+//! `replay_gl_trace` issues the calls of a trace again through a `GlApi`, usually `GleamGl` on
+//! the current GL context. Object names and uniform locations returned during the replay are
+//! mapped to the ones in the trace, and uploads recorded without their bytes are replayed with
+//! zeroed data of the same size.
+//!
+//! The wrapped calls are issued through the `GlApi` passed to them, which the render context and
+//! textures hold on to; it is `GleamGl` unless the embedder passes another one when creating the
+//! render context. Passing a `RecordingGl` instead lets the renderer run without a GL context: it
+//! logs the calls in the same form as a trace and hands out made-up names and locations, so a
+//! trace can be replayed into one too.
+
+pub use gleam::gl::*;
+
+use gleam::gl as gleam_gl;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::mem;
+use std::ptr;
+use std::slice;
+
+/// A fence sync object. `GLsync` is a pointer, which can't be recorded in a trace or sent to
+/// another thread, so syncs are passed around as its address.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct GlSync(pub u64);
+
+/// Data uploaded to GL, as recorded in a trace.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct GlData {
+    /// The size of the data in bytes.
+    pub len: usize,
+
+    /// A 64-bit FNV-1a digest of the data, for telling uploads apart.
+    pub digest: u64,
+
+    /// The data itself, if the trace was recorded verbosely.
+    pub bytes: Option<Vec<u8>>,
+}
+
+impl GlData {
+    fn new(bytes: &[u8], verbose: bool) -> GlData {
+        let mut digest: u64 = 0xcbf29ce484222325;
+        for byte in bytes {
+            digest ^= *byte as u64;
+            digest = digest.wrapping_mul(0x100000001b3);
+        }
+        GlData {
+            len: bytes.len(),
+            digest: digest,
+            bytes: if verbose { Some(bytes.to_vec()) } else { None },
+        }
+    }
+
+    /// Returns the recorded bytes, or zeroes of the recorded length.
+    fn bytes_or_zeroes(&self) -> Vec<u8> {
+        match self.bytes {
+            Some(ref bytes) => bytes.clone(),
+            None => vec![0; self.len],
+        }
+    }
+}
+
+/// A single recorded GL call, with its arguments and, for calls that return something, the
+/// result that was returned at recording time.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub enum GlCall {
+    ActiveTexture(GLenum),
+    AttachShader(GLuint, GLuint),
+    BindBuffer(GLenum, GLuint),
+    BindFramebuffer(GLenum, GLuint),
+    BindRenderbuffer(GLenum, GLuint),
+    BindTexture(GLenum, GLuint),
+    BlendFunc(GLenum, GLenum),
+    BlendFuncSeparate(GLenum, GLenum, GLenum, GLenum),
+    BufferData(GLenum, GlData, GLenum),
+    BufferDataUninitialized(GLenum, usize, GLenum),
+    BufferSubData(GLenum, usize, GlData),
+    CheckFramebufferStatus(GLenum, GLenum),
+    Clear(GLbitfield),
+    ClearColor(f32, f32, f32, f32),
+    ClientWaitSync(GlSync, GLuint64, GLenum),
+    CompileShader(GLuint),
+    CreateProgram(GLuint),
+    CreateShader(GLenum, GLuint),
+    DeleteBuffers(Vec<GLuint>),
+    DeleteFramebuffers(Vec<GLuint>),
+    DeleteProgram(GLuint),
+    DeleteRenderbuffers(Vec<GLuint>),
+    DeleteShader(GLuint),
+    DeleteSync(GlSync),
+    DeleteTextures(Vec<GLuint>),
+    DepthFunc(GLenum),
+    Disable(GLenum),
+    DisableVertexAttribArray(GLuint),
+    DrawArrays(GLenum, GLint, GLsizei),
+    Enable(GLenum),
+    EnableVertexAttribArray(GLuint),
+    FenceSync(GlSync),
+    FramebufferRenderbuffer(GLenum, GLenum, GLenum, GLuint),
+    FramebufferTexture2D(GLenum, GLenum, GLenum, GLuint, GLint),
+    GenBuffers(Vec<GLuint>),
+    GenFramebuffers(Vec<GLuint>),
+    GenRenderbuffers(Vec<GLuint>),
+    GenTextures(Vec<GLuint>),
+    GenerateMipmap(GLenum),
+    GetAttribLocation(GLuint, String, GLint),
+    GetFloatv4(GLenum, Vec<GLfloat>),
+    GetIntegerv(GLenum, GLint),
+    GetIntegerv4(GLenum, Vec<GLint>),
+    GetProgramInfoLog(GLuint),
+    GetProgramiv(GLuint, GLenum, GLint),
+    GetShaderInfoLog(GLuint),
+    GetShaderiv(GLuint, GLenum, GLint),
+    GetString(GLenum, String),
+    GetUniformLocation(GLuint, String, GLint),
+    IsEnabled(GLenum, GLboolean),
+    LineWidth(GLfloat),
+    LinkProgram(GLuint),
+    PixelStorei(GLenum, GLint),
+    ReadPixels(GLint, GLint, GLsizei, GLsizei, GLenum, GLenum),
+    RenderbufferStorage(GLenum, GLenum, GLsizei, GLsizei),
+    Scissor(GLint, GLint, GLsizei, GLsizei),
+    ShaderSource(GLuint, Vec<Vec<u8>>),
+    TexImage2D {
+        target: GLenum,
+        level: GLint,
+        internal_format: GLint,
+        width: GLsizei,
+        height: GLsizei,
+        border: GLint,
+        format: GLenum,
+        ty: GLenum,
+        data: Option<GlData>,
+    },
+    TexParameteri(GLenum, GLenum, GLint),
+    TexSubImage2D {
+        target: GLenum,
+        level: GLint,
+        xoffset: GLint,
+        yoffset: GLint,
+        width: GLsizei,
+        height: GLsizei,
+        format: GLenum,
+        ty: GLenum,
+        data: GlData,
+    },
+    TexSubImage2DPbo {
+        target: GLenum,
+        level: GLint,
+        xoffset: GLint,
+        yoffset: GLint,
+        width: GLsizei,
+        height: GLsizei,
+        format: GLenum,
+        ty: GLenum,
+        offset: usize,
+    },
+    Uniform1f(GLint, GLfloat),
+    Uniform1i(GLint, GLint),
+    Uniform4f(GLint, GLfloat, GLfloat, GLfloat, GLfloat),
+    UniformMatrix4fv(GLint, bool, Vec<f32>),
+    UseProgram(GLuint),
+    VertexAttribPointerF32(GLuint, GLint, bool, GLsizei, GLuint),
+    Viewport(GLint, GLint, GLsizei, GLsizei),
+}
+
+/// A recorded sequence of GL calls.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct GlTrace {
+    pub calls: Vec<GlCall>,
+}
+
+struct Recorder {
+    trace: GlTrace,
+    verbose: bool,
+}
+
+thread_local!(static RECORDER: RefCell<Option<Recorder>> = RefCell::new(None));
+
+/// Starts recording the GL calls made on this thread, discarding any trace being recorded. If
+/// `verbose` is true, the full contents of uploads are recorded instead of just their digests.
+pub fn start_recording(verbose: bool) {
+    RECORDER.with(|recorder| {
+        *recorder.borrow_mut() = Some(Recorder {
+            trace: GlTrace {
+                calls: vec!(),
+            },
+            verbose: verbose,
+        });
+    });
+}
+
+/// Stops recording on this thread and returns the recorded trace, or None if recording was not
+/// on.
+pub fn stop_recording() -> Option<GlTrace> {
+    RECORDER.with(|recorder| recorder.borrow_mut().take().map(|recorder| recorder.trace))
+}
+
+/// Returns true if GL calls made on this thread are being recorded.
+pub fn is_recording() -> bool {
+    RECORDER.with(|recorder| recorder.borrow().is_some())
+}
+
+/// Records a call if recording is on. The call is only built in that case.
+fn record<F>(call: F) where F: FnOnce(bool) -> GlCall {
+    RECORDER.with(|recorder| {
+        if let Some(ref mut recorder) = *recorder.borrow_mut() {
+            let verbose = recorder.verbose;
+            recorder.trace.calls.push(call(verbose));
+        }
+    });
+}
+
+/// The GL entry points used by this crate. All GL calls of the crate are issued through an
+/// implementation of this trait, held by the render context and the textures it creates, which is
+/// `GleamGl` unless the embedder says otherwise. `RecordingGl` can be used to run the compositor
+/// without a GL context, for instance in unit tests.
+pub trait GlApi {
+    fn active_texture(&self, texture: GLenum);
+    fn attach_shader(&self, program: GLuint, shader: GLuint);
+    fn bind_buffer(&self, target: GLenum, buffer: GLuint);
+    fn bind_framebuffer(&self, target: GLenum, framebuffer: GLuint);
+    fn bind_renderbuffer(&self, target: GLenum, renderbuffer: GLuint);
+    fn bind_texture(&self, target: GLenum, texture: GLuint);
+    fn blend_func(&self, sfactor: GLenum, dfactor: GLenum);
+    fn blend_func_separate(&self,
+                           src_rgb: GLenum,
+                           dst_rgb: GLenum,
+                           src_alpha: GLenum,
+                           dst_alpha: GLenum);
+    fn buffer_data(&self, target: GLenum, data: &[u8], usage: GLenum);
+    fn buffer_data_uninitialized(&self, target: GLenum, size: usize, usage: GLenum);
+    fn buffer_sub_data(&self, target: GLenum, offset: usize, data: &[u8]);
+    fn check_frame_buffer_status(&self, target: GLenum) -> GLenum;
+    fn clear(&self, buffer_mask: GLbitfield);
+    fn clear_color(&self, r: f32, g: f32, b: f32, a: f32);
+    fn client_wait_sync(&self, sync: GlSync, timeout: GLuint64) -> GLenum;
+    fn compile_shader(&self, shader: GLuint);
+    fn create_program(&self) -> GLuint;
+    fn create_shader(&self, shader_type: GLenum) -> GLuint;
+    fn delete_buffers(&self, buffers: &[GLuint]);
+    fn delete_framebuffers(&self, framebuffers: &[GLuint]);
+    fn delete_program(&self, program: GLuint);
+    fn delete_renderbuffers(&self, renderbuffers: &[GLuint]);
+    fn delete_shader(&self, shader: GLuint);
+    fn delete_sync(&self, sync: GlSync);
+    fn delete_textures(&self, textures: &[GLuint]);
+    fn depth_func(&self, func: GLenum);
+    fn disable(&self, cap: GLenum);
+    fn disable_vertex_attrib_array(&self, index: GLuint);
+    fn draw_arrays(&self, mode: GLenum, first: GLint, count: GLsizei);
+    fn enable(&self, cap: GLenum);
+    fn enable_vertex_attrib_array(&self, index: GLuint);
+    fn fence_sync(&self) -> GlSync;
+    fn framebuffer_renderbuffer(&self,
+                                target: GLenum,
+                                attachment: GLenum,
+                                renderbuffertarget: GLenum,
+                                renderbuffer: GLuint);
+    fn framebuffer_texture_2d(&self,
+                              target: GLenum,
+                              attachment: GLenum,
+                              textarget: GLenum,
+                              texture: GLuint,
+                              level: GLint);
+    fn gen_buffers(&self, n: GLsizei) -> Vec<GLuint>;
+    fn gen_framebuffers(&self, n: GLsizei) -> Vec<GLuint>;
+    fn gen_renderbuffers(&self, n: GLsizei) -> Vec<GLuint>;
+    fn gen_textures(&self, n: GLsizei) -> Vec<GLuint>;
+    fn generate_mipmap(&self, target: GLenum);
+    fn get_attrib_location(&self, program: GLuint, name: &str) -> GLint;
+    fn get_integer_v(&self, name: GLenum) -> GLint;
+    fn get_integer_4v(&self, name: GLenum) -> [GLint; 4];
+    fn get_float_4v(&self, name: GLenum) -> [GLfloat; 4];
+    fn get_program_info_log(&self, program: GLuint) -> String;
+    fn get_program_iv(&self, program: GLuint, pname: GLenum) -> GLint;
+    fn get_shader_info_log(&self, shader: GLuint) -> String;
+    fn get_shader_iv(&self, shader: GLuint, pname: GLenum) -> GLint;
+    fn get_string(&self, which: GLenum) -> String;
+    fn get_uniform_location(&self, program: GLuint, name: &str) -> GLint;
+    fn is_enabled(&self, cap: GLenum) -> GLboolean;
+    fn line_width(&self, width: GLfloat);
+    fn link_program(&self, program: GLuint);
+    fn pixel_store_i(&self, name: GLenum, param: GLint);
+    fn read_pixels(&self,
+                   x: GLint,
+                   y: GLint,
+                   width: GLsizei,
+                   height: GLsizei,
+                   format: GLenum,
+                   pixel_type: GLenum)
+                   -> Vec<u8>;
+    fn renderbuffer_storage(&self,
+                            target: GLenum,
+                            internalformat: GLenum,
+                            width: GLsizei,
+                            height: GLsizei);
+    fn scissor(&self, x: GLint, y: GLint, width: GLsizei, height: GLsizei);
+    fn shader_source(&self, shader: GLuint, strings: &[&[u8]]);
+    fn tex_image_2d(&self,
+                    target: GLenum,
+                    level: GLint,
+                    internal_format: GLint,
+                    width: GLsizei,
+                    height: GLsizei,
+                    border: GLint,
+                    format: GLenum,
+                    ty: GLenum,
+                    opt_data: Option<&[u8]>);
+    fn tex_parameter_i(&self, target: GLenum, pname: GLenum, param: GLint);
+    fn tex_sub_image_2d(&self,
+                        target: GLenum,
+                        level: GLint,
+                        xoffset: GLint,
+                        yoffset: GLint,
+                        width: GLsizei,
+                        height: GLsizei,
+                        format: GLenum,
+                        ty: GLenum,
+                        data: &[u8]);
+    fn tex_sub_image_2d_pbo(&self,
+                            target: GLenum,
+                            level: GLint,
+                            xoffset: GLint,
+                            yoffset: GLint,
+                            width: GLsizei,
+                            height: GLsizei,
+                            format: GLenum,
+                            ty: GLenum,
+                            offset: usize);
+    fn uniform_1f(&self, location: GLint, v0: GLfloat);
+    fn uniform_1i(&self, location: GLint, v0: GLint);
+    fn uniform_4f(&self, location: GLint, x: GLfloat, y: GLfloat, z: GLfloat, w: GLfloat);
+    fn uniform_matrix_4fv(&self, location: GLint, transpose: bool, value: &[f32]);
+    fn use_program(&self, program: GLuint);
+    fn vertex_attrib_pointer_f32(&self,
+                                 index: GLuint,
+                                 size: GLint,
+                                 normalized: bool,
+                                 stride: GLsizei,
+                                 offset: GLuint);
+    fn viewport(&self, x: GLint, y: GLint, width: GLsizei, height: GLsizei);
+}
+
+/// The `GlApi` that issues calls to the current GL context through `gleam`.
+pub struct GleamGl;
+
+impl GlApi for GleamGl {
+    fn active_texture(&self, texture: GLenum) {
+        gleam_gl::active_texture(texture)
+    }
+
+    fn attach_shader(&self, program: GLuint, shader: GLuint) {
+        gleam_gl::attach_shader(program, shader)
+    }
+
+    fn bind_buffer(&self, target: GLenum, buffer: GLuint) {
+        gleam_gl::bind_buffer(target, buffer)
+    }
+
+    fn bind_framebuffer(&self, target: GLenum, framebuffer: GLuint) {
+        gleam_gl::bind_framebuffer(target, framebuffer)
+    }
+
+    fn bind_renderbuffer(&self, target: GLenum, renderbuffer: GLuint) {
+        gleam_gl::bind_renderbuffer(target, renderbuffer)
+    }
+
+    fn bind_texture(&self, target: GLenum, texture: GLuint) {
+        gleam_gl::bind_texture(target, texture)
+    }
+
+    fn blend_func(&self, sfactor: GLenum, dfactor: GLenum) {
+        gleam_gl::blend_func(sfactor, dfactor)
+    }
+
+    fn blend_func_separate(&self,
+                           src_rgb: GLenum,
+                           dst_rgb: GLenum,
+                           src_alpha: GLenum,
+                           dst_alpha: GLenum) {
+        gleam_gl::blend_func_separate(src_rgb, dst_rgb, src_alpha, dst_alpha)
+    }
+
+    fn buffer_data(&self, target: GLenum, data: &[u8], usage: GLenum) {
+        gleam_gl::buffer_data(target, data, usage)
+    }
+
+    fn buffer_data_uninitialized(&self, target: GLenum, size: usize, usage: GLenum) {
+        unsafe {
+            gleam_gl::BufferData(target, size as GLsizeiptr, ptr::null(), usage);
+        }
+    }
+
+    fn buffer_sub_data(&self, target: GLenum, offset: usize, data: &[u8]) {
+        unsafe {
+            gleam_gl::BufferSubData(target,
+                                    offset as GLintptr,
+                                    data.len() as GLsizeiptr,
+                                    data.as_ptr() as *const GLvoid);
+        }
+    }
+
+    fn check_frame_buffer_status(&self, target: GLenum) -> GLenum {
+        gleam_gl::check_frame_buffer_status(target)
+    }
+
+    fn clear(&self, buffer_mask: GLbitfield) {
+        gleam_gl::clear(buffer_mask)
+    }
+
+    fn clear_color(&self, r: f32, g: f32, b: f32, a: f32) {
+        gleam_gl::clear_color(r, g, b, a)
+    }
+
+    fn client_wait_sync(&self, sync: GlSync, timeout: GLuint64) -> GLenum {
+        unsafe {
+            gleam_gl::ClientWaitSync(sync.0 as usize as GLsync, SYNC_FLUSH_COMMANDS_BIT, timeout)
+        }
+    }
+
+    fn compile_shader(&self, shader: GLuint) {
+        gleam_gl::compile_shader(shader)
+    }
+
+    fn create_program(&self) -> GLuint {
+        gleam_gl::create_program()
+    }
+
+    fn create_shader(&self, shader_type: GLenum) -> GLuint {
+        gleam_gl::create_shader(shader_type)
+    }
+
+    fn delete_buffers(&self, buffers: &[GLuint]) {
+        gleam_gl::delete_buffers(buffers)
+    }
+
+    fn delete_framebuffers(&self, framebuffers: &[GLuint]) {
+        gleam_gl::delete_framebuffers(framebuffers)
+    }
+
+    fn delete_program(&self, program: GLuint) {
+        gleam_gl::delete_program(program)
+    }
+
+    fn delete_renderbuffers(&self, renderbuffers: &[GLuint]) {
+        gleam_gl::delete_renderbuffers(renderbuffers)
+    }
+
+    fn delete_shader(&self, shader: GLuint) {
+        gleam_gl::delete_shader(shader)
+    }
+
+    fn delete_sync(&self, sync: GlSync) {
+        unsafe {
+            gleam_gl::DeleteSync(sync.0 as usize as GLsync)
+        }
+    }
+
+    fn delete_textures(&self, textures: &[GLuint]) {
+        gleam_gl::delete_textures(textures)
+    }
+
+    fn depth_func(&self, func: GLenum) {
+        gleam_gl::depth_func(func)
+    }
+
+    fn disable(&self, cap: GLenum) {
+        gleam_gl::disable(cap)
+    }
+
+    fn disable_vertex_attrib_array(&self, index: GLuint) {
+        gleam_gl::disable_vertex_attrib_array(index)
+    }
+
+    fn draw_arrays(&self, mode: GLenum, first: GLint, count: GLsizei) {
+        gleam_gl::draw_arrays(mode, first, count)
+    }
+
+    fn enable(&self, cap: GLenum) {
+        gleam_gl::enable(cap)
+    }
+
+    fn enable_vertex_attrib_array(&self, index: GLuint) {
+        gleam_gl::enable_vertex_attrib_array(index)
+    }
+
+    fn fence_sync(&self) -> GlSync {
+        unsafe {
+            GlSync(gleam_gl::FenceSync(SYNC_GPU_COMMANDS_COMPLETE, 0) as usize as u64)
+        }
+    }
+
+    fn framebuffer_renderbuffer(&self,
+                                target: GLenum,
+                                attachment: GLenum,
+                                renderbuffertarget: GLenum,
+                                renderbuffer: GLuint) {
+        gleam_gl::framebuffer_renderbuffer(target, attachment, renderbuffertarget, renderbuffer)
+    }
+
+    fn framebuffer_texture_2d(&self,
+                              target: GLenum,
+                              attachment: GLenum,
+                              textarget: GLenum,
+                              texture: GLuint,
+                              level: GLint) {
+        gleam_gl::framebuffer_texture_2d(target, attachment, textarget, texture, level)
+    }
+
+    fn gen_buffers(&self, n: GLsizei) -> Vec<GLuint> {
+        gleam_gl::gen_buffers(n)
+    }
+
+    fn gen_framebuffers(&self, n: GLsizei) -> Vec<GLuint> {
+        gleam_gl::gen_framebuffers(n)
+    }
+
+    fn gen_renderbuffers(&self, n: GLsizei) -> Vec<GLuint> {
+        gleam_gl::gen_renderbuffers(n)
+    }
+
+    fn gen_textures(&self, n: GLsizei) -> Vec<GLuint> {
+        gleam_gl::gen_textures(n)
+    }
+
+    fn generate_mipmap(&self, target: GLenum) {
+        gleam_gl::generate_mipmap(target)
+    }
+
+    fn get_attrib_location(&self, program: GLuint, name: &str) -> GLint {
+        gleam_gl::get_attrib_location(program, name)
+    }
+
+    fn get_integer_v(&self, name: GLenum) -> GLint {
+        gleam_gl::get_integer_v(name)
+    }
+
+    fn get_integer_4v(&self, name: GLenum) -> [GLint; 4] {
+        let mut values = [0; 4];
+        unsafe {
+            gleam_gl::GetIntegerv(name, values.as_mut_ptr());
+        }
+        values
+    }
+
+    fn get_float_4v(&self, name: GLenum) -> [GLfloat; 4] {
+        let mut values = [0.0; 4];
+        unsafe {
+            gleam_gl::GetFloatv(name, values.as_mut_ptr());
+        }
+        values
+    }
+
+    fn get_program_info_log(&self, program: GLuint) -> String {
+        gleam_gl::get_program_info_log(program)
+    }
+
+    fn get_program_iv(&self, program: GLuint, pname: GLenum) -> GLint {
+        gleam_gl::get_program_iv(program, pname)
+    }
+
+    fn get_shader_info_log(&self, shader: GLuint) -> String {
+        gleam_gl::get_shader_info_log(shader)
+    }
+
+    fn get_shader_iv(&self, shader: GLuint, pname: GLenum) -> GLint {
+        gleam_gl::get_shader_iv(shader, pname)
+    }
+
+    fn get_string(&self, which: GLenum) -> String {
+        gleam_gl::get_string(which)
+    }
+
+    fn get_uniform_location(&self, program: GLuint, name: &str) -> GLint {
+        gleam_gl::get_uniform_location(program, name)
+    }
+
+    fn is_enabled(&self, cap: GLenum) -> GLboolean {
+        gleam_gl::is_enabled(cap)
+    }
+
+    fn line_width(&self, width: GLfloat) {
+        gleam_gl::line_width(width)
+    }
+
+    fn link_program(&self, program: GLuint) {
+        gleam_gl::link_program(program)
+    }
+
+    fn pixel_store_i(&self, name: GLenum, param: GLint) {
+        gleam_gl::pixel_store_i(name, param)
+    }
+
+    fn read_pixels(&self,
+                   x: GLint,
+                   y: GLint,
+                   width: GLsizei,
+                   height: GLsizei,
+                   format: GLenum,
+                   pixel_type: GLenum)
+                   -> Vec<u8> {
+        gleam_gl::read_pixels(x, y, width, height, format, pixel_type)
+    }
+
+    fn renderbuffer_storage(&self,
+                            target: GLenum,
+                            internalformat: GLenum,
+                            width: GLsizei,
+                            height: GLsizei) {
+        gleam_gl::renderbuffer_storage(target, internalformat, width, height)
+    }
+
+    fn scissor(&self, x: GLint, y: GLint, width: GLsizei, height: GLsizei) {
+        gleam_gl::scissor(x, y, width, height)
+    }
+
+    fn shader_source(&self, shader: GLuint, strings: &[&[u8]]) {
+        gleam_gl::shader_source(shader, strings)
+    }
+
+    fn tex_image_2d(&self,
+                    target: GLenum,
+                    level: GLint,
+                    internal_format: GLint,
+                    width: GLsizei,
+                    height: GLsizei,
+                    border: GLint,
+                    format: GLenum,
+                    ty: GLenum,
+                    opt_data: Option<&[u8]>) {
+        gleam_gl::tex_image_2d(target,
+                               level,
+                               internal_format,
+                               width,
+                               height,
+                               border,
+                               format,
+                               ty,
+                               opt_data)
+    }
+
+    fn tex_parameter_i(&self, target: GLenum, pname: GLenum, param: GLint) {
+        gleam_gl::tex_parameter_i(target, pname, param)
+    }
+
+    fn tex_sub_image_2d(&self,
+                        target: GLenum,
+                        level: GLint,
+                        xoffset: GLint,
+                        yoffset: GLint,
+                        width: GLsizei,
+                        height: GLsizei,
+                        format: GLenum,
+                        ty: GLenum,
+                        data: &[u8]) {
+        gleam_gl::tex_sub_image_2d(target, level, xoffset, yoffset, width, height, format, ty, data)
+    }
+
+    fn tex_sub_image_2d_pbo(&self,
+                            target: GLenum,
+                            level: GLint,
+                            xoffset: GLint,
+                            yoffset: GLint,
+                            width: GLsizei,
+                            height: GLsizei,
+                            format: GLenum,
+                            ty: GLenum,
+                            offset: usize) {
+        // With a buffer bound to `PIXEL_UNPACK_BUFFER`, the pointer is an offset into it.
+        unsafe {
+            gleam_gl::TexSubImage2D(target,
+                                    level,
+                                    xoffset,
+                                    yoffset,
+                                    width,
+                                    height,
+                                    format,
+                                    ty,
+                                    offset as *const GLvoid);
+        }
+    }
+
+    fn uniform_1f(&self, location: GLint, v0: GLfloat) {
+        gleam_gl::uniform_1f(location, v0)
+    }
+
+    fn uniform_1i(&self, location: GLint, v0: GLint) {
+        gleam_gl::uniform_1i(location, v0)
+    }
+
+    fn uniform_4f(&self, location: GLint, x: GLfloat, y: GLfloat, z: GLfloat, w: GLfloat) {
+        gleam_gl::uniform_4f(location, x, y, z, w)
+    }
+
+    fn uniform_matrix_4fv(&self, location: GLint, transpose: bool, value: &[f32]) {
+        gleam_gl::uniform_matrix_4fv(location, transpose, value)
+    }
+
+    fn use_program(&self, program: GLuint) {
+        gleam_gl::use_program(program)
+    }
+
+    fn vertex_attrib_pointer_f32(&self,
+                                 index: GLuint,
+                                 size: GLint,
+                                 normalized: bool,
+                                 stride: GLsizei,
+                                 offset: GLuint) {
+        gleam_gl::vertex_attrib_pointer_f32(index, size, normalized, stride, offset)
+    }
+
+    fn viewport(&self, x: GLint, y: GLint, width: GLsizei, height: GLsizei) {
+        gleam_gl::viewport(x, y, width, height)
+    }
+}
+
+pub fn active_texture(gl: &GlApi, texture: GLenum) {
+    record(|_| GlCall::ActiveTexture(texture));
+    gl.active_texture(texture)
+}
+
+pub fn attach_shader(gl: &GlApi, program: GLuint, shader: GLuint) {
+    record(|_| GlCall::AttachShader(program, shader));
+    gl.attach_shader(program, shader)
+}
+
+pub fn bind_buffer(gl: &GlApi, target: GLenum, buffer: GLuint) {
+    record(|_| GlCall::BindBuffer(target, buffer));
+    gl.bind_buffer(target, buffer)
+}
+
+pub fn bind_framebuffer(gl: &GlApi, target: GLenum, framebuffer: GLuint) {
+    record(|_| GlCall::BindFramebuffer(target, framebuffer));
+    gl.bind_framebuffer(target, framebuffer)
+}
+
+pub fn bind_renderbuffer(gl: &GlApi, target: GLenum, renderbuffer: GLuint) {
+    record(|_| GlCall::BindRenderbuffer(target, renderbuffer));
+    gl.bind_renderbuffer(target, renderbuffer)
+}
+
+pub fn bind_texture(gl: &GlApi, target: GLenum, texture: GLuint) {
+    record(|_| GlCall::BindTexture(target, texture));
+    gl.bind_texture(target, texture)
+}
+
+pub fn blend_func(gl: &GlApi, sfactor: GLenum, dfactor: GLenum) {
+    record(|_| GlCall::BlendFunc(sfactor, dfactor));
+    gl.blend_func(sfactor, dfactor)
+}
+
+pub fn blend_func_separate(gl: &GlApi,
+                           src_rgb: GLenum,
+                           dst_rgb: GLenum,
+                           src_alpha: GLenum,
+                           dst_alpha: GLenum) {
+    record(|_| GlCall::BlendFuncSeparate(src_rgb, dst_rgb, src_alpha, dst_alpha));
+    gl.blend_func_separate(src_rgb, dst_rgb, src_alpha, dst_alpha)
+}
+
+pub fn buffer_data<T>(gl: &GlApi, target: GLenum, data: &[T], usage: GLenum) {
+    let bytes = unsafe {
+        slice::from_raw_parts(data.as_ptr() as *const u8, data.len() * mem::size_of::<T>())
+    };
+    record(|verbose| GlCall::BufferData(target, GlData::new(bytes, verbose), usage));
+    gl.buffer_data(target, bytes, usage)
+}
+
+/// Allocates the storage of the bound buffer without initializing it, which also orphans the
+/// previous storage if the GPU is still reading from it.
+pub fn buffer_data_uninitialized(gl: &GlApi, target: GLenum, size: usize, usage: GLenum) {
+    record(|_| GlCall::BufferDataUninitialized(target, size, usage));
+    gl.buffer_data_uninitialized(target, size, usage)
+}
+
+pub fn buffer_sub_data(gl: &GlApi, target: GLenum, offset: usize, data: &[u8]) {
+    record(|verbose| GlCall::BufferSubData(target, offset, GlData::new(data, verbose)));
+    gl.buffer_sub_data(target, offset, data)
+}
+
+pub fn check_frame_buffer_status(gl: &GlApi, target: GLenum) -> GLenum {
+    let status = gl.check_frame_buffer_status(target);
+    record(|_| GlCall::CheckFramebufferStatus(target, status));
+    status
+}
+
+pub fn clear(gl: &GlApi, buffer_mask: GLbitfield) {
+    record(|_| GlCall::Clear(buffer_mask));
+    gl.clear(buffer_mask)
+}
+
+pub fn clear_color(gl: &GlApi, r: f32, g: f32, b: f32, a: f32) {
+    record(|_| GlCall::ClearColor(r, g, b, a));
+    gl.clear_color(r, g, b, a)
+}
+
+/// Returns ALREADY_SIGNALED or CONDITION_SATISFIED if `sync` was signaled within `timeout`
+/// nanoseconds, TIMEOUT_EXPIRED if not, or WAIT_FAILED. Commands issued before the fence are
+/// flushed, so that it gets signaled at all.
+pub fn client_wait_sync(gl: &GlApi, sync: GlSync, timeout: GLuint64) -> GLenum {
+    let status = gl.client_wait_sync(sync, timeout);
+    record(|_| GlCall::ClientWaitSync(sync, timeout, status));
+    status
+}
+
+pub fn compile_shader(gl: &GlApi, shader: GLuint) {
+    record(|_| GlCall::CompileShader(shader));
+    gl.compile_shader(shader)
+}
+
+pub fn create_program(gl: &GlApi) -> GLuint {
+    let program = gl.create_program();
+    record(|_| GlCall::CreateProgram(program));
+    program
+}
+
+pub fn create_shader(gl: &GlApi, shader_type: GLenum) -> GLuint {
+    let shader = gl.create_shader(shader_type);
+    record(|_| GlCall::CreateShader(shader_type, shader));
+    shader
+}
+
+pub fn delete_buffers(gl: &GlApi, buffers: &[GLuint]) {
+    record(|_| GlCall::DeleteBuffers(buffers.to_vec()));
+    gl.delete_buffers(buffers)
+}
+
+pub fn delete_framebuffers(gl: &GlApi, framebuffers: &[GLuint]) {
+    record(|_| GlCall::DeleteFramebuffers(framebuffers.to_vec()));
+    gl.delete_framebuffers(framebuffers)
+}
+
+pub fn delete_program(gl: &GlApi, program: GLuint) {
+    record(|_| GlCall::DeleteProgram(program));
+    gl.delete_program(program)
+}
+
+pub fn delete_renderbuffers(gl: &GlApi, renderbuffers: &[GLuint]) {
+    record(|_| GlCall::DeleteRenderbuffers(renderbuffers.to_vec()));
+    gl.delete_renderbuffers(renderbuffers)
+}
+
+pub fn delete_shader(gl: &GlApi, shader: GLuint) {
+    record(|_| GlCall::DeleteShader(shader));
+    gl.delete_shader(shader)
+}
+
+pub fn delete_sync(gl: &GlApi, sync: GlSync) {
+    record(|_| GlCall::DeleteSync(sync));
+    gl.delete_sync(sync)
+}
+
+pub fn delete_textures(gl: &GlApi, textures: &[GLuint]) {
+    record(|_| GlCall::DeleteTextures(textures.to_vec()));
+    gl.delete_textures(textures)
+}
+
+pub fn depth_func(gl: &GlApi, func: GLenum) {
+    record(|_| GlCall::DepthFunc(func));
+    gl.depth_func(func)
+}
+
+pub fn disable(gl: &GlApi, cap: GLenum) {
+    record(|_| GlCall::Disable(cap));
+    gl.disable(cap)
+}
+
+pub fn disable_vertex_attrib_array(gl: &GlApi, index: GLuint) {
+    record(|_| GlCall::DisableVertexAttribArray(index));
+    gl.disable_vertex_attrib_array(index)
+}
+
+pub fn draw_arrays(gl: &GlApi, mode: GLenum, first: GLint, count: GLsizei) {
+    record(|_| GlCall::DrawArrays(mode, first, count));
+    gl.draw_arrays(mode, first, count)
+}
+
+pub fn enable(gl: &GlApi, cap: GLenum) {
+    record(|_| GlCall::Enable(cap));
+    gl.enable(cap)
+}
+
+pub fn enable_vertex_attrib_array(gl: &GlApi, index: GLuint) {
+    record(|_| GlCall::EnableVertexAttribArray(index));
+    gl.enable_vertex_attrib_array(index)
+}
+
+/// Inserts a fence that gets signaled once the GPU has executed the commands issued before it.
+pub fn fence_sync(gl: &GlApi) -> GlSync {
+    let sync = gl.fence_sync();
+    record(|_| GlCall::FenceSync(sync));
+    sync
+}
+
+pub fn framebuffer_renderbuffer(gl: &GlApi,
+                                target: GLenum,
+                                attachment: GLenum,
+                                renderbuffertarget: GLenum,
+                                renderbuffer: GLuint) {
+    record(|_| {
+        GlCall::FramebufferRenderbuffer(target, attachment, renderbuffertarget, renderbuffer)
+    });
+    gl.framebuffer_renderbuffer(target, attachment, renderbuffertarget, renderbuffer)
+}
+
+pub fn framebuffer_texture_2d(gl: &GlApi,
+                              target: GLenum,
+                              attachment: GLenum,
+                              textarget: GLenum,
+                              texture: GLuint,
+                              level: GLint) {
+    record(|_| GlCall::FramebufferTexture2D(target, attachment, textarget, texture, level));
+    gl.framebuffer_texture_2d(target, attachment, textarget, texture, level)
+}
+
+pub fn gen_buffers(gl: &GlApi, n: GLsizei) -> Vec<GLuint> {
+    let buffers = gl.gen_buffers(n);
+    record(|_| GlCall::GenBuffers(buffers.clone()));
+    buffers
+}
+
+pub fn gen_framebuffers(gl: &GlApi, n: GLsizei) -> Vec<GLuint> {
+    let framebuffers = gl.gen_framebuffers(n);
+    record(|_| GlCall::GenFramebuffers(framebuffers.clone()));
+    framebuffers
+}
+
+pub fn gen_renderbuffers(gl: &GlApi, n: GLsizei) -> Vec<GLuint> {
+    let renderbuffers = gl.gen_renderbuffers(n);
+    record(|_| GlCall::GenRenderbuffers(renderbuffers.clone()));
+    renderbuffers
+}
+
+pub fn gen_textures(gl: &GlApi, n: GLsizei) -> Vec<GLuint> {
+    let textures = gl.gen_textures(n);
+    record(|_| GlCall::GenTextures(textures.clone()));
+    textures
+}
+
+pub fn generate_mipmap(gl: &GlApi, target: GLenum) {
+    record(|_| GlCall::GenerateMipmap(target));
+    gl.generate_mipmap(target)
+}
+
+pub fn get_attrib_location(gl: &GlApi, program: GLuint, name: &str) -> GLint {
+    let location = gl.get_attrib_location(program, name);
+    record(|_| GlCall::GetAttribLocation(program, name.to_owned(), location));
+    location
+}
+
+pub fn get_integer_v(gl: &GlApi, name: GLenum) -> GLint {
+    let value = gl.get_integer_v(name);
+    record(|_| GlCall::GetIntegerv(name, value));
+    value
+}
+
+/// Returns the four integers of a GL state, such as `VIEWPORT` or `SCISSOR_BOX`.
+pub fn get_integer_4v(gl: &GlApi, name: GLenum) -> [GLint; 4] {
+    let values = gl.get_integer_4v(name);
+    record(|_| GlCall::GetIntegerv4(name, values.to_vec()));
+    values
+}
+
+/// Returns the four floats of a GL state, such as `COLOR_CLEAR_VALUE`.
+pub fn get_float_4v(gl: &GlApi, name: GLenum) -> [GLfloat; 4] {
+    let values = gl.get_float_4v(name);
+    record(|_| GlCall::GetFloatv4(name, values.to_vec()));
+    values
+}
+
+pub fn get_program_info_log(gl: &GlApi, program: GLuint) -> String {
+    record(|_| GlCall::GetProgramInfoLog(program));
+    gl.get_program_info_log(program)
+}
+
+pub fn get_program_iv(gl: &GlApi, program: GLuint, pname: GLenum) -> GLint {
+    let value = gl.get_program_iv(program, pname);
+    record(|_| GlCall::GetProgramiv(program, pname, value));
+    value
+}
+
+pub fn get_shader_info_log(gl: &GlApi, shader: GLuint) -> String {
+    record(|_| GlCall::GetShaderInfoLog(shader));
+    gl.get_shader_info_log(shader)
+}
+
+pub fn get_shader_iv(gl: &GlApi, shader: GLuint, pname: GLenum) -> GLint {
+    let value = gl.get_shader_iv(shader, pname);
+    record(|_| GlCall::GetShaderiv(shader, pname, value));
+    value
+}
+
+pub fn get_string(gl: &GlApi, which: GLenum) -> String {
+    let string = gl.get_string(which);
+    record(|_| GlCall::GetString(which, string.clone()));
+    string
+}
+
+pub fn get_uniform_location(gl: &GlApi, program: GLuint, name: &str) -> GLint {
+    let location = gl.get_uniform_location(program, name);
+    record(|_| GlCall::GetUniformLocation(program, name.to_owned(), location));
+    location
+}
+
+pub fn is_enabled(gl: &GlApi, cap: GLenum) -> GLboolean {
+    let enabled = gl.is_enabled(cap);
+    record(|_| GlCall::IsEnabled(cap, enabled));
+    enabled
+}
+
+pub fn line_width(gl: &GlApi, width: GLfloat) {
+    record(|_| GlCall::LineWidth(width));
+    gl.line_width(width)
+}
+
+pub fn link_program(gl: &GlApi, program: GLuint) {
+    record(|_| GlCall::LinkProgram(program));
+    gl.link_program(program)
+}
+
+pub fn pixel_store_i(gl: &GlApi, name: GLenum, param: GLint) {
+    record(|_| GlCall::PixelStorei(name, param));
+    gl.pixel_store_i(name, param)
+}
+
+pub fn read_pixels(gl: &GlApi,
+                   x: GLint,
+                   y: GLint,
+                   width: GLsizei,
+                   height: GLsizei,
+                   format: GLenum,
+                   pixel_type: GLenum)
+                   -> Vec<u8> {
+    record(|_| GlCall::ReadPixels(x, y, width, height, format, pixel_type));
+    gl.read_pixels(x, y, width, height, format, pixel_type)
+}
+
+pub fn renderbuffer_storage(gl: &GlApi,
+                            target: GLenum,
+                            internalformat: GLenum,
+                            width: GLsizei,
+                            height: GLsizei) {
+    record(|_| GlCall::RenderbufferStorage(target, internalformat, width, height));
+    gl.renderbuffer_storage(target, internalformat, width, height)
+}
+
+pub fn scissor(gl: &GlApi, x: GLint, y: GLint, width: GLsizei, height: GLsizei) {
+    record(|_| GlCall::Scissor(x, y, width, height));
+    gl.scissor(x, y, width, height)
+}
+
+pub fn shader_source(gl: &GlApi, shader: GLuint, strings: &[&[u8]]) {
+    record(|_| {
+        GlCall::ShaderSource(shader, strings.iter().map(|string| string.to_vec()).collect())
+    });
+    gl.shader_source(shader, strings)
+}
+
+pub fn tex_image_2d(gl: &GlApi,
+                    target: GLenum,
+                    level: GLint,
+                    internal_format: GLint,
+                    width: GLsizei,
+                    height: GLsizei,
+                    border: GLint,
+                    format: GLenum,
+                    ty: GLenum,
+                    opt_data: Option<&[u8]>) {
+    record(|verbose| {
+        GlCall::TexImage2D {
+            target: target,
+            level: level,
+            internal_format: internal_format,
+            width: width,
+            height: height,
+            border: border,
+            format: format,
+            ty: ty,
+            data: opt_data.map(|data| GlData::new(data, verbose)),
+        }
+    });
+    gl.tex_image_2d(target, level, internal_format, width, height, border, format, ty, opt_data)
+}
+
+pub fn tex_parameter_i(gl: &GlApi, target: GLenum, pname: GLenum, param: GLint) {
+    record(|_| GlCall::TexParameteri(target, pname, param));
+    gl.tex_parameter_i(target, pname, param)
+}
+
+pub fn tex_sub_image_2d(gl: &GlApi,
+                        target: GLenum,
+                        level: GLint,
+                        xoffset: GLint,
+                        yoffset: GLint,
+                        width: GLsizei,
+                        height: GLsizei,
+                        format: GLenum,
+                        ty: GLenum,
+                        data: &[u8]) {
+    record(|verbose| {
+        GlCall::TexSubImage2D {
+            target: target,
+            level: level,
+            xoffset: xoffset,
+            yoffset: yoffset,
+            width: width,
+            height: height,
+            format: format,
+            ty: ty,
+            data: GlData::new(data, verbose),
+        }
+    });
+    gl.tex_sub_image_2d(target, level, xoffset, yoffset, width, height, format, ty, data)
+}
+
+/// Uploads to a texture from the buffer bound to `PIXEL_UNPACK_BUFFER`, starting `offset` bytes
+/// into it.
+pub fn tex_sub_image_2d_pbo(gl: &GlApi,
+                            target: GLenum,
+                            level: GLint,
+                            xoffset: GLint,
+                            yoffset: GLint,
+                            width: GLsizei,
+                            height: GLsizei,
+                            format: GLenum,
+                            ty: GLenum,
+                            offset: usize) {
+    record(|_| {
+        GlCall::TexSubImage2DPbo {
+            target: target,
+            level: level,
+            xoffset: xoffset,
+            yoffset: yoffset,
+            width: width,
+            height: height,
+            format: format,
+            ty: ty,
+            offset: offset,
+        }
+    });
+    gl.tex_sub_image_2d_pbo(target, level, xoffset, yoffset, width, height, format, ty, offset)
+}
+
+pub fn uniform_1f(gl: &GlApi, location: GLint, v0: GLfloat) {
+    record(|_| GlCall::Uniform1f(location, v0));
+    gl.uniform_1f(location, v0)
+}
+
+pub fn uniform_1i(gl: &GlApi, location: GLint, v0: GLint) {
+    record(|_| GlCall::Uniform1i(location, v0));
+    gl.uniform_1i(location, v0)
+}
+
+pub fn uniform_4f(gl: &GlApi, location: GLint, x: GLfloat, y: GLfloat, z: GLfloat, w: GLfloat) {
+    record(|_| GlCall::Uniform4f(location, x, y, z, w));
+    gl.uniform_4f(location, x, y, z, w)
+}
+
+pub fn uniform_matrix_4fv(gl: &GlApi, location: GLint, transpose: bool, value: &[f32]) {
+    record(|_| GlCall::UniformMatrix4fv(location, transpose, value.to_vec()));
+    gl.uniform_matrix_4fv(location, transpose, value)
+}
+
+pub fn use_program(gl: &GlApi, program: GLuint) {
+    record(|_| GlCall::UseProgram(program));
+    gl.use_program(program)
+}
+
+pub fn vertex_attrib_pointer_f32(gl: &GlApi,
+                                 index: GLuint,
+                                 size: GLint,
+                                 normalized: bool,
+                                 stride: GLsizei,
+                                 offset: GLuint) {
+    record(|_| GlCall::VertexAttribPointerF32(index, size, normalized, stride, offset));
+    gl.vertex_attrib_pointer_f32(index, size, normalized, stride, offset)
+}
+
+pub fn viewport(gl: &GlApi, x: GLint, y: GLint, width: GLsizei, height: GLsizei) {
+    record(|_| GlCall::Viewport(x, y, width, height));
+    gl.viewport(x, y, width, height)
+}
+
+/// A `GlApi` that doesn't talk to a GL context, but logs the calls made through it, for running the
+/// compositor headless, for instance in unit tests. Generated names and locations count up from 1,
+/// status queries report success unless `fail_shaders_containing` or `fail_links` say otherwise, in
+/// which case the info logs say what failed, names passed to `remove_location` have no location,
+/// and `get_integer_v` and `get_string` return the values set with `set_integer` and `set_string`,
+/// or 0 and an empty string. `is_enabled` reports the capabilities set with `set_enabled`, whatever
+/// was enabled or disabled since. `get_integer_4v` reports the last viewport set for `VIEWPORT`,
+/// and zeroes otherwise. Fences stay unsignaled until `signal_fences` is called. `read_pixels`
+/// returns zeroes, or the pixels set with `set_framebuffer_pixels`.
+pub struct RecordingGl {
+    calls: RefCell<Vec<GlCall>>,
+    next_name: Cell<GLuint>,
+    next_sync: Cell<u64>,
+    signaled_syncs: Cell<u64>,
+    next_location: Cell<GLint>,
+    integers: RefCell<HashMap<GLenum, GLint>>,
+    enabled_capabilities: RefCell<Vec<GLenum>>,
+    viewport: Cell<[GLint; 4]>,
+    framebuffer_pixels: RefCell<Option<Vec<u8>>>,
+    strings: RefCell<HashMap<GLenum, String>>,
+    shader_sources: RefCell<HashMap<GLuint, String>>,
+    failing_shader_text: RefCell<Option<String>>,
+    failing_links: Cell<bool>,
+    removed_locations: RefCell<Vec<String>>,
+}
+
+impl RecordingGl {
+    pub fn new() -> RecordingGl {
+        RecordingGl {
+            calls: RefCell::new(vec!()),
+            next_name: Cell::new(1),
+            next_sync: Cell::new(1),
+            signaled_syncs: Cell::new(0),
+            next_location: Cell::new(1),
+            integers: RefCell::new(HashMap::new()),
+            enabled_capabilities: RefCell::new(vec!()),
+            viewport: Cell::new([0; 4]),
+            framebuffer_pixels: RefCell::new(None),
+            strings: RefCell::new(HashMap::new()),
+            shader_sources: RefCell::new(HashMap::new()),
+            failing_shader_text: RefCell::new(None),
+            failing_links: Cell::new(false),
+            removed_locations: RefCell::new(vec!()),
+        }
+    }
+
+    /// Makes the shaders whose source contains `text` fail to compile, as a driver that rejects
+    /// them would.
+    pub fn fail_shaders_containing(&self, text: &str) {
+        *self.failing_shader_text.borrow_mut() = Some(text.to_owned());
+    }
+
+    /// Makes every program fail to link.
+    pub fn fail_links(&self) {
+        self.failing_links.set(true);
+    }
+
+    /// Makes the attributes and uniforms called `name` report no location (-1), as when a driver
+    /// optimizes them away.
+    pub fn remove_location(&self, name: &str) {
+        self.removed_locations.borrow_mut().push(name.to_owned());
+    }
+
+    /// Signals the fences inserted so far, as the GPU does once it has caught up with them.
+    pub fn signal_fences(&self) {
+        self.signaled_syncs.set(self.next_sync.get() - 1);
+    }
+
+    /// Sets the pixels returned by `read_pixels`, as rows ordered from bottom to top like GL
+    /// returns them.
+    pub fn set_framebuffer_pixels(&self, pixels: Vec<u8>) {
+        *self.framebuffer_pixels.borrow_mut() = Some(pixels);
+    }
+
+    /// Sets the value returned by `get_integer_v` for `name`, such as `MAX_TEXTURE_SIZE`.
+    pub fn set_integer(&self, name: GLenum, value: GLint) {
+        self.integers.borrow_mut().insert(name, value);
+    }
+
+    /// Sets whether `is_enabled` reports `cap`, such as `BLEND`, as enabled.
+    pub fn set_enabled(&self, cap: GLenum, enabled: bool) {
+        let mut enabled_capabilities = self.enabled_capabilities.borrow_mut();
+        enabled_capabilities.retain(|enabled_cap| *enabled_cap != cap);
+        if enabled {
+            enabled_capabilities.push(cap);
+        }
+    }
+
+    /// Sets the string returned by `get_string` for `which`, such as `VERSION`.
+    pub fn set_string(&self, which: GLenum, string: &str) {
+        self.strings.borrow_mut().insert(which, string.to_owned());
+    }
+
+    /// Returns the calls logged so far.
+    pub fn calls(&self) -> Vec<GlCall> {
+        self.calls.borrow().clone()
+    }
+
+    /// Returns the calls logged so far and clears the log.
+    pub fn take_calls(&self) -> Vec<GlCall> {
+        mem::replace(&mut *self.calls.borrow_mut(), vec!())
+    }
+
+    fn log(&self, call: GlCall) {
+        self.calls.borrow_mut().push(call)
+    }
+
+    fn shader_fails(&self, shader: GLuint) -> bool {
+        match *self.failing_shader_text.borrow() {
+            Some(ref text) => {
+                self.shader_sources.borrow().get(&shader).map_or(false, |source| {
+                    source.contains(&**text)
+                })
+            }
+            None => false,
+        }
+    }
+
+    fn gen_names(&self, n: GLsizei) -> Vec<GLuint> {
+        let first = self.next_name.get();
+        self.next_name.set(first + n as GLuint);
+        (first..first + n as GLuint).collect()
+    }
+
+    fn gen_location(&self, name: &str) -> GLint {
+        if self.removed_locations.borrow().iter().any(|removed| removed == name) {
+            return -1;
+        }
+        let location = self.next_location.get();
+        self.next_location.set(location + 1);
+        location
+    }
+}
+
+impl GlApi for RecordingGl {
+    fn active_texture(&self, texture: GLenum) {
+        self.log(GlCall::ActiveTexture(texture))
+    }
+
+    fn attach_shader(&self, program: GLuint, shader: GLuint) {
+        self.log(GlCall::AttachShader(program, shader))
+    }
+
+    fn bind_buffer(&self, target: GLenum, buffer: GLuint) {
+        self.log(GlCall::BindBuffer(target, buffer))
+    }
+
+    fn bind_framebuffer(&self, target: GLenum, framebuffer: GLuint) {
+        self.log(GlCall::BindFramebuffer(target, framebuffer))
+    }
+
+    fn bind_renderbuffer(&self, target: GLenum, renderbuffer: GLuint) {
+        self.log(GlCall::BindRenderbuffer(target, renderbuffer))
+    }
+
+    fn bind_texture(&self, target: GLenum, texture: GLuint) {
+        self.log(GlCall::BindTexture(target, texture))
+    }
+
+    fn blend_func(&self, sfactor: GLenum, dfactor: GLenum) {
+        self.log(GlCall::BlendFunc(sfactor, dfactor))
+    }
+
+    fn blend_func_separate(&self,
+                           src_rgb: GLenum,
+                           dst_rgb: GLenum,
+                           src_alpha: GLenum,
+                           dst_alpha: GLenum) {
+        self.log(GlCall::BlendFuncSeparate(src_rgb, dst_rgb, src_alpha, dst_alpha))
+    }
+
+    fn buffer_data(&self, target: GLenum, data: &[u8], usage: GLenum) {
+        self.log(GlCall::BufferData(target, GlData::new(data, false), usage))
+    }
+
+    fn buffer_data_uninitialized(&self, target: GLenum, size: usize, usage: GLenum) {
+        self.log(GlCall::BufferDataUninitialized(target, size, usage))
+    }
+
+    fn buffer_sub_data(&self, target: GLenum, offset: usize, data: &[u8]) {
+        self.log(GlCall::BufferSubData(target, offset, GlData::new(data, false)))
+    }
+
+    fn check_frame_buffer_status(&self, target: GLenum) -> GLenum {
+        self.log(GlCall::CheckFramebufferStatus(target, FRAMEBUFFER_COMPLETE));
+        FRAMEBUFFER_COMPLETE
+    }
+
+    fn clear(&self, buffer_mask: GLbitfield) {
+        self.log(GlCall::Clear(buffer_mask))
+    }
+
+    fn clear_color(&self, r: f32, g: f32, b: f32, a: f32) {
+        self.log(GlCall::ClearColor(r, g, b, a))
+    }
+
+    fn client_wait_sync(&self, sync: GlSync, timeout: GLuint64) -> GLenum {
+        let status = if sync.0 <= self.signaled_syncs.get() {
+            ALREADY_SIGNALED
+        } else {
+            TIMEOUT_EXPIRED
+        };
+        self.log(GlCall::ClientWaitSync(sync, timeout, status));
+        status
+    }
+
+    fn compile_shader(&self, shader: GLuint) {
+        self.log(GlCall::CompileShader(shader))
+    }
+
+    fn create_program(&self) -> GLuint {
+        let program = self.gen_names(1)[0];
+        self.log(GlCall::CreateProgram(program));
+        program
+    }
+
+    fn create_shader(&self, shader_type: GLenum) -> GLuint {
+        let shader = self.gen_names(1)[0];
+        self.log(GlCall::CreateShader(shader_type, shader));
+        shader
+    }
+
+    fn delete_buffers(&self, buffers: &[GLuint]) {
+        self.log(GlCall::DeleteBuffers(buffers.to_vec()))
+    }
+
+    fn delete_framebuffers(&self, framebuffers: &[GLuint]) {
+        self.log(GlCall::DeleteFramebuffers(framebuffers.to_vec()))
+    }
+
+    fn delete_program(&self, program: GLuint) {
+        self.log(GlCall::DeleteProgram(program))
+    }
+
+    fn delete_renderbuffers(&self, renderbuffers: &[GLuint]) {
+        self.log(GlCall::DeleteRenderbuffers(renderbuffers.to_vec()))
+    }
+
+    fn delete_shader(&self, shader: GLuint) {
+        self.log(GlCall::DeleteShader(shader))
+    }
+
+    fn delete_sync(&self, sync: GlSync) {
+        self.log(GlCall::DeleteSync(sync))
+    }
+
+    fn delete_textures(&self, textures: &[GLuint]) {
+        self.log(GlCall::DeleteTextures(textures.to_vec()))
+    }
+
+    fn depth_func(&self, func: GLenum) {
+        self.log(GlCall::DepthFunc(func))
+    }
+
+    fn disable(&self, cap: GLenum) {
+        self.log(GlCall::Disable(cap))
+    }
+
+    fn disable_vertex_attrib_array(&self, index: GLuint) {
+        self.log(GlCall::DisableVertexAttribArray(index))
+    }
+
+    fn draw_arrays(&self, mode: GLenum, first: GLint, count: GLsizei) {
+        self.log(GlCall::DrawArrays(mode, first, count))
+    }
+
+    fn enable(&self, cap: GLenum) {
+        self.log(GlCall::Enable(cap))
+    }
+
+    fn enable_vertex_attrib_array(&self, index: GLuint) {
+        self.log(GlCall::EnableVertexAttribArray(index))
+    }
+
+    fn fence_sync(&self) -> GlSync {
+        let sync = GlSync(self.next_sync.get());
+        self.next_sync.set(sync.0 + 1);
+        self.log(GlCall::FenceSync(sync));
+        sync
+    }
+
+    fn framebuffer_renderbuffer(&self,
+                                target: GLenum,
+                                attachment: GLenum,
+                                renderbuffertarget: GLenum,
+                                renderbuffer: GLuint) {
+        self.log(GlCall::FramebufferRenderbuffer(target,
+                                                 attachment,
+                                                 renderbuffertarget,
+                                                 renderbuffer))
+    }
+
+    fn framebuffer_texture_2d(&self,
+                              target: GLenum,
+                              attachment: GLenum,
+                              textarget: GLenum,
+                              texture: GLuint,
+                              level: GLint) {
+        self.log(GlCall::FramebufferTexture2D(target, attachment, textarget, texture, level))
+    }
+
+    fn gen_buffers(&self, n: GLsizei) -> Vec<GLuint> {
+        let buffers = self.gen_names(n);
+        self.log(GlCall::GenBuffers(buffers.clone()));
+        buffers
+    }
+
+    fn gen_framebuffers(&self, n: GLsizei) -> Vec<GLuint> {
+        let framebuffers = self.gen_names(n);
+        self.log(GlCall::GenFramebuffers(framebuffers.clone()));
+        framebuffers
+    }
+
+    fn gen_renderbuffers(&self, n: GLsizei) -> Vec<GLuint> {
+        let renderbuffers = self.gen_names(n);
+        self.log(GlCall::GenRenderbuffers(renderbuffers.clone()));
+        renderbuffers
+    }
+
+    fn gen_textures(&self, n: GLsizei) -> Vec<GLuint> {
+        let textures = self.gen_names(n);
+        self.log(GlCall::GenTextures(textures.clone()));
+        textures
+    }
+
+    fn generate_mipmap(&self, target: GLenum) {
+        self.log(GlCall::GenerateMipmap(target))
+    }
+
+    fn get_attrib_location(&self, program: GLuint, name: &str) -> GLint {
+        let location = self.gen_location(name);
+        self.log(GlCall::GetAttribLocation(program, name.to_owned(), location));
+        location
+    }
+
+    fn get_integer_v(&self, name: GLenum) -> GLint {
+        let value = self.integers.borrow().get(&name).cloned().unwrap_or(0);
+        self.log(GlCall::GetIntegerv(name, value));
+        value
+    }
+
+    fn get_integer_4v(&self, name: GLenum) -> [GLint; 4] {
+        let value = if name == VIEWPORT { self.viewport.get() } else { [0; 4] };
+        self.log(GlCall::GetIntegerv4(name, value.to_vec()));
+        value
+    }
+
+    fn get_float_4v(&self, name: GLenum) -> [GLfloat; 4] {
+        self.log(GlCall::GetFloatv4(name, vec![0.0; 4]));
+        [0.0; 4]
+    }
+
+    fn get_program_info_log(&self, program: GLuint) -> String {
+        self.log(GlCall::GetProgramInfoLog(program));
+        if self.failing_links.get() { "link failed".to_owned() } else { String::new() }
+    }
+
+    fn get_program_iv(&self, program: GLuint, pname: GLenum) -> GLint {
+        let value = if pname == LINK_STATUS && self.failing_links.get() { 0 } else { 1 };
+        self.log(GlCall::GetProgramiv(program, pname, value));
+        value
+    }
+
+    fn get_shader_info_log(&self, shader: GLuint) -> String {
+        self.log(GlCall::GetShaderInfoLog(shader));
+        if self.shader_fails(shader) { "compile failed".to_owned() } else { String::new() }
+    }
+
+    fn get_shader_iv(&self, shader: GLuint, pname: GLenum) -> GLint {
+        let value = if self.shader_fails(shader) { 0 } else { 1 };
+        self.log(GlCall::GetShaderiv(shader, pname, value));
+        value
+    }
+
+    fn get_string(&self, which: GLenum) -> String {
+        let string = self.strings.borrow().get(&which).cloned().unwrap_or(String::new());
+        self.log(GlCall::GetString(which, string.clone()));
+        string
+    }
+
+    fn get_uniform_location(&self, program: GLuint, name: &str) -> GLint {
+        let location = self.gen_location(name);
+        self.log(GlCall::GetUniformLocation(program, name.to_owned(), location));
+        location
+    }
+
+    fn is_enabled(&self, cap: GLenum) -> GLboolean {
+        let enabled = if self.enabled_capabilities.borrow().contains(&cap) { TRUE } else { FALSE };
+        self.log(GlCall::IsEnabled(cap, enabled));
+        enabled
+    }
+
+    fn line_width(&self, width: GLfloat) {
+        self.log(GlCall::LineWidth(width))
+    }
+
+    fn link_program(&self, program: GLuint) {
+        self.log(GlCall::LinkProgram(program))
+    }
+
+    fn pixel_store_i(&self, name: GLenum, param: GLint) {
+        self.log(GlCall::PixelStorei(name, param))
+    }
+
+    fn read_pixels(&self,
+                   x: GLint,
+                   y: GLint,
+                   width: GLsizei,
+                   height: GLsizei,
+                   format: GLenum,
+                   pixel_type: GLenum)
+                   -> Vec<u8> {
+        self.log(GlCall::ReadPixels(x, y, width, height, format, pixel_type));
+        match *self.framebuffer_pixels.borrow() {
+            Some(ref pixels) => pixels.clone(),
+            None => vec![0; width as usize * height as usize * 4],
+        }
+    }
+
+    fn renderbuffer_storage(&self,
+                            target: GLenum,
+                            internalformat: GLenum,
+                            width: GLsizei,
+                            height: GLsizei) {
+        self.log(GlCall::RenderbufferStorage(target, internalformat, width, height))
+    }
+
+    fn scissor(&self, x: GLint, y: GLint, width: GLsizei, height: GLsizei) {
+        self.log(GlCall::Scissor(x, y, width, height))
+    }
+
+    fn shader_source(&self, shader: GLuint, strings: &[&[u8]]) {
+        let source = strings.iter().map(|string| {
+            String::from_utf8_lossy(string).into_owned()
+        }).collect();
+        self.shader_sources.borrow_mut().insert(shader, source);
+        let strings = strings.iter().map(|string| string.to_vec()).collect();
+        self.log(GlCall::ShaderSource(shader, strings))
+    }
+
+    fn tex_image_2d(&self,
+                    target: GLenum,
+                    level: GLint,
+                    internal_format: GLint,
+                    width: GLsizei,
+                    height: GLsizei,
+                    border: GLint,
+                    format: GLenum,
+                    ty: GLenum,
+                    opt_data: Option<&[u8]>) {
+        self.log(GlCall::TexImage2D {
+            target: target,
+            level: level,
+            internal_format: internal_format,
+            width: width,
+            height: height,
+            border: border,
+            format: format,
+            ty: ty,
+            data: opt_data.map(|data| GlData::new(data, false)),
+        })
+    }
+
+    fn tex_parameter_i(&self, target: GLenum, pname: GLenum, param: GLint) {
+        self.log(GlCall::TexParameteri(target, pname, param))
+    }
+
+    fn tex_sub_image_2d(&self,
+                        target: GLenum,
+                        level: GLint,
+                        xoffset: GLint,
+                        yoffset: GLint,
+                        width: GLsizei,
+                        height: GLsizei,
+                        format: GLenum,
+                        ty: GLenum,
+                        data: &[u8]) {
+        self.log(GlCall::TexSubImage2D {
+            target: target,
+            level: level,
+            xoffset: xoffset,
+            yoffset: yoffset,
+            width: width,
+            height: height,
+            format: format,
+            ty: ty,
+            data: GlData::new(data, false),
+        })
+    }
+
+    fn tex_sub_image_2d_pbo(&self,
+                            target: GLenum,
+                            level: GLint,
+                            xoffset: GLint,
+                            yoffset: GLint,
+                            width: GLsizei,
+                            height: GLsizei,
+                            format: GLenum,
+                            ty: GLenum,
+                            offset: usize) {
+        self.log(GlCall::TexSubImage2DPbo {
+            target: target,
+            level: level,
+            xoffset: xoffset,
+            yoffset: yoffset,
+            width: width,
+            height: height,
+            format: format,
+            ty: ty,
+            offset: offset,
+        })
+    }
+
+    fn uniform_1f(&self, location: GLint, v0: GLfloat) {
+        self.log(GlCall::Uniform1f(location, v0))
+    }
+
+    fn uniform_1i(&self, location: GLint, v0: GLint) {
+        self.log(GlCall::Uniform1i(location, v0))
+    }
+
+    fn uniform_4f(&self, location: GLint, x: GLfloat, y: GLfloat, z: GLfloat, w: GLfloat) {
+        self.log(GlCall::Uniform4f(location, x, y, z, w))
+    }
+
+    fn uniform_matrix_4fv(&self, location: GLint, transpose: bool, value: &[f32]) {
+        self.log(GlCall::UniformMatrix4fv(location, transpose, value.to_vec()))
+    }
+
+    fn use_program(&self, program: GLuint) {
+        self.log(GlCall::UseProgram(program))
+    }
+
+    fn vertex_attrib_pointer_f32(&self,
+                                 index: GLuint,
+                                 size: GLint,
+                                 normalized: bool,
+                                 stride: GLsizei,
+                                 offset: GLuint) {
+        self.log(GlCall::VertexAttribPointerF32(index, size, normalized, stride, offset))
+    }
+
+    fn viewport(&self, x: GLint, y: GLint, width: GLsizei, height: GLsizei) {
+        self.viewport.set([x, y, width, height]);
+        self.log(GlCall::Viewport(x, y, width, height))
+    }
+}
+
+/// Maps the object names and locations of a trace to the ones returned during replay. Each kind
+/// of object has its own namespace in GL, so the same name may stand for a texture and a buffer
+/// at once; programs and shaders share one.
+struct ReplayNames {
+    textures: HashMap<GLuint, GLuint>,
+    buffers: HashMap<GLuint, GLuint>,
+    framebuffers: HashMap<GLuint, GLuint>,
+    renderbuffers: HashMap<GLuint, GLuint>,
+    programs: HashMap<GLuint, GLuint>,
+    syncs: HashMap<GlSync, GlSync>,
+    uniform_locations: HashMap<(GLuint, GLint), GLint>,
+    attrib_locations: HashMap<(GLuint, GLuint), GLuint>,
+    current_program: GLuint,
+}
+
+impl ReplayNames {
+    fn uniform_location(&self, location: GLint) -> GLint {
+        *self.uniform_locations.get(&(self.current_program, location)).unwrap_or(&location)
+    }
+
+    fn attrib_location(&self, index: GLuint) -> GLuint {
+        *self.attrib_locations.get(&(self.current_program, index)).unwrap_or(&index)
+    }
+}
+
+fn replayed_name(names: &HashMap<GLuint, GLuint>, name: GLuint) -> GLuint {
+    *names.get(&name).unwrap_or(&name)
+}
+
+fn replayed_names(names: &HashMap<GLuint, GLuint>, recorded: &[GLuint]) -> Vec<GLuint> {
+    recorded.iter().map(|name| replayed_name(names, *name)).collect()
+}
+
+fn map_names(names: &mut HashMap<GLuint, GLuint>, recorded: &[GLuint], replayed: &[GLuint]) {
+    for (recorded, replayed) in recorded.iter().zip(replayed.iter()) {
+        names.insert(*recorded, *replayed);
+    }
+}
+
+/// Issues the calls of a trace again through the given API, usually `GleamGl` on a current GL
+/// context. Queries are issued but their results are ignored. Recording should be off while
+/// replaying.
+pub fn replay_gl_trace(trace: &GlTrace, gl: &GlApi) {
+    let mut names = ReplayNames {
+        textures: HashMap::new(),
+        buffers: HashMap::new(),
+        framebuffers: HashMap::new(),
+        renderbuffers: HashMap::new(),
+        programs: HashMap::new(),
+        syncs: HashMap::new(),
+        uniform_locations: HashMap::new(),
+        attrib_locations: HashMap::new(),
+        current_program: 0,
+    };
+
+    for call in &trace.calls {
+        match *call {
+            GlCall::ActiveTexture(texture) => gl.active_texture(texture),
+            GlCall::AttachShader(program, shader) => {
+                gl.attach_shader(replayed_name(&names.programs, program),
+                                 replayed_name(&names.programs, shader))
+            }
+            GlCall::BindBuffer(target, buffer) => {
+                gl.bind_buffer(target, replayed_name(&names.buffers, buffer))
+            }
+            GlCall::BindFramebuffer(target, framebuffer) => {
+                gl.bind_framebuffer(target, replayed_name(&names.framebuffers, framebuffer))
+            }
+            GlCall::BindRenderbuffer(target, renderbuffer) => {
+                gl.bind_renderbuffer(target, replayed_name(&names.renderbuffers, renderbuffer))
+            }
+            GlCall::BindTexture(target, texture) => {
+                gl.bind_texture(target, replayed_name(&names.textures, texture))
+            }
+            GlCall::BlendFunc(sfactor, dfactor) => gl.blend_func(sfactor, dfactor),
+            GlCall::BlendFuncSeparate(src_rgb, dst_rgb, src_alpha, dst_alpha) => {
+                gl.blend_func_separate(src_rgb, dst_rgb, src_alpha, dst_alpha)
+            }
+            GlCall::BufferData(target, ref data, usage) => {
+                gl.buffer_data(target, &data.bytes_or_zeroes(), usage)
+            }
+            GlCall::BufferDataUninitialized(target, size, usage) => {
+                gl.buffer_data_uninitialized(target, size, usage)
+            }
+            GlCall::BufferSubData(target, offset, ref data) => {
+                gl.buffer_sub_data(target, offset, &data.bytes_or_zeroes())
+            }
+            GlCall::CheckFramebufferStatus(target, _) => {
+                gl.check_frame_buffer_status(target);
+            }
+            GlCall::Clear(buffer_mask) => gl.clear(buffer_mask),
+            GlCall::ClearColor(r, g, b, a) => gl.clear_color(r, g, b, a),
+            GlCall::ClientWaitSync(sync, timeout, _) => {
+                if let Some(replayed) = names.syncs.get(&sync) {
+                    gl.client_wait_sync(*replayed, timeout);
+                }
+            }
+            GlCall::CompileShader(shader) => {
+                gl.compile_shader(replayed_name(&names.programs, shader))
+            }
+            GlCall::CreateProgram(program) => {
+                let replayed = gl.create_program();
+                map_names(&mut names.programs, &[program], &[replayed]);
+            }
+            GlCall::CreateShader(shader_type, shader) => {
+                let replayed = gl.create_shader(shader_type);
+                map_names(&mut names.programs, &[shader], &[replayed]);
+            }
+            GlCall::DeleteBuffers(ref buffers) => {
+                gl.delete_buffers(&replayed_names(&names.buffers, buffers))
+            }
+            GlCall::DeleteFramebuffers(ref framebuffers) => {
+                gl.delete_framebuffers(&replayed_names(&names.framebuffers, framebuffers))
+            }
+            GlCall::DeleteProgram(program) => {
+                gl.delete_program(replayed_name(&names.programs, program))
+            }
+            GlCall::DeleteRenderbuffers(ref renderbuffers) => {
+                gl.delete_renderbuffers(&replayed_names(&names.renderbuffers, renderbuffers))
+            }
+            GlCall::DeleteShader(shader) => {
+                gl.delete_shader(replayed_name(&names.programs, shader))
+            }
+            GlCall::DeleteSync(sync) => {
+                if let Some(replayed) = names.syncs.remove(&sync) {
+                    gl.delete_sync(replayed)
+                }
+            }
+            GlCall::DeleteTextures(ref textures) => {
+                gl.delete_textures(&replayed_names(&names.textures, textures))
+            }
+            GlCall::DepthFunc(func) => gl.depth_func(func),
+            GlCall::Disable(cap) => gl.disable(cap),
+            GlCall::DisableVertexAttribArray(index) => {
+                gl.disable_vertex_attrib_array(names.attrib_location(index))
+            }
+            GlCall::DrawArrays(mode, first, count) => gl.draw_arrays(mode, first, count),
+            GlCall::Enable(cap) => gl.enable(cap),
+            GlCall::EnableVertexAttribArray(index) => {
+                gl.enable_vertex_attrib_array(names.attrib_location(index))
+            }
+            GlCall::FenceSync(sync) => {
+                names.syncs.insert(sync, gl.fence_sync());
+            }
+            GlCall::FramebufferRenderbuffer(target, attachment, rb_target, renderbuffer) => {
+                gl.framebuffer_renderbuffer(target,
+                                            attachment,
+                                            rb_target,
+                                            replayed_name(&names.renderbuffers, renderbuffer))
+            }
+            GlCall::FramebufferTexture2D(target, attachment, textarget, texture, level) => {
+                gl.framebuffer_texture_2d(target,
+                                          attachment,
+                                          textarget,
+                                          replayed_name(&names.textures, texture),
+                                          level)
+            }
+            GlCall::GenBuffers(ref buffers) => {
+                let replayed = gl.gen_buffers(buffers.len() as GLsizei);
+                map_names(&mut names.buffers, buffers, &replayed);
+            }
+            GlCall::GenFramebuffers(ref framebuffers) => {
+                let replayed = gl.gen_framebuffers(framebuffers.len() as GLsizei);
+                map_names(&mut names.framebuffers, framebuffers, &replayed);
+            }
+            GlCall::GenRenderbuffers(ref renderbuffers) => {
+                let replayed = gl.gen_renderbuffers(renderbuffers.len() as GLsizei);
+                map_names(&mut names.renderbuffers, renderbuffers, &replayed);
+            }
+            GlCall::GenTextures(ref textures) => {
+                let replayed = gl.gen_textures(textures.len() as GLsizei);
+                map_names(&mut names.textures, textures, &replayed);
+            }
+            GlCall::GenerateMipmap(target) => gl.generate_mipmap(target),
+            GlCall::GetAttribLocation(program, ref name, location) => {
+                let replayed = gl.get_attrib_location(replayed_name(&names.programs, program),
+                                                      name);
+                if location >= 0 && replayed >= 0 {
+                    names.attrib_locations.insert((program, location as GLuint),
+                                                  replayed as GLuint);
+                }
+            }
+            GlCall::GetFloatv4(name, _) => {
+                gl.get_float_4v(name);
+            }
+            GlCall::GetIntegerv(name, _) => {
+                gl.get_integer_v(name);
+            }
+            GlCall::GetIntegerv4(name, _) => {
+                gl.get_integer_4v(name);
+            }
+            GlCall::GetProgramInfoLog(program) => {
+                gl.get_program_info_log(replayed_name(&names.programs, program));
+            }
+            GlCall::GetProgramiv(program, pname, _) => {
+                gl.get_program_iv(replayed_name(&names.programs, program), pname);
+            }
+            GlCall::GetShaderInfoLog(shader) => {
+                gl.get_shader_info_log(replayed_name(&names.programs, shader));
+            }
+            GlCall::GetShaderiv(shader, pname, _) => {
+                gl.get_shader_iv(replayed_name(&names.programs, shader), pname);
+            }
+            GlCall::GetString(which, _) => {
+                gl.get_string(which);
+            }
+            GlCall::GetUniformLocation(program, ref name, location) => {
+                let replayed = gl.get_uniform_location(replayed_name(&names.programs, program),
+                                                       name);
+                names.uniform_locations.insert((program, location), replayed);
+            }
+            GlCall::IsEnabled(cap, _) => {
+                gl.is_enabled(cap);
+            }
+            GlCall::LineWidth(width) => gl.line_width(width),
+            GlCall::LinkProgram(program) => {
+                gl.link_program(replayed_name(&names.programs, program))
+            }
+            GlCall::PixelStorei(name, param) => gl.pixel_store_i(name, param),
+            GlCall::ReadPixels(x, y, width, height, format, pixel_type) => {
+                gl.read_pixels(x, y, width, height, format, pixel_type);
+            }
+            GlCall::RenderbufferStorage(target, internalformat, width, height) => {
+                gl.renderbuffer_storage(target, internalformat, width, height)
+            }
+            GlCall::Scissor(x, y, width, height) => gl.scissor(x, y, width, height),
+            GlCall::ShaderSource(shader, ref strings) => {
+                let strings: Vec<&[u8]> = strings.iter().map(|string| &string[..]).collect();
+                gl.shader_source(replayed_name(&names.programs, shader), &strings)
+            }
+            GlCall::TexImage2D { target,
+                                 level,
+                                 internal_format,
+                                 width,
+                                 height,
+                                 border,
+                                 format,
+                                 ty,
+                                 ref data } => {
+                let bytes = data.as_ref().map(|data| data.bytes_or_zeroes());
+                gl.tex_image_2d(target,
+                                level,
+                                internal_format,
+                                width,
+                                height,
+                                border,
+                                format,
+                                ty,
+                                bytes.as_ref().map(|bytes| &bytes[..]))
+            }
+            GlCall::TexParameteri(target, pname, param) => {
+                gl.tex_parameter_i(target, pname, param)
+            }
+            GlCall::TexSubImage2D { target,
+                                    level,
+                                    xoffset,
+                                    yoffset,
+                                    width,
+                                    height,
+                                    format,
+                                    ty,
+                                    ref data } => {
+                gl.tex_sub_image_2d(target,
+                                    level,
+                                    xoffset,
+                                    yoffset,
+                                    width,
+                                    height,
+                                    format,
+                                    ty,
+                                    &data.bytes_or_zeroes())
+            }
+            GlCall::TexSubImage2DPbo { target,
+                                       level,
+                                       xoffset,
+                                       yoffset,
+                                       width,
+                                       height,
+                                       format,
+                                       ty,
+                                       offset } => {
+                gl.tex_sub_image_2d_pbo(target,
+                                        level,
+                                        xoffset,
+                                        yoffset,
+                                        width,
+                                        height,
+                                        format,
+                                        ty,
+                                        offset)
+            }
+            GlCall::Uniform1f(location, v0) => {
+                gl.uniform_1f(names.uniform_location(location), v0)
+            }
+            GlCall::Uniform1i(location, v0) => {
+                gl.uniform_1i(names.uniform_location(location), v0)
+            }
+            GlCall::Uniform4f(location, x, y, z, w) => {
+                gl.uniform_4f(names.uniform_location(location), x, y, z, w)
+            }
+            GlCall::UniformMatrix4fv(location, transpose, ref value) => {
+                gl.uniform_matrix_4fv(names.uniform_location(location), transpose, value)
+            }
+            GlCall::UseProgram(program) => {
+                names.current_program = program;
+                gl.use_program(replayed_name(&names.programs, program))
+            }
+            GlCall::VertexAttribPointerF32(index, size, normalized, stride, offset) => {
+                gl.vertex_attrib_pointer_f32(names.attrib_location(index),
+                                             size,
+                                             normalized,
+                                             stride,
+                                             offset)
+            }
+            GlCall::Viewport(x, y, width, height) => gl.viewport(x, y, width, height),
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use rustc_serialize::json;
+    use super::{GlApi, GlCall, GlTrace, RecordingGl, replay_gl_trace};
+    use super::{ARRAY_BUFFER, FRAGMENT_SHADER, RGBA, TEXTURE_2D, TRIANGLE_STRIP, UNSIGNED_BYTE};
+    use super::{STATIC_DRAW, stop_recording, start_recording};
+
+    /// Records the calls made to draw a textured quad, uploading zeroes so that the upload
+    /// replays to the same digest.
+    fn record_quad() -> GlTrace {
+        let gl = RecordingGl::new();
+        start_recording(false);
+        let texture = super::gen_textures(&gl, 1)[0];
+        super::bind_texture(&gl, TEXTURE_2D, texture);
+        super::tex_image_2d(&gl, TEXTURE_2D, 0, RGBA as i32, 2, 2, 0, RGBA, UNSIGNED_BYTE,
+                            Some(&[0; 16]));
+        let buffer = super::gen_buffers(&gl, 1)[0];
+        super::bind_buffer(&gl, ARRAY_BUFFER, buffer);
+        super::buffer_data(&gl, ARRAY_BUFFER, &[0.0f32; 8], STATIC_DRAW);
+        let program = super::create_program(&gl);
+        let shader = super::create_shader(&gl, FRAGMENT_SHADER);
+        super::shader_source(&gl, shader, &[b"void main() {}"]);
+        super::compile_shader(&gl, shader);
+        super::attach_shader(&gl, program, shader);
+        super::link_program(&gl, program);
+        let location = super::get_uniform_location(&gl, program, "uSampler");
+        super::use_program(&gl, program);
+        super::uniform_1i(&gl, location, 0);
+        super::draw_arrays(&gl, TRIANGLE_STRIP, 0, 4);
+        super::delete_buffers(&gl, &[buffer]);
+        super::delete_textures(&gl, &[texture]);
+        super::delete_program(&gl, program);
+        let trace = stop_recording().unwrap();
+        trace
+    }
+
+    #[test]
+    fn recorded_traces_replay_to_the_same_calls() {
+        let trace = record_quad();
+        let decoded: GlTrace = json::decode(&json::encode(&trace).unwrap()).unwrap();
+        assert_eq!(decoded, trace);
+
+        let gl = RecordingGl::new();
+        replay_gl_trace(&decoded, &gl);
+        assert_eq!(gl.take_calls(), trace.calls);
+    }
+
+    #[test]
+    fn names_are_mapped_to_the_replayed_ones() {
+        let trace = record_quad();
+        let gl = RecordingGl::new();
+        let taken = gl.gen_textures(5);
+        gl.take_calls();
+        replay_gl_trace(&trace, &gl);
+
+        let replayed = gl.take_calls();
+        assert_eq!(replayed.len(), trace.calls.len());
+        let (recorded_texture, replayed_texture) = match (&trace.calls[0], &replayed[0]) {
+            (&GlCall::GenTextures(ref recorded), &GlCall::GenTextures(ref replayed)) => {
+                (recorded[0], replayed[0])
+            }
+            calls => panic!("unexpected calls {:?}", calls),
+        };
+        assert!(!taken.contains(&replayed_texture));
+        assert!(replayed_texture != recorded_texture);
+        assert_eq!(replayed[1], GlCall::BindTexture(TEXTURE_2D, replayed_texture));
+        assert!(replayed.contains(&GlCall::DeleteTextures(vec!(replayed_texture))));
+    }
+
+    #[test]
+    fn each_kind_of_object_has_its_own_names() {
+        // Drivers hand out the same name for a texture and a buffer.
+        let trace = GlTrace {
+            calls: vec!(GlCall::GenTextures(vec!(1)),
+                        GlCall::GenBuffers(vec!(1)),
+                        GlCall::BindTexture(TEXTURE_2D, 1),
+                        GlCall::BindBuffer(ARRAY_BUFFER, 1),
+                        GlCall::DeleteTextures(vec!(1)),
+                        GlCall::DeleteBuffers(vec!(1))),
+        };
+        let gl = RecordingGl::new();
+        replay_gl_trace(&trace, &gl);
+        assert_eq!(gl.take_calls(),
+                   vec!(GlCall::GenTextures(vec!(1)),
+                        GlCall::GenBuffers(vec!(2)),
+                        GlCall::BindTexture(TEXTURE_2D, 1),
+                        GlCall::BindBuffer(ARRAY_BUFFER, 2),
+                        GlCall::DeleteTextures(vec!(1)),
+                        GlCall::DeleteBuffers(vec!(2))));
+    }
+}
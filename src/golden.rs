@@ -0,0 +1,241 @@
+// Copyright 2016 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Golden images for rendering tests.
+//!
+//! `assert_matches_golden` compares a rendered image with the golden image of the same name in
+//! `tests/goldens/`, allowing the small differences a `Tolerance` describes. After a deliberate
+//! change to what is rendered, regenerate the goldens with
+//!
+//! ```text
+//! UPDATE_GOLDENS=1 cargo test
+//! ```
+//!
+//! and review the changes to `tests/goldens/` before committing them.
+//!
+//! Goldens are text, so that changes to them can be reviewed in diffs. The first line holds the
+//! width and height of the image. Each following line up to an empty line maps a character to
+//! a color, given as the hex BGRA bytes of the premultiplied color. Then come the rows of the
+//! image, top row first, with a character per pixel.
+
+use euclid::size::Size2D;
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+/// The characters that stand for colors, in the order in which colors are given them.
+static COLOR_CHARACTERS: &'static str =
+    ".#o+x*=%@&$abcdefghijklmnpqrstuvwyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+/// An image of BGRA pixels with premultiplied alpha, top row first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub size: Size2D<usize>,
+    pub pixels: Vec<u8>,
+}
+
+/// How much a rendered image may differ from its golden.
+#[derive(Clone, Copy, Debug)]
+pub struct Tolerance {
+    /// The largest difference allowed in any channel of a pixel.
+    pub channel_threshold: u8,
+
+    /// The number of pixels that may differ by more than `channel_threshold`.
+    pub max_differing_pixels: usize,
+}
+
+/// Allows the off-by-one channel values of differently rounding rasterizers, and nothing else.
+pub static ROUNDING_TOLERANCE: Tolerance = Tolerance {
+    channel_threshold: 2,
+    max_differing_pixels: 0,
+};
+
+/// Returns the number of pixels in which the images differ by more than `channel_threshold` in
+/// some channel, or None if they differ in size.
+pub fn count_differing_pixels(expected: &Image, actual: &Image, channel_threshold: u8)
+                              -> Option<usize> {
+    if expected.size != actual.size || expected.pixels.len() != actual.pixels.len() {
+        return None
+    }
+    Some(expected.pixels.chunks(4).zip(actual.pixels.chunks(4)).filter(|&(expected, actual)| {
+        expected.iter().zip(actual.iter()).any(|(&expected, &actual)| {
+            (expected as i32 - actual as i32).abs() > channel_threshold as i32
+        })
+    }).count())
+}
+
+/// Encodes an image in the text format of goldens.
+pub fn encode(image: &Image) -> String {
+    let mut colors: Vec<&[u8]> = vec!();
+    let mut rows = String::new();
+    for row in image.pixels.chunks(image.size.width * 4) {
+        for pixel in row.chunks(4) {
+            let index = match colors.iter().position(|color| *color == pixel) {
+                Some(index) => index,
+                None => {
+                    colors.push(pixel);
+                    colors.len() - 1
+                }
+            };
+            rows.push(COLOR_CHARACTERS.chars().nth(index).expect("Too many colors for a golden"));
+        }
+        rows.push('\n');
+    }
+
+    let mut text = format!("{} {}\n", image.size.width, image.size.height);
+    for (character, color) in COLOR_CHARACTERS.chars().zip(colors.iter()) {
+        text.push_str(&format!("{} {:02x}{:02x}{:02x}{:02x}\n",
+                               character,
+                               color[0],
+                               color[1],
+                               color[2],
+                               color[3]));
+    }
+    text.push('\n');
+    text.push_str(&rows);
+    text
+}
+
+/// Decodes an image in the text format of goldens.
+pub fn decode(text: &str) -> Result<Image, String> {
+    let mut lines = text.lines();
+    let size: Vec<usize> = match lines.next() {
+        Some(line) => line.split_whitespace().filter_map(|number| number.parse().ok()).collect(),
+        None => return Err("Empty golden".to_owned()),
+    };
+    if size.len() != 2 {
+        return Err("Malformed size".to_owned());
+    }
+    let size = Size2D::new(size[0], size[1]);
+
+    let mut colors = vec!();
+    for line in lines.by_ref().take_while(|line| !line.is_empty()) {
+        let mut fields = line.split_whitespace();
+        let (character, color) = match (fields.next(), fields.next()) {
+            (Some(character), Some(color)) if character.len() == 1 && color.len() == 8 => {
+                (character.chars().next().unwrap(), color)
+            }
+            _ => return Err(format!("Malformed color: {}", line)),
+        };
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = match u8::from_str_radix(&color[i * 2..i * 2 + 2], 16) {
+                Ok(byte) => byte,
+                Err(_) => return Err(format!("Malformed color: {}", line)),
+            };
+        }
+        colors.push((character, bytes));
+    }
+
+    let mut pixels = Vec::with_capacity(size.width * size.height * 4);
+    let mut row_count = 0;
+    for row in lines {
+        if row.chars().count() != size.width {
+            return Err(format!("Row {} isn't {} pixels wide", row_count, size.width));
+        }
+        for character in row.chars() {
+            match colors.iter().find(|&&(color_character, _)| color_character == character) {
+                Some(&(_, color)) => pixels.extend_from_slice(&color),
+                None => return Err(format!("Unknown color: {}", character)),
+            }
+        }
+        row_count += 1;
+    }
+    if row_count != size.height {
+        return Err(format!("Expected {} rows, found {}", size.height, row_count));
+    }
+    Ok(Image {
+        size: size,
+        pixels: pixels,
+    })
+}
+
+/// Returns the path of the golden with the given name.
+pub fn golden_path(name: &str) -> PathBuf {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests");
+    path.push("goldens");
+    path.push(format!("{}.golden", name));
+    path
+}
+
+/// Panics unless `image` matches the golden with the given name within `tolerance`. When the
+/// `UPDATE_GOLDENS` environment variable is set, the golden is replaced by `image` instead.
+pub fn assert_matches_golden(name: &str, image: &Image, tolerance: Tolerance) {
+    let path = golden_path(name);
+    if env::var_os("UPDATE_GOLDENS").is_some() {
+        let mut file = File::create(&path).unwrap();
+        file.write_all(encode(image).as_bytes()).unwrap();
+        return;
+    }
+
+    let mut text = String::new();
+    match File::open(&path) {
+        Ok(mut file) => {
+            file.read_to_string(&mut text).unwrap();
+        }
+        Err(_) => {
+            panic!("No golden at {}; run `UPDATE_GOLDENS=1 cargo test` to create it",
+                   path.display())
+        }
+    }
+    let golden = match decode(&text) {
+        Ok(golden) => golden,
+        Err(error) => panic!("Malformed golden {}: {}", path.display(), error),
+    };
+    match count_differing_pixels(&golden, image, tolerance.channel_threshold) {
+        Some(count) if count <= tolerance.max_differing_pixels => {}
+        result => {
+            panic!("{} doesn't match its golden ({:?} pixels differ); the image is\n{}",
+                   name,
+                   result,
+                   encode(image))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use euclid::size::Size2D;
+    use super::{Image, count_differing_pixels, decode, encode};
+
+    fn image(pixels: &[[u8; 4]], width: usize) -> Image {
+        Image {
+            size: Size2D::new(width, pixels.len() / width),
+            pixels: pixels.iter().flat_map(|pixel| pixel.iter().cloned()).collect(),
+        }
+    }
+
+    #[test]
+    fn images_survive_encoding() {
+        let image = image(&[[255, 255, 255, 255], [0, 128, 255, 255], [0, 0, 0, 0],
+                            [0, 0, 0, 0], [255, 255, 255, 255], [0, 102, 0, 102]], 3);
+        let text = encode(&image);
+        assert_eq!(text, "3 2\n. ffffffff\n# 0080ffff\no 00000000\n+ 00660066\n\n.#o\no.+\n");
+        assert_eq!(decode(&text), Ok(image));
+    }
+
+    #[test]
+    fn malformed_goldens_are_rejected() {
+        assert!(decode("").is_err());
+        assert!(decode("2 1\n. ffffffff\n\n..\n..\n").is_err());
+        assert!(decode("2 1\n. ffffffff\n\n.#\n").is_err());
+        assert!(decode("2 1\n. ffffff\n\n..\n").is_err());
+    }
+
+    #[test]
+    fn small_channel_differences_are_tolerated() {
+        let expected = image(&[[100, 100, 100, 255], [0, 0, 0, 0]], 2);
+        let actual = image(&[[102, 99, 100, 255], [0, 0, 3, 3]], 2);
+        assert_eq!(count_differing_pixels(&expected, &actual, 2), Some(1));
+        assert_eq!(count_differing_pixels(&expected, &actual, 3), Some(0));
+        assert_eq!(count_differing_pixels(&expected, &image(&[[0, 0, 0, 0]], 1), 255), None);
+    }
+}
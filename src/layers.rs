@@ -7,52 +7,194 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use color::Color;
-use geometry::{DevicePixel, LayerPixel};
-use tiling::{Tile, TileGrid};
+//! The layer tree.
+//!
+//! Changing the opacity, transform, transform origin, or perspective of a layer only affects
+//! compositing, so these properties should be changed via `set_opacity`, `set_transform`,
+//! `set_transform_origin`, and `set_perspective`. Only call `contents_changed` when the painted
+//! content of the layer is actually out of date, since it causes every tile of the layer to be
+//! requested again. For example, a fade and move animation never generates buffer requests
+//! once the tiles of the layer have been requested:
+//!
+//! ```
+//! # extern crate euclid;
+//! # extern crate layers;
+//! # fn main() {
+//! use euclid::Matrix4D;
+//! use euclid::point::TypedPoint2D;
+//! use euclid::rect::TypedRect;
+//! use euclid::scale_factor::ScaleFactor;
+//! use euclid::size::TypedSize2D;
+//! use layers::color::Color;
+//! use layers::geometry::ScreenPixel;
+//! use layers::layers::Layer;
+//!
+//! let bounds = TypedRect::new(TypedPoint2D::new(0.0, 0.0), TypedSize2D::new(256.0, 256.0));
+//! let transparent = Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 };
+//! let layer = Layer::new(bounds, 256, transparent, 1.0, false, ());
+//! let viewport: TypedRect<f32, ScreenPixel> =
+//!     TypedRect::new(TypedPoint2D::new(0.0, 0.0), TypedSize2D::new(256.0, 256.0));
+//! let scale = ScaleFactor::new(1.0);
+//! assert_eq!(layer.get_buffer_requests(viewport, viewport, scale).len(), 1);
+//!
+//! for frame in 0..10 {
+//!     layer.set_opacity(1.0 - frame as f32 / 10.0);
+//!     layer.set_transform(Matrix4D::identity().pre_translated(frame as f32 * 10.0, 0.0, 0.0));
+//!     assert!(layer.get_buffer_requests(viewport, viewport, scale).is_empty());
+//! }
+//!
+//! // Changing the content requests the tile again.
+//! layer.contents_changed();
+//! assert_eq!(layer.get_buffer_requests(viewport, viewport, scale).len(), 1);
+//! # }
+//! ```
 
-use euclid::Matrix4D;
+use capabilities::Capabilities;
+use color::{Color, ColorMatrix};
+use frame::FrameIndex;
+use geometry::{DevicePixel, LayerPixel, ScreenPixel, screen_to_device_scale};
+use gltrace::GlApi;
+use texturegl::{Rotation, Texture};
+use tiling::{LayerTooLargeError, RESOLUTION_LOOP_FRAME_WINDOW, Tile, TileGrid, TileGridStatistics};
+use tiling::TileGridUpdate;
+
+use euclid::{Matrix2D, Matrix4D};
 use euclid::scale_factor::ScaleFactor;
 use euclid::size::{Size2D, TypedSize2D};
-use euclid::point::{Point2D, TypedPoint2D};
+use euclid::point::{Point2D, Point3D, TypedPoint2D};
 use euclid::rect::{Rect, TypedRect};
-use platform::surface::{NativeDisplay, NativeSurface};
-use std::cell::{RefCell, RefMut};
+use euclid::side_offsets::SideOffsets2D;
+use platform::surface::{NativeDisplay, NativeSurface, SerializedSurface, SurfaceError};
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+use std::cell::{Cell, RefCell, RefMut};
+use std::cmp;
+use std::mem;
 use std::rc::Rc;
-use util::{project_rect_to_screen, ScreenRect};
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use util::{inflate_rect, inset_rect, is_2d_transform, is_axis_aligned_2d_transform};
+use util::{project_rect_to_screen, rect_has_valid_size, transform_collapses_layer};
+use util::{invert_layer_transform, rect_crosses_near_plane, unproject_point};
+use util::{ScreenRect, subtract_rect};
+
+/// The default relative tolerance used to decide whether a buffer was painted at the scale it is
+/// displayed at. Scales reach the painter and come back through several f32 multiplications,
+/// each of which may be off by about 1e-7 relative to the exact result, so an exact comparison
+/// rejects buffers at some fractional zoom levels. A tolerance of 0.1% is far above that error,
+/// and leaves any distinct zoom level apart, while shifting a tile by less than a pixel over a
+/// thousand pixels.
+pub const DEFAULT_RESOLUTION_EPSILON: f32 = 1.0e-3;
 
-#[derive(Clone, Copy, PartialEq, PartialOrd)]
+/// The version of the content of a layer. Ages are ordered by epoch first, so that every age of
+/// a later epoch is newer than all ages of earlier epochs, which lets a painter that starts over,
+/// for instance after the paint process crashed, outdate the buffers of its predecessor.
+///
+/// Epochs wrap around after `u32::MAX` and are compared by their distance, like serial numbers,
+/// so that an epoch that wrapped around is still later than the one before it. Only epochs more
+/// than 2^31 apart are misordered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, RustcDecodable, RustcEncodable)]
 pub struct ContentAge {
+    epoch: u32,
     age: usize,
 }
 
+/// The epoch most recently returned by `ContentAge::new_epoch`, or before the first call, 0, the
+/// epoch of `ContentAge::new`.
+static LAST_EPOCH: AtomicUsize = ATOMIC_USIZE_INIT;
+
 #[cfg(feature = "heapsize")]
 known_heap_size!(0, ContentAge);
 
 impl ContentAge {
     pub fn new() -> ContentAge {
         ContentAge {
+            epoch: 0,
+            age: 0,
+        }
+    }
+
+    /// Returns the first content age of an epoch that no other call in this process returned.
+    /// It is newer than the ages of all epochs created before it, even by calls made back to back
+    /// on different threads; use `next_epoch` instead when the last content age of the previous
+    /// painter is known.
+    pub fn new_epoch() -> ContentAge {
+        let epoch = LAST_EPOCH.fetch_add(1, Ordering::SeqCst).wrapping_add(1);
+        ContentAge {
+            epoch: epoch as u32,
             age: 0,
         }
     }
 
+    /// Returns the first content age of the epoch following the epoch of this age. The epoch
+    /// after `u32::MAX` is 0, which is still newer.
+    pub fn next_epoch(&self) -> ContentAge {
+        ContentAge {
+            epoch: self.epoch.wrapping_add(1),
+            age: 0,
+        }
+    }
+
+    /// Returns the epoch of this age.
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
     pub fn next(&mut self) {
         self.age += 1;
     }
+
+    /// Returns how many ages this age is newer than `earlier`, or None if the two ages belong to
+    /// different epochs.
+    pub fn ages_since(&self, earlier: ContentAge) -> Option<usize> {
+        if self.epoch == earlier.epoch {
+            Some(self.age.saturating_sub(earlier.age))
+        } else {
+            None
+        }
+    }
+}
+
+impl PartialOrd for ContentAge {
+    fn partial_cmp(&self, other: &ContentAge) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ContentAge {
+    fn cmp(&self, other: &ContentAge) -> cmp::Ordering {
+        // The distance between the epochs, taken as signed, tells which comes later even when
+        // the later one has wrapped around.
+        let epoch_distance = self.epoch.wrapping_sub(other.epoch) as i32;
+        epoch_distance.cmp(&0).then(self.age.cmp(&other.age))
+    }
 }
 
 pub struct TransformState {
     /// Final, concatenated transform + perspective matrix for this layer
     pub final_transform: Matrix4D<f32>,
 
+    /// The inverse of `final_transform`, or None if it can't be inverted or flattens the layer
+    /// to zero area. See `Layer::convert_point_from_screen`.
+    pub inverse_transform: Option<Matrix4D<f32>>,
+
     /// If this is none, the rect was clipped and is not visible at all!
     pub screen_rect: Option<ScreenRect>,
 
     /// Rectangle in global coordinates, but not transformed.
-    pub world_rect: Rect<f32>,
+    pub world_rect: TypedRect<f32, LayerPixel>,
 
     /// True if this layer has a non-identity transform
     pub has_transform: bool,
+
+    /// True if the final transform puts some of the corners of the layer behind the near plane,
+    /// so that its quads are clipped to the near plane before they are drawn.
+    pub crosses_near_plane: bool,
+
+    /// The opacity of this layer multiplied by the effective opacities of all its ancestors.
+    ///
+    /// Drawing each layer with this opacity only approximates group opacity: where children of a
+    /// translucent layer overlap, the ones behind show through the ones in front, which wouldn't
+    /// happen if the subtree were composited as a whole.
+    pub effective_opacity: f32,
 }
 
 #[cfg(feature = "heapsize")]
@@ -62,18 +204,105 @@ impl TransformState {
     fn new() -> TransformState {
         TransformState {
             final_transform: Matrix4D::identity(),
+            inverse_transform: Some(Matrix4D::identity()),
             screen_rect: None,
-            world_rect: Rect::zero(),
+            world_rect: TypedRect::zero(),
             has_transform: false,
+            crosses_near_plane: false,
+            effective_opacity: 1.0,
+        }
+    }
+}
+
+/// Returns true if the given transform maps screen-space rects back to world-space rects
+/// exactly: it is a 2D transform that only translates and scales, possibly flipping.
+fn clip_rect_stays_rect(transform: &Matrix4D<f32>) -> bool {
+    is_2d_transform(transform) && transform.m12 == 0.0 && transform.m21 == 0.0
+}
+
+/// A rect with rounded corners that the descendants of a masking layer are clipped to. See
+/// `Layer::corner_radii`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RoundedClip {
+    /// The clip rect in screen space.
+    pub rect: TypedRect<f32, ScreenPixel>,
+    /// The radii of the corners in screen space, clockwise from the top left.
+    pub radii: [f32; 4],
+}
+
+/// Debugging overlays drawn for a single layer, in addition to those enabled globally on the
+/// `RenderContext`.
+#[derive(Copy, Clone, Debug)]
+pub struct LayerDebugOptions {
+    /// Whether to outline the boundaries of the layer.
+    pub show_borders: bool,
+
+    /// Whether to outline the boundaries of each tile of the layer.
+    pub show_tile_borders: bool,
+
+    /// Whether to outline the screen-space bounding box of the layer.
+    pub show_aabb: bool,
+
+    /// A translucent, premultiplied color drawn over the layer so that it can be found on screen.
+    pub label_color: Option<Color>,
+}
+
+#[cfg(feature = "heapsize")]
+known_heap_size!(0, LayerDebugOptions);
+
+impl LayerDebugOptions {
+    pub fn new() -> LayerDebugOptions {
+        LayerDebugOptions {
+            show_borders: false,
+            show_tile_borders: false,
+            show_aabb: false,
+            label_color: None,
         }
     }
 }
 
+/// How the content of a layer is provided.
+pub enum ContentMode {
+    /// The content is painted into tiles, which are requested as they become visible. This is the
+    /// default.
+    Tiled,
+
+    /// The content is a single static buffer, drawn like a CSS border image: the insets, in
+    /// pixels of the buffer, cut it into corners that are drawn unscaled, edges that are
+    /// stretched along their length, and a center that is stretched both ways to fill the
+    /// bounds of the layer. No tiles are requested, so resizing the layer needs no repaint.
+    NinePatch {
+        buffer: Box<LayerBuffer>,
+        insets: SideOffsets2D<f32>,
+    },
+}
+
+/// The properties of a layer that its transform state is computed from.
+#[derive(Copy, Clone, PartialEq)]
+struct TransformInputs {
+    transform: Matrix4D<f32>,
+    perspective: Matrix4D<f32>,
+    transform_origin: Point3D<f32>,
+    bounds: TypedRect<f32, LayerPixel>,
+    content_offset: TypedPoint2D<f32, LayerPixel>,
+    fixed_to_viewport: bool,
+    fixed_offset: TypedPoint2D<f32, LayerPixel>,
+    masks_to_bounds: bool,
+    opacity: f32,
+}
+
 pub struct Layer<T> {
     pub children: RefCell<Vec<Rc<Layer<T>>>>,
     pub transform: RefCell<Matrix4D<f32>>,
     pub perspective: RefCell<Matrix4D<f32>>,
-    pub tile_size: usize,
+    /// The point about which `transform` and `perspective` are applied, relative to the top left
+    /// corner of the layer. This defaults to the top left corner itself, (0, 0, 0); CSS
+    /// transforms, which default to the center, must set it explicitly. It moves along with the
+    /// layer, but is not adjusted when the layer is resized.
+    pub transform_origin: RefCell<Point3D<f32>>,
+    /// The requested size of the tiles of this layer in device pixels. See `set_tile_size` and
+    /// `set_max_tile_size`.
+    pub tile_size: Cell<usize>,
     pub extra_data: RefCell<T>,
     tile_grid: RefCell<TileGrid>,
 
@@ -86,22 +315,155 @@ pub struct Layer<T> {
     /// The content offset for this layer in unscaled layer pixels.
     pub content_offset: RefCell<TypedPoint2D<f32, LayerPixel>>,
 
+    /// Whether this layer is fixed to the scene viewport, as for `position: fixed`. The bounds of
+    /// such a layer are relative to `fixed_offset` rather than to its parent, so it stays in
+    /// place when its ancestors are moved or scrolled. Its own content offset, the transforms of
+    /// its ancestors, and the clips of masking ancestors still apply.
+    pub fixed_to_viewport: RefCell<bool>,
+
+    /// The origin of the bounds of this layer in world space, that is, relative to the top left
+    /// corner of the scene viewport before the scene scale, if `fixed_to_viewport` is set.
+    pub fixed_offset: RefCell<TypedPoint2D<f32, LayerPixel>>,
+
+    /// Whether the size of this layer follows the size of the viewport, for layers fixed to the
+    /// viewport whose size is derived from it. `Scene::handle_viewport_resize` grows or shrinks
+    /// such layers by as much as the viewport.
+    pub resizes_with_viewport: Cell<bool>,
+
+    /// The size of the scrollable content of this layer, used to clamp the content offset when
+    /// the layer has no children. If None, the content is the size of the layer.
+    pub content_size: RefCell<Option<TypedSize2D<f32, LayerPixel>>>,
+
     /// Whether this layer clips its children to its boundaries.
     pub masks_to_bounds: RefCell<bool>,
 
+    /// The radii of the corners of the clip applied to the children of this layer when it masks
+    /// to its bounds, in unscaled layer pixels and clockwise from the top left. Rounded corners
+    /// only clip the tiles of descendants, and only while this layer has an axis-aligned 2D
+    /// transform; a masking descendant with its own radii replaces them.
+    pub corner_radii: RefCell<Option<[f32; 4]>>,
+
     /// The background color for this layer.
     pub background_color: RefCell<Color>,
 
     /// The opacity of this layer, from 0.0 (fully transparent) to 1.0 (fully opaque).
     pub opacity: RefCell<f32>,
 
+    /// Whether this layer is drawn. A hidden layer keeps its tiles, but it is not drawn, hit
+    /// tested, or requested, so it can be shown again without repainting. See `set_visible`.
+    pub visible: RefCell<bool>,
+
+    /// Whether hiding this layer hides its descendants too. Only used while `visible` is false.
+    pub hides_subtree: RefCell<bool>,
+
+    /// Insets from the layer boundaries, in layer pixels, outside of which this layer does not
+    /// draw its background or tiles. Children are unaffected. This is useful for content that is
+    /// overlapped by chrome, without having to change the bounds of the layer.
+    content_inset: RefCell<SideOffsets2D<f32>>,
+
+    /// The distance, in layer pixels, by which tiles are requested and kept around the dirty
+    /// rect and viewport of each request pass, so that small scrolls find them painted already.
+    /// This applies to this layer only, not to its children.
+    pub prefetch_margin: RefCell<f32>,
+
+    /// Whether the content of this layer covers every pixel of its content rect with fully opaque
+    /// pixels. Opaque layers hide the layers behind them, whose tiles need not be painted, and
+    /// which aren't drawn when entirely hidden.
+    pub contents_opaque: RefCell<bool>,
+
     /// Whether this stacking context creates a new 3d rendering context.
     pub establishes_3d_context: bool,
 
+    /// Orders this layer among the layers of its 3D context at the same depth: the layer with the
+    /// larger index is drawn later, on top of the other. This defaults to the order in which the
+    /// layers were created, so that the stacking of coplanar layers doesn't change when the tree
+    /// is rebuilt with its children added in another order. `insert_child_at_index` and
+    /// `set_child_index` restack the children they reorder. Flat layer trees are drawn in paint
+    /// order regardless.
+    pub stacking_index: RefCell<u64>,
+
     /// Collection of state related to transforms for this layer.
     pub transform_state: RefCell<TransformState>,
+
+    /// An embedder-supplied identifier used to attribute memory and paint traffic to a group of
+    /// layers (for instance, all the layers belonging to one tab). Unless set explicitly via
+    /// `set_group_id`, this is inherited from the parent layer when the layer is added as a
+    /// child.
+    pub group_id: Cell<Option<u64>>,
+
+    /// Whether `group_id` was set explicitly rather than inherited from the parent.
+    group_id_is_explicit: Cell<bool>,
+
+    /// The group id last passed down by the parent of this layer, which `group_id` reverts to
+    /// when the explicit group id is cleared.
+    inherited_group_id: Cell<Option<u64>>,
+
+    /// Whether a composite-only property of this layer changed since the transform state of the
+    /// layer was last updated, which render and buffer request passes do before anything else.
+    /// Used to warn about needless calls to `contents_changed` in the same frame.
+    composite_properties_changed: Cell<bool>,
+
+    /// Whether the transform state of this layer is out of date.
+    transform_state_dirty: Cell<bool>,
+
+    /// The properties the transform state of this layer was last computed from, so that changes
+    /// written directly to the public fields are noticed by the next flush. None before the
+    /// first update.
+    flushed_transform_inputs: Cell<Option<TransformInputs>>,
+
+    /// Whether a culled update left the transform state of this layer and its descendants out of
+    /// date during a gesture. Unlike a dirty transform state, this doesn't make the next flush
+    /// run an update pass.
+    transform_state_skipped: Cell<bool>,
+
+    /// The area of this layer whose pixels changed since the damage was last taken, relative to
+    /// the top left corner of the layer.
+    damage: Cell<Option<TypedRect<f32, LayerPixel>>>,
+
+    /// Whether the transform state of this layer was invalidated since the damage was last
+    /// taken, which can move content anywhere on the screen.
+    composite_damaged: Cell<bool>,
+
+    /// Debugging overlays to draw for this layer.
+    pub debug_options: RefCell<LayerDebugOptions>,
+
+    /// Whether the render pass found this layer entirely hidden behind opaque layers drawn after
+    /// it, in which case it isn't drawn. The render passes set this for every layer they draw.
+    pub culled: Cell<bool>,
+
+    /// A color transform applied to the content of this layer when it is composited, for CSS
+    /// color filters. It doesn't apply to the background color or the children of the layer.
+    pub filter: RefCell<Option<ColorMatrix>>,
+
+    /// How the content of this layer is provided.
+    content_mode: RefCell<ContentMode>,
+
+    /// The texture bound to the buffer of a nine-patch layer.
+    nine_patch_texture: RefCell<Texture>,
+
+    /// The relative tolerance used by `Scene::add_buffer` and `add_buffer_checked` to decide
+    /// whether a buffer for this layer was painted at the right scale. If None, the tolerance of
+    /// the scene, or `DEFAULT_RESOLUTION_EPSILON`, is used.
+    pub resolution_epsilon: Cell<Option<f32>>,
+
+    /// The scale at which the tiles of this layer are requested and painted instead of the scene
+    /// scale, for content painted at another device pixel ratio, such as an iframe painted by
+    /// another process. Children are unaffected. The tiles are still drawn at the size of the
+    /// layer; the page rects of their buffers must be derived from the resolution of the
+    /// requests rather than from the scene scale.
+    pub scale_override: RefCell<Option<ScaleFactor<f32, LayerPixel, DevicePixel>>>,
+
+    /// The generation of the last buffer set swapped in with `swap_in_buffer_set`.
+    buffer_set_generation: Cell<Option<u64>>,
+
+    /// The content offset accumulated by `scroll_by`, kept in double precision so that many
+    /// small scroll deltas don't drift. `content_offset` is derived from this.
+    precise_content_offset: Cell<(f64, f64)>,
 }
 
+/// The default stacking index of the next layer created.
+static NEXT_STACKING_INDEX: AtomicUsize = ATOMIC_USIZE_INIT;
+
 impl<T> Layer<T> {
     pub fn new(bounds: TypedRect<f32, LayerPixel>,
                tile_size: usize,
@@ -114,17 +476,47 @@ impl<T> Layer<T> {
             children: RefCell::new(vec!()),
             transform: RefCell::new(Matrix4D::identity()),
             perspective: RefCell::new(Matrix4D::identity()),
+            transform_origin: RefCell::new(Point3D::new(0.0, 0.0, 0.0)),
             bounds: RefCell::new(bounds),
-            tile_size: tile_size,
+            tile_size: Cell::new(tile_size),
             extra_data: RefCell::new(data),
             tile_grid: RefCell::new(TileGrid::new(tile_size)),
             content_age: RefCell::new(ContentAge::new()),
             masks_to_bounds: RefCell::new(false),
+            corner_radii: RefCell::new(None),
             content_offset: RefCell::new(TypedPoint2D::zero()),
+            fixed_to_viewport: RefCell::new(false),
+            fixed_offset: RefCell::new(TypedPoint2D::zero()),
+            resizes_with_viewport: Cell::new(false),
+            content_size: RefCell::new(None),
             background_color: RefCell::new(background_color),
             opacity: RefCell::new(opacity),
+            visible: RefCell::new(true),
+            hides_subtree: RefCell::new(false),
+            content_inset: RefCell::new(SideOffsets2D::zero()),
+            prefetch_margin: RefCell::new(0.0),
+            contents_opaque: RefCell::new(false),
             establishes_3d_context: establishes_3d_context,
+            stacking_index: RefCell::new(NEXT_STACKING_INDEX.fetch_add(1, Ordering::SeqCst) as u64),
             transform_state: RefCell::new(TransformState::new()),
+            group_id: Cell::new(None),
+            group_id_is_explicit: Cell::new(false),
+            inherited_group_id: Cell::new(None),
+            composite_properties_changed: Cell::new(false),
+            transform_state_dirty: Cell::new(true),
+            flushed_transform_inputs: Cell::new(None),
+            transform_state_skipped: Cell::new(false),
+            damage: Cell::new(None),
+            composite_damaged: Cell::new(true),
+            precise_content_offset: Cell::new((0.0, 0.0)),
+            debug_options: RefCell::new(LayerDebugOptions::new()),
+            culled: Cell::new(false),
+            filter: RefCell::new(None),
+            content_mode: RefCell::new(ContentMode::Tiled),
+            nine_patch_texture: RefCell::new(Texture::zero()),
+            resolution_epsilon: Cell::new(None),
+            scale_override: RefCell::new(None),
+            buffer_set_generation: Cell::new(None),
         }
     }
 
@@ -133,201 +525,3315 @@ impl<T> Layer<T> {
     }
 
     pub fn add_child(&self, new_child: Rc<Layer<T>>) {
+        new_child.inherit_group_id(self.group_id.get());
+        new_child.mark_transform_state_dirty();
         self.children().push(new_child);
     }
 
+    /// Inserts a child at the given position among the children of this layer, shifting the
+    /// following children back. Children are drawn in order, so later children are drawn on top
+    /// of earlier ones at equal depth; see `restack_children`. Panics if `index` is greater than
+    /// the number of children.
+    pub fn insert_child_at_index(&self, new_child: Rc<Layer<T>>, index: usize) {
+        new_child.inherit_group_id(self.group_id.get());
+        new_child.mark_transform_state_dirty();
+        let mut children = self.children();
+        children.insert(index, new_child);
+        Layer::restack_children(&children);
+    }
+
+    /// Moves an existing child to the given position among the children of this layer, keeping
+    /// its tiles. Returns an error if the layer is not a child of this layer or if `new_index`
+    /// is out of bounds.
+    pub fn set_child_index(&self, child: &Rc<Layer<T>>, new_index: usize) -> Result<(), ()> {
+        let mut children = self.children();
+        if new_index >= children.len() {
+            return Err(());
+        }
+        let index = match children.iter().position(|existing_child| {
+            &**existing_child as *const Layer<T> == &**child as *const Layer<T>
+        }) {
+            Some(index) => index,
+            None => return Err(()),
+        };
+        let child = children.remove(index);
+        child.mark_transform_state_dirty();
+        children.insert(new_index, child);
+        Layer::restack_children(&children);
+        Ok(())
+    }
+
+    /// Hands the stacking indices of the given children back out in child order, so that a
+    /// reordering also reorders them among each other at equal depth in 3D contexts, while
+    /// layers elsewhere in the context keep their place relative to them.
+    fn restack_children(children: &[Rc<Layer<T>>]) {
+        let mut stacking_indices: Vec<u64> =
+            children.iter().map(|child| *child.stacking_index.borrow()).collect();
+        stacking_indices.sort();
+        for (child, stacking_index) in children.iter().zip(stacking_indices.into_iter()) {
+            *child.stacking_index.borrow_mut() = stacking_index;
+        }
+    }
+
     pub fn remove_child_at_index(&self, index: usize) {
-        self.children().remove(index);
+        let child = self.children().remove(index);
+        child.inherited_group_id.set(None);
+        self.mark_transform_state_dirty();
     }
 
-    /// Returns buffer requests inside the given dirty rect, and simultaneously throws out tiles
-    /// outside the given viewport rect.
-    pub fn get_buffer_requests(&self,
-                               rect_in_layer: TypedRect<f32, LayerPixel>,
-                               viewport_in_layer: TypedRect<f32, LayerPixel>,
-                               scale: ScaleFactor<f32, LayerPixel, DevicePixel>)
-                               -> Vec<BufferRequest> {
-        let mut tile_grid = self.tile_grid.borrow_mut();
-        tile_grid.get_buffer_requests_in_rect(rect_in_layer * scale,
-                                              viewport_in_layer * scale,
-                                              self.bounds.borrow().size * scale,
-                                              &(self.transform_state.borrow().world_rect.origin *
-                                                scale.get()),
-                                              &self.transform_state.borrow().final_transform,
-                                              *self.content_age.borrow())
+    /// Removes the given layer from the children of this layer, comparing layers by identity.
+    /// Returns true if it was a child. The removed layer keeps its tiles, whose buffers must
+    /// still be collected with `collect_buffers`.
+    pub fn remove_child(&self, child: &Rc<Layer<T>>) -> bool {
+        let index = self.children().iter().position(|existing_child| {
+            &**existing_child as *const Layer<T> == &**child as *const Layer<T>
+        });
+        match index {
+            Some(index) => {
+                self.remove_child_at_index(index);
+                true
+            }
+            None => false,
+        }
     }
 
-    pub fn resize(&self, new_size: TypedSize2D<f32, LayerPixel>) {
-        self.bounds.borrow_mut().size = new_size;
+    /// Removes the given layer from wherever it is in the subtree rooted at this layer. Returns
+    /// true if it was found. Useful when only the layer itself is known, not its parent.
+    pub fn remove_descendant(&self, descendant: &Rc<Layer<T>>) -> bool {
+        if self.remove_child(descendant) {
+            return true;
+        }
+        let children = self.children().clone();
+        children.iter().any(|child| child.remove_descendant(descendant))
     }
 
-    pub fn add_buffer(&self, tile: Box<LayerBuffer>) {
-        self.tile_grid.borrow_mut().add_buffer(tile);
+    /// Explicitly sets the group id of this layer. Descendants that have not had their group id
+    /// set explicitly follow this layer. Passing `None` reverts to the group of the current
+    /// parent, and to following it from then on.
+    pub fn set_group_id(&self, group_id: Option<u64>) {
+        self.group_id_is_explicit.set(group_id.is_some());
+        let group_id = group_id.or(self.inherited_group_id.get());
+        self.group_id.set(group_id);
+        for child in self.children().iter() {
+            child.inherit_group_id(group_id);
+        }
     }
 
-    pub fn collect_unused_buffers(&self) -> Vec<Box<LayerBuffer>> {
-        self.tile_grid.borrow_mut().take_unused_buffers()
+    fn inherit_group_id(&self, group_id: Option<u64>) {
+        self.inherited_group_id.set(group_id);
+        if self.group_id_is_explicit.get() {
+            return;
+        }
+        self.group_id.set(group_id);
+        for child in self.children().iter() {
+            child.inherit_group_id(group_id);
+        }
     }
 
-    pub fn collect_buffers(&self) -> Vec<Box<LayerBuffer>> {
-        self.tile_grid.borrow_mut().collect_buffers()
+    /// Returns the opacity of this layer, from 0.0 (fully transparent) to 1.0 (fully opaque).
+    pub fn opacity(&self) -> f32 {
+        *self.opacity.borrow()
     }
 
-    pub fn contents_changed(&self) {
-        self.content_age.borrow_mut().next();
+    /// Sets the opacity of this layer. This only affects compositing and never causes the
+    /// content of the layer to be repainted.
+    pub fn set_opacity(&self, opacity: f32) {
+        *self.opacity.borrow_mut() = opacity;
+        self.composite_properties_changed.set(true);
+        // The effective opacity of the subtree is computed with the transform state.
+        self.mark_transform_state_dirty();
     }
 
-    pub fn create_textures(&self, display: &NativeDisplay) {
-        self.tile_grid.borrow_mut().create_textures(display);
+    /// Shows or hides this layer, and if `hides_subtree` is true, its descendants along with it.
+    /// Otherwise the descendants are drawn as usual. This only affects compositing: the tiles of
+    /// hidden layers are kept, and their content age is left alone.
+    pub fn set_visible(&self, visible: bool, hides_subtree: bool) {
+        *self.visible.borrow_mut() = visible;
+        *self.hides_subtree.borrow_mut() = hides_subtree;
+        self.composite_properties_changed.set(true);
+        // Like the other composite-only changes, this is taken in by the next transform update.
+        self.mark_transform_state_dirty();
     }
 
-    pub fn do_for_all_tiles<F: FnMut(&Tile)>(&self, f: F) {
-        self.tile_grid.borrow().do_for_all_tiles(f);
+    /// Returns true if this layer is hidden. Its descendants may still be drawn; see
+    /// `subtree_is_hidden`.
+    pub fn is_hidden(&self) -> bool {
+        !*self.visible.borrow()
     }
 
-    pub fn update_transform_state(&self,
-                                  parent_transform: &Matrix4D<f32>,
-                                  parent_perspective: &Matrix4D<f32>,
-                                  parent_origin: &Point2D<f32>) {
-        let mut ts = self.transform_state.borrow_mut();
-        let rect_without_scroll = self.bounds.borrow()
-                                             .to_untyped()
-                                             .translate(parent_origin);
+    /// Returns true if this layer is hidden along with its descendants.
+    pub fn subtree_is_hidden(&self) -> bool {
+        self.is_hidden() && *self.hides_subtree.borrow()
+    }
 
-        ts.world_rect = rect_without_scroll.translate(&self.content_offset.borrow().to_untyped());
+    /// Returns the transform of this layer.
+    pub fn transform(&self) -> Matrix4D<f32> {
+        *self.transform.borrow()
+    }
 
-        let x0 = ts.world_rect.origin.x;
-        let y0 = ts.world_rect.origin.y;
+    /// Sets the transform of this layer. This only affects compositing and never causes the
+    /// content of the layer to be repainted.
+    pub fn set_transform(&self, transform: Matrix4D<f32>) {
+        *self.transform.borrow_mut() = transform;
+        self.composite_properties_changed.set(true);
+        self.mark_transform_state_dirty();
+    }
 
-        // Build world space transform
-        let local_transform = Matrix4D::identity()
-            .pre_translated(x0, y0, 0.0)
-            .pre_mul(&*self.transform.borrow())
-            .pre_translated(-x0, -y0, 0.0);
+    /// Returns the point about which the transform and perspective of this layer are applied.
+    /// See `transform_origin`.
+    pub fn transform_origin(&self) -> Point3D<f32> {
+        *self.transform_origin.borrow()
+    }
 
-        ts.final_transform = parent_perspective
-            .pre_mul(&local_transform)
-            .pre_mul(&parent_transform);
-        ts.screen_rect = project_rect_to_screen(&ts.world_rect, &ts.final_transform);
+    /// Sets the point about which the transform and perspective of this layer are applied. This
+    /// only affects compositing and never causes the content of the layer to be repainted.
+    pub fn set_transform_origin(&self, transform_origin: Point3D<f32>) {
+        *self.transform_origin.borrow_mut() = transform_origin;
+        self.composite_properties_changed.set(true);
+        self.mark_transform_state_dirty();
+    }
 
-        // TODO(gw): This is quite bogus. It's a hack to allow the paint task
-        // to avoid "optimizing" 3d layers with an incorrect clip rect.
-        // We should probably make the display list optimizer work with transforms!
-        // This layer is part of a 3d context if its concatenated transform
-        // is not identity, since 2d transforms don't get layers.
-        ts.has_transform = ts.final_transform != Matrix4D::identity();
+    /// Returns the perspective this layer applies to its children.
+    pub fn perspective(&self) -> Matrix4D<f32> {
+        *self.perspective.borrow()
+    }
 
-        // Build world space perspective transform
-        let perspective_transform = Matrix4D::identity()
-            .pre_translated(x0, y0, 0.0)
-            .pre_mul(&*self.perspective.borrow())
-            .pre_translated(-x0, -y0, 0.0);
+    /// Sets the perspective this layer applies to its children. This only affects compositing
+    /// and never causes the content of the layer to be repainted.
+    pub fn set_perspective(&self, perspective: Matrix4D<f32>) {
+        *self.perspective.borrow_mut() = perspective;
+        self.composite_properties_changed.set(true);
+        self.mark_transform_state_dirty();
+    }
 
-        for child in self.children().iter() {
-            child.update_transform_state(&ts.final_transform,
-                                         &perspective_transform,
-                                         &rect_without_scroll.origin);
+    /// Fixes this layer to the scene viewport at the given offset, or if None, places it relative
+    /// to its parent again. See `fixed_to_viewport`. This only affects compositing and never
+    /// causes the content of the layer to be repainted.
+    pub fn set_fixed_to_viewport(&self, fixed_offset: Option<TypedPoint2D<f32, LayerPixel>>) {
+        *self.fixed_to_viewport.borrow_mut() = fixed_offset.is_some();
+        if let Some(fixed_offset) = fixed_offset {
+            *self.fixed_offset.borrow_mut() = fixed_offset;
         }
+        self.composite_properties_changed.set(true);
+        self.mark_transform_state_dirty();
     }
 
-    /// Calculate the amount of memory used by this layer and all its children.
-    /// The memory may be allocated on the heap or in GPU memory.
-    pub fn get_memory_usage(&self) -> usize {
-        let size_of_children : usize = self.children().iter().map(|ref child| -> usize {
-            child.get_memory_usage()
-        }).sum();
-        size_of_children + self.tile_grid.borrow().get_memory_usage()
+    /// Returns true if this layer is fixed to the scene viewport.
+    pub fn is_fixed_to_viewport(&self) -> bool {
+        *self.fixed_to_viewport.borrow()
     }
-}
 
-/// A request from the compositor to the renderer for tiles that need to be (re)displayed.
-pub struct BufferRequest {
-    /// The rect in pixels that will be drawn to the screen
-    pub screen_rect: Rect<usize>,
+    /// Returns the world-space origin of the bounds of this layer while it is fixed to the
+    /// viewport. See `fixed_offset`.
+    pub fn fixed_offset(&self) -> TypedPoint2D<f32, LayerPixel> {
+        *self.fixed_offset.borrow()
+    }
 
-    /// The rect in page coordinates that this tile represents
-    pub page_rect: Rect<f32>,
+    /// Returns the boundaries of this layer in the coordinate system of the parent layer.
+    pub fn bounds(&self) -> TypedRect<f32, LayerPixel> {
+        *self.bounds.borrow()
+    }
 
-    /// The content age of that this BufferRequest corresponds to.
-    pub content_age: ContentAge,
+    /// Moves and resizes this layer. Unlike `resize_preserving_tiles`, this doesn't touch the
+    /// tiles of the layer.
+    pub fn set_bounds(&self, bounds: TypedRect<f32, LayerPixel>) {
+        *self.bounds.borrow_mut() = bounds;
+        self.mark_transform_state_dirty();
+    }
 
-    /// A cached NativeSurface that can be used to avoid allocating a new one.
-    pub native_surface: Option<NativeSurface>,
-}
+    /// Returns true if this layer clips its children to its boundaries.
+    pub fn masks_to_bounds(&self) -> bool {
+        *self.masks_to_bounds.borrow()
+    }
 
-impl BufferRequest {
-    pub fn new(screen_rect: Rect<usize>, page_rect: Rect<f32>, content_age: ContentAge)
-               -> BufferRequest {
-        BufferRequest {
-            screen_rect: screen_rect,
-            page_rect: page_rect,
-            content_age: content_age,
-            native_surface: None,
+    /// Sets whether this layer clips its children to its boundaries.
+    pub fn set_masks_to_bounds(&self, masks_to_bounds: bool) {
+        *self.masks_to_bounds.borrow_mut() = masks_to_bounds;
+        self.composite_properties_changed.set(true);
+        // Masking layers outside the viewport are culled by the transform update.
+        self.mark_transform_state_dirty();
+    }
+
+    /// Returns the content offset of this layer in unscaled layer pixels.
+    pub fn content_offset(&self) -> TypedPoint2D<f32, LayerPixel> {
+        *self.content_offset.borrow()
+    }
+
+    /// Sets the content offset of this layer as given, without clamping it. See
+    /// `set_content_offset_clamped`.
+    pub fn set_content_offset(&self, offset: TypedPoint2D<f32, LayerPixel>) {
+        self.precise_content_offset.set((offset.x as f64, offset.y as f64));
+        self.set_content_offset_if_changed(offset);
+    }
+
+    /// Sets the debugging overlays drawn for this layer. These are combined with the overlays
+    /// enabled for all layers on the `RenderContext`.
+    pub fn set_debug_options(&self, options: LayerDebugOptions) {
+        *self.debug_options.borrow_mut() = options;
+        self.damage_all();
+    }
+
+    /// Scrolls the content of this layer by the given delta, clamped as by
+    /// `set_content_offset_clamped`. The offset is accumulated in double precision, so any
+    /// number of small deltas add up to their exact sum. Writing to `content_offset` directly
+    /// resets the accumulated offset to the written value. Returns true if the content offset
+    /// changed.
+    pub fn scroll_by(&self, delta: TypedPoint2D<f32, LayerPixel>) -> bool {
+        let (mut x, mut y) = self.precise_content_offset();
+        let (min, max) = self.content_offset_range();
+        x = (x + delta.x as f64).max(min.x as f64).min(max.x as f64);
+        y = (y + delta.y as f64).max(min.y as f64).min(max.y as f64);
+        self.precise_content_offset.set((x, y));
+        self.set_content_offset_if_changed(TypedPoint2D::new(x as f32, y as f32))
+    }
+
+    /// Returns the content offset accumulated by `scroll_by` in double precision, or the value
+    /// of `content_offset` if it was written directly since.
+    fn precise_content_offset(&self) -> (f64, f64) {
+        let current_offset = *self.content_offset.borrow();
+        let (x, y) = self.precise_content_offset.get();
+        if x as f32 != current_offset.x || y as f32 != current_offset.y {
+            return (current_offset.x as f64, current_offset.y as f64);
         }
+        (x, y)
     }
-}
 
-pub struct LayerBuffer {
-    /// The native surface which can be shared between threads or processes. On Mac this is an
-    /// `IOSurface`; on Linux this is an X Pixmap; on Android this is an `EGLImageKHR`.
-    pub native_surface: NativeSurface,
+    /// Returns the content offset of this layer rounded to whole device pixels at the given
+    /// scale, for drawing scrolled content at pixel-aligned positions. The offset accumulated by
+    /// `scroll_by` is rounded rather than `content_offset`, so that the rounding doesn't flip
+    /// back and forth as single precision errors pile up.
+    pub fn snapped_content_offset(&self, scale: ScaleFactor<f32, LayerPixel, DevicePixel>)
+                                  -> TypedPoint2D<f32, LayerPixel> {
+        let (x, y) = self.precise_content_offset();
+        let scale = scale.get() as f64;
+        if !(scale > 0.0) {
+            return *self.content_offset.borrow();
+        }
+        TypedPoint2D::new(((x * scale).round() / scale) as f32,
+                          ((y * scale).round() / scale) as f32)
+    }
 
-    /// The rect in the containing RenderLayer that this represents.
-    pub rect: Rect<f32>,
+    /// Sets the content offset of this layer, clamped so that its content always covers the
+    /// bounds of the layer. The content is the union of the bounds of the children that aren't
+    /// fixed to the viewport, or `content_size` if there are none; along an axis where it is
+    /// smaller than the layer, the offset is zero. This only moves the layer and its children;
+    /// tiles are revalidated by the next call to `get_buffer_requests` as usual. Returns true if
+    /// the content offset changed.
+    pub fn set_content_offset_clamped(&self, offset: TypedPoint2D<f32, LayerPixel>) -> bool {
+        let (min, max) = self.content_offset_range();
+        let offset = TypedPoint2D::new(offset.x.max(min.x).min(max.x),
+                                       offset.y.max(min.y).min(max.y));
+        self.precise_content_offset.set((offset.x as f64, offset.y as f64));
+        self.set_content_offset_if_changed(offset)
+    }
 
-    /// The rect in pixels that will be drawn to the screen.
-    pub screen_pos: Rect<usize>,
+    fn set_content_offset_if_changed(&self, offset: TypedPoint2D<f32, LayerPixel>) -> bool {
+        if *self.content_offset.borrow() == offset {
+            return false;
+        }
+        *self.content_offset.borrow_mut() = offset;
+        self.mark_transform_state_dirty();
+        true
+    }
 
-    /// The scale at which this tile is rendered
-    pub resolution: f32,
+    /// Returns the smallest and largest content offsets that keep the content of this layer
+    /// covering its bounds. See `set_content_offset_clamped`.
+    fn content_offset_range(&self)
+                            -> (TypedPoint2D<f32, LayerPixel>, TypedPoint2D<f32, LayerPixel>) {
+        let size = self.bounds.borrow().size;
+        let content_rect = {
+            let children = self.children();
+            let mut child_bounds = children.iter()
+                                           .filter(|child| !child.is_fixed_to_viewport())
+                                           .map(|child| *child.bounds.borrow());
+            match child_bounds.next() {
+                Some(first) => child_bounds.fold(first, |union, bounds| union.union(&bounds)),
+                None => {
+                    TypedRect::new(TypedPoint2D::zero(),
+                                   self.content_size.borrow().unwrap_or(size))
+                }
+            }
+        };
 
-    /// Whether or not this buffer was painted with the CPU rasterization.
-    pub painted_with_cpu: bool,
+        let range = |content_min: f32, content_max: f32, size: f32| {
+            if content_max - content_min < size {
+                (0.0, 0.0)
+            } else {
+                (size - content_max, -content_min)
+            }
+        };
+        let (min_x, max_x) = range(content_rect.min_x(), content_rect.max_x(), size.width);
+        let (min_y, max_y) = range(content_rect.min_y(), content_rect.max_y(), size.height);
+        (TypedPoint2D::new(min_x, min_y), TypedPoint2D::new(max_x, max_y))
+    }
 
-    /// The content age of that this buffer request corresponds to.
-    pub content_age: ContentAge,
-}
+    /// Returns the content inset of this layer, in layer pixels.
+    pub fn content_inset(&self) -> SideOffsets2D<f32> {
+        *self.content_inset.borrow()
+    }
 
-impl LayerBuffer {
-    /// Returns the amount of memory used by the tile
-    pub fn get_mem(&self) -> usize {
-        self.native_surface.get_memory_usage()
+    /// Sets the content inset of this layer. Negative insets are clamped to zero.
+    pub fn set_content_inset(&self, inset: SideOffsets2D<f32>) {
+        if inset.top < 0.0 || inset.right < 0.0 || inset.bottom < 0.0 || inset.left < 0.0 {
+            debug!("Clamping negative content inset to zero");
+        }
+        *self.content_inset.borrow_mut() = SideOffsets2D::new(inset.top.max(0.0),
+                                                              inset.right.max(0.0),
+                                                              inset.bottom.max(0.0),
+                                                              inset.left.max(0.0));
+        self.damage_all();
     }
 
-    /// Returns true if the tile is displayable at the given scale
-    pub fn is_valid(&self, scale: f32) -> bool {
-        (self.resolution - scale).abs() < 1.0e-6
+    /// Records that the pixels of the given area of this layer changed, relative to the top
+    /// left corner of the layer. See `Scene::accumulate_damage`.
+    pub fn add_damage(&self, rect: TypedRect<f32, LayerPixel>) {
+        let damage = match self.damage.get() {
+            Some(damage) => damage.union(&rect),
+            None => rect,
+        };
+        self.damage.set(Some(damage));
     }
 
-    /// Returns the Size2D of the tile
-    pub fn get_size_2d(&self) -> Size2D<usize> {
-        self.screen_pos.size
+    fn damage_all(&self) {
+        self.add_damage(TypedRect::new(TypedPoint2D::zero(), self.bounds.borrow().size));
     }
 
-    /// Marks the layer buffer as not leaking. See comments on
-    /// `NativeSurfaceMethods::mark_wont_leak` for how this is used.
-    pub fn mark_wont_leak(&mut self) {
-        self.native_surface.mark_wont_leak()
+    /// Returns the area of this layer that changed since the last call, relative to the top left
+    /// corner of the layer, and clears it.
+    pub fn take_damage(&self) -> Option<TypedRect<f32, LayerPixel>> {
+        let damage = self.damage.get();
+        self.damage.set(None);
+        damage
     }
 
-    /// Destroys the layer buffer. Painting task only.
-    pub fn destroy(self, display: &NativeDisplay) {
-        let mut this = self;
-        this.native_surface.destroy(display)
+    /// Returns true if the transform state of this layer was invalidated since the last call,
+    /// for instance by a change of its transform, opacity, or children, and clears the flag.
+    /// Such changes can move content anywhere on the screen.
+    pub fn take_composite_damage(&self) -> bool {
+        let damaged = self.composite_damaged.get();
+        self.composite_damaged.set(false);
+        damaged
     }
-}
 
-/// A set of layer buffers. This is an atomic unit used to switch between the front and back
-/// buffers.
-pub struct LayerBufferSet {
-    pub buffers: Vec<Box<LayerBuffer>>
-}
+    /// Returns the area of this layer in which content is drawn, in global coordinates but not
+    /// transformed. This is the world rect of the layer shrunk by its content inset.
+    pub fn content_world_rect(&self) -> TypedRect<f32, LayerPixel> {
+        inset_rect(&self.transform_state.borrow().world_rect, &*self.content_inset.borrow())
+    }
 
-impl LayerBufferSet {
-    /// Notes all buffer surfaces will leak if not destroyed via a call to `destroy`.
-    pub fn mark_will_leak(&mut self) {
-        for buffer in &mut self.buffers {
-            buffer.native_surface.mark_will_leak()
+    /// Returns the clip rect this layer imposes on what is drawn with it, given the clip rect of
+    /// its parent context: the intersection of both if this layer masks to its bounds, or the
+    /// parent clip rect otherwise. An empty rect means that nothing is drawn.
+    pub fn mask_clip_rect(&self, parent_clip_rect: Option<TypedRect<f32, ScreenPixel>>)
+                          -> Option<TypedRect<f32, ScreenPixel>> {
+        // TODO(gw): This doesn't work for iframes that are transformed.
+        if !*self.masks_to_bounds.borrow() {
+            return parent_clip_rect;
         }
+
+        let layer_clip = match self.transform_state.borrow().screen_rect.as_ref() {
+            Some(screen_rect) => screen_rect.rect,
+            None => return Some(TypedRect::zero()), // Layer is entirely clipped away.
+        };
+
+        match parent_clip_rect {
+            Some(parent_clip_rect) => match layer_clip.intersection(&parent_clip_rect) {
+                Some(intersected_clip) => Some(intersected_clip),
+                None => Some(TypedRect::zero()), // No intersection.
+            },
+            None => Some(layer_clip),
+        }
+    }
+
+    /// Returns the rounded clip that the children of this layer are drawn with, given the one
+    /// this layer is drawn with. See `corner_radii`.
+    pub fn mask_rounded_clip(&self, parent_rounded_clip: Option<RoundedClip>)
+                             -> Option<RoundedClip> {
+        if !*self.masks_to_bounds.borrow() {
+            return parent_rounded_clip;
+        }
+        let radii = match *self.corner_radii.borrow() {
+            Some(radii) if radii.iter().any(|radius| *radius > 0.0) => radii,
+            _ => return parent_rounded_clip,
+        };
+
+        let ts = self.transform_state.borrow();
+        let screen_rect = match ts.screen_rect {
+            Some(ref screen_rect) => screen_rect.rect,
+            None => return parent_rounded_clip,
+        };
+        if !is_axis_aligned_2d_transform(&ts.final_transform) {
+            debug!("Not rounding the clip of a layer without an axis-aligned 2D transform");
+            return parent_rounded_clip;
+        }
+
+        // Corners stay circular, so scale the radii by the smaller of the two scale factors.
+        let scale = ts.final_transform.m11.min(ts.final_transform.m22);
+        Some(RoundedClip {
+            rect: screen_rect,
+            radii: [radii[0].max(0.0) * scale,
+                    radii[1].max(0.0) * scale,
+                    radii[2].max(0.0) * scale,
+                    radii[3].max(0.0) * scale],
+        })
+    }
+
+    /// Returns true if this layer draws its children in a nested rendering context, clipped by
+    /// `mask_clip_rect`.
+    pub fn establishes_render_context(&self) -> bool {
+        self.establishes_3d_context && !self.children.borrow().is_empty()
+    }
+
+    /// Converts a screen-space clip rect to the world space of this layer. Returns None if the
+    /// clip rect doesn't stay a rect in world space, because the layer has a 3D transform or is
+    /// rotated or skewed; such layers must be clipped in screen space with `shader_clip`.
+    pub fn world_clip_rect(&self, clip_rect: Option<TypedRect<f32, ScreenPixel>>)
+                           -> Option<Rect<f32>> {
+        clip_rect.and_then(|cr| {
+            let ts = self.transform_state.borrow();
+            if !clip_rect_stays_rect(&ts.final_transform) {
+                None
+            } else {
+                // If the transform only translates and scales, invert it and back-transform
+                // the clip rect into world space. A layer whose transform can't be inverted
+                // covers next to nothing, so it is left unclipped rather than clipped to a rect
+                // of NaNs; layers scaled to exactly nothing were already treated as clipped by
+                // the transform update.
+                ts.inverse_transform.map(|transform| {
+                    transform.to_2d().transform_rect(&cr.to_untyped())
+                })
+            }
+        })
+    }
+
+    /// Returns the rounded clip to draw this layer with, given its screen-space clip rect and the
+    /// rounded clip of its masking ancestors. If `world_clip_rect` can't map the clip rect to the
+    /// world space of this layer and the layer isn't entirely within it, the clip rect is
+    /// applied by the shader in screen space as well, as a rounded clip with square corners.
+    pub fn shader_clip(&self,
+                       clip_rect: Option<TypedRect<f32, ScreenPixel>>,
+                       rounded_clip: Option<RoundedClip>)
+                       -> Option<RoundedClip> {
+        let clip_rect = match clip_rect {
+            Some(clip_rect) => clip_rect,
+            None => return rounded_clip,
+        };
+        {
+            let ts = self.transform_state.borrow();
+            if clip_rect_stays_rect(&ts.final_transform) {
+                return rounded_clip;
+            }
+            if let Some(ref screen_rect) = ts.screen_rect {
+                if clip_rect.contains_rect(&screen_rect.rect) {
+                    return rounded_clip;
+                }
+            }
+        }
+
+        let rounded_clip = match rounded_clip {
+            Some(rounded_clip) => rounded_clip,
+            None => return Some(RoundedClip { rect: clip_rect, radii: [0.0; 4] }),
+        };
+        let rect = match rounded_clip.rect.intersection(&clip_rect) {
+            Some(rect) => rect,
+            None => return Some(RoundedClip { rect: TypedRect::zero(), radii: [0.0; 4] }),
+        };
+
+        // Corners cut off by the clip rect are square.
+        let outer = rounded_clip.rect;
+        let keep = |on_x_edge: bool, on_y_edge: bool, radius: f32| {
+            if on_x_edge && on_y_edge { radius } else { 0.0 }
+        };
+        let (left, right) = (rect.min_x() == outer.min_x(), rect.max_x() == outer.max_x());
+        let (top, bottom) = (rect.min_y() == outer.min_y(), rect.max_y() == outer.max_y());
+        Some(RoundedClip {
+            rect: rect,
+            radii: [keep(left, top, rounded_clip.radii[0]),
+                    keep(right, top, rounded_clip.radii[1]),
+                    keep(right, bottom, rounded_clip.radii[2]),
+                    keep(left, bottom, rounded_clip.radii[3])],
+        })
+    }
+
+    /// Converts a point on the screen to the coordinates of this layer, relative to the top left
+    /// corner of its world rect, according to the current transform state. The point is mapped
+    /// onto the plane of the layer, so perspective is taken into account.
+    ///
+    /// Returns None if the transform of the layer can't be inverted, or if the point maps to a
+    /// part of the plane of the layer that lies behind the eye.
+    pub fn convert_point_from_screen(&self, point: Point2D<f32>) -> Option<Point2D<f32>> {
+        let ts = self.transform_state.borrow();
+        let world_origin = ts.world_rect.origin.to_untyped();
+        ts.inverse_transform.as_ref().and_then(|inverse| unproject_point(inverse, &point)).map(|p| {
+            Point2D::new(p.x - world_origin.x, p.y - world_origin.y)
+        })
+    }
+
+    /// Returns the world-space rect in which this layer draws its background and tiles, given a
+    /// world-space clip rect. The rect is empty if nothing of the layer is drawn.
+    pub fn clipped_content_rect(&self, clip_rect: Option<Rect<f32>>) -> Rect<f32> {
+        let content_rect = self.content_world_rect().to_untyped();
+        clip_rect.map_or(content_rect, |clip_rect| {
+            match clip_rect.intersection(&content_rect) {
+                Some(layer_rect) => layer_rect,
+                None => Rect::zero(),
+            }
+        })
+    }
+
+    /// Returns true if the transform state of this layer is out of date, because it changed
+    /// since the last update or because a culled update skipped it during a gesture. The
+    /// descendants of a skipped layer are out of date as well.
+    pub fn has_dirty_transform_state(&self) -> bool {
+        self.transform_state_dirty.get() || self.transform_state_skipped.get() ||
+            self.has_unflushed_transform_inputs()
+    }
+
+    fn transform_inputs(&self) -> TransformInputs {
+        TransformInputs {
+            transform: *self.transform.borrow(),
+            perspective: *self.perspective.borrow(),
+            transform_origin: *self.transform_origin.borrow(),
+            bounds: *self.bounds.borrow(),
+            content_offset: *self.content_offset.borrow(),
+            fixed_to_viewport: *self.fixed_to_viewport.borrow(),
+            fixed_offset: *self.fixed_offset.borrow(),
+            masks_to_bounds: *self.masks_to_bounds.borrow(),
+            opacity: *self.opacity.borrow(),
+        }
+    }
+
+    /// Returns true if a property the transform state depends on was written directly since the
+    /// last update, bypassing the setters.
+    fn has_unflushed_transform_inputs(&self) -> bool {
+        self.flushed_transform_inputs.get() != Some(self.transform_inputs())
+    }
+
+    /// Returns true if the given tile of this layer intersects the given screen-space rect.
+    pub fn tile_intersects_screen_rect(&self,
+                                       tile_index: &Point2D<usize>,
+                                       rect: &TypedRect<f32, ScreenPixel>,
+                                       scale: ScaleFactor<f32, LayerPixel, DevicePixel>)
+                                       -> bool {
+        let ts = self.transform_state.borrow();
+        self.tile_grid.borrow().tile_intersects_rect(tile_index,
+                                                     &(*rect * screen_to_device_scale(scale)),
+                                                     self.bounds.borrow().size * scale,
+                                                     &ts.world_rect.origin,
+                                                     scale,
+                                                     &ts.final_transform)
+    }
+
+    /// Deletes the textures of the tiles of this layer that lie outside the given screen-space
+    /// viewport, to save GPU memory. Their buffers are kept, so the textures are recreated from
+    /// them when the tiles come back into view, without repainting.
+    pub fn evict_offscreen_textures(&self,
+                                    viewport: &TypedRect<f32, ScreenPixel>,
+                                    scale: ScaleFactor<f32, LayerPixel, DevicePixel>) {
+        let ts = self.transform_state.borrow();
+        self.tile_grid.borrow_mut().evict_textures_outside_rect(
+            *viewport * screen_to_device_scale(scale),
+            &ts.world_rect.origin,
+            scale,
+            &ts.final_transform,
+            self.bounds.borrow().size * scale);
+    }
+
+    /// Throws out the tiles of this layer that lie outside the given screen-space viewport, and
+    /// returns their buffers along with the other unused buffers of the layer. The tiles are
+    /// requested again when they come back into view.
+    pub fn collect_offscreen_buffers(&self,
+                                     viewport: &TypedRect<f32, ScreenPixel>,
+                                     scale: ScaleFactor<f32, LayerPixel, DevicePixel>)
+                                     -> Vec<Box<LayerBuffer>> {
+        let ts = self.transform_state.borrow();
+        let mut tile_grid = self.tile_grid.borrow_mut();
+        tile_grid.mark_tiles_outside_of_rect_as_unused(*viewport * screen_to_device_scale(scale),
+                                                       &ts.world_rect.origin,
+                                                       scale,
+                                                       &ts.final_transform,
+                                                       self.bounds.borrow().size * scale);
+        tile_grid.take_unused_buffers()
+    }
+
+    /// Sets whether buffer request passes throw out the tiles of this layer outside their
+    /// viewport and prefetch margin, which is the default. Embedders that disable this, for
+    /// instance to keep tiles while an overscroll briefly moves the viewport past the content
+    /// edge, call `evict_tiles_outside` once the viewport has settled.
+    pub fn set_evicts_tiles_on_request(&self, evicts_tiles_on_request: bool) {
+        self.tile_grid.borrow_mut().set_evicts_on_request(evicts_tiles_on_request);
+    }
+
+    /// Throws out the tiles of this layer that lie outside the given screen-space viewport grown
+    /// by `keep_margin` screen pixels. Their buffers are kept with the unused buffers of the layer
+    /// until they are collected.
+    pub fn evict_tiles_outside(&self,
+                               viewport: &TypedRect<f32, ScreenPixel>,
+                               keep_margin: f32,
+                               scale: ScaleFactor<f32, LayerPixel, DevicePixel>) {
+        let ts = self.transform_state.borrow();
+        self.tile_grid.borrow_mut().evict_tiles_outside(*viewport * screen_to_device_scale(scale),
+                                                        keep_margin * scale.get(),
+                                                        &ts.world_rect.origin,
+                                                        scale,
+                                                        &ts.final_transform,
+                                                        self.bounds.borrow().size * scale);
+    }
+
+    /// Calls the given function with the tile at the given index and whether the last request
+    /// pass skipped it because it was hidden behind opaque layers. The tile is None if the layer
+    /// has no tile at that index.
+    pub fn with_tile<F, R>(&self, tile_index: &Point2D<usize>, f: F) -> R
+                           where F: FnOnce(Option<&Tile>, bool) -> R {
+        let tile_grid = self.tile_grid.borrow();
+        f(tile_grid.tiles.get(tile_index), tile_grid.was_occluded(tile_index))
+    }
+
+    /// Shrinks a rect used in the buffer request pass to the screen-space area in which this layer
+    /// draws content. Returns None if no content of this layer can be visible within it.
+    fn clip_rect_to_content_inset(&self, rect: TypedRect<f32, ScreenPixel>)
+                                  -> Option<TypedRect<f32, ScreenPixel>> {
+        let inset = *self.content_inset.borrow();
+        if inset == SideOffsets2D::zero() {
+            return Some(rect);
+        }
+
+        let content_rect = project_rect_to_screen(&self.content_world_rect(),
+                                                  &self.transform_state.borrow().final_transform);
+        match content_rect {
+            Some(content_rect) => rect.intersection(&content_rect.rect),
+            None => None,
+        }
+    }
+
+    /// Returns the screen-space rect that this layer is known to cover with opaque pixels, if it
+    /// can hide the layers behind it. Only visible, untransformed or axis-aligned layers that
+    /// are fully opaque qualify.
+    pub fn opaque_screen_rect(&self) -> Option<TypedRect<f32, ScreenPixel>> {
+        if self.is_hidden() || !*self.contents_opaque.borrow() ||
+                self.transform_state.borrow().effective_opacity < 1.0 {
+            return None;
+        }
+
+        let final_transform = self.transform_state.borrow().final_transform;
+        if !is_axis_aligned_2d_transform(&final_transform) {
+            return None;
+        }
+        project_rect_to_screen(&self.content_world_rect(), &final_transform).map(|screen_rect| {
+            screen_rect.rect
+        })
+    }
+
+    /// Returns the screen rect of this layer if the layers drawn in front of it can hide it,
+    /// which they only do for layers with an axis-aligned 2D transform. See `occlude`.
+    pub fn occludable_screen_rect(&self) -> Option<TypedRect<f32, ScreenPixel>> {
+        let ts = self.transform_state.borrow();
+        match ts.screen_rect {
+            Some(ref screen_rect) if is_axis_aligned_2d_transform(&ts.final_transform) => {
+                Some(screen_rect.rect)
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks this layer against the opaque region of the layers drawn in front of it, which must
+    /// have been visited first, from front to back. Returns true if the region hides this layer
+    /// entirely. Otherwise, the part of the screen that this layer hides when drawn with the
+    /// given clips is added to the region. A layer that draws a nested context, or whose
+    /// transform isn't axis-aligned 2D, is never hidden and hides nothing.
+    pub fn occlude(&self,
+                   draws_nested_context: bool,
+                   clip_rect: Option<TypedRect<f32, ScreenPixel>>,
+                   rounded_clip: Option<RoundedClip>,
+                   opaque_region: &mut Vec<TypedRect<f32, ScreenPixel>>)
+                   -> bool {
+        if draws_nested_context {
+            return false;
+        }
+        let screen_rect = match self.occludable_screen_rect() {
+            Some(screen_rect) => screen_rect,
+            None => return false,
+        };
+        if is_hidden_by(&screen_rect, opaque_region) {
+            return true;
+        }
+
+        // The opaque rect of a layer only hides what is behind it if the layer is drawn as is:
+        // not filtered, not clipped by rounded corners, and with no tiles missing.
+        let opaque_rect = match self.opaque_screen_rect() {
+            Some(opaque_rect) => opaque_rect,
+            None => return false,
+        };
+        if self.filter.borrow().is_some() || self.shader_clip(clip_rect, rounded_clip).is_some() ||
+                !self.is_fully_painted() {
+            return false;
+        }
+        let opaque_rect = match clip_rect {
+            Some(clip_rect) => opaque_rect.intersection(&clip_rect),
+            None => Some(opaque_rect),
+        };
+        if let Some(opaque_rect) = opaque_rect {
+            opaque_region.push(opaque_rect);
+        }
+        false
+    }
+
+    /// Returns buffer requests inside the given dirty rect, and simultaneously throws out tiles
+    /// outside the given viewport rect unless `set_evicts_tiles_on_request` disabled it. Both
+    /// rects are in screen space, that is, after this layer's transform has been applied but
+    /// before the scene scale.
+    pub fn get_buffer_requests(&self,
+                               rect_in_layer: TypedRect<f32, ScreenPixel>,
+                               viewport_in_layer: TypedRect<f32, ScreenPixel>,
+                               scale: ScaleFactor<f32, LayerPixel, DevicePixel>)
+                               -> Vec<BufferRequest> {
+        let mut requests = Vec::new();
+        self.get_buffer_requests_into(rect_in_layer,
+                                      viewport_in_layer,
+                                      scale,
+                                      &[],
+                                      RequestOrdering::ColumnMajor,
+                                      &mut requests);
+        requests
+    }
+
+    /// Like `get_buffer_requests`, but appends the requests to a vector supplied by the caller,
+    /// which can be reused across frames to avoid allocating. No requests are made for tiles
+    /// hidden behind the given screen-space occluders, and tiles that are partially hidden carry
+    /// the visible part in their request. The requests appended for this layer are sorted by
+    /// priority, and in the given order within each priority.
+    pub fn get_buffer_requests_into(&self,
+                                    rect_in_layer: TypedRect<f32, ScreenPixel>,
+                                    viewport_in_layer: TypedRect<f32, ScreenPixel>,
+                                    scale: ScaleFactor<f32, LayerPixel, DevicePixel>,
+                                    occluders: &[TypedRect<f32, ScreenPixel>],
+                                    ordering: RequestOrdering,
+                                    requests: &mut Vec<BufferRequest>) {
+        let update = self.compute_buffer_requests(rect_in_layer,
+                                                  viewport_in_layer,
+                                                  scale,
+                                                  occluders,
+                                                  ordering);
+        self.apply_buffer_request_update_into(update, requests);
+    }
+
+    /// Works out the buffer requests that `get_buffer_requests_into` would make with the same
+    /// arguments, without changing the layer. The result must be applied with
+    /// `apply_buffer_request_update` before anything else changes the layer. This only reads the
+    /// layer, so embedders can compute the updates of many layers in parallel and apply them
+    /// afterwards. Returns None for layers that make no requests, such as nine-patches.
+    pub fn compute_buffer_requests(&self,
+                                   rect_in_layer: TypedRect<f32, ScreenPixel>,
+                                   viewport_in_layer: TypedRect<f32, ScreenPixel>,
+                                   scale: ScaleFactor<f32, LayerPixel, DevicePixel>,
+                                   occluders: &[TypedRect<f32, ScreenPixel>],
+                                   ordering: RequestOrdering)
+                                   -> Option<TileGridUpdate> {
+        // The buffer of a nine-patch is supplied up front and stretched by the compositor.
+        if self.is_nine_patch() {
+            return None;
+        }
+
+        // The prefetch margin grows the dirty rect before the scale is applied, so that it
+        // covers the same content at any scale. The content inset then shrinks both rects for
+        // this layer only; an empty rect still lets the tile grid throw out tiles that are no
+        // longer visible.
+        let prefetch_margin = self.prefetch_margin.borrow().max(0.0);
+        let rect_in_layer = inflate_rect(&rect_in_layer, prefetch_margin);
+        let rect_in_layer = self.clip_rect_to_content_inset(rect_in_layer)
+                                .unwrap_or(TypedRect::zero());
+        let viewport_in_layer = self.clip_rect_to_content_inset(viewport_in_layer)
+                                    .unwrap_or(TypedRect::zero());
+
+        let screen_scale = screen_to_device_scale(scale);
+        let transform_state = self.transform_state.borrow();
+        Some(self.tile_grid.borrow().compute_buffer_requests(rect_in_layer * screen_scale,
+                                                             viewport_in_layer * screen_scale,
+                                                             self.bounds.borrow().size * scale,
+                                                             &transform_state.world_rect.origin,
+                                                             scale,
+                                                             &transform_state.final_transform,
+                                                             occluders.iter().map(|occluder| {
+                                                                 *occluder * screen_scale
+                                                             }),
+                                                             prefetch_margin * scale.get(),
+                                                             *self.content_age.borrow(),
+                                                             ordering))
+    }
+
+    /// Applies an update computed by `compute_buffer_requests`, and returns its requests.
+    pub fn apply_buffer_request_update(&self, update: Option<TileGridUpdate>)
+                                       -> Vec<BufferRequest> {
+        let mut requests = Vec::new();
+        self.apply_buffer_request_update_into(update, &mut requests);
+        requests
+    }
+
+    /// Like `apply_buffer_request_update`, but appends the requests to a vector supplied by the
+    /// caller.
+    pub fn apply_buffer_request_update_into(&self,
+                                            update: Option<TileGridUpdate>,
+                                            requests: &mut Vec<BufferRequest>) {
+        if let Some(update) = update {
+            self.tile_grid.borrow_mut().apply_update_into(update, requests);
+        }
+    }
+
+    pub fn resize(&self, new_size: TypedSize2D<f32, LayerPixel>) {
+        self.bounds.borrow_mut().size = new_size;
+        self.mark_transform_state_dirty();
+    }
+
+    /// Resizes this layer, keeping the tiles whose buffers still cover them. Only edge tiles that
+    /// grew and newly exposed tiles are requested again; the content age is not changed. See
+    /// `TileGrid::handle_layer_size_change`.
+    pub fn resize_preserving_tiles(&self,
+                                   new_size: TypedSize2D<f32, LayerPixel>,
+                                   scale: ScaleFactor<f32, LayerPixel, DevicePixel>) {
+        self.resize(new_size);
+        self.tile_grid.borrow_mut().handle_layer_size_change(new_size * scale, scale);
+    }
+
+    /// Switches this layer between tiled content and a single buffer covering its whole bounds,
+    /// which avoids the per-tile overhead for modest layers whose content changes wholesale. When
+    /// the mode changes, existing content is discarded and requested again, as it is when a
+    /// single buffer layer is resized. Enabling fails if the layer is larger than
+    /// `max_texture_size` at the given scale; in that case, and if the layer later grows beyond
+    /// that size, the layer stays tiled.
+    pub fn set_single_buffer_mode(&self,
+                                  enabled: bool,
+                                  max_texture_size: usize,
+                                  scale: ScaleFactor<f32, LayerPixel, DevicePixel>)
+                                  -> Result<(), LayerTooLargeError> {
+        let mut tile_grid = self.tile_grid.borrow_mut();
+        if !enabled {
+            tile_grid.set_single_buffer_mode(None);
+            return Ok(());
+        }
+
+        let size = self.bounds.borrow().size * scale;
+        if size.width > max_texture_size as f32 || size.height > max_texture_size as f32 {
+            warn!("Refusing single buffer mode for a layer larger than the maximum texture size.");
+            return Err(LayerTooLargeError);
+        }
+
+        tile_grid.set_single_buffer_mode(Some(max_texture_size));
+        Ok(())
+    }
+
+    /// Changes the size of the tiles of this layer, for instance when the device pixel ratio
+    /// changes. All tiles are requested again with the new size at the current content age, and
+    /// their old buffers can be collected with `collect_unused_buffers`.
+    pub fn set_tile_size(&self, new_size: usize) {
+        self.tile_size.set(new_size);
+        self.tile_grid.borrow_mut().set_tile_size(new_size);
+    }
+
+    /// Limits the size of the tiles of this layer, usually to `RenderContext::max_texture_size`,
+    /// since larger tiles can't be displayed. The compositor sets this when it creates textures
+    /// for the layer; setting it up front avoids requesting tiles that are thrown out at that
+    /// point. `tile_size` keeps the requested size.
+    pub fn set_max_tile_size(&self, max_tile_size: Option<usize>) {
+        self.tile_grid.borrow_mut().set_max_tile_size(max_tile_size);
+    }
+
+    /// Returns the size of the tiles of this layer in device pixels, which is `tile_size`
+    /// clamped to the maximum tile size.
+    pub fn effective_tile_size(&self) -> usize {
+        self.tile_grid.borrow().tile_size()
+    }
+
+    /// Enables or disables double buffering of the tiles of this layer, for layers whose content
+    /// changes rapidly. Double-buffered tiles keep their previous buffer and hand its surface to
+    /// the painter with the next request, so that the painter never draws into a surface that is
+    /// being displayed. This doubles the memory used by the tiles of the layer.
+    pub fn set_double_buffered(&self, double_buffered: bool) {
+        self.tile_grid.borrow_mut().set_double_buffered(double_buffered);
+    }
+
+    /// Adds a buffer sent by the painter. Buffers painted at a different scale than the one of
+    /// the last request pass, within the tolerance of this layer or `DEFAULT_RESOLUTION_EPSILON`,
+    /// are not displayed; they are put with the unused buffers and their tile is requested again.
+    /// `add_buffer_checked` also keeps a tile from being requested forever when the painter
+    /// never gets the scale right.
+    pub fn add_buffer(&self, tile: Box<LayerBuffer>) {
+        let epsilon = self.resolution_epsilon.get().unwrap_or(DEFAULT_RESOLUTION_EPSILON);
+        self.damage_buffer_rect(&tile);
+        self.tile_grid.borrow_mut().add_buffer(tile, Some(epsilon));
+    }
+
+    /// Adds a buffer sent by the painter regardless of its resolution. This is for callers that
+    /// have checked the resolution themselves, like `Scene::add_buffer`.
+    pub fn add_buffer_at_any_resolution(&self, tile: Box<LayerBuffer>) {
+        self.damage_buffer_rect(&tile);
+        self.tile_grid.borrow_mut().add_buffer(tile, None);
+    }
+
+    /// Adds a buffer sent by the painter if it was painted at the effective scale of this layer
+    /// for the given scene scale, within the relative tolerance of this layer or
+    /// `scene_epsilon`. Otherwise the buffer is put with the unused buffers of the layer and its
+    /// tile is requested again. Returns true if the buffer was added.
+    ///
+    /// If a tile keeps receiving buffers at the wrong scale without its content changing, which
+    /// happens when the painter computes the scale slightly differently, the buffer is added
+    /// anyway and an error is logged, at most once every `RESOLUTION_LOOP_FRAME_WINDOW` frames
+    /// since the frame stored in `last_loop_report`.
+    pub fn add_buffer_checked(&self,
+                              tile: Box<LayerBuffer>,
+                              scene_scale: ScaleFactor<f32, LayerPixel, DevicePixel>,
+                              scene_epsilon: f32,
+                              frame: FrameIndex,
+                              last_loop_report: &Cell<Option<FrameIndex>>)
+                              -> bool {
+        let scale = self.effective_scale(scene_scale).get();
+        let epsilon = self.resolution_epsilon.get().unwrap_or(scene_epsilon);
+        if tile.is_valid_with_epsilon(scale, epsilon) {
+            self.add_buffer_at_any_resolution(tile);
+            return true;
+        }
+
+        let tile = match self.reject_buffer(tile, frame) {
+            Some(tile) => tile,
+            None => return false,
+        };
+
+        let should_report = match last_loop_report.get() {
+            Some(last_report) => {
+                frame.0.wrapping_sub(last_report.0) >= RESOLUTION_LOOP_FRAME_WINDOW
+            }
+            None => true,
+        };
+        if should_report {
+            error!("Tile kept receiving buffers painted at scale {} instead of {}; accepting the \
+                    buffer to stop repainting it",
+                   tile.resolution,
+                   scale);
+            last_loop_report.set(Some(frame));
+        }
+        self.add_buffer_at_any_resolution(tile);
+        true
+    }
+
+    /// Exports the buffers displayed by the tiles of this layer, so that a compositor started
+    /// after this one goes away can import them into the layer standing for this one. Memory
+    /// surfaces are copied into the snapshot; platform surfaces are exported by ID, and the
+    /// imported surfaces share them, so only one of the two layers may destroy them. Buffers
+    /// whose surfaces can't outlive this process, and back buffers, are left out.
+    pub fn export_tile_cache(&self) -> TileCacheSnapshot {
+        let tile_grid = self.tile_grid.borrow();
+        TileCacheSnapshot {
+            tile_size: tile_grid.tile_size(),
+            layer_size: self.bounds.borrow().size,
+            tiles: tile_grid.export_tiles(),
+        }
+    }
+
+    /// Gives the tiles of this layer the buffers of a snapshot taken with `export_tile_cache`.
+    /// Like painted tiles, the tiles are only requested again once their buffers are older than
+    /// the content age of the layer, and their textures are created when they are first drawn. If
+    /// the tile size or the size of the bounds of this layer differ from those of the snapshot,
+    /// the buffers would not fit, and are all put with the unused buffers of this layer instead.
+    /// Surfaces that can't be decoded on this platform are skipped. Returns the number of tiles
+    /// given a buffer.
+    pub fn import_tile_cache(&self, snapshot: &TileCacheSnapshot) -> usize {
+        let mut buffers = vec!();
+        for tile in &snapshot.tiles {
+            let native_surface = match NativeSurface::deserialize(&tile.native_surface) {
+                Some(native_surface) => native_surface,
+                None => {
+                    warn!("Not importing a tile whose surface can't be decoded.");
+                    continue;
+                }
+            };
+            buffers.push((tile.tile_index, Box::new(LayerBuffer {
+                native_surface: native_surface,
+                rect: tile.rect,
+                screen_pos: tile.screen_pos,
+                resolution: tile.resolution,
+                painted_with_cpu: tile.painted_with_cpu,
+                content_age: tile.content_age,
+                rotation: tile.rotation,
+                mirrored: tile.mirrored,
+            })));
+        }
+
+        let mut tile_grid = self.tile_grid.borrow_mut();
+        if snapshot.tile_size != tile_grid.tile_size() ||
+                snapshot.layer_size != self.bounds.borrow().size {
+            debug!("Not importing a tile cache taken with another tile size or layer size.");
+            for (_, buffer) in buffers {
+                tile_grid.add_unused_buffer(Some(buffer));
+            }
+            return 0;
+        }
+
+        let imported_count = tile_grid.import_tiles(buffers);
+        if imported_count > 0 {
+            self.damage_all();
+        }
+        imported_count
+    }
+
+    /// Replaces the buffers of the tiles covered by the given set all at once, so that a
+    /// half-delivered set is never displayed, and returns the buffers this layer no longer holds
+    /// as a set: the displaced buffers, which for double-buffered layers are the previous back
+    /// buffers, and the buffers of the set whose tiles no longer exist. A set older than the
+    /// last one swapped in is returned untouched. Compositor task only.
+    pub fn swap_in_buffer_set(&self, set: LayerBufferSet) -> LayerBufferSet {
+        let last_generation = self.buffer_set_generation.get();
+        if last_generation.map_or(false, |last_generation| set.generation < last_generation) {
+            debug!("Rejecting buffer set {} older than the current set {:?}.",
+                   set.generation,
+                   last_generation);
+            return set;
+        }
+
+        for buffer in &set.buffers {
+            self.damage_buffer_rect(buffer);
+        }
+        let displaced_buffers = self.tile_grid.borrow_mut().swap_in_buffers(set.buffers);
+        self.buffer_set_generation.set(Some(set.generation));
+        LayerBufferSet::new(displaced_buffers, last_generation.unwrap_or(0))
+    }
+
+    /// Returns the generation of the last buffer set swapped in, if any.
+    pub fn buffer_set_generation(&self) -> Option<u64> {
+        self.buffer_set_generation.get()
+    }
+
+    /// Damages the area of this layer covered by the given buffer.
+    fn damage_buffer_rect(&self, buffer: &LayerBuffer) {
+        // The rect of the buffer is in device pixels, at the resolution of the buffer.
+        let (rect, scale) = (buffer.rect, buffer.resolution);
+        self.add_damage(TypedRect::new(TypedPoint2D::new(rect.origin.x / scale,
+                                                         rect.origin.y / scale),
+                                       TypedSize2D::new(rect.size.width / scale,
+                                                        rect.size.height / scale)));
+    }
+
+    /// Rejects a buffer that was painted at the wrong scale, so that its tile is requested
+    /// again. Returns the buffer if its tile keeps being rejected at the same content age, in
+    /// which case the caller should accept it anyway to avoid an endless repaint loop.
+    pub fn reject_buffer(&self, tile: Box<LayerBuffer>, frame: FrameIndex)
+                         -> Option<Box<LayerBuffer>> {
+        self.tile_grid.borrow_mut().reject_buffer(tile, frame)
+    }
+
+    /// Changes how the content of this layer is provided, and returns the buffers that are no
+    /// longer used: the buffer of the previous nine-patch, if any, and the buffers of all tiles
+    /// when switching to a nine-patch. Compositor task only.
+    pub fn set_content_mode(&self, content_mode: ContentMode) -> Vec<Box<LayerBuffer>> {
+        let mut unused_buffers = match content_mode {
+            ContentMode::NinePatch { .. } => self.tile_grid.borrow_mut().collect_buffers(),
+            ContentMode::Tiled => vec!(),
+        };
+        if let ContentMode::NinePatch { buffer, .. } =
+                mem::replace(&mut *self.content_mode.borrow_mut(), content_mode) {
+            buffer.native_surface.release_texture_binding();
+            unused_buffers.push(buffer);
+        }
+        *self.nine_patch_texture.borrow_mut() = Texture::zero();
+        self.damage_all();
+        unused_buffers
+    }
+
+    /// Returns the scale at which the tiles of this layer are requested, which is the scale
+    /// override of the layer if it has one and the given scene scale otherwise. The `scale`
+    /// passed to the methods of this layer should be this.
+    pub fn effective_scale(&self, scene_scale: ScaleFactor<f32, LayerPixel, DevicePixel>)
+                           -> ScaleFactor<f32, LayerPixel, DevicePixel> {
+        self.scale_override.borrow().unwrap_or(scene_scale)
+    }
+
+    /// Returns true if the content of this layer is a nine-patch.
+    pub fn is_nine_patch(&self) -> bool {
+        match *self.content_mode.borrow() {
+            ContentMode::NinePatch { .. } => true,
+            ContentMode::Tiled => false,
+        }
+    }
+
+    /// Calls `f` with the texture, the size of the buffer in pixels, and the insets of the
+    /// nine-patch of this layer, if it is one and its texture has been created.
+    pub fn with_nine_patch<F>(&self, f: F)
+                              where F: FnOnce(&Texture, Size2D<f32>, &SideOffsets2D<f32>) {
+        let texture = self.nine_patch_texture.borrow();
+        if texture.is_zero() {
+            return;
+        }
+        if let ContentMode::NinePatch { ref buffer, ref insets } = *self.content_mode.borrow() {
+            let size = buffer.get_size_2d();
+            f(&*texture, Size2D::new(size.width as f32, size.height as f32), insets);
+        }
+    }
+
+    pub fn collect_unused_buffers(&self) -> Vec<Box<LayerBuffer>> {
+        self.tile_grid.borrow_mut().take_unused_buffers()
+    }
+
+    /// Takes all buffers of this layer. A nine-patch layer goes back to being tiled.
+    pub fn collect_buffers(&self) -> Vec<Box<LayerBuffer>> {
+        let mut buffers = self.tile_grid.borrow_mut().collect_buffers();
+        if self.is_nine_patch() {
+            buffers.extend(self.set_content_mode(ContentMode::Tiled).into_iter());
+        }
+        buffers
+    }
+
+    /// Marks the content of this layer as out of date, so that all of its tiles are requested
+    /// again. This must not be used to force a recomposite after changing opacity or transforms.
+    pub fn contents_changed(&self) {
+        if cfg!(debug_assertions) && self.composite_properties_changed.get() {
+            warn!("contents_changed() called in the same frame as a composite-only property \
+                   change; this forces a repaint of the whole layer");
+            self.composite_properties_changed.set(false);
+        }
+        self.content_age.borrow_mut().next();
+        self.damage_all();
+    }
+
+    pub fn forget_textures(&self) {
+        self.tile_grid.borrow_mut().forget_textures();
+        self.nine_patch_texture.borrow_mut().mark_weak();
+    }
+
+    /// Creates the textures of the tiles that have received a buffer since they were last drawn,
+    /// issuing their GL calls through `gl`. If `mipmap_scale_threshold` is given, mipmaps are
+    /// generated for the tiles painted at a resolution below it, which are drawn minified when
+    /// the scene is zoomed out.
+    pub fn create_textures(&self,
+                           gl: &Rc<GlApi>,
+                           display: &NativeDisplay,
+                           capabilities: &Capabilities,
+                           mipmap_scale_threshold: Option<f32>) {
+        {
+            let mut tile_grid = self.tile_grid.borrow_mut();
+            tile_grid.set_max_tile_size(Some(capabilities.max_texture_size));
+            tile_grid.create_textures(gl, display, capabilities, mipmap_scale_threshold);
+        }
+
+        if let ContentMode::NinePatch { ref buffer, .. } = *self.content_mode.borrow() {
+            let mut texture = self.nine_patch_texture.borrow_mut();
+            if texture.is_zero() {
+                match Texture::try_new_with_buffer(gl.clone(), buffer, capabilities) {
+                    Ok(new_texture) => *texture = new_texture,
+                    Err(error) => {
+                        warn!("Not drawing nine-patch: {}", error);
+                        return;
+                    }
+                }
+                if let Err(error) = buffer.native_surface.bind_to_texture(display, &*texture) {
+                    warn!("Not drawing nine-patch: failed to bind its surface ({:?})", error);
+                    buffer.native_surface.release_texture_binding();
+                    *texture = Texture::zero();
+                }
+            }
+        }
+    }
+
+    pub fn do_for_all_tiles<F: FnMut(&Tile)>(&self, f: F) {
+        self.tile_grid.borrow().do_for_all_tiles(f);
+    }
+
+    pub fn update_transform_state(&self,
+                                  parent_transform: &Matrix4D<f32>,
+                                  parent_perspective: &Matrix4D<f32>,
+                                  parent_origin: &Point2D<f32>) {
+        self.update_transform_state_culled(parent_transform,
+                                           parent_perspective,
+                                           parent_origin,
+                                           None);
+    }
+
+    /// Like `update_transform_state`, but if a cull rect is given, the subtrees of layers that
+    /// mask to their bounds and lie entirely outside of it are not updated. Those subtrees are
+    /// recorded as skipped rather than dirty, so that they don't make every later flush run an
+    /// update pass; they are caught up by the next update without a cull rect. Returns true if
+    /// any subtree was skipped.
+    ///
+    /// The effective opacity is computed as if this layer were the root of the tree.
+    pub fn update_transform_state_culled(&self,
+                                         parent_transform: &Matrix4D<f32>,
+                                         parent_perspective: &Matrix4D<f32>,
+                                         parent_origin: &Point2D<f32>,
+                                         cull_rect: Option<&TypedRect<f32, ScreenPixel>>)
+                                         -> bool {
+        self.update_subtree_transform_state(parent_transform,
+                                            parent_perspective,
+                                            parent_origin,
+                                            1.0,
+                                            cull_rect)
+    }
+
+    fn update_subtree_transform_state(&self,
+                                      parent_transform: &Matrix4D<f32>,
+                                      parent_perspective: &Matrix4D<f32>,
+                                      parent_origin: &Point2D<f32>,
+                                      parent_opacity: f32,
+                                      cull_rect: Option<&TypedRect<f32, ScreenPixel>>)
+                                      -> bool {
+        self.transform_state_dirty.set(false);
+        self.transform_state_skipped.set(false);
+        self.composite_properties_changed.set(false);
+        self.flushed_transform_inputs.set(Some(self.transform_inputs()));
+
+        let mut ts = self.transform_state.borrow_mut();
+        let origin = if self.is_fixed_to_viewport() {
+            *self.fixed_offset.borrow()
+        } else {
+            TypedPoint2D::from_untyped(parent_origin)
+        };
+        let rect_without_scroll = self.bounds.borrow().translate(&origin);
+
+        ts.world_rect = rect_without_scroll.translate(&*self.content_offset.borrow());
+
+        let transform_origin = *self.transform_origin.borrow();
+        let x0 = ts.world_rect.origin.x + transform_origin.x;
+        let y0 = ts.world_rect.origin.y + transform_origin.y;
+        let z0 = transform_origin.z;
+
+        // Build world space transform
+        let local_transform = Matrix4D::identity()
+            .pre_translated(x0, y0, z0)
+            .pre_mul(&*self.transform.borrow())
+            .pre_translated(-x0, -y0, -z0);
+
+        ts.final_transform = parent_perspective
+            .pre_mul(&local_transform)
+            .pre_mul(&parent_transform);
+        ts.inverse_transform = invert_layer_transform(&ts.final_transform);
+        // Inverted bounds would project to an ordinary rect, so treat the layer as clipped. So is
+        // a layer scaled to nothing, as happens at keyframes of CSS animations; a nearly singular
+        // transform can't be inverted, but the layer is still drawn, unclipped by
+        // `world_clip_rect`, since it covers next to nothing.
+        if rect_has_valid_size(&ts.world_rect) && !transform_collapses_layer(&ts.final_transform) {
+            ts.screen_rect = project_rect_to_screen(&ts.world_rect, &ts.final_transform);
+            ts.crosses_near_plane = rect_crosses_near_plane(&ts.world_rect, &ts.final_transform);
+        } else {
+            ts.screen_rect = None;
+            ts.crosses_near_plane = false;
+        }
+
+        // TODO(gw): This is quite bogus. It's a hack to allow the paint task
+        // to avoid "optimizing" 3d layers with an incorrect clip rect.
+        // We should probably make the display list optimizer work with transforms!
+        // This layer is part of a 3d context if its concatenated transform
+        // is not identity, since 2d transforms don't get layers.
+        ts.has_transform = ts.final_transform != Matrix4D::identity();
+
+        ts.effective_opacity = parent_opacity * *self.opacity.borrow();
+
+        // Build world space perspective transform
+        let perspective_transform = Matrix4D::identity()
+            .pre_translated(x0, y0, z0)
+            .pre_mul(&*self.perspective.borrow())
+            .pre_translated(-x0, -y0, -z0);
+
+        if let Some(cull_rect) = cull_rect {
+            if *self.masks_to_bounds.borrow() {
+                let visible = match ts.screen_rect {
+                    Some(ref screen_rect) => screen_rect.rect.intersects(cull_rect),
+                    None => false,
+                };
+                if !visible {
+                    let children = self.children();
+                    for child in children.iter() {
+                        child.transform_state_skipped.set(true);
+                    }
+                    return !children.is_empty();
+                }
+            }
+        }
+
+        let child_origin = rect_without_scroll.origin.to_untyped();
+        let mut skipped = false;
+        for child in self.children().iter() {
+            skipped |= child.update_subtree_transform_state(&ts.final_transform,
+                                                            &perspective_transform,
+                                                            &child_origin,
+                                                            ts.effective_opacity,
+                                                            cull_rect);
+        }
+        skipped
+    }
+
+    /// Returns true if this layer or any of its descendants has a 3D transform or perspective,
+    /// according to the current transform state.
+    pub fn subtree_has_3d_transforms(&self) -> bool {
+        if !is_2d_transform(&self.transform_state.borrow().final_transform) {
+            return true;
+        }
+        self.children().iter().any(|child| child.subtree_has_3d_transforms())
+    }
+
+    /// Returns true if this layer and its descendants can be drawn as one flat list in paint
+    /// order: none of them has a 3D transform or draws its children in a nested rendering
+    /// context. Such a tree draws the same without depth sorting or the depth buffer.
+    pub fn subtree_is_flat(&self) -> bool {
+        if self.establishes_render_context() ||
+           !is_2d_transform(&self.transform_state.borrow().final_transform) {
+            return false;
+        }
+        self.children().iter().all(|child| child.subtree_is_flat())
+    }
+
+    /// Returns true if this layer can be drawn in a batch together with its siblings: it has no
+    /// transform, doesn't clip, doesn't establish a 3d context, and has no children that would
+    /// have to be painted in between.
+    pub fn is_batchable(&self) -> bool {
+        !self.transform_state.borrow().has_transform &&
+            !*self.masks_to_bounds.borrow() &&
+            !self.establishes_3d_context &&
+            self.children.borrow().is_empty()
+    }
+
+    /// Records that the transform state of this layer is out of date. The setters on `Layer` do
+    /// this automatically. Direct writes to fields such as `bounds` or `content_offset` are
+    /// noticed by the next flush without it, but they only count as composite damage (see
+    /// `take_composite_damage`) if this is called.
+    pub fn mark_transform_state_dirty(&self) {
+        self.transform_state_dirty.set(true);
+        self.composite_damaged.set(true);
+    }
+
+    /// Returns true if the transform state of this layer or any of its descendants changed since
+    /// the last update. Subtrees that were only skipped by a culled update don't count.
+    pub fn subtree_has_dirty_transform_state(&self) -> bool {
+        self.transform_state_dirty.get() || self.has_unflushed_transform_inputs() ||
+            self.children().iter().any(|child| child.subtree_has_dirty_transform_state())
+    }
+
+    /// Calculate the amount of memory used by this layer and all its children.
+    /// The memory may be allocated on the heap or in GPU memory.
+    pub fn get_memory_usage(&self) -> usize {
+        let size_of_children : usize = self.children().iter().map(|ref child| -> usize {
+            child.get_memory_usage()
+        }).sum();
+        size_of_children + self.get_memory_usage_without_children()
+    }
+
+    /// Returns counters describing the tiles of this layer alone. The buffer of a nine-patch
+    /// counts towards the buffer memory.
+    pub fn tile_statistics(&self) -> TileGridStatistics {
+        let mut statistics = self.tile_grid.borrow().statistics();
+        if let ContentMode::NinePatch { ref buffer, .. } = *self.content_mode.borrow() {
+            statistics.buffer_memory += buffer.get_mem();
+        }
+        statistics
+    }
+
+    /// Returns true if every tile of this layer displays content of the current content age and
+    /// no buffer requested for it is still being painted. This doesn't include the children of
+    /// this layer; see `Scene::is_fully_painted`.
+    pub fn is_fully_painted(&self) -> bool {
+        self.tile_grid.borrow().is_fully_painted(*self.content_age.borrow())
+    }
+
+    /// Returns the number of tiles of this layer displaying content older than its current
+    /// content age.
+    pub fn stale_tile_count(&self) -> usize {
+        self.tile_grid.borrow().stale_tile_count(*self.content_age.borrow())
+    }
+
+    /// Calculate the amount of memory used by the buffers of this layer alone.
+    pub fn get_memory_usage_without_children(&self) -> usize {
+        let nine_patch_usage = match *self.content_mode.borrow() {
+            ContentMode::NinePatch { ref buffer, .. } => buffer.get_mem(),
+            ContentMode::Tiled => 0,
+        };
+        self.tile_grid.borrow().get_memory_usage() + nine_patch_usage
+    }
+
+    /// Returns the area of the buffers of this layer alone, in device pixels.
+    pub fn get_raster_area_without_children(&self) -> usize {
+        let nine_patch_area = match *self.content_mode.borrow() {
+            ContentMode::NinePatch { ref buffer, .. } => {
+                buffer.screen_pos.size.width * buffer.screen_pos.size.height
+            }
+            ContentMode::Tiled => 0,
+        };
+        self.tile_grid.borrow().get_raster_area() + nine_patch_area
+    }
+}
+
+/// Returns true if the given rects together cover `rect`. Like `subtract_rect`, this is
+/// conservative: rects that only cover `rect` together with others that are partially in front
+/// of it may not be found to.
+fn is_hidden_by(rect: &TypedRect<f32, ScreenPixel>,
+                opaque_region: &[TypedRect<f32, ScreenPixel>])
+                -> bool {
+    let mut visible_rect = *rect;
+    for opaque_rect in opaque_region {
+        visible_rect = match subtract_rect(&visible_rect, opaque_rect) {
+            Some(visible_rect) => visible_rect,
+            None => return true,
+        };
+    }
+    false
+}
+
+/// The order in which the buffer requests of a layer are returned. Painters that rasterize with
+/// strong spatial locality can pick the order that matches their traversal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestOrdering {
+    /// Tiles closest to the center of the viewport first.
+    ViewportDistance,
+    /// Row by row from the top, each row from left to right.
+    RowMajor,
+    /// Column by column from the left, each column from top to bottom.
+    ColumnMajor,
+    /// Along a Z-order curve over the tile indices, which keeps nearby tiles close together in
+    /// both directions.
+    MortonOrder,
+}
+
+impl Default for RequestOrdering {
+    /// Tiles closest to the center of the viewport first, so that what the user looks at is
+    /// painted first.
+    fn default() -> RequestOrdering {
+        RequestOrdering::ViewportDistance
+    }
+}
+
+/// How urgently the tile of a buffer request is needed. Requests are returned with the most
+/// urgent ones first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BufferRequestPriority {
+    /// The tile is at least partly within the viewport.
+    Visible,
+    /// The tile is outside of the viewport, but within one tile of it.
+    Near,
+    /// The tile is further away and only requested ahead of time, in case it scrolls into view.
+    Prefetch,
+}
+
+/// A request from the compositor to the renderer for tiles that need to be (re)displayed.
+pub struct BufferRequest {
+    /// The rect in pixels that will be drawn to the screen
+    pub screen_rect: Rect<usize>,
+
+    /// The rect in page coordinates that this tile represents
+    pub page_rect: Rect<f32>,
+
+    /// The content age of that this BufferRequest corresponds to.
+    pub content_age: ContentAge,
+
+    /// If part of the tile is hidden behind opaque layers, the part of `screen_rect` that is
+    /// visible. Only that part needs to be painted.
+    pub visible_rect: Option<Rect<usize>>,
+
+    /// The order in which the requests for this layer were sorted, within each priority.
+    pub ordering: RequestOrdering,
+
+    /// How urgently the tile is needed. Painters that fall behind should paint the requests with
+    /// the highest priority first.
+    pub priority: BufferRequestPriority,
+
+    /// The scale at which the tile is to be painted. The painter should copy it into
+    /// `LayerBuffer::resolution`.
+    pub resolution: f32,
+
+    /// The transform from layer coordinates to the device pixels of the buffer: the scale of
+    /// the request followed by the translation of the tile to the origin. Painters should paint
+    /// with exactly this transform, since it is the one the compositor positions the tile with;
+    /// deriving it again from `page_rect` and `resolution` can round differently and leave
+    /// seams between tiles at fractional scales.
+    pub paint_transform: Matrix2D<f32>,
+
+    /// A cached NativeSurface that can be used to avoid allocating a new one. For double-buffered
+    /// layers, this is the back buffer of the tile, which is not being displayed.
+    pub native_surface: Option<NativeSurface>,
+}
+
+impl BufferRequest {
+    pub fn new(screen_rect: Rect<usize>, page_rect: Rect<f32>, content_age: ContentAge)
+               -> BufferRequest {
+        BufferRequest {
+            screen_rect: screen_rect,
+            page_rect: page_rect,
+            content_age: content_age,
+            visible_rect: None,
+            ordering: RequestOrdering::ColumnMajor,
+            priority: BufferRequestPriority::Visible,
+            resolution: 1.0,
+            paint_transform: Matrix2D::row_major(1.0, 0.0,
+                                                 0.0, 1.0,
+                                                 -page_rect.origin.x, -page_rect.origin.y),
+            native_surface: None,
+        }
+    }
+
+    /// Returns the size in pixels of the buffer to paint.
+    pub fn device_size(&self) -> Size2D<i32> {
+        Size2D::new(self.screen_rect.size.width as i32, self.screen_rect.size.height as i32)
+    }
+}
+
+pub struct LayerBuffer {
+    /// The native surface which can be shared between threads or processes. On Mac this is an
+    /// `IOSurface`; on Linux this is an X Pixmap; on Android this is an `EGLImageKHR`.
+    pub native_surface: NativeSurface,
+
+    /// The rect in the containing RenderLayer that this represents.
+    pub rect: Rect<f32>,
+
+    /// The rect in pixels that will be drawn to the screen.
+    pub screen_pos: Rect<usize>,
+
+    /// The scale at which this tile is rendered
+    pub resolution: f32,
+
+    /// Whether or not this buffer was painted with the CPU rasterization.
+    pub painted_with_cpu: bool,
+
+    /// The content age of that this buffer request corresponds to.
+    pub content_age: ContentAge,
+
+    /// How the native surface is rotated when it is displayed, for surfaces such as camera
+    /// frames that are not stored upright.
+    pub rotation: Rotation,
+
+    /// Whether the native surface is mirrored horizontally when it is displayed. Mirroring is
+    /// applied after the rotation.
+    pub mirrored: bool,
+}
+
+impl LayerBuffer {
+    /// Creates a layer buffer fulfilling the given request by copying the pixels it covers out of
+    /// a larger source image of the layer, which must be in the native pixel format of the surface
+    /// and have one pixel per layer pixel. The source is sampled at the resolution of the request,
+    /// taking the nearest pixel. Parts of the request lying outside the source image are filled
+    /// with transparent black. The native surface cached on the request is reused when it has the
+    /// right size. Painting task only.
+    pub fn from_request_and_pixels(mut request: BufferRequest,
+                                   display: &NativeDisplay,
+                                   source: &[u8],
+                                   source_size: Size2D<usize>,
+                                   source_stride: usize)
+                                   -> Result<Box<LayerBuffer>, SurfaceError> {
+        if source_size.height > 0 &&
+           (source_stride < source_size.width * 4 ||
+            source.len() < source_stride * (source_size.height - 1) + source_size.width * 4) {
+            return Err(SurfaceError::SourceTooSmall);
+        }
+
+        // The layer pixel sampled by the center of each buffer pixel along one axis, if it lies
+        // in the source image.
+        let resolution = request.resolution;
+        let source_index = |page_origin: f32, index: usize, source_length: usize| {
+            let source_index = (page_origin + (index as f32 + 0.5) / resolution).floor();
+            if source_index >= 0.0 && source_index < source_length as f32 {
+                Some(source_index as usize)
+            } else {
+                None
+            }
+        };
+
+        let size = request.screen_rect.size;
+        let page_origin = request.page_rect.origin;
+        let source_columns: Vec<Option<usize>> = (0..size.width).map(|x| {
+            source_index(page_origin.x, x, source_size.width)
+        }).collect();
+        let mut pixels = vec![0; size.width * size.height * 4];
+        for y in 0..size.height {
+            let source_row = match source_index(page_origin.y, y, source_size.height) {
+                Some(source_y) => source_y * source_stride,
+                None => continue,
+            };
+            for (x, source_column) in source_columns.iter().enumerate() {
+                if let Some(source_x) = *source_column {
+                    let source_start = source_row + source_x * 4;
+                    let destination_start = (y * size.width + x) * 4;
+                    pixels[destination_start..destination_start + 4]
+                        .clone_from_slice(&source[source_start..source_start + 4]);
+                }
+            }
+        }
+
+        let surface_size = Size2D::new(size.width as i32, size.height as i32);
+        let mut native_surface = match request.native_surface.take() {
+            Some(native_surface) => {
+                if native_surface.get_size() == surface_size {
+                    native_surface
+                } else {
+                    let mut native_surface = native_surface;
+                    if let Err(error) = native_surface.destroy(display) {
+                        warn!("Failed to destroy a cached surface ({:?})", error);
+                    }
+                    NativeSurface::new(display, surface_size)
+                }
+            }
+            None => NativeSurface::new(display, surface_size),
+        };
+        try!(native_surface.upload(display, &pixels));
+
+        Ok(Box::new(LayerBuffer {
+            native_surface: native_surface,
+            rect: request.page_rect,
+            screen_pos: request.screen_rect,
+            resolution: request.resolution,
+            painted_with_cpu: true,
+            content_age: request.content_age,
+            rotation: Rotation::NoRotation,
+            mirrored: false,
+        }))
+    }
+
+    /// Repaints part of the buffer with new pixels, for small updates such as a blinking caret.
+    /// `rect` is in the pixels of the native surface, and `data` holds its rows `stride` bytes
+    /// apart. Memory surfaces only upload the changed rows when they are bound to the same texture
+    /// again. Painting task only.
+    pub fn upload_rect(&mut self,
+                       display: &NativeDisplay,
+                       data: &[u8],
+                       rect: Rect<i32>,
+                       stride: i32)
+                       -> Result<(), SurfaceError> {
+        self.native_surface.upload_rect(display, data, rect, stride)
+    }
+
+    /// Returns the amount of memory used by the tile
+    pub fn get_mem(&self) -> usize {
+        self.native_surface.get_memory_usage()
+    }
+
+    /// Returns true if the tile is displayable at the given scale, using
+    /// `DEFAULT_RESOLUTION_EPSILON`.
+    pub fn is_valid(&self, scale: f32) -> bool {
+        self.is_valid_with_epsilon(scale, DEFAULT_RESOLUTION_EPSILON)
+    }
+
+    /// Returns true if the resolution of the tile differs from the given scale by at most
+    /// `epsilon` times the larger of the two.
+    pub fn is_valid_with_epsilon(&self, scale: f32, epsilon: f32) -> bool {
+        (self.resolution - scale).abs() <= epsilon * self.resolution.abs().max(scale.abs())
+    }
+
+    /// Returns the Size2D of the tile
+    pub fn get_size_2d(&self) -> Size2D<usize> {
+        self.screen_pos.size
+    }
+
+    /// Marks the layer buffer as not leaking. See comments on
+    /// `NativeSurfaceMethods::mark_wont_leak` for how this is used.
+    pub fn mark_wont_leak(&mut self) {
+        self.native_surface.mark_wont_leak()
+    }
+
+    /// Destroys the layer buffer. Painting task only.
+    pub fn destroy(self, display: &NativeDisplay) -> Result<(), SurfaceError> {
+        let mut this = self;
+        this.native_surface.destroy(display)
+    }
+}
+
+/// The buffers displayed by the tiles of a layer, exported with `Layer::export_tile_cache` so
+/// that a restarted compositor can display them again with `Layer::import_tile_cache` instead of
+/// waiting for every tile to be repainted.
+///
+/// The sizes, points and rects of snapshots are encoded as tuples. The JSON decoder leaves the
+/// unit markers of euclid types on its stack, where the next element of a sequence trips over
+/// them, so snapshots holding those types would fail to decode once they have two tiles.
+#[derive(Clone, Debug)]
+pub struct TileCacheSnapshot {
+    /// The size of the tiles of the layer, in device pixels.
+    pub tile_size: usize,
+
+    /// The size of the bounds of the layer.
+    pub layer_size: TypedSize2D<f32, LayerPixel>,
+
+    pub tiles: Vec<TileSnapshot>,
+}
+
+impl Decodable for TileCacheSnapshot {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Self, D::Error> {
+        d.read_struct("TileCacheSnapshot", 3, |d| {
+            let (width, height) = try!(d.read_struct_field("layer_size", 1, Decodable::decode));
+            Ok(TileCacheSnapshot {
+                tile_size: try!(d.read_struct_field("tile_size", 0, Decodable::decode)),
+                layer_size: TypedSize2D::new(width, height),
+                tiles: try!(d.read_struct_field("tiles", 2, Decodable::decode)),
+            })
+        })
+    }
+}
+
+impl Encodable for TileCacheSnapshot {
+    fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+        e.emit_struct("TileCacheSnapshot", 3, |e| {
+            try!(e.emit_struct_field("tile_size", 0, |e| self.tile_size.encode(e)));
+            try!(e.emit_struct_field("layer_size", 1, |e| {
+                (self.layer_size.width, self.layer_size.height).encode(e)
+            }));
+            e.emit_struct_field("tiles", 2, |e| self.tiles.encode(e))
+        })
+    }
+}
+
+/// The buffer displayed by one tile in a `TileCacheSnapshot`, with its native surface encoded.
+/// The other fields are those of the `LayerBuffer`.
+#[derive(Clone, Debug)]
+pub struct TileSnapshot {
+    pub tile_index: Point2D<usize>,
+    pub native_surface: SerializedSurface,
+    pub rect: Rect<f32>,
+    pub screen_pos: Rect<usize>,
+    pub resolution: f32,
+    pub painted_with_cpu: bool,
+    pub content_age: ContentAge,
+    pub rotation: Rotation,
+    pub mirrored: bool,
+}
+
+fn rect_from_tuple<T: Copy>((x, y, width, height): (T, T, T, T)) -> Rect<T> {
+    Rect::new(Point2D::new(x, y), Size2D::new(width, height))
+}
+
+fn rect_to_tuple<T: Copy>(rect: &Rect<T>) -> (T, T, T, T) {
+    (rect.origin.x, rect.origin.y, rect.size.width, rect.size.height)
+}
+
+impl Decodable for TileSnapshot {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Self, D::Error> {
+        d.read_struct("TileSnapshot", 9, |d| {
+            let (x, y) = try!(d.read_struct_field("tile_index", 0, Decodable::decode));
+            Ok(TileSnapshot {
+                tile_index: Point2D::new(x, y),
+                native_surface: try!(d.read_struct_field("native_surface", 1, Decodable::decode)),
+                rect: rect_from_tuple(try!(d.read_struct_field("rect", 2, Decodable::decode))),
+                screen_pos: rect_from_tuple(try!(d.read_struct_field("screen_pos",
+                                                                     3,
+                                                                     Decodable::decode))),
+                resolution: try!(d.read_struct_field("resolution", 4, Decodable::decode)),
+                painted_with_cpu: try!(d.read_struct_field("painted_with_cpu",
+                                                           5,
+                                                           Decodable::decode)),
+                content_age: try!(d.read_struct_field("content_age", 6, Decodable::decode)),
+                rotation: try!(d.read_struct_field("rotation", 7, Decodable::decode)),
+                mirrored: try!(d.read_struct_field("mirrored", 8, Decodable::decode)),
+            })
+        })
+    }
+}
+
+impl Encodable for TileSnapshot {
+    fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+        e.emit_struct("TileSnapshot", 9, |e| {
+            try!(e.emit_struct_field("tile_index", 0, |e| {
+                (self.tile_index.x, self.tile_index.y).encode(e)
+            }));
+            try!(e.emit_struct_field("native_surface", 1, |e| self.native_surface.encode(e)));
+            try!(e.emit_struct_field("rect", 2, |e| rect_to_tuple(&self.rect).encode(e)));
+            try!(e.emit_struct_field("screen_pos", 3, |e| {
+                rect_to_tuple(&self.screen_pos).encode(e)
+            }));
+            try!(e.emit_struct_field("resolution", 4, |e| self.resolution.encode(e)));
+            try!(e.emit_struct_field("painted_with_cpu", 5, |e| self.painted_with_cpu.encode(e)));
+            try!(e.emit_struct_field("content_age", 6, |e| self.content_age.encode(e)));
+            try!(e.emit_struct_field("rotation", 7, |e| self.rotation.encode(e)));
+            e.emit_struct_field("mirrored", 8, |e| self.mirrored.encode(e))
+        })
+    }
+}
+
+/// A set of layer buffers. This is an atomic unit used to switch between the front and back
+/// buffers; see `Layer::swap_in_buffer_set`.
+pub struct LayerBufferSet {
+    pub buffers: Vec<Box<LayerBuffer>>,
+
+    /// Orders the sets sent to a layer. Sets older than the last one swapped in are rejected.
+    pub generation: u64,
+}
+
+impl LayerBufferSet {
+    pub fn new(buffers: Vec<Box<LayerBuffer>>, generation: u64) -> LayerBufferSet {
+        LayerBufferSet {
+            buffers: buffers,
+            generation: generation,
+        }
+    }
+
+    /// Notes all buffer surfaces will leak if not destroyed via a call to `destroy`.
+    pub fn mark_will_leak(&mut self) {
+        for buffer in &mut self.buffers {
+            buffer.native_surface.mark_will_leak()
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use capabilities::Capabilities;
+    use euclid::{Matrix4D, Radians};
+    use euclid::point::{Point2D, Point3D, TypedPoint2D};
+    use euclid::rect::{Rect, TypedRect};
+    use euclid::scale_factor::ScaleFactor;
+    use euclid::side_offsets::SideOffsets2D;
+    use euclid::size::{Size2D, TypedSize2D};
+    use frame::FrameIndex;
+    use geometry::ScreenPixel;
+    use gltrace::{GlApi, GlCall};
+    use platform::surface::{MemoryBufferNativeSurface, NativeSurface, SurfaceError};
+    use rustc_serialize::json;
+    use std::cell::Cell;
+    use std::f32::consts::PI;
+    use std::rc::Rc;
+    use std::u32;
+    use super::{BufferRequest, BufferRequestPriority, ContentAge, DEFAULT_RESOLUTION_EPSILON};
+    use super::{Layer, LayerBuffer};
+    use super::{LayerBufferSet, RequestOrdering, TileCacheSnapshot};
+    use test_util::{buffer_for_request, headless_display, layer, record_gl_calls};
+    use tiling::{RESOLUTION_LOOP_REJECTION_LIMIT, TileGridUpdate, clamp_tile_size};
+
+    fn request_at_scale(layer: &Layer<()>, scale: f32) -> Vec<BufferRequest> {
+        let rect: TypedRect<f32, ScreenPixel> =
+            TypedRect::new(TypedPoint2D::new(0.0, 0.0), TypedSize2D::new(1024.0, 1024.0));
+        layer.get_buffer_requests(rect, rect, ScaleFactor::new(scale))
+    }
+
+    /// Adds a buffer to a layer of a scene at the given scale and the default tolerance.
+    fn add_buffer_checked(layer: &Layer<()>, buffer: Box<LayerBuffer>, scale: f32, frame: u32)
+                          -> bool {
+        let last_loop_report = Cell::new(None);
+        layer.add_buffer_checked(buffer,
+                                 ScaleFactor::new(scale),
+                                 DEFAULT_RESOLUTION_EPSILON,
+                                 FrameIndex(frame),
+                                 &last_loop_report)
+    }
+
+    /// Returns the tile indices requested for a layer of `columns` by `rows` 256-pixel tiles, in
+    /// the order they were requested, with the given viewport.
+    fn requested_tiles(columns: usize,
+                       rows: usize,
+                       viewport: TypedRect<f32, ScreenPixel>,
+                       ordering: RequestOrdering)
+                       -> Vec<(usize, usize)> {
+        let layer = layer(0.0, 0.0, columns as f32 * 256.0, rows as f32 * 256.0, 256);
+        let rect = TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                  TypedSize2D::new(columns as f32 * 256.0, rows as f32 * 256.0));
+        let mut requests = vec!();
+        layer.get_buffer_requests_into(rect,
+                                       viewport,
+                                       ScaleFactor::new(1.0),
+                                       &[],
+                                       ordering,
+                                       &mut requests);
+        requests.iter().map(|request| {
+            (request.screen_rect.origin.x / 256, request.screen_rect.origin.y / 256)
+        }).collect()
+    }
+
+    fn whole_layer(columns: usize, rows: usize) -> TypedRect<f32, ScreenPixel> {
+        TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                       TypedSize2D::new(columns as f32 * 256.0, rows as f32 * 256.0))
+    }
+
+    #[test]
+    fn requests_come_in_column_major_order() {
+        assert_eq!(requested_tiles(3, 2, whole_layer(3, 2), RequestOrdering::ColumnMajor),
+                   vec!((0, 0), (0, 1), (1, 0), (1, 1), (2, 0), (2, 1)));
+    }
+
+    #[test]
+    fn requests_come_in_row_major_order() {
+        assert_eq!(requested_tiles(3, 2, whole_layer(3, 2), RequestOrdering::RowMajor),
+                   vec!((0, 0), (1, 0), (2, 0), (0, 1), (1, 1), (2, 1)));
+    }
+
+    #[test]
+    fn requests_come_in_morton_order_for_wide_and_tall_layers() {
+        assert_eq!(requested_tiles(4, 2, whole_layer(4, 2), RequestOrdering::MortonOrder),
+                   vec!((0, 0), (1, 0), (0, 1), (1, 1), (2, 0), (3, 0), (2, 1), (3, 1)));
+        assert_eq!(requested_tiles(2, 3, whole_layer(2, 3), RequestOrdering::MortonOrder),
+                   vec!((0, 0), (1, 0), (0, 1), (1, 1), (0, 2), (1, 2)));
+        assert_eq!(requested_tiles(3, 3, whole_layer(3, 3), RequestOrdering::MortonOrder),
+                   vec!((0, 0), (1, 0), (0, 1), (1, 1), (2, 0), (2, 1), (0, 2), (1, 2), (2, 2)));
+    }
+
+    #[test]
+    fn requests_come_closest_to_the_viewport_center_first() {
+        // Ties keep column-major order.
+        assert_eq!(requested_tiles(3, 1, whole_layer(3, 1), RequestOrdering::ViewportDistance),
+                   vec!((1, 0), (0, 0), (2, 0)));
+        let viewport = TypedRect::new(TypedPoint2D::new(512.0, 256.0),
+                                      TypedSize2D::new(256.0, 256.0));
+        assert_eq!(requested_tiles(3, 3, viewport, RequestOrdering::ViewportDistance),
+                   vec!((2, 1), (1, 1), (2, 0), (2, 2), (1, 0), (1, 2), (0, 1), (0, 0), (0, 2)));
+    }
+
+    /// Returns the tile indices and priorities requested for a row of five 256-pixel tiles whose
+    /// whole area is dirty, but only the middle tile of which is in the viewport.
+    fn requested_priorities(ordering: RequestOrdering)
+                            -> Vec<((usize, usize), BufferRequestPriority)> {
+        let layer = layer(0.0, 0.0, 1280.0, 256.0, 256);
+        let viewport = TypedRect::new(TypedPoint2D::new(512.0, 0.0),
+                                      TypedSize2D::new(256.0, 256.0));
+        let mut requests = vec!();
+        layer.get_buffer_requests_into(whole_layer(5, 1),
+                                       viewport,
+                                       ScaleFactor::new(1.0),
+                                       &[],
+                                       ordering,
+                                       &mut requests);
+        requests.iter().map(|request| {
+            ((request.screen_rect.origin.x / 256, request.screen_rect.origin.y / 256),
+             request.priority)
+        }).collect()
+    }
+
+    #[test]
+    fn visible_tiles_are_requested_before_near_and_prefetched_ones() {
+        assert_eq!(requested_priorities(RequestOrdering::ColumnMajor),
+                   vec!(((2, 0), BufferRequestPriority::Visible),
+                        ((1, 0), BufferRequestPriority::Near),
+                        ((3, 0), BufferRequestPriority::Near),
+                        ((0, 0), BufferRequestPriority::Prefetch),
+                        ((4, 0), BufferRequestPriority::Prefetch)));
+    }
+
+    #[test]
+    fn the_ordering_applies_within_each_priority() {
+        let layer = layer(0.0, 0.0, 768.0, 768.0, 256);
+        let viewport = TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                      TypedSize2D::new(512.0, 256.0));
+        let mut requests = vec!();
+        layer.get_buffer_requests_into(whole_layer(3, 3),
+                                       viewport,
+                                       ScaleFactor::new(1.0),
+                                       &[],
+                                       RequestOrdering::RowMajor,
+                                       &mut requests);
+        let requested: Vec<_> = requests.iter().map(|request| {
+            ((request.screen_rect.origin.x / 256, request.screen_rect.origin.y / 256),
+             request.priority)
+        }).collect();
+        assert_eq!(requested,
+                   vec!(((0, 0), BufferRequestPriority::Visible),
+                        ((1, 0), BufferRequestPriority::Visible),
+                        ((2, 0), BufferRequestPriority::Near),
+                        ((0, 1), BufferRequestPriority::Near),
+                        ((1, 1), BufferRequestPriority::Near),
+                        ((2, 1), BufferRequestPriority::Near),
+                        ((0, 2), BufferRequestPriority::Prefetch),
+                        ((1, 2), BufferRequestPriority::Prefetch),
+                        ((2, 2), BufferRequestPriority::Prefetch)));
+        assert_eq!(requested_priorities(RequestOrdering::ViewportDistance)[0],
+                   ((2, 0), BufferRequestPriority::Visible));
+    }
+
+    /// Returns the content ages of the front buffer of the first tile and of its pending request.
+    fn first_tile_ages(layer: &Layer<()>) -> (Option<ContentAge>, Option<ContentAge>) {
+        layer.with_tile(&Point2D::new(0, 0), |tile, _| {
+            let tile = tile.unwrap();
+            (tile.buffer_content_age(), tile.pending_content_age())
+        })
+    }
+
+    #[test]
+    fn tiles_go_stale_when_the_content_changes_until_their_buffers_arrive() {
+        let layer = layer(0.0, 0.0, 512.0, 256.0, 256);
+        let counters = || (layer.stale_tile_count(), layer.is_fully_painted());
+
+        // Tiles waiting for their first buffers aren't stale, but aren't painted either.
+        let requests = request_at_scale(&layer, 1.0);
+        assert_eq!(counters(), (0, false));
+        layer.add_buffer(buffer_for_request(&requests[0]));
+        assert_eq!(counters(), (0, false));
+        layer.add_buffer(buffer_for_request(&requests[1]));
+        assert_eq!(counters(), (0, true));
+
+        // A content change makes both tiles stale, requested or not.
+        layer.contents_changed();
+        assert_eq!(counters(), (2, false));
+        let requests = request_at_scale(&layer, 1.0);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(counters(), (2, false));
+        layer.add_buffer(buffer_for_request(&requests[1]));
+        assert_eq!(counters(), (1, false));
+        layer.add_buffer(buffer_for_request(&requests[0]));
+        assert_eq!(counters(), (0, true));
+        assert!(layer.with_tile(&Point2D::new(0, 0), |tile, _| {
+            !tile.unwrap().is_stale(*layer.content_age.borrow())
+        }));
+    }
+
+    #[test]
+    fn an_older_buffer_is_shown_without_clearing_the_newer_request() {
+        let layer = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let requests_n = request_at_scale(&layer, 1.0);
+        let age_n = requests_n[0].content_age;
+
+        // The content changes again before the painter answers the request for N.
+        layer.contents_changed();
+        let requests_n1 = request_at_scale(&layer, 1.0);
+        assert_eq!(requests_n1.len(), 1);
+        let age_n1 = requests_n1[0].content_age;
+        assert!(age_n1 > age_n);
+
+        // The buffer for N is shown in the meantime, but the tile keeps waiting for N+1, so it
+        // isn't requested a third time.
+        layer.add_buffer(buffer_for_request(&requests_n[0]));
+        assert_eq!(first_tile_ages(&layer), (Some(age_n), Some(age_n1)));
+        assert_eq!(layer.stale_tile_count(), 1);
+        assert!(request_at_scale(&layer, 1.0).is_empty());
+
+        // The buffer for N+1 settles the tile.
+        layer.add_buffer(buffer_for_request(&requests_n1[0]));
+        assert_eq!(first_tile_ages(&layer), (Some(age_n1), None));
+        assert_eq!(layer.stale_tile_count(), 0);
+        assert!(request_at_scale(&layer, 1.0).is_empty());
+    }
+
+    #[test]
+    fn neighboring_tiles_paint_their_shared_edge_at_the_same_device_pixel() {
+        for &scale in &[1.25, 1.5, 0.75] {
+            let layer = layer(0.0, 0.0, 700.0, 500.0, 256);
+            let requests = request_at_scale(&layer, scale);
+            let tile_at = |x: usize, y: usize| {
+                requests.iter().find(|request| request.screen_rect.origin == Point2D::new(x, y))
+            };
+            let mut shared_edges = 0;
+            for request in &requests {
+                let rect = request.screen_rect;
+                assert_eq!(request.device_size(),
+                           Size2D::new(rect.size.width as i32, rect.size.height as i32));
+
+                // The layer point at the origin of the tile to the right, or below, is painted
+                // at the far edge of this tile and at the near edge of the other one.
+                let neighbors = [(tile_at(rect.max_x(), rect.origin.y), true),
+                                 (tile_at(rect.origin.x, rect.max_y()), false)];
+                for &(neighbor, horizontal) in &neighbors {
+                    let neighbor = match neighbor {
+                        Some(neighbor) => neighbor,
+                        None => continue,
+                    };
+                    let edge = Point2D::new(neighbor.screen_rect.origin.x as f32 / scale,
+                                            neighbor.screen_rect.origin.y as f32 / scale);
+                    let here = request.paint_transform.transform_point(&edge);
+                    let there = neighbor.paint_transform.transform_point(&edge);
+                    if horizontal {
+                        assert!((here.x - rect.size.width as f32).abs() < 1e-3);
+                        assert!(there.x.abs() < 1e-3);
+                        assert_eq!(here.y, there.y);
+                    } else {
+                        assert!((here.y - rect.size.height as f32).abs() < 1e-3);
+                        assert!(there.y.abs() < 1e-3);
+                        assert_eq!(here.x, there.x);
+                    }
+                    shared_edges += 1;
+                }
+            }
+            assert!(shared_edges > 0);
+
+            // The far corner of the layer is painted within the last pixel of the last tile.
+            let last = requests.iter().max_by_key(|request| request.screen_rect.max_x() +
+                                                            request.screen_rect.max_y())
+                                      .unwrap();
+            let corner = last.paint_transform.transform_point(&Point2D::new(700.0, 500.0));
+            let size = last.device_size();
+            assert!(corner.x <= size.width as f32 && corner.x > size.width as f32 - 1.0);
+            assert!(corner.y <= size.height as f32 && corner.y > size.height as f32 - 1.0);
+        }
+    }
+
+    fn buffer_addresses(buffers: &[Box<LayerBuffer>]) -> Vec<*const LayerBuffer> {
+        buffers.iter().map(|buffer| &**buffer as *const LayerBuffer).collect()
+    }
+
+    /// Returns the addresses of the buffers the tiles of a 512x256 layer display.
+    fn displayed_buffers(layer: &Layer<()>) -> Vec<Option<*const LayerBuffer>> {
+        [Point2D::new(0, 0), Point2D::new(1, 0)].iter().map(|index| {
+            layer.with_tile(index, |tile, _| {
+                tile.unwrap().buffer().map(|buffer| buffer as *const LayerBuffer)
+            })
+        }).collect()
+    }
+
+    #[test]
+    fn buffer_sets_older_than_the_last_one_are_returned_untouched() {
+        let layer = layer(0.0, 0.0, 512.0, 256.0, 256);
+        let requests = request_at_scale(&layer, 1.0);
+        let new_set = |generation| {
+            LayerBufferSet::new(requests.iter().map(buffer_for_request).collect(), generation)
+        };
+
+        let second = new_set(2);
+        let second_buffers = buffer_addresses(&second.buffers);
+        let displaced = layer.swap_in_buffer_set(second);
+        assert!(displaced.buffers.is_empty());
+        assert_eq!(layer.buffer_set_generation(), Some(2));
+        assert_eq!(displayed_buffers(&layer),
+                   vec!(Some(second_buffers[0]), Some(second_buffers[1])));
+
+        // The first set arrives late, and is handed back as it came.
+        let first = new_set(1);
+        let first_buffers = buffer_addresses(&first.buffers);
+        let returned = layer.swap_in_buffer_set(first);
+        assert_eq!(returned.generation, 1);
+        assert_eq!(buffer_addresses(&returned.buffers), first_buffers);
+        assert_eq!(layer.buffer_set_generation(), Some(2));
+        assert_eq!(displayed_buffers(&layer),
+                   vec!(Some(second_buffers[0]), Some(second_buffers[1])));
+
+        // A newer set displaces the buffers of its tiles, and gives back those without a tile.
+        let mut orphan = buffer_for_request(&requests[1]);
+        orphan.screen_pos.origin.x = 1024;
+        let orphan_address = &*orphan as *const LayerBuffer;
+        let third = LayerBufferSet::new(vec!(buffer_for_request(&requests[0]), orphan), 3);
+        let third_buffers = buffer_addresses(&third.buffers);
+        let displaced = layer.swap_in_buffer_set(third);
+        assert_eq!(displaced.generation, 2);
+        let mut displaced_buffers = buffer_addresses(&displaced.buffers);
+        displaced_buffers.sort();
+        let mut expected = vec!(second_buffers[0], orphan_address);
+        expected.sort();
+        assert_eq!(displaced_buffers, expected);
+        assert_eq!(layer.buffer_set_generation(), Some(3));
+        assert_eq!(displayed_buffers(&layer),
+                   vec!(Some(third_buffers[0]), Some(second_buffers[1])));
+    }
+
+    #[test]
+    fn buffers_requested_before_a_resize_are_not_used() {
+        let layer = layer(0.0, 0.0, 200.0, 200.0, 256);
+        let before_resize = request_at_scale(&layer, 1.0);
+        assert_eq!(before_resize[0].screen_rect.size, Size2D::new(200, 200));
+
+        // The layer shrinks while the painter works on the request, and its answer arrives after
+        // the next request pass.
+        layer.set_bounds(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                        TypedSize2D::new(100.0, 150.0)));
+        assert!(request_at_scale(&layer, 1.0).is_empty());
+        layer.add_buffer(buffer_for_request(&before_resize[0]));
+        assert_eq!(first_tile_ages(&layer), (None, None));
+        let unused_buffers = layer.collect_unused_buffers();
+        assert_eq!(unused_buffers.len(), 1);
+        assert_eq!(unused_buffers[0].screen_pos.size, Size2D::new(200, 200));
+
+        // The tile is requested again at the new size, and that buffer is used.
+        let after_resize = request_at_scale(&layer, 1.0);
+        assert_eq!(after_resize.len(), 1);
+        assert_eq!(after_resize[0].screen_rect.size, Size2D::new(100, 150));
+        layer.add_buffer(buffer_for_request(&after_resize[0]));
+        assert_eq!(first_tile_ages(&layer), (Some(after_resize[0].content_age), None));
+        assert!(layer.collect_unused_buffers().is_empty());
+    }
+
+    #[test]
+    fn buffers_that_arent_aligned_to_a_tile_are_not_used() {
+        let layer = layer(0.0, 0.0, 512.0, 256.0, 256);
+        let requests = request_at_scale(&layer, 1.0);
+        let mut misaligned = buffer_for_request(&requests[1]);
+        misaligned.screen_pos.origin.x -= 10;
+        layer.add_buffer(misaligned);
+        assert_eq!(layer.collect_unused_buffers().len(), 1);
+        assert_eq!(layer.tile_statistics().tiles_with_buffers, 0);
+    }
+
+    #[test]
+    fn a_newer_buffer_arriving_first_isnt_replaced_by_the_older_one() {
+        let layer = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let requests_n = request_at_scale(&layer, 1.0);
+        layer.contents_changed();
+        let requests_n1 = request_at_scale(&layer, 1.0);
+
+        layer.add_buffer(buffer_for_request(&requests_n1[0]));
+        layer.add_buffer(buffer_for_request(&requests_n[0]));
+        assert_eq!(first_tile_ages(&layer), (Some(requests_n1[0].content_age), None));
+        assert_eq!(layer.collect_unused_buffers().len(), 1);
+        assert!(request_at_scale(&layer, 1.0).is_empty());
+    }
+
+    #[test]
+    fn content_ages_are_ordered_by_epoch_first() {
+        let mut age = ContentAge::new();
+        let first = age;
+        age.next();
+        assert!(age > first);
+        assert_eq!(age.ages_since(first), Some(1));
+        assert_eq!(first.ages_since(age), Some(0));
+
+        // Every age of the next epoch is newer than any age of this one.
+        for _ in 0..100 {
+            age.next();
+        }
+        let restarted = first.next_epoch();
+        assert_eq!(restarted.epoch(), first.epoch() + 1);
+        assert!(restarted > age);
+        assert_eq!(restarted.ages_since(age), None);
+        assert_eq!(age.ages_since(restarted), None);
+
+        assert_eq!(json::decode::<ContentAge>(&json::encode(&age).unwrap()).unwrap(), age);
+        assert!(ContentAge::new_epoch() > age);
+    }
+
+    #[test]
+    fn epochs_created_back_to_back_are_ordered() {
+        let first = ContentAge::new_epoch();
+        let second = ContentAge::new_epoch();
+        assert!(second.epoch() != first.epoch());
+        assert!(second > first);
+        assert_eq!(second.ages_since(first), None);
+    }
+
+    #[test]
+    fn epochs_are_still_ordered_after_wrapping_around() {
+        let mut last = ContentAge { epoch: u32::MAX, age: 0 };
+        last.next();
+        let wrapped = last.next_epoch();
+        assert_eq!(wrapped.epoch(), 0);
+        assert!(wrapped > last);
+        assert!(last < wrapped);
+        assert!(wrapped.next_epoch() > wrapped);
+    }
+
+    #[test]
+    fn buffers_of_an_earlier_epoch_are_outdated_by_a_restarted_painter() {
+        let layer = layer(0.0, 0.0, 256.0, 256.0, 256);
+        for _ in 0..3 {
+            layer.contents_changed();
+        }
+        let first = request_at_scale(&layer, 1.0);
+        layer.add_buffer(buffer_for_request(&first[0]));
+        layer.contents_changed();
+        let late = request_at_scale(&layer, 1.0);
+
+        // The painter restarts before answering, so its ages start over in a new epoch.
+        let restarted = first[0].content_age.next_epoch();
+        *layer.content_age.borrow_mut() = restarted;
+        assert_eq!(layer.stale_tile_count(), 1);
+        let requests = request_at_scale(&layer, 1.0);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].content_age, restarted);
+
+        // The late answer of the old painter is shown for now, but doesn't settle the tile.
+        layer.add_buffer(buffer_for_request(&late[0]));
+        assert_eq!(first_tile_ages(&layer), (Some(late[0].content_age), Some(restarted)));
+        assert!(request_at_scale(&layer, 1.0).is_empty());
+
+        // Once the new painter answers, buffers of the old epoch are turned away.
+        layer.add_buffer(buffer_for_request(&requests[0]));
+        assert_eq!(first_tile_ages(&layer), (Some(restarted), None));
+        assert_eq!(layer.collect_unused_buffers().len(), 2);
+        layer.add_buffer(buffer_for_request(&late[0]));
+        assert_eq!(first_tile_ages(&layer), (Some(restarted), None));
+        assert_eq!(layer.collect_unused_buffers().len(), 1);
+        assert_eq!(layer.stale_tile_count(), 0);
+    }
+
+    #[test]
+    fn scrolling_recycles_the_surfaces_of_tiles_left_behind() {
+        let layer = layer(0.0, 0.0, 256.0 * 8.0, 256.0, 256);
+        let mut recycled = vec!();
+        for column in 0..8 {
+            let viewport = TypedRect::new(TypedPoint2D::new(column as f32 * 256.0, 0.0),
+                                          TypedSize2D::new(256.0, 256.0));
+            let requests = layer.get_buffer_requests(viewport, viewport, ScaleFactor::new(1.0));
+            assert_eq!(requests.len(), 1);
+            assert_eq!(requests[0].screen_rect.origin.x, column * 256);
+            recycled.push(requests[0].native_surface.is_some());
+            for request in &requests {
+                layer.add_buffer(buffer_for_request(request));
+            }
+        }
+        // Each tile after the first reuses the surface of the tile scrolled out of view.
+        assert_eq!(recycled, vec!(false, true, true, true, true, true, true, true));
+        assert!(layer.collect_unused_buffers().is_empty());
+    }
+
+    #[test]
+    fn surfaces_of_another_size_are_not_recycled() {
+        let layer = layer(0.0, 0.0, 256.0 * 2.0 + 100.0, 256.0, 256);
+        let request_column = |column: usize| {
+            let viewport = TypedRect::new(TypedPoint2D::new(column as f32 * 256.0, 0.0),
+                                          TypedSize2D::new(100.0, 256.0));
+            layer.get_buffer_requests(viewport, viewport, ScaleFactor::new(1.0))
+        };
+        let first = request_column(0);
+        layer.add_buffer(buffer_for_request(&first[0]));
+
+        // The last tile is only 100 pixels wide.
+        let last = request_column(2);
+        assert_eq!(last[0].screen_rect.size.width, 100);
+        assert!(last[0].native_surface.is_none());
+        assert_eq!(layer.collect_unused_buffers().len(), 1);
+    }
+
+    /// The parts of a request that `compute_buffer_requests` and `apply_buffer_request_update`
+    /// must reproduce.
+    fn request_summary(request: &BufferRequest)
+                       -> (Rect<usize>, ContentAge, BufferRequestPriority, Option<Rect<usize>>,
+                           f32, bool) {
+        (request.screen_rect,
+         request.content_age,
+         request.priority,
+         request.visible_rect,
+         request.resolution,
+         request.native_surface.is_some())
+    }
+
+    #[test]
+    fn computing_then_applying_requests_matches_requesting_directly() {
+        let direct = layer(0.0, 0.0, 1024.0, 1024.0, 256);
+        let split = layer(0.0, 0.0, 1024.0, 1024.0, 256);
+        let steps = [(0.0, 0.0, 1.0, false),
+                     (128.0, 0.0, 1.0, false),
+                     (512.0, 300.0, 1.0, false),
+                     (512.0, 300.0, 1.0, true),
+                     (600.0, 700.0, 2.0, false),
+                     (0.0, 0.0, 1.0, true)];
+        for &(x, y, scale, contents_changed) in &steps {
+            let viewport = TypedRect::new(TypedPoint2D::new(x, y),
+                                          TypedSize2D::new(300.0, 300.0));
+            let dirty_rect = TypedRect::new(TypedPoint2D::new(x - 100.0, y - 100.0),
+                                            TypedSize2D::new(500.0, 500.0));
+            if contents_changed {
+                direct.contents_changed();
+                split.contents_changed();
+            }
+
+            let direct_requests =
+                direct.get_buffer_requests(dirty_rect, viewport, ScaleFactor::new(scale));
+            // Computing the requests changes nothing until they are applied.
+            let update = split.compute_buffer_requests(dirty_rect,
+                                                       viewport,
+                                                       ScaleFactor::new(scale),
+                                                       &[],
+                                                       RequestOrdering::ColumnMajor);
+            drop(update);
+            let update = split.compute_buffer_requests(dirty_rect,
+                                                       viewport,
+                                                       ScaleFactor::new(scale),
+                                                       &[],
+                                                       RequestOrdering::ColumnMajor);
+            let split_requests = split.apply_buffer_request_update(update);
+
+            assert!(!direct_requests.is_empty());
+            assert_eq!(split_requests.iter().map(request_summary).collect::<Vec<_>>(),
+                       direct_requests.iter().map(request_summary).collect::<Vec<_>>());
+            assert_eq!(split.tile_statistics(), direct.tile_statistics());
+            for (layer, requests) in vec!((&direct, direct_requests), (&split, split_requests)) {
+                for request in &requests {
+                    layer.add_buffer(buffer_for_request(request));
+                }
+            }
+            assert_eq!(split.collect_unused_buffers().len(),
+                       direct.collect_unused_buffers().len());
+        }
+    }
+
+    #[test]
+    fn updates_can_be_computed_on_other_threads() {
+        fn assert_send<T: Send>() {}
+        assert_send::<TileGridUpdate>();
+        assert_send::<BufferRequest>();
+    }
+
+    #[test]
+    fn double_buffered_tiles_hand_the_back_surface_to_the_next_request() {
+        let layer = layer(0.0, 0.0, 256.0, 256.0, 256);
+        layer.set_double_buffered(true);
+        let first = request_at_scale(&layer, 1.0);
+        assert!(first[0].native_surface.is_none());
+        layer.add_buffer(buffer_for_request(&first[0]));
+        let single_buffer_memory = layer.get_memory_usage();
+
+        // Nothing has been displayed before the first buffer, so there is no back surface yet.
+        layer.contents_changed();
+        let second = request_at_scale(&layer, 1.0);
+        assert!(second[0].native_surface.is_none());
+        layer.add_buffer(buffer_for_request(&second[0]));
+
+        // The old front buffer is kept as the back buffer instead of being released.
+        assert_eq!(first_tile_ages(&layer), (Some(second[0].content_age), None));
+        assert!(layer.collect_unused_buffers().is_empty());
+        assert_eq!(layer.get_memory_usage(), 2 * single_buffer_memory);
+
+        // The next request paints into the back surface, which leaves the tile with only the
+        // displayed buffer until the answer arrives.
+        layer.contents_changed();
+        let third = request_at_scale(&layer, 1.0);
+        assert!(third[0].native_surface.is_some());
+        assert_eq!(layer.get_memory_usage(), single_buffer_memory);
+        assert_eq!(first_tile_ages(&layer),
+                   (Some(second[0].content_age), Some(third[0].content_age)));
+
+        // The answer flips the buffers again.
+        layer.add_buffer(buffer_for_request(&third[0]));
+        assert_eq!(first_tile_ages(&layer), (Some(third[0].content_age), None));
+        assert!(layer.collect_unused_buffers().is_empty());
+        assert_eq!(layer.get_memory_usage(), 2 * single_buffer_memory);
+    }
+
+    #[test]
+    fn single_buffered_tiles_release_the_replaced_buffer() {
+        let layer = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let first = request_at_scale(&layer, 1.0);
+        layer.add_buffer(buffer_for_request(&first[0]));
+        layer.contents_changed();
+        let second = request_at_scale(&layer, 1.0);
+        layer.add_buffer(buffer_for_request(&second[0]));
+        assert_eq!(layer.collect_unused_buffers().len(), 1);
+
+        layer.contents_changed();
+        assert!(request_at_scale(&layer, 1.0)[0].native_surface.is_none());
+    }
+
+    #[test]
+    fn turning_double_buffering_off_releases_the_back_buffers() {
+        let layer = layer(0.0, 0.0, 256.0, 256.0, 256);
+        layer.set_double_buffered(true);
+        for _ in 0..2 {
+            layer.contents_changed();
+            let requests = request_at_scale(&layer, 1.0);
+            layer.add_buffer(buffer_for_request(&requests[0]));
+        }
+        let double_buffer_memory = layer.get_memory_usage();
+
+        layer.set_double_buffered(false);
+        assert_eq!(layer.collect_unused_buffers().len(), 1);
+        assert_eq!(layer.get_memory_usage(), double_buffer_memory / 2);
+
+        // Replaced buffers are released right away from now on.
+        layer.contents_changed();
+        let requests = request_at_scale(&layer, 1.0);
+        assert!(requests[0].native_surface.is_none());
+        layer.add_buffer(buffer_for_request(&requests[0]));
+        assert_eq!(layer.collect_unused_buffers().len(), 1);
+    }
+
+    #[test]
+    fn buffers_painted_before_a_zoom_are_rejected() {
+        let layer = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let old_requests = request_at_scale(&layer, 1.0);
+        assert_eq!(old_requests.len(), 1);
+        assert_eq!(old_requests[0].resolution, 1.0);
+
+        // The old buffer has the size of a tile at the new scale, but must not be shown there.
+        let new_requests = request_at_scale(&layer, 2.0);
+        assert_eq!(new_requests.len(), 4);
+        assert!(!add_buffer_checked(&layer, buffer_for_request(&old_requests[0]), 2.0, 0));
+        let statistics = layer.tile_statistics();
+        assert_eq!(statistics.tiles_with_buffers, 0);
+        assert_eq!(statistics.unused_buffer_count, 1);
+
+        // The requests at the new scale stay pending.
+        assert!(request_at_scale(&layer, 2.0).is_empty());
+
+        for request in &new_requests {
+            assert_eq!(request.resolution, 2.0);
+            assert!(add_buffer_checked(&layer, buffer_for_request(request), 2.0, 1));
+        }
+        assert_eq!(layer.tile_statistics().tiles_with_buffers, 4);
+    }
+
+    #[test]
+    fn buffers_keeping_the_wrong_resolution_are_accepted_eventually() {
+        let layer = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let requests = request_at_scale(&layer, 1.0);
+        let mismatched_buffer = || {
+            let mut buffer = buffer_for_request(&requests[0]);
+            buffer.resolution = 1.01;
+            buffer
+        };
+
+        for frame in 0..RESOLUTION_LOOP_REJECTION_LIMIT {
+            assert!(!add_buffer_checked(&layer, mismatched_buffer(), 1.0, frame));
+            // The tile is requested again each time.
+            assert_eq!(request_at_scale(&layer, 1.0).len(), 1);
+        }
+        assert!(add_buffer_checked(&layer,
+                                   mismatched_buffer(),
+                                   1.0,
+                                   RESOLUTION_LOOP_REJECTION_LIMIT));
+        assert_eq!(layer.tile_statistics().tiles_with_buffers, 1);
+    }
+
+    #[test]
+    fn buffers_a_few_ulps_off_a_fractional_scale_are_accepted_at_once() {
+        // Painters derive the resolution with their own float arithmetic, which can land a few
+        // ulps away from the scale of the request. An absolute 1e-6 tolerance rejected these
+        // forever, and the tiles were repainted on every frame.
+        for &scale in &[1.1f32, 2.7, 3.3] {
+            let layer = layer(0.0, 0.0, 256.0, 256.0, 256);
+            let requests = request_at_scale(&layer, scale);
+            assert!(!requests.is_empty());
+            for request in &requests {
+                let mut buffer = buffer_for_request(request);
+                buffer.resolution = f32::from_bits(scale.to_bits() + 16);
+                assert!((buffer.resolution - scale).abs() > 1e-6);
+                assert!(add_buffer_checked(&layer, buffer, scale, 0));
+            }
+            assert_eq!(layer.tile_statistics().unused_buffer_count, 0);
+            assert!(request_at_scale(&layer, scale).is_empty());
+        }
+    }
+
+    #[test]
+    fn buffers_painted_before_a_zoom_are_not_added_to_their_tiles() {
+        let layer = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let old_requests = request_at_scale(&layer, 1.0);
+        assert_eq!(old_requests.len(), 1);
+
+        // The zoom changes after the request but before its answer arrives.
+        let new_requests = request_at_scale(&layer, 2.0);
+        assert_eq!(new_requests.len(), 4);
+        layer.add_buffer(buffer_for_request(&old_requests[0]));
+        let statistics = layer.tile_statistics();
+        assert_eq!(statistics.tiles_with_buffers, 0);
+        assert_eq!(statistics.unused_buffer_count, 1);
+
+        // The tiles stay pending at the new scale and take the buffers painted at it.
+        assert!(request_at_scale(&layer, 2.0).is_empty());
+        for request in &new_requests {
+            layer.add_buffer(buffer_for_request(request));
+        }
+        assert_eq!(layer.tile_statistics().tiles_with_buffers, 4);
+
+        // A buffer a tolerance away from the scale is rejected too, and its tile requested again.
+        layer.contents_changed();
+        let requests = request_at_scale(&layer, 2.0);
+        let mut buffer = buffer_for_request(&requests[0]);
+        buffer.resolution = 2.01;
+        layer.add_buffer(buffer);
+        assert_eq!(layer.collect_unused_buffers().len(), 2);
+        assert_eq!(request_at_scale(&layer, 2.0).len(), 1);
+    }
+
+    /// A 4x4 source image whose pixels hold their index in the blue channel.
+    fn source_pixels() -> Vec<u8> {
+        (0..16).flat_map(|index| vec!(index as u8, 0, 0, 255)).collect()
+    }
+
+    fn request(x: f32, y: f32, page_size: f32, device_size: usize, resolution: f32)
+               -> BufferRequest {
+        let mut request = BufferRequest::new(Rect::new(Point2D::zero(),
+                                                       Size2D::new(device_size, device_size)),
+                                             Rect::new(Point2D::new(x, y),
+                                                       Size2D::new(page_size, page_size)),
+                                             ContentAge::new());
+        request.resolution = resolution;
+        request
+    }
+
+    /// Returns the source indices held by the pixels of the buffer, or None for transparent ones.
+    fn sampled_indices(buffer: &LayerBuffer) -> Vec<Option<u8>> {
+        let pixels = buffer.native_surface.read_pixels(&headless_display()).unwrap();
+        pixels.chunks(4).map(|pixel| if pixel[3] == 0 { None } else { Some(pixel[0]) }).collect()
+    }
+
+    #[test]
+    fn pixels_outside_the_source_are_transparent() {
+        let buffer = LayerBuffer::from_request_and_pixels(request(2.0, -1.0, 3.0, 3, 1.0),
+                                                          &headless_display(),
+                                                          &source_pixels(),
+                                                          Size2D::new(4, 4),
+                                                          16).unwrap();
+        assert_eq!(sampled_indices(&buffer),
+                   vec!(None, None, None,
+                        Some(2), Some(3), None,
+                        Some(6), Some(7), None));
+        assert_eq!(buffer.rect, Rect::new(Point2D::new(2.0, -1.0), Size2D::new(3.0, 3.0)));
+        assert_eq!(buffer.screen_pos.size, Size2D::new(3, 3));
+    }
+
+    #[test]
+    fn pixels_are_sampled_at_the_requested_resolution() {
+        let buffer = LayerBuffer::from_request_and_pixels(request(1.0, 1.0, 2.0, 4, 2.0),
+                                                          &headless_display(),
+                                                          &source_pixels(),
+                                                          Size2D::new(4, 4),
+                                                          16).unwrap();
+        assert_eq!(buffer.resolution, 2.0);
+        assert_eq!(sampled_indices(&buffer),
+                   vec!(Some(5), Some(5), Some(6), Some(6),
+                        Some(5), Some(5), Some(6), Some(6),
+                        Some(9), Some(9), Some(10), Some(10),
+                        Some(9), Some(9), Some(10), Some(10)));
+    }
+
+    #[test]
+    fn the_cached_surface_is_reused_if_it_fits() {
+        let display = headless_display();
+        let cached_surface = |size| {
+            let mut surface = MemoryBufferNativeSurface::new(&display, Size2D::new(size, size));
+            surface.is_fallback = true;
+            Some(NativeSurface::MemoryBuffer(surface))
+        };
+
+        let mut fitting_request = request(0.0, 0.0, 2.0, 2, 1.0);
+        fitting_request.native_surface = cached_surface(2);
+        let buffer = LayerBuffer::from_request_and_pixels(fitting_request,
+                                                          &display,
+                                                          &source_pixels(),
+                                                          Size2D::new(4, 4),
+                                                          16).unwrap();
+        assert!(buffer.native_surface.is_fallback());
+
+        let mut other_request = request(0.0, 0.0, 2.0, 2, 1.0);
+        other_request.native_surface = cached_surface(3);
+        let buffer = LayerBuffer::from_request_and_pixels(other_request,
+                                                          &display,
+                                                          &source_pixels(),
+                                                          Size2D::new(4, 4),
+                                                          16).unwrap();
+        assert!(!buffer.native_surface.is_fallback());
+        assert_eq!(buffer.native_surface.get_size(), Size2D::new(2, 2));
+    }
+
+    #[test]
+    fn sources_too_small_for_their_stride_are_refused() {
+        match LayerBuffer::from_request_and_pixels(request(0.0, 0.0, 2.0, 2, 1.0),
+                                                   &headless_display(),
+                                                   &source_pixels(),
+                                                   Size2D::new(4, 4),
+                                                   20) {
+            Err(SurfaceError::SourceTooSmall) => {}
+            _ => panic!("expected SourceTooSmall"),
+        }
+    }
+
+    #[test]
+    fn changing_the_tile_size_requests_the_layer_again_once() {
+        let layer = layer(0.0, 0.0, 512.0, 512.0, 256);
+        let old_requests = request_at_scale(&layer, 1.0);
+        assert_eq!(old_requests.len(), 4);
+        for request in &old_requests[1..] {
+            layer.add_buffer(buffer_for_request(request));
+        }
+        let content_age = *layer.content_age.borrow();
+
+        layer.set_tile_size(512);
+        assert_eq!(layer.tile_size.get(), 512);
+        assert_eq!(layer.collect_unused_buffers().len(), 3);
+        assert_eq!(*layer.content_age.borrow(), content_age);
+
+        let new_requests = request_at_scale(&layer, 1.0);
+        assert_eq!(new_requests.len(), 1);
+        assert_eq!(new_requests[0].screen_rect.size, Size2D::new(512, 512));
+        assert_eq!(new_requests[0].content_age, content_age);
+
+        // The buffer still being painted at the old size lines up with the new tile, but is put
+        // with the unused buffers, without being shown or making the tile be requested again.
+        assert_eq!(old_requests[0].screen_rect.origin, Point2D::zero());
+        layer.add_buffer(buffer_for_request(&old_requests[0]));
+        assert_eq!(layer.collect_unused_buffers().len(), 1);
+        assert_eq!(layer.tile_statistics().tiles_with_buffers, 0);
+        assert!(request_at_scale(&layer, 1.0).is_empty());
+
+        layer.add_buffer(buffer_for_request(&new_requests[0]));
+        assert_eq!(layer.tile_statistics().tiles_with_buffers, 1);
+        assert!(layer.collect_unused_buffers().is_empty());
+    }
+
+    #[test]
+    fn tile_sizes_are_clamped_to_the_maximum_texture_size() {
+        let layer = layer(0.0, 0.0, 1024.0, 512.0, 512);
+        layer.set_max_tile_size(Some(256));
+        assert_eq!(layer.effective_tile_size(), 256);
+        let requests = request_at_scale(&layer, 1.0);
+        assert_eq!(requests.len(), 8);
+        assert!(requests.iter().all(|request| request.screen_rect.size == Size2D::new(256, 256)));
+
+        // The requested size is kept, and applies again once the maximum is lifted.
+        layer.set_tile_size(1024);
+        assert_eq!((layer.tile_size.get(), layer.effective_tile_size()), (1024, 256));
+        layer.set_max_tile_size(Some(0));
+        assert_eq!(layer.effective_tile_size(), 1024);
+        layer.set_max_tile_size(None);
+        assert_eq!(layer.effective_tile_size(), 1024);
+        assert_eq!(clamp_tile_size(1024, Some(4096)), 1024);
+        assert_eq!(clamp_tile_size(8192, Some(4096)), 4096);
+    }
+
+    #[test]
+    fn creating_textures_clamps_the_tiles_to_the_maximum_texture_size() {
+        let gl: Rc<GlApi> = record_gl_calls();
+        let layer = layer(0.0, 0.0, 1024.0, 512.0, 1024);
+        let requests = request_at_scale(&layer, 1.0);
+        assert_eq!(requests.len(), 1);
+        layer.add_buffer(buffer_for_request(&requests[0]));
+        layer.create_textures(&gl,
+                              &headless_display(),
+                              &Capabilities::from_strings("2.1", "", 512),
+                              None);
+
+        // The oversized tile is thrown out and requested again in pieces that can be displayed.
+        assert_eq!(layer.effective_tile_size(), 512);
+        assert_eq!(layer.collect_unused_buffers().len(), 1);
+        assert_eq!(request_at_scale(&layer, 1.0).len(), 2);
+    }
+
+    #[test]
+    fn tiles_whose_surface_fails_to_bind_are_not_drawn() {
+        let gl: Rc<GlApi> = record_gl_calls();
+        let layer = layer(0.0, 0.0, 512.0, 256.0, 256);
+        let display = headless_display();
+        for (index, request) in request_at_scale(&layer, 1.0).iter().enumerate() {
+            let mut buffer = buffer_for_request(request);
+            if index == 0 {
+                buffer.native_surface.destroy(&display).unwrap();
+            }
+            layer.add_buffer(buffer);
+        }
+        layer.create_textures(&gl, &display, &Capabilities::from_strings("2.1", "", 4096), None);
+
+        let mut drawable = vec!();
+        layer.do_for_all_tiles(|tile| drawable.push(tile.is_drawable()));
+        drawable.sort();
+        assert_eq!(drawable, vec!(false, true));
+    }
+
+    #[test]
+    fn evicted_tiles_are_textured_again_without_requests() {
+        let gl: Rc<GlApi> = record_gl_calls();
+        let layer = layer(0.0, 0.0, 512.0, 256.0, 256);
+        for request in &request_at_scale(&layer, 1.0) {
+            layer.add_buffer(buffer_for_request(request));
+        }
+        layer.update_transform_state(&Matrix4D::identity(),
+                                     &Matrix4D::identity(),
+                                     &Point2D::zero());
+        let capabilities = Capabilities::from_strings("2.1", "", 4096);
+        layer.create_textures(&gl, &headless_display(), &capabilities, None);
+        let drawable_tiles = || {
+            let mut origins = vec!();
+            layer.do_for_all_tiles(|tile| {
+                if tile.is_drawable() {
+                    origins.push(tile.bounds.unwrap().origin.x);
+                }
+            });
+            origins.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            origins
+        };
+        assert_eq!(drawable_tiles(), vec!(0.0, 256.0));
+
+        // Only the left tile is in view.
+        let viewport = TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                      TypedSize2D::new(200.0, 256.0));
+        layer.evict_offscreen_textures(&viewport, ScaleFactor::new(1.0));
+        assert_eq!(drawable_tiles(), vec!(0.0));
+        assert_eq!(layer.tile_statistics().tiles_with_buffers, 2);
+        assert!(request_at_scale(&layer, 1.0).is_empty());
+
+        // The evicted tile is textured from its buffer again, without repainting.
+        layer.create_textures(&gl, &headless_display(), &capabilities, None);
+        assert_eq!(drawable_tiles(), vec!(0.0, 256.0));
+        assert!(request_at_scale(&layer, 1.0).is_empty());
+        assert!(layer.collect_unused_buffers().is_empty());
+    }
+
+    /// Paints the four 64-pixel tiles of a 128x128 layer with surfaces holding pixels, and
+    /// returns a snapshot of its tile cache after a round trip through JSON.
+    fn exported_tile_cache() -> TileCacheSnapshot {
+        let layer = layer(0.0, 0.0, 128.0, 128.0, 64);
+        let display = headless_display();
+        for (index, request) in request_at_scale(&layer, 1.0).iter().enumerate() {
+            let mut buffer = buffer_for_request(request);
+            let pixels = vec![index as u8; 64 * 64 * 4];
+            assert!(buffer.native_surface.upload(&display, &pixels).is_ok());
+            layer.add_buffer(buffer);
+        }
+        let snapshot = layer.export_tile_cache();
+        assert_eq!(snapshot.tiles.len(), 4);
+        json::decode(&json::encode(&snapshot).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn imported_tile_caches_are_drawn_without_new_requests() {
+        let gl = record_gl_calls();
+        let gl_api: Rc<GlApi> = gl.clone();
+        let snapshot = exported_tile_cache();
+        let layer = layer(0.0, 0.0, 128.0, 128.0, 64);
+        update(&layer);
+        assert_eq!(layer.import_tile_cache(&snapshot), 4);
+        assert!(request_at_scale(&layer, 1.0).is_empty());
+        assert_eq!(layer.tile_statistics().tiles_with_buffers, 4);
+
+        // The textures are created from the imported pixels when the tiles are first drawn.
+        gl.take_calls();
+        layer.create_textures(&gl_api,
+                              &headless_display(),
+                              &Capabilities::from_strings("2.1", "", 4096),
+                              None);
+        let mut drawable_count = 0;
+        layer.do_for_all_tiles(|tile| if tile.is_drawable() { drawable_count += 1 });
+        assert_eq!(drawable_count, 4);
+        let mut uploads: Vec<_> = gl.take_calls().into_iter().filter_map(|call| match call {
+            GlCall::TexSubImage2D { data, .. } => Some((data.len, data.digest)),
+            _ => None,
+        }).collect();
+        // Each tile is uploaded whole, with the pixels of its own surface.
+        uploads.sort();
+        uploads.dedup();
+        assert_eq!(uploads.len(), 4);
+        assert!(uploads.iter().all(|&(len, _)| len == 64 * 64 * 4));
+
+        // Once the content changes, the imported buffers are stale like painted ones.
+        layer.contents_changed();
+        assert_eq!(request_at_scale(&layer, 1.0).len(), 4);
+    }
+
+    #[test]
+    fn tile_caches_of_another_tile_size_or_layer_size_are_not_imported() {
+        let snapshot = exported_tile_cache();
+        for layer in &[layer(0.0, 0.0, 128.0, 128.0, 32), layer(0.0, 0.0, 128.0, 64.0, 64)] {
+            update(layer);
+            assert_eq!(layer.import_tile_cache(&snapshot), 0);
+            assert_eq!(layer.tile_statistics().tiles_with_buffers, 0);
+            assert_eq!(layer.collect_unused_buffers().len(), 4);
+            assert!(!request_at_scale(layer, 1.0).is_empty());
+        }
+    }
+
+    #[test]
+    fn removed_descendants_keep_their_buffers_for_collection() {
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let middle = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let leaf = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let sibling = layer(0.0, 0.0, 256.0, 256.0, 256);
+        middle.add_child(leaf.clone());
+        middle.add_child(sibling.clone());
+        root.add_child(middle.clone());
+        for request in &request_at_scale(&leaf, 1.0) {
+            leaf.add_buffer(buffer_for_request(request));
+        }
+
+        // The leaf is a grandchild, so only the subtree search finds it.
+        assert!(!root.remove_child(&leaf));
+        assert!(root.remove_descendant(&leaf));
+        assert_eq!(middle.children().len(), 1);
+        assert!(&*middle.children()[0] as *const Layer<()> == &*sibling as *const Layer<()>);
+        assert!(!root.remove_descendant(&leaf));
+
+        assert_eq!(leaf.collect_buffers().len(), 1);
+        assert!(leaf.collect_buffers().is_empty());
+    }
+
+    fn update(layer: &Layer<()>) {
+        layer.update_transform_state(&Matrix4D::identity(),
+                                     &Matrix4D::identity(),
+                                     &Point2D::zero());
+    }
+
+    fn assert_marks_transform_state_dirty<F>(layer: &Layer<()>, setter: F)
+                                             where F: Fn(&Layer<()>) {
+        update(layer);
+        assert!(!layer.has_dirty_transform_state());
+        setter(layer);
+        assert!(layer.has_dirty_transform_state());
+    }
+
+    #[test]
+    fn setters_mark_the_transform_state_dirty() {
+        let layer = layer(0.0, 0.0, 100.0, 100.0, 256);
+        let matrix = Matrix4D::identity().pre_translated(1.0, 0.0, 0.0);
+        assert_marks_transform_state_dirty(&layer, |layer| layer.set_transform(matrix));
+        assert_marks_transform_state_dirty(&layer, |layer| layer.set_perspective(matrix));
+        assert_marks_transform_state_dirty(&layer, |layer| {
+            layer.set_transform_origin(Point3D::new(50.0, 50.0, 0.0))
+        });
+        assert_marks_transform_state_dirty(&layer, |layer| {
+            layer.set_bounds(TypedRect::new(TypedPoint2D::new(5.0, 5.0),
+                                            TypedSize2D::new(10.0, 10.0)))
+        });
+        assert_marks_transform_state_dirty(&layer, |layer| {
+            layer.set_content_offset(TypedPoint2D::new(-3.0, 0.0))
+        });
+        assert_marks_transform_state_dirty(&layer, |layer| layer.set_masks_to_bounds(true));
+        assert_marks_transform_state_dirty(&layer, |layer| layer.set_opacity(0.5));
+        assert_marks_transform_state_dirty(&layer, |layer| {
+            layer.set_fixed_to_viewport(Some(TypedPoint2D::new(1.0, 1.0)))
+        });
+    }
+
+    fn screen_rect_of(layer: &Layer<()>) -> Rect<f32> {
+        update(layer);
+        layer.transform_state.borrow().screen_rect.as_ref().unwrap().rect.to_untyped()
+    }
+
+    fn assert_rect_near(rect: Rect<f32>, x: f32, y: f32, width: f32, height: f32) {
+        let expected = [x, y, width, height];
+        let actual = [rect.origin.x, rect.origin.y, rect.size.width, rect.size.height];
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 0.001, "{:?} isn't near {:?}", rect, expected);
+        }
+    }
+
+    #[test]
+    fn layers_are_transformed_about_their_transform_origin() {
+        let layer = layer(20.0, 30.0, 100.0, 100.0, 256);
+        let quarter_turn = Matrix4D::create_rotation(0.0, 0.0, 1.0, Radians::new(PI / 2.0));
+        layer.set_transform(quarter_turn);
+
+        // By default, layers turn about their top left corner.
+        assert_rect_near(screen_rect_of(&layer), 20.0, -70.0, 100.0, 100.0);
+
+        // A square turned a quarter about its center stays in place, and keeps doing so when it
+        // moves, since the origin is relative to the layer.
+        layer.set_transform_origin(Point3D::new(50.0, 50.0, 0.0));
+        assert_rect_near(screen_rect_of(&layer), 20.0, 30.0, 100.0, 100.0);
+        layer.set_bounds(TypedRect::new(TypedPoint2D::new(60.0, 0.0),
+                                        TypedSize2D::new(100.0, 100.0)));
+        assert_rect_near(screen_rect_of(&layer), 60.0, 0.0, 100.0, 100.0);
+
+        // Scaling about the center grows the layer on all sides.
+        layer.set_transform(Matrix4D::identity().pre_scaled(2.0, 2.0, 1.0));
+        assert_rect_near(screen_rect_of(&layer), 10.0, -50.0, 200.0, 200.0);
+    }
+
+    #[test]
+    fn perspective_is_applied_about_the_transform_origin() {
+        let parent = layer(0.0, 0.0, 200.0, 200.0, 256);
+        let child = layer(50.0, 50.0, 100.0, 100.0, 256);
+        parent.add_child(child.clone());
+        let mut perspective = Matrix4D::identity();
+        perspective.m34 = -1.0 / 100.0;
+        parent.set_perspective(perspective);
+        parent.set_transform_origin(Point3D::new(100.0, 100.0, 0.0));
+        child.set_transform(Matrix4D::identity().pre_translated(0.0, 0.0, -100.0));
+
+        // Pushed away from a viewer centered on it, the child shrinks about the center of the
+        // parent, to half its size.
+        update(&parent);
+        let rect = child.transform_state.borrow().screen_rect.as_ref().unwrap().rect.to_untyped();
+        assert_rect_near(rect, 75.0, 75.0, 50.0, 50.0);
+    }
+
+    fn assert_point_near(point: Option<Point2D<f32>>, x: f32, y: f32) {
+        let point = point.unwrap();
+        assert!((point.x - x).abs() < 0.001 && (point.y - y).abs() < 0.001,
+                "{:?} isn't near ({}, {})", point, x, y);
+    }
+
+    #[test]
+    fn screen_points_are_converted_to_translated_and_rotated_layers() {
+        let layer = layer(20.0, 30.0, 100.0, 100.0, 256);
+        layer.set_transform(Matrix4D::identity().pre_translated(5.0, 10.0, 0.0));
+        update(&layer);
+        assert_point_near(layer.convert_point_from_screen(Point2D::new(25.0, 40.0)), 0.0, 0.0);
+        assert_point_near(layer.convert_point_from_screen(Point2D::new(35.0, 45.0)), 10.0, 5.0);
+
+        // Turned a quarter about its center, the top edge of the layer becomes its left edge.
+        layer.set_transform_origin(Point3D::new(50.0, 50.0, 0.0));
+        layer.set_transform(Matrix4D::create_rotation(0.0, 0.0, 1.0, Radians::new(PI / 2.0)));
+        update(&layer);
+        assert!(layer.transform_state.borrow().inverse_transform.is_some());
+        assert_point_near(layer.convert_point_from_screen(Point2D::new(20.0, 120.0)), 10.0, 0.0);
+        assert_point_near(layer.convert_point_from_screen(Point2D::new(70.0, 80.0)), 50.0, 50.0);
+    }
+
+    #[test]
+    fn screen_points_are_converted_through_perspective() {
+        let parent = layer(0.0, 0.0, 200.0, 200.0, 256);
+        let child = layer(50.0, 50.0, 100.0, 100.0, 256);
+        parent.add_child(child.clone());
+        let mut perspective = Matrix4D::identity();
+        perspective.m34 = -1.0 / 100.0;
+        parent.set_perspective(perspective);
+        parent.set_transform_origin(Point3D::new(100.0, 100.0, 0.0));
+        child.set_transform(Matrix4D::identity().pre_translated(0.0, 0.0, -100.0));
+        update(&parent);
+
+        // The child is drawn at half its size about the center of the parent.
+        assert_point_near(child.convert_point_from_screen(Point2D::new(75.0, 75.0)), 0.0, 0.0);
+        assert_point_near(child.convert_point_from_screen(Point2D::new(100.0, 100.0)), 50.0, 50.0);
+        assert_point_near(child.convert_point_from_screen(Point2D::new(125.0, 75.0)), 100.0, 0.0);
+
+        // Tilted back, the plane of the child recedes to a horizon, beyond which no point of the
+        // screen maps onto it.
+        child.set_transform_origin(Point3D::new(50.0, 50.0, 0.0));
+        child.set_transform(Matrix4D::create_rotation(1.0, 0.0, 0.0, Radians::new(PI / 3.0)));
+        update(&parent);
+        assert_point_near(child.convert_point_from_screen(Point2D::new(100.0, 100.0)), 50.0, 50.0);
+        assert!(child.convert_point_from_screen(Point2D::new(100.0, -10000.0)).is_some());
+        assert!(child.convert_point_from_screen(Point2D::new(100.0, 10000.0)).is_none());
+    }
+
+    #[test]
+    fn layers_flattened_to_nothing_convert_no_points() {
+        let layer = layer(20.0, 30.0, 100.0, 100.0, 256);
+        for transform in &[Matrix4D::identity().pre_scaled(0.0, 0.0, 1.0),
+                           Matrix4D::identity().pre_scaled(0.0, 1.0, 1.0),
+                           Matrix4D::create_rotation(0.0, 1.0, 0.0, Radians::new(PI / 2.0))] {
+            layer.set_transform(*transform);
+            update(&layer);
+            assert!(layer.transform_state.borrow().inverse_transform.is_none());
+            assert!(layer.convert_point_from_screen(Point2D::new(20.0, 30.0)).is_none());
+        }
+
+        layer.set_transform(Matrix4D::identity());
+        update(&layer);
+        assert_point_near(layer.convert_point_from_screen(Point2D::new(20.0, 30.0)), 0.0, 0.0);
+    }
+
+    fn assert_rect_is_finite(rect: &Rect<f32>) {
+        let values = [rect.origin.x, rect.origin.y, rect.size.width, rect.size.height];
+        assert!(values.iter().all(|value| value.is_finite()), "{:?} isn't finite", rect);
+    }
+
+    #[test]
+    fn singular_and_nearly_singular_transforms_leave_no_nans_in_the_clip_math() {
+        let layer = layer(20.0, 30.0, 100.0, 100.0, 256);
+        layer.set_transform_origin(Point3D::new(50.0, 50.0, 0.0));
+        let clip = TypedRect::new(TypedPoint2D::new(0.0, 0.0), TypedSize2D::new(200.0, 200.0));
+
+        // Scaled up, the clip rect is back-transformed into world space.
+        layer.set_transform(Matrix4D::identity().pre_scaled(2.0, 2.0, 1.0));
+        update(&layer);
+        assert_rect_near(layer.world_clip_rect(Some(clip)).unwrap(), 35.0, 40.0, 100.0, 100.0);
+
+        // Scaled to nothing, the layer is treated as clipped away.
+        layer.set_transform(Matrix4D::identity().pre_scaled(0.0, 0.0, 1.0));
+        update(&layer);
+        assert!(layer.transform_state.borrow().screen_rect.is_none());
+        assert!(layer.transform_state.borrow().inverse_transform.is_none());
+        assert!(layer.world_clip_rect(Some(clip)).is_none());
+
+        // Scaled to next to nothing, it can't be inverted, so it is drawn unclipped, as a speck
+        // at its center.
+        layer.set_transform(Matrix4D::identity().pre_scaled(1e-20, 1e-20, 1.0));
+        let rect = screen_rect_of(&layer);
+        assert_rect_is_finite(&rect);
+        assert_rect_near(rect, 70.0, 80.0, 0.0, 0.0);
+        assert!(layer.transform_state.borrow().inverse_transform.is_none());
+        assert!(layer.world_clip_rect(Some(clip)).is_none());
+        assert_eq!(layer.shader_clip(Some(clip), None), None);
+
+        // Rotated, the clip rect can't be back-transformed, and is applied in screen space
+        // instead.
+        layer.set_transform(Matrix4D::create_rotation(0.0, 0.0, 1.0, Radians::new(PI / 4.0)));
+        let rect = screen_rect_of(&layer);
+        assert_rect_is_finite(&rect);
+        let diagonal = 100.0 * 2.0f32.sqrt();
+        assert_rect_near(rect, 70.0 - diagonal / 2.0, 80.0 - diagonal / 2.0, diagonal, diagonal);
+        let inverse = layer.transform_state.borrow().inverse_transform.unwrap();
+        assert_rect_is_finite(&inverse.to_2d().transform_rect(&clip.to_untyped()));
+        assert!(layer.world_clip_rect(Some(clip)).is_none());
+        let small_clip = TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                        TypedSize2D::new(100.0, 100.0));
+        assert_eq!(layer.shader_clip(Some(small_clip), None).unwrap().rect, small_clip);
+    }
+
+    #[test]
+    fn content_offsets_are_clamped_at_every_edge() {
+        let scroller = layer(0.0, 0.0, 100.0, 100.0, 256);
+        scroller.add_child(layer(-50.0, -20.0, 300.0, 200.0, 256));
+        let clamped = |x, y| {
+            scroller.set_content_offset_clamped(TypedPoint2D::new(x, y));
+            scroller.content_offset()
+        };
+        // The content spans from (-50, -20) to (250, 180).
+        assert_eq!(clamped(100.0, 0.0), TypedPoint2D::new(50.0, 0.0));
+        assert_eq!(clamped(0.0, 100.0), TypedPoint2D::new(0.0, 20.0));
+        assert_eq!(clamped(-500.0, 0.0), TypedPoint2D::new(-150.0, 0.0));
+        assert_eq!(clamped(0.0, -500.0), TypedPoint2D::new(0.0, -80.0));
+        assert_eq!(clamped(-10.0, 10.0), TypedPoint2D::new(-10.0, 10.0));
+
+        // Scrolling past an edge stops there, and further scrolling changes nothing.
+        assert!(scroller.scroll_by(TypedPoint2D::new(1000.0, 0.0)));
+        assert_eq!(scroller.content_offset(), TypedPoint2D::new(50.0, 10.0));
+        assert!(!scroller.scroll_by(TypedPoint2D::new(1.0, 0.0)));
+        assert!(!scroller.set_content_offset_clamped(TypedPoint2D::new(50.0, 10.0)));
+    }
+
+    #[test]
+    fn content_smaller_than_the_layer_is_not_scrolled() {
+        let scroller = layer(0.0, 0.0, 100.0, 100.0, 256);
+        scroller.add_child(layer(0.0, 0.0, 80.0, 300.0, 256));
+        assert!(scroller.set_content_offset_clamped(TypedPoint2D::new(-30.0, -30.0)));
+        assert_eq!(scroller.content_offset(), TypedPoint2D::new(0.0, -30.0));
+
+        let empty = layer(0.0, 0.0, 100.0, 100.0, 256);
+        assert!(!empty.scroll_by(TypedPoint2D::new(-30.0, -30.0)));
+        assert_eq!(empty.content_offset(), TypedPoint2D::new(0.0, 0.0));
+
+        // Without children, the explicit content size is scrolled.
+        *empty.content_size.borrow_mut() = Some(TypedSize2D::new(100.0, 150.0));
+        assert!(empty.scroll_by(TypedPoint2D::new(-30.0, -80.0)));
+        assert_eq!(empty.content_offset(), TypedPoint2D::new(0.0, -50.0));
+    }
+
+    #[test]
+    fn scrolling_damages_nothing_by_itself() {
+        let scroller = layer(0.0, 0.0, 100.0, 100.0, 256);
+        let content = layer(0.0, 0.0, 100.0, 1000.0, 256);
+        scroller.add_child(content.clone());
+        scroller.take_damage();
+        content.take_damage();
+        assert!(scroller.scroll_by(TypedPoint2D::new(0.0, -100.0)));
+        assert_eq!(scroller.take_damage(), None);
+        assert_eq!(content.take_damage(), None);
+    }
+
+    #[test]
+    fn set_content_offset_resets_the_accumulated_scroll() {
+        let scroller = layer(0.0, 0.0, 100.0, 100.0, 256);
+        scroller.add_child(layer(0.0, 0.0, 100.0, 1000.0, 256));
+        assert!(scroller.scroll_by(TypedPoint2D::new(0.0, -10.5)));
+        scroller.set_content_offset(TypedPoint2D::new(0.0, -100.0));
+        assert!(scroller.scroll_by(TypedPoint2D::new(0.0, -0.5)));
+        assert_eq!(scroller.content_offset(), TypedPoint2D::new(0.0, -100.5));
+    }
+
+    #[test]
+    fn fixed_layers_stay_in_place_when_their_parent_scrolls() {
+        let root = layer(0.0, 0.0, 800.0, 600.0, 256);
+        let page = layer(0.0, 0.0, 800.0, 3000.0, 256);
+        let header = layer(0.0, 0.0, 800.0, 50.0, 256);
+        let sibling = layer(0.0, 100.0, 800.0, 50.0, 256);
+        header.set_fixed_to_viewport(Some(TypedPoint2D::new(0.0, 20.0)));
+        page.add_child(header.clone());
+        page.add_child(sibling.clone());
+        root.add_child(page.clone());
+        let rects = |layer: &Layer<()>| {
+            let ts = layer.transform_state.borrow();
+            (ts.world_rect, ts.screen_rect.as_ref().map(|screen_rect| screen_rect.rect))
+        };
+
+        update(&root);
+        let (header_world_rect, header_screen_rect) = rects(&header);
+        let (sibling_world_rect, sibling_screen_rect) = rects(&sibling);
+        assert_eq!(header_world_rect.origin, TypedPoint2D::new(0.0, 20.0));
+        assert_eq!(sibling_world_rect.origin, TypedPoint2D::new(0.0, 100.0));
+
+        // Scroll the page down by 500 pixels.
+        page.set_bounds(TypedRect::new(TypedPoint2D::new(0.0, -500.0),
+                                       TypedSize2D::new(800.0, 3000.0)));
+        update(&root);
+        assert_eq!(rects(&header), (header_world_rect, header_screen_rect));
+        let (world_rect, screen_rect) = rects(&sibling);
+        assert_eq!(world_rect, sibling_world_rect.translate(&TypedPoint2D::new(0.0, -500.0)));
+        assert_eq!(screen_rect.unwrap().origin.y, sibling_screen_rect.unwrap().origin.y - 500.0);
+
+        // Once unfixed, the header scrolls with the page again.
+        header.set_fixed_to_viewport(None);
+        update(&root);
+        assert_eq!(rects(&header).0.origin, TypedPoint2D::new(0.0, -500.0));
+    }
+
+    #[test]
+    fn negative_content_insets_are_clamped_to_zero() {
+        let layer = layer(0.0, 0.0, 100.0, 100.0, 256);
+        layer.take_damage();
+        layer.set_content_inset(SideOffsets2D::new(-5.0, 10.0, -0.5, 20.0));
+        assert_eq!(layer.content_inset(), SideOffsets2D::new(0.0, 10.0, 0.0, 20.0));
+        assert_eq!(layer.take_damage(),
+                   Some(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                       TypedSize2D::new(100.0, 100.0))));
+    }
+
+    #[test]
+    fn tiles_hidden_by_the_content_inset_are_not_requested() {
+        let layer = layer(0.0, 0.0, 512.0, 512.0, 256);
+        update(&layer);
+        assert_eq!(request_at_scale(&layer, 1.0).len(), 4);
+
+        let layer = self::layer(0.0, 0.0, 512.0, 512.0, 256);
+        layer.set_content_inset(SideOffsets2D::new(0.0, 0.0, 300.0, 0.0));
+        update(&layer);
+        let requests = request_at_scale(&layer, 1.0);
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|request| request.page_rect.origin.y == 0.0));
+    }
+
+    fn painted_layer(layer: &Layer<()>, scale: f32) -> usize {
+        let requests = request_at_scale(layer, scale);
+        for request in &requests {
+            layer.add_buffer(buffer_for_request(request));
+        }
+        requests.len()
+    }
+
+    #[test]
+    fn single_buffer_layers_request_their_whole_bounds_at_once() {
+        let layer = layer(0.0, 0.0, 600.0, 400.0, 256);
+        layer.set_single_buffer_mode(true, 4096, ScaleFactor::new(2.0)).unwrap();
+        update(&layer);
+        let requests = request_at_scale(&layer, 2.0);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].page_rect, Rect::new(Point2D::zero(), Size2D::new(1200.0, 800.0)));
+        assert_eq!(requests[0].screen_rect.size, Size2D::new(1200, 800));
+
+        // A resize asks for a single new buffer of the new size.
+        layer.add_buffer(buffer_for_request(&requests[0]));
+        layer.resize(TypedSize2D::new(300.0, 100.0));
+        update(&layer);
+        let requests = request_at_scale(&layer, 2.0);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].screen_rect.size, Size2D::new(600, 200));
+    }
+
+    #[test]
+    fn switching_to_a_single_buffer_discards_the_tiles() {
+        let layer = layer(0.0, 0.0, 600.0, 400.0, 256);
+        update(&layer);
+        assert_eq!(painted_layer(&layer, 1.0), 6);
+
+        layer.set_single_buffer_mode(true, 4096, ScaleFactor::new(1.0)).unwrap();
+        assert_eq!(layer.collect_unused_buffers().len(), 6);
+        assert_eq!(painted_layer(&layer, 1.0), 1);
+
+        layer.set_single_buffer_mode(false, 4096, ScaleFactor::new(1.0)).unwrap();
+        assert_eq!(layer.collect_unused_buffers().len(), 1);
+        assert_eq!(request_at_scale(&layer, 1.0).len(), 6);
+    }
+
+    #[test]
+    fn layers_larger_than_a_texture_stay_tiled() {
+        let layer = layer(0.0, 0.0, 600.0, 400.0, 256);
+        update(&layer);
+        assert!(layer.set_single_buffer_mode(true, 512, ScaleFactor::new(1.0)).is_err());
+        assert_eq!(request_at_scale(&layer, 1.0).len(), 6);
+
+        // Growing past the limit later falls back to tiling.
+        let layer = self::layer(0.0, 0.0, 500.0, 400.0, 256);
+        layer.set_single_buffer_mode(true, 512, ScaleFactor::new(1.0)).unwrap();
+        update(&layer);
+        assert_eq!(painted_layer(&layer, 1.0), 1);
+        layer.resize(TypedSize2D::new(600.0, 400.0));
+        update(&layer);
+        assert_eq!(request_at_scale(&layer, 1.0).len(), 6);
+        assert_eq!(layer.collect_unused_buffers().len(), 1);
+    }
+
+    #[test]
+    fn small_scroll_deltas_add_up_without_drift() {
+        let scroller = layer(0.0, 0.0, 100.0, 100.0, 256);
+        scroller.add_child(layer(0.0, 0.0, 100.0, 100000.0, 256));
+        let delta = -0.037f32;
+        let scale = ScaleFactor::new(2.0);
+        let mut naive_offset = 0.0f32;
+        let mut last_snapped = 0.0;
+        for step in 1..100001 {
+            scroller.scroll_by(TypedPoint2D::new(0.0, delta));
+            naive_offset += delta;
+
+            // Snapping follows the exact offset, so it only ever moves in the scroll direction.
+            let exact = step as f64 * delta as f64;
+            let snapped = scroller.snapped_content_offset(scale).y;
+            assert!(snapped <= last_snapped);
+            assert!((snapped as f64 - exact).abs() <= 0.25 + 1e-6);
+            last_snapped = snapped;
+        }
+
+        let exact = 100000.0 * delta as f64;
+        assert!((scroller.content_offset().y as f64 - exact).abs() < 0.5 / scale.get() as f64);
+        assert!((naive_offset as f64 - exact).abs() > 0.5);
+    }
+
+    #[test]
+    fn composite_changes_are_forgotten_once_the_transform_state_is_updated() {
+        let layer = layer(0.0, 0.0, 100.0, 100.0, 256);
+        layer.set_opacity(0.5);
+        assert!(layer.composite_properties_changed.get());
+        update(&layer);
+        assert!(!layer.composite_properties_changed.get());
+
+        layer.set_visible(false, false);
+        assert!(layer.composite_properties_changed.get());
+        layer.contents_changed();
+        assert_eq!(layer.composite_properties_changed.get(), !cfg!(debug_assertions));
     }
 }
@@ -36,14 +36,28 @@ extern crate glx;
 #[cfg(any(target_os = "linux", target_os = "android"))]
 extern crate egl;
 
+pub mod capabilities;
 pub mod color;
+pub mod completion;
+pub mod debug_overlay;
+pub mod dump;
+pub mod frame;
 pub mod geometry;
+pub mod gltrace;
 pub mod layers;
 pub mod rendergl;
+pub mod rendersw;
+pub mod replay;
 pub mod scene;
 pub mod texturegl;
 pub mod tiling;
 pub mod util;
+pub mod visibility;
+
+#[cfg(test)]
+mod golden;
+#[cfg(test)]
+mod test_util;
 
 pub mod platform {
     #[cfg(target_os="linux")]
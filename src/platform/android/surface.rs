@@ -149,6 +149,10 @@ impl EGLImageNativeSurface {
         self.will_leak = false
     }
 
+    pub fn will_leak(&self) -> bool {
+        self.will_leak
+    }
+
     pub fn gl_rasterization_context(&mut self,
                                     gl_context: Arc<GLContext>)
                                     -> Option<GLRasterizationContext> {
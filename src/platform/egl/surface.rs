@@ -8,20 +8,31 @@
 // except according to those terms.
 
 //! Implementation of cross-process surfaces implementing  EGL surface.
+//!
+//! Painters that draw on the CPU upload into a bitmap, which is copied into the texture when the
+//! surface is bound. Painters that draw with GL into a renderbuffer of an EGL context can share
+//! it with the compositor without copying by wrapping it in an EGLImage with `from_renderbuffer`;
+//! the compositor binds the image with `glEGLImageTargetTexture2DOES`, which requires the
+//! `GL_OES_EGL_image` extension. This is what Wayland uses, where there are no X pixmaps.
 
-use texturegl::Texture;
+use platform::surface::{ContextPriorityHint, SurfaceError, check_upload_rect, copy_rect};
+use texturegl::{Format, Texture};
 
-use egl::eglext::EGLImageKHR;
+use egl::egl::{EGLBoolean, EGLClientBuffer, EGLContext, EGLDisplay, EGLenum, EGLint};
+use egl::egl::{GetCurrentContext, GetError};
+use egl::eglext::{CreateImageKHR, DestroyImageKHR, EGLImageKHR};
+use euclid::rect::Rect;
 use euclid::size::Size2D;
-use gleam::gl::{TEXTURE_2D, TexImage2D, UNSIGNED_BYTE};
+use gleam::gl::{TEXTURE_2D, UNSIGNED_BYTE};
 use skia::gl_context::GLContext;
 use skia::gl_rasterization_context::GLRasterizationContext;
+use std::ffi::CStr;
 use std::iter::repeat;
-use std::os::raw::c_void;
+use std::os::raw::{c_char, c_void};
 use std::sync::Arc;
 use std::vec::Vec;
 
-use gleam::gl;
+use gltrace::{self as gl, GlApi};
 
 #[cfg(target_os = "linux")]
 const GL_FORMAT_BGRA: gl::GLuint = gl::BGRA;
@@ -35,6 +46,187 @@ pub use platform::linux::surface::NativeDisplay;
 #[cfg(target_os="android")]
 pub use platform::android::surface::NativeDisplay;
 
+/// The `EGL_KHR_gl_renderbuffer_image` target for images made from renderbuffers.
+const EGL_GL_RENDERBUFFER_KHR: EGLenum = 0x30B9;
+
+/// Terminates EGL attribute lists.
+const EGL_NONE: EGLint = 0x3038;
+
+/// The `eglQueryString` name of the extensions of a display.
+const EGL_EXTENSIONS: EGLint = 0x3055;
+
+/// The `EGL_IMG_context_priority` context attribute and its values.
+const EGL_CONTEXT_PRIORITY_LEVEL_IMG: EGLint = 0x3100;
+const EGL_CONTEXT_PRIORITY_HIGH_IMG: EGLint = 0x3101;
+const EGL_CONTEXT_PRIORITY_MEDIUM_IMG: EGLint = 0x3102;
+const EGL_CONTEXT_PRIORITY_LOW_IMG: EGLint = 0x3103;
+
+extern {
+    fn eglQueryString(display: EGLDisplay, name: EGLint) -> *const c_char;
+    fn eglQueryContext(display: EGLDisplay,
+                       context: EGLContext,
+                       attribute: EGLint,
+                       value: *mut EGLint)
+                       -> EGLBoolean;
+}
+
+/// Returns the EGL display to create and destroy images with, or None if the display isn't an
+/// EGL display.
+#[cfg(target_os="linux")]
+fn egl_display(display: &NativeDisplay) -> Option<EGLDisplay> {
+    match *display {
+        NativeDisplay::EGL(info) => Some(info.display),
+        NativeDisplay::GLX(_) => None,
+    }
+}
+
+#[cfg(target_os="android")]
+fn egl_display(display: &NativeDisplay) -> Option<EGLDisplay> {
+    Some(display.display)
+}
+
+#[cfg(target_os="android")]
+fn egl_image_target_texture2d_oes(_: &GlApi, image: EGLImageKHR) -> bool {
+    gl::egl_image_target_texture2d_oes(TEXTURE_2D, image as *const c_void);
+    true
+}
+
+/// Points the bound `GL_TEXTURE_2D` texture at the given image. Returns false if
+/// `GL_OES_EGL_image` isn't supported. Gleam only links the entry point on Android, so it is
+/// looked up here, once.
+#[cfg(target_os="linux")]
+fn egl_image_target_texture2d_oes(gl: &GlApi, image: EGLImageKHR) -> bool {
+    use std::mem;
+    use std::os::raw::c_char;
+    use std::ptr;
+    use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+
+    extern {
+        fn eglGetProcAddress(procname: *const c_char) -> *const c_void;
+    }
+
+    /// The address of `glEGLImageTargetTexture2DOES`, 1 if it is unsupported, or 0 if it
+    /// wasn't looked up yet.
+    static FUNCTION: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    let mut function = FUNCTION.load(Ordering::Relaxed);
+    if function == 0 {
+        let extensions = gl::get_string(gl, gl::EXTENSIONS);
+        let supported = extensions.split_whitespace().any(|extension| {
+            extension == "GL_OES_EGL_image"
+        });
+        let address = if supported {
+            unsafe {
+                eglGetProcAddress(b"glEGLImageTargetTexture2DOES\0".as_ptr() as *const c_char)
+            }
+        } else {
+            ptr::null()
+        };
+        function = if address.is_null() { 1 } else { address as usize };
+        FUNCTION.store(function, Ordering::Relaxed);
+    }
+    if function == 1 {
+        return false;
+    }
+
+    unsafe {
+        let function: extern "C" fn(gl::GLenum, *const c_void) = mem::transmute(function);
+        function(TEXTURE_2D, image as *const c_void);
+    }
+    true
+}
+
+/// Finds out whether the contexts of an EGL display can be prioritized with
+/// `EGL_IMG_context_priority`, and which priority they got. The queries can be replaced, for
+/// instance to pretend that the extension is missing in tests.
+#[derive(Clone, Copy)]
+pub struct ContextPriorityQueries {
+    /// Returns the space-separated extensions of the display.
+    pub extensions: fn(EGLDisplay) -> Option<String>,
+
+    /// Returns the `EGL_CONTEXT_PRIORITY_LEVEL_IMG` of the current context.
+    pub current_context_priority: fn(EGLDisplay) -> Option<EGLint>,
+}
+
+impl ContextPriorityQueries {
+    pub fn new() -> ContextPriorityQueries {
+        ContextPriorityQueries {
+            extensions: query_extensions,
+            current_context_priority: query_current_context_priority,
+        }
+    }
+
+    /// Returns true if the display supports `EGL_IMG_context_priority`.
+    pub fn is_supported(&self, display: EGLDisplay) -> bool {
+        (self.extensions)(display).map_or(false, |extensions| {
+            extensions.split_whitespace().any(|extension| extension == "EGL_IMG_context_priority")
+        })
+    }
+
+    /// Returns the attributes to append to the `eglCreateContext` attribute list of a context of
+    /// the display to request `priority`. This is empty if the display isn't an EGL display or
+    /// can't prioritize contexts, so that the hint is ignored there.
+    pub fn context_attributes(&self, display: &NativeDisplay, priority: ContextPriorityHint)
+                              -> Vec<EGLint> {
+        match egl_display(display) {
+            Some(egl_display) if self.is_supported(egl_display) => {
+                vec!(EGL_CONTEXT_PRIORITY_LEVEL_IMG, priority_level(priority))
+            }
+            _ => vec!(),
+        }
+    }
+
+    /// Returns the priority the current context of the display runs at, or None if the display
+    /// can't prioritize contexts. EGL may grant a lower priority than requested, for example to
+    /// processes without the permission to run high priority contexts.
+    pub fn granted_priority(&self, display: EGLDisplay) -> Option<ContextPriorityHint> {
+        if !self.is_supported(display) {
+            return None
+        }
+        (self.current_context_priority)(display).and_then(priority_hint)
+    }
+}
+
+fn priority_level(priority: ContextPriorityHint) -> EGLint {
+    match priority {
+        ContextPriorityHint::Low => EGL_CONTEXT_PRIORITY_LOW_IMG,
+        ContextPriorityHint::Medium => EGL_CONTEXT_PRIORITY_MEDIUM_IMG,
+        ContextPriorityHint::High => EGL_CONTEXT_PRIORITY_HIGH_IMG,
+    }
+}
+
+fn priority_hint(level: EGLint) -> Option<ContextPriorityHint> {
+    match level {
+        EGL_CONTEXT_PRIORITY_LOW_IMG => Some(ContextPriorityHint::Low),
+        EGL_CONTEXT_PRIORITY_MEDIUM_IMG => Some(ContextPriorityHint::Medium),
+        EGL_CONTEXT_PRIORITY_HIGH_IMG => Some(ContextPriorityHint::High),
+        _ => None,
+    }
+}
+
+fn query_extensions(display: EGLDisplay) -> Option<String> {
+    unsafe {
+        let extensions = eglQueryString(display, EGL_EXTENSIONS);
+        if extensions.is_null() {
+            return None
+        }
+        Some(CStr::from_ptr(extensions).to_string_lossy().into_owned())
+    }
+}
+
+fn query_current_context_priority(display: EGLDisplay) -> Option<EGLint> {
+    let mut level = 0;
+    unsafe {
+        if eglQueryContext(display,
+                           GetCurrentContext(),
+                           EGL_CONTEXT_PRIORITY_LEVEL_IMG,
+                           &mut level) == 0 {
+            return None
+        }
+    }
+    Some(level)
+}
+
 pub struct EGLImageNativeSurface {
     /// An EGLImage for the case of GPU rendering.
     image: Option<EGLImageKHR>,
@@ -47,6 +239,13 @@ pub struct EGLImageNativeSurface {
 
     /// The size of this surface.
     pub size: Size2D<i32>,
+
+    /// How to find out the priority of painting contexts. This is `ContextPriorityQueries::new()`
+    /// unless replaced.
+    pub context_priority_queries: ContextPriorityQueries,
+
+    /// The priority the context of the last rasterization context runs at, if known.
+    granted_context_priority: Option<ContextPriorityHint>,
 }
 
 unsafe impl Send for EGLImageNativeSurface {}
@@ -61,47 +260,126 @@ impl EGLImageNativeSurface {
             bitmap: Some(bitmap),
             will_leak: true,
             size: size,
+            context_priority_queries: ContextPriorityQueries::new(),
+            granted_context_priority: None,
+        }
+    }
+
+    /// Wraps a renderbuffer of the current EGL context in an EGLImage, so that the compositor can
+    /// display what the painter drew into it with GL without copying. The renderbuffer must hold
+    /// BGRA-compatible RGBA pixels of the given size; it stays owned by the caller, but its
+    /// storage lives as long as the image. This may only be called on the painting side. The
+    /// priority of the current context is recorded for `granted_context_priority`.
+    pub fn from_renderbuffer(display: &NativeDisplay, renderbuffer: gl::GLuint, size: Size2D<i32>)
+                             -> Result<EGLImageNativeSurface, SurfaceError> {
+        let egl_display = match egl_display(display) {
+            Some(egl_display) => egl_display,
+            None => return Err(SurfaceError::Unsupported),
+        };
+
+        let attributes = [EGL_NONE];
+        let image = CreateImageKHR(egl_display,
+                                   GetCurrentContext(),
+                                   EGL_GL_RENDERBUFFER_KHR,
+                                   renderbuffer as usize as EGLClientBuffer,
+                                   attributes.as_ptr());
+        if image.is_null() {
+            return Err(SurfaceError::AllocationFailed);
         }
+
+        let mut surface = EGLImageNativeSurface {
+            image: Some(image),
+            bitmap: None,
+            will_leak: true,
+            size: size,
+            context_priority_queries: ContextPriorityQueries::new(),
+            granted_context_priority: None,
+        };
+        surface.record_granted_context_priority(egl_display);
+        Ok(surface)
     }
 
     /// This may only be called on the compositor side.
-    pub fn bind_to_texture(&self, _: &NativeDisplay, texture: &Texture) {
-        let _bound = texture.bind();
+    pub fn bind_to_texture(&self, _: &NativeDisplay, texture: &Texture)
+                           -> Result<(), SurfaceError> {
         match self.image {
             None => match self.bitmap {
                 Some(ref bitmap) => {
-                    let data = bitmap.as_ptr() as *const c_void;
-                    unsafe {
-                        TexImage2D(TEXTURE_2D,
-                                   0,
-                                   GL_FORMAT_BGRA as i32,
-                                   self.size.width as i32,
-                                   self.size.height as i32,
-                                   0,
-                                   GL_FORMAT_BGRA as u32,
-                                   UNSIGNED_BYTE,
-                                   data);
-                     }
-                }
-                None => {
-                    debug!("Cannot bind the buffer(CPU rendering), there is no bitmap");
+                    if bitmap.len() < self.size.width as usize * self.size.height as usize * 4 {
+                        return Err(SurfaceError::InvalidState);
+                    }
+                    let _bound = texture.bind();
+                    let size = Size2D::new(self.size.width as usize, self.size.height as usize);
+                    texture.ensure_storage(size, Format::ARGB32Format);
+                    gl::tex_sub_image_2d(texture.gl(),
+                                         TEXTURE_2D,
+                                         0,
+                                         0,
+                                         0,
+                                         self.size.width,
+                                         self.size.height,
+                                         GL_FORMAT_BGRA,
+                                         UNSIGNED_BYTE,
+                                         &bitmap[..]);
+                    Ok(())
                 }
+                None => Err(SurfaceError::InvalidState),
             },
-            Some(_image_khr) => {
-                panic!("TODO: Support GPU rasterizer path on EGL");
+            Some(image_khr) => {
+                let _bound = texture.bind();
+                texture.forget_storage();
+                if egl_image_target_texture2d_oes(texture.gl(), image_khr) {
+                    Ok(())
+                } else {
+                    Err(SurfaceError::Unsupported)
+                }
             }
         }
     }
 
-    /// This may only be called on the painting side.
-    pub fn upload(&mut self, _: &NativeDisplay, data: &[u8]) {
+    /// This may only be called on the painting side. Images can only be drawn into with GL.
+    pub fn upload(&mut self, _: &NativeDisplay, data: &[u8]) -> Result<(), SurfaceError> {
+        let length = self.size.width as usize * self.size.height as usize * 4;
         match self.bitmap {
             Some(ref mut bitmap) => {
+                if data.len() < length {
+                    return Err(SurfaceError::SourceTooSmall);
+                }
                 bitmap.clear();
-                bitmap.extend_from_slice(data);
+                bitmap.extend_from_slice(&data[..length]);
+                Ok(())
             }
-            None => {
-                debug!("Cannot upload the buffer(CPU rendering), there is no bitmap");
+            None if self.image.is_some() => Err(SurfaceError::Unsupported),
+            None => Err(SurfaceError::InvalidState),
+        }
+    }
+
+    /// This may only be called on the painting side.
+    pub fn upload_rect(&mut self, _: &NativeDisplay, data: &[u8], rect: Rect<i32>, stride: i32)
+                       -> Result<(), SurfaceError> {
+        try!(check_upload_rect(self.size, 4, data, &rect, stride));
+        let surface_stride = self.size.width as usize * 4;
+        match self.bitmap {
+            Some(ref mut bitmap) => {
+                let len = surface_stride * self.size.height as usize;
+                if bitmap.len() < len {
+                    bitmap.resize(len, 0);
+                }
+                copy_rect(bitmap, surface_stride, 4, data, &rect, stride);
+            }
+            None if self.image.is_some() => return Err(SurfaceError::Unsupported),
+            None => return Err(SurfaceError::InvalidState),
+        }
+        Ok(())
+    }
+
+    pub fn read_pixels(&self, _: &NativeDisplay) -> Option<Vec<u8>> {
+        match self.image {
+            None => self.bitmap.clone(),
+            Some(_image_khr) => {
+                // The pixels of an image can only be read by drawing it.
+                debug!("Cannot read back an EGLImage surface");
+                None
             }
         }
     }
@@ -113,11 +391,24 @@ impl EGLImageNativeSurface {
         }
     }
 
-    pub fn destroy(&mut self, _graphics_context: &NativeDisplay) {
-        if self.image.is_some() {
-            panic!("TODO: Support GPU rendering path on Android");
+    /// Destroys the image, if there is one, with the EGL display it was created with.
+    pub fn destroy(&mut self, display: &NativeDisplay) -> Result<(), SurfaceError> {
+        if self.image.is_none() && self.bitmap.is_none() {
+            return Err(SurfaceError::InvalidState);
+        }
+        if let Some(image_khr) = self.image {
+            let egl_display = match egl_display(display) {
+                Some(egl_display) => egl_display,
+                None => return Err(SurfaceError::Unsupported),
+            };
+            if DestroyImageKHR(egl_display, image_khr) == 0 {
+                return Err(SurfaceError::PlatformError(GetError()));
+            }
         }
-        self.mark_wont_leak()
+        self.image = None;
+        self.bitmap = None;
+        self.mark_wont_leak();
+        Ok(())
     }
 
     pub fn mark_will_leak(&mut self) {
@@ -128,9 +419,183 @@ impl EGLImageNativeSurface {
         self.will_leak = false
     }
 
+    pub fn will_leak(&self) -> bool {
+        self.will_leak
+    }
+
+    /// Skia can't rasterize into EGLImages outside of Android, so painters fall back to the CPU
+    /// or draw into a renderbuffer themselves; see `from_renderbuffer`.
+    #[cfg(target_os="linux")]
+    pub fn gl_rasterization_context(&mut self,
+                                    _gl_context: Arc<GLContext>,
+                                    _priority: ContextPriorityHint)
+                                    -> Result<GLRasterizationContext, SurfaceError> {
+        Err(SurfaceError::Unsupported)
+    }
+
+    /// Creates a rasterization context drawing into a new EGLImage, which replaces the image or
+    /// bitmap the surface held. The priority of `gl_context` is fixed when it is created, with
+    /// the attributes of `ContextPriorityQueries::context_attributes`; this only records the
+    /// priority it was granted.
+    #[cfg(target_os="android")]
     pub fn gl_rasterization_context(&mut self,
-                                    _gl_context: Arc<GLContext>)
-                                    -> Option<GLRasterizationContext> {
-        panic!("TODO: Support GL context on EGL");
+                                    gl_context: Arc<GLContext>,
+                                    priority: ContextPriorityHint)
+                                    -> Result<GLRasterizationContext, SurfaceError> {
+        let display = gl_context.platform_context.display;
+        if let Some(egl_image) = self.image.take() {
+            DestroyImageKHR(display, egl_image);
+        }
+
+        let gl_rasterization_context =
+            try!(GLRasterizationContext::new(gl_context, self.size)
+                     .ok_or(SurfaceError::Unsupported));
+        self.bitmap = None;
+        self.image = Some(gl_rasterization_context.egl_image);
+        self.record_granted_context_priority(display);
+        if let Some(granted) = self.granted_context_priority {
+            if granted != priority {
+                debug!("Painting context runs at {:?} priority instead of {:?}",
+                       granted,
+                       priority);
+            }
+        }
+        Ok(gl_rasterization_context)
+    }
+
+    /// Records the priority the current context of `display`, which paints into this surface,
+    /// runs at.
+    fn record_granted_context_priority(&mut self, display: EGLDisplay) {
+        self.granted_context_priority = self.context_priority_queries.granted_priority(display);
+    }
+
+    /// Returns the priority of the context that paints into the surface, or None if it is
+    /// unknown or the display can't prioritize contexts.
+    pub fn granted_context_priority(&self) -> Option<ContextPriorityHint> {
+        self.granted_context_priority
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::{ContextPriorityQueries, EGLImageNativeSurface, EGL_CONTEXT_PRIORITY_HIGH_IMG};
+    use super::{EGL_CONTEXT_PRIORITY_LEVEL_IMG, EGL_CONTEXT_PRIORITY_LOW_IMG, NativeDisplay};
+    use egl::egl::{EGLDisplay, EGLint};
+    use euclid::size::Size2D;
+    use platform::linux::surface::EGLDisplayInfo;
+    use platform::surface::{ContextPriorityHint, NativeSurface};
+    use test_util::headless_display;
+    #[cfg(feature = "egl-tests")]
+    use test_util::make_headless_egl_context_current;
+
+    fn with_extension(_: EGLDisplay) -> Option<String> {
+        Some("EGL_KHR_image_base EGL_IMG_context_priority EGL_KHR_fence_sync".to_owned())
+    }
+
+    fn without_extension(_: EGLDisplay) -> Option<String> {
+        Some("EGL_KHR_image_base EGL_IMG_context_priority_extended".to_owned())
+    }
+
+    fn granted_low(_: EGLDisplay) -> Option<EGLint> {
+        Some(EGL_CONTEXT_PRIORITY_LOW_IMG)
+    }
+
+    fn granted_high(_: EGLDisplay) -> Option<EGLint> {
+        Some(EGL_CONTEXT_PRIORITY_HIGH_IMG)
+    }
+
+    fn queries(extensions: fn(EGLDisplay) -> Option<String>,
+               current_context_priority: fn(EGLDisplay) -> Option<EGLint>)
+               -> ContextPriorityQueries {
+        ContextPriorityQueries {
+            extensions: extensions,
+            current_context_priority: current_context_priority,
+        }
+    }
+
+    fn egl_display() -> NativeDisplay {
+        NativeDisplay::EGL(EGLDisplayInfo {
+            display: 1 as EGLDisplay,
+        })
+    }
+
+    #[test]
+    fn hints_become_context_attributes_when_the_extension_is_available() {
+        let queries = queries(with_extension, granted_high);
+        assert_eq!(queries.context_attributes(&egl_display(), ContextPriorityHint::Low),
+                   vec!(EGL_CONTEXT_PRIORITY_LEVEL_IMG, EGL_CONTEXT_PRIORITY_LOW_IMG));
+        assert_eq!(queries.context_attributes(&egl_display(), ContextPriorityHint::High),
+                   vec!(EGL_CONTEXT_PRIORITY_LEVEL_IMG, EGL_CONTEXT_PRIORITY_HIGH_IMG));
+
+        // GLX displays have no EGL contexts to prioritize.
+        assert!(queries.context_attributes(&headless_display(), ContextPriorityHint::Low)
+                       .is_empty());
+    }
+
+    #[test]
+    fn hints_are_ignored_without_the_extension() {
+        // Extensions whose names merely start with the name of the extension don't count.
+        let queries = queries(without_extension, granted_high);
+        assert!(!queries.is_supported(1 as EGLDisplay));
+        assert!(queries.context_attributes(&egl_display(), ContextPriorityHint::High)
+                       .is_empty());
+        assert_eq!(queries.granted_priority(1 as EGLDisplay), None);
+    }
+
+    #[test]
+    fn surfaces_report_the_granted_priority() {
+        let mut surface = EGLImageNativeSurface::new(&egl_display(), Size2D::new(16, 16));
+        assert_eq!(surface.granted_context_priority(), None);
+
+        // EGL may grant less than was asked for.
+        surface.context_priority_queries = queries(with_extension, granted_low);
+        surface.record_granted_context_priority(1 as EGLDisplay);
+        let mut surface = NativeSurface::EGLImage(surface);
+        assert_eq!(surface.granted_context_priority(), Some(ContextPriorityHint::Low));
+
+        if let NativeSurface::EGLImage(ref mut surface) = surface {
+            surface.context_priority_queries = queries(without_extension, granted_low);
+            surface.record_granted_context_priority(1 as EGLDisplay);
+        }
+        assert_eq!(surface.granted_context_priority(), None);
+    }
+
+    /// Shares a renderbuffer with itself through an EGLImage. Run with `--features egl-tests` on
+    /// a machine with an EGL implementation that supports `EGL_KHR_gl_renderbuffer_image`; Mesa
+    /// needs `EGL_PLATFORM=surfaceless` when there is no window system.
+    #[cfg(feature = "egl-tests")]
+    #[test]
+    fn renderbuffers_are_shared_through_egl_images() {
+        use gltrace::{self as gl, GleamGl};
+        use platform::surface::SurfaceError;
+        use texturegl::{Texture, TextureTarget};
+
+        let display = make_headless_egl_context_current(1, 1);
+        let gl = &GleamGl;
+        let renderbuffer = gl::gen_renderbuffers(gl, 1)[0];
+        gl::bind_renderbuffer(gl, gl::RENDERBUFFER, renderbuffer);
+        gl::renderbuffer_storage(gl, gl::RENDERBUFFER, gl::RGBA4, 16, 16);
+        gl::bind_renderbuffer(gl, gl::RENDERBUFFER, 0);
+
+        let size = Size2D::new(16, 16);
+        let mut surface = EGLImageNativeSurface::from_renderbuffer(&display, renderbuffer, size)
+            .unwrap();
+        assert!(surface.get_id() != 0);
+
+        // Images are only drawn into with GL.
+        match surface.upload(&display, &[0; 16 * 16 * 4]) {
+            Err(SurfaceError::Unsupported) => {}
+            result => panic!("Expected Unsupported, got {:?}", result),
+        }
+        assert!(surface.read_pixels(&display).is_none());
+
+        let texture = Texture::new(TextureTarget::TextureTarget2D, Size2D::new(16, 16));
+        assert!(surface.bind_to_texture(&display, &texture).is_ok());
+        assert_eq!(gl::get_error(), gl::NO_ERROR);
+
+        assert!(surface.destroy(&display).is_ok());
+        assert!(!surface.will_leak());
+        assert!(surface.destroy(&display).is_err());
+        gl::delete_renderbuffers(gl, &[renderbuffer]);
     }
 }
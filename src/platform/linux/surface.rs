@@ -15,19 +15,27 @@
 #[link(name = "EGL")]
 extern {}
 
+use platform::surface::{ContextPriorityHint, SurfaceError, check_upload_rect};
 use texturegl::Texture;
 
+use euclid::point::Point2D;
+use euclid::rect::Rect;
 use euclid::size::Size2D;
-use libc::{c_int, c_uint, c_void};
+use libc::{c_int, c_uint, c_ulong, c_void};
 use glx;
 use skia::gl_context::{GLContext, PlatformDisplayData};
 use skia::gl_rasterization_context::GLRasterizationContext;
 use std::ascii::AsciiExt;
+use rustc_serialize::{Decoder, Decodable, Encoder, Encodable};
+use std::cell::RefCell;
 use std::ffi::CStr;
 use std::mem;
 use std::ptr;
+use std::slice;
 use std::str;
+use std::sync::atomic::{ATOMIC_BOOL_INIT, AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread;
 use x11::xlib;
 
 use egl::egl::{EGLDisplay, GetCurrentDisplay};
@@ -178,11 +186,87 @@ impl NativeDisplay {
     }
 }
 
-#[derive(RustcDecodable, RustcEncodable)]
+/// Returns the GLX display info of a display, or `Unsupported` for EGL displays, which have no
+/// X pixmaps.
+fn glx_display_info(display: &NativeDisplay) -> Result<GLXDisplayInfo, SurfaceError> {
+    match *display {
+        NativeDisplay::GLX(info) => Ok(info),
+        NativeDisplay::EGL(_) => Err(SurfaceError::Unsupported),
+    }
+}
+
+/// A GLX pixmap made from the X pixmap of a surface, kept from one bind to the next by the
+/// compositor that created it. This is never encoded: the display and the GLX pixmap only mean
+/// something in the compositor process.
+struct CachedGLXPixmap {
+    /// The address of the display the GLX pixmap was created on, which identifies the
+    /// compositor.
+    display: usize,
+
+    /// The GLX pixmap.
+    glx_pixmap: glx::types::GLXPixmap,
+
+    /// Whether the GLX pixmap is bound to a texture and must be released before binding again.
+    bound: bool,
+}
+
+type GLXBindTexImageFunction = extern "C" fn(*mut xlib::Display,
+                                             glx::types::GLXDrawable,
+                                             c_int,
+                                             *mut c_int);
+type GLXReleaseTexImageFunction = extern "C" fn(*mut xlib::Display,
+                                                glx::types::GLXDrawable,
+                                                c_int);
+
+fn glx_bind_tex_image() -> Result<GLXBindTexImageFunction, SurfaceError> {
+    unsafe {
+        let function = glx::GetProcAddress(
+            mem::transmute(&"glXBindTexImageEXT\x00".as_bytes()[0]));
+        if function as *const c_void == ptr::null() {
+            return Err(SurfaceError::Unsupported);
+        }
+        Ok(mem::transmute(function))
+    }
+}
+
+fn glx_release_tex_image() -> Result<GLXReleaseTexImageFunction, SurfaceError> {
+    unsafe {
+        let function = glx::GetProcAddress(
+            mem::transmute(&"glXReleaseTexImageEXT\x00".as_bytes()[0]));
+        if function as *const c_void == ptr::null() {
+            return Err(SurfaceError::Unsupported);
+        }
+        Ok(mem::transmute(function))
+    }
+}
+
+impl CachedGLXPixmap {
+    /// Releases the GLX pixmap from its texture, if it is bound, and destroys it. This uses the
+    /// display the GLX pixmap was created on, which, like the one in `GLXDisplayInfo`, is an
+    /// unchecked weak reference.
+    fn destroy(&self) -> Result<(), SurfaceError> {
+        let display = self.display as *mut xlib::Display;
+        if self.bound {
+            let release_tex_image = try!(glx_release_tex_image());
+            release_tex_image(display,
+                              self.glx_pixmap as glx::types::GLXDrawable,
+                              glx::FRONT_EXT as i32);
+        }
+        unsafe {
+            glx::DestroyPixmap(mem::transmute(display), self.glx_pixmap);
+        }
+        Ok(())
+    }
+}
+
 pub struct PixmapNativeSurface {
     /// The pixmap.
     pixmap: xlib::Pixmap,
 
+    /// The GLX pixmaps the compositors bind textures to, one per compositing display, created on
+    /// the first bind and destroyed by `release_glx_pixmaps`. Not encoded.
+    glx_pixmaps: RefCell<Vec<CachedGLXPixmap>>,
+
     /// Whether this pixmap will leak if the destructor runs. This is for debugging purposes.
     will_leak: bool,
 
@@ -190,6 +274,29 @@ pub struct PixmapNativeSurface {
     pub size: Size2D<i32>,
 }
 
+impl Decodable for PixmapNativeSurface {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Self, D::Error> {
+        d.read_struct("PixmapNativeSurface", 3, |d| {
+            Ok(PixmapNativeSurface {
+                pixmap: try!(d.read_struct_field("pixmap", 0, Decodable::decode)),
+                glx_pixmaps: RefCell::new(vec!()),
+                will_leak: try!(d.read_struct_field("will_leak", 1, Decodable::decode)),
+                size: try!(d.read_struct_field("size", 2, Decodable::decode)),
+            })
+        })
+    }
+}
+
+impl Encodable for PixmapNativeSurface {
+    fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+        e.emit_struct("PixmapNativeSurface", 3, |e| {
+            try!(e.emit_struct_field("pixmap", 0, |e| self.pixmap.encode(e)));
+            try!(e.emit_struct_field("will_leak", 1, |e| self.will_leak.encode(e)));
+            e.emit_struct_field("size", 2, |e| self.size.encode(e))
+        })
+    }
+}
+
 impl Drop for PixmapNativeSurface {
     fn drop(&mut self) {
         if self.will_leak {
@@ -199,12 +306,95 @@ impl Drop for PixmapNativeSurface {
     }
 }
 
+/// Held while a pixmap is allocated. Allocating installs a process-wide X error handler, so
+/// allocations on different threads must not overlap, or one would restore the handler while the
+/// other still relies on it.
+static PIXMAP_ALLOCATION_LOCK: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Set by `record_x_error` when the request of the pixmap allocation in progress fails.
+static X_ERROR_OCCURRED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The pixmap allocation in progress, which `record_x_error` attributes errors to. Only written
+/// while `PIXMAP_ALLOCATION_LOCK` is held. Serial numbers only grow, so the errors of later
+/// requests are never attributed to an allocation that is over.
+static mut PIXMAP_ALLOCATION: PixmapAllocation = PixmapAllocation {
+    display: 0 as *mut xlib::Display,
+    serial: 0,
+    previous_error_handler: None,
+};
+
+#[derive(Copy, Clone)]
+struct PixmapAllocation {
+    /// The display the pixmap is allocated on.
+    display: *mut xlib::Display,
+
+    /// The serial number of the `XCreatePixmap` request.
+    serial: c_ulong,
+
+    /// The error handler that was installed before `record_x_error`, to which the errors of
+    /// other requests are passed on.
+    previous_error_handler: XErrorHandler,
+}
+
+/// The type of the error handlers `XSetErrorHandler` installs.
+type XErrorHandler = Option<unsafe extern "C" fn(*mut xlib::Display, *mut xlib::XErrorEvent)
+                                                  -> c_int>;
+
+/// Releases `PIXMAP_ALLOCATION_LOCK` when dropped.
+struct PixmapAllocationLock;
+
+impl PixmapAllocationLock {
+    fn acquire() -> PixmapAllocationLock {
+        while PIXMAP_ALLOCATION_LOCK.compare_and_swap(false, true, Ordering::Acquire) {
+            thread::yield_now();
+        }
+        PixmapAllocationLock
+    }
+}
+
+impl Drop for PixmapAllocationLock {
+    fn drop(&mut self) {
+        PIXMAP_ALLOCATION_LOCK.store(false, Ordering::Release);
+    }
+}
+
+/// Records the failure of the pixmap allocation in progress. Errors of other requests, which
+/// other threads may cause while this is installed, are passed on to the previous handler
+/// instead of being mistaken for an allocation failure.
+unsafe extern "C" fn record_x_error(display: *mut xlib::Display, event: *mut xlib::XErrorEvent)
+                                    -> c_int {
+    let allocation = PIXMAP_ALLOCATION;
+    if display == allocation.display && (*event).serial == allocation.serial {
+        X_ERROR_OCCURRED.store(true, Ordering::SeqCst);
+        return 0;
+    }
+    match allocation.previous_error_handler {
+        Some(previous_error_handler) => previous_error_handler(display, event),
+        None => 0,
+    }
+}
+
 impl PixmapNativeSurface {
-    pub fn new(display: &GLXDisplayInfo, size: Size2D<i32>) -> PixmapNativeSurface {
+    /// Creates a new pixmap. X reports errors asynchronously, so this waits for the server to
+    /// process the request in order to find out whether the pixmap could be allocated. Meanwhile
+    /// an X error handler is installed for the whole process; pixmap allocations are serialized
+    /// so that they don't replace each other's handler, and errors of other requests are passed
+    /// on to the handler that was installed before.
+    pub fn new(display: &GLXDisplayInfo, size: Size2D<i32>)
+               -> Result<PixmapNativeSurface, SurfaceError> {
+        let _lock = PixmapAllocationLock::acquire();
         unsafe {
-            // Create the pixmap.
+            X_ERROR_OCCURRED.store(false, Ordering::SeqCst);
+            let previous_error_handler = xlib::XSetErrorHandler(Some(record_x_error));
+            PIXMAP_ALLOCATION.previous_error_handler = previous_error_handler;
+
+            // Create the pixmap. The display is locked so that no other thread sends a request
+            // between looking up the serial of the request and sending it.
             let screen = xlib::XDefaultScreen(display.display);
             let window = xlib::XRootWindow(display.display, screen);
+            xlib::XLockDisplay(display.display);
+            PIXMAP_ALLOCATION.display = display.display;
+            PIXMAP_ALLOCATION.serial = xlib::XNextRequest(display.display);
             // The X server we use for testing on build machines always returns
             // visuals that report 24 bit depth. But creating a 32 bit pixmap does work, so
             // hard code the depth here.
@@ -213,71 +403,129 @@ impl PixmapNativeSurface {
                                              size.width as c_uint,
                                              size.height as c_uint,
                                              32);
-            PixmapNativeSurface {
+            xlib::XUnlockDisplay(display.display);
+
+            xlib::XSync(display.display, xlib::False);
+            xlib::XSetErrorHandler(previous_error_handler);
+            if pixmap == 0 || X_ERROR_OCCURRED.load(Ordering::SeqCst) {
+                return Err(SurfaceError::AllocationFailed);
+            }
+
+            Ok(PixmapNativeSurface {
                 pixmap: pixmap,
+                glx_pixmaps: RefCell::new(vec!()),
                 will_leak: true,
                 size: size,
-            }
+            })
         }
     }
 
-    /// This may only be called on the compositor side.
-    pub fn bind_to_texture(&self, display: &NativeDisplay, texture: &Texture) {
-        // Create the GLX pixmap.
-        //
-        // FIXME(pcwalton): RAII for exception safety?
-        unsafe {
-            let display = match display {
-                &NativeDisplay::GLX(info) => info,
-                &NativeDisplay::EGL(_) => unreachable!(),
-            };
-
-            let pixmap_attributes = [
-                glx::TEXTURE_TARGET_EXT as i32, glx::TEXTURE_2D_EXT as i32,
-                glx::TEXTURE_FORMAT_EXT as i32, glx::TEXTURE_FORMAT_RGBA_EXT as i32,
-                0
-            ];
+    /// Returns an error unless the surface still holds a pixmap.
+    fn check_pixmap(&self) -> Result<(), SurfaceError> {
+        if self.pixmap == 0 {
+            Err(SurfaceError::InvalidState)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// This may only be called on the compositor side. The GLX pixmap created for the display
+    /// is kept until `release_glx_pixmaps` is called.
+    pub fn bind_to_texture(&self, display: &NativeDisplay, texture: &Texture)
+                           -> Result<(), SurfaceError> {
+        try!(self.check_pixmap());
+        let display = try!(glx_display_info(display));
+        let framebuffer_configuration = match display.framebuffer_configuration {
+            Some(framebuffer_configuration) => framebuffer_configuration,
+            None => return Err(SurfaceError::Unsupported),
+        };
+
+        let bind_tex_image = try!(glx_bind_tex_image());
+        let release_tex_image = try!(glx_release_tex_image());
+
+        let mut glx_pixmaps = self.glx_pixmaps.borrow_mut();
+        let index = match glx_pixmaps.iter().position(|cached| {
+            cached.display == display.display as usize
+        }) {
+            Some(index) => index,
+            None => {
+                let pixmap_attributes = [
+                    glx::TEXTURE_TARGET_EXT as i32, glx::TEXTURE_2D_EXT as i32,
+                    glx::TEXTURE_FORMAT_EXT as i32, glx::TEXTURE_FORMAT_RGBA_EXT as i32,
+                    0
+                ];
+                let glx_pixmap = unsafe {
+                    glx::CreatePixmap(mem::transmute(display.display),
+                                      framebuffer_configuration,
+                                      self.pixmap,
+                                      pixmap_attributes.as_ptr())
+                };
+                if glx_pixmap == 0 {
+                    return Err(SurfaceError::PlatformError(0));
+                }
+                glx_pixmaps.push(CachedGLXPixmap {
+                    display: display.display as usize,
+                    glx_pixmap: glx_pixmap,
+                    bound: false,
+                });
+                glx_pixmaps.len() - 1
+            }
+        };
 
-            let glx_display = mem::transmute(display.display);
-
-            let glx_pixmap = glx::CreatePixmap(glx_display,
-                                               display.framebuffer_configuration.expect(
-                                                   "GLX 1.3 should have a framebuffer_configuration"),
-                                               self.pixmap,
-                                               pixmap_attributes.as_ptr());
-
-            let glx_bind_tex_image: extern "C" fn(*mut xlib::Display, glx::types::GLXDrawable, c_int, *mut c_int) =
-                mem::transmute(glx::GetProcAddress(mem::transmute(&"glXBindTexImageEXT\x00".as_bytes()[0])));
-            assert!(glx_bind_tex_image as *mut c_void != ptr::null_mut());
-            let _bound = texture.bind();
-            glx_bind_tex_image(display.display,
-                               mem::transmute(glx_pixmap),
-                               glx::FRONT_EXT  as i32,
-                               ptr::null_mut());
-
-            // FIXME(pcwalton): Recycle these for speed?
-            glx::DestroyPixmap(glx_display, glx_pixmap);
+        let cached = &mut glx_pixmaps[index];
+        if cached.bound {
+            release_tex_image(display.display,
+                              cached.glx_pixmap as glx::types::GLXDrawable,
+                              glx::FRONT_EXT as i32);
+        }
+
+        let _bound = texture.bind();
+        texture.forget_storage();
+        bind_tex_image(display.display,
+                       cached.glx_pixmap as glx::types::GLXDrawable,
+                       glx::FRONT_EXT as i32,
+                       ptr::null_mut());
+        cached.bound = true;
+        Ok(())
+    }
+
+    /// Releases the GLX pixmaps made from this surface from their textures and destroys them.
+    /// The compositor calls this when it drops the texture of the surface or hands the surface
+    /// back to the painter, while the X pixmap still exists. This may only be called on the
+    /// compositor side.
+    pub fn release_glx_pixmaps(&self) {
+        for cached in self.glx_pixmaps.borrow_mut().drain(..) {
+            if let Err(error) = cached.destroy() {
+                warn!("Failed to release a GLX pixmap ({:?})", error);
+            }
         }
     }
 
     /// This may only be called on the painting side.
-    pub fn upload(&mut self, display: &NativeDisplay, data: &[u8]) {
-        unsafe {
-            let display = match display {
-                &NativeDisplay::GLX(info) => info,
-                &NativeDisplay::EGL(_) => unreachable!(),
-            };
+    pub fn upload(&mut self, display: &NativeDisplay, data: &[u8]) -> Result<(), SurfaceError> {
+        try!(self.check_pixmap());
+        let stride = self.size.width * 4;
+        try!(check_upload_rect(self.size,
+                               4,
+                               data,
+                               &Rect::new(Point2D::zero(), self.size),
+                               stride));
+        let display = try!(glx_display_info(display));
 
+        unsafe {
             let image = xlib::XCreateImage(display.display,
                                            (*display.visual_info).visual,
                                            32,
                                            xlib::ZPixmap,
                                            0,
-                                           mem::transmute(&data[0]),
+                                           data.as_ptr() as *mut _,
                                            self.size.width as c_uint,
                                            self.size.height as c_uint,
                                            32,
                                            0);
+            if image.is_null() {
+                return Err(SurfaceError::AllocationFailed);
+            }
 
             let gc = xlib::XCreateGC(display.display, self.pixmap, 0, ptr::null_mut());
             let _ = xlib::XPutImage(display.display,
@@ -290,6 +538,99 @@ impl PixmapNativeSurface {
                                     0,
                                     self.size.width as c_uint,
                                     self.size.height as c_uint);
+            xlib::XFreeGC(display.display, gc);
+
+            // The data belongs to the caller; keep XDestroyImage from freeing it.
+            (*image).data = ptr::null_mut();
+            xlib::XDestroyImage(image);
+        }
+        Ok(())
+    }
+
+    pub fn upload_rect(&mut self,
+                       display: &NativeDisplay,
+                       data: &[u8],
+                       rect: Rect<i32>,
+                       stride: i32)
+                       -> Result<(), SurfaceError> {
+        try!(self.check_pixmap());
+        try!(check_upload_rect(self.size, 4, data, &rect, stride));
+        if rect.size.width == 0 || rect.size.height == 0 {
+            return Ok(());
+        }
+        let display = try!(glx_display_info(display));
+
+        unsafe {
+
+            let image = xlib::XCreateImage(display.display,
+                                           (*display.visual_info).visual,
+                                           32,
+                                           xlib::ZPixmap,
+                                           0,
+                                           data.as_ptr() as *mut _,
+                                           rect.size.width as c_uint,
+                                           rect.size.height as c_uint,
+                                           32,
+                                           stride);
+            if image.is_null() {
+                return Err(SurfaceError::AllocationFailed);
+            }
+
+            let gc = xlib::XCreateGC(display.display, self.pixmap, 0, ptr::null_mut());
+            let _ = xlib::XPutImage(display.display,
+                                    self.pixmap,
+                                    gc,
+                                    image,
+                                    0,
+                                    0,
+                                    rect.origin.x,
+                                    rect.origin.y,
+                                    rect.size.width as c_uint,
+                                    rect.size.height as c_uint);
+            xlib::XFreeGC(display.display, gc);
+
+            // The data belongs to the caller; keep XDestroyImage from freeing it.
+            (*image).data = ptr::null_mut();
+            xlib::XDestroyImage(image);
+        }
+        Ok(())
+    }
+
+    pub fn read_pixels(&self, display: &NativeDisplay) -> Option<Vec<u8>> {
+        if self.pixmap == 0 {
+            return None;
+        }
+        let display = match glx_display_info(display) {
+            Ok(display) => display,
+            Err(_) => return None,
+        };
+
+        unsafe {
+
+            let image = xlib::XGetImage(display.display,
+                                        self.pixmap,
+                                        0,
+                                        0,
+                                        self.size.width as c_uint,
+                                        self.size.height as c_uint,
+                                        !0,
+                                        xlib::ZPixmap);
+            if image.is_null() {
+                return None;
+            }
+
+            // The rows of the image may be padded; 32-bit pixels are BGRA in memory on the
+            // little-endian machines we run on.
+            let row_length = self.size.width as usize * 4;
+            let stride = (*image).bytes_per_line as usize;
+            let data = (*image).data as *const u8;
+            let mut pixels = Vec::with_capacity(row_length * self.size.height as usize);
+            for y in 0..self.size.height as usize {
+                let row = data.offset((y * stride) as isize);
+                pixels.extend_from_slice(slice::from_raw_parts(row, row_length));
+            }
+            xlib::XDestroyImage(image);
+            Some(pixels)
         }
     }
 
@@ -297,17 +638,18 @@ impl PixmapNativeSurface {
         self.pixmap as isize
     }
 
-    pub fn destroy(&mut self, display: &NativeDisplay) {
+    pub fn destroy(&mut self, display: &NativeDisplay) -> Result<(), SurfaceError> {
+        try!(self.check_pixmap());
+        let display = try!(glx_display_info(display));
+        if !self.glx_pixmaps.borrow().is_empty() {
+            warn!("Destroying a pixmap whose GLX pixmaps the compositor didn't release");
+        }
         unsafe {
-            let display = match display {
-                &NativeDisplay::GLX(info) => info,
-                &NativeDisplay::EGL(_) => unreachable!(),
-            };
-
-            assert!(self.pixmap != 0);
             xlib::XFreePixmap(display.display, self.pixmap);
-            self.mark_wont_leak()
         }
+        self.pixmap = 0;
+        self.mark_wont_leak();
+        Ok(())
     }
 
     pub fn mark_will_leak(&mut self) {
@@ -318,9 +660,106 @@ impl PixmapNativeSurface {
         self.will_leak = false;
     }
 
+    pub fn will_leak(&self) -> bool {
+        self.will_leak
+    }
+
     pub fn gl_rasterization_context(&mut self,
-                                    gl_context: Arc<GLContext>)
-                                    -> Option<GLRasterizationContext> {
+                                    gl_context: Arc<GLContext>,
+                                    _: ContextPriorityHint)
+                                    -> Result<GLRasterizationContext, SurfaceError> {
+        try!(self.check_pixmap());
         GLRasterizationContext::new(gl_context, self.pixmap, self.size)
+            .ok_or(SurfaceError::Unsupported)
+    }
+
+    /// GLX has no context priorities.
+    pub fn granted_context_priority(&self) -> Option<ContextPriorityHint> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CachedGLXPixmap, PixmapNativeSurface};
+    use euclid::size::Size2D;
+    use rustc_serialize::json;
+    use std::cell::RefCell;
+
+    #[test]
+    fn glx_pixmaps_are_not_encoded() {
+        let surface = PixmapNativeSurface {
+            pixmap: 42,
+            glx_pixmaps: RefCell::new(vec!(CachedGLXPixmap {
+                display: 0x1000,
+                glx_pixmap: 7,
+                bound: true,
+            })),
+            will_leak: false,
+            size: Size2D::new(64, 32),
+        };
+        let encoded = json::encode(&surface).unwrap();
+        assert!(!encoded.contains("glx"));
+
+        let decoded: PixmapNativeSurface = json::decode(&encoded).unwrap();
+        assert_eq!(decoded.pixmap, 42);
+        assert_eq!(decoded.size, Size2D::new(64, 32));
+        assert!(decoded.glx_pixmaps.borrow().is_empty());
+
+        // The cached GLX pixmap belongs to a display that doesn't exist; don't destroy it.
+        surface.glx_pixmaps.borrow_mut().clear();
+    }
+
+    /// Binds a pixmap to a texture twice on one display and then on another, with a GLX context
+    /// of each display current in turn, and destroys it. Any X error fails the test. Run with
+    /// `--features x11-tests`; see `make_glx_context_current`.
+    #[cfg(feature = "x11-tests")]
+    #[test]
+    fn cached_glx_pixmaps_are_rebound_and_destroyed_without_x_errors() {
+        use super::glx_display_info;
+        use test_util::{XErrorLog, make_glx_context_current};
+        use texturegl::{Texture, TextureTarget};
+
+        let errors = XErrorLog::install();
+        let first_display = make_glx_context_current();
+        let first = glx_display_info(&first_display).unwrap();
+        let mut surface = PixmapNativeSurface::new(&first, Size2D::new(16, 16)).unwrap();
+        surface.upload(&first_display, &vec![0xff; 16 * 16 * 4]).unwrap();
+        let first_texture = Texture::new(TextureTarget::TextureTarget2D, Size2D::new(16, 16));
+        surface.bind_to_texture(&first_display, &first_texture).unwrap();
+        let glx_pixmap = surface.glx_pixmaps.borrow()[0].glx_pixmap;
+        assert_eq!(errors.take_errors(&first_display), vec!());
+
+        // Binding again releases the cached GLX pixmap and binds it again.
+        surface.bind_to_texture(&first_display, &first_texture).unwrap();
+        {
+            let glx_pixmaps = surface.glx_pixmaps.borrow();
+            assert_eq!(glx_pixmaps.len(), 1);
+            assert_eq!(glx_pixmaps[0].glx_pixmap, glx_pixmap);
+            assert!(glx_pixmaps[0].bound);
+        }
+        assert_eq!(errors.take_errors(&first_display), vec!());
+
+        // A compositor on another display doesn't use the GLX pixmap of the first one, but
+        // creates its own.
+        let second_display = make_glx_context_current();
+        let second = glx_display_info(&second_display).unwrap();
+        let second_texture = Texture::new(TextureTarget::TextureTarget2D, Size2D::new(16, 16));
+        surface.bind_to_texture(&second_display, &second_texture).unwrap();
+        {
+            let glx_pixmaps = surface.glx_pixmaps.borrow();
+            assert_eq!(glx_pixmaps.len(), 2);
+            assert_eq!(glx_pixmaps[0].display, first.display as usize);
+            assert_eq!(glx_pixmaps[1].display, second.display as usize);
+            assert!(glx_pixmaps[1].bound);
+        }
+        assert_eq!(errors.take_errors(&second_display), vec!());
+
+        surface.release_glx_pixmaps();
+        assert!(surface.glx_pixmaps.borrow().is_empty());
+        assert_eq!(errors.take_errors(&first_display), vec!());
+        assert_eq!(errors.take_errors(&second_display), vec!());
+        surface.destroy(&first_display).unwrap();
+        assert_eq!(errors.take_errors(&first_display), vec!());
     }
 }
@@ -10,21 +10,77 @@
 //! Mac OS-specific implementation of cross-process surfaces. This uses `IOSurface`, introduced
 //! in Mac OS X 10.6 Snow Leopard.
 
+use platform::surface::{ContextPriorityHint, SurfaceError, check_upload_rect, copy_rect};
 use texturegl::Texture;
 
 use cgl;
-use core_foundation::base::TCFType;
+use core_foundation::array::CFArray;
+use core_foundation::base::{CFType, TCFType};
 use core_foundation::boolean::CFBoolean;
 use core_foundation::dictionary::CFDictionary;
 use core_foundation::number::CFNumber;
-use core_foundation::string::CFString;
+use core_foundation::string::{CFString, CFStringRef};
+use euclid::rect::Rect;
 use euclid::size::Size2D;
+use gltrace as gl;
 use io_surface;
 use rustc_serialize::{Decoder, Decodable, Encoder, Encodable};
 use skia::gl_context::{GLContext, PlatformDisplayData};
 use skia::gl_rasterization_context::GLRasterizationContext;
+use std::mem;
+use std::slice;
 use std::sync::Arc;
 
+/// `kIOSurfaceLockReadOnly`, which avoids invalidating caches of the surface on unlock.
+const READ_ONLY_LOCK: u32 = 1;
+
+// The `io_surface` crate doesn't declare the keys for pixel formats and planes.
+#[link(name = "IOSurface", kind = "framework")]
+extern {
+    static kIOSurfacePixelFormat: CFStringRef;
+    static kIOSurfacePlaneInfo: CFStringRef;
+    static kIOSurfacePlaneWidth: CFStringRef;
+    static kIOSurfacePlaneHeight: CFStringRef;
+    static kIOSurfacePlaneBytesPerRow: CFStringRef;
+    static kIOSurfacePlaneBytesPerElement: CFStringRef;
+    static kIOSurfacePlaneOffset: CFStringRef;
+    static kIOSurfacePlaneSize: CFStringRef;
+}
+
+/// The layout of the pixels of an IOSurface.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub enum IOSurfacePixelFormat {
+    /// Four bytes per pixel in BGRA order, with premultiplied alpha. This is what we paint.
+    BGRA8,
+    /// Four half-precision floats per pixel in RGBA order, for content with more than eight bits
+    /// per channel.
+    RGBA16F,
+    /// The bi-planar 4:2:0 video format `420v`: a plane of luma samples followed by a plane of
+    /// interleaved chroma samples at half the resolution in both directions. Surfaces of this
+    /// format can't be bound to a single texture.
+    YCbCr420,
+}
+
+impl IOSurfacePixelFormat {
+    /// Returns the four-character code that identifies this format to IOSurface.
+    fn four_char_code(self) -> i32 {
+        match self {
+            IOSurfacePixelFormat::BGRA8 => 0x42475241, // 'BGRA'
+            IOSurfacePixelFormat::RGBA16F => 0x52476841, // 'RGhA'
+            IOSurfacePixelFormat::YCbCr420 => 0x34323076, // '420v'
+        }
+    }
+
+    /// Returns the number of bytes each pixel takes, or None for planar formats.
+    pub fn bytes_per_element(self) -> Option<i32> {
+        match self {
+            IOSurfacePixelFormat::BGRA8 => Some(4),
+            IOSurfacePixelFormat::RGBA16F => Some(8),
+            IOSurfacePixelFormat::YCbCr420 => None,
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct NativeDisplay {
     pub pixel_format: cgl::CGLPixelFormatObj,
@@ -51,6 +107,7 @@ pub struct IOSurfaceNativeSurface {
     surface: Option<io_surface::IOSurface>,
     will_leak: bool,
     pub size: Size2D<i32>,
+    pub format: IOSurfacePixelFormat,
 }
 
 unsafe impl Send for IOSurfaceNativeSurface {}
@@ -63,6 +120,7 @@ impl Decodable for IOSurfaceNativeSurface {
             surface: id.map(io_surface::lookup),
             will_leak: try!(Decodable::decode(d)),
             size: try!(Decodable::decode(d)),
+            format: try!(Decodable::decode(d)),
         })
     }
 }
@@ -71,12 +129,21 @@ impl Encodable for IOSurfaceNativeSurface {
         try!(self.surface.as_ref().map(io_surface::IOSurface::get_id).encode(e));
         try!(self.will_leak.encode(e));
         try!(self.size.encode(e));
+        try!(self.format.encode(e));
         Ok(())
     }
 }
 
 impl IOSurfaceNativeSurface {
-    pub fn new(_: &NativeDisplay, size: Size2D<i32>) -> IOSurfaceNativeSurface {
+    pub fn new(display: &NativeDisplay, size: Size2D<i32>)
+               -> Result<IOSurfaceNativeSurface, SurfaceError> {
+        IOSurfaceNativeSurface::new_with_format(display, size, IOSurfacePixelFormat::BGRA8)
+    }
+
+    /// Creates a surface of the given pixel format. Only `BGRA8` surfaces can be painted into
+    /// and uploaded to by us; the others hold content produced elsewhere, such as video frames.
+    pub fn new_with_format(_: &NativeDisplay, size: Size2D<i32>, format: IOSurfacePixelFormat)
+                           -> Result<IOSurfaceNativeSurface, SurfaceError> {
         unsafe {
             let width_key: CFString = TCFType::wrap_under_get_rule(io_surface::kIOSurfaceWidth);
             let width_value: CFNumber = CFNumber::from_i32(size.width);
@@ -84,43 +151,179 @@ impl IOSurfaceNativeSurface {
             let height_key: CFString = TCFType::wrap_under_get_rule(io_surface::kIOSurfaceHeight);
             let height_value: CFNumber = CFNumber::from_i32(size.height);
 
-            let bytes_per_row_key: CFString =
-                TCFType::wrap_under_get_rule(io_surface::kIOSurfaceBytesPerRow);
-            let bytes_per_row_value: CFNumber = CFNumber::from_i32(size.width * 4);
-
-            let bytes_per_elem_key: CFString =
-                TCFType::wrap_under_get_rule(io_surface::kIOSurfaceBytesPerElement);
-            let bytes_per_elem_value: CFNumber = CFNumber::from_i32(4);
+            let pixel_format_key: CFString = TCFType::wrap_under_get_rule(kIOSurfacePixelFormat);
+            let pixel_format_value: CFNumber = CFNumber::from_i32(format.four_char_code());
 
             let is_global_key: CFString =
                 TCFType::wrap_under_get_rule(io_surface::kIOSurfaceIsGlobal);
             let is_global_value = CFBoolean::true_value();
 
-            let surface = io_surface::new(&CFDictionary::from_CFType_pairs(&[
+            let mut properties = vec![
                 (width_key.as_CFType(), width_value.as_CFType()),
                 (height_key.as_CFType(), height_value.as_CFType()),
-                (bytes_per_row_key.as_CFType(), bytes_per_row_value.as_CFType()),
-                (bytes_per_elem_key.as_CFType(), bytes_per_elem_value.as_CFType()),
+                (pixel_format_key.as_CFType(), pixel_format_value.as_CFType()),
                 (is_global_key.as_CFType(), is_global_value.as_CFType()),
-            ]));
+            ];
+            match format.bytes_per_element() {
+                Some(bytes_per_element) => {
+                    let bytes_per_row_key: CFString =
+                        TCFType::wrap_under_get_rule(io_surface::kIOSurfaceBytesPerRow);
+                    let bytes_per_row_value: CFNumber =
+                        CFNumber::from_i32(size.width * bytes_per_element);
 
-            IOSurfaceNativeSurface {
+                    let bytes_per_elem_key: CFString =
+                        TCFType::wrap_under_get_rule(io_surface::kIOSurfaceBytesPerElement);
+                    let bytes_per_elem_value: CFNumber = CFNumber::from_i32(bytes_per_element);
+
+                    properties.push((bytes_per_row_key.as_CFType(),
+                                     bytes_per_row_value.as_CFType()));
+                    properties.push((bytes_per_elem_key.as_CFType(),
+                                     bytes_per_elem_value.as_CFType()));
+                }
+                None => {
+                    // A full-resolution plane of one-byte luma samples, then a half-resolution
+                    // plane of two-byte chroma pairs.
+                    let luma_size = Size2D::new(size.width, size.height);
+                    let chroma_size = Size2D::new((size.width + 1) / 2, (size.height + 1) / 2);
+                    let luma_plane = plane_info(luma_size, 1, 0);
+                    let chroma_plane =
+                        plane_info(chroma_size, 2, luma_size.width * luma_size.height);
+                    let planes = CFArray::from_CFTypes(&[luma_plane.as_CFType(),
+                                                         chroma_plane.as_CFType()]);
+                    let plane_info_key: CFString =
+                        TCFType::wrap_under_get_rule(kIOSurfacePlaneInfo);
+                    properties.push((plane_info_key.as_CFType(), planes.as_CFType()));
+                }
+            }
+
+            let surface = io_surface::new(&CFDictionary::from_CFType_pairs(&properties));
+
+            // IOSurfaceCreate returns null when the surface can't be allocated, for example
+            // under memory pressure. There is nothing to release in that case.
+            if surface.obj.is_null() {
+                mem::forget(surface);
+                return Err(SurfaceError::AllocationFailed);
+            }
+
+            Ok(IOSurfaceNativeSurface {
                 surface: Some(surface),
                 will_leak: true,
                 size: size,
+                format: format,
+            })
+        }
+    }
+
+    /// Returns `Unsupported` unless this surface holds pixels we can paint, upload, and read.
+    fn check_bgra8(&self) -> Result<(), SurfaceError> {
+        match self.format {
+            IOSurfacePixelFormat::BGRA8 => Ok(()),
+            IOSurfacePixelFormat::RGBA16F | IOSurfacePixelFormat::YCbCr420 => {
+                Err(SurfaceError::Unsupported)
             }
         }
     }
 
-    pub fn bind_to_texture(&self, _: &NativeDisplay, texture: &Texture) {
+    /// Returns the IOSurface, or `InvalidState` if the surface was destroyed or its ID didn't
+    /// name a surface when it was decoded.
+    fn io_surface(&self) -> Result<&io_surface::IOSurface, SurfaceError> {
+        match self.surface {
+            Some(ref io_surface) if !io_surface.obj.is_null() => Ok(io_surface),
+            _ => Err(SurfaceError::InvalidState),
+        }
+    }
+
+    pub fn bind_to_texture(&self, _: &NativeDisplay, texture: &Texture)
+                           -> Result<(), SurfaceError> {
+        let io_surface = try!(self.io_surface());
+        let (internal_format, format, pixel_type) = match self.format {
+            IOSurfacePixelFormat::BGRA8 => {
+                let _bound_texture = texture.bind();
+                texture.forget_storage();
+                io_surface.bind_to_gl_texture(self.size.width, self.size.height);
+                return Ok(());
+            }
+            IOSurfacePixelFormat::RGBA16F => (gl::RGBA16F, gl::RGBA, gl::HALF_FLOAT),
+            // Each plane would need a texture of its own, and a shader to convert to RGB.
+            IOSurfacePixelFormat::YCbCr420 => return Err(SurfaceError::Unsupported),
+        };
+
         let _bound_texture = texture.bind();
-        let io_surface = self.surface.as_ref().unwrap();
-        io_surface.bind_to_gl_texture(self.size.width, self.size.height);
+        texture.forget_storage();
+        let error = unsafe {
+            cgl::CGLTexImageIOSurface2D(cgl::CGLGetCurrentContext(),
+                                        texture.target.as_gl_target(),
+                                        internal_format,
+                                        self.size.width,
+                                        self.size.height,
+                                        format,
+                                        pixel_type,
+                                        io_surface.obj as _,
+                                        0)
+        };
+        if error != cgl::kCGLNoError {
+            return Err(SurfaceError::PlatformError(error as i32));
+        }
+        Ok(())
+    }
+
+    pub fn upload(&mut self, _: &NativeDisplay, data: &[u8]) -> Result<(), SurfaceError> {
+        try!(self.check_bgra8());
+        let io_surface = try!(self.io_surface());
+        if data.len() < self.size.width as usize * self.size.height as usize * 4 {
+            return Err(SurfaceError::SourceTooSmall);
+        }
+        io_surface.upload(data);
+        Ok(())
+    }
+
+    pub fn upload_rect(&mut self, _: &NativeDisplay, data: &[u8], rect: Rect<i32>, stride: i32)
+                       -> Result<(), SurfaceError> {
+        try!(self.check_bgra8());
+        try!(check_upload_rect(self.size, 4, data, &rect, stride));
+        let io_surface = try!(self.io_surface());
+
+        unsafe {
+            let mut seed = 0;
+            io_surface::IOSurfaceLock(io_surface.obj, 0, &mut seed);
+
+            let surface_stride = io_surface::IOSurfaceGetBytesPerRow(io_surface.obj) as usize;
+            let address = io_surface::IOSurfaceGetBaseAddress(io_surface.obj) as *mut u8;
+            let surface_bytes = slice::from_raw_parts_mut(address,
+                                                          surface_stride *
+                                                          self.size.height as usize);
+            copy_rect(surface_bytes, surface_stride, 4, data, &rect, stride);
+
+            io_surface::IOSurfaceUnlock(io_surface.obj, 0, &mut seed);
+        }
+        Ok(())
     }
 
-    pub fn upload(&mut self, _: &NativeDisplay, data: &[u8]) {
-        let io_surface = self.surface.as_ref().unwrap();
-        io_surface.upload(data)
+    pub fn read_pixels(&self, _: &NativeDisplay) -> Option<Vec<u8>> {
+        if self.check_bgra8().is_err() {
+            return None;
+        }
+        let io_surface = match self.io_surface() {
+            Ok(io_surface) => io_surface,
+            Err(_) => return None,
+        };
+
+        unsafe {
+            let mut seed = 0;
+            io_surface::IOSurfaceLock(io_surface.obj, READ_ONLY_LOCK, &mut seed);
+
+            let row_length = self.size.width as usize * 4;
+            let stride = io_surface::IOSurfaceGetBytesPerRow(io_surface.obj) as usize;
+            let address = io_surface::IOSurfaceGetBaseAddress(io_surface.obj) as *const u8;
+            let mut pixels = Vec::with_capacity(row_length * self.size.height as usize);
+            for y in 0..self.size.height as usize {
+                let row = address.offset((y * stride) as isize);
+                pixels.extend_from_slice(slice::from_raw_parts(row, row_length));
+            }
+
+            io_surface::IOSurfaceUnlock(io_surface.obj, READ_ONLY_LOCK, &mut seed);
+            Some(pixels)
+        }
     }
 
     pub fn get_id(&self) -> isize {
@@ -130,9 +333,12 @@ impl IOSurfaceNativeSurface {
         }
     }
 
-    pub fn destroy(&mut self, _: &NativeDisplay) {
-        self.surface = None;
-        self.mark_wont_leak()
+    pub fn destroy(&mut self, _: &NativeDisplay) -> Result<(), SurfaceError> {
+        if self.surface.take().is_none() {
+            return Err(SurfaceError::InvalidState);
+        }
+        self.mark_wont_leak();
+        Ok(())
     }
 
     pub fn mark_will_leak(&mut self) {
@@ -143,11 +349,131 @@ impl IOSurfaceNativeSurface {
         self.will_leak = false
     }
 
+    pub fn will_leak(&self) -> bool {
+        self.will_leak
+    }
+
     pub fn gl_rasterization_context(&mut self,
-                                    gl_context: Arc<GLContext>)
-                                    -> Option<GLRasterizationContext> {
-        GLRasterizationContext::new(gl_context,
-                                    self.surface.as_ref().unwrap().obj,
-                                    self.size)
+                                    gl_context: Arc<GLContext>,
+                                    _: ContextPriorityHint)
+                                    -> Result<GLRasterizationContext, SurfaceError> {
+        try!(self.check_bgra8());
+        let io_surface = try!(self.io_surface()).obj;
+        GLRasterizationContext::new(gl_context, io_surface, self.size)
+            .ok_or(SurfaceError::Unsupported)
+    }
+
+    /// CGL has no context priorities.
+    pub fn granted_context_priority(&self) -> Option<ContextPriorityHint> {
+        None
+    }
+}
+
+/// Returns the description of a plane of a planar IOSurface with the given size in elements,
+/// bytes per element, and offset in bytes from the start of the surface.
+fn plane_info(size: Size2D<i32>, bytes_per_element: i32, offset: i32) -> CFDictionary {
+    let bytes_per_row = size.width * bytes_per_element;
+    let properties: Vec<(CFStringRef, i32)> = unsafe {
+        vec![
+            (kIOSurfacePlaneWidth, size.width),
+            (kIOSurfacePlaneHeight, size.height),
+            (kIOSurfacePlaneBytesPerRow, bytes_per_row),
+            (kIOSurfacePlaneBytesPerElement, bytes_per_element),
+            (kIOSurfacePlaneOffset, offset),
+            (kIOSurfacePlaneSize, bytes_per_row * size.height),
+        ]
+    };
+    let pairs: Vec<(CFType, CFType)> = properties.into_iter().map(|(key, value)| {
+        let key: CFString = unsafe { TCFType::wrap_under_get_rule(key) };
+        (key.as_CFType(), CFNumber::from_i32(value).as_CFType())
+    }).collect();
+    CFDictionary::from_CFType_pairs(&pairs)
+}
+
+#[cfg(all(test, target_os = "macos"))]
+mod tests {
+    use euclid::size::Size2D;
+    use io_surface::{self, IOSurfaceRef};
+    use platform::surface::SurfaceError;
+    use rustc_serialize::json;
+    use std::ptr;
+    use super::{IOSurfaceNativeSurface, IOSurfacePixelFormat, NativeDisplay};
+
+    #[link(name = "IOSurface", kind = "framework")]
+    extern {
+        fn IOSurfaceGetPixelFormat(buffer: IOSurfaceRef) -> u32;
+        fn IOSurfaceGetBytesPerElement(buffer: IOSurfaceRef) -> usize;
+        fn IOSurfaceGetPlaneCount(buffer: IOSurfaceRef) -> usize;
+    }
+
+    /// Surfaces are created without a GL context, so the display needs no pixel format.
+    fn display() -> NativeDisplay {
+        NativeDisplay {
+            pixel_format: ptr::null_mut(),
+        }
+    }
+
+    #[test]
+    fn surfaces_of_every_format_are_created_with_its_layout() {
+        for &(format, bytes_per_element, bytes_per_row, plane_count) in
+                &[(IOSurfacePixelFormat::BGRA8, 4, 20, 0),
+                  (IOSurfacePixelFormat::RGBA16F, 8, 40, 0),
+                  (IOSurfacePixelFormat::YCbCr420, 1, 5, 2)] {
+            let mut surface =
+                IOSurfaceNativeSurface::new_with_format(&display(), Size2D::new(5, 3), format)
+                    .unwrap();
+            {
+                let io_surface = surface.io_surface().unwrap();
+                unsafe {
+                    assert_eq!(IOSurfaceGetPixelFormat(io_surface.obj) as i32,
+                               format.four_char_code());
+                    assert_eq!(IOSurfaceGetBytesPerElement(io_surface.obj), bytes_per_element);
+                    assert!(io_surface::IOSurfaceGetBytesPerRow(io_surface.obj) as usize >=
+                            bytes_per_row);
+                    assert_eq!(IOSurfaceGetPlaneCount(io_surface.obj), plane_count);
+                }
+            }
+            assert!(surface.destroy(&display()).is_ok());
+        }
+    }
+
+    #[test]
+    fn surface_formats_round_trip_through_serialization() {
+        for &format in &[IOSurfacePixelFormat::BGRA8,
+                         IOSurfacePixelFormat::RGBA16F,
+                         IOSurfacePixelFormat::YCbCr420] {
+            let mut surface =
+                IOSurfaceNativeSurface::new_with_format(&display(), Size2D::new(5, 3), format)
+                    .unwrap();
+            let mut decoded: IOSurfaceNativeSurface =
+                json::decode(&json::encode(&surface).unwrap()).unwrap();
+            assert_eq!(decoded.format, format);
+            assert_eq!(decoded.size, Size2D::new(5, 3));
+            assert_eq!(decoded.get_id(), surface.get_id());
+            assert!(decoded.will_leak());
+            decoded.mark_wont_leak();
+            assert!(surface.destroy(&display()).is_ok());
+        }
+    }
+
+    #[test]
+    fn only_bgra8_surfaces_are_uploaded_to_and_read() {
+        let mut surface = IOSurfaceNativeSurface::new(&display(), Size2D::new(2, 2)).unwrap();
+        assert_eq!(surface.format, IOSurfacePixelFormat::BGRA8);
+        assert!(surface.upload(&display(), &[0x80; 16]).is_ok());
+        assert_eq!(surface.read_pixels(&display()), Some(vec![0x80; 16]));
+        assert!(surface.destroy(&display()).is_ok());
+
+        for &format in &[IOSurfacePixelFormat::RGBA16F, IOSurfacePixelFormat::YCbCr420] {
+            let mut surface =
+                IOSurfaceNativeSurface::new_with_format(&display(), Size2D::new(2, 2), format)
+                    .unwrap();
+            match surface.upload(&display(), &[0x80; 32]) {
+                Err(SurfaceError::Unsupported) => {}
+                result => panic!("Expected Unsupported, got {:?}", result),
+            }
+            assert_eq!(surface.read_pixels(&display()), None);
+            assert!(surface.destroy(&display()).is_ok());
+        }
     }
 }
@@ -10,19 +10,23 @@
 //! Implementation of cross-process surfaces. This delegates to the platform-specific
 //! implementation.
 
-use texturegl::Texture;
+use texturegl::{Format, PboPool, Texture};
 
+use euclid::rect::Rect;
 use euclid::size::Size2D;
+use rustc_serialize::json;
 use skia::gl_rasterization_context::GLRasterizationContext;
 use skia::gl_context::GLContext;
+use std::borrow::Cow;
+use std::cell::Cell;
 use std::sync::Arc;
 
-#[cfg(not(target_os="android"))]
-use gleam::gl;
+use gltrace as gl;
 
 #[cfg(target_os="macos")]
 pub use platform::macos::surface::{NativeDisplay,
-                                   IOSurfaceNativeSurface};
+                                   IOSurfaceNativeSurface,
+                                   IOSurfacePixelFormat};
 
 #[cfg(target_os="linux")]
 pub use platform::linux::surface::{NativeDisplay,
@@ -37,7 +41,46 @@ pub use platform::egl::surface::{EGLImageNativeSurface};
 pub use platform::android::surface::NativeDisplay;
 
 #[cfg(target_os="windows")]
-pub use platform::windows::surface::NativeDisplay;
+pub use platform::windows::surface::{NativeDisplay,
+                                     GdiDibNativeSurface};
+
+/// Errors that can occur when filling or creating native surfaces.
+#[derive(Debug)]
+pub enum SurfaceError {
+    /// The source pixel data is too small for the given size and stride.
+    SourceTooSmall,
+    /// The rect to upload does not lie within the surface.
+    RectOutOfBounds,
+    /// The platform could not allocate a surface, for example because the X server ran out of
+    /// pixmaps or the system is under memory pressure.
+    AllocationFailed,
+    /// The surface can't be used this way in its current state, for example because it was
+    /// already destroyed or holds no pixels.
+    InvalidState,
+    /// The platform reported an error, with the given platform-specific code.
+    PlatformError(i32),
+    /// The operation isn't supported by this kind of surface or display.
+    Unsupported,
+}
+
+/// How much of the GPU a GL context should get when other contexts compete for it. Servo paints
+/// with `Low` and composites with `High`, so that rasterizing tiles on the same GPU can't starve
+/// composition. Platforms that can't prioritize contexts ignore the hint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContextPriorityHint {
+    Low,
+    Medium,
+    High,
+}
+
+/// A native surface encoded by `NativeSurface::serialize`, as JSON tagged with the kind of the
+/// surface.
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+pub enum SerializedSurface {
+    MemoryBuffer(String),
+    Pixmap(String),
+    IOSurface(String),
+}
 
 pub enum NativeSurface {
     MemoryBuffer(MemoryBufferNativeSurface),
@@ -47,21 +90,24 @@ pub enum NativeSurface {
     IOSurface(IOSurfaceNativeSurface),
 #[cfg(any(target_os="android",target_os="linux"))]
     EGLImage(EGLImageNativeSurface),
+#[cfg(target_os="windows")]
+    GdiDib(GdiDibNativeSurface),
 }
 
 #[cfg(target_os="linux")]
 impl NativeSurface {
-    /// Creates a new native surface with uninitialized data.
-    pub fn new(display: &NativeDisplay, size: Size2D<i32>) -> NativeSurface {
+    /// Creates a new platform surface with uninitialized data, without falling back to memory.
+    pub fn new_platform(display: &NativeDisplay, size: Size2D<i32>)
+                        -> Result<NativeSurface, SurfaceError> {
         match display {
             &NativeDisplay::EGL(_info) => {
-                NativeSurface::EGLImage(EGLImageNativeSurface::new(display, size))
+                Ok(NativeSurface::EGLImage(EGLImageNativeSurface::new(display, size)))
             }
             &NativeDisplay::GLX(info) => {
                 if info.display == ptr::null_mut() {
-                    NativeSurface::MemoryBuffer(MemoryBufferNativeSurface::new(display, size))
+                    Ok(NativeSurface::MemoryBuffer(MemoryBufferNativeSurface::new(display, size)))
                 } else {
-                    NativeSurface::Pixmap(PixmapNativeSurface::new(&info, size))
+                    PixmapNativeSurface::new(&info, size).map(NativeSurface::Pixmap)
                 }
             }
         }
@@ -70,28 +116,313 @@ impl NativeSurface {
 
 #[cfg(target_os="macos")]
 impl NativeSurface {
-    /// Creates a new native surface with uninitialized data.
-    pub fn new(display: &NativeDisplay, size: Size2D<i32>) -> NativeSurface {
-        NativeSurface::IOSurface(IOSurfaceNativeSurface::new(display, size))
+    /// Creates a new platform surface with uninitialized data, without falling back to memory.
+    pub fn new_platform(display: &NativeDisplay, size: Size2D<i32>)
+                        -> Result<NativeSurface, SurfaceError> {
+        IOSurfaceNativeSurface::new(display, size).map(NativeSurface::IOSurface)
    }
 }
 
 #[cfg(target_os="android")]
 impl NativeSurface {
-    /// Creates a new native surface with uninitialized data.
-    pub fn new(display: &NativeDisplay, size: Size2D<i32>) -> NativeSurface {
-        NativeSurface::EGLImage(EGLImageNativeSurface::new(display, size))
+    /// Creates a new platform surface with uninitialized data, without falling back to memory.
+    pub fn new_platform(display: &NativeDisplay, size: Size2D<i32>)
+                        -> Result<NativeSurface, SurfaceError> {
+        Ok(NativeSurface::EGLImage(EGLImageNativeSurface::new(display, size)))
    }
 }
 
 #[cfg(target_os="windows")]
 impl NativeSurface {
-    /// Creates a new native surface with uninitialized data.
-    pub fn new(display: &NativeDisplay, size: Size2D<i32>) -> NativeSurface {
-        NativeSurface::MemoryBuffer(MemoryBufferNativeSurface::new(display, size))
+    /// Creates a new platform surface with uninitialized data, without falling back to memory.
+    pub fn new_platform(display: &NativeDisplay, size: Size2D<i32>)
+                        -> Result<NativeSurface, SurfaceError> {
+        GdiDibNativeSurface::new(display, size).map(NativeSurface::GdiDib)
    }
 }
 
+impl NativeSurface {
+    /// Creates a new native surface with uninitialized data. If the platform surface cannot be
+    /// allocated, this falls back to a slower surface in memory, so that painting can go on.
+    /// Use a `NativeSurfaceAllocator` to avoid retrying the platform surface on every allocation
+    /// while allocations keep failing.
+    pub fn new(display: &NativeDisplay, size: Size2D<i32>) -> NativeSurface {
+        match NativeSurface::new_platform(display, size) {
+            Ok(surface) => surface,
+            Err(error) => {
+                warn!("Failed to allocate a platform surface ({:?}); using memory instead.",
+                      error);
+                NativeSurface::new_fallback(display, size)
+            }
+        }
+    }
+
+    /// Creates a new native surface with uninitialized data in the given pixel format. Platform
+    /// surfaces only hold `ARGB32Format` pixels, so surfaces in the other formats are allocated
+    /// in memory.
+    pub fn new_with_format(display: &NativeDisplay, size: Size2D<i32>, format: Format)
+                           -> NativeSurface {
+        match format {
+            Format::ARGB32Format => NativeSurface::new(display, size),
+            Format::RGB24Format | Format::A8Format => {
+                NativeSurface::MemoryBuffer(MemoryBufferNativeSurface::new_with_format(display,
+                                                                                       size,
+                                                                                       format))
+            }
+        }
+    }
+
+    /// Creates a memory surface standing in for a platform surface that couldn't be allocated.
+    fn new_fallback(display: &NativeDisplay, size: Size2D<i32>) -> NativeSurface {
+        let mut surface = MemoryBufferNativeSurface::new(display, size);
+        surface.is_fallback = true;
+        NativeSurface::MemoryBuffer(surface)
+    }
+
+    /// Returns the memory surface this is, if it is one.
+    pub fn as_memory_buffer(&self) -> Option<&MemoryBufferNativeSurface> {
+        if let NativeSurface::MemoryBuffer(ref surface) = *self {
+            Some(surface)
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if this surface is a memory surface that was created because the platform
+    /// surface couldn't be allocated.
+    pub fn is_fallback(&self) -> bool {
+        if let NativeSurface::MemoryBuffer(ref surface) = *self {
+            surface.is_fallback
+        } else {
+            false
+        }
+    }
+}
+
+/// The default number of allocations served from memory before `NativeSurfaceAllocator` tries
+/// the platform surface again.
+pub const DEFAULT_FALLBACK_RETRY_INTERVAL: usize = 32;
+
+/// Creates a platform surface, like `NativeSurface::new_platform`.
+pub type PlatformSurfaceConstructor = fn(&NativeDisplay, Size2D<i32>)
+                                         -> Result<NativeSurface, SurfaceError>;
+
+/// Allocates native surfaces for one display, keeping track of failures of the platform surface.
+/// Once an allocation has failed, further surfaces are allocated in memory without trying the
+/// platform first, except that every `retry_interval` allocations the platform surface is tried
+/// again, so that a transient failure doesn't degrade the rest of the session.
+pub struct NativeSurfaceAllocator {
+    /// The number of allocations served from memory between attempts at a platform surface.
+    pub retry_interval: usize,
+
+    /// Creates the platform surfaces. This is `NativeSurface::new_platform` unless replaced, for
+    /// instance to make allocations fail in tests.
+    pub new_platform: PlatformSurfaceConstructor,
+
+    /// The total number of surfaces that were allocated in memory because of a failure.
+    fallback_count: usize,
+
+    /// Whether the last attempt at a platform surface failed.
+    degraded: bool,
+
+    /// The number of allocations served from memory since the last attempt.
+    allocations_since_attempt: usize,
+}
+
+impl NativeSurfaceAllocator {
+    pub fn new() -> NativeSurfaceAllocator {
+        NativeSurfaceAllocator {
+            retry_interval: DEFAULT_FALLBACK_RETRY_INTERVAL,
+            new_platform: NativeSurface::new_platform,
+            fallback_count: 0,
+            degraded: false,
+            allocations_since_attempt: 0,
+        }
+    }
+
+    /// Creates a new native surface with uninitialized data, falling back to memory if the
+    /// platform surface cannot be allocated or recently failed to be.
+    pub fn allocate(&mut self, display: &NativeDisplay, size: Size2D<i32>) -> NativeSurface {
+        if self.degraded && self.allocations_since_attempt < self.retry_interval {
+            self.allocations_since_attempt += 1;
+            self.fallback_count += 1;
+            return NativeSurface::new_fallback(display, size);
+        }
+
+        match (self.new_platform)(display, size) {
+            Ok(surface) => {
+                if self.degraded {
+                    info!("Platform surface allocation recovered.");
+                    self.degraded = false;
+                }
+                surface
+            }
+            Err(error) => {
+                warn!("Failed to allocate a platform surface ({:?}); using memory instead.",
+                      error);
+                self.degraded = true;
+                self.allocations_since_attempt = 0;
+                self.fallback_count += 1;
+                NativeSurface::new_fallback(display, size)
+            }
+        }
+    }
+
+    /// Returns the number of surfaces that were allocated in memory because the platform surface
+    /// couldn't be allocated.
+    pub fn fallback_count(&self) -> usize {
+        self.fallback_count
+    }
+
+    /// Returns true if the last attempt at allocating a platform surface failed.
+    pub fn is_degraded(&self) -> bool {
+        self.degraded
+    }
+}
+
+/// The default number of contexts a `RasterizationContextPool` holds.
+pub const DEFAULT_RASTERIZATION_CONTEXT_POOL_CAPACITY: usize = 16;
+
+/// Identifies the surface and size a pooled rasterization context was created for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RasterizationContextKey {
+    /// The ID of the surface, as returned by `NativeSurface::get_id`.
+    pub surface_id: isize,
+
+    /// The size of the surface.
+    pub size: Size2D<i32>,
+}
+
+struct PooledRasterizationContext<C> {
+    key: RasterizationContextKey,
+    context: Arc<C>,
+
+    /// The value of the pool's clock when the context was last handed out.
+    last_used: u64,
+}
+
+/// Keeps the GPU rasterization contexts of native surfaces across frames, since creating one is
+/// expensive. Contexts are keyed by the ID and size of their surface; when a surface is resized
+/// its old contexts are dropped, and when it is destroyed they should be dropped with
+/// `invalidate_surface` or `NativeSurface::destroy_pooled`. Once the pool holds `capacity`
+/// contexts, the least recently used one is dropped to make room for a new one. The pool holds
+/// `GLRasterizationContext`s unless told otherwise.
+pub struct RasterizationContextPool<C = GLRasterizationContext> {
+    /// The maximum number of contexts held.
+    capacity: usize,
+
+    contexts: Vec<PooledRasterizationContext<C>>,
+
+    /// Counts the contexts handed out, to order them by use.
+    clock: u64,
+
+    hit_count: usize,
+    miss_count: usize,
+}
+
+impl<C> RasterizationContextPool<C> {
+    pub fn new() -> RasterizationContextPool<C> {
+        RasterizationContextPool {
+            capacity: DEFAULT_RASTERIZATION_CONTEXT_POOL_CAPACITY,
+            contexts: vec!(),
+            clock: 0,
+            hit_count: 0,
+            miss_count: 0,
+        }
+    }
+
+    /// Returns the context for `key`, creating it with `create` if the pool doesn't hold it.
+    /// Errors from `create` are returned without touching the pool.
+    pub fn get_or_create<F>(&mut self, key: RasterizationContextKey, create: F)
+                            -> Result<Arc<C>, SurfaceError>
+                            where F: FnOnce() -> Result<Arc<C>, SurfaceError> {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(pooled) = self.contexts.iter_mut().find(|pooled| pooled.key == key) {
+            pooled.last_used = clock;
+            self.hit_count += 1;
+            return Ok(pooled.context.clone());
+        }
+
+        self.miss_count += 1;
+        let context = try!(create());
+
+        // A context for another size of the same surface is stale.
+        self.contexts.retain(|pooled| pooled.key.surface_id != key.surface_id);
+        if self.capacity == 0 {
+            return Ok(context);
+        }
+        while self.contexts.len() >= self.capacity {
+            self.evict_least_recently_used();
+        }
+        self.contexts.push(PooledRasterizationContext {
+            key: key,
+            context: context.clone(),
+            last_used: clock,
+        });
+        Ok(context)
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let index = self.contexts
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|&(_, pooled)| pooled.last_used)
+                        .map(|(index, _)| index);
+        if let Some(index) = index {
+            self.contexts.swap_remove(index);
+        }
+    }
+
+    /// Drops the contexts held for the surface with the given ID. This must be called before the
+    /// surface is destroyed, since a new surface may reuse its ID.
+    pub fn invalidate_surface(&mut self, surface_id: isize) {
+        self.contexts.retain(|pooled| pooled.key.surface_id != surface_id);
+    }
+
+    /// Drops all the contexts held.
+    pub fn clear(&mut self) {
+        self.contexts.clear();
+    }
+
+    /// Changes the maximum number of contexts held, dropping the least recently used ones if the
+    /// pool holds more than that.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.contexts.len() > capacity {
+            self.evict_least_recently_used();
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of contexts held.
+    pub fn len(&self) -> usize {
+        self.contexts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contexts.is_empty()
+    }
+
+    /// Returns true if the pool holds a context for `key`.
+    pub fn contains(&self, key: &RasterizationContextKey) -> bool {
+        self.contexts.iter().any(|pooled| pooled.key == *key)
+    }
+
+    /// Returns the number of requests served from the pool.
+    pub fn hit_count(&self) -> usize {
+        self.hit_count
+    }
+
+    /// Returns the number of requests that had to create a context.
+    pub fn miss_count(&self) -> usize {
+        self.miss_count
+    }
+}
+
 macro_rules! native_surface_method_with_mutability {
     ($self_:ident, $function_name:ident, $surface:ident, $pattern:pat, $($argument:ident),*) => {
         match *$self_ {
@@ -106,6 +437,9 @@ macro_rules! native_surface_method_with_mutability {
             #[cfg(any(target_os="android",target_os="linux"))]
             NativeSurface::EGLImage($pattern) =>
                 $surface.$function_name($($argument), *),
+            #[cfg(target_os="windows")]
+            NativeSurface::GdiDib($pattern) =>
+                $surface.$function_name($($argument), *),
         }
     };
 }
@@ -142,31 +476,114 @@ macro_rules! native_surface_property {
             NativeSurface::IOSurface(ref surface) => surface.$property_name,
             #[cfg(any(target_os="android",target_os="linux"))]
             NativeSurface::EGLImage(ref surface) => surface.$property_name,
+            #[cfg(target_os="windows")]
+            NativeSurface::GdiDib(ref surface) => surface.$property_name,
         }
     };
 }
 
 impl NativeSurface {
     /// Binds the surface to a GPU texture. Compositing task only.
-    pub fn bind_to_texture(&self, display: &NativeDisplay, texture: &Texture) {
+    ///
+    /// Surfaces come from painting tasks that may have crashed or misbehaved, so failures are
+    /// returned rather than panicking; the texture is left without contents in that case.
+    pub fn bind_to_texture(&self, display: &NativeDisplay, texture: &Texture)
+                           -> Result<(), SurfaceError> {
         native_surface_method!(self bind_to_texture (display, texture))
     }
 
-    /// Uploads pixel data to the surface. Painting task only.
-    pub fn upload(&mut self, display: &NativeDisplay, data: &[u8]) {
+    /// Releases what the compositor holds to bind this surface to textures, such as the GLX
+    /// pixmaps made from X pixmaps. This must be called when the compositor drops the texture of
+    /// the surface or hands the surface back to the painter. Compositor task only.
+    pub fn release_texture_binding(&self) {
+        match *self {
+            #[cfg(target_os="linux")]
+            NativeSurface::Pixmap(ref surface) => surface.release_glx_pixmaps(),
+            _ => {}
+        }
+    }
+
+    /// Uploads pixel data to the surface. `data` must hold the whole surface. Painting task
+    /// only.
+    pub fn upload(&mut self, display: &NativeDisplay, data: &[u8]) -> Result<(), SurfaceError> {
         native_surface_method_mut!(self upload (display, data))
     }
 
+    /// Uploads pixel data to a part of the surface, leaving the rest of it as it is. `data` holds
+    /// the rows of `rect`, top row first, `stride` bytes apart, in the pixel format of the
+    /// surface. Painting task only.
+    pub fn upload_rect(&mut self,
+                       display: &NativeDisplay,
+                       data: &[u8],
+                       rect: Rect<i32>,
+                       stride: i32)
+                       -> Result<(), SurfaceError> {
+        native_surface_method_mut!(self upload_rect (display, data, rect, stride))
+    }
+
+    /// Copies the contents of the surface, without disturbing it, for instance to take a
+    /// screenshot. The pixels are returned in BGRA order with premultiplied alpha, top row first,
+    /// with a stride of four times the width of the surface. Returns None if the contents can't
+    /// be read back, for instance because nothing was uploaded to the surface yet.
+    pub fn read_pixels(&self, display: &NativeDisplay) -> Option<Vec<u8>> {
+        native_surface_method!(self read_pixels (display))
+    }
+
     /// Returns an opaque ID identifying the surface for debugging.
     pub fn get_id(&self) -> isize {
         native_surface_method!(self get_id ())
     }
 
-    /// Destroys the surface. After this, it is an error to use the surface. Painting task only.
-    pub fn destroy(&mut self, display: &NativeDisplay) {
+    /// Destroys the surface. After this, using the surface fails with `InvalidState`. Painting
+    /// task only.
+    pub fn destroy(&mut self, display: &NativeDisplay) -> Result<(), SurfaceError> {
         native_surface_method_mut!(self destroy (display))
     }
 
+    /// Encodes the surface so that it can be decoded with `deserialize` after the compositor
+    /// restarts, possibly in another process. Memory surfaces are encoded with their pixels, and
+    /// platform surfaces by their ID, so that the decoded surface shares the platform surface
+    /// with this one. Returns None for surfaces that can't outlive this process: EGL images and
+    /// DIB sections.
+    pub fn serialize(&self) -> Option<SerializedSurface> {
+        match *self {
+            NativeSurface::MemoryBuffer(ref surface) => {
+                json::encode(surface).ok().map(SerializedSurface::MemoryBuffer)
+            }
+            #[cfg(target_os="linux")]
+            NativeSurface::Pixmap(ref surface) => {
+                json::encode(surface).ok().map(SerializedSurface::Pixmap)
+            }
+            #[cfg(target_os="macos")]
+            NativeSurface::IOSurface(ref surface) => {
+                json::encode(surface).ok().map(SerializedSurface::IOSurface)
+            }
+            #[cfg(any(target_os="android",target_os="linux"))]
+            NativeSurface::EGLImage(_) => None,
+            #[cfg(target_os="windows")]
+            NativeSurface::GdiDib(_) => None,
+        }
+    }
+
+    /// Decodes a surface encoded with `serialize`. Returns None if the encoding is malformed or
+    /// the surface is of a kind this platform doesn't have.
+    pub fn deserialize(serialized: &SerializedSurface) -> Option<NativeSurface> {
+        match *serialized {
+            SerializedSurface::MemoryBuffer(ref encoded) => {
+                json::decode(encoded).ok().map(NativeSurface::MemoryBuffer)
+            }
+            #[cfg(target_os="linux")]
+            SerializedSurface::Pixmap(ref encoded) => {
+                json::decode(encoded).ok().map(NativeSurface::Pixmap)
+            }
+            #[cfg(target_os="macos")]
+            SerializedSurface::IOSurface(ref encoded) => {
+                json::decode(encoded).ok().map(NativeSurface::IOSurface)
+            }
+            _ => None,
+        }
+    }
+
     /// Records that the surface will leak if destroyed. This is done by the compositor immediately
     /// after receiving the surface.
     pub fn mark_will_leak(&mut self) {
@@ -193,14 +610,55 @@ impl NativeSurface {
         native_surface_method_mut!(self mark_wont_leak ())
     }
 
+    /// Returns true if the surface is marked as leaking unless it is destroyed. See
+    /// `mark_will_leak`.
+    pub fn will_leak(&self) -> bool {
+        native_surface_method!(self will_leak ())
+    }
+
+    /// Returns a context for painting into the surface with the GPU. Fails with `Unsupported`
+    /// for surfaces that can only be painted on the CPU. `priority` is the priority the painter
+    /// wants `gl_context` to run at; see `granted_context_priority` for what it got.
     pub fn gl_rasterization_context(&mut self,
-                                    gl_context: Arc<GLContext>)
-                                    -> Option<Arc<GLRasterizationContext>> {
-        match native_surface_method_mut!(self gl_rasterization_context (gl_context)) {
-            Some(context) => Some(Arc::new(context)),
-            None => None,
+                                    gl_context: Arc<GLContext>,
+                                    priority: ContextPriorityHint)
+                                    -> Result<Arc<GLRasterizationContext>, SurfaceError> {
+        native_surface_method_mut!(self gl_rasterization_context (gl_context, priority))
+            .map(Arc::new)
+    }
+
+    /// Returns the priority the GL context of the last rasterization context created for this
+    /// surface runs at, or None if the platform can't tell or no context was created.
+    pub fn granted_context_priority(&self) -> Option<ContextPriorityHint> {
+        native_surface_method!(self granted_context_priority ())
+    }
+
+    /// Like `gl_rasterization_context`, but reuses the context created for this surface at its
+    /// current size by an earlier call with the same pool, if the pool still holds it.
+    pub fn pooled_gl_rasterization_context(&mut self,
+                                           gl_context: Arc<GLContext>,
+                                           priority: ContextPriorityHint,
+                                           pool: &mut RasterizationContextPool)
+                                           -> Result<Arc<GLRasterizationContext>, SurfaceError> {
+        let key = RasterizationContextKey {
+            surface_id: self.get_id(),
+            size: self.get_size(),
+        };
+
+        // Surfaces without an ID can't be told apart.
+        if key.surface_id == 0 {
+            return self.gl_rasterization_context(gl_context, priority);
         }
+        pool.get_or_create(key, || self.gl_rasterization_context(gl_context, priority))
+    }
 
+    /// Destroys the surface after dropping the contexts `pool` holds for it. Painting task only.
+    pub fn destroy_pooled(&mut self,
+                          display: &NativeDisplay,
+                          pool: &mut RasterizationContextPool)
+                          -> Result<(), SurfaceError> {
+        pool.invalidate_surface(self.get_id());
+        self.destroy(display)
     }
 
     /// Get the memory usage of this native surface. This memory may be allocated
@@ -217,51 +675,264 @@ impl NativeSurface {
     }
 }
 
+/// Checks that `rect` lies within a surface of the given size, and that `data` holds its rows
+/// `stride` bytes apart.
+pub fn check_upload_rect(size: Size2D<i32>,
+                         bytes_per_pixel: usize,
+                         data: &[u8],
+                         rect: &Rect<i32>,
+                         stride: i32)
+                         -> Result<(), SurfaceError> {
+    if rect.origin.x < 0 || rect.origin.y < 0 || rect.size.width < 0 || rect.size.height < 0 ||
+       rect.max_x() > size.width || rect.max_y() > size.height {
+        return Err(SurfaceError::RectOutOfBounds);
+    }
+
+    let row_length = rect.size.width as usize * bytes_per_pixel;
+    if rect.size.height > 0 &&
+       (stride < 0 || (stride as usize) < row_length ||
+        data.len() < stride as usize * (rect.size.height as usize - 1) + row_length) {
+        return Err(SurfaceError::SourceTooSmall);
+    }
+    Ok(())
+}
+
+/// Copies the rows of `rect` from `data`, where they are `stride` bytes apart, into place in
+/// `destination`, whose rows are `destination_stride` bytes apart. The rect must have been checked
+/// with `check_upload_rect`.
+pub fn copy_rect(destination: &mut [u8],
+                 destination_stride: usize,
+                 bytes_per_pixel: usize,
+                 data: &[u8],
+                 rect: &Rect<i32>,
+                 stride: i32) {
+    let row_length = rect.size.width as usize * bytes_per_pixel;
+    for y in 0..rect.size.height as usize {
+        let source_start = y * stride as usize;
+        let destination_start = (rect.origin.y as usize + y) * destination_stride +
+                                rect.origin.x as usize * bytes_per_pixel;
+        destination[destination_start..destination_start + row_length]
+            .clone_from_slice(&data[source_start..source_start + row_length]);
+    }
+}
+
+/// Returns the number of bytes between the rows of a DIB of the given width. GDI pads rows to a
+/// multiple of four bytes.
+pub fn dib_stride(width: i32, bits_per_pixel: u16) -> usize {
+    (width as usize * bits_per_pixel as usize + 31) / 32 * 4
+}
+
 #[derive(RustcDecodable, RustcEncodable)]
 pub struct MemoryBufferNativeSurface {
     bytes: Vec<u8>,
     pub size: Size2D<i32>,
+
+    /// Whether this surface stands in for a platform surface that couldn't be allocated.
+    pub is_fallback: bool,
+
+    /// The layout of the uploaded pixels.
+    pub format: Format,
+
+    /// The serial number of the texture this surface was last bound to, if the texture has been
+    /// kept up to date with the surface since, except for `dirty_rect`. 0 if there is none.
+    texture_serial: Cell<usize>,
+
+    /// The part of the surface that changed since it was bound to the texture with
+    /// `texture_serial`, or None if nothing did.
+    dirty_rect: Cell<Option<Rect<i32>>>,
+
+    /// Whether `destroy` was called.
+    destroyed: bool,
 }
 
 impl MemoryBufferNativeSurface {
-    pub fn new(_: &NativeDisplay, size: Size2D<i32>) -> MemoryBufferNativeSurface {
+    pub fn new(display: &NativeDisplay, size: Size2D<i32>) -> MemoryBufferNativeSurface {
+        MemoryBufferNativeSurface::new_with_format(display, size, Format::ARGB32Format)
+    }
+
+    pub fn new_with_format(_: &NativeDisplay, size: Size2D<i32>, format: Format)
+                           -> MemoryBufferNativeSurface {
         MemoryBufferNativeSurface{
             bytes: vec!(),
             size: size,
+            is_fallback: false,
+            format: format,
+            texture_serial: Cell::new(0),
+            dirty_rect: Cell::new(None),
+            destroyed: false,
         }
     }
 
-    /// This may only be called on the compositor side.
-    #[cfg(not(target_os="android"))]
-    pub fn bind_to_texture(&self, _: &NativeDisplay, texture: &Texture) {
+    /// This may only be called on the compositor side. The storage of the texture is only
+    /// allocated when its size or format changes. If the texture is the one the surface was last
+    /// bound to, only the rows that changed since are uploaded again. Surfaces larger than
+    /// the maximum texture size are not uploaded at all, since GL would fail to allocate the
+    /// texture without telling us.
+    pub fn bind_to_texture(&self, _: &NativeDisplay, texture: &Texture)
+                           -> Result<(), SurfaceError> {
+        self.bind(texture, None)
+    }
+
+    /// Like `bind_to_texture`, but uploads through the next buffer of `pool`, so that the driver
+    /// copies the pixels to the texture asynchronously. This may only be called on the
+    /// compositor side.
+    pub fn bind_to_texture_via_pbo(&self, pool: &mut PboPool, texture: &Texture)
+                                   -> Result<(), SurfaceError> {
+        self.bind(texture, Some(pool))
+    }
+
+    fn bind(&self, texture: &Texture, pbo_pool: Option<&mut PboPool>) -> Result<(), SurfaceError> {
+        // Surfaces decoded from another process may hold fewer bytes than their size calls for,
+        // and GL would read past the end of them.
+        if self.destroyed || (!self.bytes.is_empty() && self.bytes.len() < self.byte_length()) {
+            return Err(SurfaceError::InvalidState);
+        }
+        let gl = texture.gl();
+        if texture.serial() != self.texture_serial.get() {
+            let max_texture_size = gl::get_integer_v(gl, gl::MAX_TEXTURE_SIZE);
+            if max_texture_size > 0 &&
+               (self.size.width as i32 > max_texture_size ||
+                self.size.height as i32 > max_texture_size) {
+                warn!("Not binding a {}x{} memory surface larger than the maximum texture size {}.",
+                      self.size.width,
+                      self.size.height,
+                      max_texture_size);
+                return Err(SurfaceError::Unsupported);
+            }
+        }
+
+        let (_, format, alignment) = self.format.gl_formats();
+        let target = texture.target.as_gl_target();
         let _bound = texture.bind();
-        gl::tex_image_2d(gl::TEXTURE_2D,
-                         0,
-                         gl::RGBA as i32,
-                         self.size.width as i32,
-                         self.size.height as i32,
-                         0,
-                         gl::BGRA,
-                         gl::UNSIGNED_BYTE,
-                         Some(&self.bytes));
+        gl::pixel_store_i(gl, gl::UNPACK_ALIGNMENT, alignment);
+        let size = Size2D::new(self.size.width.max(0) as usize, self.size.height.max(0) as usize);
+        let allocated = texture.ensure_storage(size, self.format);
+
+        // A surface that was never uploaded to has no data yet, so it only gets storage; GL must
+        // not read past the end of our buffer in that case. Rows are uploaded whole and tightly
+        // packed, so that this works for any width without `UNPACK_ROW_LENGTH`, given the
+        // alignment of the format.
+        if !self.bytes.is_empty() {
+            let rows = if !allocated && texture.serial() == self.texture_serial.get() {
+                self.dirty_rect.get().map(|dirty_rect| (dirty_rect.origin.y, dirty_rect.max_y()))
+            } else {
+                Some((0, self.size.height))
+            };
+            if let Some((start_row, end_row)) = rows {
+                let stride = self.size.width as usize * self.format.bytes_per_pixel();
+                let bytes = &self.bytes[start_row as usize * stride..end_row as usize * stride];
+                let height = end_row - start_row;
+                match pbo_pool {
+                    Some(pool) => {
+                        pool.upload(target, start_row, self.size.width, height, format, bytes)
+                    }
+                    None => {
+                        gl::tex_sub_image_2d(gl,
+                                             target,
+                                             0,
+                                             0,
+                                             start_row,
+                                             self.size.width,
+                                             height,
+                                             format,
+                                             gl::UNSIGNED_BYTE,
+                                             bytes)
+                    }
+                }
+            }
+            self.texture_serial.set(texture.serial());
+            self.dirty_rect.set(None);
+        }
+        gl::pixel_store_i(gl, gl::UNPACK_ALIGNMENT, 4);
+        Ok(())
     }
 
-    #[cfg(target_os="android")]
-    pub fn bind_to_texture(&self, _: &NativeDisplay, _: &Texture) {
-        panic!("Binding a memory surface to a texture is not yet supported on Android.");
+    /// Returns the number of bytes holding the pixels of the whole surface.
+    fn byte_length(&self) -> usize {
+        self.size.width.max(0) as usize * self.size.height.max(0) as usize *
+            self.format.bytes_per_pixel()
     }
 
     /// This may only be called on the painting side.
-    pub fn upload(&mut self, _: &NativeDisplay, data: &[u8]) {
+    pub fn upload(&mut self, _: &NativeDisplay, data: &[u8]) -> Result<(), SurfaceError> {
+        if self.destroyed {
+            return Err(SurfaceError::InvalidState);
+        }
+        // GL reads the whole surface out of the bytes when binding them.
+        let byte_length = self.byte_length();
+        if data.len() < byte_length {
+            return Err(SurfaceError::SourceTooSmall);
+        }
         self.bytes.clear();
-        self.bytes.extend_from_slice(data);
+        self.bytes.extend_from_slice(&data[..byte_length]);
+        self.texture_serial.set(0);
+        Ok(())
+    }
+
+    /// This may only be called on the painting side. Parts of a surface that was never uploaded
+    /// to are left transparent black.
+    pub fn upload_rect(&mut self, _: &NativeDisplay, data: &[u8], rect: Rect<i32>, stride: i32)
+                       -> Result<(), SurfaceError> {
+        if self.destroyed {
+            return Err(SurfaceError::InvalidState);
+        }
+        let bytes_per_pixel = self.format.bytes_per_pixel();
+        try!(check_upload_rect(self.size, bytes_per_pixel, data, &rect, stride));
+        if rect.size.width == 0 || rect.size.height == 0 {
+            return Ok(());
+        }
+
+        let surface_stride = self.size.width as usize * bytes_per_pixel;
+        if self.bytes.is_empty() {
+            self.bytes = vec![0; surface_stride * self.size.height as usize];
+            self.texture_serial.set(0);
+        }
+        copy_rect(&mut self.bytes, surface_stride, bytes_per_pixel, data, &rect, stride);
+
+        let dirty_rect = match self.dirty_rect.get() {
+            Some(dirty_rect) => dirty_rect.union(&rect),
+            None => rect,
+        };
+        self.dirty_rect.set(Some(dirty_rect));
+        Ok(())
+    }
+
+    pub fn read_pixels(&self, _: &NativeDisplay) -> Option<Vec<u8>> {
+        self.bgra_pixels().map(Cow::into_owned)
+    }
+
+    /// Returns the pixels of this surface as `read_pixels` does, without copying them if they
+    /// are already in that format. Returns None if nothing was uploaded to the surface yet.
+    pub fn bgra_pixels(&self) -> Option<Cow<[u8]>> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+
+        let pixels = match self.format {
+            Format::ARGB32Format => Cow::Borrowed(&self.bytes[..]),
+            Format::RGB24Format => {
+                Cow::Owned(self.bytes.chunks(3).flat_map(|rgb| {
+                    vec![rgb[2], rgb[1], rgb[0], 0xff]
+                }).collect())
+            }
+            Format::A8Format => {
+                Cow::Owned(self.bytes.iter().flat_map(|&a| vec![0, 0, 0, a]).collect())
+            }
+        };
+        Some(pixels)
     }
 
     pub fn get_id(&self) -> isize {
         0
     }
 
-    pub fn destroy(&mut self, _: &NativeDisplay) {
+    pub fn destroy(&mut self, _: &NativeDisplay) -> Result<(), SurfaceError> {
+        if self.destroyed {
+            return Err(SurfaceError::InvalidState);
+        }
+        self.destroyed = true;
+        self.bytes = vec!();
+        Ok(())
     }
 
     pub fn mark_will_leak(&mut self) {
@@ -270,9 +941,545 @@ impl MemoryBufferNativeSurface {
     pub fn mark_wont_leak(&mut self) {
     }
 
+    pub fn will_leak(&self) -> bool {
+        false
+    }
+
     pub fn gl_rasterization_context(&mut self,
-                                    _: Arc<GLContext>)
-                                    -> Option<GLRasterizationContext> {
+                                    _: Arc<GLContext>,
+                                    _: ContextPriorityHint)
+                                    -> Result<GLRasterizationContext, SurfaceError> {
+        Err(SurfaceError::Unsupported)
+    }
+
+    pub fn granted_context_priority(&self) -> Option<ContextPriorityHint> {
         None
     }
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use euclid::point::Point2D;
+    use euclid::rect::Rect;
+    use euclid::size::Size2D;
+    use gltrace::{self as gl, GLenum, GLint, GlCall};
+    use rustc_serialize::json::{self, Json};
+    use std::cell::Cell;
+    use std::sync::Arc;
+    use super::{MemoryBufferNativeSurface, NativeDisplay, NativeSurface, NativeSurfaceAllocator};
+    use super::{RasterizationContextKey, RasterizationContextPool, SurfaceError};
+    use super::{check_upload_rect, copy_rect, dib_stride};
+    use test_util::{headless_display, record_gl_calls};
+    use texturegl::{Format, PboPool, Texture, TextureTarget};
+
+    thread_local!(static PLATFORM_ATTEMPTS: Cell<usize> = Cell::new(0));
+    thread_local!(static PLATFORM_FAILS: Cell<bool> = Cell::new(true));
+
+    fn flaky_new_platform(display: &NativeDisplay, size: Size2D<i32>)
+                          -> Result<NativeSurface, SurfaceError> {
+        PLATFORM_ATTEMPTS.with(|attempts| attempts.set(attempts.get() + 1));
+        if PLATFORM_FAILS.with(|fails| fails.get()) {
+            Err(SurfaceError::AllocationFailed)
+        } else {
+            NativeSurface::new_platform(display, size)
+        }
+    }
+
+    fn platform_attempts() -> usize {
+        PLATFORM_ATTEMPTS.with(|attempts| attempts.get())
+    }
+
+    #[test]
+    fn failed_allocations_fall_back_to_memory_and_retry_later() {
+        let display = headless_display();
+        let size = Size2D::new(64, 64);
+        let mut allocator = NativeSurfaceAllocator::new();
+        allocator.new_platform = flaky_new_platform;
+        allocator.retry_interval = 2;
+
+        let surface = allocator.allocate(&display, size);
+        assert!(surface.is_fallback());
+        assert!(allocator.is_degraded());
+        assert_eq!(allocator.fallback_count(), 1);
+        assert_eq!(platform_attempts(), 1);
+
+        // The platform isn't tried again until `retry_interval` allocations went to memory.
+        assert!(allocator.allocate(&display, size).is_fallback());
+        assert!(allocator.allocate(&display, size).is_fallback());
+        assert_eq!(platform_attempts(), 1);
+        assert_eq!(allocator.fallback_count(), 3);
+
+        PLATFORM_FAILS.with(|fails| fails.set(false));
+        assert!(!allocator.allocate(&display, size).is_fallback());
+        assert_eq!(platform_attempts(), 2);
+        assert!(!allocator.is_degraded());
+        assert_eq!(allocator.fallback_count(), 3);
+
+        assert!(!allocator.allocate(&display, size).is_fallback());
+        assert_eq!(platform_attempts(), 3);
+    }
+
+    #[test]
+    fn memory_surfaces_are_not_fallbacks() {
+        let surface = NativeSurface::new(&headless_display(), Size2D::new(16, 16));
+        assert!(surface.as_memory_buffer().is_some());
+        assert!(!surface.is_fallback());
+    }
+
+    #[test]
+    fn destroyed_memory_surfaces_refuse_every_operation() {
+        let gl = record_gl_calls();
+        let display = headless_display();
+        let mut surface = NativeSurface::new(&display, Size2D::new(2, 2));
+        assert!(surface.destroy(&display).is_ok());
+        let texture = Texture::new_with_gl(gl.clone(),
+                                           TextureTarget::TextureTarget2D,
+                                           Size2D::new(2, 2));
+        let rect = Rect::new(Point2D::new(0, 0), Size2D::new(1, 1));
+        let results = [surface.upload(&display, &[0; 16]),
+                       surface.upload_rect(&display, &[0; 4], rect, 4),
+                       surface.bind_to_texture(&display, &texture),
+                       surface.destroy(&display)];
+        for result in &results {
+            match *result {
+                Err(SurfaceError::InvalidState) => {}
+                ref result => panic!("Expected InvalidState, got {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn short_uploads_leave_memory_surfaces_alone() {
+        let display = headless_display();
+        let mut surface = NativeSurface::new(&display, Size2D::new(2, 2));
+        assert!(surface.upload(&display, &[1; 16]).is_ok());
+        match surface.upload(&display, &[2; 15]) {
+            Err(SurfaceError::SourceTooSmall) => {}
+            result => panic!("Expected SourceTooSmall, got {:?}", result),
+        }
+        assert_eq!(surface.read_pixels(&display), Some(vec![1; 16]));
+    }
+
+    #[test]
+    fn decoded_memory_surfaces_missing_bytes_are_not_bound() {
+        let gl = record_gl_calls();
+        let display = headless_display();
+        let mut surface = MemoryBufferNativeSurface::new(&display, Size2D::new(2, 2));
+        assert!(surface.upload(&display, &[1; 16]).is_ok());
+
+        // A painter sends fewer bytes than the size of the surface calls for.
+        let mut encoded = Json::from_str(&json::encode(&surface).unwrap()).unwrap();
+        if let Json::Object(ref mut fields) = encoded {
+            if let Some(&mut Json::Array(ref mut bytes)) = fields.get_mut("bytes") {
+                bytes.truncate(15);
+            }
+        }
+        let decoded: MemoryBufferNativeSurface = json::decode(&encoded.to_string()).unwrap();
+        let texture = Texture::new_with_gl(gl.clone(),
+                                           TextureTarget::TextureTarget2D,
+                                           Size2D::new(2, 2));
+        match decoded.bind_to_texture(&display, &texture) {
+            Err(SurfaceError::InvalidState) => {}
+            result => panic!("Expected InvalidState, got {:?}", result),
+        }
+    }
+
+    /// Uploads a 3x2 surface in the given format and binds it to a new texture, returning the
+    /// calls made while binding.
+    fn bind_odd_width_surface(format: Format) -> Vec<GlCall> {
+        let gl = record_gl_calls();
+        let display = headless_display();
+        let mut surface = NativeSurface::new_with_format(&display, Size2D::new(3, 2), format);
+        assert!(surface.upload(&display, &vec![0x80; 3 * 2 * format.bytes_per_pixel()]).is_ok());
+        let texture = Texture::new_with_gl(gl.clone(),
+                                           TextureTarget::TextureTarget2D,
+                                           Size2D::new(3, 2));
+        gl.take_calls();
+        assert!(surface.bind_to_texture(&display, &texture).is_ok());
+        gl.take_calls()
+    }
+
+    fn assert_uploaded_as(calls: &[GlCall],
+                          internal_format: GLenum,
+                          format: GLenum,
+                          alignment: GLint,
+                          byte_length: usize) {
+        let alignments: Vec<_> = calls.iter().filter_map(|call| match *call {
+            GlCall::PixelStorei(gl::UNPACK_ALIGNMENT, alignment) => Some(alignment),
+            _ => None,
+        }).collect();
+        // The default alignment is restored for the uploads of other code.
+        assert_eq!(alignments, vec![alignment, 4]);
+
+        let allocations: Vec<_> = calls.iter().filter_map(|call| match *call {
+            GlCall::TexImage2D { internal_format, width, height, format, .. } => {
+                Some((internal_format, width, height, format))
+            }
+            _ => None,
+        }).collect();
+        assert_eq!(allocations, vec![(internal_format as GLint, 3, 2, format)]);
+
+        let uploads: Vec<_> = calls.iter().filter_map(|call| match *call {
+            GlCall::TexSubImage2D { width, height, format, ref data, .. } => {
+                Some((width, height, format, data.len))
+            }
+            _ => None,
+        }).collect();
+        assert_eq!(uploads, vec![(3, 2, format, byte_length)]);
+    }
+
+    #[test]
+    fn rgb24_surfaces_are_uploaded_as_rgb_without_row_alignment() {
+        let calls = bind_odd_width_surface(Format::RGB24Format);
+        assert_uploaded_as(&calls, gl::RGB, gl::RGB, 1, 3 * 2 * 3);
+    }
+
+    #[test]
+    fn a8_surfaces_are_uploaded_as_alpha_without_row_alignment() {
+        let calls = bind_odd_width_surface(Format::A8Format);
+        assert_uploaded_as(&calls, gl::ALPHA, gl::ALPHA, 1, 3 * 2);
+    }
+
+    #[test]
+    fn argb32_surfaces_are_uploaded_as_bgra() {
+        let calls = bind_odd_width_surface(Format::ARGB32Format);
+        assert_uploaded_as(&calls, gl::RGBA, gl::BGRA, 4, 3 * 2 * 4);
+    }
+
+    #[test]
+    fn surfaces_in_other_formats_hold_the_bytes_of_that_format() {
+        let display = headless_display();
+        let mut surface = NativeSurface::new_with_format(&display, Size2D::new(3, 2),
+                                                         Format::A8Format);
+        match surface.upload(&display, &[0x80; 5]) {
+            Err(SurfaceError::SourceTooSmall) => {}
+            result => panic!("Expected SourceTooSmall, got {:?}", result),
+        }
+        assert!(surface.upload(&display, &[0x80; 6]).is_ok());
+        assert_eq!(surface.read_pixels(&display).unwrap().len(), 3 * 2 * 4);
+    }
+
+    #[test]
+    fn memory_surfaces_read_back_the_pixels_uploaded_to_them() {
+        let display = headless_display();
+        let mut surface = NativeSurface::new(&display, Size2D::new(2, 2));
+        assert_eq!(surface.read_pixels(&display), None);
+
+        let pixels: Vec<u8> = (0..16).collect();
+        assert!(surface.upload(&display, &pixels).is_ok());
+        assert_eq!(surface.read_pixels(&display), Some(pixels));
+    }
+
+    #[test]
+    fn memory_surfaces_in_other_formats_read_back_as_bgra() {
+        let display = headless_display();
+        let mut surface = NativeSurface::new_with_format(&display, Size2D::new(2, 1),
+                                                         Format::RGB24Format);
+        assert!(surface.upload(&display, &[1, 2, 3, 4, 5, 6]).is_ok());
+        assert_eq!(surface.read_pixels(&display), Some(vec![3, 2, 1, 0xff, 6, 5, 4, 0xff]));
+
+        let mut surface = NativeSurface::new_with_format(&display, Size2D::new(2, 1),
+                                                         Format::A8Format);
+        assert!(surface.upload(&display, &[0x40, 0x80]).is_ok());
+        assert_eq!(surface.read_pixels(&display), Some(vec![0, 0, 0, 0x40, 0, 0, 0, 0x80]));
+    }
+
+    #[test]
+    fn uploading_a_rect_only_changes_the_pixels_in_it() {
+        let display = headless_display();
+        let mut surface = NativeSurface::new_with_format(&display, Size2D::new(4, 3),
+                                                         Format::A8Format);
+        assert!(surface.upload(&display, &[0; 12]).is_ok());
+        // Two rows of two pixels, with a padding byte after each row.
+        let data = [1, 2, 0xff, 3, 4];
+        assert!(surface.upload_rect(&display, &data, Rect::new(Point2D::new(1, 1),
+                                                               Size2D::new(2, 2)), 3).is_ok());
+        let alphas: Vec<_> = surface.read_pixels(&display).unwrap().chunks(4).map(|pixel| {
+            pixel[3]
+        }).collect();
+        assert_eq!(alphas, vec!(0, 0, 0, 0,
+                                0, 1, 2, 0,
+                                0, 3, 4, 0));
+    }
+
+    #[test]
+    fn rects_outside_the_surface_are_rejected() {
+        let display = headless_display();
+        let mut surface = NativeSurface::new(&display, Size2D::new(4, 4));
+        let data = [0; 64];
+        for rect in &[Rect::new(Point2D::new(-1, 0), Size2D::new(2, 2)),
+                      Rect::new(Point2D::new(0, -1), Size2D::new(2, 2)),
+                      Rect::new(Point2D::new(3, 0), Size2D::new(2, 2)),
+                      Rect::new(Point2D::new(0, 3), Size2D::new(2, 2)),
+                      Rect::new(Point2D::new(0, 0), Size2D::new(-1, 2))] {
+            match surface.upload_rect(&display, &data, *rect, 16) {
+                Err(SurfaceError::RectOutOfBounds) => {}
+                result => panic!("Expected RectOutOfBounds for {:?}, got {:?}", rect, result),
+            }
+        }
+
+        // Rows must fit in the stride, and the last row in the data.
+        let rect = Rect::new(Point2D::new(0, 0), Size2D::new(2, 2));
+        for &(length, stride) in &[(64, 4), (11, 8)] {
+            match surface.upload_rect(&display, &data[..length], rect, stride) {
+                Err(SurfaceError::SourceTooSmall) => {}
+                result => panic!("Expected SourceTooSmall, got {:?}", result),
+            }
+        }
+        assert!(surface.upload_rect(&display, &data[..16], rect, 8).is_ok());
+    }
+
+    #[test]
+    fn only_the_changed_rows_are_uploaded_to_the_same_texture_again() {
+        let gl = record_gl_calls();
+        let display = headless_display();
+        let mut surface = NativeSurface::new(&display, Size2D::new(4, 4));
+        assert!(surface.upload(&display, &[0; 64]).is_ok());
+        let texture = Texture::new_with_gl(gl.clone(),
+                                           TextureTarget::TextureTarget2D,
+                                           Size2D::new(4, 4));
+        assert!(surface.bind_to_texture(&display, &texture).is_ok());
+        gl.take_calls();
+
+        let rect = Rect::new(Point2D::new(1, 1), Size2D::new(1, 2));
+        assert!(surface.upload_rect(&display, &[0xff; 8], rect, 4).is_ok());
+        assert!(surface.bind_to_texture(&display, &texture).is_ok());
+        let uploads: Vec<_> = gl.take_calls().into_iter().filter_map(|call| match call {
+            GlCall::TexSubImage2D { yoffset, width, height, data, .. } => {
+                Some((yoffset, width, height, data.len))
+            }
+            GlCall::TexImage2D { .. } => panic!("The storage of the texture was reallocated"),
+            _ => None,
+        }).collect();
+        // Whole rows are uploaded, so that no row length needs to be set.
+        assert_eq!(uploads, vec!((1, 4, 2, 4 * 2 * 4)));
+
+        // Nothing changed since.
+        assert!(surface.bind_to_texture(&display, &texture).is_ok());
+        assert!(!gl.take_calls().iter().any(|call| match *call {
+            GlCall::TexSubImage2D { .. } => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn texture_storage_is_allocated_once_for_surfaces_of_the_same_size() {
+        let gl = record_gl_calls();
+        let display = headless_display();
+        let mut surface = NativeSurface::new(&display, Size2D::new(4, 4));
+        let texture = Texture::new_with_gl(gl.clone(),
+                                           TextureTarget::TextureTarget2D,
+                                           Size2D::new(4, 4));
+        gl.take_calls();
+        let bind_with = |surface: &mut NativeSurface, byte: u8| {
+            assert!(surface.upload(&display, &[byte; 64]).is_ok());
+            assert!(surface.bind_to_texture(&display, &texture).is_ok());
+        };
+        let allocations_and_uploads = |calls: Vec<GlCall>| {
+            calls.into_iter().filter_map(|call| match call {
+                GlCall::TexImage2D { width, height, data, .. } => {
+                    // Storage is allocated without any pixels.
+                    assert!(data.is_none());
+                    Some((true, width, height))
+                }
+                GlCall::TexSubImage2D { width, height, data, .. } => {
+                    assert_eq!(data.len, (width * height * 4) as usize);
+                    Some((false, width, height))
+                }
+                _ => None,
+            }).collect::<Vec<_>>()
+        };
+
+        // Binding the same surface twice allocates storage once and uploads the pixels twice.
+        bind_with(&mut surface, 0x10);
+        bind_with(&mut surface, 0x20);
+        assert_eq!(allocations_and_uploads(gl.take_calls()),
+                   vec!((true, 4, 4), (false, 4, 4), (false, 4, 4)));
+
+        // So does another surface of the same size, but not one of another size.
+        bind_with(&mut NativeSurface::new(&display, Size2D::new(4, 4)), 0x30);
+        assert_eq!(allocations_and_uploads(gl.take_calls()), vec!((false, 4, 4)));
+        let mut larger = NativeSurface::new(&display, Size2D::new(8, 4));
+        assert!(larger.upload(&display, &[0x40; 128]).is_ok());
+        assert!(larger.bind_to_texture(&display, &texture).is_ok());
+        assert_eq!(allocations_and_uploads(gl.take_calls()),
+                   vec!((true, 8, 4), (false, 8, 4)));
+    }
+
+    #[test]
+    fn pbo_uploads_rotate_through_the_buffers_of_the_pool() {
+        let gl = record_gl_calls();
+        let display = headless_display();
+        let mut pool = PboPool::new(gl.clone(), 2);
+        let buffers = match gl.take_calls()[..] {
+            [GlCall::GenBuffers(ref buffers)] => buffers.clone(),
+            ref calls => panic!("Expected the buffers to be generated, got {:?}", calls),
+        };
+        assert_eq!(pool.len(), 2);
+
+        let mut uploaded_buffers = vec!();
+        for byte in 1..4 {
+            let mut surface = MemoryBufferNativeSurface::new(&display, Size2D::new(4, 2));
+            assert!(surface.upload(&display, &[byte; 32]).is_ok());
+            let texture = Texture::new_with_gl(gl.clone(),
+                                               TextureTarget::TextureTarget2D,
+                                               Size2D::new(4, 2));
+            gl.take_calls();
+            assert!(surface.bind_to_texture_via_pbo(&mut pool, &texture).is_ok());
+
+            // The pixels are copied into the buffer and uploaded from its start, and the buffer
+            // is unbound again for the uploads of other code.
+            let calls: Vec<_> = gl.take_calls().into_iter().filter(|call| match *call {
+                GlCall::BindBuffer(..) |
+                GlCall::BufferDataUninitialized(..) |
+                GlCall::BufferSubData(..) |
+                GlCall::TexSubImage2D { .. } |
+                GlCall::TexSubImage2DPbo { .. } => true,
+                _ => false,
+            }).collect();
+            let buffer = match calls[0] {
+                GlCall::BindBuffer(gl::PIXEL_UNPACK_BUFFER, buffer) => buffer,
+                ref call => panic!("Expected a buffer to be bound, got {:?}", call),
+            };
+            assert_eq!(calls[1],
+                       GlCall::BufferDataUninitialized(gl::PIXEL_UNPACK_BUFFER,
+                                                       32,
+                                                       gl::STREAM_DRAW));
+            match calls[2] {
+                GlCall::BufferSubData(gl::PIXEL_UNPACK_BUFFER, 0, ref data) => {
+                    assert_eq!(data.len, 32)
+                }
+                ref call => panic!("Expected the pixels to be copied, got {:?}", call),
+            }
+            match calls[3] {
+                GlCall::TexSubImage2DPbo { yoffset: 0, width: 4, height: 2, offset: 0, .. } => {}
+                ref call => panic!("Expected an upload from the buffer, got {:?}", call),
+            }
+            assert_eq!(calls[4], GlCall::BindBuffer(gl::PIXEL_UNPACK_BUFFER, 0));
+            assert_eq!(calls.len(), 5);
+            uploaded_buffers.push(buffer);
+        }
+        assert_eq!(uploaded_buffers, vec!(buffers[0], buffers[1], buffers[0]));
+
+        pool.destroy();
+        assert_eq!(gl.take_calls(), vec!(GlCall::DeleteBuffers(buffers)));
+    }
+
+    #[test]
+    fn dib_rows_are_padded_to_four_bytes() {
+        for &(width, bits_per_pixel, stride) in &[(0, 32, 0), (1, 32, 4), (5, 32, 20),
+                                                  (1, 24, 4), (3, 24, 12), (5, 24, 16),
+                                                  (1, 8, 4), (4, 8, 4), (5, 8, 8),
+                                                  (7, 8, 8), (1, 1, 4), (33, 1, 8)] {
+            assert_eq!(dib_stride(width, bits_per_pixel), stride,
+                       "width {} at {} bits per pixel", width, bits_per_pixel);
+        }
+    }
+
+    #[test]
+    fn rects_are_copied_into_padded_dib_rows() {
+        // A 3x2 24-bit DIB has 9 bytes of pixels and 3 of padding in each row.
+        let size = Size2D::new(3, 2);
+        let stride = dib_stride(size.width, 24);
+        let mut bits = vec![0; stride * size.height as usize];
+        let data: Vec<u8> = (1..13).collect();
+        let rect = Rect::new(Point2D::new(1, 0), Size2D::new(2, 2));
+        assert!(check_upload_rect(size, 3, &data, &rect, 6).is_ok());
+        copy_rect(&mut bits, stride, 3, &data, &rect, 6);
+        assert_eq!(bits, vec![0, 0, 0, 1, 2, 3, 4, 5, 6, 0, 0, 0,
+                              0, 0, 0, 7, 8, 9, 10, 11, 12, 0, 0, 0]);
+    }
+
+    #[test]
+    fn surfaces_larger_than_the_maximum_texture_size_are_not_bound() {
+        let gl = record_gl_calls();
+        gl.set_integer(gl::MAX_TEXTURE_SIZE, 4);
+        let display = headless_display();
+        let texture = Texture::new_with_gl(gl.clone(),
+                                           TextureTarget::TextureTarget2D,
+                                           Size2D::new(8, 4));
+        let surface = NativeSurface::new(&display, Size2D::new(8, 4));
+        gl.take_calls();
+        match surface.bind_to_texture(&display, &texture) {
+            Err(SurfaceError::Unsupported) => {}
+            result => panic!("Expected Unsupported, got {:?}", result),
+        }
+        assert!(!gl.take_calls().iter().any(|call| match *call {
+            GlCall::TexImage2D { .. } | GlCall::TexSubImage2D { .. } => true,
+            _ => false,
+        }));
+
+        gl.set_integer(gl::MAX_TEXTURE_SIZE, 8);
+        assert!(surface.bind_to_texture(&display, &texture).is_ok());
+    }
+
+    fn key(surface_id: isize, width: i32) -> RasterizationContextKey {
+        RasterizationContextKey {
+            surface_id: surface_id,
+            size: Size2D::new(width, 256),
+        }
+    }
+
+    /// Gets the context for `key` from the pool, creating it as the given number if needed.
+    fn get(pool: &mut RasterizationContextPool<usize>, key: RasterizationContextKey, new: usize)
+           -> usize {
+        *pool.get_or_create(key, || Ok(Arc::new(new))).unwrap()
+    }
+
+    #[test]
+    fn pooled_contexts_are_keyed_by_surface_and_size() {
+        let mut pool = RasterizationContextPool::new();
+        assert_eq!(get(&mut pool, key(1, 256), 1), 1);
+        assert_eq!(get(&mut pool, key(2, 256), 2), 2);
+        assert_eq!(get(&mut pool, key(1, 256), 3), 1);
+        assert_eq!((pool.hit_count(), pool.miss_count(), pool.len()), (1, 2, 2));
+
+        // A resized surface gets a new context, and its context for the old size is dropped.
+        assert_eq!(get(&mut pool, key(1, 128), 4), 4);
+        assert!(!pool.contains(&key(1, 256)));
+        assert!(pool.contains(&key(1, 128)) && pool.contains(&key(2, 256)));
+        assert_eq!((pool.hit_count(), pool.miss_count(), pool.len()), (1, 3, 2));
+
+        // Contexts that fail to be created are counted as misses but not pooled.
+        match pool.get_or_create(key(3, 256), || Err(SurfaceError::Unsupported)) {
+            Err(SurfaceError::Unsupported) => {}
+            result => panic!("Expected Unsupported, got {:?}", result),
+        }
+        assert_eq!((pool.miss_count(), pool.len()), (4, 2));
+        assert!(pool.contains(&key(1, 128)));
+    }
+
+    #[test]
+    fn the_least_recently_used_pooled_context_is_evicted() {
+        let mut pool = RasterizationContextPool::new();
+        pool.set_capacity(2);
+        get(&mut pool, key(1, 256), 1);
+        get(&mut pool, key(2, 256), 2);
+        get(&mut pool, key(1, 256), 0);
+        get(&mut pool, key(3, 256), 3);
+        assert!(pool.contains(&key(1, 256)) && pool.contains(&key(3, 256)));
+        assert!(!pool.contains(&key(2, 256)));
+
+        // Lowering the capacity evicts too, and a pool without capacity holds nothing.
+        pool.set_capacity(1);
+        assert!(pool.contains(&key(3, 256)) && pool.len() == 1);
+        pool.set_capacity(0);
+        assert_eq!(get(&mut pool, key(4, 256), 4), 4);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn pooled_contexts_of_destroyed_surfaces_are_dropped() {
+        let mut pool = RasterizationContextPool::new();
+        get(&mut pool, key(1, 256), 1);
+        get(&mut pool, key(2, 256), 2);
+        pool.invalidate_surface(1);
+        assert!(!pool.contains(&key(1, 256)) && pool.contains(&key(2, 256)));
+
+        // A new surface reusing the ID gets a new context.
+        assert_eq!(get(&mut pool, key(1, 256), 3), 3);
+        pool.clear();
+        assert!(pool.is_empty());
+        assert_eq!(pool.miss_count(), 3);
+    }
+}
@@ -1,9 +1,94 @@
-use skia::gl_context::PlatformDisplayData;
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Implementation of cross-process surfaces for Windows. This uses DIB sections whose pixels live
+//! in a file mapping, which other processes can map after duplicating its handle.
+
+#![allow(non_snake_case)]
+
+use platform::surface::{ContextPriorityHint, SurfaceError, check_upload_rect, copy_rect,
+                        dib_stride};
+use texturegl::Texture;
+
+use euclid::point::Point2D;
+use euclid::rect::Rect;
+use euclid::size::Size2D;
+use gltrace as gl;
+use libc::{c_int, c_uint, c_void};
+use skia::gl_context::{GLContext, PlatformDisplayData};
+use skia::gl_rasterization_context::GLRasterizationContext;
+use std::cmp;
+use std::mem;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+
+type HANDLE = *mut c_void;
+type HBITMAP = *mut c_void;
+
+const PAGE_READWRITE: u32 = 0x04;
+const BI_RGB: u32 = 0;
+const DIB_RGB_COLORS: c_uint = 0;
+
+#[repr(C)]
+struct BITMAPINFOHEADER {
+    biSize: u32,
+    biWidth: i32,
+    biHeight: i32,
+    biPlanes: u16,
+    biBitCount: u16,
+    biCompression: u32,
+    biSizeImage: u32,
+    biXPelsPerMeter: i32,
+    biYPelsPerMeter: i32,
+    biClrUsed: u32,
+    biClrImportant: u32,
+}
+
+#[repr(C)]
+struct BITMAPINFO {
+    bmiHeader: BITMAPINFOHEADER,
+    bmiColors: [u32; 1],
+}
+
+#[link(name = "gdi32")]
+extern "system" {
+    fn CreateDIBSection(hdc: HANDLE,
+                        pbmi: *const BITMAPINFO,
+                        usage: c_uint,
+                        ppvBits: *mut *mut c_void,
+                        hSection: HANDLE,
+                        offset: u32)
+                        -> HBITMAP;
+    fn DeleteObject(ho: HANDLE) -> c_int;
+    fn GdiFlush() -> c_int;
+}
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn CreateFileMappingW(hFile: HANDLE,
+                          lpFileMappingAttributes: *mut c_void,
+                          flProtect: u32,
+                          dwMaximumSizeHigh: u32,
+                          dwMaximumSizeLow: u32,
+                          lpName: *const u16)
+                          -> HANDLE;
+    fn CloseHandle(hObject: HANDLE) -> c_int;
+}
+
+fn invalid_handle_value() -> HANDLE {
+    -1isize as HANDLE
+}
 
 #[derive(Copy, Clone)]
 pub struct NativeDisplay;
 
-#[cfg(target_os="windows")]
 impl NativeDisplay {
     pub fn new() -> NativeDisplay {
         NativeDisplay
@@ -14,3 +99,206 @@ impl NativeDisplay {
     }
 }
 
+/// A top-down, 32-bit BGRA DIB section. Its pixels live in an unnamed file mapping, whose handle
+/// serves as the ID of the surface.
+pub struct GdiDibNativeSurface {
+    /// The file mapping holding the pixels.
+    section: HANDLE,
+
+    /// The bitmap mapping the section into this process.
+    bitmap: HBITMAP,
+
+    /// The pixels of the bitmap, top row first.
+    bits: *mut u8,
+
+    /// Whether this surface will leak if it is dropped without being destroyed.
+    will_leak: bool,
+
+    /// The size of this surface.
+    pub size: Size2D<i32>,
+}
+
+unsafe impl Send for GdiDibNativeSurface {}
+
+impl Drop for GdiDibNativeSurface {
+    fn drop(&mut self) {
+        if self.will_leak {
+            panic!("You should have disposed of the DIB section properly with destroy()! This \
+                   DIB section will leak!");
+        }
+    }
+}
+
+impl GdiDibNativeSurface {
+    pub fn new(_: &NativeDisplay, size: Size2D<i32>)
+               -> Result<GdiDibNativeSurface, SurfaceError> {
+        // Neither file mappings nor bitmaps can be empty.
+        let byte_length = dib_stride(size.width, 32) * cmp::max(size.height, 0) as usize;
+        if byte_length == 0 || byte_length > u32::max_value() as usize {
+            return Err(SurfaceError::AllocationFailed);
+        }
+
+        unsafe {
+            let section = CreateFileMappingW(invalid_handle_value(),
+                                             ptr::null_mut(),
+                                             PAGE_READWRITE,
+                                             0,
+                                             byte_length as u32,
+                                             ptr::null());
+            if section.is_null() {
+                return Err(SurfaceError::AllocationFailed);
+            }
+
+            // A negative height makes the DIB top-down, like the pixels handed to `upload`.
+            let info = BITMAPINFO {
+                bmiHeader: BITMAPINFOHEADER {
+                    biSize: mem::size_of::<BITMAPINFOHEADER>() as u32,
+                    biWidth: size.width,
+                    biHeight: -size.height,
+                    biPlanes: 1,
+                    biBitCount: 32,
+                    biCompression: BI_RGB,
+                    biSizeImage: 0,
+                    biXPelsPerMeter: 0,
+                    biYPelsPerMeter: 0,
+                    biClrUsed: 0,
+                    biClrImportant: 0,
+                },
+                bmiColors: [0],
+            };
+            let mut bits = ptr::null_mut();
+            let bitmap = CreateDIBSection(ptr::null_mut(),
+                                          &info,
+                                          DIB_RGB_COLORS,
+                                          &mut bits,
+                                          section,
+                                          0);
+            if bitmap.is_null() {
+                CloseHandle(section);
+                return Err(SurfaceError::AllocationFailed);
+            }
+
+            Ok(GdiDibNativeSurface {
+                section: section,
+                bitmap: bitmap,
+                bits: bits as *mut u8,
+                will_leak: true,
+                size: size,
+            })
+        }
+    }
+
+    fn stride(&self) -> usize {
+        dib_stride(self.size.width, 32)
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self.bits, self.stride() * self.size.height as usize)
+        }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self.bits, self.stride() * self.size.height as usize)
+        }
+    }
+
+    /// Returns an error if the surface was destroyed.
+    fn check_bits(&self) -> Result<(), SurfaceError> {
+        if self.bits.is_null() {
+            Err(SurfaceError::InvalidState)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// This may only be called on the compositor side.
+    pub fn bind_to_texture(&self, _: &NativeDisplay, texture: &Texture)
+                           -> Result<(), SurfaceError> {
+        try!(self.check_bits());
+        let _bound = texture.bind();
+        texture.forget_storage();
+        unsafe {
+            GdiFlush();
+        }
+        gl::tex_image_2d(texture.gl(),
+                         gl::TEXTURE_2D,
+                         0,
+                         gl::RGBA as i32,
+                         self.size.width,
+                         self.size.height,
+                         0,
+                         gl::BGRA,
+                         gl::UNSIGNED_BYTE,
+                         Some(self.bytes()));
+        Ok(())
+    }
+
+    /// This may only be called on the painting side. `data` holds the rows of the surface
+    /// without padding.
+    pub fn upload(&mut self, display: &NativeDisplay, data: &[u8]) -> Result<(), SurfaceError> {
+        let rect = Rect::new(Point2D::zero(), self.size);
+        let stride = self.size.width * 4;
+        self.upload_rect(display, data, rect, stride)
+    }
+
+    pub fn upload_rect(&mut self, _: &NativeDisplay, data: &[u8], rect: Rect<i32>, stride: i32)
+                       -> Result<(), SurfaceError> {
+        try!(self.check_bits());
+        try!(check_upload_rect(self.size, 4, data, &rect, stride));
+        let surface_stride = self.stride();
+        copy_rect(self.bytes_mut(), surface_stride, 4, data, &rect, stride);
+        Ok(())
+    }
+
+    pub fn read_pixels(&self, _: &NativeDisplay) -> Option<Vec<u8>> {
+        if self.bits.is_null() {
+            return None;
+        }
+        unsafe {
+            GdiFlush();
+        }
+        Some(self.bytes().to_vec())
+    }
+
+    pub fn get_id(&self) -> isize {
+        self.section as isize
+    }
+
+    pub fn destroy(&mut self, _: &NativeDisplay) -> Result<(), SurfaceError> {
+        try!(self.check_bits());
+        unsafe {
+            DeleteObject(self.bitmap);
+            CloseHandle(self.section);
+        }
+        self.bitmap = ptr::null_mut();
+        self.section = ptr::null_mut();
+        self.bits = ptr::null_mut();
+        self.mark_wont_leak();
+        Ok(())
+    }
+
+    pub fn mark_will_leak(&mut self) {
+        self.will_leak = true
+    }
+
+    pub fn mark_wont_leak(&mut self) {
+        self.will_leak = false
+    }
+
+    pub fn will_leak(&self) -> bool {
+        self.will_leak
+    }
+
+    pub fn gl_rasterization_context(&mut self,
+                                    _: Arc<GLContext>,
+                                    _: ContextPriorityHint)
+                                    -> Result<GLRasterizationContext, SurfaceError> {
+        Err(SurfaceError::Unsupported)
+    }
+
+    pub fn granted_context_priority(&self) -> Option<ContextPriorityHint> {
+        None
+    }
+}
@@ -0,0 +1,779 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Building and drawing 3D rendering contexts.
+//!
+//! A `RenderContext3D` is a flattened list of the layers that are drawn together, sorted by the
+//! z of their screen rect and then by paint order, along with the clip rect and rounded clip
+//! inherited from masking ancestors. Layers that establish a new 3D context get a nested
+//! `RenderContext3D`. The layer tree must have an up-to-date transform state when a context is
+//! built.
+//!
+//! Drawing a context clears the depth buffer first, so that its layers are only depth-tested
+//! against each other. Depth testing must already be enabled. A context built for a flat layer
+//! tree, in which no layer has a 3D transform or establishes a nested context, keeps its layers
+//! in paint order and skips the clear. So does a context without nested contexts whose layers,
+//! once sorted, never cover a part of an earlier layer that is nearer to the viewer; its layers
+//! are drawn with depth testing disabled, which shows the same thing.
+//!
+//! Both paths cull the layers that are entirely hidden behind opaque layers drawn after them,
+//! visiting the layers from front to back and accumulating the screen rects of opaque layers, as
+//! given by `Layer::opaque_screen_rect`. Only layers with an axis-aligned 2D transform hide or
+//! are hidden, and layers drawn as a nested 3D context do neither, so the region never has to
+//! account for depth. Culled layers are marked with `Layer::culled`, which `render_layer` checks.
+
+use frame::FrameInfo;
+use geometry::ScreenPixel;
+use layers::{Layer, RoundedClip};
+use super::draw::{self, RenderContext};
+use super::statistics::{self, ContextStatistics};
+use util::ScreenRect;
+
+use euclid::Matrix4D;
+use euclid::rect::TypedRect;
+use gltrace as gl;
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+struct RenderContextChild<T> {
+    layer: Option<Rc<Layer<T>>>,
+    context: Option<RenderContext3D<T>>,
+    paint_order: usize,
+    z_center: f32,
+
+    /// The stacking index of the layer that added this child, which breaks ties in depth.
+    stacking_index: u64,
+
+    /// The screen rect of the layer, or None if the layer is entirely clipped.
+    screen_rect: Option<ScreenRect>,
+}
+
+pub struct RenderContext3D<T>{
+    children: Vec<RenderContextChild<T>>,
+    clip_rect: Option<TypedRect<f32, ScreenPixel>>,
+    rounded_clip: Option<RoundedClip>,
+    is_flat: bool,
+
+    /// Whether the layers of this context must be drawn with depth testing, after clearing the
+    /// depth buffer, to show correctly.
+    needs_depth_buffer: bool,
+}
+
+impl<T> RenderContext3D<T> {
+    pub fn new(layer: Rc<Layer<T>>) -> RenderContext3D<T> {
+        let mut render_context = RenderContext3D {
+            children: vec!(),
+            clip_rect: None,
+            rounded_clip: None,
+            is_flat: false,
+            needs_depth_buffer: true,
+        };
+        render_context.rebuild_into(layer);
+        render_context
+    }
+
+    /// Rebuilds this render context for the given root layer, reusing the allocation of the
+    /// previous frame's list of children.
+    pub fn rebuild_into(&mut self, layer: Rc<Layer<T>>) {
+        self.children.clear();
+        self.clip_rect = RenderContext3D::calculate_context_clip(layer.clone(), None);
+        self.rounded_clip = layer.mask_rounded_clip(None);
+        self.is_flat = layer.subtree_is_flat();
+        layer.build(self);
+
+        // All layers of a flat tree are at z = 0, so sorting would keep the paint order anyway.
+        if !self.is_flat {
+            self.sort_children();
+        }
+        self.needs_depth_buffer = !self.is_flat && self.layers_intersect();
+        self.cull_occluded_layers();
+    }
+
+    /// Returns true if this context was built for a flat layer tree, so that its layers are drawn
+    /// in paint order without clearing the depth buffer.
+    pub fn is_flat(&self) -> bool {
+        self.is_flat
+    }
+
+    /// Returns true if this context has to clear the depth buffer and depth-test its layers when
+    /// it is drawn. This is false for flat contexts, and for contexts without nested contexts
+    /// whose layers don't intersect in the order they are drawn.
+    pub fn needs_depth_buffer(&self) -> bool {
+        self.needs_depth_buffer
+    }
+
+    /// Returns the stacking indices of the layers of this context and its nested contexts, in the
+    /// order in which they are drawn. This is for checking that the order is stable from one
+    /// frame to the next.
+    pub fn debug_render_order(&self) -> Vec<u64> {
+        let mut order = vec!();
+        self.for_each_layer(&mut |layer: &Rc<Layer<T>>, _, _| {
+            order.push(*layer.stacking_index.borrow())
+        });
+        order
+    }
+
+    /// Calls `f` with each layer of this context and its nested contexts in the order in which
+    /// they are drawn, along with the clip rect and rounded clip they are drawn with. Only the
+    /// clip rects of nested contexts are accumulated; the world clip rect of each layer is left
+    /// to the caller, as in `Layer::world_clip_rect`.
+    pub fn for_each_layer<F>(&self, f: &mut F)
+                             where F: FnMut(&Rc<Layer<T>>,
+                                            Option<TypedRect<f32, ScreenPixel>>,
+                                            Option<&RoundedClip>) {
+        for child in &self.children {
+            if let Some(ref layer) = child.layer {
+                f(layer, self.clip_rect, self.rounded_clip.as_ref());
+            }
+            if let Some(ref context) = child.context {
+                context.for_each_layer(f);
+            }
+        }
+    }
+
+    fn build_child(layer: Rc<Layer<T>>,
+                   parent_clip_rect: Option<TypedRect<f32, ScreenPixel>>,
+                   parent_rounded_clip: Option<RoundedClip>)
+                   -> Option<RenderContext3D<T>> {
+        let clip_rect = RenderContext3D::calculate_context_clip(layer.clone(), parent_clip_rect);
+        if let Some(ref clip_rect) = clip_rect {
+            if clip_rect.is_empty() {
+                return None;
+            }
+        }
+
+        let mut render_context = RenderContext3D {
+            children: vec!(),
+            clip_rect: clip_rect,
+            rounded_clip: layer.mask_rounded_clip(parent_rounded_clip),
+            is_flat: false,
+            needs_depth_buffer: true,
+        };
+
+        for child in layer.children().iter() {
+            child.build(&mut render_context);
+        }
+
+        render_context.sort_children();
+        render_context.needs_depth_buffer = render_context.layers_intersect();
+        render_context.cull_occluded_layers();
+        Some(render_context)
+    }
+
+    /// Returns true if, drawn in their sorted order without depth testing, some layer of this
+    /// context would cover a part of an earlier layer that is nearer to the viewer, or if this
+    /// context has nested contexts, whose extent isn't known. The children must be sorted.
+    fn layers_intersect(&self) -> bool {
+        for (index, child) in self.children.iter().enumerate() {
+            if child.context.is_some() {
+                return true;
+            }
+            let front = match child.screen_rect {
+                Some(ref screen_rect) if child.layer.is_some() => screen_rect,
+                _ => continue,
+            };
+            for earlier in &self.children[..index] {
+                if let Some(ref behind) = earlier.screen_rect {
+                    if earlier.layer.is_some() && !draws_over_correctly(behind, front) {
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    fn sort_children(&mut self) {
+        // TODO(gw): This is basically what FF does, which breaks badly
+        // when there are intersecting polygons. Need to split polygons
+        // to handle this case correctly (Blink uses a BSP tree).
+        //
+        // Ties in depth are broken by the stacking index, which unlike the paint order doesn't
+        // depend on the order in which the tree was built.
+        self.children.sort_by(|a, b| {
+            if a.z_center < b.z_center {
+                Ordering::Less
+            } else if a.z_center > b.z_center {
+                Ordering::Greater
+            } else if a.stacking_index != b.stacking_index {
+                a.stacking_index.cmp(&b.stacking_index)
+            } else if a.paint_order < b.paint_order {
+                Ordering::Less
+            } else if a.paint_order > b.paint_order {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        });
+    }
+
+    /// Marks the layers of this context that are hidden behind opaque layers drawn after them as
+    /// culled. The children must be sorted. Layers with axis-aligned 2D transforms are all at
+    /// z = 0, so those drawn later cover the earlier ones with or without depth testing.
+    fn cull_occluded_layers(&self) {
+        let mut opaque_region = vec!();
+        for child in self.children.iter().rev() {
+            if let Some(ref layer) = child.layer {
+                cull_occluded_layer(layer,
+                                    child.context.is_some(),
+                                    self.clip_rect,
+                                    self.rounded_clip,
+                                    &mut opaque_region);
+            }
+        }
+    }
+
+    fn calculate_context_clip(layer: Rc<Layer<T>>,
+                              parent_clip_rect: Option<TypedRect<f32, ScreenPixel>>)
+                              -> Option<TypedRect<f32, ScreenPixel>> {
+        layer.mask_clip_rect(parent_clip_rect)
+    }
+
+    fn add_child(&mut self,
+                 layer: Option<Rc<Layer<T>>>,
+                 child_context: Option<RenderContext3D<T>>,
+                 stacking_index: u64,
+                 screen_rect: Option<ScreenRect>) {
+        let paint_order = self.children.len();
+        self.children.push(RenderContextChild {
+            layer: layer,
+            context: child_context,
+            z_center: screen_rect.map_or(0., |screen_rect| screen_rect.z_center),
+            paint_order: paint_order,
+            stacking_index: stacking_index,
+            screen_rect: screen_rect,
+        });
+    }
+}
+
+/// Returns true if drawing `front` after `behind` without depth testing shows what the depth
+/// buffer would: either they share no screen area, or `front` is nowhere farther from the viewer
+/// than `behind`. Rects that only touch share no area. As with `GL_LEQUAL`, equal depths are
+/// drawn in order.
+fn draws_over_correctly(behind: &ScreenRect, front: &ScreenRect) -> bool {
+    !behind.rect.intersects(&front.rect) || behind.z_max <= front.z_min
+}
+
+/// Marks a layer as culled if the opaque region of the layers drawn after it hides it entirely,
+/// and otherwise adds the part of the screen it hides itself to the region. Layers must be
+/// visited from front to back. See `Layer::occlude`.
+fn cull_occluded_layer<T>(layer: &Layer<T>,
+                          draws_nested_context: bool,
+                          clip_rect: Option<TypedRect<f32, ScreenPixel>>,
+                          rounded_clip: Option<RoundedClip>,
+                          opaque_region: &mut Vec<TypedRect<f32, ScreenPixel>>) {
+    layer.culled.set(layer.occlude(draws_nested_context, clip_rect, rounded_clip, opaque_region));
+}
+
+pub trait RenderContext3DBuilder<T> {
+    fn build(&self, current_context: &mut RenderContext3D<T>);
+}
+
+impl<T> RenderContext3DBuilder<T> for Rc<Layer<T>> {
+    fn build(&self, current_context: &mut RenderContext3D<T>) {
+        if self.subtree_is_hidden() {
+            return;
+        }
+
+        let screen_rect = self.transform_state.borrow().screen_rect;
+        let layer = match screen_rect {
+            Some(_) => Some(self.clone()),
+            None => None, // Layer is entirely clipped.
+        };
+
+        // A hidden layer still places its descendants, but isn't drawn itself.
+        let drawn_layer = if self.is_hidden() { None } else { layer.clone() };
+
+        if self.establishes_render_context() {
+            let child_context = RenderContext3D::build_child(self.clone(),
+                                                             current_context.clip_rect,
+                                                             current_context.rounded_clip);
+            if child_context.is_some() {
+                current_context.add_child(drawn_layer,
+                                          child_context,
+                                          *self.stacking_index.borrow(),
+                                          screen_rect);
+                return;
+            }
+        };
+
+        // If we are completely clipped out, don't add anything to this context.
+        if layer.is_none() {
+            return;
+        }
+
+        if drawn_layer.is_some() {
+            current_context.add_child(drawn_layer,
+                                      None,
+                                      *self.stacking_index.borrow(),
+                                      screen_rect);
+        }
+
+        for child in self.children().iter() {
+            child.build(current_context);
+        }
+    }
+}
+
+/// Draws a layer tree without 3D transforms in paint order, without building a
+/// `RenderContext3D`. Clipping is the same as for the general path: layers are clipped by the
+/// nearest enclosing layer that establishes a 3D context, and by the root. This is only correct
+/// if no layer in the tree has a 3D transform, and doesn't need the depth buffer.
+pub fn render_2d_layer_tree<T>(render_context: &RenderContext,
+                               root: &Rc<Layer<T>>,
+                               transform: &Matrix4D<f32>,
+                               projection: &Matrix4D<f32>,
+                               frame: &FrameInfo) {
+    let clip_rect = RenderContext3D::calculate_context_clip(root.clone(), None);
+    let rounded_clip = root.mask_rounded_clip(None);
+    cull_occluded_2d_layer(root, clip_rect, rounded_clip, &mut vec!());
+    for_each_2d_layer(root, &mut |layer: &Rc<Layer<T>>,
+                                  clip_rect: Option<TypedRect<f32, ScreenPixel>>,
+                                  rounded_clip: Option<&RoundedClip>| {
+        draw::render_layer(render_context,
+                           layer.clone(),
+                           transform,
+                           projection,
+                           layer.world_clip_rect(clip_rect),
+                           layer.shader_clip(clip_rect, rounded_clip.cloned()).as_ref(),
+                           frame);
+    });
+}
+
+/// Marks the layers of a tree drawn by `render_2d_layer` that are hidden behind opaque layers
+/// drawn after them as culled, visiting the tree in reverse paint order with the same clips.
+fn cull_occluded_2d_layer<T>(layer: &Rc<Layer<T>>,
+                             clip_rect: Option<TypedRect<f32, ScreenPixel>>,
+                             rounded_clip: Option<RoundedClip>,
+                             opaque_region: &mut Vec<TypedRect<f32, ScreenPixel>>) {
+    if layer.subtree_is_hidden() {
+        return;
+    }
+    let is_drawn = layer.transform_state.borrow().screen_rect.is_some();
+    if !is_drawn && !layer.establishes_render_context() {
+        return;
+    }
+
+    let (child_clip_rect, child_rounded_clip) = if layer.establishes_render_context() {
+        (RenderContext3D::calculate_context_clip(layer.clone(), clip_rect),
+         layer.mask_rounded_clip(rounded_clip))
+    } else {
+        (clip_rect, rounded_clip)
+    };
+    let children_are_drawn = child_clip_rect.map_or(true, |clip_rect| !clip_rect.is_empty());
+    if children_are_drawn {
+        for child in layer.children().iter().rev() {
+            cull_occluded_2d_layer(child, child_clip_rect, child_rounded_clip, opaque_region);
+        }
+    }
+
+    if is_drawn && !layer.is_hidden() {
+        cull_occluded_layer(layer,
+                            layer.establishes_render_context(),
+                            clip_rect,
+                            rounded_clip,
+                            opaque_region);
+    }
+}
+
+/// Calls `f` with each layer of a layer tree without 3D transforms in paint order, along with
+/// the clip rect and rounded clip it is drawn with, as `render_2d_layer_tree` draws them. As
+/// with `RenderContext3D::for_each_layer`, the world clip rect of each layer is left to the
+/// caller.
+pub fn for_each_2d_layer<T, F>(root: &Rc<Layer<T>>, f: &mut F)
+                               where F: FnMut(&Rc<Layer<T>>,
+                                              Option<TypedRect<f32, ScreenPixel>>,
+                                              Option<&RoundedClip>) {
+    let clip_rect = RenderContext3D::calculate_context_clip(root.clone(), None);
+    let rounded_clip = root.mask_rounded_clip(None);
+    visit_2d_layer(root, clip_rect, rounded_clip, f)
+}
+
+fn visit_2d_layer<T, F>(layer: &Rc<Layer<T>>,
+                        clip_rect: Option<TypedRect<f32, ScreenPixel>>,
+                        rounded_clip: Option<RoundedClip>,
+                        f: &mut F)
+                        where F: FnMut(&Rc<Layer<T>>,
+                                       Option<TypedRect<f32, ScreenPixel>>,
+                                       Option<&RoundedClip>) {
+    // As when building a 3D context, layers that are entirely clipped are not drawn, and
+    // neither are their children unless they are drawn in a nested context. Hidden layers are
+    // not drawn either, but their children are unless the whole subtree is hidden.
+    if layer.subtree_is_hidden() {
+        return;
+    }
+    if layer.transform_state.borrow().screen_rect.is_some() {
+        if !layer.is_hidden() {
+            f(layer, clip_rect, rounded_clip.as_ref());
+        }
+    } else if !layer.establishes_render_context() {
+        return;
+    }
+
+    let (child_clip_rect, child_rounded_clip) = if layer.establishes_render_context() {
+        (RenderContext3D::calculate_context_clip(layer.clone(), clip_rect),
+         layer.mask_rounded_clip(rounded_clip))
+    } else {
+        (clip_rect, rounded_clip)
+    };
+    if let Some(ref child_clip_rect) = child_clip_rect {
+        if child_clip_rect.is_empty() {
+            return;
+        }
+    }
+
+    for child in layer.children().iter() {
+        visit_2d_layer(child, child_clip_rect, child_rounded_clip, f);
+    }
+}
+
+/// Draws the layers of a 3D context and its nested contexts, in order. Returns the number of
+/// contexts drawn without clearing the depth buffer because their layers don't intersect. Flat
+/// contexts aren't counted.
+pub fn render_3d_context<T>(render_context: &RenderContext,
+                            context: &RenderContext3D<T>,
+                            transform: &Matrix4D<f32>,
+                            projection: &Matrix4D<f32>,
+                            frame: &FrameInfo)
+                            -> usize {
+    if context.children.is_empty() {
+        return 0;
+    }
+
+    // Clear the z-buffer for each 3d render context. The layers of a flat context are all at
+    // z = 0 and pass the LEQUAL test in paint order whatever the depth buffer holds. A context
+    // whose layers don't intersect has no nested contexts and is drawn without depth testing.
+    // The context is given its place in the breakdown before its nested contexts.
+    let statistics_index = if render_context.options().collect_context_statistics {
+        let (layer_count, tile_count) = statistics::layer_and_tile_counts();
+        let mut index = 0;
+        statistics::record(|statistics| {
+            index = statistics.contexts.len();
+            statistics.contexts.push(ContextStatistics {
+                layer_count: layer_count,
+                tile_count: tile_count,
+            });
+        });
+        Some(index)
+    } else {
+        None
+    };
+
+    let gl = render_context.gl();
+    let skips_depth_buffer = !context.is_flat && !context.needs_depth_buffer;
+    let mut skipped_clear_count = 0;
+    if skips_depth_buffer {
+        gl::disable(gl, gl::DEPTH_TEST);
+        skipped_clear_count += 1;
+    } else if !context.is_flat {
+        gl::clear(gl, gl::DEPTH_BUFFER_BIT);
+    }
+
+    // Render child layers with z-testing.
+    for child in &context.children {
+        if let Some(ref layer) = child.layer {
+            draw::render_layer(render_context,
+                               layer.clone(),
+                               transform,
+                               projection,
+                               layer.world_clip_rect(context.clip_rect),
+                               layer.shader_clip(context.clip_rect, context.rounded_clip).as_ref(),
+                               frame);
+        }
+
+        if let Some(ref context) = child.context {
+            skipped_clear_count += render_3d_context(render_context,
+                                                     context,
+                                                     transform,
+                                                     projection,
+                                                     frame);
+        }
+    }
+
+    if skips_depth_buffer {
+        gl::enable(gl, gl::DEPTH_TEST);
+    }
+
+    // Until now the entry held the counts before the context was drawn.
+    if let Some(index) = statistics_index {
+        let (layer_count, tile_count) = statistics::layer_and_tile_counts();
+        statistics::record(|statistics| {
+            if let Some(entry) = statistics.contexts.get_mut(index) {
+                entry.layer_count = layer_count - entry.layer_count;
+                entry.tile_count = tile_count - entry.tile_count;
+            }
+        });
+    }
+    skipped_clear_count
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use color::Color;
+    use euclid::{Matrix4D, Point3D, Radians};
+    use euclid::point::TypedPoint2D;
+    use euclid::rect::TypedRect;
+    use euclid::size::TypedSize2D;
+    use layers::Layer;
+    use scene::Scene;
+    use std::rc::Rc;
+    use super::{RenderContext3D, draws_over_correctly, for_each_2d_layer};
+    use test_util::{count_allocations, layer};
+    use util::ScreenRect;
+
+    /// Returns an up-to-date scene whose root holds coplanar siblings lifted off the root plane,
+    /// so that they are sorted by depth rather than drawn in paint order.
+    fn scene_with_coplanar_siblings(count: usize)
+                                    -> (Scene<()>, Rc<Layer<()>>, Vec<Rc<Layer<()>>>) {
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let siblings: Vec<_> = (0..count).map(|_| {
+            let sibling = layer(0.0, 0.0, 100.0, 100.0, 256);
+            sibling.set_transform(Matrix4D::identity().pre_translated(0.0, 0.0, 10.0));
+            root.add_child(sibling.clone());
+            sibling
+        }).collect();
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(256.0, 256.0)));
+        scene.root = Some(root.clone());
+        scene.flush_transform_updates();
+        (scene, root, siblings)
+    }
+
+    /// Returns the positions among `siblings` of the siblings in `context`, in drawing order.
+    fn drawing_order(context: &RenderContext3D<()>, siblings: &[Rc<Layer<()>>]) -> Vec<usize> {
+        let mut order = vec!();
+        context.for_each_layer(&mut |layer: &Rc<Layer<()>>, _, _| {
+            if let Some(position) = siblings.iter().position(|sibling| {
+                &**sibling as *const Layer<()> == &**layer as *const Layer<()>
+            }) {
+                order.push(position)
+            }
+        });
+        order
+    }
+
+    #[test]
+    fn reordered_coplanar_siblings_are_drawn_in_their_new_order() {
+        let (scene, root, siblings) = scene_with_coplanar_siblings(2);
+        let context = RenderContext3D::new(root.clone());
+        assert!(!context.is_flat());
+        assert_eq!(drawing_order(&context, &siblings), vec!(0, 1));
+
+        // Moving the second sibling first puts the first one on top.
+        root.set_child_index(&siblings[1], 0).unwrap();
+        scene.flush_transform_updates();
+        assert_eq!(drawing_order(&RenderContext3D::new(root.clone()), &siblings), vec!(1, 0));
+
+        assert_eq!(root.set_child_index(&siblings[1], 2), Err(()));
+        assert_eq!(root.set_child_index(&layer(0.0, 0.0, 1.0, 1.0, 256), 0), Err(()));
+    }
+
+    #[test]
+    fn coplanar_siblings_inserted_at_an_index_are_drawn_at_that_index() {
+        let (scene, root, mut siblings) = scene_with_coplanar_siblings(2);
+        let inserted = layer(0.0, 0.0, 100.0, 100.0, 256);
+        inserted.set_transform(Matrix4D::identity().pre_translated(0.0, 0.0, 10.0));
+        root.insert_child_at_index(inserted.clone(), 1);
+        siblings.push(inserted);
+        scene.flush_transform_updates();
+        assert_eq!(drawing_order(&RenderContext3D::new(root), &siblings), vec!(0, 2, 1));
+    }
+
+    #[test]
+    fn coplanar_siblings_added_in_another_order_are_drawn_in_the_same_order() {
+        let (mut scene, root, siblings) = scene_with_coplanar_siblings(4);
+        let order = RenderContext3D::new(root.clone()).debug_render_order();
+        assert_eq!(drawing_order(&RenderContext3D::new(root.clone()), &siblings),
+                   vec!(0, 1, 2, 3));
+
+        // Re-creating the tree with the siblings added in shuffled orders, as embedders do when
+        // they rebuild sibling layers, keeps the order given by the stacking indices.
+        for shuffled in &[[2, 0, 3, 1], [3, 2, 1, 0], [1, 3, 0, 2]] {
+            for sibling in &siblings {
+                assert!(root.remove_child(sibling));
+            }
+            for &position in shuffled {
+                root.add_child(siblings[position].clone());
+            }
+            scene.flush_transform_updates();
+            assert_eq!(RenderContext3D::new(root.clone()).debug_render_order(), order);
+            assert_eq!(scene.debug_render_order(), order);
+        }
+
+        // A new root with the same siblings draws them in the same order too.
+        let new_root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        for sibling in siblings.iter().rev() {
+            new_root.add_child(sibling.clone());
+        }
+        scene.root = Some(new_root.clone());
+        scene.flush_transform_updates();
+        assert_eq!(drawing_order(&RenderContext3D::new(new_root.clone()), &siblings),
+                   vec!(0, 1, 2, 3));
+
+        // Embedders may restack the siblings explicitly.
+        *siblings[0].stacking_index.borrow_mut() = order.iter().max().unwrap() + 1;
+        assert_eq!(drawing_order(&RenderContext3D::new(new_root), &siblings), vec!(1, 2, 3, 0));
+    }
+
+    /// Returns an up-to-date scene with a flat tree of 100 layers under the root, in ten rows of
+    /// ten nested layers.
+    fn scene_with_flat_tree() -> (Scene<()>, Rc<Layer<()>>) {
+        let root = layer(0.0, 0.0, 1000.0, 1000.0, 256);
+        for row in 0..10 {
+            let mut parent = root.clone();
+            for column in 0..10 {
+                let x = if column == 0 { 0.0 } else { 100.0 };
+                let child = layer(x, row as f32 * 100.0, 100.0, 100.0, 256);
+                parent.add_child(child.clone());
+                parent = child;
+            }
+        }
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(1000.0, 1000.0)));
+        scene.root = Some(root.clone());
+        scene.flush_transform_updates();
+        (scene, root)
+    }
+
+    #[test]
+    fn flat_trees_are_visited_in_the_order_of_their_render_context() {
+        let (scene, root) = scene_with_flat_tree();
+        assert!(scene.draws_flat(&root));
+        let context = RenderContext3D::new(root.clone());
+        assert!(context.is_flat());
+
+        let mut context_order = vec!();
+        context.for_each_layer(&mut |layer: &Rc<Layer<()>>, _, _| {
+            context_order.push(&**layer as *const Layer<()>)
+        });
+        let mut flat_order = vec!();
+        for_each_2d_layer(&root, &mut |layer: &Rc<Layer<()>>, _, _| {
+            flat_order.push(&**layer as *const Layer<()>)
+        });
+        assert_eq!(flat_order.len(), 101);
+        assert_eq!(flat_order, context_order);
+    }
+
+    #[test]
+    fn visiting_a_flat_tree_does_not_allocate() {
+        let (_scene, root) = scene_with_flat_tree();
+        let mut visited = 0;
+        let allocations = count_allocations(|| {
+            for _ in 0..100 {
+                for_each_2d_layer(&root, &mut |_: &Rc<Layer<()>>, _, _| visited += 1);
+            }
+        });
+        assert_eq!(visited, 100 * 101);
+        assert_eq!(allocations, 0);
+
+        // Building a render context allocates for every layer.
+        assert!(count_allocations(|| drop(RenderContext3D::new(root.clone()))) > 0);
+    }
+
+    #[test]
+    fn hidden_layers_are_not_drawn_but_their_children_may_be() {
+        let (scene, root, mut siblings) = scene_with_coplanar_siblings(2);
+        let grandchild = layer(0.0, 0.0, 50.0, 50.0, 256);
+        siblings[0].add_child(grandchild.clone());
+        siblings.push(grandchild);
+        scene.flush_transform_updates();
+        assert_eq!(drawing_order(&RenderContext3D::new(root.clone()), &siblings), vec!(0, 1, 2));
+
+        siblings[0].set_visible(false, false);
+        scene.flush_transform_updates();
+        assert_eq!(drawing_order(&RenderContext3D::new(root.clone()), &siblings), vec!(1, 2));
+        siblings[0].set_visible(false, true);
+        scene.flush_transform_updates();
+        assert_eq!(drawing_order(&RenderContext3D::new(root.clone()), &siblings), vec!(1));
+        siblings[0].set_visible(true, true);
+        scene.flush_transform_updates();
+        assert_eq!(drawing_order(&RenderContext3D::new(root), &siblings), vec!(0, 1, 2));
+    }
+
+    #[test]
+    fn hidden_layers_are_skipped_by_the_flat_path() {
+        let (scene, root) = scene_with_flat_tree();
+        let row = root.children()[0].clone();
+        let count_visited = || {
+            let mut visited = 0;
+            for_each_2d_layer(&root, &mut |_: &Rc<Layer<()>>, _, _| visited += 1);
+            visited
+        };
+
+        // The first row is a chain of ten layers.
+        row.set_visible(false, false);
+        scene.flush_transform_updates();
+        assert_eq!(count_visited(), 100);
+        row.set_visible(false, true);
+        scene.flush_transform_updates();
+        assert_eq!(count_visited(), 91);
+        row.set_visible(true, false);
+        scene.flush_transform_updates();
+        assert_eq!(count_visited(), 101);
+    }
+
+    fn screen_rect(x: f32, y: f32, width: f32, height: f32, z_min: f32, z_max: f32)
+                   -> ScreenRect {
+        ScreenRect {
+            rect: TypedRect::new(TypedPoint2D::new(x, y), TypedSize2D::new(width, height)),
+            z_center: (z_min + z_max) / 2.0,
+            z_min: z_min,
+            z_max: z_max,
+        }
+    }
+
+    #[test]
+    fn only_overlapping_layers_farther_than_earlier_ones_are_drawn_over_incorrectly() {
+        let behind = screen_rect(0.0, 0.0, 10.0, 10.0, 5.0, 5.0);
+
+        // Layers that share no area may be drawn in any order, even touching ones.
+        assert!(draws_over_correctly(&behind, &screen_rect(20.0, 0.0, 10.0, 10.0, 0.0, 0.0)));
+        assert!(draws_over_correctly(&behind, &screen_rect(10.0, 0.0, 10.0, 10.0, 0.0, 0.0)));
+        assert!(draws_over_correctly(&behind, &screen_rect(0.0, 10.0, 10.0, 10.0, 0.0, 0.0)));
+        assert!(draws_over_correctly(&behind, &screen_rect(10.0, 10.0, 10.0, 10.0, 0.0, 0.0)));
+
+        // Overlapping layers must be nowhere farther than the layers drawn before them.
+        assert!(!draws_over_correctly(&behind, &screen_rect(9.0, 9.0, 10.0, 10.0, 0.0, 0.0)));
+        assert!(draws_over_correctly(&behind, &screen_rect(9.0, 9.0, 10.0, 10.0, 6.0, 8.0)));
+        assert!(draws_over_correctly(&behind, &screen_rect(9.0, 9.0, 10.0, 10.0, 5.0, 5.0)));
+        assert!(!draws_over_correctly(&behind, &screen_rect(9.0, 9.0, 10.0, 10.0, 4.0, 8.0)));
+        assert!(!draws_over_correctly(&screen_rect(0.0, 0.0, 10.0, 10.0, 0.0, 10.0),
+                                      &screen_rect(2.0, 2.0, 2.0, 2.0, 5.0, 15.0)));
+    }
+
+    #[test]
+    fn contexts_need_the_depth_buffer_only_when_their_layers_intersect() {
+        let (scene, root, siblings) = scene_with_coplanar_siblings(2);
+        assert!(!RenderContext3D::new(root.clone()).needs_depth_buffer());
+
+        // Turning a sibling about its vertical center line makes it pierce the other one.
+        siblings[1].set_transform_origin(Point3D::new(50.0, 50.0, 0.0));
+        siblings[1].set_transform(Matrix4D::identity()
+            .pre_translated(0.0, 0.0, 10.0)
+            .pre_mul(&Matrix4D::create_rotation(0.0, 1.0, 0.0, Radians::new(0.5))));
+        scene.flush_transform_updates();
+        assert!(RenderContext3D::new(root.clone()).needs_depth_buffer());
+
+        // Nested contexts always need it, since their extent isn't known.
+        siblings[1].set_transform(Matrix4D::identity().pre_translated(0.0, 0.0, 10.0));
+        let bounds = TypedRect::new(TypedPoint2D::new(0.0, 0.0), TypedSize2D::new(50.0, 50.0));
+        let transparent = Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 };
+        let nested = Rc::new(Layer::new(bounds, 256, transparent, 1.0, true, ()));
+        nested.add_child(layer(0.0, 0.0, 10.0, 10.0, 256));
+        root.add_child(nested);
+        scene.flush_transform_updates();
+        assert!(RenderContext3D::new(root.clone()).needs_depth_buffer());
+
+        // Flat trees never do.
+        let (_, flat_root) = scene_with_flat_tree();
+        let context = RenderContext3D::new(flat_root);
+        assert!(context.is_flat());
+        assert!(!context.needs_depth_buffer());
+    }
+}
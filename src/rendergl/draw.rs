@@ -0,0 +1,2615 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Drawing layers, tiles, and debug overlays.
+//!
+//! `RenderContext::new` enables `GL_TEXTURE_2D` (and `GL_TEXTURE_RECTANGLE_ARB` when supported)
+//! and premultiplied-alpha blending, which every draw call relies on. Each draw call uses its
+//! own program, enables its attribute arrays and disables them again afterwards, and leaves
+//! `GL_ARRAY_BUFFER` bound to one of our vertex buffers. Textured draws bind the texture on the
+//! texture unit chosen in `RenderOptions`, which must already be active, and unbind it
+//! afterwards. Layers drawn with a clip rect under an axis-aligned transform are also clipped
+//! with the scissor test, which is disabled again afterwards.
+
+use capabilities::Capabilities;
+use color::{Color, ColorMatrix};
+use debug_overlay::{DebugOverlayMode, LAYER_AABB_BORDER_COLOR, LAYER_AABB_BORDER_THICKNESS};
+use debug_overlay::{LAYER_BORDER_COLOR, LAYER_BORDER_THICKNESS, TILE_BORDER_COLOR};
+use debug_overlay::{TILE_BORDER_THICKNESS, heatmap_color};
+use frame::{FrameClock, FrameIndex, FrameInfo};
+use layers::{ContentAge, Layer, RoundedClip};
+use texturegl::{FenceQueue, PboPool, Texture, TextureReclaimQueue};
+use texturegl::Flip::VerticalFlip;
+use texturegl::Rotation;
+use texturegl::TextureTarget::{TextureTarget2D, TextureTargetRectangle};
+use tiling::Tile;
+use util::{clip_polygon_to_rect, clip_textured_rect_to_near_plane, is_2d_transform};
+use util::{is_axis_aligned_2d_transform, nine_patch_quads, rect_has_valid_size};
+use platform::surface::NativeDisplay;
+use super::context3d::{self, RenderContext3D};
+use super::offscreen::{OffscreenTarget, RenderError};
+use super::programs::{Buffers, ColorMatrixPrograms, ColorVertex, ShaderError};
+use super::programs::{RoundedClipTextureProgram, SolidColorProgram, TextureProgram};
+use super::programs::TextureVertex;
+use super::statistics::{self, RenderStatistics};
+
+use euclid::{Matrix4D, Point2D, Rect, Size2D};
+use euclid::side_offsets::SideOffsets2D;
+use gltrace::{self as gl, GlApi, GleamGl};
+use gleam::gl::{GLboolean, GLenum, GLfloat, GLint, GLsizei, GLuint};
+use std::mem;
+use std::rc::Rc;
+
+const ORTHO_NEAR_PLANE: f32 = -1000000.0;
+const ORTHO_FAR_PLANE: f32 = 1000000.0;
+
+pub fn create_ortho(scene_size: &Size2D<f32>) -> Matrix4D<f32> {
+    create_ortho_for_rect(&Rect::new(Point2D::zero(), *scene_size))
+}
+
+/// Returns an orthographic projection that maps the given rect to the GL viewport, with the
+/// origin of the rect at the top left.
+pub fn create_ortho_for_rect(rect: &Rect<f32>) -> Matrix4D<f32> {
+    Matrix4D::ortho(rect.min_x(),
+                    rect.max_x(),
+                    rect.max_y(),
+                    rect.min_y(),
+                    ORTHO_NEAR_PLANE,
+                    ORTHO_FAR_PLANE)
+}
+
+/// Drawn over stale tiles when `RenderContext::set_show_stale_tiles` is on. Premultiplied.
+static STALE_TILE_TINT_COLOR: Color = Color { r: 0.3, g: 0., b: 0., a: 0.3 };
+
+/// Options controlling how the render context interacts with the rest of the GL state.
+#[derive(Copy, Clone, Debug)]
+pub struct RenderOptions {
+    /// The texture unit used to sample layer textures, as an offset from `GL_TEXTURE0`.
+    pub texture_unit: GLuint,
+
+    /// Whether to save the GL state that rendering changes, including the active texture unit and
+    /// the texture bindings on our unit, and restore it after each render pass or at
+    /// `RenderContext::end_frame`, for embedders that interleave their own GL rendering with
+    /// ours.
+    pub preserve_embedder_gl_state: bool,
+
+    /// How thumbnails are fitted to their target size when the aspect ratios differ.
+    pub thumbnail_aspect: ThumbnailAspect,
+
+    /// Whether to break the render statistics down by 3D context. See
+    /// `RenderStatistics::contexts`.
+    pub collect_context_statistics: bool,
+
+    /// What `render_scene` clears before drawing a scene.
+    pub clear_policy: ClearPolicy,
+
+    /// The resolution below which tiles get mipmaps when `RenderContext::set_use_mipmaps` is on.
+    pub mipmap_scale_threshold: f32,
+}
+
+impl RenderOptions {
+    pub fn new() -> RenderOptions {
+        RenderOptions {
+            texture_unit: 0,
+            preserve_embedder_gl_state: false,
+            thumbnail_aspect: ThumbnailAspect::Letterbox,
+            collect_context_statistics: false,
+            clear_policy: ClearPolicy::ClearAll,
+            mipmap_scale_threshold: 0.5,
+        }
+    }
+
+    /// The texture unit reserved for a second sampler, such as a mask or an effect input. This is
+    /// always the unit after `texture_unit`.
+    pub fn secondary_texture_unit(&self) -> GLuint {
+        self.texture_unit + 1
+    }
+}
+
+/// The texture state of the embedder, saved before rendering when
+/// `RenderOptions::preserve_embedder_gl_state` is set.
+#[derive(Copy, Clone)]
+pub struct SavedTextureState {
+    active_texture: GLint,
+    texture_2d_binding: GLint,
+    texture_rectangle_binding: Option<GLint>,
+}
+
+impl SavedTextureState {
+    /// Saves the active texture unit and the bindings on `texture_unit`, which is left active.
+    pub fn save(gl: &GlApi, texture_unit: GLuint, capabilities: &Capabilities)
+                -> SavedTextureState {
+        let active_texture = gl::get_integer_v(gl, gl::ACTIVE_TEXTURE);
+        gl::active_texture(gl, gl::TEXTURE0 + texture_unit);
+        SavedTextureState {
+            active_texture: active_texture,
+            texture_2d_binding: gl::get_integer_v(gl, gl::TEXTURE_BINDING_2D),
+            texture_rectangle_binding:
+                SavedTextureState::texture_rectangle_binding(gl, capabilities),
+        }
+    }
+
+    #[cfg(not(target_os="android"))]
+    fn texture_rectangle_binding(gl: &GlApi, capabilities: &Capabilities) -> Option<GLint> {
+        if capabilities.texture_rectangle {
+            Some(gl::get_integer_v(gl, gl::TEXTURE_BINDING_RECTANGLE_ARB))
+        } else {
+            None
+        }
+    }
+
+    #[cfg(target_os="android")]
+    fn texture_rectangle_binding(_: &GlApi, _: &Capabilities) -> Option<GLint> {
+        None
+    }
+
+    /// Restores the saved bindings. This must be called with the same texture unit active as
+    /// when the state was saved.
+    pub fn restore(&self, gl: &GlApi) {
+        gl::bind_texture(gl, gl::TEXTURE_2D, self.texture_2d_binding as GLuint);
+        self.restore_texture_rectangle_binding(gl);
+        gl::active_texture(gl, self.active_texture as GLenum);
+    }
+
+    #[cfg(not(target_os="android"))]
+    fn restore_texture_rectangle_binding(&self, gl: &GlApi) {
+        if let Some(binding) = self.texture_rectangle_binding {
+            gl::bind_texture(gl, gl::TEXTURE_RECTANGLE_ARB, binding as GLuint);
+        }
+    }
+
+    #[cfg(target_os="android")]
+    fn restore_texture_rectangle_binding(&self, _: &GlApi) {
+    }
+}
+
+/// The GL state that rendering changes, other than texture state, saved so that it can be
+/// restored afterwards.
+///
+/// Some state that we change is deliberately not saved, since reading it back is costly and
+/// embedders rarely depend on it: the enabled vertex attribute arrays and their pointers, the
+/// uniforms of our programs, the parameters of our textures, the unpack alignment, which is
+/// reset to its default of 4 after each upload, and the pixel unpack buffer binding, which
+/// uploads through pixel buffer objects leave at 0. State we never change, such as the blend
+/// equation, the color mask, stencil testing and face culling, is not saved either.
+#[derive(Copy, Clone)]
+pub struct SavedGlState {
+    framebuffer: GLint,
+    viewport: [GLint; 4],
+    clear_color: [GLfloat; 4],
+    depth_test: GLboolean,
+    depth_func: GLint,
+    blend: GLboolean,
+    blend_func: [GLint; 4],
+    scissor_test: GLboolean,
+    scissor_box: [GLint; 4],
+    program: GLint,
+    array_buffer: GLint,
+}
+
+impl SavedGlState {
+    pub fn save(gl: &GlApi) -> SavedGlState {
+        SavedGlState {
+            framebuffer: gl::get_integer_v(gl, gl::FRAMEBUFFER_BINDING),
+            viewport: gl::get_integer_4v(gl, gl::VIEWPORT),
+            clear_color: gl::get_float_4v(gl, gl::COLOR_CLEAR_VALUE),
+            depth_test: gl::is_enabled(gl, gl::DEPTH_TEST),
+            depth_func: gl::get_integer_v(gl, gl::DEPTH_FUNC),
+            blend: gl::is_enabled(gl, gl::BLEND),
+            blend_func: [gl::get_integer_v(gl, gl::BLEND_SRC_RGB),
+                         gl::get_integer_v(gl, gl::BLEND_DST_RGB),
+                         gl::get_integer_v(gl, gl::BLEND_SRC_ALPHA),
+                         gl::get_integer_v(gl, gl::BLEND_DST_ALPHA)],
+            scissor_test: gl::is_enabled(gl, gl::SCISSOR_TEST),
+            scissor_box: gl::get_integer_4v(gl, gl::SCISSOR_BOX),
+            program: gl::get_integer_v(gl, gl::CURRENT_PROGRAM),
+            array_buffer: gl::get_integer_v(gl, gl::ARRAY_BUFFER_BINDING),
+        }
+    }
+
+    pub fn restore(&self, gl: &GlApi) {
+        gl::bind_framebuffer(gl, gl::FRAMEBUFFER, self.framebuffer as GLuint);
+        gl::viewport(gl,
+                     self.viewport[0],
+                     self.viewport[1],
+                     self.viewport[2] as GLsizei,
+                     self.viewport[3] as GLsizei);
+        gl::clear_color(gl,
+                        self.clear_color[0],
+                        self.clear_color[1],
+                        self.clear_color[2],
+                        self.clear_color[3]);
+        if self.depth_test != 0 {
+            gl::enable(gl, gl::DEPTH_TEST);
+        } else {
+            gl::disable(gl, gl::DEPTH_TEST);
+        }
+        gl::depth_func(gl, self.depth_func as GLenum);
+        if self.blend != 0 {
+            gl::enable(gl, gl::BLEND);
+        } else {
+            gl::disable(gl, gl::BLEND);
+        }
+        gl::blend_func_separate(gl,
+                                self.blend_func[0] as GLenum,
+                                self.blend_func[1] as GLenum,
+                                self.blend_func[2] as GLenum,
+                                self.blend_func[3] as GLenum);
+        if self.scissor_test != 0 {
+            gl::enable(gl, gl::SCISSOR_TEST);
+        } else {
+            gl::disable(gl, gl::SCISSOR_TEST);
+        }
+        gl::scissor(gl,
+                    self.scissor_box[0],
+                    self.scissor_box[1],
+                    self.scissor_box[2] as GLsizei,
+                    self.scissor_box[3] as GLsizei);
+        gl::use_program(gl, self.program as GLuint);
+        gl::bind_buffer(gl, gl::ARRAY_BUFFER, self.array_buffer as GLuint);
+    }
+}
+
+/// What is cleared before a scene is drawn.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ClearPolicy {
+    /// Clear the whole framebuffer, whatever the viewport of the scene.
+    ClearAll,
+    /// Clear only the viewport of the scene, so that other scenes drawn into the same
+    /// framebuffer survive.
+    ClearViewportOnly,
+    /// Leave the color buffer alone, drawing the scene over what is already there. The depth
+    /// buffer is still cleared within the viewport when it is used.
+    NoClear,
+}
+
+/// How a thumbnail is fitted to its target size when the aspect ratio of the layer differs from
+/// that of the target.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ThumbnailAspect {
+    /// Scale the layer to fit entirely within the target, centered, leaving transparent bars.
+    Letterbox,
+    /// Scale the layer to cover the entire target, centered, cutting off the overflow.
+    Crop,
+}
+
+#[derive(Clone)]
+pub struct RenderContext {
+    /// The GL implementation that all of our calls go through.
+    gl: Rc<GlApi>,
+
+    texture_2d_program: TextureProgram,
+    texture_rectangle_program: Option<TextureProgram>,
+    rounded_clip_2d_program: RoundedClipTextureProgram,
+    rounded_clip_rectangle_program: Option<RoundedClipTextureProgram>,
+    color_matrix_programs: ColorMatrixPrograms,
+    solid_color_program: SolidColorProgram,
+    buffers: Buffers,
+
+    /// The platform-specific graphics context.
+    compositing_display: NativeDisplay,
+
+    /// What to draw over layers and tiles for debugging purposes.
+    debug_overlay_mode: DebugOverlayMode,
+
+    force_near_texture_filter: bool,
+
+    /// Whether to tint tiles displaying content older than their layer, which are waiting for a
+    /// repaint.
+    show_stale_tiles: bool,
+
+    /// Whether to generate mipmaps for tiles painted at a resolution below
+    /// `RenderOptions::mipmap_scale_threshold`, and minify textures that have them with
+    /// trilinear filtering.
+    use_mipmaps: bool,
+
+    /// What the GL implementation supports.
+    capabilities: Capabilities,
+
+    options: RenderOptions,
+
+    /// Counts the frames begun by the embedder.
+    frame_clock: FrameClock,
+
+    /// The GL state of the embedder, saved by `begin_frame` when
+    /// `RenderOptions::preserve_embedder_gl_state` is set and restored by `end_frame`.
+    embedder_gl_state: Option<(SavedGlState, SavedTextureState)>,
+
+    /// Whether layers are drawn without being touched: no textures are created for their
+    /// buffers, and their tiles don't record the frame they were drawn in. This is set on the
+    /// copy of the context that draws thumbnails, which must not disturb the scene.
+    read_only: bool,
+}
+
+impl RenderContext {
+    /// Creates a render context, panicking if our shaders fail to compile. See `try_new`.
+    pub fn new(compositing_display: NativeDisplay,
+               show_debug_borders: bool,
+               force_near_texture_filter: bool) -> RenderContext {
+        RenderContext::new_with_gl(Rc::new(GleamGl),
+                                   compositing_display,
+                                   show_debug_borders,
+                                   force_near_texture_filter)
+    }
+
+    /// Creates a render context that issues its GL calls through `gl`, panicking if our shaders
+    /// fail to compile.
+    pub fn new_with_gl(gl: Rc<GlApi>,
+                       compositing_display: NativeDisplay,
+                       show_debug_borders: bool,
+                       force_near_texture_filter: bool) -> RenderContext {
+        match RenderContext::try_new_with_gl(gl,
+                                             compositing_display,
+                                             show_debug_borders,
+                                             force_near_texture_filter) {
+            Ok(render_context) => render_context,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    /// Creates a render context, returning an error if the GL implementation fails to compile
+    /// or link one of our shaders.
+    pub fn try_new(compositing_display: NativeDisplay,
+                   show_debug_borders: bool,
+                   force_near_texture_filter: bool)
+                   -> Result<RenderContext, ShaderError> {
+        RenderContext::try_new_with_gl(Rc::new(GleamGl),
+                                       compositing_display,
+                                       show_debug_borders,
+                                       force_near_texture_filter)
+    }
+
+    /// Creates a render context that issues its GL calls through the given implementation, such
+    /// as a recording one in tests. Textures created for layers drawn with it share it.
+    pub fn try_new_with_gl(gl_api: Rc<GlApi>,
+                           compositing_display: NativeDisplay,
+                           show_debug_borders: bool,
+                           force_near_texture_filter: bool)
+                           -> Result<RenderContext, ShaderError> {
+        let gl = &*gl_api;
+        gl::enable(gl, gl::TEXTURE_2D);
+
+        // Each layer uses premultiplied alpha!
+        gl::enable(gl, gl::BLEND);
+        gl::blend_func(gl, gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
+
+        let capabilities = Capabilities::detect(gl);
+
+        // Programs created so far are deleted if a later one fails.
+        let texture_2d_program = try!(TextureProgram::create_2d_program(gl));
+        let mut program_ids = vec![texture_2d_program.program.id];
+        let solid_color_program = match SolidColorProgram::new(gl) {
+            Ok(program) => program,
+            Err(error) => {
+                delete_programs(gl, &program_ids);
+                return Err(error);
+            }
+        };
+        program_ids.push(solid_color_program.program.id);
+        let texture_rectangle_program =
+            TextureProgram::create_rectangle_program_if_necessary(gl, &capabilities);
+        program_ids.extend(texture_rectangle_program.map(|program| program.program.id));
+        let rounded_clip_2d_program = match RoundedClipTextureProgram::create_2d_program(gl) {
+            Ok(program) => program,
+            Err(error) => {
+                delete_programs(gl, &program_ids);
+                return Err(error);
+            }
+        };
+        program_ids.push(rounded_clip_2d_program.texture_program.program.id);
+        let rounded_clip_rectangle_program =
+            RoundedClipTextureProgram::create_rectangle_program_if_necessary(gl, &capabilities);
+        program_ids.extend(rounded_clip_rectangle_program.map(|program| {
+            program.texture_program.program.id
+        }));
+        let color_matrix_programs = match ColorMatrixPrograms::new(gl, &capabilities) {
+            Ok(programs) => programs,
+            Err(error) => {
+                delete_programs(gl, &program_ids);
+                return Err(error);
+            }
+        };
+
+        Ok(RenderContext {
+            gl: gl_api.clone(),
+            texture_2d_program: texture_2d_program,
+            texture_rectangle_program: texture_rectangle_program,
+            rounded_clip_2d_program: rounded_clip_2d_program,
+            rounded_clip_rectangle_program: rounded_clip_rectangle_program,
+            color_matrix_programs: color_matrix_programs,
+            solid_color_program: solid_color_program,
+            buffers: RenderContext::init_buffers(gl),
+            compositing_display: compositing_display,
+            debug_overlay_mode: if show_debug_borders {
+                DebugOverlayMode::Borders
+            } else {
+                DebugOverlayMode::None
+            },
+            force_near_texture_filter: force_near_texture_filter,
+            show_stale_tiles: false,
+            use_mipmaps: false,
+            capabilities: capabilities,
+            options: RenderOptions::new(),
+            frame_clock: FrameClock::new(),
+            embedder_gl_state: None,
+            read_only: false,
+        })
+    }
+
+    /// Returns the GL implementation that this context issues its calls through.
+    pub fn gl(&self) -> &GlApi {
+        &*self.gl
+    }
+
+    /// Returns a shared reference to the GL implementation of this context, for the textures and
+    /// framebuffers created to be drawn with it.
+    pub fn shared_gl(&self) -> Rc<GlApi> {
+        self.gl.clone()
+    }
+
+    /// Returns what the GL implementation supports.
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Returns the maximum width and height of a texture, as reported by `GL_MAX_TEXTURE_SIZE`
+    /// when this context was created. Layers clamp their tile size to it.
+    pub fn max_texture_size(&self) -> usize {
+        self.capabilities.max_texture_size
+    }
+
+    /// Returns true if textures with the `GL_TEXTURE_RECTANGLE_ARB` target can be drawn.
+    pub fn supports_rectangle_textures(&self) -> bool {
+        self.texture_rectangle_program.is_some()
+    }
+
+    pub fn options(&self) -> RenderOptions {
+        self.options
+    }
+
+    /// Deletes the textures that were dropped since the last call. Textures are only queued for
+    /// deletion when dropped, since the GL context may not be current at that point; this is
+    /// done at the start of every frame. Returns the number of textures deleted.
+    pub fn purge_dead_textures(&self) -> usize {
+        TextureReclaimQueue::for_current_thread().purge(&*self.gl)
+    }
+
+    pub fn set_options(&mut self, options: RenderOptions) {
+        self.options = options;
+    }
+
+    /// Turns mipmaps for zoomed-out tiles on or off. They are off by default, since generating
+    /// them costs time on every tile upload.
+    pub fn set_use_mipmaps(&mut self, use_mipmaps: bool) {
+        self.use_mipmaps = use_mipmaps;
+    }
+
+    /// Turns uploads of memory surfaces through pixel buffer objects on or off for the current
+    /// thread. With `buffer_count` buffers to rotate through, tile uploads no longer stall the
+    /// driver while it copies the pixels. Returns false, leaving uploads direct, if the GL
+    /// implementation lacks pixel buffer objects, as OpenGL ES 2 does without extensions.
+    ///
+    /// If the GL implementation has fence sync objects, uploads are fenced, and
+    /// `PboPool::last_upload` tells when the GPU is done with them.
+    pub fn set_use_pbo_uploads(&self, use_pbo_uploads: bool, buffer_count: usize) -> bool {
+        let pool = if use_pbo_uploads && self.capabilities.pixel_buffer_objects {
+            if self.capabilities.fence_sync {
+                Some(PboPool::new_with_fences(self.gl.clone(), buffer_count))
+            } else {
+                Some(PboPool::new(self.gl.clone(), buffer_count))
+            }
+        } else {
+            None
+        };
+        let enabled = pool.is_some();
+        if let Some(previous_pool) = PboPool::set_for_current_thread(pool) {
+            previous_pool.destroy();
+        }
+        enabled || !use_pbo_uploads
+    }
+
+    /// Turns the tint of stale tiles on or off, for visualizing outstanding repaints.
+    pub fn set_show_stale_tiles(&mut self, show_stale_tiles: bool) {
+        self.show_stale_tiles = show_stale_tiles;
+    }
+
+    /// Sets what is drawn over layers and tiles for debugging. Passing true for
+    /// `show_debug_borders` when creating the context is the same as `DebugOverlayMode::Borders`.
+    pub fn set_debug_overlay_mode(&mut self, debug_overlay_mode: DebugOverlayMode) {
+        self.debug_overlay_mode = debug_overlay_mode;
+    }
+
+    pub fn debug_overlay_mode(&self) -> DebugOverlayMode {
+        self.debug_overlay_mode
+    }
+
+    /// Returns the filters to sample the given texture with when magnified and minified.
+    fn texture_filters(&self, texture: &Texture) -> (GLint, GLint) {
+        if self.force_near_texture_filter {
+            (gl::NEAREST as GLint, gl::NEAREST as GLint)
+        } else if self.use_mipmaps && texture.has_mipmaps() {
+            (gl::LINEAR as GLint, gl::LINEAR_MIPMAP_LINEAR as GLint)
+        } else {
+            (gl::LINEAR as GLint, gl::LINEAR as GLint)
+        }
+    }
+
+    /// Begins a new frame. This must be called before each call to `render_scene`, with the
+    /// current time according to the embedder's clock.
+    ///
+    /// If `RenderOptions::preserve_embedder_gl_state` is set, this also saves the GL state that
+    /// rendering changes, and `end_frame` must be called after the last render pass of the frame
+    /// to restore it. Render passes then leave the state alone in between. Otherwise each render
+    /// pass saves and restores the state itself, including when it bails out early.
+    ///
+    /// The fences of the current thread are polled too, completing the tokens of the uploads and
+    /// other GL work that the GPU has finished.
+    pub fn begin_frame(&mut self, now: f64) -> FrameInfo {
+        let gl = &*self.gl;
+        if self.capabilities.fence_sync {
+            FenceQueue::poll_current_thread(gl);
+        }
+        if self.options.preserve_embedder_gl_state && self.embedder_gl_state.is_none() {
+            let texture_state = SavedTextureState::save(gl,
+                                                        self.options.texture_unit,
+                                                        &self.capabilities);
+            self.embedder_gl_state = Some((SavedGlState::save(gl), texture_state));
+        }
+        self.frame_clock.begin_frame(now)
+    }
+
+    /// Ends the current frame, restoring the GL state saved by `begin_frame`, if any.
+    pub fn end_frame(&mut self) {
+        let gl = &*self.gl;
+        if let Some((gl_state, texture_state)) = self.embedder_gl_state.take() {
+            gl::active_texture(gl, gl::TEXTURE0 + self.options.texture_unit);
+            texture_state.restore(gl);
+            gl_state.restore(gl);
+        }
+    }
+
+    /// Returns true if the GL state of the embedder was saved by `begin_frame` and will be
+    /// restored by `end_frame`.
+    pub fn has_saved_embedder_gl_state(&self) -> bool {
+        self.embedder_gl_state.is_some()
+    }
+
+    /// Returns the most recently begun frame, or None if `begin_frame` was never called.
+    pub fn last_frame(&self) -> Option<FrameInfo> {
+        self.frame_clock.last_frame()
+    }
+
+    /// Returns what the render passes on this thread did since the statistics were last taken,
+    /// and starts counting afresh.
+    pub fn take_statistics(&self) -> RenderStatistics {
+        statistics::take()
+    }
+
+    /// Deletes the shader programs and vertex buffers owned by this context, along with the pixel
+    /// buffer objects and fences of the current thread.
+    fn destroy(self) {
+        let gl = &*self.gl;
+        if let Some(pool) = PboPool::set_for_current_thread(None) {
+            pool.destroy();
+        }
+        if self.capabilities.fence_sync {
+            FenceQueue::cancel_current_thread(gl);
+        }
+        gl::delete_program(gl, self.texture_2d_program.program.id);
+        if let Some(program) = self.texture_rectangle_program {
+            gl::delete_program(gl, program.program.id);
+        }
+        gl::delete_program(gl, self.rounded_clip_2d_program.texture_program.program.id);
+        if let Some(program) = self.rounded_clip_rectangle_program {
+            gl::delete_program(gl, program.texture_program.program.id);
+        }
+        gl::delete_program(gl, self.solid_color_program.program.id);
+        gl::delete_buffers(gl,
+                           &[self.buffers.quad_vertex_buffer,
+                             self.buffers.line_quad_vertex_buffer]);
+    }
+
+    fn init_buffers(gl: &GlApi) -> Buffers {
+        let quad_vertex_buffer = gl::gen_buffers(gl, 1)[0];
+        gl::bind_buffer(gl, gl::ARRAY_BUFFER, quad_vertex_buffer);
+
+        let line_quad_vertex_buffer = gl::gen_buffers(gl, 1)[0];
+        gl::bind_buffer(gl, gl::ARRAY_BUFFER, line_quad_vertex_buffer);
+
+        Buffers {
+            quad_vertex_buffer: quad_vertex_buffer,
+            line_quad_vertex_buffer: line_quad_vertex_buffer,
+        }
+    }
+
+    /// Fills a rect with a solid color. If `clip_to_near_plane` is true, only the part of the rect
+    /// in front of the near plane is drawn.
+    fn bind_and_render_solid_rect(&self,
+                                  rect: &Rect<f32>,
+                                  clip_to_near_plane: bool,
+                                  transform: &Matrix4D<f32>,
+                                  projection: &Matrix4D<f32>,
+                                  color: &Color) {
+        if !clip_to_near_plane {
+            let vertices = [
+                ColorVertex::new(rect.origin),
+                ColorVertex::new(rect.top_right()),
+                ColorVertex::new(rect.bottom_left()),
+                ColorVertex::new(rect.bottom_right()),
+            ];
+            self.bind_and_render_solid_vertices(&vertices,
+                                                gl::TRIANGLE_STRIP,
+                                                transform,
+                                                projection,
+                                                color);
+            return;
+        }
+
+        let vertices: Vec<ColorVertex> =
+            clip_textured_rect_to_near_plane(rect, &Rect::zero(), transform)
+                .into_iter()
+                .map(|(point, _)| ColorVertex::new(point))
+                .collect();
+        if !vertices.is_empty() {
+            self.bind_and_render_solid_vertices(&vertices,
+                                                gl::TRIANGLE_FAN,
+                                                transform,
+                                                projection,
+                                                color);
+        }
+    }
+
+    fn bind_and_render_solid_vertices(&self,
+                                      vertices: &[ColorVertex],
+                                      mode: GLenum,
+                                      transform: &Matrix4D<f32>,
+                                      projection: &Matrix4D<f32>,
+                                      color: &Color) {
+        let gl = &*self.gl;
+        self.solid_color_program.enable_attribute_arrays(gl);
+        gl::use_program(gl, self.solid_color_program.program.id);
+        self.solid_color_program.bind_uniforms_and_attributes_for_quad(gl,
+                                                                       vertices,
+                                                                       transform,
+                                                                       projection,
+                                                                       &self.buffers,
+                                                                       color);
+        gl::draw_arrays(gl, mode, 0, vertices.len() as GLsizei);
+        record_draw_call(vertices);
+        self.solid_color_program.disable_attribute_arrays(gl);
+    }
+
+    /// Draws a textured quad. If a rounded clip is given, along with the transform mapping the
+    /// vertices to its screen space, fragments outside of it are discarded.
+    fn bind_and_render_quad(&self,
+                            vertices: &[TextureVertex; 4],
+                            texture: &Texture,
+                            transform: &Matrix4D<f32>,
+                            projection_matrix: &Matrix4D<f32>,
+                            opacity: f32,
+                            rounded_clip: Option<(&RoundedClip, &Matrix4D<f32>)>,
+                            color_matrix: Option<&ColorMatrix>) {
+        self.bind_and_render_vertices(vertices,
+                                      gl::TRIANGLE_STRIP,
+                                      texture,
+                                      transform,
+                                      projection_matrix,
+                                      opacity,
+                                      rounded_clip,
+                                      color_matrix);
+    }
+
+    /// Draws `texture_rect` of a texture, in normalized texture coordinates, into a rect. If
+    /// `clip_to_near_plane` is true, only the part of the rect in front of the near plane is
+    /// drawn, so that a layer turned away from the viewer doesn't project through the camera.
+    /// The sampled colors are transformed by `color_matrix`, if given.
+    fn bind_and_render_textured_rect(&self,
+                                     rect: &Rect<f32>,
+                                     texture_rect: &Rect<f32>,
+                                     clip_to_near_plane: bool,
+                                     texture: &Texture,
+                                     transform: &Matrix4D<f32>,
+                                     projection_matrix: &Matrix4D<f32>,
+                                     opacity: f32,
+                                     rounded_clip: Option<(&RoundedClip, &Matrix4D<f32>)>,
+                                     color_matrix: Option<&ColorMatrix>) {
+        if !clip_to_near_plane {
+            self.bind_and_render_quad(&texture_vertices(rect, texture_rect),
+                                      texture,
+                                      transform,
+                                      projection_matrix,
+                                      opacity,
+                                      rounded_clip,
+                                      color_matrix);
+            return;
+        }
+
+        let vertices: Vec<TextureVertex> =
+            clip_textured_rect_to_near_plane(rect, texture_rect, transform)
+                .into_iter()
+                .map(|(point, texture_point)| TextureVertex::new(point, texture_point))
+                .collect();
+        if !vertices.is_empty() {
+            self.bind_and_render_vertices(&vertices,
+                                          gl::TRIANGLE_FAN,
+                                          texture,
+                                          transform,
+                                          projection_matrix,
+                                          opacity,
+                                          rounded_clip,
+                                          color_matrix);
+        }
+    }
+
+    fn bind_and_render_vertices(&self,
+                                vertices: &[TextureVertex],
+                                mode: GLenum,
+                                texture: &Texture,
+                                transform: &Matrix4D<f32>,
+                                projection_matrix: &Matrix4D<f32>,
+                                opacity: f32,
+                                rounded_clip: Option<(&RoundedClip, &Matrix4D<f32>)>,
+                                color_matrix: Option<&ColorMatrix>) {
+        let gl = &*self.gl;
+        let texture_coordinates_need_to_be_scaled_by_size = match texture.target {
+            TextureTarget2D => false,
+            TextureTargetRectangle => true,
+        };
+        let color_matrix_program = color_matrix.and_then(|_| {
+            self.color_matrix_programs.select(texture.target, rounded_clip.is_some())
+        });
+        let rounded_clip_program = match (texture.target, rounded_clip, color_matrix_program) {
+            (_, None, _) => None,
+            (_, Some(_), Some(color_matrix_program)) => color_matrix_program.rounded_clip_program,
+            (TextureTarget2D, Some(_), None) => Some(self.rounded_clip_2d_program),
+            (TextureTargetRectangle, Some(_), None) => self.rounded_clip_rectangle_program,
+        };
+        let program = match (texture.target, color_matrix_program, rounded_clip_program) {
+            (_, Some(color_matrix_program), _) => color_matrix_program.texture_program,
+            (_, None, Some(rounded_clip_program)) => rounded_clip_program.texture_program,
+            (TextureTarget2D, None, None) => self.texture_2d_program,
+            (TextureTargetRectangle, None, None) => match self.texture_rectangle_program {
+                Some(program) => program,
+                None => {
+                    error!("Can't draw a rectangle texture without texture rectangle support");
+                    return;
+                }
+            },
+        };
+        program.enable_attribute_arrays(gl);
+
+        gl::use_program(gl, program.program.id);
+        gl::active_texture(gl, gl::TEXTURE0 + self.options.texture_unit);
+        gl::bind_texture(gl, texture.target.as_gl_target(), texture.native_texture());
+
+        let (mag_filter, min_filter) = self.texture_filters(texture);
+        gl::tex_parameter_i(gl, texture.target.as_gl_target(), gl::TEXTURE_MAG_FILTER, mag_filter);
+        gl::tex_parameter_i(gl, texture.target.as_gl_target(), gl::TEXTURE_MIN_FILTER, min_filter);
+
+        let texture_transform =
+            texture_coordinate_transform(texture,
+                                         texture_coordinates_need_to_be_scaled_by_size);
+
+        program.bind_uniforms_and_attributes(gl,
+                                             vertices,
+                                             transform,
+                                             &projection_matrix,
+                                             &texture_transform,
+                                             &self.buffers,
+                                             self.options.texture_unit,
+                                             opacity);
+        if let (Some(program), Some((clip, clip_transform))) = (rounded_clip_program,
+                                                                 rounded_clip) {
+            program.bind_clip(gl, clip, clip_transform);
+        }
+        if let (Some(program), Some(color_matrix)) = (color_matrix_program, color_matrix) {
+            program.bind_color_matrix(gl, color_matrix);
+        }
+
+        // Draw!
+        gl::draw_arrays(gl, mode, 0, vertices.len() as GLsizei);
+        record_draw_call(vertices);
+        gl::bind_texture(gl, gl::TEXTURE_2D, 0);
+
+        gl::bind_texture(gl, texture.target.as_gl_target(), 0);
+        program.disable_attribute_arrays(gl)
+    }
+
+    pub fn bind_and_render_quad_lines(&self,
+                                      vertices: &[ColorVertex; 5],
+                                      transform: &Matrix4D<f32>,
+                                      projection: &Matrix4D<f32>,
+                                      color: &Color,
+                                      line_thickness: usize) {
+        let gl = &*self.gl;
+        self.solid_color_program.enable_attribute_arrays(gl);
+        gl::use_program(gl, self.solid_color_program.program.id);
+        self.solid_color_program.bind_uniforms_and_attributes_for_lines(gl,
+                                                                        vertices,
+                                                                        transform,
+                                                                        projection,
+                                                                        &self.buffers,
+                                                                        color);
+        gl::line_width(gl, line_thickness as GLfloat);
+        gl::draw_arrays(gl, gl::LINE_STRIP, 0, 5);
+        record_draw_call(vertices);
+        self.solid_color_program.disable_attribute_arrays(gl);
+    }
+
+    fn render_layer<T>(&self,
+                       layer: Rc<Layer<T>>,
+                       transform: &Matrix4D<f32>,
+                       projection: &Matrix4D<f32>,
+                       clip_rect: Option<Rect<f32>>,
+                       rounded_clip: Option<&RoundedClip>,
+                       frame: &FrameInfo) {
+        let gl = &*self.gl;
+        if layer.culled.get() {
+            statistics::record(|statistics| statistics.culled_layer_count += 1);
+            return;
+        }
+
+        let ts = layer.transform_state.borrow();
+        let screen_transform = *transform;
+        let transform = transform.pre_mul(&ts.final_transform);
+        let opacity = ts.effective_opacity;
+        let background_color = *layer.background_color.borrow();
+
+        // Quads of a layer that crosses the near plane are clipped to it in the plane of the
+        // layer, where texture coordinates are still affine, rather than by the GPU after
+        // projection.
+        let clip_to_near_plane = ts.crosses_near_plane;
+
+        // Create native textures for this layer
+        if !self.read_only {
+            let mipmap_scale_threshold = if self.use_mipmaps {
+                Some(self.options.mipmap_scale_threshold)
+            } else {
+                None
+            };
+            layer.create_textures(&self.gl,
+                                  &self.compositing_display,
+                                  &self.capabilities,
+                                  mipmap_scale_threshold);
+        }
+
+        // The content inset shrinks the area in which the background and tiles are drawn.
+        let layer_rect = layer.clipped_content_rect(clip_rect);
+
+        if layer_rect.is_empty() || !rect_has_valid_size(&layer_rect) {
+            return;
+        }
+        statistics::record(|statistics| {
+            statistics.layer_count += 1;
+            if let Some(group_id) = layer.group_id.get() {
+                statistics.group_mut(group_id).layer_count += 1;
+            }
+        });
+
+        // Clipping the geometry alone lets filtered edges bleed past the clip rect, so also clip
+        // with the scissor test when the clip rect stays a rect on the screen.
+        let scissor_box = clip_rect.and_then(|clip_rect| {
+            scissor_box_for_clip_rect(gl, &clip_rect, &transform, projection)
+        });
+        if let Some(scissor_box) = scissor_box {
+            gl::enable(gl, gl::SCISSOR_TEST);
+            gl::scissor(gl,
+                        scissor_box[0],
+                        scissor_box[1],
+                        scissor_box[2] as GLsizei,
+                        scissor_box[3] as GLsizei);
+        }
+
+        let tile_clip_rect = if layer.content_inset() == SideOffsets2D::zero() {
+            clip_rect
+        } else {
+            Some(layer_rect)
+        };
+
+        if background_color.a != 0.0 && opacity != 0.0 {
+            // Blending expects premultiplied colors, so apply the alpha of the color and the
+            // opacity of the layer to all components.
+            let background_color = Color {
+                a: background_color.a * opacity,
+                ..background_color
+            }.premultiply();
+
+            // The shader clips only textured quads, so a clip that doesn't stay a rect in world
+            // space is applied to the background polygon in screen space instead.
+            let background_rect = background_rect(&*layer, &layer_rect, tile_clip_rect);
+            let screen_clipped_vertices = match (clip_rect, rounded_clip) {
+                (None, Some(rounded_clip)) => {
+                    clip_rect_in_screen_space(&background_rect,
+                                              &ts.final_transform,
+                                              ts.inverse_transform.as_ref(),
+                                              &rounded_clip.rect.to_untyped())
+                }
+                _ => None,
+            };
+            match screen_clipped_vertices {
+                Some(vertices) => {
+                    if !vertices.is_empty() {
+                        let vertices: Vec<_> =
+                            vertices.into_iter().map(ColorVertex::new).collect();
+                        self.bind_and_render_solid_vertices(&vertices,
+                                                            gl::TRIANGLE_FAN,
+                                                            &transform,
+                                                            &projection,
+                                                            &background_color);
+                    }
+                }
+                None => {
+                    self.bind_and_render_solid_rect(&background_rect,
+                                                    clip_to_near_plane,
+                                                    &transform,
+                                                    &projection,
+                                                    &background_color);
+                }
+            }
+        }
+
+        let debug_options = *layer.debug_options.borrow();
+        let show_debug_borders = self.debug_overlay_mode == DebugOverlayMode::Borders;
+        let show_tile_borders = show_debug_borders || debug_options.show_tile_borders;
+
+        // The rounded clip is in screen space, which the final transform maps to.
+        let rounded_clip = rounded_clip.map(|rounded_clip| (rounded_clip, &ts.final_transform));
+
+        // The filter applies to the content of this layer alone, not to its background or its
+        // children.
+        let filter = *layer.filter.borrow();
+
+        if layer.is_nine_patch() {
+            let world_rect = ts.world_rect.to_untyped();
+            layer.with_nine_patch(|texture, source_size, insets| {
+                for (rect, texture_rect) in nine_patch_quads(&world_rect.size,
+                                                             &source_size,
+                                                             insets) {
+                    let rect = rect.translate(&world_rect.origin);
+                    if let Some((rect, texture_rect)) =
+                            clip_texture_rect(&rect, &texture_rect, tile_clip_rect) {
+                        self.bind_and_render_textured_rect(&rect,
+                                                           &texture_rect,
+                                                           clip_to_near_plane,
+                                                           texture,
+                                                           &transform,
+                                                           projection,
+                                                           opacity,
+                                                           rounded_clip,
+                                                           filter.as_ref());
+                    }
+                }
+            });
+        } else {
+            // The tiles of a layer in a group are also counted for the group.
+            let tile_counts = layer.group_id.get().map(|group_id| {
+                (group_id, statistics::drawn_and_skipped_tile_counts())
+            });
+            let content_age = *layer.content_age.borrow();
+            layer.do_for_all_tiles(|tile: &Tile| {
+               self.render_tile(tile,
+                                &ts.world_rect.origin.to_untyped(),
+                                &transform,
+                                projection,
+                                tile_clip_rect,
+                                clip_to_near_plane,
+                                rounded_clip,
+                                filter.as_ref(),
+                                opacity,
+                                show_tile_borders,
+                                content_age,
+                                frame);
+            });
+            if let Some((group_id, (drawn_before, skipped_before))) = tile_counts {
+                statistics::record(|statistics| {
+                    let (drawn, skipped) = (statistics.tile_count, statistics.skipped_tile_count);
+                    let group = statistics.group_mut(group_id);
+                    group.tile_count += drawn - drawn_before;
+                    group.skipped_tile_count += skipped - skipped_before;
+                });
+            }
+        }
+
+        if scissor_box.is_some() {
+            gl::disable(gl, gl::SCISSOR_TEST);
+        }
+
+        if let Some(ref label_color) = debug_options.label_color {
+            self.bind_and_render_solid_rect(&layer_rect,
+                                            clip_to_near_plane,
+                                            &transform,
+                                            &projection,
+                                            label_color);
+        }
+
+        if show_debug_borders || debug_options.show_borders {
+            let debug_vertices = [
+                ColorVertex::new(layer_rect.origin),
+                ColorVertex::new(layer_rect.top_right()),
+                ColorVertex::new(layer_rect.bottom_right()),
+                ColorVertex::new(layer_rect.bottom_left()),
+                ColorVertex::new(layer_rect.origin),
+            ];
+            self.bind_and_render_quad_lines(&debug_vertices,
+                                            &transform,
+                                            projection,
+                                            &LAYER_BORDER_COLOR,
+                                            LAYER_BORDER_THICKNESS);
+        }
+
+        if show_debug_borders || debug_options.show_aabb {
+            let aabb = ts.screen_rect.as_ref().unwrap().rect.to_untyped();
+            let debug_vertices = [
+                ColorVertex::new(aabb.origin),
+                ColorVertex::new(aabb.top_right()),
+                ColorVertex::new(aabb.bottom_right()),
+                ColorVertex::new(aabb.bottom_left()),
+                ColorVertex::new(aabb.origin),
+            ];
+            self.bind_and_render_quad_lines(&debug_vertices,
+                                            &screen_transform,
+                                            projection,
+                                            &LAYER_AABB_BORDER_COLOR,
+                                            LAYER_AABB_BORDER_THICKNESS);
+        }
+    }
+
+    fn render_tile(&self,
+                   tile: &Tile,
+                   layer_origin: &Point2D<f32>,
+                   transform: &Matrix4D<f32>,
+                   projection: &Matrix4D<f32>,
+                   clip_rect: Option<Rect<f32>>,
+                   clip_to_near_plane: bool,
+                   rounded_clip: Option<(&RoundedClip, &Matrix4D<f32>)>,
+                   color_matrix: Option<&ColorMatrix>,
+                   opacity: f32,
+                   show_tile_border: bool,
+                   content_age: ContentAge,
+                   frame: &FrameInfo) {
+        if !tile.is_drawable() {
+            statistics::record(|statistics| statistics.skipped_tile_count += 1);
+            return;
+        }
+
+        let tile_rect = tile.bounds.unwrap().to_untyped().translate(layer_origin);
+        let clip_rect = tile.combined_clip_rect(layer_origin, clip_rect);
+        let whole_texture_rect = Rect::new(Point2D::zero(), Size2D::new(1.0, 1.0));
+        let (clipped_tile_rect, texture_rect) =
+            match clip_texture_rect(&tile_rect, &whole_texture_rect, clip_rect) {
+                Some(rects) => rects,
+                None => return,
+            };
+
+        self.bind_and_render_textured_rect(&clipped_tile_rect,
+                                           &texture_rect,
+                                           clip_to_near_plane,
+                                           &tile.texture,
+                                           &transform,
+                                           projection,
+                                           opacity,
+                                           rounded_clip,
+                                           color_matrix);
+        if self.show_stale_tiles && tile.is_stale(content_age) {
+            self.bind_and_render_solid_rect(&clipped_tile_rect,
+                                            clip_to_near_plane,
+                                            &transform,
+                                            projection,
+                                            &STALE_TILE_TINT_COLOR);
+        }
+        if let Some(heatmap_color) = heatmap_color(self.debug_overlay_mode, tile, content_age) {
+            self.bind_and_render_solid_rect(&clipped_tile_rect,
+                                            clip_to_near_plane,
+                                            &transform,
+                                            projection,
+                                            &heatmap_color);
+        }
+
+        // The border goes over the tile, so that opaque content can't hide it.
+        if show_tile_border {
+            let debug_vertices = [
+                // The weird ordering is converting from triangle-strip into a line-strip.
+                ColorVertex::new(clipped_tile_rect.origin),
+                ColorVertex::new(clipped_tile_rect.top_right()),
+                ColorVertex::new(clipped_tile_rect.bottom_right()),
+                ColorVertex::new(clipped_tile_rect.bottom_left()),
+                ColorVertex::new(clipped_tile_rect.origin),
+            ];
+            self.bind_and_render_quad_lines(&debug_vertices,
+                                            &transform,
+                                            projection,
+                                            &TILE_BORDER_COLOR,
+                                            TILE_BORDER_THICKNESS);
+        }
+        if !self.read_only {
+            tile.last_drawn_frame.set(Some(frame.index));
+        }
+        statistics::record(|statistics| statistics.tile_count += 1);
+    }
+
+    /// Renders the subtree rooted at `root` into a thumbnail of the given size, returning tightly
+    /// packed RGBA rows ordered from top to bottom. The subtree is drawn offscreen at no more than
+    /// twice the thumbnail resolution and then filtered down, which avoids aliasing without
+    /// reading back the whole window. The transform state of the subtree must be up to date.
+    ///
+    /// No tiles are requested or thrown away, and all GL state that is changed is restored. The
+    /// layers are drawn read-only: tiles whose textures haven't been created by a frame yet are
+    /// left out, and no tile records the thumbnail as the frame it was last drawn in.
+    pub fn render_thumbnail<T>(&self, root: Rc<Layer<T>>, target_size: Size2D<u32>)
+                               -> Result<Vec<u8>, RenderError> {
+        let gl = &*self.gl;
+        if target_size.width == 0 || target_size.height == 0 {
+            return Err(RenderError::EmptyTarget);
+        }
+        let source_rect = root.transform_state.borrow().world_rect.to_untyped();
+        if source_rect.is_empty() {
+            return Err(RenderError::EmptyLayer);
+        }
+
+        let saved_gl_state = SavedGlState::save(gl);
+        let saved_texture_state = SavedTextureState::save(gl,
+                                                          self.options.texture_unit,
+                                                          &self.capabilities);
+
+        let result = self.render_thumbnail_passes(root, &source_rect, target_size);
+
+        gl::active_texture(gl, gl::TEXTURE0 + self.options.texture_unit);
+        saved_texture_state.restore(gl);
+        saved_gl_state.restore(gl);
+        result
+    }
+
+    fn render_thumbnail_passes<T>(&self,
+                                  root: Rc<Layer<T>>,
+                                  source_rect: &Rect<f32>,
+                                  target_size: Size2D<u32>)
+                                  -> Result<Vec<u8>, RenderError> {
+        let gl = &*self.gl;
+        let target_width = target_size.width as f32;
+        let target_height = target_size.height as f32;
+        let x_scale = target_width / source_rect.size.width;
+        let y_scale = target_height / source_rect.size.height;
+        let scale = match self.options.thumbnail_aspect {
+            ThumbnailAspect::Letterbox => x_scale.min(y_scale),
+            ThumbnailAspect::Crop => x_scale.max(y_scale),
+        };
+
+        // Draw the layers at no more than twice the final scale, so that a single linearly
+        // filtered pass can bring them down to the thumbnail size.
+        let mut intermediate_scale = (scale * 2.0).min(1.0);
+        let max_texture_size = self.capabilities.max_texture_size as f32;
+        if max_texture_size > 0.0 {
+            intermediate_scale = intermediate_scale.min(max_texture_size / source_rect.size.width)
+                                                   .min(max_texture_size / source_rect.size.height);
+        }
+        let intermediate_size =
+            Size2D::new((source_rect.size.width * intermediate_scale).ceil().max(1.0),
+                        (source_rect.size.height * intermediate_scale).ceil().max(1.0));
+
+        let frame = self.last_frame().unwrap_or(FrameInfo {
+            index: FrameIndex(0),
+            time: 0.0,
+        });
+
+        // First pass: draw the subtree into the intermediate target.
+        let mut intermediate =
+            try!(OffscreenTarget::new(self.gl.clone(),
+                                      Size2D::new(intermediate_size.width as usize,
+                                                  intermediate_size.height as usize),
+                                      true));
+        gl::enable(gl, gl::DEPTH_TEST);
+        gl::depth_func(gl, gl::LEQUAL);
+        gl::clear_color(gl, 0.0, 0.0, 0.0, 0.0);
+        gl::clear(gl, gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+
+        let transform = Matrix4D::identity()
+            .pre_scaled(intermediate_scale, intermediate_scale, 1.0)
+            .pre_translated(-source_rect.origin.x, -source_rect.origin.y, 0.0);
+        let projection = create_ortho(&intermediate_size);
+        let read_only_context = RenderContext {
+            read_only: true,
+            ..self.clone()
+        };
+        let _ = context3d::render_3d_context(&read_only_context,
+                                             &RenderContext3D::new(root),
+                                             &transform,
+                                             &projection,
+                                             &frame);
+
+        // Second pass: filter the intermediate target down to the thumbnail, centered.
+        let target = try!(OffscreenTarget::new(self.gl.clone(),
+                                               Size2D::new(target_size.width as usize,
+                                                           target_size.height as usize),
+                                               false));
+        gl::disable(gl, gl::DEPTH_TEST);
+        gl::clear(gl, gl::COLOR_BUFFER_BIT);
+
+        let scaled_size = Size2D::new(source_rect.size.width * scale,
+                                      source_rect.size.height * scale);
+        let origin = Point2D::new((target_width - scaled_size.width) / 2.0,
+                                  (target_height - scaled_size.height) / 2.0);
+        let destination_rect = Rect::new(origin, scaled_size);
+        let vertices = [
+            TextureVertex::new(destination_rect.origin, Point2D::new(0.0, 0.0)),
+            TextureVertex::new(destination_rect.top_right(), Point2D::new(1.0, 0.0)),
+            TextureVertex::new(destination_rect.bottom_left(), Point2D::new(0.0, 1.0)),
+            TextureVertex::new(destination_rect.bottom_right(), Point2D::new(1.0, 1.0)),
+        ];
+
+        // The intermediate target was drawn with the first row at the bottom.
+        intermediate.texture.flip = VerticalFlip;
+        let projection = create_ortho(&Size2D::new(target_width, target_height));
+        self.bind_and_render_quad(&vertices,
+                                  &intermediate.texture,
+                                  &Matrix4D::identity(),
+                                  &projection,
+                                  1.0,
+                                  None,
+                                  None);
+
+        Ok(target.read_pixels())
+    }
+}
+
+/// Clips `rect` to `clip_rect`, and the part of the texture drawn into it, given in normalized
+/// texture coordinates, along with it. Returns None if nothing is left to draw.
+fn clip_texture_rect(rect: &Rect<f32>,
+                     texture_rect: &Rect<f32>,
+                     clip_rect: Option<Rect<f32>>)
+                     -> Option<(Rect<f32>, Rect<f32>)> {
+    let clipped_rect = match clip_rect {
+        Some(clip_rect) => clip_rect.intersection(rect).unwrap_or(Rect::zero()),
+        None => *rect,
+    };
+    // Dividing by an empty or invalid size would give NaN texture coordinates.
+    if clipped_rect.is_empty() || !rect_has_valid_size(&clipped_rect) ||
+       rect.is_empty() || !rect_has_valid_size(rect) {
+        return None;
+    }
+
+    let x_scale = texture_rect.size.width / rect.size.width;
+    let y_scale = texture_rect.size.height / rect.size.height;
+    let clipped_texture_rect = Rect::new(
+        Point2D::new(texture_rect.origin.x + (clipped_rect.origin.x - rect.origin.x) * x_scale,
+                     texture_rect.origin.y + (clipped_rect.origin.y - rect.origin.y) * y_scale),
+        Size2D::new(clipped_rect.size.width * x_scale, clipped_rect.size.height * y_scale));
+    Some((clipped_rect, clipped_texture_rect))
+}
+
+/// Returns the rect in which the background of a layer is drawn: the clipped content rect, grown
+/// to the clipped rects of the tiles drawn over it. At fractional scales the edges of the tiles
+/// don't fall exactly on the edges of the content rect, and drawing the background up to the
+/// same edges leaves no sliver between the two.
+fn background_rect<T>(layer: &Layer<T>, layer_rect: &Rect<f32>, tile_clip_rect: Option<Rect<f32>>)
+                      -> Rect<f32> {
+    let layer_origin = layer.transform_state.borrow().world_rect.origin.to_untyped();
+    let mut background_rect = *layer_rect;
+    layer.do_for_all_tiles(|tile: &Tile| {
+        if !tile.is_drawable() {
+            return;
+        }
+        let tile_rect = tile.bounds.unwrap().to_untyped().translate(&layer_origin);
+        let drawn_rect = match tile.combined_clip_rect(&layer_origin, tile_clip_rect) {
+            Some(tile_clip_rect) => tile_clip_rect.intersection(&tile_rect),
+            None => Some(tile_rect),
+        };
+        if let Some(drawn_rect) = drawn_rect {
+            background_rect = background_rect.union(&drawn_rect);
+        }
+    });
+    background_rect
+}
+
+/// Clips a world-space rect of a layer with a 2D transform to a screen-space clip rect. Returns
+/// the corners of the clipped polygon in world space, in triangle fan order, or None if the
+/// transform isn't 2D or can't be inverted.
+fn clip_rect_in_screen_space(rect: &Rect<f32>,
+                             transform: &Matrix4D<f32>,
+                             inverse_transform: Option<&Matrix4D<f32>>,
+                             clip_rect: &Rect<f32>)
+                             -> Option<Vec<Point2D<f32>>> {
+    let inverse_transform = match inverse_transform {
+        Some(inverse_transform) if is_2d_transform(transform) => inverse_transform.to_2d(),
+        _ => return None,
+    };
+    let transform = transform.to_2d();
+    let corners = [rect.origin, rect.top_right(), rect.bottom_right(), rect.bottom_left()];
+    let screen_corners: Vec<_> =
+        corners.iter().map(|corner| transform.transform_point(corner)).collect();
+    Some(clip_polygon_to_rect(&screen_corners, clip_rect).iter().map(|point| {
+        inverse_transform.transform_point(point)
+    }).collect())
+}
+
+/// Returns the vertices of a textured quad, in triangle strip order.
+fn texture_vertices(rect: &Rect<f32>, texture_rect: &Rect<f32>) -> [TextureVertex; 4] {
+    [
+        TextureVertex::new(rect.origin, texture_rect.origin),
+        TextureVertex::new(rect.top_right(), texture_rect.top_right()),
+        TextureVertex::new(rect.bottom_left(), texture_rect.bottom_left()),
+        TextureVertex::new(rect.bottom_right(), texture_rect.bottom_right()),
+    ]
+}
+
+/// Counts a draw call and the vertex data uploaded for it.
+fn record_draw_call<V>(vertices: &[V]) {
+    statistics::record(|statistics| {
+        statistics.draw_call_count += 1;
+        statistics.vertex_bytes += mem::size_of_val(vertices);
+    })
+}
+
+fn delete_programs(gl: &GlApi, program_ids: &[GLuint]) {
+    for program_id in program_ids {
+        gl::delete_program(gl, *program_id);
+    }
+}
+
+/// Returns the transformation matrix for the texture coordinates of the given texture, which
+/// rotates and flips the texture as it requests and, for GL_ARB_texture_rectangle, scales the
+/// coordinates to its size. Transforms are applied to the coordinates in the reverse of the order
+/// they are added in here.
+fn texture_coordinate_transform(texture: &Texture, scale_by_size: bool) -> Matrix4D<f32> {
+    let mut texture_transform = Matrix4D::identity();
+    if scale_by_size {
+        texture_transform = texture_transform.pre_scaled(texture.size.width as f32,
+                                                         texture.size.height as f32,
+                                                         1.0);
+    }
+    if texture.flip.is_horizontal() {
+        texture_transform = texture_transform.pre_translated(1.0, 0.0, 0.0)
+                                             .pre_scaled(-1.0, 1.0, 1.0);
+    }
+    if texture.flip.is_vertical() {
+        texture_transform = texture_transform.pre_translated(0.0, 1.0, 0.0)
+                                             .pre_scaled(1.0, -1.0, 1.0);
+    }
+
+    // Rotating the texture clockwise on the screen means sampling it counterclockwise. Each
+    // rotation maps the unit square onto itself, and the scale above is applied afterwards, so
+    // rectangle textures are scaled along their own axes whatever the rotation.
+    let rotation = match texture.rotation {
+        Rotation::NoRotation => return texture_transform,
+        Rotation::Rotate90 => Matrix4D::row_major(0.0, -1.0, 0.0, 0.0,
+                                            1.0, 0.0, 0.0, 0.0,
+                                            0.0, 0.0, 1.0, 0.0,
+                                            0.0, 1.0, 0.0, 1.0),
+        Rotation::Rotate180 => Matrix4D::row_major(-1.0, 0.0, 0.0, 0.0,
+                                             0.0, -1.0, 0.0, 0.0,
+                                             0.0, 0.0, 1.0, 0.0,
+                                             1.0, 1.0, 0.0, 1.0),
+        Rotation::Rotate270 => Matrix4D::row_major(0.0, 1.0, 0.0, 0.0,
+                                             -1.0, 0.0, 0.0, 0.0,
+                                             0.0, 0.0, 1.0, 0.0,
+                                             1.0, 0.0, 0.0, 1.0),
+    };
+    texture_transform.pre_mul(&rotation)
+}
+
+/// Returns the scissor box, in window coordinates, that clips to the given world-space rect when
+/// drawing with the given transform and orthographic projection, or None if the transform is not
+/// axis-aligned.
+fn scissor_box_for_clip_rect(gl: &GlApi,
+                             clip_rect: &Rect<f32>,
+                             transform: &Matrix4D<f32>,
+                             projection: &Matrix4D<f32>)
+                             -> Option<[GLint; 4]> {
+    if !is_axis_aligned_2d_transform(transform) {
+        return None;
+    }
+
+    // Map the rect to normalized device coordinates and from there to the GL viewport, whose
+    // window coordinates start at the bottom left of the window.
+    let viewport = gl::get_integer_4v(gl, gl::VIEWPORT);
+    let window_x = |x: f32| {
+        let x = (x * transform.m11 + transform.m41) * projection.m11 + projection.m41;
+        (viewport[0] as f32 + (x + 1.0) * 0.5 * viewport[2] as f32).round() as GLint
+    };
+    let window_y = |y: f32| {
+        let y = (y * transform.m22 + transform.m42) * projection.m22 + projection.m42;
+        (viewport[1] as f32 + (y + 1.0) * 0.5 * viewport[3] as f32).round() as GLint
+    };
+    let (min_x, max_x) = (window_x(clip_rect.min_x()), window_x(clip_rect.max_x()));
+    let (min_y, max_y) = (window_y(clip_rect.max_y()), window_y(clip_rect.min_y()));
+    Some([min_x, min_y, (max_x - min_x).max(0), (max_y - min_y).max(0)])
+}
+
+/// Draws a single layer without its children. See `RenderContext::render_layer`.
+pub fn render_layer<T>(render_context: &RenderContext,
+                       layer: Rc<Layer<T>>,
+                       transform: &Matrix4D<f32>,
+                       projection: &Matrix4D<f32>,
+                       clip_rect: Option<Rect<f32>>,
+                       rounded_clip: Option<&RoundedClip>,
+                       frame: &FrameInfo) {
+    render_context.render_layer(layer, transform, projection, clip_rect, rounded_clip, frame)
+}
+
+/// Deletes the shader programs and vertex buffers owned by a render context, along with the pixel
+/// buffer objects of the current thread.
+pub fn destroy(render_context: RenderContext) {
+    render_context.destroy()
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use color::Color;
+    use debug_overlay::DebugOverlayMode;
+    use euclid::Matrix4D;
+    use euclid::point::{Point2D, Point3D, TypedPoint2D};
+    use euclid::Radians;
+    use euclid::rect::{Rect, TypedRect};
+    use euclid::scale_factor::ScaleFactor;
+    use euclid::size::{Size2D, TypedSize2D};
+    use frame::FrameIndex;
+    use gltrace::{self as gl, GLint, GlCall, RecordingGl};
+    use layers::{Layer, LayerDebugOptions};
+    use scene::Scene;
+    use std::f32::consts::PI;
+    use std::rc::Rc;
+    use super::{RenderContext, clip_rect_in_screen_space, texture_coordinate_transform};
+    use super::texture_vertices;
+    use super::super::programs::ShaderError;
+    use super::super::scene_render::render_scene;
+    use test_util::{buffer_for_request, headless_display, layer, record_gl_calls};
+    use texturegl::{FenceQueue, Flip, Rotation, Texture, TextureTarget};
+
+    /// Returns a scene whose root layer has a single painted tile, along with the root.
+    fn painted_scene() -> (Scene<()>, Rc<Layer<()>>) {
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(256.0, 256.0)));
+        scene.root = Some(root.clone());
+        let mut requests = vec!();
+        let mut unused_buffers = vec!();
+        scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+        for request in &requests[0].1 {
+            assert!(scene.add_buffer(&root, buffer_for_request(request), FrameIndex(0)));
+        }
+        (scene, root)
+    }
+
+    /// Draws a painted 256x256 layer at the given scale, and returns the number of times mipmaps
+    /// were generated and the filters the tile was last drawn with when magnified and minified.
+    fn texture_filters(scale: f32, use_mipmaps: bool, force_near_texture_filter: bool)
+                       -> (usize, Option<(GLint, GLint)>) {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(),
+                                       headless_display(),
+                                       false,
+                                       force_near_texture_filter);
+        render_context.set_use_mipmaps(use_mipmaps);
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(256.0, 256.0)));
+        scene.root = Some(root.clone());
+        scene.scale = ScaleFactor::new(scale);
+        let mut requests = vec!();
+        scene.get_buffer_requests(&mut requests, &mut vec!());
+        for request in &requests[0].1 {
+            assert!(scene.add_buffer(&root, buffer_for_request(request), FrameIndex(0)));
+        }
+        gl.take_calls();
+
+        render_context.begin_frame(0.0);
+        render_scene(root, render_context.clone(), &scene);
+        let calls = gl.take_calls();
+        let generated_mipmaps = calls.iter().filter(|call| match **call {
+            GlCall::GenerateMipmap(gl::TEXTURE_2D) => true,
+            _ => false,
+        }).count();
+
+        // Textures are created with default parameters before they are drawn.
+        let last_filter = |name| calls.iter().filter_map(|call| match *call {
+            GlCall::TexParameteri(_, pname, filter) if pname == name => Some(filter),
+            _ => None,
+        }).last();
+        let filters = match (last_filter(gl::TEXTURE_MAG_FILTER),
+                             last_filter(gl::TEXTURE_MIN_FILTER)) {
+            (Some(mag_filter), Some(min_filter)) => Some((mag_filter, min_filter)),
+            _ => None,
+        };
+        (generated_mipmaps, filters)
+    }
+
+    #[test]
+    fn zoomed_out_tiles_are_minified_with_mipmaps_if_asked_to() {
+        let linear = (gl::LINEAR as GLint, gl::LINEAR as GLint);
+        let nearest = (gl::NEAREST as GLint, gl::NEAREST as GLint);
+        let trilinear = (gl::LINEAR as GLint, gl::LINEAR_MIPMAP_LINEAR as GLint);
+        assert_eq!(texture_filters(0.25, false, false), (0, Some(linear)));
+        assert_eq!(texture_filters(0.25, true, false), (1, Some(trilinear)));
+
+        // Tiles painted at or above the threshold get no mipmaps.
+        assert_eq!(texture_filters(0.5, true, false), (0, Some(linear)));
+        assert_eq!(texture_filters(1.0, true, false), (0, Some(linear)));
+
+        // Forcing the nearest filter takes precedence.
+        assert_eq!(texture_filters(0.25, true, true), (1, Some(nearest)));
+        assert_eq!(texture_filters(1.0, false, true), (0, Some(nearest)));
+    }
+
+    fn first_tile_state(layer: &Layer<()>) -> (bool, Option<FrameIndex>) {
+        layer.with_tile(&Point2D::new(0, 0), |tile, _| {
+            let tile = tile.unwrap();
+            (tile.texture.is_zero(), tile.last_drawn_frame.get())
+        })
+    }
+
+    fn draw_call_count(calls: &[GlCall]) -> usize {
+        calls.iter().filter(|call| match **call {
+            GlCall::DrawArrays(..) => true,
+            _ => false,
+        }).count()
+    }
+
+    #[test]
+    fn beginning_a_frame_completes_the_tokens_of_signaled_fences() {
+        let gl = record_gl_calls();
+        gl.set_string(gl::VERSION, "3.3.0");
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let token = FenceQueue::insert_for_current_thread(&*gl);
+        render_context.begin_frame(0.0);
+        assert!(!token.is_complete());
+
+        gl.signal_fences();
+        render_context.begin_frame(1.0 / 60.0);
+        assert!(token.is_complete());
+    }
+
+    #[test]
+    fn shader_failures_are_returned_without_leaking_programs() {
+        let gl = record_gl_calls();
+
+        // The rounded clip programs are created after the plain ones.
+        gl.fail_shaders_containing("#define ROUNDED_CLIP");
+        match RenderContext::try_new_with_gl(gl.clone(), headless_display(), false, false) {
+            Err(ShaderError::Compile { ref source, .. }) => {
+                assert!(source.contains("#define ROUNDED_CLIP"))
+            }
+            _ => panic!("expected a compile error"),
+        }
+        let programs_left = |calls: &[GlCall]| {
+            calls.iter().fold(0, |count, call| match *call {
+                GlCall::CreateProgram(..) => count + 1,
+                GlCall::DeleteProgram(..) => count - 1,
+                _ => count,
+            })
+        };
+        let calls = gl.take_calls();
+        assert!(calls.iter().any(|call| match *call {
+            GlCall::DeleteProgram(..) => true,
+            _ => false,
+        }));
+        assert_eq!(programs_left(&calls), 0);
+
+        let gl = record_gl_calls();
+        gl.fail_links();
+        match RenderContext::try_new_with_gl(gl.clone(), headless_display(), false, false) {
+            Err(ShaderError::Link { ref info_log, .. }) => assert_eq!(info_log, "link failed"),
+            _ => panic!("expected a link error"),
+        }
+        assert_eq!(programs_left(&gl.take_calls()), 0);
+    }
+
+    #[test]
+    fn opacity_multiplies_down_the_tree() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let (mut scene, root) = painted_scene();
+        let child = layer(0.0, 0.0, 128.0, 128.0, 256);
+        root.add_child(child.clone());
+        let mut requests = vec!();
+        let mut unused_buffers = vec!();
+        scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+        for &(ref layer, ref layer_requests) in &requests {
+            for request in layer_requests {
+                assert!(scene.add_buffer(layer, buffer_for_request(request), FrameIndex(0)));
+            }
+        }
+        root.set_opacity(0.5);
+        child.set_opacity(0.5);
+
+        let opacity_locations: Vec<_> = gl.take_calls().iter().filter_map(|call| match *call {
+            GlCall::GetUniformLocation(_, ref name, location) if name == "uOpacity" => {
+                Some(location)
+            }
+            _ => None,
+        }).collect();
+        render_context.begin_frame(0.0);
+        render_scene(root.clone(), render_context.clone(), &scene);
+        let tile_opacities: Vec<_> = gl.take_calls().iter().filter_map(|call| match *call {
+            GlCall::Uniform1f(location, value) if opacity_locations.contains(&location) => {
+                Some(value)
+            }
+            _ => None,
+        }).collect();
+        assert_eq!(tile_opacities, vec!(0.5, 0.25));
+        assert_eq!(child.transform_state.borrow().effective_opacity, 0.25);
+    }
+
+    #[test]
+    fn backgrounds_are_premultiplied_with_the_layer_opacity() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let color_locations: Vec<_> = gl.take_calls().iter().filter_map(|call| match *call {
+            GlCall::GetUniformLocation(_, ref name, location) if name == "uColor" => {
+                Some(location)
+            }
+            _ => None,
+        }).collect();
+        let root = Rc::new(Layer::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                     TypedSize2D::new(64.0, 64.0)),
+                                      256,
+                                      Color { r: 1.0, g: 0.5, b: 0.0, a: 0.5 },
+                                      0.5,
+                                      false,
+                                      ()));
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(64.0, 64.0)));
+        scene.root = Some(root.clone());
+
+        // Like the pixels of tiles, the color is premultiplied by its alpha and the opacity.
+        render_context.begin_frame(0.0);
+        render_scene(root, render_context.clone(), &scene);
+        let colors: Vec<_> = gl.take_calls().iter().filter_map(|call| match *call {
+            GlCall::Uniform4f(location, r, g, b, a) if color_locations.contains(&location) => {
+                Some((r, g, b, a))
+            }
+            _ => None,
+        }).collect();
+        assert_eq!(colors, vec!((0.25, 0.125, 0.0, 0.25)));
+    }
+
+    #[test]
+    fn stale_tiles_are_tinted_if_asked_to() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let (scene, root) = painted_scene();
+        let render = |render_context: &mut RenderContext, time: f64| {
+            render_context.begin_frame(time);
+            render_scene(root.clone(), render_context.clone(), &scene);
+            draw_call_count(&gl.take_calls())
+        };
+        render_context.set_show_stale_tiles(true);
+        assert_eq!(render(&mut render_context, 0.0), 1);
+
+        // Once the content changes, the old tile is drawn with a tint over it until the new
+        // buffer arrives.
+        root.contents_changed();
+        assert_eq!(render(&mut render_context, 1.0), 2);
+        render_context.set_show_stale_tiles(false);
+        assert_eq!(render(&mut render_context, 2.0), 1);
+    }
+
+    #[test]
+    fn heatmap_quads_are_drawn_only_in_heatmap_modes() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let (scene, root) = painted_scene();
+        let mut render = |mode: DebugOverlayMode, time: f64| {
+            render_context.set_debug_overlay_mode(mode);
+            render_context.begin_frame(time);
+            render_scene(root.clone(), render_context.clone(), &scene);
+            let calls = gl.take_calls();
+            let colors: Vec<_> = calls.iter().filter_map(|call| match *call {
+                GlCall::Uniform4f(_, r, g, b, a) => Some((r, g, b, a)),
+                _ => None,
+            }).collect();
+            (draw_call_count(&calls), colors)
+        };
+        let fresh_tint = (0.0, 0.4, 0.0, 0.4);
+
+        // Only the tile is drawn without a heatmap, and borders draw no solid quads over it.
+        assert_eq!(render(DebugOverlayMode::None, 0.0), (1, vec!()));
+        let (_, border_colors) = render(DebugOverlayMode::Borders, 1.0);
+        assert!(!border_colors.contains(&fresh_tint));
+
+        // The fresh tile painted once is tinted green by both heatmaps.
+        assert_eq!(render(DebugOverlayMode::ContentAgeHeatmap, 2.0), (2, vec!(fresh_tint)));
+        assert_eq!(render(DebugOverlayMode::PaintCountHeatmap, 3.0), (2, vec!(fresh_tint)));
+
+        // A content age behind, the tile turns a little yellow in the content age heatmap only.
+        root.contents_changed();
+        let (draw_calls, colors) = render(DebugOverlayMode::ContentAgeHeatmap, 4.0);
+        assert_eq!((draw_calls, colors.len()), (2, 1));
+        assert!((colors[0].0 - 0.1).abs() < 0.001 && colors[0].1 == 0.4, "{:?}", colors);
+        assert_eq!(render(DebugOverlayMode::PaintCountHeatmap, 5.0), (2, vec!(fresh_tint)));
+        assert_eq!(render(DebugOverlayMode::None, 6.0), (1, vec!()));
+    }
+
+    /// Returns the units selected by the ActiveTexture calls among `calls`, as offsets from
+    /// `GL_TEXTURE0`.
+    fn active_texture_units(calls: &[GlCall]) -> Vec<u32> {
+        calls.iter().filter_map(|call| match *call {
+            GlCall::ActiveTexture(texture) => Some(texture - gl::TEXTURE0),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn layer_textures_are_sampled_from_the_configured_unit() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let mut options = render_context.options();
+        options.texture_unit = 3;
+        render_context.set_options(options);
+        assert_eq!(options.secondary_texture_unit(), 4);
+        let (scene, root) = painted_scene();
+        gl.take_calls();
+
+        render_context.begin_frame(0.0);
+        render_scene(root, render_context.clone(), &scene);
+        let calls = gl.take_calls();
+        let units = active_texture_units(&calls);
+        assert!(!units.is_empty());
+        assert!(units.iter().all(|unit| *unit == 3), "units: {:?}", units);
+
+        // The sampler of every textured draw reads from the same unit.
+        let samplers: Vec<_> = calls.iter().filter_map(|call| match *call {
+            GlCall::Uniform1i(_, value) => Some(value),
+            _ => None,
+        }).collect();
+        assert!(!samplers.is_empty());
+        assert!(samplers.iter().all(|sampler| *sampler == 3), "samplers: {:?}", samplers);
+        assert_eq!(draw_call_count(&calls), 1);
+    }
+
+    #[test]
+    fn the_active_unit_and_its_binding_are_restored_after_rendering() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let mut options = render_context.options();
+        options.texture_unit = 2;
+        options.preserve_embedder_gl_state = true;
+        render_context.set_options(options);
+        let (scene, root) = painted_scene();
+        gl.set_integer(gl::ACTIVE_TEXTURE, (gl::TEXTURE0 + 5) as i32);
+        gl.set_integer(gl::TEXTURE_BINDING_2D, 42);
+        gl.take_calls();
+
+        // The state is saved for the whole frame, and restored when it ends.
+        render_context.begin_frame(0.0);
+        render_scene(root, render_context.clone(), &scene);
+        assert!(render_context.has_saved_embedder_gl_state());
+        render_context.end_frame();
+        let calls = gl.take_calls();
+
+        // The active unit is queried first, then ours is selected and its binding queried.
+        assert_eq!(&calls[..3],
+                   &[GlCall::GetIntegerv(gl::ACTIVE_TEXTURE, (gl::TEXTURE0 + 5) as i32),
+                     GlCall::ActiveTexture(gl::TEXTURE0 + 2),
+                     GlCall::GetIntegerv(gl::TEXTURE_BINDING_2D, 42)][..]);
+
+        // After the last draw, the binding is restored on our unit, and then the unit.
+        let last_draw = calls.iter().rposition(|call| match *call {
+            GlCall::DrawArrays(..) => true,
+            _ => false,
+        }).unwrap();
+        let restore = &calls[last_draw..];
+        let bind = restore.iter().rposition(|call| {
+            *call == GlCall::BindTexture(gl::TEXTURE_2D, 42)
+        }).unwrap();
+        assert_eq!(active_texture_units(&restore[..bind]).last(), Some(&2));
+        assert_eq!(active_texture_units(&restore[bind..]), vec!(5));
+        assert_eq!(active_texture_units(&calls).last(), Some(&5));
+    }
+
+    /// Renders a frame that preserves the GL state of an embedder with blending and scissoring
+    /// enabled or not, and returns the calls that set that state after the last draw.
+    fn embedder_state_restored_after_a_frame(enabled: bool) -> Vec<GlCall> {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let mut options = render_context.options();
+        options.preserve_embedder_gl_state = true;
+        render_context.set_options(options);
+        let (scene, root) = painted_scene();
+        gl.set_enabled(gl::BLEND, enabled);
+        gl.set_enabled(gl::SCISSOR_TEST, enabled);
+        gl.set_integer(gl::BLEND_SRC_RGB, gl::SRC_ALPHA as GLint);
+        gl.set_integer(gl::BLEND_DST_RGB, gl::ONE_MINUS_SRC_ALPHA as GLint);
+        gl.set_integer(gl::BLEND_SRC_ALPHA, gl::ONE as GLint);
+        gl.set_integer(gl::BLEND_DST_ALPHA, gl::ZERO as GLint);
+        gl.set_integer(gl::CURRENT_PROGRAM, 42);
+        gl.take_calls();
+
+        render_context.begin_frame(0.0);
+        render_scene(root, render_context.clone(), &scene);
+        render_context.end_frame();
+        let calls = gl.take_calls();
+        let last_draw = calls.iter().rposition(|call| match *call {
+            GlCall::DrawArrays(..) => true,
+            _ => false,
+        }).unwrap();
+        calls[last_draw..].iter().filter(|call| match **call {
+            GlCall::Enable(gl::BLEND) | GlCall::Disable(gl::BLEND) |
+            GlCall::Enable(gl::SCISSOR_TEST) | GlCall::Disable(gl::SCISSOR_TEST) |
+            GlCall::BlendFuncSeparate(..) | GlCall::Scissor(..) | GlCall::UseProgram(..) => true,
+            _ => false,
+        }).cloned().collect()
+    }
+
+    #[test]
+    fn embedder_blending_and_scissoring_are_restored_after_a_frame() {
+        let blend_func = GlCall::BlendFuncSeparate(gl::SRC_ALPHA,
+                                                   gl::ONE_MINUS_SRC_ALPHA,
+                                                   gl::ONE,
+                                                   gl::ZERO);
+        assert_eq!(embedder_state_restored_after_a_frame(true),
+                   vec!(GlCall::Enable(gl::BLEND),
+                        blend_func.clone(),
+                        GlCall::Enable(gl::SCISSOR_TEST),
+                        GlCall::Scissor(0, 0, 0, 0),
+                        GlCall::UseProgram(42)));
+        assert_eq!(embedder_state_restored_after_a_frame(false),
+                   vec!(GlCall::Disable(gl::BLEND),
+                        blend_func,
+                        GlCall::Disable(gl::SCISSOR_TEST),
+                        GlCall::Scissor(0, 0, 0, 0),
+                        GlCall::UseProgram(42)));
+    }
+
+    #[test]
+    fn embedder_texture_state_is_left_alone_by_default() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let (scene, root) = painted_scene();
+        gl.take_calls();
+
+        render_context.begin_frame(0.0);
+        render_scene(root, render_context.clone(), &scene);
+        let calls = gl.take_calls();
+        assert!(!calls.iter().any(|call| match *call {
+            GlCall::GetIntegerv(gl::ACTIVE_TEXTURE, _) |
+            GlCall::GetIntegerv(gl::TEXTURE_BINDING_2D, _) => true,
+            _ => false,
+        }));
+        let units = active_texture_units(&calls);
+        assert!(!units.is_empty() && units.iter().all(|unit| *unit == 0), "units: {:?}", units);
+    }
+
+    #[test]
+    fn thumbnails_leave_new_buffers_without_textures() {
+        let gl = record_gl_calls();
+        let render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let (scene, root) = painted_scene();
+        scene.flush_transform_updates();
+        gl.take_calls();
+
+        let pixels = render_context.render_thumbnail(root.clone(), Size2D::new(16, 16)).unwrap();
+        assert_eq!(pixels.len(), 16 * 16 * 4);
+        assert_eq!(first_tile_state(&root), (true, None));
+        // Only the downsampling pass draws anything.
+        assert_eq!(draw_call_count(&gl.take_calls()), 1);
+    }
+
+    #[test]
+    fn thumbnails_draw_existing_textures_without_recording_the_frame() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let (scene, root) = painted_scene();
+        let frame = render_context.begin_frame(0.0);
+        render_scene(root.clone(), render_context.clone(), &scene);
+        assert_eq!(first_tile_state(&root), (false, Some(frame.index)));
+        root.with_tile(&Point2D::new(0, 0), |tile, _| tile.unwrap().last_drawn_frame.set(None));
+        gl.take_calls();
+
+        render_context.render_thumbnail(root.clone(), Size2D::new(16, 16)).unwrap();
+        assert_eq!(first_tile_state(&root), (false, None));
+        assert_eq!(draw_call_count(&gl.take_calls()), 2);
+    }
+
+    /// Compares thumbnails of a layer with a different color in each quadrant against its full
+    /// render, scaled down by averaging blocks of pixels. Both readbacks go from the top row
+    /// down. Run with `--features egl-tests`.
+    #[cfg(feature = "egl-tests")]
+    #[test]
+    fn thumbnails_match_the_full_render_scaled_down() {
+        use gltrace::GleamGl;
+        use super::ThumbnailAspect;
+        use super::super::scene_render::{ReadbackTarget, render_scene_to_framebuffer};
+        use test_util::make_headless_egl_context_current;
+
+        let display = make_headless_egl_context_current(64, 32);
+        let gl = &GleamGl;
+        let mut render_context = RenderContext::new(display, false, false);
+        while gl::get_error() != gl::NO_ERROR {}
+        let root = layer(0.0, 0.0, 64.0, 32.0, 256);
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(64.0, 32.0)));
+        scene.root = Some(root.clone());
+        // Red, green, blue and white quadrants, uploaded as BGRA from the top row down.
+        let quadrant = |x: usize, y: usize| match (x < 32, y < 16) {
+            (true, true) => [0, 0, 255, 255],
+            (false, true) => [0, 255, 0, 255],
+            (true, false) => [255, 0, 0, 255],
+            (false, false) => [255, 255, 255, 255],
+        };
+        let mut requests = vec!();
+        scene.get_buffer_requests(&mut requests, &mut vec!());
+        for request in &requests[0].1 {
+            let mut buffer = buffer_for_request(request);
+            let pixels: Vec<u8> =
+                (0..32 * 64).flat_map(|i| quadrant(i % 64, i / 64).to_vec()).collect();
+            buffer.native_surface.upload(&display, &pixels).unwrap();
+            assert!(scene.add_buffer(&root, buffer, FrameIndex(0)));
+        }
+
+        render_context.begin_frame(0.0);
+        let full = render_scene_to_framebuffer(root.clone(),
+                                               render_context.clone(),
+                                               &scene,
+                                               &mut ReadbackTarget::new(),
+                                               Size2D::new(64, 32)).unwrap();
+        let pixel = |pixels: &[u8], width: usize, x: usize, y: usize| {
+            let start = (y * width + x) * 4;
+            pixels[start..start + 4].to_vec()
+        };
+        assert_eq!(pixel(&full, 64, 0, 0), vec!(255, 0, 0, 255));
+        assert_eq!(pixel(&full, 64, 0, 31), vec!(0, 0, 255, 255));
+        // The average of the `size` by `size` block of the full render at (`x`, `y`).
+        let block = |x: usize, y: usize, size: usize| -> Vec<u8> {
+            (0..4).map(|channel| {
+                let sum: usize = (0..size * size).map(|i| {
+                    full[((y + i / size) * 64 + x + i % size) * 4 + channel] as usize
+                }).sum();
+                (sum / (size * size)) as u8
+            }).collect()
+        };
+        let assert_close = |actual: Vec<u8>, expected: Vec<u8>, x: usize, y: usize| {
+            let close =
+                actual.iter().zip(&expected).all(|(a, e)| (*a as i32 - *e as i32).abs() <= 3);
+            assert!(close, "{:?} at ({}, {}) isn't close to {:?}", actual, x, y, expected);
+        };
+
+        // Letterboxed, the layer is scaled by a quarter and centered, leaving transparent bars
+        // above and below it.
+        let thumbnail = render_context.render_thumbnail(root.clone(), Size2D::new(16, 16)).unwrap();
+        assert_close(pixel(&thumbnail, 16, 0, 4), vec!(255, 0, 0, 255), 0, 4);
+        assert_close(pixel(&thumbnail, 16, 0, 11), vec!(0, 0, 255, 255), 0, 11);
+        for (x, y) in (0..16 * 16).map(|i| (i % 16, i / 16)) {
+            let expected =
+                if y < 4 || y >= 12 { vec!(0, 0, 0, 0) } else { block(x * 4, (y - 4) * 4, 4) };
+            assert_close(pixel(&thumbnail, 16, x, y), expected, x, y);
+        }
+
+        // Cropped, the layer is scaled by half to cover the thumbnail, cutting a quarter of its
+        // width off either side.
+        let mut options = render_context.options();
+        options.thumbnail_aspect = ThumbnailAspect::Crop;
+        render_context.set_options(options);
+        let thumbnail = render_context.render_thumbnail(root.clone(), Size2D::new(16, 16)).unwrap();
+        for (x, y) in (0..16 * 16).map(|i| (i % 16, i / 16)) {
+            assert_close(pixel(&thumbnail, 16, x, y), block(16 + x * 2, y * 2, 2), x, y);
+        }
+        assert_eq!(gl::get_error(), gl::NO_ERROR);
+    }
+
+    #[test]
+    fn tile_borders_are_drawn_over_their_tiles() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let (scene, root) = painted_scene();
+        let mut debug_options = LayerDebugOptions::new();
+        debug_options.show_tile_borders = true;
+        root.set_debug_options(debug_options);
+        render_context.begin_frame(0.0);
+        gl.take_calls();
+
+        render_scene(root.clone(), render_context.clone(), &scene);
+        let modes: Vec<_> = gl.take_calls().iter().filter_map(|call| match *call {
+            GlCall::DrawArrays(mode, _, _) => Some(mode),
+            _ => None,
+        }).collect();
+        assert_eq!(modes.len(), 2);
+        assert!(modes[0] != gl::LINE_STRIP);
+        assert_eq!(modes[1], gl::LINE_STRIP);
+    }
+
+    /// Sets up a painted 256x256 root with two painted 64x64 children side by side, and returns
+    /// the scene, the root and the children.
+    fn painted_scene_with_siblings() -> (Scene<()>, Rc<Layer<()>>, Rc<Layer<()>>, Rc<Layer<()>>) {
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let first = layer(0.0, 0.0, 64.0, 64.0, 256);
+        let second = layer(100.0, 0.0, 64.0, 64.0, 256);
+        root.add_child(first.clone());
+        root.add_child(second.clone());
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(256.0, 256.0)));
+        scene.root = Some(root.clone());
+        let mut requests = vec!();
+        scene.get_buffer_requests(&mut requests, &mut vec!());
+        for &(ref layer, ref layer_requests) in &requests {
+            for request in layer_requests {
+                assert!(scene.add_buffer(layer, buffer_for_request(request), FrameIndex(0)));
+            }
+        }
+        (scene, root, first, second)
+    }
+
+    /// Returns the number of solid quads and outlines of the given color drawn by `calls`.
+    fn solid_color_count(calls: &[GlCall], color: &Color) -> usize {
+        calls.iter().filter(|call| match **call {
+            GlCall::Uniform4f(_, r, g, b, a) => {
+                (r, g, b, a) == (color.r, color.g, color.b, color.a)
+            }
+            _ => false,
+        }).count()
+    }
+
+    #[test]
+    fn layer_overlays_are_drawn_for_their_layer_only() {
+        use debug_overlay::{LAYER_AABB_BORDER_COLOR, LAYER_BORDER_COLOR, TILE_BORDER_COLOR};
+
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let (scene, root, first, _) = painted_scene_with_siblings();
+        let label_color = Color { r: 0.25, g: 0.0, b: 0.25, a: 0.25 };
+        let mut debug_options = LayerDebugOptions::new();
+        debug_options.show_borders = true;
+        debug_options.show_tile_borders = true;
+        debug_options.show_aabb = true;
+        debug_options.label_color = Some(label_color);
+        first.set_debug_options(debug_options);
+        render_context.begin_frame(0.0);
+        gl.take_calls();
+
+        // With the global overlays off, the sibling and the root get none.
+        render_scene(root.clone(), render_context.clone(), &scene);
+        let calls = gl.take_calls();
+        assert_eq!(render_context.debug_overlay_mode(), DebugOverlayMode::None);
+        assert_eq!(solid_color_count(&calls, &LAYER_BORDER_COLOR), 1);
+        assert_eq!(solid_color_count(&calls, &TILE_BORDER_COLOR), 1);
+        assert_eq!(solid_color_count(&calls, &LAYER_AABB_BORDER_COLOR), 1);
+        assert_eq!(solid_color_count(&calls, &label_color), 1);
+        // Three tiles, and the label and three outlines of the first layer.
+        assert_eq!(draw_call_count(&calls), 7);
+    }
+
+    #[test]
+    fn global_borders_outline_every_layer() {
+        use debug_overlay::{LAYER_AABB_BORDER_COLOR, LAYER_BORDER_COLOR, TILE_BORDER_COLOR};
+
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let (scene, root, _, _) = painted_scene_with_siblings();
+        render_context.set_debug_overlay_mode(DebugOverlayMode::Borders);
+        render_context.begin_frame(0.0);
+        gl.take_calls();
+
+        // Each of the three layers has a single tile. There are no labels without a color.
+        render_scene(root.clone(), render_context.clone(), &scene);
+        let calls = gl.take_calls();
+        assert_eq!(solid_color_count(&calls, &LAYER_BORDER_COLOR), 3);
+        assert_eq!(solid_color_count(&calls, &TILE_BORDER_COLOR), 3);
+        assert_eq!(solid_color_count(&calls, &LAYER_AABB_BORDER_COLOR), 3);
+        assert_eq!(draw_call_count(&calls), 12);
+    }
+
+    #[test]
+    fn rectangle_textures_are_supported_where_the_extension_is_advertised() {
+        let gl = record_gl_calls();
+        gl.set_string(gl::EXTENSIONS, "GL_ARB_texture_rectangle");
+        let render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        assert!(render_context.capabilities().texture_rectangle);
+        assert!(render_context.supports_rectangle_textures());
+
+        let gl = record_gl_calls();
+        gl.set_string(gl::EXTENSIONS, "");
+        let render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        assert!(!render_context.capabilities().texture_rectangle);
+        assert!(!render_context.supports_rectangle_textures());
+    }
+
+    #[test]
+    fn rectangle_textures_are_skipped_without_support() {
+        let gl = record_gl_calls();
+        gl.set_string(gl::EXTENSIONS, "");
+        let render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let rect = Rect::new(Point2D::new(0.0, 0.0), Size2D::new(16.0, 16.0));
+        let vertices = texture_vertices(&rect, &rect);
+        let identity = Matrix4D::identity();
+        gl.take_calls();
+
+        let texture = Texture::new_with_gl(gl.clone(),
+                                           TextureTarget::TextureTargetRectangle,
+                                           Size2D::new(16, 16));
+        render_context.bind_and_render_quad(&vertices, &texture, &identity, &identity, 1.0, None,
+                                            None);
+        assert_eq!(draw_call_count(&gl.take_calls()), 0);
+
+        let texture = Texture::new_with_gl(gl.clone(),
+                                           TextureTarget::TextureTarget2D,
+                                           Size2D::new(16, 16));
+        render_context.bind_and_render_quad(&vertices, &texture, &identity, &identity, 1.0, None,
+                                            None);
+        assert_eq!(draw_call_count(&gl.take_calls()), 1);
+    }
+
+    /// Renders a scene with a newly painted 256x256 tile, and returns whether the tile was
+    /// uploaded directly and whether it was uploaded through a pixel buffer object.
+    fn tile_upload_paths(gl: &RecordingGl, mut render_context: RenderContext) -> (bool, bool) {
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(256.0, 256.0)));
+        scene.root = Some(root.clone());
+        let mut requests = vec!();
+        scene.get_buffer_requests(&mut requests, &mut vec!());
+        let mut buffer = buffer_for_request(&requests[0].1[0]);
+        let pixels = vec![0x80; 256 * 256 * 4];
+        assert!(buffer.native_surface.upload(&headless_display(), &pixels).is_ok());
+        assert!(scene.add_buffer(&root, buffer, FrameIndex(0)));
+        gl.take_calls();
+        render_context.begin_frame(0.0);
+        render_scene(root, render_context.clone(), &scene);
+        let calls = gl.take_calls();
+        (calls.iter().any(|call| match *call {
+            GlCall::TexSubImage2D { .. } => true,
+            _ => false,
+        }), calls.iter().any(|call| match *call {
+            GlCall::TexSubImage2DPbo { .. } => true,
+            _ => false,
+        }))
+    }
+
+    #[test]
+    fn tiles_are_uploaded_through_pbos_only_where_supported() {
+        let gl = record_gl_calls();
+        gl.set_string(gl::VERSION, "OpenGL ES 2.0");
+        gl.set_string(gl::EXTENSIONS, "GL_EXT_texture_format_BGRA8888");
+        let render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        assert!(!render_context.set_use_pbo_uploads(true, 3));
+        assert_eq!(tile_upload_paths(&gl, render_context.clone()), (true, false));
+
+        gl.set_string(gl::VERSION, "3.3.0");
+        let render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        assert!(render_context.set_use_pbo_uploads(true, 3));
+        assert_eq!(tile_upload_paths(&gl, render_context.clone()), (false, true));
+
+        // Destroying the context deletes the buffers of the pool and goes back to direct uploads.
+        super::destroy(render_context);
+        assert!(gl.take_calls().iter().any(|call| match *call {
+            GlCall::DeleteBuffers(ref buffers) => buffers.len() == 3,
+            _ => false,
+        }));
+        let render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        assert_eq!(tile_upload_paths(&gl, render_context.clone()), (true, false));
+    }
+
+    fn transformed_corners(texture: &Texture, scale_by_size: bool) -> Vec<(f32, f32)> {
+        let transform = texture_coordinate_transform(texture, scale_by_size);
+        [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)].iter().map(|&(u, v)| {
+            let point = transform.transform_point(&Point2D::new(u, v));
+            (point.x, point.y)
+        }).collect()
+    }
+
+    #[test]
+    fn texture_coordinates_are_rotated_and_flipped() {
+        // The corners of the texture sampled at the top left, top right, bottom right, and bottom
+        // left corners of the quad.
+        let gl = record_gl_calls();
+        let mut texture = Texture::new_with_gl(gl.clone(),
+                                               TextureTarget::TextureTarget2D,
+                                               Size2D::new(100, 50));
+        assert_eq!(transformed_corners(&texture, false),
+                   vec!((0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)));
+        texture.rotation = Rotation::Rotate90;
+        assert_eq!(transformed_corners(&texture, false),
+                   vec!((0.0, 1.0), (0.0, 0.0), (1.0, 0.0), (1.0, 1.0)));
+        texture.rotation = Rotation::Rotate180;
+        assert_eq!(transformed_corners(&texture, false),
+                   vec!((1.0, 1.0), (0.0, 1.0), (0.0, 0.0), (1.0, 0.0)));
+        texture.rotation = Rotation::Rotate270;
+        assert_eq!(transformed_corners(&texture, false),
+                   vec!((1.0, 0.0), (1.0, 1.0), (0.0, 1.0), (0.0, 0.0)));
+
+        texture.rotation = Rotation::NoRotation;
+        texture.flip = Flip::HorizontalFlip;
+        assert_eq!(transformed_corners(&texture, false),
+                   vec!((1.0, 0.0), (0.0, 0.0), (0.0, 1.0), (1.0, 1.0)));
+        texture.flip = Flip::HorizontalAndVerticalFlip;
+        assert_eq!(transformed_corners(&texture, false),
+                   vec!((1.0, 1.0), (0.0, 1.0), (0.0, 0.0), (1.0, 0.0)));
+    }
+
+    #[test]
+    fn rotated_rectangle_textures_are_scaled_along_their_own_axes() {
+        let gl = record_gl_calls();
+        let mut texture = Texture::new_with_gl(gl.clone(), TextureTarget::TextureTargetRectangle,
+                                       Size2D::new(100, 50));
+        texture.rotation = Rotation::Rotate90;
+        assert_eq!(transformed_corners(&texture, true),
+                   vec!((0.0, 50.0), (0.0, 0.0), (100.0, 0.0), (100.0, 50.0)));
+    }
+
+    #[test]
+    fn rotated_buffers_sample_the_expected_corner() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let transform_locations: Vec<_> = gl.take_calls().iter().filter_map(|call| match *call {
+            GlCall::GetUniformLocation(_, ref name, location)
+                    if name == "uTextureSpaceTransform" => Some(location),
+            _ => None,
+        }).collect();
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(256.0, 256.0)));
+        scene.root = Some(root.clone());
+        let mut requests = vec!();
+        let mut unused_buffers = vec!();
+        scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+        let mut buffer = buffer_for_request(&requests[0].1[0]);
+        buffer.rotation = Rotation::Rotate90;
+        assert!(scene.add_buffer(&root, buffer, FrameIndex(0)));
+
+        render_context.begin_frame(0.0);
+        render_scene(root.clone(), render_context.clone(), &scene);
+        let transforms: Vec<_> = gl.take_calls().into_iter().filter_map(|call| match call {
+            GlCall::UniformMatrix4fv(location, false, values)
+                    if transform_locations.contains(&location) => Some(values),
+            _ => None,
+        }).collect();
+        assert_eq!(transforms.len(), 1);
+        let values = &transforms[0];
+        let transform = Matrix4D::row_major(values[0], values[1], values[2], values[3],
+                                            values[4], values[5], values[6], values[7],
+                                            values[8], values[9], values[10], values[11],
+                                            values[12], values[13], values[14], values[15]);
+        // The bottom left corner of the buffer is shown at the top left of the layer.
+        assert_eq!(transform.transform_point(&Point2D::new(0.0, 0.0)), Point2D::new(0.0, 1.0));
+        assert_eq!(transform.transform_point(&Point2D::new(1.0, 0.0)), Point2D::new(0.0, 0.0));
+    }
+
+    #[test]
+    fn tiles_under_rounded_masks_are_drawn_with_the_rounded_clip() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let mut rounded_programs = vec!();
+        let mut radii_locations = vec!();
+        let mut rect_locations = vec!();
+        for call in gl.take_calls() {
+            match call {
+                GlCall::GetUniformLocation(program, ref name, location) if name == "uClipRadii" => {
+                    rounded_programs.push(program);
+                    radii_locations.push(location);
+                }
+                GlCall::GetUniformLocation(_, ref name, location) if name == "uClipRect" => {
+                    rect_locations.push(location);
+                }
+                _ => {}
+            }
+        }
+
+        let root = layer(0.0, 0.0, 512.0, 512.0, 256);
+        // Only layers establishing a 3D context clip their descendants.
+        let mask = Rc::new(Layer::new(TypedRect::new(TypedPoint2D::new(64.0, 64.0),
+                                                     TypedSize2D::new(256.0, 256.0)),
+                                      256,
+                                      Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 },
+                                      1.0,
+                                      true,
+                                      ()));
+        *mask.masks_to_bounds.borrow_mut() = true;
+        *mask.corner_radii.borrow_mut() = Some([32.0; 4]);
+        // A 2x2 grid of tiles.
+        let content = layer(0.0, 0.0, 256.0, 256.0, 128);
+        mask.add_child(content);
+        root.add_child(mask);
+        root.add_child(layer(384.0, 384.0, 64.0, 64.0, 256));
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(512.0, 512.0)));
+        scene.root = Some(root.clone());
+        let mut requests = vec!();
+        let mut unused_buffers = vec!();
+        scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+        for &(ref layer, ref layer_requests) in &requests {
+            for request in layer_requests {
+                assert!(scene.add_buffer(layer, buffer_for_request(request), FrameIndex(0)));
+            }
+        }
+        gl.take_calls();
+
+        render_context.begin_frame(0.0);
+        render_scene(root.clone(), render_context.clone(), &scene);
+        let mut program = 0;
+        let mut rounded_draws = 0;
+        let mut other_draws = 0;
+        let mut clips = vec!();
+        for call in gl.take_calls() {
+            match call {
+                GlCall::UseProgram(id) => program = id,
+                GlCall::DrawArrays(..) if rounded_programs.contains(&program) => {
+                    rounded_draws += 1
+                }
+                GlCall::DrawArrays(..) => other_draws += 1,
+                GlCall::Uniform4f(location, x, y, z, w) if rect_locations.contains(&location) ||
+                                                            radii_locations.contains(&location) => {
+                    clips.push((x, y, z, w))
+                }
+                _ => {}
+            }
+        }
+        // Only the tiles of the masked subtree pay for the rounded clip; the corners outside of
+        // it are discarded by the fragment shader.
+        assert_eq!(rounded_draws, 4);
+        assert_eq!(other_draws, 1 + 1 + 4);
+        assert_eq!(clips.len(), 8);
+        for clip in clips.chunks(2) {
+            assert_eq!(clip, &[(64.0, 64.0, 320.0, 320.0), (32.0, 32.0, 32.0, 32.0)]);
+        }
+    }
+
+    #[test]
+    fn layers_crossing_the_near_plane_are_drawn_clipped_to_it() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let child = layer(0.0, 0.0, 256.0, 256.0, 256);
+        // Everything right of x = 100 is behind the eye.
+        child.set_transform(Matrix4D::row_major(1.0, 0.0, 0.0, -0.01,
+                                                0.0, 1.0, 0.0, 0.0,
+                                                0.0, 0.0, 1.0, 0.0,
+                                                0.0, 0.0, 0.0, 1.0));
+        root.add_child(child.clone());
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(256.0, 256.0)));
+        scene.root = Some(root.clone());
+        let mut requests = vec!();
+        let mut unused_buffers = vec!();
+        scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+        for &(ref layer, ref layer_requests) in &requests {
+            for request in layer_requests {
+                assert!(scene.add_buffer(layer, buffer_for_request(request), FrameIndex(0)));
+            }
+        }
+        assert!(child.transform_state.borrow().crosses_near_plane);
+        gl.take_calls();
+
+        render_context.begin_frame(0.0);
+        render_scene(root.clone(), render_context.clone(), &scene);
+        let draws: Vec<_> = gl.take_calls().iter().filter_map(|call| match *call {
+            GlCall::DrawArrays(mode, first, count) => Some((mode, first, count)),
+            _ => None,
+        }).collect();
+        assert_eq!(draws, vec!((gl::TRIANGLE_STRIP, 0, 4), (gl::TRIANGLE_FAN, 0, 4)));
+    }
+
+    /// Returns a scene whose root holds a layer masking to the given bounds, with a painted child
+    /// that overflows it.
+    fn scene_with_masked_tiles(viewport_origin: (f32, f32), mask: (f32, f32, f32, f32))
+                               -> (Scene<()>, Rc<Layer<()>>, Rc<Layer<()>>) {
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        // Only layers that establish a 3D context clip their children.
+        let transparent = Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 };
+        let masking = Rc::new(Layer::new(TypedRect::new(TypedPoint2D::new(mask.0, mask.1),
+                                                        TypedSize2D::new(mask.2, mask.3)),
+                                         256,
+                                         transparent,
+                                         1.0,
+                                         true,
+                                         ()));
+        masking.set_masks_to_bounds(true);
+        let child = layer(0.0, 0.0, 200.0, 200.0, 256);
+        masking.add_child(child.clone());
+        root.add_child(masking.clone());
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(viewport_origin.0,
+                                                                    viewport_origin.1),
+                                                  TypedSize2D::new(256.0, 256.0)));
+        scene.root = Some(root.clone());
+        let mut requests = vec!();
+        let mut unused_buffers = vec!();
+        scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+        for &(ref requested, ref layer_requests) in &requests {
+            for request in layer_requests {
+                assert!(scene.add_buffer(requested, buffer_for_request(request), FrameIndex(0)));
+            }
+        }
+        (scene, root, masking)
+    }
+
+    /// Returns the calls among `calls` that set up the scissor test for layers, leaving out the
+    /// ones that limit the clear at the start of the frame.
+    fn layer_scissor_calls(calls: &[GlCall]) -> Vec<GlCall> {
+        let first_draw = calls.iter().position(|call| match *call {
+            GlCall::DrawArrays(..) => true,
+            _ => false,
+        }).unwrap_or(calls.len());
+        let first_layer_call = calls[..first_draw].iter().rposition(|call| match *call {
+            GlCall::Clear(..) => true,
+            _ => false,
+        }).map_or(0, |index| index + 1);
+        calls[first_layer_call..].iter().filter(|call| match **call {
+            GlCall::Scissor(..) => true,
+            GlCall::Enable(cap) | GlCall::Disable(cap) => cap == gl::SCISSOR_TEST,
+            _ => false,
+        }).cloned().collect()
+    }
+
+    #[test]
+    fn masked_layers_are_scissored_to_their_mask() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let (scene, root, _) = scene_with_masked_tiles((0.0, 0.0), (10.0, 20.0, 100.0, 50.0));
+        gl.take_calls();
+
+        render_context.begin_frame(0.0);
+        render_scene(root, render_context.clone(), &scene);
+        // Window coordinates start at the bottom left.
+        assert_eq!(layer_scissor_calls(&gl.take_calls()),
+                   vec!(GlCall::Enable(gl::SCISSOR_TEST),
+                        GlCall::Scissor(10, 186, 100, 50),
+                        GlCall::Disable(gl::SCISSOR_TEST)));
+    }
+
+    #[test]
+    fn scissor_boxes_follow_the_viewport_origin() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        // The layer tree shares the space of the viewport, while the GL viewport puts it at the
+        // origin of the viewport in the window.
+        let (scene, root, _) = scene_with_masked_tiles((50.0, 30.0), (60.0, 50.0, 100.0, 50.0));
+        gl.take_calls();
+
+        render_context.begin_frame(0.0);
+        render_scene(root, render_context.clone(), &scene);
+        assert_eq!(layer_scissor_calls(&gl.take_calls()),
+                   vec!(GlCall::Enable(gl::SCISSOR_TEST),
+                        GlCall::Scissor(60, 216, 100, 50),
+                        GlCall::Disable(gl::SCISSOR_TEST)));
+    }
+
+    #[test]
+    fn rotated_masked_layers_are_not_scissored() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let (scene, root, masking) =
+            scene_with_masked_tiles((0.0, 0.0), (10.0, 20.0, 100.0, 50.0));
+        masking.set_transform(Matrix4D::create_rotation(0.0, 0.0, 1.0, Radians::new(0.1)));
+        gl.take_calls();
+
+        render_context.begin_frame(0.0);
+        render_scene(root, render_context.clone(), &scene);
+        assert_eq!(layer_scissor_calls(&gl.take_calls()), vec!());
+    }
+
+    #[test]
+    fn rotated_backgrounds_are_clipped_in_screen_space() {
+        // A 128-pixel square turned 45 degrees about its center at (128, 128), clipped to the
+        // square it was turned in, leaves an octagon.
+        let rect = Rect::new(Point2D::new(0.0, 0.0), Size2D::new(128.0, 128.0));
+        let transform = Matrix4D::create_translation(-64.0, -64.0, 0.0)
+            .post_mul(&Matrix4D::create_rotation(0.0, 0.0, 1.0, Radians::new(PI / 4.0)))
+            .post_translated(128.0, 128.0, 0.0);
+        let clip_rect = Rect::new(Point2D::new(64.0, 64.0), Size2D::new(128.0, 128.0));
+        let vertices = clip_rect_in_screen_space(&rect,
+                                                 &transform,
+                                                 transform.inverse().as_ref(),
+                                                 &clip_rect).unwrap();
+        assert_eq!(vertices.len(), 8);
+        let transform = transform.to_2d();
+        for vertex in &vertices {
+            assert!(vertex.x > -0.001 && vertex.x < 128.001 &&
+                    vertex.y > -0.001 && vertex.y < 128.001);
+            let point = transform.transform_point(vertex);
+            assert!(point.x > 63.999 && point.x < 192.001 && point.y > 63.999 && point.y < 192.001,
+                    "{:?} is outside of the clip rect", point);
+        }
+
+        // Nothing is left of a layer outside of the clip rect, and 3D layers aren't clipped here.
+        let identity = Matrix4D::identity();
+        let far_clip_rect = Rect::new(Point2D::new(300.0, 300.0), Size2D::new(10.0, 10.0));
+        assert!(clip_rect_in_screen_space(&rect, &identity, Some(&identity), &far_clip_rect)
+                    .unwrap()
+                    .is_empty());
+        let turned_over = Matrix4D::create_rotation(0.0, 1.0, 0.0, Radians::new(0.5));
+        assert!(clip_rect_in_screen_space(&rect,
+                                          &turned_over,
+                                          turned_over.inverse().as_ref(),
+                                          &clip_rect).is_none());
+    }
+
+    #[test]
+    fn rotated_layers_in_masks_are_drawn_clipped_to_the_mask() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let mut rounded_programs = vec!();
+        let mut clip_locations = vec!();
+        for call in gl.take_calls() {
+            match call {
+                GlCall::GetUniformLocation(program, ref name, location) if name == "uClipRect" ||
+                                                                           name == "uClipRadii" => {
+                    rounded_programs.push(program);
+                    clip_locations.push(location);
+                }
+                _ => {}
+            }
+        }
+
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let masking = Rc::new(Layer::new(TypedRect::new(TypedPoint2D::new(64.0, 64.0),
+                                                        TypedSize2D::new(128.0, 128.0)),
+                                         256,
+                                         Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 },
+                                         1.0,
+                                         true,
+                                         ()));
+        masking.set_masks_to_bounds(true);
+        let child = layer(0.0, 0.0, 128.0, 128.0, 256);
+        *child.background_color.borrow_mut() = Color { r: 1.0, g: 0.0, b: 0.0, a: 1.0 };
+        child.set_transform_origin(Point3D::new(64.0, 64.0, 0.0));
+        child.set_transform(Matrix4D::create_rotation(0.0, 0.0, 1.0, Radians::new(PI / 4.0)));
+        masking.add_child(child);
+        root.add_child(masking);
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(256.0, 256.0)));
+        scene.root = Some(root.clone());
+        let mut requests = vec!();
+        let mut unused_buffers = vec!();
+        scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+        for &(ref layer, ref layer_requests) in &requests {
+            for request in layer_requests {
+                assert!(scene.add_buffer(layer, buffer_for_request(request), FrameIndex(0)));
+            }
+        }
+        gl.take_calls();
+
+        render_context.begin_frame(0.0);
+        render_scene(root, render_context.clone(), &scene);
+        let calls = gl.take_calls();
+
+        // The background is the octagon left of the turned square within the mask.
+        assert!(calls.contains(&GlCall::DrawArrays(gl::TRIANGLE_FAN, 0, 8)));
+
+        // The tile is clipped to the mask by the shader, with square corners.
+        let mut program = 0;
+        let mut clipped_draws = 0;
+        let mut clips = vec!();
+        for call in calls {
+            match call {
+                GlCall::UseProgram(id) => program = id,
+                GlCall::DrawArrays(..) if rounded_programs.contains(&program) => clipped_draws += 1,
+                GlCall::Uniform4f(location, x, y, z, w) if clip_locations.contains(&location) => {
+                    clips.push((x, y, z, w))
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(clipped_draws, 1);
+        assert_eq!(clips, vec!((64.0, 64.0, 192.0, 192.0), (0.0, 0.0, 0.0, 0.0)));
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The OpenGL compositor.
+//!
+//! This is split into:
+//!
+//! * `programs`: the shader programs and the vertex formats they consume.
+//! * `context3d`: building the sorted, clipped lists of layers that are drawn together
+//!   (`RenderContext3D`), and walking them.
+//! * `draw`: the `RenderContext`, which owns the programs and vertex buffers and draws layers,
+//!   tiles, and debug overlays.
+//! * `offscreen`: framebuffer objects used to render into textures.
+//! * `scene_render`: the entry points that set up the GL state for a frame and tear a scene down.
+//! * `statistics`: counters of the work done by render passes.
+//!
+//! Each of these documents the GL state it expects on entry and leaves behind on exit. The
+//! public API is re-exported from here.
+
+pub use self::context3d::{RenderContext3D, RenderContext3DBuilder, for_each_2d_layer};
+pub use debug_overlay::DebugOverlayMode;
+pub use self::draw::{ClearPolicy, RenderContext, RenderOptions};
+pub use self::draw::ThumbnailAspect;
+pub use self::offscreen::RenderError;
+pub use self::programs::{ColorVertex, ShaderError, TextureVertex};
+pub use self::scene_render::{render_scene, render_scene_to_framebuffer, render_scenes};
+pub use self::scene_render::{render_scene_with_3d_context, shutdown};
+pub use self::scene_render::{LeakRecord, ReadbackTarget, ShutdownArtifacts};
+pub use self::statistics::{ContextStatistics, GroupStatistics, RenderStatistics};
+
+mod context3d;
+mod draw;
+mod offscreen;
+mod programs;
+mod scene_render;
+mod statistics;
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use euclid::Size2D;
+    use layers::Layer;
+    use rendergl::{self, ClearPolicy, ContextStatistics, DebugOverlayMode, LeakRecord};
+    use rendergl::{ReadbackTarget, RenderContext, RenderContext3D, RenderContext3DBuilder};
+    use rendergl::{RenderError, RenderOptions, RenderStatistics, ShaderError, ShutdownArtifacts};
+    use rendergl::{ColorVertex, TextureVertex, ThumbnailAspect};
+    use scene::Scene;
+    use std::rc::Rc;
+
+    /// Fails to compile if the public API of the compositor loses an item or changes the
+    /// signature of an entry point, since embedders name them through this module.
+    #[test]
+    fn the_public_api_is_reexported_with_stable_signatures() {
+        let _: fn(Rc<Layer<()>>, RenderContext, &Scene<()>) = rendergl::render_scene::<()>;
+        let _: fn(&[(&Scene<()>, Rc<Layer<()>>)], RenderContext) = rendergl::render_scenes::<()>;
+        let _: fn(&RenderContext3D<()>, RenderContext, &Scene<()>) =
+            rendergl::render_scene_with_3d_context::<()>;
+        let _: fn(Rc<Layer<()>>, RenderContext, &Scene<()>, &mut ReadbackTarget, Size2D<usize>)
+                  -> Result<Vec<u8>, RenderError> = rendergl::render_scene_to_framebuffer::<()>;
+        let _: fn(Scene<()>, RenderContext, bool) -> ShutdownArtifacts = rendergl::shutdown::<()>;
+        let _: fn(Rc<Layer<()>>) -> RenderContext3D<()> = RenderContext3D::new;
+        let _: fn(&Rc<Layer<()>>, &mut RenderContext3D<()>) =
+            <Rc<Layer<()>> as RenderContext3DBuilder<()>>::build;
+        let _: fn() -> RenderOptions = RenderOptions::new;
+        let _: fn() -> RenderStatistics = RenderStatistics::new;
+        let _: fn() -> ReadbackTarget = ReadbackTarget::new;
+
+        // Types that are only named.
+        let _: Option<(ClearPolicy, ContextStatistics, DebugOverlayMode, LeakRecord)> = None;
+        let _: Option<(ShaderError, ColorVertex, TextureVertex, ThumbnailAspect)> = None;
+    }
+}
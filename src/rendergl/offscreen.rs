@@ -0,0 +1,220 @@
+// Copyright 2016 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Offscreen render targets.
+//!
+//! An `OffscreenTarget` is a framebuffer object with a color texture and, optionally, a depth
+//! buffer. Binding one sets the viewport to cover it. Callers are responsible for saving and
+//! restoring the framebuffer binding and the viewport around their use of it; see
+//! `SavedGlState`.
+
+use texturegl::Texture;
+use texturegl::TextureTarget::TextureTarget2D;
+
+use euclid::Size2D;
+use gltrace::{self as gl, GlApi};
+use gleam::gl::{GLint, GLsizei, GLuint};
+use std::rc::Rc;
+
+/// Errors that can occur when rendering offscreen.
+#[derive(Debug)]
+pub enum RenderError {
+    /// The requested output size is empty.
+    EmptyTarget,
+    /// The layer to render has an empty rect.
+    EmptyLayer,
+    /// The GL implementation rejected the framebuffer with the given status.
+    IncompleteFramebuffer(u32),
+}
+
+/// A framebuffer object rendering into a texture.
+pub struct OffscreenTarget {
+    framebuffer: GLuint,
+    depth_renderbuffer: Option<GLuint>,
+
+    /// The texture holding the rendered color data.
+    pub texture: Texture,
+
+    /// The size of the target in pixels.
+    pub size: Size2D<usize>,
+}
+
+impl OffscreenTarget {
+    /// Creates a new target of the given size, leaving it bound. The texture must be created on
+    /// the texture unit that the caller will restore afterwards, since creating it binds it.
+    pub fn new(gl: Rc<GlApi>, size: Size2D<usize>, with_depth: bool)
+               -> Result<OffscreenTarget, RenderError> {
+        let texture = Texture::new_with_gl(gl.clone(), TextureTarget2D, size);
+        let gl = &*gl;
+        {
+            let _bound_texture = texture.bind();
+            gl::tex_image_2d(gl,
+                             gl::TEXTURE_2D,
+                             0,
+                             gl::RGBA as GLint,
+                             size.width as GLsizei,
+                             size.height as GLsizei,
+                             0,
+                             gl::RGBA,
+                             gl::UNSIGNED_BYTE,
+                             None);
+        }
+
+        let framebuffer = gl::gen_framebuffers(gl, 1)[0];
+        gl::bind_framebuffer(gl, gl::FRAMEBUFFER, framebuffer);
+        gl::framebuffer_texture_2d(gl,
+                                   gl::FRAMEBUFFER,
+                                   gl::COLOR_ATTACHMENT0,
+                                   gl::TEXTURE_2D,
+                                   texture.native_texture(),
+                                   0);
+
+        let depth_renderbuffer = if with_depth {
+            let renderbuffer = gl::gen_renderbuffers(gl, 1)[0];
+            gl::bind_renderbuffer(gl, gl::RENDERBUFFER, renderbuffer);
+            gl::renderbuffer_storage(gl,
+                                     gl::RENDERBUFFER,
+                                     gl::DEPTH_COMPONENT16,
+                                     size.width as GLsizei,
+                                     size.height as GLsizei);
+            gl::bind_renderbuffer(gl, gl::RENDERBUFFER, 0);
+            gl::framebuffer_renderbuffer(gl,
+                                         gl::FRAMEBUFFER,
+                                         gl::DEPTH_ATTACHMENT,
+                                         gl::RENDERBUFFER,
+                                         renderbuffer);
+            Some(renderbuffer)
+        } else {
+            None
+        };
+
+        let target = OffscreenTarget {
+            framebuffer: framebuffer,
+            depth_renderbuffer: depth_renderbuffer,
+            texture: texture,
+            size: size,
+        };
+
+        let status = gl::check_frame_buffer_status(gl, gl::FRAMEBUFFER);
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            return Err(RenderError::IncompleteFramebuffer(status));
+        }
+
+        target.bind();
+        Ok(target)
+    }
+
+    /// Binds this target for drawing and sets the viewport to cover it.
+    pub fn bind(&self) {
+        let gl = self.texture.gl();
+        gl::bind_framebuffer(gl, gl::FRAMEBUFFER, self.framebuffer);
+        gl::viewport(gl, 0, 0, self.size.width as GLsizei, self.size.height as GLsizei);
+    }
+
+    /// Reads back the contents of this target, which must be bound, as tightly packed RGBA rows
+    /// ordered from top to bottom.
+    pub fn read_pixels(&self) -> Vec<u8> {
+        let gl = self.texture.gl();
+        let pixels = gl::read_pixels(gl,
+                                     0,
+                                     0,
+                                     self.size.width as GLsizei,
+                                     self.size.height as GLsizei,
+                                     gl::RGBA,
+                                     gl::UNSIGNED_BYTE);
+
+        // GL returns the rows from bottom to top.
+        let stride = self.size.width * 4;
+        let mut flipped = Vec::with_capacity(pixels.len());
+        for row in pixels.chunks(stride).rev() {
+            flipped.extend_from_slice(row);
+        }
+        flipped
+    }
+}
+
+impl Drop for OffscreenTarget {
+    fn drop(&mut self) {
+        let gl = self.texture.gl();
+        gl::delete_framebuffers(gl, &[self.framebuffer]);
+        if let Some(renderbuffer) = self.depth_renderbuffer {
+            gl::delete_renderbuffers(gl, &[renderbuffer]);
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use euclid::Size2D;
+    use gltrace::{self as gl, GlCall};
+    use super::OffscreenTarget;
+    use test_util::record_gl_calls;
+
+    fn is_framebuffer_setup(call: &GlCall) -> bool {
+        match *call {
+            GlCall::GenFramebuffers(..) | GlCall::BindFramebuffer(..) |
+            GlCall::FramebufferTexture2D(..) | GlCall::GenRenderbuffers(..) |
+            GlCall::BindRenderbuffer(..) | GlCall::RenderbufferStorage(..) |
+            GlCall::FramebufferRenderbuffer(..) | GlCall::CheckFramebufferStatus(..) |
+            GlCall::Viewport(..) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn targets_attach_their_texture_and_depth_buffer_and_stay_bound() {
+        let gl = record_gl_calls();
+        let target = OffscreenTarget::new(gl.clone(), Size2D::new(64, 32), true).unwrap();
+        let texture = target.texture.native_texture();
+        let calls: Vec<_> = gl.take_calls().into_iter().filter(is_framebuffer_setup).collect();
+        let framebuffer = match calls[0] {
+            GlCall::GenFramebuffers(ref framebuffers) => framebuffers[0],
+            ref call => panic!("unexpected call {:?}", call),
+        };
+        let renderbuffer = framebuffer + 1;
+        assert_eq!(calls,
+                   vec!(GlCall::GenFramebuffers(vec!(framebuffer)),
+                        GlCall::BindFramebuffer(gl::FRAMEBUFFER, framebuffer),
+                        GlCall::FramebufferTexture2D(gl::FRAMEBUFFER,
+                                                     gl::COLOR_ATTACHMENT0,
+                                                     gl::TEXTURE_2D,
+                                                     texture,
+                                                     0),
+                        GlCall::GenRenderbuffers(vec!(renderbuffer)),
+                        GlCall::BindRenderbuffer(gl::RENDERBUFFER, renderbuffer),
+                        GlCall::RenderbufferStorage(gl::RENDERBUFFER,
+                                                    gl::DEPTH_COMPONENT16,
+                                                    64,
+                                                    32),
+                        GlCall::BindRenderbuffer(gl::RENDERBUFFER, 0),
+                        GlCall::FramebufferRenderbuffer(gl::FRAMEBUFFER,
+                                                        gl::DEPTH_ATTACHMENT,
+                                                        gl::RENDERBUFFER,
+                                                        renderbuffer),
+                        GlCall::CheckFramebufferStatus(gl::FRAMEBUFFER,
+                                                       gl::FRAMEBUFFER_COMPLETE),
+                        GlCall::BindFramebuffer(gl::FRAMEBUFFER, framebuffer),
+                        GlCall::Viewport(0, 0, 64, 32)));
+
+        drop(target);
+        let calls = gl.take_calls();
+        assert!(calls.contains(&GlCall::DeleteFramebuffers(vec!(framebuffer))));
+        assert!(calls.contains(&GlCall::DeleteRenderbuffers(vec!(renderbuffer))));
+    }
+
+    #[test]
+    fn targets_without_depth_have_no_renderbuffer() {
+        let gl = record_gl_calls();
+        drop(OffscreenTarget::new(gl.clone(), Size2D::new(16, 16), false).unwrap());
+        assert!(!gl.take_calls().iter().any(|call| match *call {
+            GlCall::GenRenderbuffers(..) | GlCall::DeleteRenderbuffers(..) => true,
+            _ => false,
+        }));
+    }
+}
@@ -0,0 +1,957 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Shader programs and the vertex formats they consume.
+//!
+//! Creating a program compiles and links it and looks up all of its attribute and uniform
+//! locations. Compilation and link failures are returned as a `ShaderError`, since drivers do
+//! reject valid shaders. Drivers also optimize unused attributes and uniforms away, so a missing
+//! location is only warned about when the program is created, is listed by its
+//! `missing_locations`, and is never set.
+//!
+//! Programs don't track GL state: callers must `use_program` and enable the attribute arrays
+//! before binding uniforms and attributes, and disable the arrays again after drawing. Binding
+//! attributes leaves `GL_ARRAY_BUFFER` bound to the vertex buffer that was used.
+
+use capabilities::Capabilities;
+use color::{Color, ColorMatrix};
+use layers::RoundedClip;
+use texturegl::TextureTarget;
+
+use euclid::{Matrix4D, Point2D};
+use gltrace::{self as gl, GlApi};
+use gleam::gl::{GLenum, GLfloat, GLint, GLsizei, GLuint};
+use std::fmt;
+use std::mem;
+
+#[derive(Copy, Clone, Debug)]
+pub struct ColorVertex {
+    x: f32,
+    y: f32,
+}
+
+#[cfg(feature = "heapsize")]
+known_heap_size!(0, ColorVertex);
+
+impl ColorVertex {
+    pub fn new(point: Point2D<f32>) -> ColorVertex {
+        ColorVertex {
+            x: point.x,
+            y: point.y,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct TextureVertex {
+    x: f32,
+    y: f32,
+    u: f32,
+    v: f32,
+}
+
+#[cfg(feature = "heapsize")]
+known_heap_size!(0, TextureVertex);
+
+impl TextureVertex {
+    pub fn new(point: Point2D<f32>, texture_coordinates: Point2D<f32>) -> TextureVertex {
+        TextureVertex {
+            x: point.x,
+            y: point.y,
+            u: texture_coordinates.x,
+            v: texture_coordinates.y,
+        }
+    }
+}
+
+static TEXTURE_FRAGMENT_SHADER_SOURCE: &'static str = "
+    #ifdef GL_ES
+        precision mediump float;
+    #endif
+
+    varying vec2 vTextureCoord;
+    uniform samplerType uSampler;
+    uniform float uOpacity;
+
+    #ifdef ROUNDED_CLIP
+        varying vec2 vClipPosition;
+        uniform vec4 uClipRect;
+        uniform vec4 uClipRadii;
+
+        bool outsideCorner(vec2 position, vec2 center, vec2 direction, float radius) {
+            vec2 offset = (position - center) * direction;
+            return offset.x > 0.0 && offset.y > 0.0 && length(offset) > radius;
+        }
+
+        bool outsideRoundedClip(vec2 position) {
+            vec2 topLeft = uClipRect.xy;
+            vec2 bottomRight = uClipRect.zw;
+            return outsideCorner(position, topLeft + uClipRadii.xx, vec2(-1.0, -1.0),
+                                 uClipRadii.x) ||
+                   outsideCorner(position, vec2(bottomRight.x - uClipRadii.y,
+                                                topLeft.y + uClipRadii.y),
+                                 vec2(1.0, -1.0), uClipRadii.y) ||
+                   outsideCorner(position, bottomRight - uClipRadii.zz, vec2(1.0, 1.0),
+                                 uClipRadii.z) ||
+                   outsideCorner(position, vec2(topLeft.x + uClipRadii.w,
+                                                bottomRight.y - uClipRadii.w),
+                                 vec2(-1.0, 1.0), uClipRadii.w);
+        }
+    #endif
+
+    #ifdef COLOR_MATRIX
+        uniform mat4 uColorMatrix;
+        uniform vec4 uColorOffset;
+
+        // The texture is premultiplied, but color matrices apply to straight alpha.
+        vec4 applyColorMatrix(vec4 color) {
+            vec4 straight = color.a > 0.0 ? vec4(color.rgb / color.a, color.a) : vec4(0.0);
+            vec4 filtered = clamp(uColorMatrix * straight + uColorOffset, 0.0, 1.0);
+            return vec4(filtered.rgb * filtered.a, filtered.a);
+        }
+    #endif
+
+    void main(void) {
+        #ifdef ROUNDED_CLIP
+            if (outsideRoundedClip(vClipPosition)) {
+                discard;
+            }
+        #endif
+        vec4 lSampledColor = samplerFunction(uSampler, vTextureCoord);
+        #ifdef COLOR_MATRIX
+            lSampledColor = applyColorMatrix(lSampledColor);
+        #endif
+        vec4 lFragColor = uOpacity * lSampledColor;
+        gl_FragColor = lFragColor;
+    }
+";
+
+static SOLID_COLOR_FRAGMENT_SHADER_SOURCE: &'static str = "
+    #ifdef GL_ES
+        precision mediump float;
+    #endif
+
+    uniform vec4 uColor;
+    void main(void) {
+        gl_FragColor = uColor;
+    }
+";
+
+static TEXTURE_VERTEX_SHADER_SOURCE: &'static str = "
+    attribute vec2 aVertexPosition;
+    attribute vec2 aVertexUv;
+
+    uniform mat4 uMVMatrix;
+    uniform mat4 uPMatrix;
+    uniform mat4 uTextureSpaceTransform;
+
+    varying vec2 vTextureCoord;
+
+    #ifdef ROUNDED_CLIP
+        uniform mat4 uClipTransform;
+        varying vec2 vClipPosition;
+    #endif
+
+    void main(void) {
+        gl_Position = uPMatrix * uMVMatrix * vec4(aVertexPosition, 0.0, 1.0);
+        vTextureCoord = (uTextureSpaceTransform * vec4(aVertexUv, 0., 1.)).xy;
+        #ifdef ROUNDED_CLIP
+            vClipPosition = (uClipTransform * vec4(aVertexPosition, 0.0, 1.0)).xy;
+        #endif
+    }
+";
+
+static SOLID_COLOR_VERTEX_SHADER_SOURCE: &'static str = "
+    attribute vec2 aVertexPosition;
+
+    uniform mat4 uMVMatrix;
+    uniform mat4 uPMatrix;
+
+    void main(void) {
+        gl_Position = uPMatrix * uMVMatrix * vec4(aVertexPosition, 0.0, 1.0);
+    }
+";
+
+#[derive(Copy, Clone)]
+pub struct Buffers {
+    pub quad_vertex_buffer: GLuint,
+    pub line_quad_vertex_buffer: GLuint,
+}
+
+/// A shader that the GL implementation failed to compile or link.
+#[derive(Clone, Debug)]
+pub enum ShaderError {
+    /// A shader failed to compile.
+    Compile {
+        /// The info log of the shader.
+        info_log: String,
+        /// The source of the shader.
+        source: String,
+    },
+    /// The program failed to link.
+    Link {
+        /// The info log of the program.
+        info_log: String,
+        /// The source of the vertex shader.
+        vertex_source: String,
+        /// The source of the fragment shader.
+        fragment_source: String,
+    },
+}
+
+impl fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ShaderError::Compile { ref info_log, ref source } => {
+                write!(f, "Failed to compile shader: {}\n{}", info_log, source)
+            }
+            ShaderError::Link { ref info_log, ref vertex_source, ref fragment_source } => {
+                write!(f, "Failed to link shader program: {}\n{}\n{}",
+                       info_log, vertex_source, fragment_source)
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct ShaderProgram {
+    pub id: GLuint,
+}
+
+impl ShaderProgram {
+    /// Compiles and links a program from the given sources. Nothing is left behind on failure.
+    pub fn from_sources(gl: &GlApi, vertex_shader_source: &str, fragment_shader_source: &str)
+                        -> Result<ShaderProgram, ShaderError> {
+        let fragment_shader =
+            try!(ShaderProgram::compile_shader(gl, fragment_shader_source, gl::FRAGMENT_SHADER));
+        let vertex_shader =
+            match ShaderProgram::compile_shader(gl, vertex_shader_source, gl::VERTEX_SHADER) {
+                Ok(vertex_shader) => vertex_shader,
+                Err(error) => {
+                    gl::delete_shader(gl, fragment_shader);
+                    return Err(error);
+                }
+            };
+
+        let id = gl::create_program(gl);
+        gl::attach_shader(gl, id, fragment_shader);
+        gl::attach_shader(gl, id, vertex_shader);
+        gl::link_program(gl, id);
+
+        // The program keeps the shaders alive for as long as they are attached.
+        gl::delete_shader(gl, fragment_shader);
+        gl::delete_shader(gl, vertex_shader);
+
+        if gl::get_program_iv(gl, id, gl::LINK_STATUS) == (0 as GLint) {
+            let info_log = gl::get_program_info_log(gl, id);
+            gl::delete_program(gl, id);
+            return Err(ShaderError::Link {
+                info_log: info_log,
+                vertex_source: vertex_shader_source.to_owned(),
+                fragment_source: fragment_shader_source.to_owned(),
+            });
+        }
+
+        Ok(ShaderProgram {
+            id: id,
+        })
+    }
+
+    pub fn compile_shader(gl: &GlApi, source_string: &str, shader_type: GLenum)
+                          -> Result<GLuint, ShaderError> {
+        let id = gl::create_shader(gl, shader_type);
+        gl::shader_source(gl, id, &[ source_string.as_bytes() ]);
+        gl::compile_shader(gl, id);
+        if gl::get_shader_iv(gl, id, gl::COMPILE_STATUS) == (0 as GLint) {
+            let info_log = gl::get_shader_info_log(gl, id);
+            gl::delete_shader(gl, id);
+            return Err(ShaderError::Compile {
+                info_log: info_log,
+                source: source_string.to_owned(),
+            });
+        }
+
+        Ok(id)
+    }
+
+    /// Looks up a vertex attribute. Drivers may optimize unused attributes away, so a missing
+    /// one isn't fatal: the returned attribute is then never touched.
+    pub fn get_attribute(&self, gl: &GlApi, name: &str) -> Attribute {
+        let location = gl::get_attrib_location(gl, self.id, name);
+        if location < 0 {
+            warn!("Shader program {} has no attribute named {}", self.id, name);
+            return Attribute(None);
+        }
+        Attribute(Some(location as GLuint))
+    }
+
+    /// Looks up a uniform. As with `get_attribute`, a missing uniform is never set.
+    pub fn get_uniform(&self, gl: &GlApi, name: &str) -> Uniform {
+        let location = gl::get_uniform_location(gl, self.id, name);
+        if location < 0 {
+            warn!("Shader program {} has no uniform named {}", self.id, name);
+            return Uniform(None);
+        }
+        Uniform(Some(location))
+    }
+}
+
+/// The location of a vertex attribute in a shader program, or None if the program doesn't have
+/// the attribute.
+#[derive(Copy, Clone)]
+pub struct Attribute(Option<GLuint>);
+
+impl Attribute {
+    /// Returns true if the program has this attribute.
+    pub fn is_present(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn enable(&self, gl: &GlApi) {
+        if let Some(location) = self.0 {
+            gl::enable_vertex_attrib_array(gl, location);
+        }
+    }
+
+    pub fn disable(&self, gl: &GlApi) {
+        if let Some(location) = self.0 {
+            gl::disable_vertex_attrib_array(gl, location);
+        }
+    }
+
+    pub fn set_f32_pointer(&self, gl: &GlApi, size: GLint, stride: usize, offset: u32) {
+        if let Some(location) = self.0 {
+            gl::vertex_attrib_pointer_f32(gl, location, size, false, stride as GLsizei, offset);
+        }
+    }
+}
+
+/// The location of a uniform in a shader program, or None if the program doesn't have the
+/// uniform.
+#[derive(Copy, Clone)]
+pub struct Uniform(Option<GLint>);
+
+impl Uniform {
+    /// Returns true if the program has this uniform.
+    pub fn is_present(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn set_mat4(&self, gl: &GlApi, matrix: &Matrix4D<f32>) {
+        if let Some(location) = self.0 {
+            gl::uniform_matrix_4fv(gl, location, false, &matrix.to_row_major_array());
+        }
+    }
+
+    /// Sets a matrix given in the column-major order of GLSL.
+    pub fn set_column_major_mat4(&self, gl: &GlApi, values: &[f32; 16]) {
+        if let Some(location) = self.0 {
+            gl::uniform_matrix_4fv(gl, location, false, values);
+        }
+    }
+
+    pub fn set_f32(&self, gl: &GlApi, value: f32) {
+        if let Some(location) = self.0 {
+            gl::uniform_1f(gl, location, value);
+        }
+    }
+
+    pub fn set_vec4(&self, gl: &GlApi, x: f32, y: f32, z: f32, w: f32) {
+        if let Some(location) = self.0 {
+            gl::uniform_4f(gl, location, x as GLfloat, y as GLfloat, z as GLfloat, w as GLfloat);
+        }
+    }
+
+    pub fn set_color(&self, gl: &GlApi, color: &Color) {
+        self.set_vec4(gl, color.r, color.g, color.b, color.a)
+    }
+
+    pub fn set_sampler(&self, gl: &GlApi, texture_unit: GLint) {
+        if let Some(location) = self.0 {
+            gl::uniform_1i(gl, location, texture_unit);
+        }
+    }
+}
+
+/// Declares a shader program struct holding typed locations for the given attributes and
+/// uniforms. The generated `from_program` constructor looks all of them up, so a misspelled name
+/// is warned about as soon as the program is created, and `missing_locations` lists them.
+macro_rules! shader_program {
+    ($name:ident {
+        attributes { $($attribute:ident: $attribute_name:expr),* }
+        uniforms { $($uniform:ident: $uniform_name:expr),* }
+    }) => {
+        #[derive(Copy, Clone)]
+        pub struct $name {
+            pub program: ShaderProgram,
+            $(pub $attribute: Attribute,)*
+            $(pub $uniform: Uniform,)*
+        }
+
+        impl $name {
+            pub fn from_program(gl: &GlApi, program: ShaderProgram) -> $name {
+                $name {
+                    program: program,
+                    $($attribute: program.get_attribute(gl, $attribute_name),)*
+                    $($uniform: program.get_uniform(gl, $uniform_name),)*
+                }
+            }
+
+            /// Returns the names of the attributes and uniforms that the program doesn't have.
+            pub fn missing_locations(&self) -> Vec<&'static str> {
+                let mut missing = vec!();
+                $(if !self.$attribute.is_present() { missing.push($attribute_name); })*
+                $(if !self.$uniform.is_present() { missing.push($uniform_name); })*
+                missing
+            }
+
+            pub fn enable_attribute_arrays(&self, gl: &GlApi) {
+                $(self.$attribute.enable(gl);)*
+            }
+
+            pub fn disable_attribute_arrays(&self, gl: &GlApi) {
+                $(self.$attribute.disable(gl);)*
+            }
+        }
+    };
+}
+
+shader_program!(TextureProgram {
+    attributes {
+        vertex_position_attr: "aVertexPosition",
+        vertex_uv_attr: "aVertexUv"
+    }
+    uniforms {
+        modelview_uniform: "uMVMatrix",
+        projection_uniform: "uPMatrix",
+        sampler_uniform: "uSampler",
+        texture_space_transform_uniform: "uTextureSpaceTransform",
+        opacity_uniform: "uOpacity"
+    }
+});
+
+impl TextureProgram {
+    fn new(gl: &GlApi, sampler_function: &str, sampler_type: &str)
+           -> Result<TextureProgram, ShaderError> {
+        let program = try!(TextureProgram::compile(gl, sampler_function, sampler_type, ""));
+        Ok(TextureProgram::from_program(gl, program))
+    }
+
+    /// Compiles the texture shaders for the given sampler, prefixed with the given defines.
+    /// Rectangle samplers need an extension in GLSL before 1.40.
+    fn compile(gl: &GlApi, sampler_function: &str, sampler_type: &str, defines: &str)
+               -> Result<ShaderProgram, ShaderError> {
+        let vertex_shader_source = format!("{}{}", defines, TEXTURE_VERTEX_SHADER_SOURCE);
+        let extension = if sampler_type == "sampler2DRect" {
+            "#extension GL_ARB_texture_rectangle : enable\n"
+        } else {
+            ""
+        };
+        let fragment_shader_source =
+            fmt::format(format_args!("{}{}#define samplerFunction {}\n#define samplerType {}\n{}",
+                                     extension,
+                                     defines,
+                                     sampler_function,
+                                     sampler_type,
+                                     TEXTURE_FRAGMENT_SHADER_SOURCE));
+        ShaderProgram::from_sources(gl, &vertex_shader_source, &fragment_shader_source)
+    }
+
+    pub fn bind_uniforms_and_attributes(&self,
+                                    gl: &GlApi,
+                                    vertices: &[TextureVertex],
+                                    transform: &Matrix4D<f32>,
+                                    projection_matrix: &Matrix4D<f32>,
+                                    texture_space_transform: &Matrix4D<f32>,
+                                    buffers: &Buffers,
+                                    texture_unit: GLuint,
+                                    opacity: f32) {
+        self.sampler_uniform.set_sampler(gl, texture_unit as GLint);
+        self.modelview_uniform.set_mat4(gl, transform);
+        self.projection_uniform.set_mat4(gl, projection_matrix);
+
+        let vertex_size = mem::size_of::<TextureVertex>();
+
+        gl::bind_buffer(gl, gl::ARRAY_BUFFER, buffers.quad_vertex_buffer);
+        gl::buffer_data(gl, gl::ARRAY_BUFFER, vertices, gl::DYNAMIC_DRAW);
+        self.vertex_position_attr.set_f32_pointer(gl, 2, vertex_size, 0);
+        self.vertex_uv_attr.set_f32_pointer(gl, 2, vertex_size, 8);
+
+        self.texture_space_transform_uniform.set_mat4(gl, texture_space_transform);
+        self.opacity_uniform.set_f32(gl, opacity);
+    }
+
+    pub fn create_2d_program(gl: &GlApi) -> Result<TextureProgram, ShaderError> {
+        TextureProgram::new(gl, "texture2D", "sampler2D")
+    }
+
+    /// Creates the program for rectangle textures if they are supported. Returns None if they
+    /// aren't, or if the program fails to compile, in which case rectangle textures aren't
+    /// drawn.
+    #[cfg(not(target_os="android"))]
+    pub fn create_rectangle_program_if_necessary(gl: &GlApi, capabilities: &Capabilities)
+                                             -> Option<TextureProgram> {
+        if !capabilities.texture_rectangle {
+            return None;
+        }
+        gl::enable(gl, gl::TEXTURE_RECTANGLE_ARB);
+        rectangle_program_or_none(TextureProgram::new(gl, "texture2DRect", "sampler2DRect"))
+    }
+
+    #[cfg(target_os="android")]
+    pub fn create_rectangle_program_if_necessary(_: &GlApi, _: &Capabilities)
+                                                 -> Option<TextureProgram> {
+        None
+    }
+}
+
+/// Returns the given rectangle texture program, or None with a warning if it failed to compile.
+/// Some drivers advertise rectangle textures but reject their samplers, which must not keep the
+/// other programs from being used.
+fn rectangle_program_or_none<P>(program: Result<P, ShaderError>) -> Option<P> {
+    match program {
+        Ok(program) => Some(program),
+        Err(error) => {
+            warn!("Not drawing rectangle textures: their program failed to compile ({:?})",
+                  error);
+            None
+        }
+    }
+}
+
+/// A texture program that also discards the fragments outside of a rounded clip. Layers that
+/// are not clipped that way use the plain `TextureProgram`, so they don't pay for the clip.
+#[derive(Copy, Clone)]
+pub struct RoundedClipTextureProgram {
+    pub texture_program: TextureProgram,
+    pub clip_transform_uniform: Uniform,
+    pub clip_rect_uniform: Uniform,
+    pub clip_radii_uniform: Uniform,
+}
+
+impl RoundedClipTextureProgram {
+    fn new(gl: &GlApi, sampler_function: &str, sampler_type: &str)
+           -> Result<RoundedClipTextureProgram, ShaderError> {
+        let program = try!(TextureProgram::compile(gl,
+                                                   sampler_function,
+                                                   sampler_type,
+                                                   "#define ROUNDED_CLIP\n"));
+        Ok(RoundedClipTextureProgram::from_program(gl, program))
+    }
+
+    /// Looks up the locations of a program compiled with `ROUNDED_CLIP` defined.
+    fn from_program(gl: &GlApi, program: ShaderProgram) -> RoundedClipTextureProgram {
+        RoundedClipTextureProgram {
+            texture_program: TextureProgram::from_program(gl, program),
+            clip_transform_uniform: program.get_uniform(gl, "uClipTransform"),
+            clip_rect_uniform: program.get_uniform(gl, "uClipRect"),
+            clip_radii_uniform: program.get_uniform(gl, "uClipRadii"),
+        }
+    }
+
+    /// Binds the given clip. `clip_transform` maps the vertices to the screen space of the clip.
+    pub fn bind_clip(&self, gl: &GlApi, clip: &RoundedClip, clip_transform: &Matrix4D<f32>) {
+        self.clip_transform_uniform.set_mat4(gl, clip_transform);
+        self.clip_rect_uniform.set_vec4(gl,
+                                        clip.rect.min_x(),
+                                        clip.rect.min_y(),
+                                        clip.rect.max_x(),
+                                        clip.rect.max_y());
+        self.clip_radii_uniform.set_vec4(gl,
+                                         clip.radii[0],
+                                         clip.radii[1],
+                                         clip.radii[2],
+                                         clip.radii[3]);
+    }
+
+    pub fn create_2d_program(gl: &GlApi) -> Result<RoundedClipTextureProgram, ShaderError> {
+        RoundedClipTextureProgram::new(gl, "texture2D", "sampler2D")
+    }
+
+    /// See `TextureProgram::create_rectangle_program_if_necessary`.
+    #[cfg(not(target_os="android"))]
+    pub fn create_rectangle_program_if_necessary(gl: &GlApi, capabilities: &Capabilities)
+                                             -> Option<RoundedClipTextureProgram> {
+        if !capabilities.texture_rectangle {
+            return None;
+        }
+        rectangle_program_or_none(RoundedClipTextureProgram::new(gl,
+                                                                 "texture2DRect",
+                                                                 "sampler2DRect"))
+    }
+
+    #[cfg(target_os="android")]
+    pub fn create_rectangle_program_if_necessary(_: &GlApi, _: &Capabilities)
+                                             -> Option<RoundedClipTextureProgram> {
+        None
+    }
+}
+
+/// A texture program that also transforms the sampled colors with a `ColorMatrix`, for layers
+/// with a filter. Like the rounded clip, the filter is compiled into separate programs so that
+/// unfiltered layers don't pay for it. Filtered layers with a rounded clip use a variant that
+/// does both, whose clip is in `rounded_clip_program`.
+#[derive(Copy, Clone)]
+pub struct ColorMatrixTextureProgram {
+    pub texture_program: TextureProgram,
+    pub rounded_clip_program: Option<RoundedClipTextureProgram>,
+    pub color_matrix_uniform: Uniform,
+    pub color_offset_uniform: Uniform,
+}
+
+impl ColorMatrixTextureProgram {
+    fn new(gl: &GlApi, sampler_function: &str, sampler_type: &str, rounded_clip: bool)
+           -> Result<ColorMatrixTextureProgram, ShaderError> {
+        let defines = if rounded_clip {
+            "#define COLOR_MATRIX\n#define ROUNDED_CLIP\n"
+        } else {
+            "#define COLOR_MATRIX\n"
+        };
+        let program = try!(TextureProgram::compile(gl, sampler_function, sampler_type, defines));
+        Ok(ColorMatrixTextureProgram {
+            texture_program: TextureProgram::from_program(gl, program),
+            rounded_clip_program: if rounded_clip {
+                Some(RoundedClipTextureProgram::from_program(gl, program))
+            } else {
+                None
+            },
+            color_matrix_uniform: program.get_uniform(gl, "uColorMatrix"),
+            color_offset_uniform: program.get_uniform(gl, "uColorOffset"),
+        })
+    }
+
+    pub fn bind_color_matrix(&self, gl: &GlApi, color_matrix: &ColorMatrix) {
+        self.color_matrix_uniform.set_column_major_mat4(gl, &color_matrix.to_column_major_array());
+        let offset = color_matrix.offset();
+        self.color_offset_uniform.set_vec4(gl, offset[0], offset[1], offset[2], offset[3]);
+    }
+}
+
+/// The filtered texture programs, for each sampler and with and without a rounded clip.
+#[derive(Copy, Clone)]
+pub struct ColorMatrixPrograms {
+    plain_2d: ColorMatrixTextureProgram,
+    rounded_clip_2d: ColorMatrixTextureProgram,
+    plain_rectangle: Option<ColorMatrixTextureProgram>,
+    rounded_clip_rectangle: Option<ColorMatrixTextureProgram>,
+}
+
+impl ColorMatrixPrograms {
+    /// Creates the programs. If one of the 2D programs fails, those created so far are deleted;
+    /// a rectangle program that fails is left out, as in
+    /// `TextureProgram::create_rectangle_program_if_necessary`.
+    pub fn new(gl: &GlApi, capabilities: &Capabilities)
+               -> Result<ColorMatrixPrograms, ShaderError> {
+        let samplers = ColorMatrixPrograms::samplers(capabilities);
+        let mut programs = vec!();
+        for (index, &(sampler_function, sampler_type)) in samplers.iter().enumerate() {
+            for &rounded_clip in &[false, true] {
+                let program = ColorMatrixTextureProgram::new(gl,
+                                                             sampler_function,
+                                                             sampler_type,
+                                                             rounded_clip);
+                match program {
+                    Ok(program) => programs.push(Some(program)),
+                    Err(error) => {
+                        if index > 0 {
+                            programs.push(rectangle_program_or_none(Err(error)));
+                            continue;
+                        }
+                        for program in programs.iter().filter_map(|program| program.as_ref()) {
+                            gl::delete_program(gl, program.texture_program.program.id);
+                        }
+                        return Err(error);
+                    }
+                }
+            }
+        }
+        Ok(ColorMatrixPrograms {
+            plain_2d: programs[0].unwrap(),
+            rounded_clip_2d: programs[1].unwrap(),
+            plain_rectangle: programs.get(2).and_then(|program| *program),
+            rounded_clip_rectangle: programs.get(3).and_then(|program| *program),
+        })
+    }
+
+    #[cfg(not(target_os="android"))]
+    fn samplers(capabilities: &Capabilities) -> Vec<(&'static str, &'static str)> {
+        if capabilities.texture_rectangle {
+            vec![("texture2D", "sampler2D"), ("texture2DRect", "sampler2DRect")]
+        } else {
+            vec![("texture2D", "sampler2D")]
+        }
+    }
+
+    #[cfg(target_os="android")]
+    fn samplers(_: &Capabilities) -> Vec<(&'static str, &'static str)> {
+        vec![("texture2D", "sampler2D")]
+    }
+
+    /// Returns the program for textures with the given target, with or without a rounded clip,
+    /// or None if textures with that target can't be drawn.
+    pub fn select(&self, target: TextureTarget, rounded_clip: bool)
+                  -> Option<ColorMatrixTextureProgram> {
+        match (target, rounded_clip) {
+            (TextureTarget::TextureTarget2D, false) => Some(self.plain_2d),
+            (TextureTarget::TextureTarget2D, true) => Some(self.rounded_clip_2d),
+            (TextureTarget::TextureTargetRectangle, false) => self.plain_rectangle,
+            (TextureTarget::TextureTargetRectangle, true) => self.rounded_clip_rectangle,
+        }
+    }
+}
+
+shader_program!(SolidColorProgram {
+    attributes {
+        vertex_position_attr: "aVertexPosition"
+    }
+    uniforms {
+        modelview_uniform: "uMVMatrix",
+        projection_uniform: "uPMatrix",
+        color_uniform: "uColor"
+    }
+});
+
+impl SolidColorProgram {
+    pub fn new(gl: &GlApi) -> Result<SolidColorProgram, ShaderError> {
+        let program = try!(ShaderProgram::from_sources(gl,
+                                                       SOLID_COLOR_VERTEX_SHADER_SOURCE,
+                                                       SOLID_COLOR_FRAGMENT_SHADER_SOURCE));
+        Ok(SolidColorProgram::from_program(gl, program))
+    }
+
+    fn bind_uniforms_and_attributes_common(&self,
+                                           gl: &GlApi,
+                                           transform: &Matrix4D<f32>,
+                                           projection_matrix: &Matrix4D<f32>,
+                                           color: &Color) {
+        self.modelview_uniform.set_mat4(gl, transform);
+        self.projection_uniform.set_mat4(gl, projection_matrix);
+        self.color_uniform.set_color(gl, color);
+    }
+
+    pub fn bind_uniforms_and_attributes_for_lines(&self,
+                                              gl: &GlApi,
+                                              vertices: &[ColorVertex; 5],
+                                              transform: &Matrix4D<f32>,
+                                              projection_matrix: &Matrix4D<f32>,
+                                              buffers: &Buffers,
+                                              color: &Color) {
+        self.bind_uniforms_and_attributes_common(gl, transform, projection_matrix, color);
+
+        gl::bind_buffer(gl, gl::ARRAY_BUFFER, buffers.line_quad_vertex_buffer);
+        gl::buffer_data(gl, gl::ARRAY_BUFFER, vertices, gl::DYNAMIC_DRAW);
+        self.vertex_position_attr.set_f32_pointer(gl, 2, 0, 0);
+    }
+
+    pub fn bind_uniforms_and_attributes_for_quad(&self,
+                                             gl: &GlApi,
+                                             vertices: &[ColorVertex],
+                                             transform: &Matrix4D<f32>,
+                                             projection_matrix: &Matrix4D<f32>,
+                                             buffers: &Buffers,
+                                             color: &Color) {
+        self.bind_uniforms_and_attributes_common(gl, transform, projection_matrix, color);
+
+        gl::bind_buffer(gl, gl::ARRAY_BUFFER, buffers.quad_vertex_buffer);
+        gl::buffer_data(gl, gl::ARRAY_BUFFER, vertices, gl::DYNAMIC_DRAW);
+        self.vertex_position_attr.set_f32_pointer(gl, 2, 0, 0);
+    }
+}
+
+#[cfg(all(test, not(target_os = "android")))]
+mod tests {
+    use super::{ColorMatrixPrograms, RoundedClipTextureProgram, ShaderError, ShaderProgram};
+    use super::TextureProgram;
+    use capabilities::Capabilities;
+    use gltrace::GlCall;
+    use test_util::record_gl_calls;
+    use texturegl::TextureTarget;
+
+    fn capabilities_with_rectangle_textures() -> Capabilities {
+        Capabilities::from_strings("2.1", "GL_ARB_texture_rectangle", 4096)
+    }
+
+    #[test]
+    fn rectangle_programs_enable_their_extension() {
+        let gl = record_gl_calls();
+        let capabilities = capabilities_with_rectangle_textures();
+        assert!(TextureProgram::create_rectangle_program_if_necessary(&*gl, &capabilities)
+                    .is_some());
+
+        let fragment_sources: Vec<String> = gl.calls().iter().filter_map(|call| {
+            match *call {
+                GlCall::ShaderSource(_, ref strings) => {
+                    Some(String::from_utf8_lossy(&strings[0]).into_owned())
+                }
+                _ => None,
+            }
+        }).filter(|source| source.contains("samplerType")).collect();
+        assert_eq!(fragment_sources.len(), 1);
+        assert!(fragment_sources[0].starts_with("#extension GL_ARB_texture_rectangle : enable\n"));
+    }
+
+    #[test]
+    fn no_rectangle_programs_without_rectangle_textures() {
+        let gl = record_gl_calls();
+        let capabilities = Capabilities::from_strings("2.1", "", 4096);
+        assert!(TextureProgram::create_rectangle_program_if_necessary(&*gl, &capabilities)
+                    .is_none());
+        assert!(RoundedClipTextureProgram::create_rectangle_program_if_necessary(&*gl,
+                                                                                 &capabilities)
+                    .is_none());
+    }
+
+    #[test]
+    fn failed_rectangle_programs_are_left_out() {
+        let gl = record_gl_calls();
+        gl.fail_shaders_containing("sampler2DRect");
+        let capabilities = capabilities_with_rectangle_textures();
+        assert!(TextureProgram::create_rectangle_program_if_necessary(&*gl, &capabilities)
+                    .is_none());
+        assert!(RoundedClipTextureProgram::create_rectangle_program_if_necessary(&*gl,
+                                                                                 &capabilities)
+                    .is_none());
+        assert!(TextureProgram::create_2d_program(&*gl).is_ok());
+
+        let programs = ColorMatrixPrograms::new(&*gl, &capabilities).unwrap();
+        assert!(programs.select(TextureTarget::TextureTarget2D, false).is_some());
+        assert!(programs.select(TextureTarget::TextureTarget2D, true).is_some());
+        assert!(programs.select(TextureTarget::TextureTargetRectangle, false).is_none());
+        assert!(programs.select(TextureTarget::TextureTargetRectangle, true).is_none());
+    }
+
+    #[test]
+    fn failed_2d_programs_are_deleted() {
+        let gl = record_gl_calls();
+        gl.fail_shaders_containing("#define ROUNDED_CLIP");
+        assert!(ColorMatrixPrograms::new(&*gl, &capabilities_with_rectangle_textures()).is_err());
+        let deleted = gl.calls().iter().filter(|call| {
+            match **call {
+                GlCall::DeleteProgram(_) => true,
+                _ => false,
+            }
+        }).count();
+        assert_eq!(deleted, 1);
+    }
+
+    fn count_calls<F>(calls: &[GlCall], f: F) -> usize where F: Fn(&GlCall) -> bool {
+        calls.iter().filter(|call| f(call)).count()
+    }
+
+    #[test]
+    fn compile_failures_are_returned_and_leave_nothing_behind() {
+        let gl = record_gl_calls();
+        gl.fail_shaders_containing("broken");
+        match ShaderProgram::from_sources(&*gl, "void main() {}", "broken") {
+            Err(ShaderError::Compile { info_log, source }) => {
+                assert_eq!(info_log, "compile failed");
+                assert_eq!(source, "broken");
+            }
+            _ => panic!("expected a compile error"),
+        }
+
+        let calls = gl.take_calls();
+        let created = count_calls(&calls, |call| match *call {
+            GlCall::CreateShader(..) => true,
+            _ => false,
+        });
+        let deleted = count_calls(&calls, |call| match *call {
+            GlCall::DeleteShader(..) => true,
+            _ => false,
+        });
+        assert_eq!((created, deleted), (1, 1));
+        assert_eq!(count_calls(&calls, |call| match *call {
+            GlCall::CreateProgram(..) => true,
+            _ => false,
+        }), 0);
+
+        // A failing vertex shader also deletes the fragment shader compiled before it.
+        match ShaderProgram::from_sources(&*gl, "broken", "void main() {}") {
+            Err(ShaderError::Compile { source, .. }) => assert_eq!(source, "broken"),
+            _ => panic!("expected a compile error"),
+        }
+        let calls = gl.take_calls();
+        assert_eq!(count_calls(&calls, |call| match *call {
+            GlCall::DeleteShader(..) => true,
+            _ => false,
+        }), 2);
+    }
+
+    #[test]
+    fn link_failures_are_returned_and_delete_the_program() {
+        let gl = record_gl_calls();
+        gl.fail_links();
+        match ShaderProgram::from_sources(&*gl, "vertex", "fragment") {
+            Err(ShaderError::Link { info_log, vertex_source, fragment_source }) => {
+                assert_eq!(info_log, "link failed");
+                assert_eq!((&*vertex_source, &*fragment_source), ("vertex", "fragment"));
+            }
+            _ => panic!("expected a link error"),
+        }
+        let calls = gl.take_calls();
+        assert_eq!(count_calls(&calls, |call| match *call {
+            GlCall::DeleteProgram(..) => true,
+            _ => false,
+        }), 1);
+        assert_eq!(count_calls(&calls, |call| match *call {
+            GlCall::DeleteShader(..) => true,
+            _ => false,
+        }), 2);
+    }
+
+    #[test]
+    fn missing_locations_are_not_fatal_and_never_set() {
+        let gl = record_gl_calls();
+        gl.remove_location("aVertexUv");
+        gl.remove_location("uOpacity");
+        let program = TextureProgram::create_2d_program(&*gl).unwrap();
+        assert_eq!(program.missing_locations(), vec!("aVertexUv", "uOpacity"));
+        gl.take_calls();
+
+        program.enable_attribute_arrays(&*gl);
+        program.vertex_uv_attr.set_f32_pointer(&*gl, 2, 16, 8);
+        program.opacity_uniform.set_f32(&*gl, 0.5);
+        program.disable_attribute_arrays(&*gl);
+
+        let calls = gl.take_calls();
+        assert_eq!(calls.len(), 2);
+        match (&calls[0], &calls[1]) {
+            (&GlCall::EnableVertexAttribArray(enabled),
+             &GlCall::DisableVertexAttribArray(disabled)) => assert_eq!(enabled, disabled),
+            _ => panic!("unexpected calls"),
+        }
+    }
+
+    #[test]
+    fn present_locations_are_set() {
+        let gl = record_gl_calls();
+        let program = TextureProgram::create_2d_program(&*gl).unwrap();
+        assert!(program.missing_locations().is_empty());
+        let location = gl.calls().iter().filter_map(|call| {
+            match *call {
+                GlCall::GetUniformLocation(_, ref name, location) if name == "uOpacity" => {
+                    Some(location)
+                }
+                _ => None,
+            }
+        }).next().unwrap();
+        gl.take_calls();
+
+        program.opacity_uniform.set_f32(&*gl, 0.5);
+        let calls = gl.take_calls();
+        assert_eq!(calls.len(), 1);
+        match calls[0] {
+            GlCall::Uniform1f(set_location, value) => {
+                assert_eq!(set_location, location);
+                assert_eq!(value, 0.5);
+            }
+            _ => panic!("unexpected calls"),
+        }
+    }
+}
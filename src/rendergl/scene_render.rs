@@ -0,0 +1,1087 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The entry points for rendering and tearing down a scene.
+//!
+//! Rendering a scene sets the GL viewport to the scene viewport and projects the part of the
+//! layer tree within the viewport onto it. It enables depth testing with `GL_LEQUAL`, clears the
+//! color and depth buffers, the former to the premultiplied background color of the scene, and
+//! selects our texture unit. Depth testing is left enabled afterwards.
+//! When the 2D path is taken, depth testing is disabled instead and only the color buffer is
+//! cleared. `RenderOptions::clear_policy` can limit the clears to the viewport, or leave the
+//! color buffer alone, so that several scenes can be drawn into one framebuffer. Blending is
+//! enabled with the premultiplied alpha blend function. If
+//! `RenderOptions::preserve_embedder_gl_state` is set, all of this state, the texture unit that
+//! was active on entry, and the texture bindings on our unit are restored before returning, or
+//! by `RenderContext::end_frame` if `RenderContext::begin_frame` saved them for the whole frame;
+//! `SavedGlState` lists the state that is not. Textures dropped since the last frame are deleted
+//! first.
+//!
+//! `render_scene_to_framebuffer` draws the same way into a framebuffer object and reads the
+//! result back, restoring all the GL state it changes.
+
+use frame::{FrameIndex, FrameInfo};
+use geometry::DevicePixel;
+use layers::{Layer, LayerBuffer};
+use scene::Scene;
+use texturegl::TextureReclaimQueue;
+use super::context3d::{self, RenderContext3D};
+use super::draw::{self, create_ortho_for_rect, ClearPolicy, RenderContext, SavedGlState};
+use super::draw::SavedTextureState;
+use super::offscreen::{OffscreenTarget, RenderError};
+use super::statistics;
+
+use euclid::{Matrix4D, Size2D};
+use euclid::point::TypedPoint2D;
+use euclid::rect::{Rect, TypedRect};
+use euclid::size::TypedSize2D;
+use gltrace as gl;
+use gleam::gl::{GLint, GLsizei};
+use std::rc::Rc;
+use std::time::Instant;
+
+/// Renders a scene. If the layer tree is flat, that is, no layer has a 3D transform or
+/// establishes a nested rendering context, or if the scene prefers 2D rendering and no layer has
+/// a 3D transform, the layer tree is drawn directly in paint order without depth testing. This
+/// path doesn't allocate and draws the same as the general one.
+pub fn render_scene<T>(root_layer: Rc<Layer<T>>,
+                       render_context: RenderContext,
+                       scene: &Scene<T>) {
+    draw_scene(root_layer, &render_context, scene, &scene.viewport);
+}
+
+/// Renders several scenes into one framebuffer, in order, each into its own viewport. The whole
+/// framebuffer is cleared once to transparent black, and each scene then clears only its
+/// viewport to its background color, so where viewports overlap the later scene wins. The
+/// clear policy in the options of the render context is ignored.
+pub fn render_scenes<T>(scenes: &[(&Scene<T>, Rc<Layer<T>>)], render_context: RenderContext) {
+    let gl = render_context.gl();
+    gl::clear_color(gl, 0.0, 0.0, 0.0, 0.0);
+    gl::clear(gl, gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+
+    let mut render_context = render_context;
+    let mut options = render_context.options();
+    options.clear_policy = ClearPolicy::ClearViewportOnly;
+    render_context.set_options(options);
+    for &(scene, ref root_layer) in scenes {
+        draw_scene(root_layer.clone(), &render_context, scene, &scene.viewport);
+    }
+}
+
+/// Renders a scene from a render context built by the caller. Embedders can keep a
+/// `RenderContext3D` around and call `rebuild_into` each frame to avoid reallocating it.
+pub fn render_scene_with_3d_context<T>(context_3d: &RenderContext3D<T>,
+                                       render_context: RenderContext,
+                                       scene: &Scene<T>) {
+    draw_scene_with_3d_context(context_3d, &render_context, scene, &scene.viewport);
+}
+
+/// A framebuffer that scenes are rendered into by `render_scene_to_framebuffer`. It is kept
+/// between calls and only recreated when the requested size changes. It must be dropped while
+/// the GL context is current.
+pub struct ReadbackTarget {
+    target: Option<OffscreenTarget>,
+}
+
+impl ReadbackTarget {
+    pub fn new() -> ReadbackTarget {
+        ReadbackTarget {
+            target: None,
+        }
+    }
+}
+
+/// Renders a scene into the framebuffer of `target`, at the given size in device pixels, and
+/// returns the result as tightly packed RGBA rows ordered from top to bottom. The scene is drawn
+/// as by `render_scene`, but with a viewport at the origin of the framebuffer with the given
+/// size, which shows the layer tree from the origin of the scene viewport. All GL state that is
+/// changed is restored.
+pub fn render_scene_to_framebuffer<T>(root_layer: Rc<Layer<T>>,
+                                      render_context: RenderContext,
+                                      scene: &Scene<T>,
+                                      target: &mut ReadbackTarget,
+                                      size: Size2D<usize>)
+                                      -> Result<Vec<u8>, RenderError> {
+    if size.width == 0 || size.height == 0 {
+        return Err(RenderError::EmptyTarget);
+    }
+
+    let gl = render_context.gl();
+    let options = render_context.options();
+    let saved_gl_state = SavedGlState::save(gl);
+    let saved_texture_state = SavedTextureState::save(gl,
+                                                      options.texture_unit,
+                                                      &render_context.capabilities());
+
+    let result = render_scene_into_target(root_layer, &render_context, scene, target, size);
+
+    gl::active_texture(gl, gl::TEXTURE0 + options.texture_unit);
+    saved_texture_state.restore(gl);
+    saved_gl_state.restore(gl);
+    result
+}
+
+fn render_scene_into_target<T>(root_layer: Rc<Layer<T>>,
+                               render_context: &RenderContext,
+                               scene: &Scene<T>,
+                               target: &mut ReadbackTarget,
+                               size: Size2D<usize>)
+                               -> Result<Vec<u8>, RenderError> {
+    let reusable = target.target.as_ref().map_or(false, |offscreen| offscreen.size == size);
+    if reusable {
+        target.target.as_ref().unwrap().bind();
+    } else {
+        // Delete the old framebuffer before creating the new one.
+        target.target = None;
+        target.target = Some(try!(OffscreenTarget::new(render_context.shared_gl(), size, true)));
+    }
+
+    let viewport = TypedRect::new(TypedPoint2D::zero(),
+                                  TypedSize2D::new(size.width as f32, size.height as f32));
+    draw_scene(root_layer, render_context, scene, &viewport);
+    Ok(target.target.as_ref().unwrap().read_pixels())
+}
+
+/// Draws a scene into the given viewport, taking the 2D path if possible.
+fn draw_scene<T>(root_layer: Rc<Layer<T>>,
+                 render_context: &RenderContext,
+                 scene: &Scene<T>,
+                 viewport: &TypedRect<f32, DevicePixel>) {
+    scene.flush_transform_updates();
+    if scene.draws_flat(&root_layer) {
+        scene.note_flat_frame();
+        let (batch_count, batched_layer_count) = Scene::count_layer_batches(&root_layer);
+        statistics::record(|statistics| {
+            statistics.flat_pass_count += 1;
+            statistics.batch_count += batch_count;
+            statistics.batched_layer_count += batched_layer_count;
+        });
+        render_frame(render_context, scene, viewport, false, |transform, projection, frame| {
+            context3d::render_2d_layer_tree(render_context,
+                                            &root_layer,
+                                            transform,
+                                            projection,
+                                            frame);
+        });
+    } else {
+        draw_scene_with_3d_context(&RenderContext3D::new(root_layer),
+                                   render_context,
+                                   scene,
+                                   viewport);
+    }
+}
+
+fn draw_scene_with_3d_context<T>(context_3d: &RenderContext3D<T>,
+                                 render_context: &RenderContext,
+                                 scene: &Scene<T>,
+                                 viewport: &TypedRect<f32, DevicePixel>) {
+    render_frame(render_context, scene, viewport, true, |transform, projection, frame| {
+        let skipped_clear_count =
+            context3d::render_3d_context(render_context, context_3d, transform, projection, frame);
+        scene.note_skipped_depth_clears(skipped_clear_count);
+    });
+}
+
+/// Sets up the GL state for a frame drawn into the given viewport, calls `draw` with the initial
+/// transform, the projection, and the current frame, and restores the embedder state afterwards
+/// if requested.
+fn render_frame<T, F>(render_context: &RenderContext,
+                      scene: &Scene<T>,
+                      viewport: &TypedRect<f32, DevicePixel>,
+                      use_depth_buffer: bool,
+                      draw: F)
+                      where F: FnOnce(&Matrix4D<f32>, &Matrix4D<f32>, &FrameInfo) {
+    let gl = render_context.gl();
+    let start_time = Instant::now();
+    debug_assert!(render_context.last_frame().is_some(),
+                  "RenderContext::begin_frame must be called before rendering");
+    let frame = render_context.last_frame().unwrap_or(FrameInfo {
+        index: FrameIndex(0),
+        time: 0.0,
+    });
+
+    render_context.purge_dead_textures();
+
+    // Textures are bound and uploaded on our texture unit only, so that we don't disturb the
+    // bindings of the embedder on other units. Embedder state saved for the whole frame is
+    // restored by `RenderContext::end_frame` instead.
+    let options = render_context.options();
+    let saved_state = if options.preserve_embedder_gl_state &&
+                         !render_context.has_saved_embedder_gl_state() {
+        let texture_state = SavedTextureState::save(gl,
+                                                    options.texture_unit,
+                                                    &render_context.capabilities());
+        Some((SavedGlState::save(gl), texture_state))
+    } else {
+        gl::active_texture(gl, gl::TEXTURE0 + options.texture_unit);
+        None
+    };
+
+    // Each layer uses premultiplied alpha.
+    gl::enable(gl, gl::BLEND);
+    gl::blend_func(gl, gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
+
+    // Set the viewport.
+    let v = viewport.to_untyped();
+    gl::viewport(gl, v.origin.x as GLint, v.origin.y as GLint,
+                 v.size.width as GLsizei, v.size.height as GLsizei);
+
+    // We blend with premultiplied alpha, so the background must be premultiplied too.
+    let background_color = scene.background_color.premultiply();
+    gl::clear_color(gl,
+                    background_color.r,
+                    background_color.g,
+                    background_color.b,
+                    background_color.a);
+    let mut clear_bits = match options.clear_policy {
+        ClearPolicy::ClearAll | ClearPolicy::ClearViewportOnly => gl::COLOR_BUFFER_BIT,
+        ClearPolicy::NoClear => 0,
+    };
+    if use_depth_buffer {
+        // Enable depth testing for 3d transforms. Set z-mode to LESS-EQUAL
+        // so that layers with equal Z are able to paint correctly in
+        // the order they are specified.
+        gl::enable(gl, gl::DEPTH_TEST);
+        gl::depth_func(gl, gl::LEQUAL);
+        clear_bits |= gl::DEPTH_BUFFER_BIT;
+    } else {
+        gl::disable(gl, gl::DEPTH_TEST);
+    }
+
+    // Clears ignore the viewport, so limit them with the scissor test. Drawing is confined to
+    // the viewport by clipping anyway.
+    if options.clear_policy == ClearPolicy::ClearAll {
+        gl::clear(gl, clear_bits);
+    } else if clear_bits != 0 {
+        gl::enable(gl, gl::SCISSOR_TEST);
+        gl::scissor(gl, v.origin.x as GLint, v.origin.y as GLint,
+                    v.size.width as GLsizei, v.size.height as GLsizei);
+        gl::clear(gl, clear_bits);
+        gl::disable(gl, gl::SCISSOR_TEST);
+    }
+
+    // Set up the initial modelview matrix.
+    let transform = Matrix4D::identity().pre_scaled(scene.scale.get(), scene.scale.get(), 1.0);
+    // The layer tree is in the same space as the scene viewport, so the projection starts at its
+    // origin even when drawing into a framebuffer at the origin.
+    let projection_rect = TypedRect::new(scene.viewport.origin, viewport.size);
+    let projection = create_ortho_for_rect(&projection_rect.to_untyped());
+
+    draw(&transform, &projection, &frame);
+
+    if let Some((ref saved_gl_state, ref saved_texture_state)) = saved_state {
+        gl::active_texture(gl, gl::TEXTURE0 + options.texture_unit);
+        saved_texture_state.restore(gl);
+        saved_gl_state.restore(gl);
+    }
+
+    let render_time = start_time.elapsed();
+    statistics::record(|statistics| {
+        statistics.pass_count += 1;
+        statistics.render_time += render_time;
+    });
+}
+
+/// Everything handed back to the embedder by `shutdown`.
+pub struct ShutdownArtifacts {
+    /// Every buffer that was held by the layer tree. These must be sent back to the painting
+    /// task to be destroyed; their surfaces keep their leak marks until then.
+    pub buffers: Vec<Box<LayerBuffer>>,
+
+    /// The buffers among `buffers` whose surfaces were still live and marked as leaking at
+    /// shutdown, which leak unless the painting task destroys them.
+    pub leaked_surfaces: Vec<LeakRecord>,
+}
+
+/// Describes a buffer whose surface was still live at shutdown.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LeakRecord {
+    /// The ID of the surface, as returned by `NativeSurface::get_id`.
+    pub surface_id: isize,
+
+    /// The size of the surface in pixels.
+    pub size: Size2D<i32>,
+
+    /// The rect of the layer covered by the buffer.
+    pub rect: Rect<f32>,
+}
+
+fn collect_buffers_for_shutdown<T>(layer: &Rc<Layer<T>>,
+                                   context_lost: bool,
+                                   buffers: &mut Vec<Box<LayerBuffer>>) {
+    if context_lost {
+        layer.forget_textures();
+    }
+    buffers.extend(layer.collect_buffers().into_iter());
+
+    for child in layer.children().iter() {
+        collect_buffers_for_shutdown(child, context_lost, buffers);
+    }
+}
+
+/// Tears down a scene and its render context. This collects all buffers from the layer tree so
+/// they can be destroyed by the painting task, deletes the textures of all tiles along with any
+/// others waiting to be deleted, and finally deletes the GL objects of the render context. Both
+/// the scene and the context are consumed. The surfaces of the buffers that still leak unless
+/// they are destroyed are listed in `ShutdownArtifacts::leaked_surfaces`.
+///
+/// If `context_lost` is true, the GL context is assumed to be gone already and no GL calls are
+/// made at all; textures waiting to be deleted are forgotten.
+pub fn shutdown<T>(scene: Scene<T>,
+                   render_context: RenderContext,
+                   context_lost: bool)
+                   -> ShutdownArtifacts {
+    let mut buffers = vec!();
+    if let Some(ref root_layer) = scene.root {
+        collect_buffers_for_shutdown(root_layer, context_lost, &mut buffers);
+    }
+
+    // The buffers are handed back to the embedder, who must have the painting task destroy
+    // them. The surfaces that leak otherwise are recorded, rather than unmarked.
+    let leaked_surfaces: Vec<LeakRecord> = buffers.iter().filter(|buffer| {
+        buffer.native_surface.will_leak()
+    }).map(|buffer| {
+        LeakRecord {
+            surface_id: buffer.native_surface.get_id(),
+            size: buffer.native_surface.get_size(),
+            rect: buffer.rect,
+        }
+    }).collect();
+    for leaked_surface in &leaked_surfaces {
+        debug!("Surface still live at shutdown: {:?}", leaked_surface);
+    }
+
+    // Dropping the layer tree queues the textures of its tiles for deletion.
+    drop(scene);
+    let reclaim_queue = TextureReclaimQueue::for_current_thread();
+    if context_lost {
+        reclaim_queue.forget();
+    } else {
+        reclaim_queue.purge(render_context.gl());
+        draw::destroy(render_context);
+    }
+
+    ShutdownArtifacts {
+        buffers: buffers,
+        leaked_surfaces: leaked_surfaces,
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use color::Color;
+    use euclid::{Matrix4D, Point3D, Radians};
+    use euclid::point::{Point2D, TypedPoint2D};
+    use euclid::rect::TypedRect;
+    use euclid::size::{Size2D, TypedSize2D};
+    use frame::FrameIndex;
+    use gltrace::{self as gl, GlApi, GlCall};
+    use layers::Layer;
+    use platform::surface::{NativeSurface, SerializedSurface};
+    use rustc_serialize::json;
+    use scene::Scene;
+    use std::rc::Rc;
+    use super::{LeakRecord, ReadbackTarget, render_scene, render_scene_to_framebuffer};
+    use super::{render_scenes, shutdown};
+    use super::super::offscreen::RenderError;
+    use super::super::draw::RenderContext;
+    use test_util::{buffer_for_request, headless_display, layer, record_gl_calls};
+
+    /// Returns a scene whose root layer has two painted tiles. If `pixmap` is given, the first
+    /// tile is given a pixmap with that ID instead of a memory surface.
+    fn painted_scene(pixmap: Option<isize>) -> Scene<()> {
+        let root = layer(0.0, 0.0, 512.0, 256.0, 256);
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(512.0, 256.0)));
+        scene.root = Some(root.clone());
+        let mut requests = vec!();
+        let mut unused_buffers = vec!();
+        scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+        for (index, request) in requests[0].1.iter().enumerate() {
+            let mut buffer = buffer_for_request(request);
+            if let (0, Some(pixmap)) = (index, pixmap) {
+                buffer.native_surface = leaking_pixmap(pixmap, buffer.native_surface.get_size());
+            }
+            assert!(scene.add_buffer(&root, buffer, FrameIndex(0)));
+        }
+        scene
+    }
+
+    /// Returns a pixmap surface that doesn't exist in any X server, marked as leaking.
+    fn leaking_pixmap(pixmap: isize, size: Size2D<i32>) -> NativeSurface {
+        let encoded = format!("{{\"pixmap\":{},\"will_leak\":true,\"size\":{}}}",
+                              pixmap,
+                              json::encode(&size).unwrap());
+        NativeSurface::deserialize(&SerializedSurface::Pixmap(encoded)).unwrap()
+    }
+
+    #[test]
+    fn the_background_is_cleared_premultiplied() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let root = layer(0.0, 0.0, 64.0, 64.0, 256);
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(64.0, 64.0)));
+        scene.root = Some(root.clone());
+
+        for &(color, ref cleared) in &[(Color { r: 1.0, g: 0.5, b: 0.0, a: 0.5 },
+                                    GlCall::ClearColor(0.5, 0.25, 0.0, 0.5)),
+                                   (Color { r: 1.0, g: 1.0, b: 1.0, a: 0.0 },
+                                    GlCall::ClearColor(0.0, 0.0, 0.0, 0.0)),
+                                   (Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+                                    GlCall::ClearColor(1.0, 1.0, 1.0, 1.0))] {
+            scene.background_color = color;
+            gl.take_calls();
+            render_context.begin_frame(0.0);
+            render_scene(root.clone(), render_context.clone(), &scene);
+            let clear_colors: Vec<_> = gl.take_calls().into_iter().filter(|call| {
+                match *call {
+                    GlCall::ClearColor(..) => true,
+                    _ => false,
+                }
+            }).collect();
+            assert_eq!(clear_colors, vec!(cleared.clone()));
+        }
+    }
+
+    #[test]
+    fn the_projection_starts_at_the_viewport_origin() {
+        let gl = record_gl_calls();
+        // Debug borders are drawn by another program, with the same projection.
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), true, false);
+        let projection_locations: Vec<_> = gl.take_calls().iter().filter_map(|call| {
+            match *call {
+                GlCall::GetUniformLocation(_, ref name, location) if name == "uPMatrix" => {
+                    Some(location)
+                }
+                _ => None,
+            }
+        }).collect();
+        let root = layer(100.0, 50.0, 200.0, 100.0, 256);
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(100.0, 50.0),
+                                                  TypedSize2D::new(200.0, 100.0)));
+        scene.root = Some(root.clone());
+        let mut requests = vec!();
+        let mut unused_buffers = vec!();
+        scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+        for request in &requests[0].1 {
+            assert!(scene.add_buffer(&root, buffer_for_request(request), FrameIndex(0)));
+        }
+
+        render_context.begin_frame(0.0);
+        render_scene(root.clone(), render_context.clone(), &scene);
+        let calls = gl.take_calls();
+        assert!(calls.contains(&GlCall::Viewport(100, 50, 200, 100)));
+        let projections: Vec<_> = calls.iter().filter_map(|call| match *call {
+            GlCall::UniformMatrix4fv(location, false, ref values)
+                    if projection_locations.contains(&location) => {
+                Some(values.clone())
+            }
+            _ => None,
+        }).collect();
+        // The tile and the debug borders.
+        assert!(projections.len() > 1);
+        for values in projections {
+            let projection = Matrix4D::row_major(values[0], values[1], values[2], values[3],
+                                                 values[4], values[5], values[6], values[7],
+                                                 values[8], values[9], values[10], values[11],
+                                                 values[12], values[13], values[14], values[15]);
+            assert_eq!(projection.transform_point(&Point2D::new(100.0, 50.0)),
+                       Point2D::new(-1.0, 1.0));
+            assert_eq!(projection.transform_point(&Point2D::new(300.0, 150.0)),
+                       Point2D::new(1.0, -1.0));
+        }
+    }
+
+    #[test]
+    fn a_two_tile_layer_is_drawn_with_two_strips_of_its_own_textures() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let scene = painted_scene(None);
+        let root = scene.root.clone().unwrap();
+        gl.take_calls();
+
+        render_context.begin_frame(0.0);
+        render_scene(root, render_context.clone(), &scene);
+        let calls = gl.take_calls();
+        let textures: Vec<_> = calls.iter().filter_map(|call| match *call {
+            GlCall::GenTextures(ref textures) => Some(textures[0]),
+            _ => None,
+        }).collect();
+        assert_eq!(textures.len(), 2);
+
+        let mut bound_texture = None;
+        let mut draws = vec!();
+        for call in &calls {
+            match *call {
+                GlCall::BindTexture(gl::TEXTURE_2D, texture) => bound_texture = Some(texture),
+                GlCall::BindTexture(target, _) => panic!("Unexpected texture target {}", target),
+                GlCall::DrawArrays(mode, first, count) => {
+                    draws.push((mode, first, count, bound_texture))
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(draws, vec!((gl::TRIANGLE_STRIP, 0, 4, Some(textures[0])),
+                               (gl::TRIANGLE_STRIP, 0, 4, Some(textures[1]))));
+    }
+
+    /// Returns a scene with an unpainted 32x32 root layer, drawn into a 32x32 viewport at the given
+    /// x position with the given background color.
+    fn scene_with_background(x: f32, background_color: Color) -> Scene<()> {
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(x, 0.0),
+                                                  TypedSize2D::new(32.0, 32.0)));
+        scene.root = Some(layer(0.0, 0.0, 32.0, 32.0, 256));
+        scene.background_color = background_color;
+        scene
+    }
+
+    #[test]
+    fn several_scenes_clear_only_their_own_viewports() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let red = scene_with_background(0.0, Color { r: 1.0, g: 0.0, b: 0.0, a: 1.0 });
+        let green = scene_with_background(32.0, Color { r: 0.0, g: 1.0, b: 0.0, a: 1.0 });
+        gl.take_calls();
+
+        render_context.begin_frame(0.0);
+        render_scenes(&[(&red, red.root.clone().unwrap()), (&green, green.root.clone().unwrap())],
+                      render_context.clone());
+        // The flat scenes don't use the depth buffer.
+        let clears: Vec<_> = gl.take_calls().into_iter().filter(|call| match *call {
+            GlCall::Clear(..) | GlCall::Scissor(..) => true,
+            _ => false,
+        }).collect();
+        assert_eq!(clears,
+                   vec!(GlCall::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT),
+                        GlCall::Scissor(0, 0, 32, 32),
+                        GlCall::Clear(gl::COLOR_BUFFER_BIT),
+                        GlCall::Scissor(32, 0, 32, 32),
+                        GlCall::Clear(gl::COLOR_BUFFER_BIT)));
+    }
+
+    /// Draws two scenes side by side, and a third over both, into a real framebuffer. Run with
+    /// `--features egl-tests`; see `make_headless_egl_context_current`.
+    #[cfg(feature = "egl-tests")]
+    #[test]
+    fn scenes_rendered_side_by_side_keep_their_backgrounds() {
+        use gltrace::GleamGl;
+        use test_util::make_headless_egl_context_current;
+
+        let display = make_headless_egl_context_current(64, 32);
+        let gl = &GleamGl;
+        let mut render_context = RenderContext::new(display, false, false);
+        // Only errors raised while rendering count.
+        while gl::get_error() != gl::NO_ERROR {}
+        let red = scene_with_background(0.0, Color { r: 1.0, g: 0.0, b: 0.0, a: 1.0 });
+        let green = scene_with_background(32.0, Color { r: 0.0, g: 1.0, b: 0.0, a: 1.0 });
+        let mut blue = scene_with_background(24.0, Color { r: 0.0, g: 0.0, b: 1.0, a: 1.0 });
+        blue.viewport.size.width = 16.0;
+        let pixel_at = |x: i32| gl::read_pixels(gl, x, 16, 1, 1, gl::RGBA, gl::UNSIGNED_BYTE);
+
+        render_context.begin_frame(0.0);
+        render_scenes(&[(&red, red.root.clone().unwrap()), (&green, green.root.clone().unwrap())],
+                      render_context.clone());
+        assert_eq!(pixel_at(8), vec!(255, 0, 0, 255));
+        assert_eq!(pixel_at(31), vec!(255, 0, 0, 255));
+        assert_eq!(pixel_at(32), vec!(0, 255, 0, 255));
+        assert_eq!(pixel_at(56), vec!(0, 255, 0, 255));
+
+        // Where viewports overlap, the later scene wins.
+        render_context.begin_frame(1.0);
+        render_scenes(&[(&red, red.root.clone().unwrap()),
+                        (&green, green.root.clone().unwrap()),
+                        (&blue, blue.root.clone().unwrap())],
+                      render_context.clone());
+        assert_eq!(pixel_at(8), vec!(255, 0, 0, 255));
+        assert_eq!(pixel_at(28), vec!(0, 0, 255, 255));
+        assert_eq!(pixel_at(36), vec!(0, 0, 255, 255));
+        assert_eq!(pixel_at(40), vec!(0, 255, 0, 255));
+        assert_eq!(gl::get_error(), gl::NO_ERROR);
+    }
+
+    /// Draws a red tile over a blue background into a real framebuffer, with and without a
+    /// grayscale filter. Run with `--features egl-tests`.
+    #[cfg(feature = "egl-tests")]
+    #[test]
+    fn grayscale_filters_make_the_channels_of_tiles_equal() {
+        use color::ColorMatrix;
+        use gltrace::GleamGl;
+        use test_util::make_headless_egl_context_current;
+
+        let display = make_headless_egl_context_current(32, 32);
+        let gl = &GleamGl;
+        let mut render_context = RenderContext::new(display, false, false);
+        while gl::get_error() != gl::NO_ERROR {}
+        let mut scene = scene_with_background(0.0, Color { r: 0.0, g: 0.0, b: 1.0, a: 1.0 });
+        let root = scene.root.clone().unwrap();
+        let mut requests = vec!();
+        scene.get_buffer_requests(&mut requests, &mut vec!());
+        for request in &requests[0].1 {
+            let mut buffer = buffer_for_request(request);
+            let bgra = [0, 0, 255, 255];
+            let pixels: Vec<u8> = (0..32 * 32).flat_map(|_| bgra.iter().cloned()).collect();
+            buffer.native_surface.upload(&display, &pixels).unwrap();
+            assert!(scene.add_buffer(&root, buffer, FrameIndex(0)));
+        }
+        let pixel_at_center = || gl::read_pixels(gl, 16, 16, 1, 1, gl::RGBA, gl::UNSIGNED_BYTE);
+
+        render_context.begin_frame(0.0);
+        render_scene(root.clone(), render_context.clone(), &scene);
+        assert_eq!(pixel_at_center(), vec!(255, 0, 0, 255));
+
+        // Red is as bright as its luminance in gray.
+        *root.filter.borrow_mut() = Some(ColorMatrix::grayscale(1.0));
+        render_context.begin_frame(1.0);
+        render_scene(root.clone(), render_context.clone(), &scene);
+        let pixel = pixel_at_center();
+        assert!(pixel[0] == pixel[1] && pixel[1] == pixel[2], "{:?} isn't gray", pixel);
+        assert!((pixel[0] as i32 - 54).abs() <= 1, "{:?} isn't 21% bright", pixel);
+        assert_eq!(pixel[3], 255);
+        assert_eq!(gl::get_error(), gl::NO_ERROR);
+    }
+
+    #[test]
+    fn layers_hidden_behind_opaque_layers_are_culled() {
+        // A stack of three layers over the root: the bottom one is entirely behind the opaque
+        // middle one, which the translucent top one covers in part.
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let root = layer(0.0, 0.0, 512.0, 256.0, 256);
+        let bottom = layer(50.0, 50.0, 100.0, 100.0, 256);
+        let middle = layer(0.0, 0.0, 200.0, 200.0, 256);
+        *middle.contents_opaque.borrow_mut() = true;
+        let top = layer(150.0, 150.0, 100.0, 100.0, 256);
+        root.add_child(bottom.clone());
+        root.add_child(middle.clone());
+        root.add_child(top.clone());
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(512.0, 256.0)));
+        scene.root = Some(root.clone());
+        let mut render = |scene: &mut Scene<()>, time: f64| {
+            let mut requests = vec!();
+            scene.get_buffer_requests(&mut requests, &mut vec!());
+            for &(ref layer, ref layer_requests) in &requests {
+                for request in layer_requests {
+                    assert!(scene.add_buffer(layer, buffer_for_request(request), FrameIndex(0)));
+                }
+            }
+            render_context.take_statistics();
+            gl.take_calls();
+            render_context.begin_frame(time);
+            render_scene(root.clone(), render_context.clone(), scene);
+            let draw_calls = gl.take_calls().iter().filter(|call| match **call {
+                GlCall::DrawArrays(..) => true,
+                _ => false,
+            }).count();
+            (render_context.take_statistics(), draw_calls)
+        };
+
+        // Only the two tiles of the root and the tiles of the middle and top layers are drawn,
+        // and the culled layer isn't counted among the drawn ones.
+        let (statistics, draw_calls) = render(&mut scene, 0.0);
+        assert_eq!((statistics.layer_count, statistics.culled_layer_count), (3, 1));
+        assert_eq!(draw_calls, 4);
+        assert!(bottom.culled.get());
+        assert!(!middle.culled.get() && !top.culled.get() && !root.culled.get());
+
+        // Turned, the middle layer hides nothing, and neither does a translucent one.
+        middle.set_transform(Matrix4D::create_rotation(0.0, 0.0, 1.0, Radians::new(0.1)));
+        let (statistics, _) = render(&mut scene, 1.0);
+        assert_eq!(statistics.culled_layer_count, 0);
+        assert!(!bottom.culled.get());
+        middle.set_transform(Matrix4D::identity());
+        *middle.contents_opaque.borrow_mut() = false;
+        let (statistics, draw_calls) = render(&mut scene, 2.0);
+        assert_eq!((statistics.culled_layer_count, draw_calls), (0, 5));
+    }
+
+    #[test]
+    fn statistics_count_the_layers_tiles_and_draw_calls_of_a_pass() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let root = layer(0.0, 0.0, 512.0, 256.0, 256);
+        let child = layer(300.0, 50.0, 100.0, 100.0, 256);
+        root.add_child(child.clone());
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(512.0, 256.0)));
+        scene.root = Some(root.clone());
+        let mut requests = vec!();
+        scene.get_buffer_requests(&mut requests, &mut vec!());
+        for &(ref layer, ref layer_requests) in &requests {
+            for request in layer_requests {
+                assert!(scene.add_buffer(layer, buffer_for_request(request), FrameIndex(0)));
+            }
+        }
+        render_context.take_statistics();
+        gl.take_calls();
+
+        let mut render = |scene: &Scene<()>, time: f64| {
+            render_context.begin_frame(time);
+            render_scene(root.clone(), render_context.clone(), scene);
+            (render_context.take_statistics(), gl.take_calls())
+        };
+
+        // The two tiles of the root and the one of the child are drawn with a call each.
+        let (statistics, calls) = render(&scene, 0.0);
+        assert_eq!((statistics.pass_count, statistics.layer_count, statistics.tile_count),
+                   (1, 2, 3));
+        assert_eq!(statistics.skipped_tile_count, 0);
+        let draw_calls = calls.iter().filter(|call| match **call {
+            GlCall::DrawArrays(..) => true,
+            _ => false,
+        }).count();
+        assert_eq!((statistics.draw_call_count, draw_calls), (3, 3));
+        let vertex_bytes: usize = calls.iter().map(|call| match *call {
+            GlCall::BufferData(gl::ARRAY_BUFFER, ref data, _) => data.len,
+            _ => 0,
+        }).sum();
+        assert!(statistics.vertex_bytes > 0);
+        assert_eq!(statistics.vertex_bytes, vertex_bytes);
+
+        // The tile of a layer that has been requested but not painted yet is skipped.
+        root.add_child(layer(0.0, 0.0, 100.0, 100.0, 256));
+        scene.get_buffer_requests(&mut vec!(), &mut vec!());
+        let (statistics, _) = render(&scene, 1.0);
+        assert_eq!((statistics.tile_count, statistics.skipped_tile_count), (3, 1));
+        assert_eq!(statistics.draw_call_count, 3);
+    }
+
+    #[test]
+    fn statistics_break_the_layers_and_tiles_down_by_group() {
+        use super::super::GroupStatistics;
+
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let root = layer(0.0, 0.0, 512.0, 256.0, 256);
+        let child = layer(300.0, 50.0, 100.0, 100.0, 256);
+        child.set_group_id(Some(1));
+        root.add_child(child.clone());
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(512.0, 256.0)));
+        scene.root = Some(root.clone());
+        let mut requests = vec!();
+        scene.get_buffer_requests(&mut requests, &mut vec!());
+        for &(ref layer, ref layer_requests) in &requests {
+            for request in layer_requests {
+                assert!(scene.add_buffer(layer, buffer_for_request(request), FrameIndex(0)));
+            }
+        }
+        // A layer of another group whose tile has been requested but not painted yet.
+        let unpainted = layer(0.0, 0.0, 100.0, 100.0, 256);
+        unpainted.set_group_id(Some(2));
+        root.add_child(unpainted);
+        scene.get_buffer_requests(&mut vec!(), &mut vec!());
+        render_context.take_statistics();
+
+        // The root has no group, so its two tiles only count towards the totals.
+        render_context.begin_frame(0.0);
+        render_scene(root.clone(), render_context.clone(), &scene);
+        let statistics = render_context.take_statistics();
+        assert_eq!((statistics.layer_count, statistics.tile_count), (3, 3));
+        let group = |layer_count, tile_count, skipped_tile_count| {
+            GroupStatistics {
+                layer_count: layer_count,
+                tile_count: tile_count,
+                skipped_tile_count: skipped_tile_count,
+            }
+        };
+        assert_eq!(statistics.groups, vec!((1, group(1, 1, 0)), (2, group(1, 0, 1))));
+    }
+
+    #[test]
+    fn flat_frames_count_their_layer_batches() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let root = layer(0.0, 0.0, 512.0, 512.0, 256);
+        for &(x, y) in &[(0.0, 0.0), (100.0, 0.0), (0.0, 100.0), (300.0, 300.0)] {
+            root.add_child(layer(x, y, 100.0, 100.0, 256));
+        }
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(512.0, 512.0)));
+        scene.root = Some(root.clone());
+        render_context.take_statistics();
+
+        render_context.begin_frame(0.0);
+        render_scene(root.clone(), render_context.clone(), &scene);
+        let statistics = render_context.take_statistics();
+        assert_eq!((statistics.batch_count, statistics.batched_layer_count), (1, 3));
+
+        // Layer trees drawn through a 3D context aren't batched.
+        root.children()[3].set_transform(Matrix4D::identity().pre_translated(0.0, 0.0, 10.0));
+        render_context.begin_frame(1.0);
+        render_scene(root.clone(), render_context.clone(), &scene);
+        let statistics = render_context.take_statistics();
+        assert_eq!((statistics.batch_count, statistics.batched_layer_count), (0, 0));
+    }
+
+    #[test]
+    fn scenes_rendered_to_a_framebuffer_are_read_back_top_row_first() {
+        let gl = record_gl_calls();
+        gl.set_integer(gl::FRAMEBUFFER_BINDING, 7);
+        gl.viewport(1, 2, 300, 200);
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let scene = painted_scene(None);
+        let root = scene.root.clone().unwrap();
+        let mut target = ReadbackTarget::new();
+
+        // Three rows of two pixels, as GL returns them from the bottom up.
+        let rows: Vec<u8> = (1..4).flat_map(|row| vec![row; 8]).collect();
+        gl.set_framebuffer_pixels(rows);
+        gl.take_calls();
+        render_context.begin_frame(0.0);
+        let pixels = render_scene_to_framebuffer(root.clone(),
+                                                 render_context,
+                                                 &scene,
+                                                 &mut target,
+                                                 Size2D::new(2, 3)).unwrap();
+        let expected: Vec<u8> = (1..4).rev().flat_map(|row| vec![row; 8]).collect();
+        assert_eq!(pixels, expected);
+
+        // The target is drawn with a viewport of its size, and the embedder's framebuffer and
+        // viewport are restored afterwards.
+        let calls = gl.take_calls();
+        assert!(calls.contains(&GlCall::Viewport(0, 0, 2, 3)));
+        assert!(calls.contains(&GlCall::ReadPixels(0, 0, 2, 3, gl::RGBA, gl::UNSIGNED_BYTE)));
+        let last_binding = calls.iter().rev().filter_map(|call| match *call {
+            GlCall::BindFramebuffer(_, framebuffer) => Some(framebuffer),
+            _ => None,
+        }).next();
+        assert_eq!(last_binding, Some(7));
+        assert_eq!(gl.get_integer_4v(gl::VIEWPORT), [1, 2, 300, 200]);
+    }
+
+    #[test]
+    fn readback_targets_are_reused_while_the_size_stays_the_same() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let scene = painted_scene(None);
+        let root = scene.root.clone().unwrap();
+        let mut target = ReadbackTarget::new();
+        let mut render = |size: Size2D<usize>, time: f64| {
+            render_context.begin_frame(time);
+            render_scene_to_framebuffer(root.clone(),
+                                        render_context.clone(),
+                                        &scene,
+                                        &mut target,
+                                        size)
+        };
+        let created_framebuffers = |calls: &[GlCall]| {
+            calls.iter().filter(|call| match **call {
+                GlCall::GenFramebuffers(..) => true,
+                _ => false,
+            }).count()
+        };
+
+        assert_eq!(render(Size2D::new(16, 16), 0.0).unwrap().len(), 16 * 16 * 4);
+        assert_eq!(created_framebuffers(&gl.take_calls()), 1);
+        assert_eq!(render(Size2D::new(16, 16), 1.0).unwrap().len(), 16 * 16 * 4);
+        assert_eq!(created_framebuffers(&gl.take_calls()), 0);
+
+        // A new size replaces the framebuffer, deleting the old one first.
+        assert_eq!(render(Size2D::new(8, 4), 2.0).unwrap().len(), 8 * 4 * 4);
+        let calls = gl.take_calls();
+        assert_eq!(created_framebuffers(&calls), 1);
+        let deleted = calls.iter().position(|call| match *call {
+            GlCall::DeleteFramebuffers(..) => true,
+            _ => false,
+        });
+        let created = calls.iter().position(|call| match *call {
+            GlCall::GenFramebuffers(..) => true,
+            _ => false,
+        });
+        assert!(deleted.unwrap() < created.unwrap());
+
+        match render(Size2D::new(0, 4), 3.0) {
+            Err(RenderError::EmptyTarget) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn statistics_report_which_path_each_pass_took() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let root = layer(0.0, 0.0, 512.0, 512.0, 256);
+        let child = Rc::new(Layer::new(TypedRect::new(TypedPoint2D::new(10.0, 10.0),
+                                                      TypedSize2D::new(100.0, 100.0)),
+                                       256,
+                                       Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 },
+                                       1.0,
+                                       true,
+                                       ()));
+        child.add_child(layer(0.0, 0.0, 50.0, 50.0, 256));
+        root.add_child(child.clone());
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(512.0, 512.0)));
+        scene.root = Some(root.clone());
+        render_context.take_statistics();
+
+        let mut render = |scene: &Scene<()>, time: f64| {
+            render_context.begin_frame(time);
+            render_scene(root.clone(), render_context.clone(), scene);
+            let statistics = render_context.take_statistics();
+            (statistics.pass_count, statistics.flat_pass_count)
+        };
+
+        // A nested context makes the tree not flat, unless 2D rendering is preferred.
+        assert_eq!(render(&scene, 0.0), (1, 0));
+        scene.prefer_2d_rendering = true;
+        assert_eq!(render(&scene, 1.0), (1, 1));
+
+        // A 3D transform always takes the 3D path.
+        child.set_transform(Matrix4D::identity().pre_translated(0.0, 0.0, 10.0));
+        assert_eq!(render(&scene, 2.0), (1, 0));
+    }
+
+    #[test]
+    fn flat_trees_are_drawn_without_the_depth_buffer() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let root = layer(0.0, 0.0, 1000.0, 1000.0, 256);
+        for index in 0..100 {
+            let (x, y) = ((index % 10) as f32 * 100.0, (index / 10) as f32 * 100.0);
+            root.add_child(layer(x, y, 100.0, 100.0, 256));
+        }
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(1000.0, 1000.0)));
+        scene.root = Some(root.clone());
+        render_context.take_statistics();
+        gl.take_calls();
+
+        let mut render = |time: f64| {
+            render_context.begin_frame(time);
+            render_scene(root.clone(), render_context.clone(), &scene);
+            let uses_depth = gl.take_calls().iter().any(|call| match *call {
+                GlCall::Enable(gl::DEPTH_TEST) => true,
+                GlCall::Clear(bits) => bits & gl::DEPTH_BUFFER_BIT != 0,
+                _ => false,
+            });
+            (render_context.take_statistics().flat_pass_count, uses_depth)
+        };
+        assert_eq!(render(0.0), (1, false));
+
+        // Lifting a layer off the root plane takes the 3D path, which uses the depth buffer.
+        root.children()[50].set_transform(Matrix4D::identity().pre_translated(0.0, 0.0, 10.0));
+        assert_eq!(render(1.0), (0, true));
+    }
+
+    #[test]
+    fn contexts_whose_layers_dont_intersect_skip_the_depth_clear() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let child = layer(50.0, 50.0, 100.0, 100.0, 256);
+        child.set_transform(Matrix4D::identity().pre_translated(0.0, 0.0, 10.0));
+        root.add_child(child.clone());
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(256.0, 256.0)));
+        scene.root = Some(root.clone());
+        gl.take_calls();
+
+        // Returns whether the context cleared the depth buffer on its own, apart from the clear
+        // at the start of the frame, and whether it turned depth testing off.
+        let mut render = |time: f64| {
+            render_context.begin_frame(time);
+            render_scene(root.clone(), render_context.clone(), &scene);
+            let calls = gl.take_calls();
+            (calls.contains(&GlCall::Clear(gl::DEPTH_BUFFER_BIT)),
+             calls.contains(&GlCall::Disable(gl::DEPTH_TEST)))
+        };
+
+        // The child is entirely in front of the root.
+        assert_eq!(render(0.0), (false, true));
+        assert_eq!(scene.skipped_depth_clear_count(), 1);
+
+        // Turned about its vertical center line, the child pierces the root.
+        child.set_transform_origin(Point3D::new(50.0, 50.0, 0.0));
+        child.set_transform(Matrix4D::create_rotation(0.0, 1.0, 0.0, Radians::new(0.5)));
+        assert_eq!(render(1.0), (true, false));
+        assert_eq!(scene.skipped_depth_clear_count(), 1);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "begin_frame must be called before rendering")]
+    fn rendering_without_beginning_a_frame_is_caught_in_debug_builds() {
+        let gl = record_gl_calls();
+        let render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let scene = painted_scene(None);
+        render_scene(scene.root.clone().unwrap(), render_context.clone(), &scene);
+    }
+
+    #[test]
+    fn shutdown_returns_every_buffer_once() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let scene = painted_scene(None);
+        render_context.begin_frame(0.0);
+        render_scene(scene.root.clone().unwrap(), render_context.clone(), &scene);
+
+        let artifacts = shutdown(scene, render_context, false);
+        let mut origins: Vec<_> = artifacts.buffers.iter().map(|buffer| {
+            buffer.screen_pos.origin.x
+        }).collect();
+        origins.sort();
+        assert_eq!(origins, vec!(0, 256));
+        assert!(artifacts.leaked_surfaces.is_empty());
+    }
+
+    #[test]
+    fn shutdown_after_context_loss_makes_no_gl_calls() {
+        let gl = record_gl_calls();
+        let mut render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let scene = painted_scene(None);
+        render_context.begin_frame(0.0);
+        render_scene(scene.root.clone().unwrap(), render_context.clone(), &scene);
+        gl.take_calls();
+
+        let artifacts = shutdown(scene, render_context, true);
+        assert_eq!(artifacts.buffers.len(), 2);
+        assert!(gl.calls().is_empty());
+    }
+
+    #[test]
+    fn surfaces_still_marked_as_leaking_are_recorded() {
+        let gl = record_gl_calls();
+        let render_context =
+            RenderContext::new_with_gl(gl.clone(), headless_display(), false, false);
+        let artifacts = shutdown(painted_scene(Some(42)), render_context, true);
+        assert_eq!(artifacts.leaked_surfaces,
+                   vec!(LeakRecord {
+                       surface_id: 42,
+                       size: Size2D::new(256, 256),
+                       rect: artifacts.buffers.iter()
+                                              .find(|buffer| buffer.native_surface.will_leak())
+                                              .unwrap()
+                                              .rect,
+                   }));
+
+        // The pixmap doesn't exist, so it can't be destroyed.
+        for mut buffer in artifacts.buffers {
+            buffer.mark_wont_leak();
+        }
+    }
+}
@@ -0,0 +1,156 @@
+// Copyright 2016 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Counters describing the work done by the render passes, for diagnosing slow frames.
+//!
+//! The counters are plain integers accumulated on the rendering thread as layers and tiles are
+//! drawn, whether or not anyone reads them. They add up over render passes until the embedder
+//! takes them with `RenderContext::take_statistics`. Like the texture reclaim queue, they are
+//! kept per thread, so render contexts sharing a thread share their statistics.
+
+use std::cell::RefCell;
+use std::mem;
+use std::time::Duration;
+
+thread_local!(static STATISTICS: RefCell<RenderStatistics> =
+                  RefCell::new(RenderStatistics::new()));
+
+/// What the render passes since the statistics were last taken did.
+#[derive(Clone, Debug)]
+pub struct RenderStatistics {
+    /// The number of calls to `render_scene` and its variants.
+    pub pass_count: usize,
+
+    /// The number of those passes that took the 2D path, drawing the layer tree in plain paint
+    /// order without building a `RenderContext3D`; see `Scene::draws_flat`. The others took the
+    /// 3D path.
+    pub flat_pass_count: usize,
+
+    /// The number of layers drawn.
+    pub layer_count: usize,
+
+    /// The number of tiles drawn.
+    pub tile_count: usize,
+
+    /// The number of layers that weren't drawn because opaque layers drawn after them hide them
+    /// entirely.
+    pub culled_layer_count: usize,
+
+    /// The number of tiles that weren't drawn because they have no texture yet.
+    pub skipped_tile_count: usize,
+
+    /// The number of runs of sibling layers that the 2D path found could be drawn as one; see
+    /// `Scene::compute_layer_batches`. Layer trees drawn through a 3D context aren't batched.
+    pub batch_count: usize,
+
+    /// The number of layers in those runs.
+    pub batched_layer_count: usize,
+
+    /// The number of draw calls made, including those for backgrounds and debug overlays.
+    pub draw_call_count: usize,
+
+    /// The number of bytes of vertex data uploaded.
+    pub vertex_bytes: usize,
+
+    /// The wall-clock time spent issuing GL commands in the render passes. The GPU may finish
+    /// the work later.
+    pub render_time: Duration,
+
+    /// The layers and tiles drawn by each 3D context, in the order the contexts were drawn, if
+    /// `RenderOptions::collect_context_statistics` is set. The counts of a context include
+    /// those of its nested contexts.
+    pub contexts: Vec<ContextStatistics>,
+
+    /// The layers and tiles drawn for each layer group, sorted by group id. Layers without a
+    /// group id are left out.
+    pub groups: Vec<(u64, GroupStatistics)>,
+}
+
+/// What drawing one 3D context did.
+#[derive(Clone, Copy, Debug)]
+pub struct ContextStatistics {
+    /// The number of layers drawn.
+    pub layer_count: usize,
+
+    /// The number of tiles drawn.
+    pub tile_count: usize,
+}
+
+/// What drawing the layers of one group did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GroupStatistics {
+    /// The number of layers drawn.
+    pub layer_count: usize,
+
+    /// The number of tiles drawn.
+    pub tile_count: usize,
+
+    /// The number of tiles that weren't drawn because they have no texture yet.
+    pub skipped_tile_count: usize,
+}
+
+impl RenderStatistics {
+    pub fn new() -> RenderStatistics {
+        RenderStatistics {
+            pass_count: 0,
+            flat_pass_count: 0,
+            layer_count: 0,
+            tile_count: 0,
+            culled_layer_count: 0,
+            skipped_tile_count: 0,
+            batch_count: 0,
+            batched_layer_count: 0,
+            draw_call_count: 0,
+            vertex_bytes: 0,
+            render_time: Duration::new(0, 0),
+            contexts: vec!(),
+            groups: vec!(),
+        }
+    }
+
+    /// Returns the statistics of the given group, adding them if the group has none yet.
+    pub fn group_mut(&mut self, group_id: u64) -> &mut GroupStatistics {
+        let index = match self.groups.binary_search_by(|entry| entry.0.cmp(&group_id)) {
+            Ok(index) => index,
+            Err(index) => {
+                self.groups.insert(index, (group_id, GroupStatistics::default()));
+                index
+            }
+        };
+        &mut self.groups[index].1
+    }
+}
+
+/// Updates the statistics of the current thread.
+pub fn record<F>(f: F) where F: FnOnce(&mut RenderStatistics) {
+    STATISTICS.with(|statistics| f(&mut *statistics.borrow_mut()))
+}
+
+/// Returns the statistics of the current thread and resets them.
+pub fn take() -> RenderStatistics {
+    STATISTICS.with(|statistics| {
+        mem::replace(&mut *statistics.borrow_mut(), RenderStatistics::new())
+    })
+}
+
+/// Returns the number of tiles drawn and skipped on the current thread so far.
+pub fn drawn_and_skipped_tile_counts() -> (usize, usize) {
+    STATISTICS.with(|statistics| {
+        let statistics = statistics.borrow();
+        (statistics.tile_count, statistics.skipped_tile_count)
+    })
+}
+
+/// Returns the number of layers and tiles drawn on the current thread so far.
+pub fn layer_and_tile_counts() -> (usize, usize) {
+    STATISTICS.with(|statistics| {
+        let statistics = statistics.borrow();
+        (statistics.layer_count, statistics.tile_count)
+    })
+}
@@ -0,0 +1,788 @@
+// Copyright 2016 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A software compositor, which draws a scene into memory without GL. It is meant for machines
+//! without a GPU and for reference images, not for speed.
+//!
+//! Layers are drawn in the order the GL compositor draws them, in plain paint order if the scene
+//! draws flat (see `Scene::draws_flat`) and in the order of their `RenderContext3D` otherwise,
+//! with the same clip rects, background colors, and opacities. Only layers whose final transform
+//! is an axis-aligned 2D transform, that is, a translation and scale, are drawn; others are
+//! skipped with a warning. Tiles are sampled at the nearest pixel, and a pixel is drawn if its
+//! center lies within a quad. Rounded clips, nine-patch layers, and the rotation of buffers are
+//! not supported. The tiles of the scene must be backed by memory surfaces.
+//!
+//! The debug overlays of `debug_overlay` are drawn as the GL compositor draws them, except that
+//! lines are drawn as rects straddling the edges they outline. Layers that are skipped still get
+//! their bounding box, which is in screen space. The golden image tests of this module check
+//! the overlays.
+
+use color::Color;
+use debug_overlay::{DebugOverlayMode, LAYER_AABB_BORDER_COLOR, LAYER_AABB_BORDER_THICKNESS};
+use debug_overlay::{LAYER_BORDER_COLOR, LAYER_BORDER_THICKNESS, TILE_BORDER_COLOR};
+use debug_overlay::{TILE_BORDER_THICKNESS, heatmap_color};
+use geometry::ScreenPixel;
+use layers::{Layer, RoundedClip};
+use rendergl::{RenderContext3D, for_each_2d_layer};
+use scene::Scene;
+use tiling::Tile;
+use util::{is_axis_aligned_2d_transform, matrix_is_finite, rect_has_valid_size};
+
+use euclid::{Matrix4D, Point2D, Rect, Size2D};
+use euclid::rect::TypedRect;
+use euclid::side_offsets::SideOffsets2D;
+use std::fmt;
+use std::rc::Rc;
+
+/// Errors that can occur when rendering in software.
+pub enum SoftwareRenderError<T> {
+    /// The output buffer is smaller than four bytes per pixel of the output size.
+    OutputTooSmall,
+    /// Some tiles are backed by surfaces whose pixels can't be read in memory. These are the
+    /// layers they belong to, in draw order. The rest of the scene was drawn.
+    UnsupportedSurfaces(Vec<Rc<Layer<T>>>),
+}
+
+impl<T> fmt::Debug for SoftwareRenderError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SoftwareRenderError::OutputTooSmall => write!(f, "OutputTooSmall"),
+            SoftwareRenderError::UnsupportedSurfaces(ref layers) => {
+                let bounds: Vec<_> = layers.iter().map(|layer| layer.bounds()).collect();
+                write!(f, "UnsupportedSurfaces(layers with bounds {:?})", bounds)
+            }
+        }
+    }
+}
+
+/// Renders a scene into `output`, which holds the BGRA pixels of an image of the given size
+/// with premultiplied alpha, top row first. As with `rendergl::render_scene_to_framebuffer`,
+/// the image shows the layer tree from the origin of the scene viewport.
+pub fn render_scene<T>(root_layer: Rc<Layer<T>>,
+                       scene: &Scene<T>,
+                       output: &mut [u8],
+                       output_size: Size2D<usize>)
+                       -> Result<(), SoftwareRenderError<T>> {
+    render_scene_with_debug_overlays(root_layer,
+                                     scene,
+                                     output,
+                                     output_size,
+                                     DebugOverlayMode::None)
+}
+
+/// Like `render_scene`, but also draws the debug overlays of `debug_overlay_mode`. The debug
+/// options of each layer are honored by both.
+pub fn render_scene_with_debug_overlays<T>(root_layer: Rc<Layer<T>>,
+                                           scene: &Scene<T>,
+                                           output: &mut [u8],
+                                           output_size: Size2D<usize>,
+                                           debug_overlay_mode: DebugOverlayMode)
+                                           -> Result<(), SoftwareRenderError<T>> {
+    if output.len() < output_size.width * output_size.height * 4 {
+        return Err(SoftwareRenderError::OutputTooSmall);
+    }
+
+    let background_color = premultiply(&scene.background_color, 1.0);
+    for pixel in output.chunks_mut(4).take(output_size.width * output_size.height) {
+        pixel.clone_from_slice(&background_color);
+    }
+
+    scene.flush_transform_updates();
+    let mut target = Target {
+        pixels: output,
+        size: output_size,
+        origin: scene.viewport.origin.to_untyped(),
+        scale: scene.scale.get(),
+    };
+    let mut unsupported_layers = vec!();
+    {
+        let mut draw_layer = |layer: &Rc<Layer<T>>,
+                              clip_rect: Option<TypedRect<f32, ScreenPixel>>,
+                              _: Option<&RoundedClip>| {
+            if !render_layer(&mut target,
+                             layer,
+                             layer.world_clip_rect(clip_rect),
+                             debug_overlay_mode) {
+                unsupported_layers.push(layer.clone());
+            }
+        };
+        if scene.draws_flat(&root_layer) {
+            for_each_2d_layer(&root_layer, &mut draw_layer);
+        } else {
+            RenderContext3D::new(root_layer).for_each_layer(&mut draw_layer);
+        }
+    }
+
+    if unsupported_layers.is_empty() {
+        Ok(())
+    } else {
+        Err(SoftwareRenderError::UnsupportedSurfaces(unsupported_layers))
+    }
+}
+
+struct Target<'a> {
+    pixels: &'a mut [u8],
+    size: Size2D<usize>,
+
+    /// The origin of the scene viewport, which is drawn at the top left corner of the output.
+    origin: Point2D<f32>,
+
+    /// The scale of the scene.
+    scale: f32,
+}
+
+impl<'a> Target<'a> {
+    /// Calls `f` with the coordinates of each output pixel whose center lies within `rect`,
+    /// given in output pixels, along with its center.
+    fn for_each_pixel<F>(&mut self, rect: &Rect<f32>, mut f: F)
+                         where F: FnMut(&mut [u8], Point2D<f32>) {
+        let min_x = (rect.origin.x - 0.5).ceil().max(0.0) as usize;
+        let min_y = (rect.origin.y - 0.5).ceil().max(0.0) as usize;
+        let max_x = ((rect.max_x() - 0.5).ceil().max(0.0) as usize).min(self.size.width);
+        let max_y = ((rect.max_y() - 0.5).ceil().max(0.0) as usize).min(self.size.height);
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                let start = (y * self.size.width + x) * 4;
+                f(&mut self.pixels[start..start + 4],
+                  Point2D::new(x as f32 + 0.5, y as f32 + 0.5));
+            }
+        }
+    }
+
+    /// Draws a premultiplied color over `rect`, given in output pixels.
+    fn fill_rect(&mut self, rect: &Rect<f32>, color: &[f32; 4]) {
+        self.for_each_pixel(rect, |pixel, _| blend_f32(pixel, color));
+    }
+
+    /// Outlines `rect`, given in output pixels, with lines of the given thickness centered on its
+    /// edges, as `glLineWidth` does.
+    fn stroke_rect(&mut self, rect: &Rect<f32>, color: &Color, thickness: usize) {
+        let color = premultiply(color, 1.0);
+        let color = [color[0] as f32 / 255.0,
+                     color[1] as f32 / 255.0,
+                     color[2] as f32 / 255.0,
+                     color[3] as f32 / 255.0];
+        let thickness = thickness as f32;
+        let half = thickness * 0.5;
+
+        // The horizontal lines cover the corners.
+        let width = rect.size.width + thickness;
+        let height = rect.size.height - thickness;
+        for y in &[rect.min_y(), rect.max_y()] {
+            self.fill_rect(&Rect::new(Point2D::new(rect.min_x() - half, *y - half),
+                                      Size2D::new(width, thickness)),
+                           &color);
+        }
+        for x in &[rect.min_x(), rect.max_x()] {
+            self.fill_rect(&Rect::new(Point2D::new(*x - half, rect.min_y() + half),
+                                      Size2D::new(thickness, height)),
+                           &color);
+        }
+    }
+
+    /// Maps a rect in world space, such as a screen rect of a layer, to output pixels.
+    fn world_to_output(&self, rect: &Rect<f32>) -> Rect<f32> {
+        Rect::new(Point2D::new(rect.origin.x * self.scale - self.origin.x,
+                               rect.origin.y * self.scale - self.origin.y),
+                  Size2D::new(rect.size.width * self.scale, rect.size.height * self.scale))
+    }
+}
+
+/// Draws a layer and its debug overlays. Returns false if some of its tiles couldn't be drawn
+/// because their surfaces are not in memory.
+fn render_layer<T>(target: &mut Target,
+                   layer: &Layer<T>,
+                   clip_rect: Option<Rect<f32>>,
+                   debug_overlay_mode: DebugOverlayMode)
+                   -> bool {
+    let all_supported = render_layer_contents(target, layer, clip_rect, debug_overlay_mode);
+
+    let show_aabb = debug_overlay_mode == DebugOverlayMode::Borders ||
+        layer.debug_options.borrow().show_aabb;
+    if show_aabb {
+        if let Some(ref screen_rect) = layer.transform_state.borrow().screen_rect {
+            let aabb = target.world_to_output(&screen_rect.rect.to_untyped());
+            target.stroke_rect(&aabb, &LAYER_AABB_BORDER_COLOR, LAYER_AABB_BORDER_THICKNESS);
+        }
+    }
+    all_supported
+}
+
+/// Draws the background and tiles of a layer, with the debug overlays in its plane.
+fn render_layer_contents<T>(target: &mut Target,
+                            layer: &Layer<T>,
+                            clip_rect: Option<Rect<f32>>,
+                            debug_overlay_mode: DebugOverlayMode)
+                            -> bool {
+    let ts = layer.transform_state.borrow();
+    if !is_axis_aligned_2d_transform(&ts.final_transform) {
+        warn!("Skipping a layer with a 3D or rotating transform in the software compositor.");
+        return true;
+    }
+    if layer.is_nine_patch() {
+        warn!("Skipping a nine-patch layer in the software compositor.");
+        return true;
+    }
+
+    let opacity = ts.effective_opacity;
+    let layer_rect = layer.clipped_content_rect(clip_rect);
+    if layer_rect.is_empty() || !rect_has_valid_size(&layer_rect) || opacity == 0.0 {
+        return true;
+    }
+
+    let origin = target.origin;
+
+    // Maps world space to the scene viewport, whose origin is at the top left of the output.
+    let transform = Matrix4D::identity().pre_scaled(target.scale, target.scale, 1.0)
+                                        .pre_mul(&ts.final_transform);
+    let from_output = match transform.inverse() {
+        Some(inverse) if matrix_is_finite(&inverse) => inverse.to_2d(),
+        _ => return true,
+    };
+    let transform = transform.to_2d();
+    let to_output = |rect: &Rect<f32>| {
+        let top_left = transform.transform_point(&rect.origin);
+        let max = transform.transform_point(&rect.bottom_right());
+        Rect::new(Point2D::new(top_left.x.min(max.x) - origin.x, top_left.y.min(max.y) - origin.y),
+                  Size2D::new((max.x - top_left.x).abs(), (max.y - top_left.y).abs()))
+    };
+
+    let background_color = *layer.background_color.borrow();
+    if background_color.a != 0.0 {
+        let color = premultiply(&background_color, opacity);
+        target.for_each_pixel(&to_output(&layer_rect), |pixel, _| blend(pixel, &color));
+    }
+
+    let tile_clip_rect = if layer.content_inset() == SideOffsets2D::zero() {
+        clip_rect
+    } else {
+        Some(layer_rect)
+    };
+    let debug_options = *layer.debug_options.borrow();
+    let show_debug_borders = debug_overlay_mode == DebugOverlayMode::Borders;
+    let show_tile_borders = show_debug_borders || debug_options.show_tile_borders;
+    let content_age = *layer.content_age.borrow();
+    let world_origin = ts.world_rect.origin.to_untyped();
+    let mut all_supported = true;
+    layer.do_for_all_tiles(|tile: &Tile| {
+        // Tiles only get their bounds once they are textured, so the rect of the buffer is used.
+        let buffer = match tile.buffer() {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        let bounds = buffer.rect;
+        let surface = match buffer.native_surface.as_memory_buffer() {
+            Some(surface) => surface,
+            None => {
+                all_supported = false;
+                return;
+            }
+        };
+        let pixels = match surface.bgra_pixels() {
+            Some(pixels) => pixels,
+            None => return,
+        };
+
+        let tile_rect = bounds.translate(&world_origin);
+        let drawn_rect = match tile.combined_clip_rect(&world_origin, tile_clip_rect) {
+            Some(clip_rect) => match clip_rect.intersection(&tile_rect) {
+                Some(drawn_rect) => drawn_rect,
+                None => return,
+            },
+            None => tile_rect,
+        };
+
+        let size = surface.size;
+        let output_rect = to_output(&drawn_rect);
+        target.for_each_pixel(&output_rect, |pixel, center| {
+            let point = from_output.transform_point(&Point2D::new(center.x + origin.x,
+                                                                  center.y + origin.y));
+            let u = (point.x - tile_rect.origin.x) / tile_rect.size.width;
+            let v = (point.y - tile_rect.origin.y) / tile_rect.size.height;
+            let x = ((u * size.width as f32) as i32).max(0).min(size.width - 1) as usize;
+            let y = ((v * size.height as f32) as i32).max(0).min(size.height - 1) as usize;
+            let start = (y * size.width as usize + x) * 4;
+            if start + 4 <= pixels.len() {
+                let source = &pixels[start..start + 4];
+                let source = [source[0] as f32 / 255.0 * opacity,
+                              source[1] as f32 / 255.0 * opacity,
+                              source[2] as f32 / 255.0 * opacity,
+                              source[3] as f32 / 255.0 * opacity];
+                blend_f32(pixel, &source);
+            }
+        });
+
+        if let Some(color) = heatmap_color(debug_overlay_mode, tile, content_age) {
+            target.fill_rect(&output_rect, &[color.b, color.g, color.r, color.a]);
+        }
+        if show_tile_borders {
+            target.stroke_rect(&output_rect, &TILE_BORDER_COLOR, TILE_BORDER_THICKNESS);
+        }
+    });
+
+    if let Some(ref label_color) = debug_options.label_color {
+        target.fill_rect(&to_output(&layer_rect),
+                         &[label_color.b, label_color.g, label_color.r, label_color.a]);
+    }
+    if show_debug_borders || debug_options.show_borders {
+        target.stroke_rect(&to_output(&layer_rect), &LAYER_BORDER_COLOR, LAYER_BORDER_THICKNESS);
+    }
+    all_supported
+}
+
+/// Returns the given color as BGRA bytes with premultiplied alpha, after multiplying its alpha
+/// with `opacity`.
+fn premultiply(color: &Color, opacity: f32) -> [u8; 4] {
+    let alpha = color.a * opacity;
+    [to_byte(color.b * alpha), to_byte(color.g * alpha), to_byte(color.r * alpha), to_byte(alpha)]
+}
+
+fn to_byte(value: f32) -> u8 {
+    (value.max(0.0).min(1.0) * 255.0).round() as u8
+}
+
+/// Draws a premultiplied BGRA color over a pixel.
+fn blend(pixel: &mut [u8], color: &[u8; 4]) {
+    blend_f32(pixel, &[color[0] as f32 / 255.0,
+                       color[1] as f32 / 255.0,
+                       color[2] as f32 / 255.0,
+                       color[3] as f32 / 255.0])
+}
+
+/// Draws a premultiplied BGRA color, with components between 0 and 1, over a pixel, as
+/// `glBlendFunc(GL_ONE, GL_ONE_MINUS_SRC_ALPHA)` does.
+fn blend_f32(pixel: &mut [u8], color: &[f32; 4]) {
+    for i in 0..4 {
+        let destination = pixel[i] as f32 / 255.0;
+        pixel[i] = to_byte(color[i] + destination * (1.0 - color[3]));
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use color::Color;
+    use debug_overlay::DebugOverlayMode;
+    use euclid::Matrix4D;
+    use euclid::point::{Point2D, Point3D, TypedPoint2D};
+    use euclid::rect::TypedRect;
+    use euclid::scale_factor::ScaleFactor;
+    use euclid::size::{Size2D, TypedSize2D};
+    use frame::FrameIndex;
+    use golden::{Image, ROUNDING_TOLERANCE, assert_matches_golden};
+    use layers::{Layer, LayerDebugOptions};
+    use scene::Scene;
+    use std::rc::Rc;
+    use super::{render_scene, render_scene_with_debug_overlays};
+    use test_util::{buffer_for_request, headless_display, layer};
+
+    /// Builds a 24x16 scene for the debug overlay goldens. Its root has transparent tiles. In
+    /// front of it are a gray layer with blue tiles, one of which is missing its buffer, and a
+    /// layer turned over around the X axis, which the software compositor skips.
+    fn overlay_scene() -> (Rc<Layer<()>>, Scene<()>) {
+        let root = layer(0.0, 0.0, 24.0, 16.0, 8);
+        let painted = layer(2.0, 2.0, 8.0, 8.0, 4);
+        *painted.background_color.borrow_mut() = Color { r: 0.5, g: 0.5, b: 0.5, a: 1.0 };
+        let turned = layer(12.0, 2.0, 8.0, 8.0, 4);
+        turned.set_transform_origin(Point3D::new(4.0, 4.0, 0.0));
+        turned.set_transform(Matrix4D::identity().pre_scaled(1.0, -1.0, -1.0));
+        root.add_child(painted.clone());
+        root.add_child(turned);
+
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(24.0, 16.0)));
+        scene.root = Some(root.clone());
+        let mut requests = vec!();
+        let mut unused_buffers = vec!();
+        scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+        for &(ref layer, ref layer_requests) in &requests {
+            let is_painted = layer.bounds() == painted.bounds();
+            for request in layer_requests {
+                if is_painted && request.page_rect.origin == Point2D::new(4.0, 4.0) {
+                    continue;
+                }
+
+                // Blue for the painted layer and transparent for the others.
+                let pixel = if is_painted { [255, 0, 0, 255] } else { [0; 4] };
+                let mut buffer = buffer_for_request(request);
+                let size = request.screen_rect.size;
+                let pixels: Vec<u8> = (0..size.width * size.height).flat_map(|_| {
+                    pixel.iter().cloned()
+                }).collect();
+                buffer.native_surface.upload(&headless_display(), &pixels).unwrap();
+                assert!(scene.add_buffer(layer, buffer, FrameIndex(0)));
+            }
+        }
+        (root, scene)
+    }
+
+    fn render_overlays(debug_overlay_mode: DebugOverlayMode, debug_options: LayerDebugOptions)
+                       -> Image {
+        let (root, scene) = overlay_scene();
+        root.set_debug_options(debug_options);
+        for child in root.children().iter() {
+            child.set_debug_options(debug_options);
+        }
+
+        let size = Size2D::new(24, 16);
+        let mut pixels = vec![0; size.width * size.height * 4];
+        render_scene_with_debug_overlays(root, &scene, &mut pixels, size, debug_overlay_mode)
+            .unwrap();
+        Image {
+            size: size,
+            pixels: pixels,
+        }
+    }
+
+    #[test]
+    fn debug_overlay_modes_match_their_goldens() {
+        let modes = [
+            ("debug_overlays_none", DebugOverlayMode::None),
+            ("debug_overlays_borders", DebugOverlayMode::Borders),
+            ("debug_overlays_content_age_heatmap", DebugOverlayMode::ContentAgeHeatmap),
+            ("debug_overlays_paint_count_heatmap", DebugOverlayMode::PaintCountHeatmap),
+        ];
+        for &(name, mode) in &modes {
+            assert_matches_golden(name,
+                                  &render_overlays(mode, LayerDebugOptions::new()),
+                                  ROUNDING_TOLERANCE);
+        }
+    }
+
+    #[test]
+    fn heatmaps_of_stale_and_repainted_tiles_match_their_goldens() {
+        // The top left tile of the painted layer is repainted each time its content changes,
+        // eight times, while its other tiles fall behind.
+        let (root, mut scene) = overlay_scene();
+        let painted = root.children()[0].clone();
+        for _ in 0..8 {
+            painted.contents_changed();
+            let mut requests = vec!();
+            scene.get_buffer_requests(&mut requests, &mut vec!());
+            for &(ref layer, ref layer_requests) in &requests {
+                for request in layer_requests {
+                    if layer.bounds() != painted.bounds() ||
+                       request.page_rect.origin != Point2D::new(0.0, 0.0) {
+                        continue;
+                    }
+                    let mut buffer = buffer_for_request(request);
+                    let pixels: Vec<u8> = (0..4 * 4).flat_map(|_| {
+                        [255, 0, 0, 255].iter().cloned()
+                    }).collect();
+                    buffer.native_surface.upload(&headless_display(), &pixels).unwrap();
+                    assert!(scene.add_buffer(layer, buffer, FrameIndex(0)));
+                }
+            }
+        }
+
+        let modes = [
+            ("debug_overlays_content_age_heatmap_stale", DebugOverlayMode::ContentAgeHeatmap),
+            ("debug_overlays_paint_count_heatmap_repainted", DebugOverlayMode::PaintCountHeatmap),
+        ];
+        for &(name, mode) in &modes {
+            let size = Size2D::new(24, 16);
+            let mut pixels = vec![0; size.width * size.height * 4];
+            render_scene_with_debug_overlays(root.clone(), &scene, &mut pixels, size, mode)
+                .unwrap();
+            let image = Image {
+                size: size,
+                pixels: pixels,
+            };
+            assert_matches_golden(name, &image, ROUNDING_TOLERANCE);
+        }
+    }
+
+    #[test]
+    fn layer_debug_options_match_their_goldens() {
+        let mut borders = LayerDebugOptions::new();
+        borders.show_borders = true;
+        let mut tile_borders = LayerDebugOptions::new();
+        tile_borders.show_tile_borders = true;
+        let mut aabb = LayerDebugOptions::new();
+        aabb.show_aabb = true;
+        let mut label = LayerDebugOptions::new();
+        label.label_color = Some(Color { r: 0.5, g: 0.0, b: 0.5, a: 0.5 });
+
+        let options = [
+            ("layer_debug_option_borders", borders),
+            ("layer_debug_option_tile_borders", tile_borders),
+            ("layer_debug_option_aabb", aabb),
+            ("layer_debug_option_label", label),
+        ];
+        for &(name, options) in &options {
+            assert_matches_golden(name,
+                                  &render_overlays(DebugOverlayMode::None, options),
+                                  ROUNDING_TOLERANCE);
+        }
+    }
+
+    #[test]
+    fn the_default_render_draws_no_overlays() {
+        let (root, scene) = overlay_scene();
+        let size = Size2D::new(24, 16);
+        let mut pixels = vec![0; size.width * size.height * 4];
+        render_scene(root, &scene, &mut pixels, size).unwrap();
+        assert!(pixels == render_overlays(DebugOverlayMode::None, LayerDebugOptions::new()).pixels);
+    }
+
+    #[test]
+    fn composite_only_animations_change_the_output_without_requests() {
+        let root = layer(0.0, 0.0, 128.0, 128.0, 128);
+        *root.background_color.borrow_mut() = Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 };
+        let moving = layer(0.0, 0.0, 32.0, 32.0, 128);
+        *moving.background_color.borrow_mut() = Color { r: 1.0, g: 0.0, b: 0.0, a: 1.0 };
+        root.add_child(moving.clone());
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(128.0, 128.0)));
+        scene.root = Some(root.clone());
+
+        let mut requests = vec!();
+        let mut unused_buffers = vec!();
+        scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+        for &(ref layer, ref layer_requests) in &requests {
+            for request in layer_requests {
+                assert!(scene.add_buffer(layer, buffer_for_request(request), FrameIndex(0)));
+            }
+        }
+
+        let mut previous_output = vec!();
+        for frame in 0..5 {
+            moving.set_opacity(1.0 - frame as f32 * 0.2);
+            moving.set_transform(Matrix4D::identity().pre_translated(frame as f32 * 16.0,
+                                                                     0.0,
+                                                                     0.0));
+            requests.clear();
+            scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+            assert!(requests.iter().all(|&(_, ref layer_requests)| layer_requests.is_empty()));
+
+            let mut output = vec![0; 128 * 128 * 4];
+            assert!(render_scene(root.clone(), &scene, &mut output, Size2D::new(128, 128)).is_ok());
+            assert!(output != previous_output);
+            previous_output = output;
+        }
+    }
+
+    #[test]
+    fn the_2d_path_draws_the_same_pixels_as_the_3d_path() {
+        // A root with a clipping container, whose nested context keeps the tree from being
+        // flat, holding overlapping, translucent, and scaled children that stick out of it.
+        let root = layer(0.0, 0.0, 64.0, 64.0, 64);
+        *root.background_color.borrow_mut() = Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 };
+        let container = Rc::new(Layer::new(TypedRect::new(TypedPoint2D::new(8.0, 8.0),
+                                                          TypedSize2D::new(40.0, 40.0)),
+                                           64,
+                                           Color { r: 0.0, g: 0.0, b: 0.5, a: 1.0 },
+                                           1.0,
+                                           true,
+                                           ()));
+        *container.masks_to_bounds.borrow_mut() = true;
+        root.add_child(container.clone());
+        let colors = [Color { r: 1.0, g: 0.0, b: 0.0, a: 1.0 },
+                      Color { r: 0.0, g: 1.0, b: 0.0, a: 0.5 },
+                      Color { r: 1.0, g: 1.0, b: 0.0, a: 1.0 }];
+        for (i, color) in colors.iter().enumerate() {
+            let child = layer(i as f32 * 12.0, i as f32 * 8.0, 24.0, 24.0, 64);
+            *child.background_color.borrow_mut() = *color;
+            container.add_child(child);
+        }
+        container.children()[1].set_opacity(0.75);
+        container.children()[2].set_transform(Matrix4D::identity().pre_scaled(1.5, 1.5, 1.0));
+        let sibling = layer(40.0, 40.0, 20.0, 20.0, 64);
+        *sibling.background_color.borrow_mut() = Color { r: 0.0, g: 1.0, b: 1.0, a: 1.0 };
+        root.add_child(sibling);
+
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(64.0, 64.0)));
+        scene.root = Some(root.clone());
+        let mut requests = vec!();
+        let mut unused_buffers = vec!();
+        scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+        for &(ref layer, ref layer_requests) in &requests {
+            for request in layer_requests {
+                assert!(scene.add_buffer(layer, buffer_for_request(request), FrameIndex(0)));
+            }
+        }
+
+        let size = Size2D::new(64, 64);
+        let mut pixels_3d = vec![0; 64 * 64 * 4];
+        assert!(!scene.draws_flat(&root));
+        render_scene(root.clone(), &scene, &mut pixels_3d, size).unwrap();
+
+        scene.prefer_2d_rendering = true;
+        let mut pixels_2d = vec![0; 64 * 64 * 4];
+        assert!(scene.draws_flat(&root));
+        render_scene(root.clone(), &scene, &mut pixels_2d, size).unwrap();
+
+        assert!(pixels_2d == pixels_3d);
+
+        // The children are clipped to the container.
+        let pixel = |x: usize, y: usize| pixels_2d[(y * 64 + x) * 4..(y * 64 + x + 1) * 4].to_vec();
+        assert_eq!(pixel(50, 30), vec!(255, 255, 255, 255));
+        assert!(pixel(40, 30) != vec!(255, 255, 255, 255));
+    }
+
+    #[test]
+    fn a_masked_child_is_tiled_clipped_and_blended() {
+        // A white root masking a half-transparent child that sticks out of its bottom right
+        // corner. The left column of tiles of the child is red, and the right one is green.
+        let root = Rc::new(Layer::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                     TypedSize2D::new(32.0, 32.0)),
+                                      16,
+                                      Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+                                      1.0,
+                                      true,
+                                      ()));
+        *root.masks_to_bounds.borrow_mut() = true;
+        let child = layer(8.0, 8.0, 32.0, 32.0, 16);
+        child.set_opacity(0.5);
+        root.add_child(child.clone());
+
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(48.0, 48.0)));
+        scene.background_color = Color { r: 0.0, g: 0.0, b: 0.0, a: 1.0 };
+        scene.root = Some(root.clone());
+        let mut requests = vec!();
+        let mut unused_buffers = vec!();
+        scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+        for &(ref layer, ref layer_requests) in &requests {
+            let is_child = layer.bounds() == child.bounds();
+            for request in layer_requests {
+                let mut buffer = buffer_for_request(request);
+                if is_child {
+                    let pixel = if request.page_rect.origin.x == 0.0 {
+                        [0, 0, 255, 255]
+                    } else {
+                        [0, 255, 0, 255]
+                    };
+                    let size = request.screen_rect.size;
+                    let pixels: Vec<u8> = (0..size.width * size.height).flat_map(|_| {
+                        pixel.iter().cloned()
+                    }).collect();
+                    buffer.native_surface.upload(&headless_display(), &pixels).unwrap();
+                }
+                assert!(scene.add_buffer(layer, buffer, FrameIndex(0)));
+            }
+        }
+
+        let size = Size2D::new(48, 48);
+        let mut pixels = vec![0; size.width * size.height * 4];
+        render_scene(root, &scene, &mut pixels, size).unwrap();
+        let assert_pixel = |x: usize, y: usize, expected: [u8; 4]| {
+            let pixel = &pixels[(y * size.width + x) * 4..(y * size.width + x + 1) * 4];
+            let is_close = pixel.iter().zip(expected.iter()).all(|(&actual, &expected)| {
+                (actual as i32 - expected as i32).abs() <= 1
+            });
+            assert!(is_close,
+                    "pixel at ({}, {}) is {:?}, expected {:?}", x, y, pixel, expected);
+        };
+
+        // The root, and the child blended over it, tile by tile.
+        assert_pixel(4, 4, [255, 255, 255, 255]);
+        assert_pixel(12, 12, [128, 128, 255, 255]);
+        assert_pixel(28, 12, [128, 255, 128, 255]);
+        assert_pixel(12, 28, [128, 128, 255, 255]);
+        assert_pixel(28, 28, [128, 255, 128, 255]);
+
+        // The child is clipped to the root, outside of which the scene background shows.
+        assert_pixel(36, 12, [0, 0, 0, 255]);
+        assert_pixel(12, 36, [0, 0, 0, 255]);
+        assert_pixel(36, 36, [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn layers_painted_at_their_scale_override_keep_their_size_on_screen() {
+        // A 2x scene holding a 6x6 layer painted red at 1x.
+        let root = layer(0.0, 0.0, 16.0, 16.0, 16);
+        let iframe = layer(2.0, 2.0, 6.0, 6.0, 8);
+        *iframe.scale_override.borrow_mut() = Some(ScaleFactor::new(1.0));
+        root.add_child(iframe.clone());
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(32.0, 32.0)));
+        scene.background_color = Color { r: 0.0, g: 0.0, b: 0.0, a: 1.0 };
+        scene.scale = ScaleFactor::new(2.0);
+        scene.root = Some(root.clone());
+        let mut requests = vec!();
+        scene.get_buffer_requests(&mut requests, &mut vec!());
+        for &(ref layer, ref layer_requests) in &requests {
+            for request in layer_requests {
+                let mut buffer = buffer_for_request(request);
+                if layer.bounds() == iframe.bounds() {
+                    assert_eq!(request.screen_rect.size, Size2D::new(6, 6));
+                    let pixels: Vec<u8> = (0..6 * 6).flat_map(|_| {
+                        [0, 0, 255, 255].iter().cloned()
+                    }).collect();
+                    buffer.native_surface.upload(&headless_display(), &pixels).unwrap();
+                }
+                assert!(scene.add_buffer(layer, buffer, FrameIndex(0)));
+            }
+        }
+
+        // The six layer pixels of the iframe cover twelve device pixels, as at the scene scale.
+        let size = Size2D::new(32, 32);
+        let mut pixels = vec![0; size.width * size.height * 4];
+        render_scene(root, &scene, &mut pixels, size).unwrap();
+        let is_red = |x: usize, y: usize| {
+            pixels[(y * size.width + x) * 4..(y * size.width + x + 1) * 4] == [0, 0, 255, 255]
+        };
+        assert!(is_red(4, 4) && is_red(15, 15) && is_red(4, 15) && is_red(15, 4));
+        assert!(!is_red(3, 3) && !is_red(16, 16) && !is_red(16, 4) && !is_red(4, 16));
+    }
+
+    #[test]
+    fn half_transparent_backgrounds_and_tiles_of_the_same_color_look_the_same() {
+        // The left half is a background of half-transparent red, and the right half a tile
+        // painted with the same color, premultiplied.
+        let half_red = Color { r: 1.0, g: 0.0, b: 0.0, a: 0.5 };
+        let root = layer(0.0, 0.0, 32.0, 16.0, 16);
+        let background = Rc::new(Layer::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                           TypedSize2D::new(16.0, 16.0)),
+                                            16,
+                                            half_red,
+                                            1.0,
+                                            false,
+                                            ()));
+        let painted = layer(16.0, 0.0, 16.0, 16.0, 16);
+        root.add_child(background);
+        root.add_child(painted.clone());
+
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(32.0, 16.0)));
+        scene.background_color = Color { r: 0.0, g: 0.0, b: 1.0, a: 1.0 };
+        scene.root = Some(root.clone());
+        let mut requests = vec!();
+        scene.get_buffer_requests(&mut requests, &mut vec!());
+        for &(ref layer, ref layer_requests) in &requests {
+            for request in layer_requests {
+                let mut buffer = buffer_for_request(request);
+                if layer.bounds() == painted.bounds() {
+                    let premultiplied = half_red.premultiply();
+                    let bgra = [(premultiplied.b * 255.0).round() as u8,
+                                (premultiplied.g * 255.0).round() as u8,
+                                (premultiplied.r * 255.0).round() as u8,
+                                (premultiplied.a * 255.0).round() as u8];
+                    let pixels: Vec<u8> = (0..16 * 16).flat_map(|_| bgra.iter().cloned())
+                                                      .collect();
+                    buffer.native_surface.upload(&headless_display(), &pixels).unwrap();
+                }
+                assert!(scene.add_buffer(layer, buffer, FrameIndex(0)));
+            }
+        }
+
+        let size = Size2D::new(32, 16);
+        let mut pixels = vec![0; size.width * size.height * 4];
+        render_scene(root, &scene, &mut pixels, size).unwrap();
+        let pixel_at = |x: usize| pixels[(8 * size.width + x) * 4..(8 * size.width + x + 1) * 4]
+                                      .to_vec();
+        let (from_background, from_tile) = (pixel_at(8), pixel_at(24));
+        assert!(from_background.iter().zip(from_tile.iter()).all(|(&a, &b)| {
+            (a as i32 - b as i32).abs() <= 1
+        }), "background {:?}, tile {:?}", from_background, from_tile);
+        assert!((from_background[0] as i32 - 128).abs() <= 1);
+        assert!((from_background[2] as i32 - 128).abs() <= 1);
+    }
+}
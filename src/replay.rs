@@ -0,0 +1,793 @@
+// Copyright 2016 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Deterministic replays of compositor sessions, for bisecting rendering regressions without the
+//! page that caused them.
+//!
+//! A `ReplayScript` holds the layer tree a session started with and what happened to it since:
+//! changes to the tree, scrolls, zooms, and frames, each with the time at which it happened.
+//! Embedders record scripts from live sessions with a `ScriptRecorder`. `run_replay` plays a
+//! script through a `Scene` and a `ReplayBackend`, painting every tile the scene requests with a
+//! checkerboard derived from the id of its layer, and returns a `FrameHash` of the output of
+//! every frame. `SoftwareReplayBackend` composites with `rendersw`, whose output, and so whose
+//! hashes, are the same on every platform.
+//!
+//! Layer dumps and `LayerDiff`s describe live layers, along with state the compositor computes,
+//! and can't be decoded, so scripts carry their own copies of the properties a session sets.
+//! Layers are identified by ids that the embedder chooses; replayed layers carry them as their
+//! extra data. Times are kept for reference only: replays don't wait between frames.
+//!
+//! The sample script in `tests/replays/` is replayed by the tests of this module, which check
+//! the hash of each of its frames. Changes to what the compositor draws must update them.
+
+use color::Color;
+use frame::FrameIndex;
+use layers::{Layer, LayerBuffer};
+use platform::surface::{MemoryBufferNativeSurface, NativeDisplay, NativeSurface, SurfaceError};
+use rendersw::render_scene;
+use scene::Scene;
+
+use euclid::Matrix4D;
+use euclid::point::{Point3D, TypedPoint2D};
+use euclid::rect::TypedRect;
+use euclid::scale_factor::ScaleFactor;
+use euclid::size::{Size2D, TypedSize2D};
+use geometry::LayerPixel;
+use rustc_serialize::json;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// The size in layer pixels of the squares of the checkerboards that tiles are painted with.
+const CHECKER_SIZE: usize = 4;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// The state of a layer and its descendants, as recorded in a script.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct ReplayLayer {
+    /// The id of the layer, unique within the script.
+    pub id: u64,
+
+    /// The bounds of the layer, as x, y, width, and height.
+    pub bounds: [f32; 4],
+
+    pub tile_size: usize,
+
+    /// The background color, as RGBA with straight alpha.
+    pub background_color: [f32; 4],
+
+    pub opacity: f32,
+
+    /// The transform of the layer, in the order of `Matrix4D::row_major`.
+    pub transform: [f32; 16],
+
+    pub transform_origin: [f32; 3],
+    pub masks_to_bounds: bool,
+    pub content_offset: [f32; 2],
+
+    /// The size of the content of the layer, which lets layers without children scroll.
+    pub content_size: Option<[f32; 2]>,
+
+    pub establishes_3d_context: bool,
+
+    /// The children of the layer, in paint order.
+    pub children: Vec<ReplayLayer>,
+}
+
+impl ReplayLayer {
+    /// Records the state of a layer and its descendants, identifying them with `layer_id`.
+    pub fn new<T>(layer: &Layer<T>, layer_id: &Fn(&T) -> u64) -> ReplayLayer {
+        let bounds = layer.bounds();
+        let background_color = *layer.background_color.borrow();
+        let transform_origin = layer.transform_origin();
+        let content_offset = layer.content_offset();
+        ReplayLayer {
+            id: layer_id(&*layer.extra_data.borrow()),
+            bounds: [bounds.origin.x, bounds.origin.y, bounds.size.width, bounds.size.height],
+            tile_size: layer.tile_size.get(),
+            background_color: [background_color.r,
+                               background_color.g,
+                               background_color.b,
+                               background_color.a],
+            opacity: layer.opacity(),
+            transform: matrix_to_array(&layer.transform()),
+            transform_origin: [transform_origin.x, transform_origin.y, transform_origin.z],
+            masks_to_bounds: layer.masks_to_bounds(),
+            content_offset: [content_offset.x, content_offset.y],
+            content_size: layer.content_size.borrow().map(|size| [size.width, size.height]),
+            establishes_3d_context: layer.establishes_3d_context,
+            children: layer.children().iter().map(|child| {
+                ReplayLayer::new(&**child, layer_id)
+            }).collect(),
+        }
+    }
+
+    /// Creates a layer tree in this state, whose layers carry their ids as extra data.
+    pub fn build(&self) -> Rc<Layer<u64>> {
+        let layer = Rc::new(Layer::new(rect_from_array(&self.bounds),
+                                       self.tile_size,
+                                       color_from_array(&self.background_color),
+                                       self.opacity,
+                                       self.establishes_3d_context,
+                                       self.id));
+        layer.set_transform(matrix_from_array(&self.transform));
+        layer.set_transform_origin(Point3D::new(self.transform_origin[0],
+                                                self.transform_origin[1],
+                                                self.transform_origin[2]));
+        layer.set_masks_to_bounds(self.masks_to_bounds);
+        layer.set_content_offset(TypedPoint2D::new(self.content_offset[0],
+                                                   self.content_offset[1]));
+        *layer.content_size.borrow_mut() = self.content_size.map(|size| {
+            TypedSize2D::new(size[0], size[1])
+        });
+        for child in &self.children {
+            layer.add_child(child.build());
+        }
+        layer
+    }
+
+    fn find_mut(&mut self, id: u64) -> Option<&mut ReplayLayer> {
+        if self.id == id {
+            return Some(self);
+        }
+        for child in self.children.iter_mut() {
+            if let Some(layer) = child.find_mut(id) {
+                return Some(layer);
+            }
+        }
+        None
+    }
+}
+
+/// Something that happened during a recorded session. Layers are given by their ids.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub enum ReplayEvent {
+    /// A layer was inserted among the children of the layer with the first id, at the given
+    /// index.
+    LayerAdded(u64, usize, ReplayLayer),
+    /// A layer was removed from its parent, along with its descendants.
+    LayerRemoved(u64),
+    BoundsChanged(u64, [f32; 4]),
+    /// The transform or the transform origin of a layer changed.
+    TransformChanged(u64, [f32; 16], [f32; 3]),
+    OpacityChanged(u64, f32),
+    BackgroundColorChanged(u64, [f32; 4]),
+    MasksToBoundsChanged(u64, bool),
+    ContentOffsetChanged(u64, [f32; 2]),
+    /// The content of a layer changed, so that all its tiles must be painted again.
+    ContentsChanged(u64),
+    /// A layer was scrolled with `Layer::scroll_by`.
+    ScrolledBy(u64, [f32; 2]),
+    /// The scale of the scene changed.
+    ScaleChanged(f32),
+    /// A frame was composited.
+    Frame,
+}
+
+/// An event and the time at which it happened, in whatever units the embedder uses.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct TimedReplayEvent {
+    pub time: f64,
+    pub event: ReplayEvent,
+}
+
+/// A recorded session: the scene it started with, and what happened to it.
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct ReplayScript {
+    /// The viewport of the scene, as x, y, width, and height in device pixels. Frames are
+    /// rendered at its size, rounded up.
+    pub viewport: [f32; 4],
+
+    pub scale: f32,
+
+    /// The background color of the scene, as RGBA with straight alpha.
+    pub background_color: [f32; 4],
+
+    pub root: ReplayLayer,
+    pub events: Vec<TimedReplayEvent>,
+}
+
+impl ReplayScript {
+    /// Encodes the script as JSON.
+    pub fn to_json_string(&self) -> String {
+        // Scripts hold no maps, whose non-string keys are the only thing the encoder rejects.
+        json::encode(self).unwrap()
+    }
+
+    /// Decodes a script encoded with `to_json_string`.
+    pub fn from_json_str(json: &str) -> Result<ReplayScript, json::DecoderError> {
+        json::decode(json)
+    }
+
+    /// Returns the number of frames the script renders.
+    pub fn frame_count(&self) -> usize {
+        self.events.iter().filter(|event| event.event == ReplayEvent::Frame).count()
+    }
+}
+
+/// A 64-bit FNV-1a digest of a rendered frame: of its width and height as little-endian 32-bit
+/// integers, followed by its BGRA pixels, row by row from the top, each row from the left.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameHash(pub u64);
+
+impl FrameHash {
+    /// Hashes the frame of the given size whose pixels `pixels` starts with.
+    pub fn new(pixels: &[u8], size: Size2D<usize>) -> FrameHash {
+        let mut digest = FNV_OFFSET_BASIS;
+        digest = fnv1a(digest, &u32_to_le_bytes(size.width as u32));
+        digest = fnv1a(digest, &u32_to_le_bytes(size.height as u32));
+        FrameHash(fnv1a(digest, &pixels[..size.width * size.height * 4]))
+    }
+}
+
+/// Errors that stop a replay.
+#[derive(Debug)]
+pub enum ReplayError {
+    /// An event refers to a layer that isn't in the tree.
+    UnknownLayer(u64),
+    /// A layer was added with the id of a layer that is already in the tree.
+    DuplicateLayer(u64),
+    /// An event tried to remove the root layer.
+    RootRemoved,
+    /// A tile couldn't be painted.
+    Surface(SurfaceError),
+    /// The backend couldn't render a frame.
+    RenderFailed(String),
+}
+
+/// What a replay paints and renders with.
+pub trait ReplayBackend {
+    /// Returns the display that buffers are created on.
+    fn display(&self) -> &NativeDisplay;
+
+    /// Creates a surface of the given size in device pixels for a tile to be painted into.
+    fn new_surface(&self, size: Size2D<i32>) -> NativeSurface;
+
+    /// Renders the scene into `output`, as BGRA pixels with premultiplied alpha, top row first,
+    /// as `rendersw::render_scene` does.
+    fn render(&mut self,
+              root_layer: Rc<Layer<u64>>,
+              scene: &Scene<u64>,
+              output: &mut [u8],
+              output_size: Size2D<usize>)
+              -> Result<(), ReplayError>;
+}
+
+/// Paints tiles into memory surfaces and renders them with the software compositor.
+pub struct SoftwareReplayBackend {
+    display: NativeDisplay,
+}
+
+impl SoftwareReplayBackend {
+    pub fn new(display: NativeDisplay) -> SoftwareReplayBackend {
+        SoftwareReplayBackend {
+            display: display,
+        }
+    }
+}
+
+impl ReplayBackend for SoftwareReplayBackend {
+    fn display(&self) -> &NativeDisplay {
+        &self.display
+    }
+
+    fn new_surface(&self, size: Size2D<i32>) -> NativeSurface {
+        NativeSurface::MemoryBuffer(MemoryBufferNativeSurface::new(&self.display, size))
+    }
+
+    fn render(&mut self,
+              root_layer: Rc<Layer<u64>>,
+              scene: &Scene<u64>,
+              output: &mut [u8],
+              output_size: Size2D<usize>)
+              -> Result<(), ReplayError> {
+        render_scene(root_layer, scene, output, output_size).map_err(|error| {
+            ReplayError::RenderFailed(format!("{:?}", error))
+        })
+    }
+}
+
+/// Plays a script through a scene, painting the tiles it requests before each frame, and
+/// returns the hashes of the frames rendered by `backend`. All buffers are destroyed when the
+/// replay ends.
+pub fn run_replay<B: ReplayBackend>(script: &ReplayScript, backend: &mut B)
+                                    -> Result<Vec<FrameHash>, ReplayError> {
+    let mut scene = Scene::new(rect_from_array(&script.viewport));
+    scene.scale = ScaleFactor::new(script.scale);
+    scene.background_color = color_from_array(&script.background_color);
+    let root = script.root.build();
+    scene.root = Some(root.clone());
+
+    let result = replay_events(script, &mut scene, &root, backend);
+    destroy_buffers_in_subtree(&root, backend.display());
+    result
+}
+
+fn replay_events<B: ReplayBackend>(script: &ReplayScript,
+                                   scene: &mut Scene<u64>,
+                                   root: &Rc<Layer<u64>>,
+                                   backend: &mut B)
+                                   -> Result<Vec<FrameHash>, ReplayError> {
+    let output_size = Size2D::new(script.viewport[2].ceil().max(0.0) as usize,
+                                  script.viewport[3].ceil().max(0.0) as usize);
+    let mut generations = HashMap::new();
+    let mut frame = FrameIndex(0);
+    let mut hashes = vec!();
+    for timed_event in &script.events {
+        match timed_event.event {
+            ReplayEvent::LayerAdded(parent_id, index, ref layer) => {
+                let parent = try!(find_layer(root, parent_id));
+                if find_layer(root, layer.id).is_ok() {
+                    return Err(ReplayError::DuplicateLayer(layer.id));
+                }
+                let index = index.min(parent.children().len());
+                parent.insert_child_at_index(layer.build(), index);
+            }
+            ReplayEvent::LayerRemoved(id) => {
+                let layer = try!(find_layer(root, id));
+                if !root.remove_descendant(&layer) {
+                    return Err(ReplayError::RootRemoved);
+                }
+                destroy_buffers_in_subtree(&layer, backend.display());
+            }
+            ReplayEvent::BoundsChanged(id, bounds) => {
+                try!(find_layer(root, id)).set_bounds(rect_from_array(&bounds));
+            }
+            ReplayEvent::TransformChanged(id, transform, origin) => {
+                let layer = try!(find_layer(root, id));
+                layer.set_transform(matrix_from_array(&transform));
+                layer.set_transform_origin(Point3D::new(origin[0], origin[1], origin[2]));
+            }
+            ReplayEvent::OpacityChanged(id, opacity) => {
+                try!(find_layer(root, id)).set_opacity(opacity);
+            }
+            ReplayEvent::BackgroundColorChanged(id, color) => {
+                let layer = try!(find_layer(root, id));
+                *layer.background_color.borrow_mut() = color_from_array(&color);
+            }
+            ReplayEvent::MasksToBoundsChanged(id, masks_to_bounds) => {
+                try!(find_layer(root, id)).set_masks_to_bounds(masks_to_bounds);
+            }
+            ReplayEvent::ContentOffsetChanged(id, offset) => {
+                try!(find_layer(root, id)).set_content_offset(TypedPoint2D::new(offset[0],
+                                                                                offset[1]));
+            }
+            ReplayEvent::ContentsChanged(id) => {
+                try!(find_layer(root, id)).contents_changed();
+                *generations.entry(id).or_insert(0) += 1;
+            }
+            ReplayEvent::ScrolledBy(id, delta) => {
+                try!(find_layer(root, id)).scroll_by(TypedPoint2D::new(delta[0], delta[1]));
+            }
+            ReplayEvent::ScaleChanged(scale) => scene.scale = ScaleFactor::new(scale),
+            ReplayEvent::Frame => {
+                try!(paint_requested_tiles(scene, backend, &generations, frame));
+                let mut output = vec![0; output_size.width * output_size.height * 4];
+                try!(backend.render(root.clone(), scene, &mut output, output_size));
+                hashes.push(FrameHash::new(&output, output_size));
+                frame = frame.next();
+            }
+        }
+    }
+    Ok(hashes)
+}
+
+/// Paints every tile the scene requests with the checkerboard of its layer, and destroys the
+/// buffers the scene no longer needs.
+fn paint_requested_tiles<B: ReplayBackend>(scene: &mut Scene<u64>,
+                                           backend: &B,
+                                           generations: &HashMap<u64, u32>,
+                                           frame: FrameIndex)
+                                           -> Result<(), ReplayError> {
+    let mut requests = vec!();
+    let mut unused_buffers = vec!();
+    scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+    destroy_buffers(unused_buffers, backend.display());
+
+    for (layer, layer_requests) in requests {
+        let id = *layer.extra_data.borrow();
+        let size = layer.bounds().size;
+        let size = layer.content_size.borrow().map_or(size, |content_size| {
+            TypedSize2D::new(size.width.max(content_size.width),
+                             size.height.max(content_size.height))
+        });
+        let source_size = Size2D::new(size.width.ceil().max(0.0) as usize,
+                                      size.height.ceil().max(0.0) as usize);
+        let source = checkerboard(id, generations.get(&id).cloned().unwrap_or(0), source_size);
+        for mut request in layer_requests {
+            if request.native_surface.is_none() {
+                request.native_surface = Some(backend.new_surface(request.device_size()));
+            }
+            let buffer = try!(LayerBuffer::from_request_and_pixels(request,
+                                                                   backend.display(),
+                                                                   &source,
+                                                                   source_size,
+                                                                   source_size.width * 4)
+                                  .map_err(ReplayError::Surface));
+            scene.add_buffer(&layer, buffer, frame);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the BGRA pixels of the content of a layer, covering its bounds and its content size:
+/// a checkerboard of an opaque color picked by hashing the id of the layer and the number of
+/// times its content changed, and of the same color at three quarters of its intensity. The top
+/// left square is the brighter one.
+fn checkerboard(id: u64, generation: u32, size: Size2D<usize>) -> Vec<u8> {
+    let mut digest = fnv1a(FNV_OFFSET_BASIS, &u64_to_le_bytes(id));
+    digest = fnv1a(digest, &u32_to_le_bytes(generation));
+    let bright = [(digest >> 16) as u8, (digest >> 8) as u8, digest as u8, 255];
+    let dark = [(bright[0] as u32 * 3 / 4) as u8,
+                (bright[1] as u32 * 3 / 4) as u8,
+                (bright[2] as u32 * 3 / 4) as u8,
+                255];
+
+    let mut pixels = Vec::with_capacity(size.width * size.height * 4);
+    for y in 0..size.height {
+        for x in 0..size.width {
+            let is_dark = (x / CHECKER_SIZE + y / CHECKER_SIZE) % 2 == 1;
+            pixels.extend_from_slice(if is_dark { &dark } else { &bright });
+        }
+    }
+    pixels
+}
+
+fn find_layer(layer: &Rc<Layer<u64>>, id: u64) -> Result<Rc<Layer<u64>>, ReplayError> {
+    if *layer.extra_data.borrow() == id {
+        return Ok(layer.clone());
+    }
+    for child in layer.children().iter() {
+        if let Ok(layer) = find_layer(child, id) {
+            return Ok(layer);
+        }
+    }
+    Err(ReplayError::UnknownLayer(id))
+}
+
+fn destroy_buffers(buffers: Vec<Box<LayerBuffer>>, display: &NativeDisplay) {
+    for buffer in buffers {
+        if let Err(error) = buffer.destroy(display) {
+            warn!("Failed to destroy a replayed buffer ({:?})", error);
+        }
+    }
+}
+
+fn destroy_buffers_in_subtree(layer: &Rc<Layer<u64>>, display: &NativeDisplay) {
+    destroy_buffers(layer.collect_buffers(), display);
+    destroy_buffers(layer.collect_unused_buffers(), display);
+    for child in layer.children().iter() {
+        destroy_buffers_in_subtree(child, display);
+    }
+}
+
+/// Records a live session as a `ReplayScript`.
+///
+/// Changes to the layer tree and to the scale of the scene are found by comparing the scene with
+/// the state recorded last, each time one of the `record_` methods is called; embedders call
+/// `record_frame` for every frame they composite. Scrolls and content changes can't be told
+/// from other changes that way, so they have methods of their own. Siblings that change places
+/// are recorded as removed and added again. Changes to the tile size, the content size, and
+/// whether a layer establishes a 3D context are not recorded.
+pub struct ScriptRecorder<T> {
+    layer_id: Box<Fn(&T) -> u64>,
+    script: ReplayScript,
+
+    /// The state of the layer tree and the scale as of the last recorded change.
+    last_root: ReplayLayer,
+    last_scale: f32,
+}
+
+impl<T> ScriptRecorder<T> {
+    /// Starts recording a session from the current state of `scene`. `layer_id` gives the id of
+    /// a layer from its extra data; ids must be unique within the tree and must not change.
+    /// Returns None if the scene has no root layer.
+    pub fn start(scene: &Scene<T>, layer_id: Box<Fn(&T) -> u64>) -> Option<ScriptRecorder<T>> {
+        let root = match scene.root {
+            Some(ref root) => ReplayLayer::new(&**root, &*layer_id),
+            None => return None,
+        };
+        let viewport = scene.viewport;
+        let background_color = scene.background_color;
+        Some(ScriptRecorder {
+            layer_id: layer_id,
+            script: ReplayScript {
+                viewport: [viewport.origin.x,
+                           viewport.origin.y,
+                           viewport.size.width,
+                           viewport.size.height],
+                scale: scene.scale.get(),
+                background_color: [background_color.r,
+                                   background_color.g,
+                                   background_color.b,
+                                   background_color.a],
+                root: root.clone(),
+                events: vec!(),
+            },
+            last_root: root,
+            last_scale: scene.scale.get(),
+        })
+    }
+
+    /// Records the changes to the scene since the last call, followed by a frame.
+    pub fn record_frame(&mut self, time: f64, scene: &Scene<T>) {
+        self.record_changes(time, scene);
+        self.push(time, ReplayEvent::Frame);
+    }
+
+    /// Marks the content of `layer` as changed, as `Layer::contents_changed` does, and records
+    /// it.
+    pub fn record_contents_changed(&mut self, time: f64, scene: &Scene<T>, layer: &Layer<T>) {
+        self.record_changes(time, scene);
+        layer.contents_changed();
+        let id = (self.layer_id)(&*layer.extra_data.borrow());
+        self.push(time, ReplayEvent::ContentsChanged(id));
+    }
+
+    /// Scrolls `layer` as `Layer::scroll_by` does, and records the scroll. Returns true if the
+    /// content offset of the layer changed.
+    pub fn record_scroll_by(&mut self,
+                            time: f64,
+                            scene: &Scene<T>,
+                            layer: &Layer<T>,
+                            delta: TypedPoint2D<f32, LayerPixel>)
+                            -> bool {
+        self.record_changes(time, scene);
+        let scrolled = layer.scroll_by(delta);
+        let id = (self.layer_id)(&*layer.extra_data.borrow());
+        let offset = layer.content_offset();
+        if let Some(recorded_layer) = self.last_root.find_mut(id) {
+            recorded_layer.content_offset = [offset.x, offset.y];
+        }
+        self.push(time, ReplayEvent::ScrolledBy(id, [delta.x, delta.y]));
+        scrolled
+    }
+
+    /// Stops recording and returns the script.
+    pub fn finish(self) -> ReplayScript {
+        self.script
+    }
+
+    fn push(&mut self, time: f64, event: ReplayEvent) {
+        self.script.events.push(TimedReplayEvent {
+            time: time,
+            event: event,
+        });
+    }
+
+    /// Records how the scene differs from the state recorded last.
+    fn record_changes(&mut self, time: f64, scene: &Scene<T>) {
+        let scale = scene.scale.get();
+        if scale != self.last_scale {
+            self.last_scale = scale;
+            self.push(time, ReplayEvent::ScaleChanged(scale));
+        }
+
+        let new_root = match scene.root {
+            Some(ref root) => ReplayLayer::new(&**root, &*self.layer_id),
+            None => return,
+        };
+        if new_root.id != self.last_root.id {
+            warn!("The root layer was replaced while recording a replay script; replays will \
+                   keep the old one.");
+            return;
+        }
+        let mut events = vec!();
+        record_removals(&self.last_root, &new_root, &mut events);
+        record_additions_and_changes(&self.last_root, &new_root, &mut events);
+        for event in events {
+            self.push(time, event);
+        }
+        self.last_root = new_root;
+    }
+}
+
+/// Returns the ids of the children of `new_layer` that were also children of `old_layer`, in
+/// order, or none if they changed places.
+fn kept_children(old_layer: &ReplayLayer, new_layer: &ReplayLayer) -> Vec<u64> {
+    let kept_in_old_order: Vec<u64> = old_layer.children.iter().map(|child| child.id).filter(|id| {
+        new_layer.children.iter().any(|child| child.id == *id)
+    }).collect();
+    let kept_in_new_order: Vec<u64> = new_layer.children.iter().map(|child| child.id).filter(|id| {
+        old_layer.children.iter().any(|child| child.id == *id)
+    }).collect();
+    if kept_in_old_order == kept_in_new_order {
+        kept_in_new_order
+    } else {
+        vec!()
+    }
+}
+
+fn record_removals(old_layer: &ReplayLayer,
+                   new_layer: &ReplayLayer,
+                   events: &mut Vec<ReplayEvent>) {
+    let kept = kept_children(old_layer, new_layer);
+    for old_child in &old_layer.children {
+        match new_layer.children.iter().find(|child| child.id == old_child.id) {
+            Some(new_child) if kept.contains(&old_child.id) => {
+                record_removals(old_child, new_child, events)
+            }
+            _ => events.push(ReplayEvent::LayerRemoved(old_child.id)),
+        }
+    }
+}
+
+fn record_additions_and_changes(old_layer: &ReplayLayer,
+                                new_layer: &ReplayLayer,
+                                events: &mut Vec<ReplayEvent>) {
+    let id = new_layer.id;
+    if new_layer.bounds != old_layer.bounds {
+        events.push(ReplayEvent::BoundsChanged(id, new_layer.bounds));
+    }
+    if new_layer.transform != old_layer.transform ||
+       new_layer.transform_origin != old_layer.transform_origin {
+        events.push(ReplayEvent::TransformChanged(id,
+                                                  new_layer.transform,
+                                                  new_layer.transform_origin));
+    }
+    if new_layer.opacity != old_layer.opacity {
+        events.push(ReplayEvent::OpacityChanged(id, new_layer.opacity));
+    }
+    if new_layer.background_color != old_layer.background_color {
+        events.push(ReplayEvent::BackgroundColorChanged(id, new_layer.background_color));
+    }
+    if new_layer.masks_to_bounds != old_layer.masks_to_bounds {
+        events.push(ReplayEvent::MasksToBoundsChanged(id, new_layer.masks_to_bounds));
+    }
+    if new_layer.content_offset != old_layer.content_offset {
+        events.push(ReplayEvent::ContentOffsetChanged(id, new_layer.content_offset));
+    }
+
+    let kept = kept_children(old_layer, new_layer);
+    for (index, new_child) in new_layer.children.iter().enumerate() {
+        match old_layer.children.iter().find(|child| child.id == new_child.id) {
+            Some(old_child) if kept.contains(&new_child.id) => {
+                record_additions_and_changes(old_child, new_child, events)
+            }
+            _ => events.push(ReplayEvent::LayerAdded(id, index, new_child.clone())),
+        }
+    }
+}
+
+fn fnv1a(digest: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(digest, |digest, byte| (digest ^ *byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+fn u32_to_le_bytes(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+fn u64_to_le_bytes(value: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = (value >> (index * 8)) as u8;
+    }
+    bytes
+}
+
+fn rect_from_array<U>(rect: &[f32; 4]) -> TypedRect<f32, U> {
+    TypedRect::new(TypedPoint2D::new(rect[0], rect[1]), TypedSize2D::new(rect[2], rect[3]))
+}
+
+fn color_from_array(color: &[f32; 4]) -> Color {
+    Color {
+        r: color[0],
+        g: color[1],
+        b: color[2],
+        a: color[3],
+    }
+}
+
+fn matrix_to_array(m: &Matrix4D<f32>) -> [f32; 16] {
+    [m.m11, m.m12, m.m13, m.m14,
+     m.m21, m.m22, m.m23, m.m24,
+     m.m31, m.m32, m.m33, m.m34,
+     m.m41, m.m42, m.m43, m.m44]
+}
+
+fn matrix_from_array(m: &[f32; 16]) -> Matrix4D<f32> {
+    Matrix4D::row_major(m[0], m[1], m[2], m[3],
+                        m[4], m[5], m[6], m[7],
+                        m[8], m[9], m[10], m[11],
+                        m[12], m[13], m[14], m[15])
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use color::Color;
+    use euclid::point::TypedPoint2D;
+    use euclid::rect::TypedRect;
+    use euclid::size::TypedSize2D;
+    use layers::Layer;
+    use scene::Scene;
+    use std::env;
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use super::{FrameHash, ReplayEvent, ReplayLayer, ReplayScript, ScriptRecorder};
+    use super::{SoftwareReplayBackend, TimedReplayEvent, run_replay};
+    use test_util::headless_display;
+
+    /// The hashes of the frames of `tests/replays/sample.json`. A change that alters them
+    /// changes what the compositor draws, and must update them deliberately.
+    const SAMPLE_FRAME_HASHES: [FrameHash; 6] = [FrameHash(0x26307be59d7b93e5),
+                                                 FrameHash(0x799c3256e4fa1b65),
+                                                 FrameHash(0x998e58bb0b648225),
+                                                 FrameHash(0xac058753f561db25),
+                                                 FrameHash(0x98c16a862c0a8905),
+                                                 FrameHash(0x84f660e4b59a7c45)];
+
+    fn layer(id: u64, x: f32, y: f32, width: f32, height: f32) -> Rc<Layer<u64>> {
+        let bounds = TypedRect::new(TypedPoint2D::new(x, y), TypedSize2D::new(width, height));
+        let transparent = Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 };
+        Rc::new(Layer::new(bounds, 8, transparent, 1.0, false, id))
+    }
+
+    fn sample_script() -> ReplayScript {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("tests");
+        path.push("replays");
+        path.push("sample.json");
+        let mut json = String::new();
+        File::open(&path).unwrap().read_to_string(&mut json).unwrap();
+        ReplayScript::from_json_str(&json).unwrap()
+    }
+
+    #[test]
+    fn sample_script_replays_to_its_hashes() {
+        let script = sample_script();
+        assert_eq!(script.frame_count(), SAMPLE_FRAME_HASHES.len());
+        let mut backend = SoftwareReplayBackend::new(headless_display());
+        assert_eq!(run_replay(&script, &mut backend).unwrap(), SAMPLE_FRAME_HASHES.to_vec());
+    }
+
+    #[test]
+    fn scripts_survive_encoding() {
+        let script = sample_script();
+        assert_eq!(ReplayScript::from_json_str(&script.to_json_string()).unwrap(), script);
+    }
+
+    #[test]
+    fn recorder_records_changes_between_frames() {
+        let root = layer(1, 0.0, 0.0, 16.0, 16.0);
+        let kept = layer(2, 0.0, 0.0, 8.0, 8.0);
+        root.add_child(kept.clone());
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(16.0, 16.0)));
+        scene.root = Some(root.clone());
+        let start = ReplayLayer::new(&*root, &|id: &u64| *id);
+        let mut recorder = ScriptRecorder::start(&scene, Box::new(|id: &u64| *id)).unwrap();
+
+        kept.set_bounds(TypedRect::new(TypedPoint2D::new(4.0, 0.0), TypedSize2D::new(8.0, 8.0)));
+        let scroller = layer(3, 8.0, 8.0, 8.0, 8.0);
+        *scroller.content_size.borrow_mut() = Some(TypedSize2D::new(8.0, 16.0));
+        root.add_child(scroller.clone());
+        let added = ReplayLayer::new(&*scroller, &|id: &u64| *id);
+        recorder.record_frame(1.0, &scene);
+        assert!(recorder.record_scroll_by(2.0, &scene, &scroller, TypedPoint2D::new(0.0, -3.0)));
+        root.remove_child(&kept);
+        recorder.record_frame(3.0, &scene);
+
+        let script = recorder.finish();
+        assert_eq!(script.root, start);
+        let events: Vec<(f64, ReplayEvent)> = script.events.into_iter().map(|event| {
+            let TimedReplayEvent { time, event } = event;
+            (time, event)
+        }).collect();
+        assert_eq!(events,
+                   vec!((1.0, ReplayEvent::BoundsChanged(2, [4.0, 0.0, 8.0, 8.0])),
+                        (1.0, ReplayEvent::LayerAdded(1, 1, added)),
+                        (1.0, ReplayEvent::Frame),
+                        (2.0, ReplayEvent::ScrolledBy(3, [0.0, -3.0])),
+                        (3.0, ReplayEvent::LayerRemoved(2)),
+                        (3.0, ReplayEvent::Frame)));
+    }
+}
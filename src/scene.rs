@@ -7,20 +7,188 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use euclid::rect::TypedRect;
+use euclid::Matrix4D;
+use euclid::point::Point2D;
+use euclid::rect::{Rect, TypedRect};
 use euclid::scale_factor::ScaleFactor;
 use euclid::size::TypedSize2D;
 use euclid::point::TypedPoint2D;
-use geometry::{DevicePixel, LayerPixel};
-use layers::{BufferRequest, Layer, LayerBuffer};
+use color::Color;
+use completion::{CompletionSignal, CompletionToken};
+use dump::{LayerDump, LayerTreeDump};
+use frame::FrameIndex;
+use geometry::{DevicePixel, LayerPixel, ScreenPixel};
+use geometry::{screen_to_device_scale, untransformed_world_rect_to_screen};
+use layers::{BufferRequest, DEFAULT_RESOLUTION_EPSILON, Layer, LayerBuffer, RequestOrdering};
+use layers::RoundedClip;
+use rendergl::RenderContext3D;
+use tiling::TileGridStatistics;
+use util::{inset_rect, project_rect_to_screen, rects_share_edge};
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::mem;
 use std::rc::Rc;
+use visibility::{VisibilityBlocker, VisibilityExplanation};
+
+/// How much memory `Scene::collect_memory` releases.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryReleasePolicy {
+    /// Only the buffers that are no longer displayed. Nothing visible is lost.
+    UnusedBuffersOnly,
+    /// The unused buffers and the buffers of tiles outside of the viewport, which are requested
+    /// again when they come back into view.
+    OffscreenTiles,
+    /// All buffers and textures. Every tile has to be painted again before it is displayed.
+    Everything,
+}
+
+/// The resources held by the buffers of a group of layers. See `Scene::memory_by_group`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The memory used by the buffers, in bytes. The memory may be allocated on the heap or in
+    /// GPU memory.
+    pub buffer_memory: usize,
+
+    /// The area of the buffers, in device pixels.
+    pub raster_area: usize,
+}
+
+/// A layer of a 3D context being hit tested, along with the hits in the context it establishes.
+struct HitTestEntry<T> {
+    layer: Option<Rc<Layer<T>>>,
+    nested_hits: Vec<Rc<Layer<T>>>,
+    z_center: f32,
+    stacking_index: u64,
+}
+
+/// Buffers for one or more layers that must appear on screen together, for instance the tiles
+/// of a page that was scrolled. Queued with `Scene::queue_buffer_transaction`, the buffers are
+/// only handed to their layers by `Scene::commit_buffer_transactions`, all at once, after which
+/// the token of the transaction completes. Dropping a transaction that was never committed
+/// cancels its token.
+pub struct BufferTransaction<T> {
+    buffers: Vec<(Rc<Layer<T>>, Box<LayerBuffer>)>,
+    signal: CompletionSignal,
+}
+
+impl<T> BufferTransaction<T> {
+    pub fn new() -> BufferTransaction<T> {
+        BufferTransaction {
+            buffers: vec!(),
+            signal: CompletionSignal::new(),
+        }
+    }
+
+    /// Adds a buffer for `layer` to the transaction.
+    pub fn add_buffer(&mut self, layer: &Rc<Layer<T>>, buffer: Box<LayerBuffer>) {
+        self.buffers.push((layer.clone(), buffer));
+    }
+
+    /// Returns a token that completes when the transaction is committed.
+    pub fn token(&self) -> CompletionToken {
+        self.signal.token()
+    }
+}
+
+/// A zoom from one scene scale to another, run by `Scene::tick_animations`.
+struct ScaleAnimation {
+    from: f32,
+    to: f32,
+    start_time: f64,
+    duration: f64,
+    signal: CompletionSignal,
+}
 
 pub struct Scene<T> {
     pub root: Option<Rc<Layer<T>>>,
+
+    /// The part of the window the scene is drawn into. The layer tree is in the same space, so
+    /// only the part of it that lies within the viewport is shown, with the viewport origin at
+    /// the top left. This lets several scenes share a window without translating their layers.
     pub viewport: TypedRect<f32, DevicePixel>,
 
     /// The scene scale, to allow for zooming and high-resolution painting.
     pub scale: ScaleFactor<f32, LayerPixel, DevicePixel>,
+
+    /// The color the viewport is cleared to before the layers are drawn. Not premultiplied; a
+    /// fully transparent color clears to transparent black, so that the output can be
+    /// composited over native content.
+    pub background_color: Color,
+
+    /// Memory budgets in bytes, keyed by layer group id. Layers belonging to a group that is over
+    /// its budget do not receive new buffer requests, so that a single group cannot starve the
+    /// others. Their tiles outside the viewport are still thrown out, which brings the group
+    /// back under its budget.
+    pub group_memory_budgets: HashMap<u64, usize>,
+
+    /// Raster area budgets in device pixels, keyed by layer group id. These are enforced like
+    /// `group_memory_budgets`.
+    pub group_raster_area_budgets: HashMap<u64, usize>,
+
+    /// The groups found to be over budget at the start of the current request pass.
+    groups_over_budget: Vec<u64>,
+
+    /// The number of buffers requested for the layers of each group by the last request pass,
+    /// sorted by group id. See `requests_by_group`.
+    requests_by_group: Vec<(u64, usize)>,
+
+    /// Whether a gesture such as a pinch or a drag is in progress. While it is, transform updates
+    /// skip masking subtrees that are outside of the viewport.
+    gesture_in_progress: Cell<bool>,
+
+    /// Whether the last transform update skipped any subtrees, which must be caught up once the
+    /// gesture ends.
+    transform_updates_skipped: Cell<bool>,
+
+    /// The number of update passes run by `flush_transform_updates`.
+    transform_update_count: Cell<usize>,
+
+    /// Whether the buffer request pass skips tiles hidden behind layers with opaque contents.
+    /// This is only done for layer trees drawn in plain paint order (see `draws_flat`), with the
+    /// same rules as the occlusion culling done when rendering. On by default; embedders that
+    /// need the content of every layer, for instance to take screenshots of occluded areas, can
+    /// turn it off.
+    pub occlusion_aware_requests: bool,
+
+    /// The order in which the buffer requests of each layer are returned. Tiles closest to the
+    /// center of the viewport come first by default.
+    pub request_ordering: RequestOrdering,
+
+    /// Whether `render_scene` may draw the layer tree in plain paint order, without sorting by
+    /// depth or using the depth buffer. This is only done while no layer has a 3D transform;
+    /// otherwise the general path is used.
+    pub prefer_2d_rendering: bool,
+
+    /// The relative tolerance used by `add_buffer` to decide whether a buffer was painted at the
+    /// scene scale, for layers that don't set their own.
+    pub resolution_epsilon: f32,
+
+    /// The frame in which a tile stuck in a repaint loop was last reported, if any.
+    last_resolution_loop_report: Cell<Option<FrameIndex>>,
+
+    /// The number of frames `render_scene` drew as a flat layer tree, in plain paint order.
+    flat_frame_count: Cell<usize>,
+
+    /// The number of 3D contexts `render_scene` drew without clearing the depth buffer, because
+    /// their layers don't intersect.
+    skipped_depth_clear_count: Cell<usize>,
+
+    /// The screen-space rects covered by opaque layers found so far in the current request pass,
+    /// which visits layers from front to back if it takes occlusion into account.
+    opaque_region: Vec<TypedRect<f32, ScreenPixel>>,
+
+    /// The clips the layers are drawn with, if the current request pass takes occlusion into
+    /// account. The layers of a tree drawn in plain paint order all share the clips of the root.
+    occlusion_clips: Option<(Option<TypedRect<f32, ScreenPixel>>, Option<RoundedClip>)>,
+
+    /// The buffer transactions waiting for `commit_buffer_transactions`, in the order they were
+    /// queued.
+    pending_transactions: Vec<BufferTransaction<T>>,
+
+    /// The zoom started by `animate_scale_to`, if it hasn't ended yet.
+    scale_animation: Option<ScaleAnimation>,
 }
 
 impl<T> Scene<T> {
@@ -29,45 +197,174 @@ impl<T> Scene<T> {
             root: None,
             viewport: viewport,
             scale: ScaleFactor::new(1.0),
+            background_color: Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 },
+            group_memory_budgets: HashMap::new(),
+            group_raster_area_budgets: HashMap::new(),
+            groups_over_budget: vec!(),
+            requests_by_group: vec!(),
+            gesture_in_progress: Cell::new(false),
+            transform_updates_skipped: Cell::new(false),
+            transform_update_count: Cell::new(0),
+            occlusion_aware_requests: true,
+            request_ordering: RequestOrdering::default(),
+            prefer_2d_rendering: false,
+            resolution_epsilon: DEFAULT_RESOLUTION_EPSILON,
+            last_resolution_loop_report: Cell::new(None),
+            flat_frame_count: Cell::new(0),
+            skipped_depth_clear_count: Cell::new(0),
+            opaque_region: vec!(),
+            occlusion_clips: None,
+            pending_transactions: vec!(),
+            scale_animation: None,
+        }
+    }
+
+    /// Returns the number of frames that `render_scene` drew in plain paint order without
+    /// building a `RenderContext3D`, because no layer had a 3D transform or established a nested
+    /// rendering context, or because 2D rendering was preferred.
+    pub fn flat_frame_count(&self) -> usize {
+        self.flat_frame_count.get()
+    }
+
+    /// Records that a frame was drawn in plain paint order. Rendering only.
+    pub fn note_flat_frame(&self) {
+        self.flat_frame_count.set(self.flat_frame_count.get() + 1)
+    }
+
+    /// Returns the number of 3D contexts that `render_scene` drew with depth testing disabled
+    /// and without clearing the depth buffer, because none of their layers intersect. Contexts
+    /// of flat layer trees aren't counted.
+    pub fn skipped_depth_clear_count(&self) -> usize {
+        self.skipped_depth_clear_count.get()
+    }
+
+    /// Records that `count` 3D contexts were drawn without clearing the depth buffer. Rendering
+    /// only.
+    pub fn note_skipped_depth_clears(&self, count: usize) {
+        self.skipped_depth_clear_count.set(self.skipped_depth_clear_count.get() + count)
+    }
+
+    fn get_buffer_requests_for_layer_without_children(
+            &mut self,
+            layer: &Rc<Layer<T>>,
+            dirty_rect: TypedRect<f32, ScreenPixel>,
+            viewport_rect: TypedRect<f32, ScreenPixel>,
+            layers_and_requests: &mut Vec<(Rc<Layer<T>>, Vec<BufferRequest>)>,
+            unused_buffers: &mut Vec<Box<LayerBuffer>>) {
+        // Get buffers for this layer, in global (screen) coordinates. Hidden layers keep their
+        // tiles as they are.
+        if layer.is_hidden() {
+            debug!("Skipping buffer requests for hidden layer");
+        } else {
+            // Only layers that the opaque region could cull when drawing are occluded.
+            let occluder_count = match self.occlusion_clips {
+                Some(_) if layer.occludable_screen_rect().is_some() => self.opaque_region.len(),
+                _ => 0,
+            };
+            let mut update = layer.compute_buffer_requests(dirty_rect,
+                                                           viewport_rect,
+                                                           layer.effective_scale(self.scale),
+                                                           &self.opaque_region[..occluder_count],
+                                                           self.request_ordering);
+            // A group over its budget still throws out its tiles outside the viewport, or it
+            // would never get back under it.
+            if self.group_over_budget(layer).is_some() {
+                debug!("Dropping buffer requests for layer in group over its budget");
+                if let Some(ref mut update) = update {
+                    update.drop_requests();
+                }
+            }
+            // An empty vector doesn't allocate, so layers without requests cost nothing.
+            let mut requests = vec!();
+            layer.apply_buffer_request_update_into(update, &mut requests);
+            if !requests.is_empty() {
+                if let Some(group_id) = layer.group_id.get() {
+                    self.add_group_requests(group_id, requests.len());
+                }
+                layers_and_requests.push((layer.clone(), requests));
+            }
+
+            if let Some((clip_rect, rounded_clip)) = self.occlusion_clips {
+                // Masking ancestors have already clipped the dirty rect, so the layer can only
+                // hide what is inside of it.
+                let clip_rect = match clip_rect {
+                    Some(clip_rect) => clip_rect.intersection(&dirty_rect),
+                    None => Some(dirty_rect),
+                };
+                if let Some(clip_rect) = clip_rect {
+                    layer.occlude(false, Some(clip_rect), rounded_clip, &mut self.opaque_region);
+                }
+            }
         }
+        unused_buffers.extend(layer.collect_unused_buffers().into_iter());
     }
 
+    /// Collects the buffer requests for a layer and its descendants. When the request pass takes
+    /// occlusion into account, layers are visited from front to back, in reverse paint order, so
+    /// that the opaque layers in front of a layer are known when its tiles are requested.
     pub fn get_buffer_requests_for_layer(&mut self,
                                          layer: Rc<Layer<T>>,
-                                         dirty_rect: TypedRect<f32, LayerPixel>,
-                                         viewport_rect: TypedRect<f32, LayerPixel>,
+                                         dirty_rect: TypedRect<f32, ScreenPixel>,
+                                         viewport_rect: TypedRect<f32, ScreenPixel>,
                                          layers_and_requests: &mut Vec<(Rc<Layer<T>>,
                                                                         Vec<BufferRequest>)>,
                                          unused_buffers: &mut Vec<Box<LayerBuffer>>) {
-        // Get buffers for this layer, in global (screen) coordinates.
-        let requests = layer.get_buffer_requests(dirty_rect, viewport_rect, self.scale);
-        if !requests.is_empty() {
-            layers_and_requests.push((layer.clone(), requests));
+        if layer.subtree_is_hidden() {
+            Scene::collect_unused_buffers_in_subtree(&layer, unused_buffers);
+            return;
+        }
+
+        // A layer fixed to the viewport doesn't move with its ancestors, so the rects they were
+        // clipped to don't apply to it; its tiles are requested and kept for the whole viewport.
+        let dirty_rect = if layer.is_fixed_to_viewport() { viewport_rect } else { dirty_rect };
+
+        let front_to_back = self.occlusion_clips.is_some();
+        if !front_to_back {
+            self.get_buffer_requests_for_layer_without_children(&layer,
+                                                                dirty_rect,
+                                                                viewport_rect,
+                                                                layers_and_requests,
+                                                                unused_buffers);
         }
-        unused_buffers.extend(layer.collect_unused_buffers().into_iter());
 
         // If this layer masks its children, we don't need to ask for tiles outside the
         // boundaries of this layer.
-        let child_dirty_rect = if !*layer.masks_to_bounds.borrow() {
-            dirty_rect
+        let child_dirty_rect = if !layer.masks_to_bounds() {
+            Some(dirty_rect)
         } else {
             match layer.transform_state.borrow().screen_rect {
-                Some(ref screen_rect) => {
-                    match dirty_rect.to_untyped().intersection(&screen_rect.rect) {
-                        Some(ref child_dirty_rect) => TypedRect::from_untyped(child_dirty_rect),
-                        None => return, // The layer is entirely outside the dirty rect.
-                    }
-                },
-                None => return, // The layer is entirely clipped.
+                // If there is no intersection, the layer is entirely outside the dirty rect.
+                Some(ref screen_rect) => dirty_rect.intersection(&screen_rect.rect),
+                None => None, // The layer is entirely clipped.
             }
         };
 
+        if let Some(child_dirty_rect) = child_dirty_rect {
+            let kids = layer.children();
+            for index in 0..kids.len() {
+                let kid = if front_to_back { &kids[kids.len() - 1 - index] } else { &kids[index] };
+                self.get_buffer_requests_for_layer(kid.clone(),
+                                                   child_dirty_rect,
+                                                   viewport_rect,
+                                                   layers_and_requests,
+                                                   unused_buffers);
+            }
+        }
+
+        if front_to_back {
+            self.get_buffer_requests_for_layer_without_children(&layer,
+                                                                dirty_rect,
+                                                                viewport_rect,
+                                                                layers_and_requests,
+                                                                unused_buffers);
+        }
+    }
+
+    fn collect_unused_buffers_in_subtree(layer: &Rc<Layer<T>>,
+                                         unused_buffers: &mut Vec<Box<LayerBuffer>>) {
+        unused_buffers.extend(layer.collect_unused_buffers().into_iter());
         for kid in layer.children().iter() {
-            self.get_buffer_requests_for_layer(kid.clone(),
-                                               child_dirty_rect,
-                                               viewport_rect,
-                                               layers_and_requests,
-                                               unused_buffers);
+            Scene::collect_unused_buffers_in_subtree(kid, unused_buffers);
         }
     }
 
@@ -79,13 +376,526 @@ impl<T> Scene<T> {
             None => return,
         };
 
+        // Requests must be computed from a consistent transform state.
+        self.flush_transform_updates();
+
+        // Finding the usage of the groups takes a walk over the whole tree, which only the
+        // budgets need.
+        self.groups_over_budget.clear();
+        if !self.group_memory_budgets.is_empty() || !self.group_raster_area_budgets.is_empty() {
+            let memory_by_group = self.memory_by_group();
+            self.groups_over_budget = memory_by_group.into_iter().filter(|&(group_id, usage)| {
+                self.group_memory_budgets.get(&group_id).map_or(false, |&budget| {
+                    usage.buffer_memory > budget
+                }) || self.group_raster_area_budgets.get(&group_id).map_or(false, |&budget| {
+                    usage.raster_area > budget
+                })
+            }).map(|(group_id, _)| group_id).collect();
+        }
+        self.requests_by_group.clear();
+        self.opaque_region.clear();
+        self.occlusion_clips = if self.occlusion_aware_requests && self.draws_flat(&root_layer) {
+            Some((root_layer.mask_clip_rect(None), root_layer.mask_rounded_clip(None)))
+        } else {
+            None
+        };
+
+        let root_rect = Scene::root_screen_rect(&root_layer);
         self.get_buffer_requests_for_layer(root_layer.clone(),
-                                           *root_layer.bounds.borrow(),
-                                           *root_layer.bounds.borrow(),
+                                           root_rect,
+                                           root_rect,
                                            requests,
                                            unused_buffers);
     }
 
+    /// Adds a buffer sent by the painter to the given layer if it was painted at the scene
+    /// scale, or at the scale override of the layer, within the relative tolerance of the layer
+    /// or of the scene. Otherwise the buffer is put with the unused buffers of the layer, and
+    /// its tile is requested again. Returns true if the buffer was added.
+    ///
+    /// See `Layer::add_buffer_checked` for tiles that keep receiving buffers at the wrong scale;
+    /// the resolution loops of all the layers of the scene share one rate-limited error report.
+    pub fn add_buffer(&self, layer: &Rc<Layer<T>>, buffer: Box<LayerBuffer>, frame: FrameIndex)
+                      -> bool {
+        layer.add_buffer_checked(buffer,
+                                 self.scale,
+                                 self.resolution_epsilon,
+                                 frame,
+                                 &self.last_resolution_loop_report)
+    }
+
+    /// Queues a buffer transaction, to be committed by the next call to
+    /// `commit_buffer_transactions`, and returns its token.
+    pub fn queue_buffer_transaction(&mut self, transaction: BufferTransaction<T>)
+                                    -> CompletionToken {
+        let token = transaction.token();
+        self.pending_transactions.push(transaction);
+        token
+    }
+
+    /// Hands the buffers of the queued transactions to their layers with `add_buffer`, in the
+    /// order the transactions were queued, and completes their tokens. This should be called once
+    /// per frame, before rendering, so that the buffers of a transaction show up in the same
+    /// frame. Returns the number of transactions committed.
+    pub fn commit_buffer_transactions(&mut self, frame: FrameIndex) -> usize {
+        let transactions = mem::replace(&mut self.pending_transactions, vec!());
+        let count = transactions.len();
+        for transaction in transactions {
+            let BufferTransaction { buffers, signal } = transaction;
+            for (layer, buffer) in buffers {
+                self.add_buffer(&layer, buffer, frame);
+            }
+            signal.complete();
+        }
+        count
+    }
+
+    /// Starts zooming the scene from its current scale to `scale` over `duration` seconds,
+    /// starting at `now`, on the embedder's clock. The scale is updated by `tick_animations`,
+    /// and the returned token completes once it reaches `scale`. A zoom still running is
+    /// replaced, and its token cancelled.
+    pub fn animate_scale_to(&mut self,
+                            scale: ScaleFactor<f32, LayerPixel, DevicePixel>,
+                            now: f64,
+                            duration: f64)
+                            -> CompletionToken {
+        let signal = CompletionSignal::new();
+        let token = signal.token();
+        self.scale_animation = Some(ScaleAnimation {
+            from: self.scale.get(),
+            to: scale.get(),
+            start_time: now,
+            duration: duration,
+            signal: signal,
+        });
+        self.tick_animations(now);
+        token
+    }
+
+    /// Advances the running zoom, if any, to `now`, and completes its token if it has ended.
+    /// Returns true if a zoom is still running, in which case another frame should be scheduled.
+    pub fn tick_animations(&mut self, now: f64) -> bool {
+        let progress = match self.scale_animation {
+            Some(ref animation) if animation.duration > 0.0 => {
+                ((now - animation.start_time) / animation.duration).max(0.0).min(1.0) as f32
+            }
+            Some(_) => 1.0,
+            None => return false,
+        };
+        let animation = self.scale_animation.take().unwrap();
+        if progress < 1.0 {
+            let scale = animation.from + (animation.to - animation.from) * progress;
+            self.scale = ScaleFactor::new(scale);
+            self.scale_animation = Some(animation);
+            return true
+        }
+        self.scale = ScaleFactor::new(animation.to);
+        animation.signal.complete();
+        false
+    }
+
+    /// Returns true if `render_scene` draws the layer tree with the given root as a flat list in
+    /// plain paint order, without sorting by depth or using the depth buffer. See
+    /// `Layer::subtree_is_flat` and `prefer_2d_rendering`.
+    pub fn draws_flat(&self, root_layer: &Layer<T>) -> bool {
+        root_layer.subtree_is_flat() ||
+            (self.prefer_2d_rendering && !root_layer.subtree_has_3d_transforms())
+    }
+
+    /// Returns the group of the given layer if that group was found to be over its memory or
+    /// raster area budget in the last request pass.
+    fn group_over_budget(&self, layer: &Layer<T>) -> Option<u64> {
+        layer.group_id.get().and_then(|group_id| {
+            if self.groups_over_budget.contains(&group_id) {
+                Some(group_id)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns the screen rect of the root layer, which bounds the buffer request pass.
+    fn root_screen_rect(root_layer: &Layer<T>) -> TypedRect<f32, ScreenPixel> {
+        // The root layer is never transformed, so its bounds are also its screen rect.
+        untransformed_world_rect_to_screen(&root_layer.bounds())
+    }
+
+    /// Finds the path from `layer` down to `target`, both included, appending it to `path`.
+    fn find_path_to_layer(layer: &Rc<Layer<T>>,
+                          target: &Rc<Layer<T>>,
+                          path: &mut Vec<Rc<Layer<T>>>)
+                          -> bool {
+        path.push(layer.clone());
+        if &**layer as *const Layer<T> == &**target as *const Layer<T> {
+            return true;
+        }
+        for kid in layer.children().iter() {
+            if Scene::find_path_to_layer(kid, target, path) {
+                return true;
+            }
+        }
+        path.pop();
+        false
+    }
+
+    /// Explains why the given layer, or the given tile of it, is not visible. This makes the same
+    /// checks as the render and buffer request paths, in order, and reports the first one that
+    /// keeps the content from showing. The answer reflects the state after the last transform
+    /// update and request pass.
+    pub fn explain_visibility(&self,
+                              layer: &Rc<Layer<T>>,
+                              tile_index: Option<Point2D<usize>>)
+                              -> VisibilityExplanation {
+        VisibilityExplanation {
+            tile_index: tile_index,
+            blocker: self.find_visibility_blocker(layer, tile_index),
+        }
+    }
+
+    fn find_visibility_blocker(&self,
+                               layer: &Rc<Layer<T>>,
+                               tile_index: Option<Point2D<usize>>)
+                               -> Option<VisibilityBlocker> {
+        let root_layer = match self.root {
+            Some(ref root_layer) => root_layer,
+            None => return Some(VisibilityBlocker::NotInScene),
+        };
+        let mut path = vec!();
+        if !Scene::find_path_to_layer(root_layer, layer, &mut path) {
+            return Some(VisibilityBlocker::NotInScene);
+        }
+        if path.iter().any(|layer_on_path| layer_on_path.has_dirty_transform_state()) {
+            return Some(VisibilityBlocker::StaleTransformState);
+        }
+
+        // Follow the clip rect down the tree as `RenderContext3D` does when it is built.
+        let mut clip_rect = root_layer.mask_clip_rect(None);
+        for (depth, ancestor) in path[..path.len() - 1].iter().enumerate() {
+            if ancestor.subtree_is_hidden() {
+                return Some(VisibilityBlocker::HiddenByAncestor { depth: depth });
+            }
+            if ancestor.establishes_render_context() {
+                clip_rect = ancestor.mask_clip_rect(clip_rect);
+                if let Some(clip_rect) = clip_rect {
+                    if clip_rect.is_empty() {
+                        return Some(VisibilityBlocker::ClippedByAncestor {
+                            depth: depth,
+                            clip_rect: clip_rect,
+                        });
+                    }
+                }
+            } else if ancestor.transform_state.borrow().screen_rect.is_none() {
+                return Some(VisibilityBlocker::AncestorEntirelyClipped { depth: depth });
+            }
+        }
+
+        if layer.is_hidden() {
+            return Some(VisibilityBlocker::Hidden);
+        }
+
+        let screen_rect = match layer.transform_state.borrow().screen_rect {
+            Some(ref screen_rect) => screen_rect.rect,
+            None => return Some(VisibilityBlocker::EntirelyClipped),
+        };
+
+        let content_rect = layer.content_world_rect();
+        if content_rect.is_empty() {
+            return Some(VisibilityBlocker::EmptyContentRect { content_rect: content_rect });
+        }
+        let world_clip_rect = layer.world_clip_rect(clip_rect);
+        if layer.clipped_content_rect(world_clip_rect).is_empty() {
+            return Some(VisibilityBlocker::ContentClipped {
+                content_rect: content_rect.to_untyped(),
+                clip_rect: world_clip_rect.unwrap_or(Rect::zero()),
+            });
+        }
+
+        let viewport = Scene::root_screen_rect(root_layer);
+        if !screen_rect.intersects(&viewport) {
+            return Some(VisibilityBlocker::OutsideViewport {
+                screen_rect: screen_rect,
+                viewport: viewport,
+            });
+        }
+
+        let opacity = layer.transform_state.borrow().effective_opacity;
+        if opacity <= 0.0 {
+            return Some(VisibilityBlocker::Transparent { opacity: opacity });
+        }
+
+        // Nine-patch layers have no tiles, only the texture of their buffer.
+        if layer.is_nine_patch() {
+            let mut has_texture = false;
+            layer.with_nine_patch(|_, _, _| has_texture = true);
+            return if has_texture { None } else { Some(VisibilityBlocker::TextureNotCreated) };
+        }
+
+        let tile_index = match tile_index {
+            Some(tile_index) => tile_index,
+            None => {
+                let mut has_drawable_tiles = false;
+                layer.do_for_all_tiles(|tile| has_drawable_tiles |= tile.is_drawable());
+                if has_drawable_tiles {
+                    return None;
+                }
+                return Some(VisibilityBlocker::NoDrawableTiles);
+            }
+        };
+
+        let content_age = *layer.content_age.borrow();
+        let over_budget = self.group_over_budget(layer);
+        let in_viewport = layer.tile_intersects_screen_rect(&tile_index,
+                                                            &viewport,
+                                                            layer.effective_scale(self.scale));
+        layer.with_tile(&tile_index, |tile, occluded| {
+            let has_buffer = tile.and_then(|tile| tile.buffer_content_age()).is_some();
+            if !has_buffer {
+                if !in_viewport {
+                    return Some(VisibilityBlocker::TileOutsideViewport { viewport: viewport });
+                }
+                if occluded {
+                    return Some(VisibilityBlocker::TileOccluded);
+                }
+                if let Some(pending_content_age) = tile.and_then(|tile| {
+                    tile.pending_content_age()
+                }) {
+                    return Some(VisibilityBlocker::AwaitingBuffer {
+                        pending_content_age: pending_content_age,
+                    });
+                }
+                if let Some(group_id) = over_budget {
+                    return Some(VisibilityBlocker::GroupOverBudget { group_id: group_id });
+                }
+                return Some(VisibilityBlocker::TileNotRequested);
+            }
+
+            let tile = tile.unwrap();
+            if !tile.is_drawable() {
+                return Some(VisibilityBlocker::TextureNotCreated);
+            }
+            match tile.buffer_content_age() {
+                Some(buffer_content_age) if buffer_content_age < content_age => {
+                    Some(VisibilityBlocker::StaleContent {
+                        buffer_content_age: buffer_content_age,
+                        content_age: content_age,
+                    })
+                }
+                _ => None,
+            }
+        })
+    }
+
+    /// Returns the topmost layer under the given point, if any. The point is in the same space
+    /// as the viewport. See `hit_test_all`.
+    pub fn hit_test(&self, point: TypedPoint2D<f32, DevicePixel>) -> Option<Rc<Layer<T>>> {
+        self.hit_test_all(point).into_iter().next()
+    }
+
+    /// Returns all the layers under the given point, from front to back. The point is in the
+    /// same space as the viewport.
+    ///
+    /// Layers are visited in reverse paint order, with the layers of 3D contexts sorted by depth
+    /// as `RenderContext3D` does, and points clipped away by masking ancestors don't hit their
+    /// descendants. The point is mapped back onto the plane of each layer through the inverse of
+    /// its transform, as `Layer::convert_point_from_screen` does, and tested against its bounds
+    /// shrunk by its content inset, which is where the layer draws.
+    ///
+    /// Hidden layers are never hit. Their descendants are, unless the whole subtree is hidden.
+    pub fn hit_test_all(&self, point: TypedPoint2D<f32, DevicePixel>) -> Vec<Rc<Layer<T>>> {
+        let root_layer = match self.root {
+            Some(ref root_layer) => root_layer.clone(),
+            None => return vec!(),
+        };
+
+        self.flush_transform_updates();
+
+        // Trees drawn in plain paint order are hit tested in that order too.
+        let sort_by_depth = !self.draws_flat(&root_layer);
+
+        let point = point / screen_to_device_scale(self.scale);
+        let mut hits = vec!();
+        Scene::hit_test_context(&[root_layer.clone()],
+                                root_layer.mask_clip_rect(None),
+                                &point,
+                                sort_by_depth,
+                                &mut hits);
+        hits
+    }
+
+    /// Returns the stacking indices of the layers of the scene in the order in which a
+    /// `RenderContext3D` built from the root layer draws them. See
+    /// `RenderContext3D::debug_render_order`.
+    pub fn debug_render_order(&self) -> Vec<u64> {
+        match self.root {
+            Some(ref root_layer) => {
+                self.flush_transform_updates();
+                RenderContext3D::new(root_layer.clone()).debug_render_order()
+            }
+            None => vec!(),
+        }
+    }
+
+    /// Appends the layers of a 3D context that are under the given point to `hits`, from front
+    /// to back. `layers` are the layers the context is built from, in paint order.
+    fn hit_test_context(layers: &[Rc<Layer<T>>],
+                        clip_rect: Option<TypedRect<f32, ScreenPixel>>,
+                        point: &TypedPoint2D<f32, ScreenPixel>,
+                        sort_by_depth: bool,
+                        hits: &mut Vec<Rc<Layer<T>>>) {
+        let mut entries = vec!();
+        for layer in layers {
+            Scene::add_to_hit_test_context(layer, clip_rect, point, sort_by_depth, &mut entries);
+        }
+
+        // Entries are added in paint order and the sort is stable, so sorting by depth and then
+        // by stacking index gives the order in which `RenderContext3D` draws them.
+        if sort_by_depth {
+            entries.sort_by(|a, b| {
+                match a.z_center.partial_cmp(&b.z_center).unwrap_or(Ordering::Equal) {
+                    Ordering::Equal => a.stacking_index.cmp(&b.stacking_index),
+                    ordering => ordering,
+                }
+            });
+        }
+
+        for entry in entries.into_iter().rev() {
+            // A nested context is drawn after the layer that establishes it.
+            hits.extend(entry.nested_hits.into_iter());
+            if let Some(layer) = entry.layer {
+                if Scene::layer_contains_point(&layer, clip_rect, point) {
+                    hits.push(layer);
+                }
+            }
+        }
+    }
+
+    /// Adds a layer and its descendants to the entries of a 3D context being hit tested,
+    /// following the structure `RenderContext3DBuilder` gives the context.
+    fn add_to_hit_test_context(layer: &Rc<Layer<T>>,
+                               clip_rect: Option<TypedRect<f32, ScreenPixel>>,
+                               point: &TypedPoint2D<f32, ScreenPixel>,
+                               sort_by_depth: bool,
+                               entries: &mut Vec<HitTestEntry<T>>) {
+        if layer.subtree_is_hidden() {
+            return;
+        }
+
+        let (hit_layer, z_center) = match layer.transform_state.borrow().screen_rect {
+            Some(ref rect) => (Some(layer.clone()), rect.z_center),
+            None => (None, 0.), // Layer is entirely clipped.
+        };
+        let clipped = hit_layer.is_none();
+        let hit_layer = if layer.is_hidden() { None } else { hit_layer };
+
+        if layer.establishes_render_context() {
+            let child_clip_rect = layer.mask_clip_rect(clip_rect);
+            if !child_clip_rect.map_or(false, |child_clip_rect| child_clip_rect.is_empty()) {
+                let mut nested_hits = vec!();
+                Scene::hit_test_context(&*layer.children(),
+                                        child_clip_rect,
+                                        point,
+                                        sort_by_depth,
+                                        &mut nested_hits);
+                entries.push(HitTestEntry {
+                    layer: hit_layer,
+                    nested_hits: nested_hits,
+                    z_center: z_center,
+                    stacking_index: *layer.stacking_index.borrow(),
+                });
+                return;
+            }
+        }
+
+        if clipped {
+            return;
+        }
+
+        if hit_layer.is_some() {
+            entries.push(HitTestEntry {
+                layer: hit_layer,
+                nested_hits: vec!(),
+                z_center: z_center,
+                stacking_index: *layer.stacking_index.borrow(),
+            });
+        }
+
+        for child in layer.children().iter() {
+            Scene::add_to_hit_test_context(child, clip_rect, point, sort_by_depth, entries);
+        }
+    }
+
+    /// Returns true if the given screen-space point lies within the clip rect and the content
+    /// rect of the layer.
+    fn layer_contains_point(layer: &Layer<T>,
+                            clip_rect: Option<TypedRect<f32, ScreenPixel>>,
+                            point: &TypedPoint2D<f32, ScreenPixel>)
+                            -> bool {
+        if let Some(clip_rect) = clip_rect {
+            if !clip_rect.contains(point) {
+                return false;
+            }
+        }
+
+        match layer.convert_point_from_screen(point.to_untyped()) {
+            Some(layer_point) => {
+                let size = layer.transform_state.borrow().world_rect.size.to_untyped();
+                inset_rect(&Rect::new(Point2D::zero(), size), &layer.content_inset())
+                    .contains(&layer_point)
+            }
+            None => false,
+        }
+    }
+
+    fn accumulate_layer_damage(layer: &Rc<Layer<T>>,
+                               damage: &mut Option<TypedRect<f32, ScreenPixel>>,
+                               composite_damaged: &mut bool) {
+        if layer.take_composite_damage() {
+            *composite_damaged = true;
+        }
+        // The content of hidden layers can change without damaging anything.
+        let layer_damage = layer.take_damage();
+        let layer_damage = if layer.is_hidden() { None } else { layer_damage };
+        if let Some(layer_damage) = layer_damage {
+            let ts = layer.transform_state.borrow();
+            let world_damage = layer_damage.translate(&ts.world_rect.origin);
+            if let Some(screen_rect) = project_rect_to_screen(&world_damage, &ts.final_transform) {
+                *damage = Some(match *damage {
+                    Some(damage) => damage.union(&screen_rect.rect),
+                    None => screen_rect.rect,
+                });
+            }
+        }
+        for kid in layer.children().iter() {
+            Scene::accumulate_layer_damage(kid, damage, composite_damaged);
+        }
+    }
+
+    /// Takes the damage of all the layers and returns the part of the viewport whose pixels
+    /// changed since the last call, in the same space as the viewport, or None if nothing
+    /// changed and the scene need not be rendered again. Changes that invalidate the transform
+    /// state of a layer, such as a new transform, opacity, or set of children, damage the whole
+    /// viewport.
+    pub fn accumulate_damage(&self) -> Option<TypedRect<f32, DevicePixel>> {
+        let root_layer = match self.root {
+            Some(ref root_layer) => root_layer,
+            None => return None,
+        };
+
+        // Damage is projected to the screen with the current transforms.
+        self.flush_transform_updates();
+
+        let mut damage = None;
+        let mut composite_damaged = false;
+        Scene::accumulate_layer_damage(root_layer, &mut damage, &mut composite_damaged);
+
+        let damage = if composite_damaged {
+            Some(self.viewport)
+        } else {
+            damage.map(|damage| damage * screen_to_device_scale(self.scale))
+        };
+        damage.and_then(|damage| damage.intersection(&self.viewport))
+    }
+
     pub fn mark_layer_contents_as_changed_recursively_for_layer(&self, layer: Rc<Layer<T>>) {
         layer.contents_changed();
         for kid in layer.children().iter() {
@@ -103,8 +913,163 @@ impl<T> Scene<T> {
 
     pub fn set_root_layer_size(&self, new_size: TypedSize2D<f32, DevicePixel>) {
         if let Some(ref root_layer) = self.root {
-            *root_layer.bounds.borrow_mut() = TypedRect::new(TypedPoint2D::zero(),
-                                                             new_size / self.scale);
+            root_layer.set_bounds(TypedRect::new(self.viewport.origin / self.scale,
+                                                 new_size / self.scale));
+        }
+    }
+
+    /// Calls `f` with each batch of the subtree rooted at `layer`, as a slice of siblings, without
+    /// allocating.
+    fn for_each_layer_batch<F>(layer: &Rc<Layer<T>>, f: &mut F) where F: FnMut(&[Rc<Layer<T>>]) {
+        let children = layer.children();
+
+        // The current run is `children[run_start..index]`.
+        let mut run_start = 0;
+        for (index, child) in children.iter().enumerate() {
+            let run = &children[run_start..index];
+            let extends_run = child.is_batchable() && match run.first() {
+                Some(first) => {
+                    let world_rect = child.transform_state.borrow().world_rect;
+                    // The run must cover a single region: the new member touches an earlier one
+                    // along an edge, and overlaps none.
+                    first.opacity() == child.opacity() &&
+                        run.iter().all(|member| {
+                            !member.transform_state.borrow().world_rect.intersects(&world_rect)
+                        }) &&
+                        run.iter().any(|member| {
+                            rects_share_edge(&member.transform_state.borrow().world_rect,
+                                             &world_rect)
+                        })
+                }
+                None => true,
+            };
+
+            if !extends_run {
+                if run.len() >= 2 {
+                    f(run);
+                }
+                run_start = if child.is_batchable() { index } else { index + 1 };
+            }
+        }
+        if children.len() - run_start >= 2 {
+            f(&children[run_start..]);
+        }
+
+        for child in children.iter() {
+            Scene::for_each_layer_batch(child, f);
+        }
+    }
+
+    /// Finds runs of sibling layers, in paint order, that are untransformed, non-clipping, have
+    /// the same opacity, and together cover a single region without overlapping, and returns
+    /// them. Such a run draws the same in any order, as if it were one layer. This is purely an
+    /// analysis and reflects the current transform state, so it must be run again after the
+    /// transform state is updated, since any change to a member may break it out of its batch.
+    pub fn compute_layer_batches(&self) -> Vec<Vec<Rc<Layer<T>>>> {
+        let mut batches = vec!();
+        if let Some(ref root_layer) = self.root {
+            Scene::for_each_layer_batch(root_layer, &mut |batch| batches.push(batch.to_vec()));
+        }
+        batches
+    }
+
+    /// Returns the number of batches that `compute_layer_batches` would find in the tree rooted at
+    /// `root_layer`, and the number of layers in them, without allocating.
+    pub fn count_layer_batches(root_layer: &Rc<Layer<T>>) -> (usize, usize) {
+        let (mut batch_count, mut layer_count) = (0, 0);
+        Scene::for_each_layer_batch(root_layer, &mut |batch| {
+            batch_count += 1;
+            layer_count += batch.len();
+        });
+        (batch_count, layer_count)
+    }
+
+    /// Brings the transform state of the layer tree up to date, if any layer changed since the
+    /// last flush. Any number of transform changes between two flushes are coalesced into a
+    /// single update pass. This is called by `get_buffer_requests` and `render_scene`. Subtrees
+    /// skipped during a gesture are left alone until the gesture ends, unless a change elsewhere
+    /// brings them back into view.
+    pub fn flush_transform_updates(&self) {
+        let root_layer = match self.root {
+            Some(ref root_layer) => root_layer,
+            None => return,
+        };
+
+        // Skipped subtrees only need catching up once the gesture is over.
+        let catch_up = self.transform_updates_skipped.get() && !self.gesture_in_progress.get();
+        if !catch_up && !root_layer.subtree_has_dirty_transform_state() {
+            return;
+        }
+
+        let cull_rect = if self.gesture_in_progress.get() {
+            Some(TypedRect::new(self.viewport.origin / screen_to_device_scale(self.scale),
+                                self.viewport.size / screen_to_device_scale(self.scale)))
+        } else {
+            None
+        };
+        let skipped = root_layer.update_transform_state_culled(&Matrix4D::identity(),
+                                                               &Matrix4D::identity(),
+                                                               &Point2D::zero(),
+                                                               cull_rect.as_ref());
+        self.transform_updates_skipped.set(skipped);
+        self.transform_update_count.set(self.transform_update_count.get() + 1);
+    }
+
+    /// Returns the number of update passes run by `flush_transform_updates` so far.
+    pub fn transform_update_count(&self) -> usize {
+        self.transform_update_count.get()
+    }
+
+    /// Hints that a continuous gesture has started. Until `end_gesture` is called, transform
+    /// updates skip the subtrees of masking layers that are outside of the viewport.
+    pub fn begin_gesture(&self) {
+        self.gesture_in_progress.set(true);
+    }
+
+    /// Ends a gesture started with `begin_gesture`, catching up any skipped subtrees.
+    pub fn end_gesture(&self) {
+        self.gesture_in_progress.set(false);
+        self.flush_transform_updates();
+    }
+
+    /// Handles a resize of the viewport. This updates the viewport and the bounds of the root
+    /// layer, and of the layers fixed to the viewport that `resizes_with_viewport`, while keeping
+    /// all tiles that remain valid, so the next call to `get_buffer_requests` only asks for the
+    /// newly exposed area. No content ages are changed, so there is no need to call
+    /// `mark_layer_contents_as_changed_recursively` after a resize.
+    pub fn handle_viewport_resize(&mut self, new_viewport: TypedRect<f32, DevicePixel>) {
+        self.viewport = new_viewport;
+        let root_layer = match self.root {
+            Some(ref root_layer) => root_layer,
+            None => return,
+        };
+
+        let old_size = root_layer.bounds().size;
+        let new_size = new_viewport.size / self.scale;
+        root_layer.set_bounds(TypedRect::new(new_viewport.origin / self.scale, old_size));
+        root_layer.resize_preserving_tiles(new_size, root_layer.effective_scale(self.scale));
+        for kid in root_layer.children().iter() {
+            Scene::resize_viewport_sized_layers(kid,
+                                                new_size.width - old_size.width,
+                                                new_size.height - old_size.height,
+                                                self.scale);
+        }
+    }
+
+    /// Grows or shrinks the layers in the given subtree that are fixed to the viewport and
+    /// `resizes_with_viewport` by the given change in viewport size, keeping their valid tiles.
+    fn resize_viewport_sized_layers(layer: &Rc<Layer<T>>,
+                                    width_change: f32,
+                                    height_change: f32,
+                                    scale: ScaleFactor<f32, LayerPixel, DevicePixel>) {
+        if layer.is_fixed_to_viewport() && layer.resizes_with_viewport.get() {
+            let size = layer.bounds().size;
+            let new_size = TypedSize2D::new((size.width + width_change).max(0.0),
+                                            (size.height + height_change).max(0.0));
+            layer.resize_preserving_tiles(new_size, layer.effective_scale(scale));
+        }
+        for kid in layer.children().iter() {
+            Scene::resize_viewport_sized_layers(kid, width_change, height_change, scale);
         }
     }
 
@@ -116,4 +1081,1611 @@ impl<T> Scene<T> {
             None => 0,
         }
     }
+
+    fn collect_memory_for_layer(layer: &Rc<Layer<T>>,
+                                policy: MemoryReleasePolicy,
+                                viewport: &TypedRect<f32, ScreenPixel>,
+                                scale: ScaleFactor<f32, LayerPixel, DevicePixel>,
+                                buffers: &mut Vec<Box<LayerBuffer>>) {
+        let layer_buffers = match policy {
+            MemoryReleasePolicy::UnusedBuffersOnly => layer.collect_unused_buffers(),
+            MemoryReleasePolicy::OffscreenTiles => {
+                layer.collect_offscreen_buffers(viewport, layer.effective_scale(scale))
+            }
+            MemoryReleasePolicy::Everything => layer.collect_buffers(),
+        };
+        buffers.extend(layer_buffers.into_iter());
+        for kid in layer.children().iter() {
+            Scene::collect_memory_for_layer(kid, policy, viewport, scale, buffers);
+        }
+    }
+
+    fn evict_tiles_outside_viewport_for_layer(layer: &Rc<Layer<T>>,
+                                              viewport: &TypedRect<f32, ScreenPixel>,
+                                              scale: ScaleFactor<f32, LayerPixel, DevicePixel>) {
+        let keep_margin = layer.prefetch_margin.borrow().max(0.0);
+        layer.evict_tiles_outside(viewport, keep_margin, layer.effective_scale(scale));
+        for kid in layer.children().iter() {
+            Scene::evict_tiles_outside_viewport_for_layer(kid, viewport, scale);
+        }
+    }
+
+    /// Throws out the tiles of every layer that lie outside the viewport and the prefetch margin
+    /// of the layer, as request passes do unless `Layer::set_evicts_tiles_on_request` disabled it.
+    /// Their buffers become unused, and are returned by the next `get_buffer_requests`.
+    pub fn evict_all_layers_outside_viewports(&self) {
+        let root_layer = match self.root {
+            Some(ref root_layer) => root_layer,
+            None => return,
+        };
+
+        // Offscreen tiles are found from the transform state.
+        self.flush_transform_updates();
+
+        Scene::evict_tiles_outside_viewport_for_layer(root_layer,
+                                                      &Scene::root_screen_rect(root_layer),
+                                                      self.scale);
+    }
+
+    /// Releases memory held by the layers of the scene, for instance in response to a memory
+    /// pressure signal, and returns the released buffers. They must be destroyed on the painting
+    /// side, with the painting `NativeDisplay`.
+    ///
+    /// With `Everything`, tiles are thrown out along with their textures, which deletes those
+    /// textures, so this must be called on the compositor thread with the GL context current.
+    pub fn collect_memory(&self, policy: MemoryReleasePolicy) -> Vec<Box<LayerBuffer>> {
+        let root_layer = match self.root {
+            Some(ref root_layer) => root_layer,
+            None => return vec!(),
+        };
+
+        // Offscreen tiles are found from the transform state.
+        self.flush_transform_updates();
+
+        let mut buffers = vec!();
+        Scene::collect_memory_for_layer(root_layer,
+                                        policy,
+                                        &Scene::root_screen_rect(root_layer),
+                                        self.scale,
+                                        &mut buffers);
+        debug!("Released {} buffers using {} bytes of memory",
+               buffers.len(),
+               buffers.iter().map(|buffer| buffer.get_mem()).sum::<usize>());
+        buffers
+    }
+
+    fn accumulate_tile_statistics(layer: &Rc<Layer<T>>, statistics: &mut TileGridStatistics) {
+        statistics.accumulate(&layer.tile_statistics());
+        for kid in layer.children().iter() {
+            Scene::accumulate_tile_statistics(kid, statistics);
+        }
+    }
+
+    /// Returns counters describing the tiles of all the layers in the scene graph.
+    pub fn tile_statistics(&self) -> TileGridStatistics {
+        let mut statistics = TileGridStatistics::default();
+        if let Some(ref root_layer) = self.root {
+            Scene::accumulate_tile_statistics(root_layer, &mut statistics);
+        }
+        statistics
+    }
+
+    fn layer_tree_is_fully_painted(layer: &Rc<Layer<T>>) -> bool {
+        layer.is_fully_painted() &&
+        layer.children().iter().all(|kid| Scene::layer_tree_is_fully_painted(kid))
+    }
+
+    /// Returns true if every layer in the scene graph displays its current content, with no
+    /// repaint outstanding. Layers that haven't requested their tiles yet count as painted.
+    pub fn is_fully_painted(&self) -> bool {
+        match self.root {
+            Some(ref root_layer) => Scene::layer_tree_is_fully_painted(root_layer),
+            None => true,
+        }
+    }
+
+    fn accumulate_memory_by_group(layer: &Rc<Layer<T>>,
+                                  memory_by_group: &mut HashMap<u64, MemoryUsage>) {
+        if let Some(group_id) = layer.group_id.get() {
+            let usage = memory_by_group.entry(group_id).or_insert(MemoryUsage::default());
+            usage.buffer_memory += layer.get_memory_usage_without_children();
+            usage.raster_area += layer.get_raster_area_without_children();
+        }
+        for kid in layer.children().iter() {
+            Scene::accumulate_memory_by_group(kid, memory_by_group);
+        }
+    }
+
+    /// Calculate the amount of memory used by the layers of each group in the scene graph,
+    /// sorted by group id. Layers without a group id are not included.
+    pub fn memory_by_group(&self) -> Vec<(u64, MemoryUsage)> {
+        let mut memory_by_group = HashMap::new();
+        if let Some(ref root_layer) = self.root {
+            Scene::accumulate_memory_by_group(root_layer, &mut memory_by_group);
+        }
+        let mut memory_by_group: Vec<(u64, MemoryUsage)> = memory_by_group.into_iter().collect();
+        memory_by_group.sort_by(|a, b| a.0.cmp(&b.0));
+        memory_by_group
+    }
+
+    /// Returns the buffer debt of each group: the number of buffers the last call to
+    /// `get_buffer_requests` requested for its layers, sorted by group id. Groups without
+    /// requests, such as those over their budget, are left out.
+    pub fn requests_by_group(&self) -> &[(u64, usize)] {
+        &self.requests_by_group
+    }
+
+    fn add_group_requests(&mut self, group_id: u64, request_count: usize) {
+        match self.requests_by_group.binary_search_by(|entry| entry.0.cmp(&group_id)) {
+            Ok(index) => self.requests_by_group[index].1 += request_count,
+            Err(index) => self.requests_by_group.insert(index, (group_id, request_count)),
+        }
+    }
+}
+
+/// Takes a snapshot of the layer tree of a scene for debugging, without the extra data of the
+/// layers. See `dump_layer_tree_with_extra_data` to include it.
+pub fn dump_layer_tree<T>(scene: &Scene<T>) -> LayerTreeDump {
+    dump_layer_tree_with(scene, &|_: &T| None)
+}
+
+/// Takes a snapshot of the layer tree of a scene for debugging, including the extra data of each
+/// layer formatted with `Debug`.
+pub fn dump_layer_tree_with_extra_data<T: Debug>(scene: &Scene<T>) -> LayerTreeDump {
+    dump_layer_tree_with(scene, &|extra_data: &T| Some(format!("{:?}", extra_data)))
+}
+
+fn dump_layer_tree_with<T, F>(scene: &Scene<T>, describe_extra_data: &F) -> LayerTreeDump
+                              where F: Fn(&T) -> Option<String> {
+    LayerTreeDump {
+        viewport: scene.viewport,
+        scale: scene.scale.get(),
+        root: scene.root.as_ref().map(|root| LayerDump::new(&**root, describe_extra_data)),
+    }
+}
+
+/// A difference between two layer trees, as found by `diff_layer_trees`. Layers that are in both
+/// trees are identified by the layer of the new tree.
+pub enum LayerDiff<T> {
+    /// The layer is only in the new tree.
+    Added(Rc<Layer<T>>),
+    /// The layer, of the old tree, is not in the new tree.
+    Removed(Rc<Layer<T>>),
+    /// The bounds of the layer changed.
+    BoundsChanged {
+        layer: Rc<Layer<T>>,
+        old: TypedRect<f32, LayerPixel>,
+        new: TypedRect<f32, LayerPixel>,
+    },
+    /// The transform, perspective, or transform origin of the layer changed.
+    TransformChanged(Rc<Layer<T>>),
+    /// The opacity of the layer changed.
+    OpacityChanged(Rc<Layer<T>>),
+    /// Whether the layer masks to its bounds, or the radii of its corners, changed.
+    MaskChanged(Rc<Layer<T>>),
+    /// The content offset of the layer changed.
+    ContentOffsetChanged(Rc<Layer<T>>),
+    /// The layer moved to another parent. Its descendants that moved along with it are not
+    /// reported.
+    Reparented(Rc<Layer<T>>),
+}
+
+/// A layer of a tree being diffed, with the index of its parent in the flattened tree.
+struct FlattenedLayer<T> {
+    layer: Rc<Layer<T>>,
+    parent: Option<usize>,
+}
+
+fn flatten_layer_tree<T>(layer: &Rc<Layer<T>>,
+                         parent: Option<usize>,
+                         layers: &mut Vec<FlattenedLayer<T>>) {
+    let index = layers.len();
+    layers.push(FlattenedLayer {
+        layer: layer.clone(),
+        parent: parent,
+    });
+    for kid in layer.children().iter() {
+        flatten_layer_tree(kid, Some(index), layers);
+    }
+}
+
+/// Works out how the layer tree rooted at `new` differs from the one rooted at `old`, so that
+/// callers that rebuild their layer trees can tell which layers actually changed.
+///
+/// Layers are matched by their extra data, regardless of the order of siblings. If the extra data
+/// of several layers is equal, the layers that share it are matched in tree order instead, with a
+/// warning. This compares every pair of layers, which is fine for the trees of a page.
+pub fn diff_layer_trees<T: PartialEq>(old: &Rc<Layer<T>>, new: &Rc<Layer<T>>)
+                                      -> Vec<LayerDiff<T>> {
+    let mut old_layers = Vec::new();
+    flatten_layer_tree(old, None, &mut old_layers);
+    let mut new_layers = Vec::new();
+    flatten_layer_tree(new, None, &mut new_layers);
+
+    let mut old_to_new = vec![None; old_layers.len()];
+    let mut new_to_old = vec![None; new_layers.len()];
+    let mut has_duplicates = false;
+    for (new_index, new_layer) in new_layers.iter().enumerate() {
+        let extra_data = new_layer.layer.extra_data.borrow();
+        let occurrence = new_layers[..new_index].iter().filter(|other| {
+            *other.layer.extra_data.borrow() == *extra_data
+        }).count();
+        let candidates: Vec<usize> = old_layers.iter().enumerate().filter(|&(_, old_layer)| {
+            *old_layer.layer.extra_data.borrow() == *extra_data
+        }).map(|(old_index, _)| old_index).collect();
+        if occurrence > 0 || candidates.len() > 1 {
+            has_duplicates = true;
+        }
+        if let Some(&old_index) = candidates.get(occurrence) {
+            old_to_new[old_index] = Some(new_index);
+            new_to_old[new_index] = Some(old_index);
+        }
+    }
+    if has_duplicates {
+        warn!("Layers with equal extra data were matched in tree order when diffing layer trees.");
+    }
+
+    let mut diffs = Vec::new();
+    for (new_index, new_layer) in new_layers.iter().enumerate() {
+        let old_layer = match new_to_old[new_index] {
+            Some(old_index) => &old_layers[old_index],
+            None => {
+                diffs.push(LayerDiff::Added(new_layer.layer.clone()));
+                continue;
+            }
+        };
+
+        let reparented = match (new_layer.parent, old_layer.parent) {
+            (Some(new_parent), Some(old_parent)) => new_to_old[new_parent] != Some(old_parent),
+            (None, None) => false,
+            _ => true,
+        };
+        if reparented {
+            diffs.push(LayerDiff::Reparented(new_layer.layer.clone()));
+        }
+
+        let (old_layer, layer) = (&old_layer.layer, &new_layer.layer);
+        let (old_bounds, new_bounds) = (old_layer.bounds(), layer.bounds());
+        if old_bounds != new_bounds {
+            diffs.push(LayerDiff::BoundsChanged {
+                layer: layer.clone(),
+                old: old_bounds,
+                new: new_bounds,
+            });
+        }
+        if old_layer.transform() != layer.transform() ||
+           old_layer.perspective() != layer.perspective() ||
+           old_layer.transform_origin() != layer.transform_origin() {
+            diffs.push(LayerDiff::TransformChanged(layer.clone()));
+        }
+        if old_layer.opacity() != layer.opacity() {
+            diffs.push(LayerDiff::OpacityChanged(layer.clone()));
+        }
+        if old_layer.masks_to_bounds() != layer.masks_to_bounds() ||
+           *old_layer.corner_radii.borrow() != *layer.corner_radii.borrow() {
+            diffs.push(LayerDiff::MaskChanged(layer.clone()));
+        }
+        if old_layer.content_offset() != layer.content_offset() {
+            diffs.push(LayerDiff::ContentOffsetChanged(layer.clone()));
+        }
+    }
+
+    for (old_index, old_layer) in old_layers.iter().enumerate() {
+        if old_to_new[old_index].is_none() {
+            diffs.push(LayerDiff::Removed(old_layer.layer.clone()));
+        }
+    }
+    diffs
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::{BufferTransaction, LayerDiff, MemoryReleasePolicy, Scene, diff_layer_trees};
+    use super::{dump_layer_tree, dump_layer_tree_with_extra_data};
+    use color::Color;
+    use completion::Cancelled;
+    use euclid::Matrix4D;
+    use euclid::point::{Point2D, TypedPoint2D};
+    use euclid::rect::TypedRect;
+    use euclid::scale_factor::ScaleFactor;
+    use euclid::side_offsets::SideOffsets2D;
+    use euclid::size::{Size2D, TypedSize2D};
+    use frame::FrameIndex;
+    use geometry::{DevicePixel, LayerPixel, ScreenPixel};
+    use layers::{BufferRequest, ContentMode, Layer, LayerBuffer, RequestOrdering};
+    use rustc_serialize::json::Json;
+    use std::rc::Rc;
+    use test_util::{block_on, buffer, buffer_for_request, count_allocations, layer};
+    use tiling::TileGridStatistics;
+    use visibility::VisibilityBlocker;
+
+    fn scene_with_root(root: Rc<Layer<()>>) -> Scene<()> {
+        let size = root.bounds().size;
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(size.width, size.height)));
+        scene.root = Some(root);
+        scene
+    }
+
+    fn request_pass(scene: &mut Scene<()>)
+                    -> (Vec<(Rc<Layer<()>>, Vec<BufferRequest>)>, Vec<Box<LayerBuffer>>) {
+        let mut requests = vec!();
+        let mut unused_buffers = vec!();
+        scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+        (requests, unused_buffers)
+    }
+
+    fn fulfill(scene: &Scene<()>, requests: &[(Rc<Layer<()>>, Vec<BufferRequest>)]) {
+        for &(ref layer, ref layer_requests) in requests {
+            for request in layer_requests {
+                assert!(scene.add_buffer(layer, buffer_for_request(request), FrameIndex(0)));
+            }
+        }
+    }
+
+    #[test]
+    fn requests_come_closest_to_the_viewport_center_first_by_default() {
+        let mut scene = scene_with_root(layer(0.0, 0.0, 768.0, 256.0, 256));
+        assert_eq!(scene.request_ordering, RequestOrdering::ViewportDistance);
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!(requests.len(), 1);
+        let origins: Vec<f32> = requests[0].1.iter().map(|request| {
+            assert_eq!(request.ordering, RequestOrdering::ViewportDistance);
+            request.page_rect.origin.x
+        }).collect();
+        assert_eq!(origins, vec!(256.0, 0.0, 512.0));
+    }
+
+    /// Makes a request pass over `layer` for a 1x1 viewport in the middle of its tile (2, 2),
+    /// and returns the indices of the requested 256-pixel tiles in layer space, along with the
+    /// number of unused buffers.
+    fn requests_around_a_pixel(scene: &mut Scene<()>, layer: &Rc<Layer<()>>)
+                               -> (Vec<(usize, usize)>, usize) {
+        let viewport: TypedRect<f32, ScreenPixel> =
+            TypedRect::new(TypedPoint2D::new(640.0, 640.0), TypedSize2D::new(1.0, 1.0));
+        let mut requests = vec!();
+        let mut unused_buffers = vec!();
+        scene.get_buffer_requests_for_layer(layer.clone(),
+                                            viewport,
+                                            viewport,
+                                            &mut requests,
+                                            &mut unused_buffers);
+        fulfill(scene, &requests);
+        let mut tiles: Vec<_> = requests.iter().flat_map(|&(_, ref layer_requests)| {
+            layer_requests.iter().map(|request| {
+                ((request.page_rect.origin.x / 256.0) as usize,
+                 (request.page_rect.origin.y / 256.0) as usize)
+            })
+        }).collect();
+        tiles.sort();
+        (tiles, unused_buffers.len())
+    }
+
+    #[test]
+    fn the_prefetch_margin_requests_and_keeps_the_ring_of_neighboring_tiles() {
+        let root = layer(0.0, 0.0, 1280.0, 1280.0, 256);
+        *root.prefetch_margin.borrow_mut() = 256.0;
+        let mut scene = scene_with_root(root.clone());
+        scene.flush_transform_updates();
+        let ring = vec!((1, 1), (1, 2), (1, 3), (2, 1), (2, 2), (2, 3), (3, 1), (3, 2), (3, 3));
+        assert_eq!(requests_around_a_pixel(&mut scene, &root), (ring, 0));
+
+        // The prefetched tiles are kept by the next pass, which has nothing left to request.
+        assert_eq!(requests_around_a_pixel(&mut scene, &root), (vec!(), 0));
+        assert_eq!(root.tile_statistics().tile_count, 9);
+
+        // Without the margin, only the tile under the viewport is kept.
+        *root.prefetch_margin.borrow_mut() = 0.0;
+        assert_eq!(requests_around_a_pixel(&mut scene, &root), (vec!(), 8));
+    }
+
+    #[test]
+    fn the_prefetch_margin_is_in_layer_pixels_at_any_scale() {
+        // At twice the scale, 256-pixel tiles cover 128 layer pixels, so the same margin spans
+        // two tiles on each side of the tile under the viewport.
+        let root = layer(0.0, 0.0, 1280.0, 1280.0, 256);
+        *root.prefetch_margin.borrow_mut() = 256.0;
+        let mut scene = scene_with_root(root.clone());
+        scene.scale = ScaleFactor::new(2.0);
+        scene.flush_transform_updates();
+        let (tiles, _) = requests_around_a_pixel(&mut scene, &root);
+        assert_eq!(tiles.len(), 5 * 5);
+        assert_eq!(requests_around_a_pixel(&mut scene, &root), (vec!(), 0));
+    }
+
+    #[test]
+    fn layers_with_a_scale_override_are_requested_and_accepted_at_that_scale() {
+        // A 2x page holding an iframe painted at 1x, which holds a layer without an override.
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let iframe = layer(0.0, 0.0, 256.0, 256.0, 256);
+        *iframe.scale_override.borrow_mut() = Some(ScaleFactor::new(1.0));
+        let inner = layer(0.0, 0.0, 128.0, 128.0, 256);
+        iframe.add_child(inner.clone());
+        root.add_child(iframe.clone());
+        let mut scene = Scene::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                  TypedSize2D::new(512.0, 512.0)));
+        scene.root = Some(root.clone());
+        scene.scale = ScaleFactor::new(2.0);
+        let (requests, _) = request_pass(&mut scene);
+        let requests_of = |layer: &Rc<Layer<()>>| {
+            requests.iter().filter(|&&(ref requested, _)| {
+                &**requested as *const Layer<()> == &**layer as *const Layer<()>
+            }).flat_map(|&(_, ref layer_requests)| layer_requests.iter()).collect::<Vec<_>>()
+        };
+
+        // The 256 layer pixels of the iframe fit a single 256-pixel tile at 1x, while those of
+        // the page take four at 2x. Children of the iframe are requested at the scene scale.
+        for &(ref layer, tile_count, resolution) in &[(&root, 4, 2.0),
+                                                      (&iframe, 1, 1.0),
+                                                      (&inner, 1, 2.0)] {
+            let layer_requests = requests_of(layer);
+            assert_eq!(layer_requests.len(), tile_count);
+            for request in &layer_requests {
+                assert_eq!(request.resolution, resolution);
+                assert_eq!(request.screen_rect.size, Size2D::new(256, 256));
+            }
+        }
+
+        // Buffers painted at the scene scale are refused by the iframe.
+        let page_buffer = buffer_for_request(requests_of(&root)[0]);
+        assert!(!scene.add_buffer(&iframe, page_buffer, FrameIndex(0)));
+        fulfill(&scene, &requests);
+        let (requests, _) = request_pass(&mut scene);
+        assert!(requests.iter().all(|&(_, ref layer_requests)| layer_requests.is_empty()));
+    }
+
+    /// Returns a 256x256 scene showing the top of a column of 128-pixel tiles, painted, with
+    /// the given eviction behavior.
+    fn scene_with_scrolled_column(evicts_tiles_on_request: bool) -> (Scene<()>, Rc<Layer<()>>) {
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let content = layer(0.0, 0.0, 256.0, 1024.0, 128);
+        content.set_evicts_tiles_on_request(evicts_tiles_on_request);
+        root.add_child(content.clone());
+        let mut scene = scene_with_root(root);
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!(requests_for(&requests, &content), 4);
+        fulfill(&scene, &requests);
+        (scene, content)
+    }
+
+    /// Makes and fulfills a request pass with the content scrolled down by `offset`, and returns
+    /// the number of tiles requested for it and the number of unused buffers.
+    fn scroll_pass(scene: &mut Scene<()>, content: &Rc<Layer<()>>, offset: f32)
+                   -> (usize, usize) {
+        content.set_transform(Matrix4D::identity().pre_translated(0.0, offset, 0.0));
+        let (requests, unused_buffers) = request_pass(scene);
+        fulfill(scene, &requests);
+        (requests_for(&requests, content), unused_buffers.len())
+    }
+
+    #[test]
+    fn overscrolling_evicts_nothing_when_eviction_is_explicit() {
+        // By default, pulling the content 200 pixels past its top edge throws out its second row
+        // of tiles, which is requested again once the content settles.
+        let (mut scene, content) = scene_with_scrolled_column(true);
+        assert_eq!(scroll_pass(&mut scene, &content, 200.0), (0, 2));
+        assert_eq!(scroll_pass(&mut scene, &content, 0.0), (2, 0));
+
+        // With eviction left to the embedder, the tiles survive the overscroll.
+        let (mut scene, content) = scene_with_scrolled_column(false);
+        assert_eq!(scroll_pass(&mut scene, &content, 200.0), (0, 0));
+        assert_eq!(content.tile_statistics().tile_count, 4);
+        assert_eq!(scroll_pass(&mut scene, &content, 0.0), (0, 0));
+        assert_eq!(content.tile_statistics().tile_count, 4);
+
+        // Scrolled to the middle, the new tiles are added to the old ones until the embedder
+        // evicts them.
+        assert_eq!(scroll_pass(&mut scene, &content, -512.0), (4, 0));
+        assert_eq!(content.tile_statistics().tile_count, 8);
+        scene.evict_all_layers_outside_viewports();
+        assert_eq!(content.tile_statistics().tile_count, 4);
+        assert_eq!(scroll_pass(&mut scene, &content, -512.0), (0, 4));
+    }
+
+    #[test]
+    fn tiles_of_fixed_layers_are_kept_when_the_page_scrolls_far() {
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let page = layer(0.0, 0.0, 256.0, 4096.0, 256);
+        let header = layer(0.0, 0.0, 256.0, 64.0, 256);
+        header.set_fixed_to_viewport(Some(TypedPoint2D::new(0.0, 0.0)));
+        page.add_child(header.clone());
+        root.add_child(page.clone());
+        let mut scene = scene_with_root(root);
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!((requests_for(&requests, &page), requests_for(&requests, &header)), (1, 1));
+        fulfill(&scene, &requests);
+
+        // Scrolled 3000 pixels down, the page needs two new tiles, while the header keeps its
+        // tile.
+        page.set_bounds(TypedRect::new(TypedPoint2D::new(0.0, -3000.0),
+                                       TypedSize2D::new(256.0, 4096.0)));
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!((requests_for(&requests, &page), requests_for(&requests, &header)), (2, 0));
+        assert_eq!(header.tile_statistics().tile_count, 1);
+    }
+
+    #[test]
+    fn explicit_eviction_keeps_a_margin_of_tiles() {
+        let (scene, content) = scene_with_scrolled_column(false);
+        let viewport = TypedRect::new(TypedPoint2D::new(0.0, 0.0), TypedSize2D::new(256.0, 100.0));
+        scene.flush_transform_updates();
+
+        // A margin of half a tile reaches into the second row of tiles, which is kept.
+        content.evict_tiles_outside(&viewport, 64.0, ScaleFactor::new(1.0));
+        assert_eq!(content.tile_statistics().tile_count, 4);
+        content.evict_tiles_outside(&viewport, 0.0, ScaleFactor::new(1.0));
+        assert_eq!(content.tile_statistics().tile_count, 2);
+    }
+
+    #[test]
+    fn the_request_pass_of_a_painted_scene_does_not_allocate() {
+        let root = layer(0.0, 0.0, 512.0, 512.0, 256);
+        let child = layer(16.0, 16.0, 300.0, 300.0, 256);
+        *child.contents_opaque.borrow_mut() = true;
+        // Without group budgets, the memory usage of the groups isn't looked up.
+        child.set_group_id(Some(1));
+        root.add_child(child.clone());
+        root.add_child(layer(100.0, 100.0, 64.0, 64.0, 256));
+        let mut scene = scene_with_root(root);
+        let (requests, _) = request_pass(&mut scene);
+        assert!(!requests.is_empty());
+        fulfill(&scene, &requests);
+
+        // The first pass over the painted scene grows the vectors of the opaque region and of
+        // the occluders of each tile grid, which later passes reuse.
+        let mut requests = Vec::with_capacity(4);
+        let mut unused_buffers = Vec::with_capacity(4);
+        scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+        for _ in 0..3 {
+            let allocations = count_allocations(|| {
+                scene.get_buffer_requests(&mut requests, &mut unused_buffers);
+            });
+            assert!(requests.is_empty() && unused_buffers.is_empty());
+            assert_eq!(allocations, 0);
+        }
+    }
+
+    #[test]
+    fn buffer_transactions_appear_together_when_committed() {
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let child = layer(0.0, 0.0, 256.0, 256.0, 256);
+        root.add_child(child.clone());
+        let mut scene = scene_with_root(root.clone());
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!(requests.len(), 2);
+
+        let mut transaction = BufferTransaction::new();
+        for &(ref layer, ref layer_requests) in &requests {
+            transaction.add_buffer(layer, buffer_for_request(&layer_requests[0]));
+        }
+        let token = scene.queue_buffer_transaction(transaction);
+        assert!(!token.is_complete());
+        assert!(!scene.is_fully_painted());
+
+        assert_eq!(scene.commit_buffer_transactions(FrameIndex(1)), 1);
+        assert!(scene.is_fully_painted());
+        assert_eq!(block_on(token.future()), Ok(()));
+        assert_eq!(scene.commit_buffer_transactions(FrameIndex(2)), 0);
+    }
+
+    #[test]
+    fn dropping_an_uncommitted_transaction_cancels_its_token() {
+        let mut transaction = BufferTransaction::<()>::new();
+        let token = transaction.token();
+        transaction.add_buffer(&layer(0.0, 0.0, 16.0, 16.0, 256), buffer(16, 16));
+        drop(transaction);
+        assert_eq!(block_on(token.future()), Err(Cancelled));
+    }
+
+    #[test]
+    fn scale_animations_complete_their_token_when_they_end() {
+        let mut scene = scene_with_root(layer(0.0, 0.0, 256.0, 256.0, 256));
+        let token = scene.animate_scale_to(ScaleFactor::new(3.0), 10.0, 0.5);
+        assert_eq!(scene.scale.get(), 1.0);
+
+        assert!(scene.tick_animations(10.25));
+        assert_eq!(scene.scale.get(), 2.0);
+        assert!(!token.is_complete());
+
+        assert!(!scene.tick_animations(11.0));
+        assert_eq!(scene.scale.get(), 3.0);
+        assert_eq!(block_on(token.future()), Ok(()));
+        assert!(!scene.tick_animations(12.0));
+    }
+
+    #[test]
+    fn a_new_scale_animation_cancels_the_running_one() {
+        let mut scene = scene_with_root(layer(0.0, 0.0, 256.0, 256.0, 256));
+        let first = scene.animate_scale_to(ScaleFactor::new(2.0), 0.0, 1.0);
+        scene.tick_animations(0.5);
+        let second = scene.animate_scale_to(ScaleFactor::new(1.0), 0.5, 0.0);
+        assert_eq!(block_on(first.future()), Err(Cancelled));
+        assert_eq!(block_on(second.future()), Ok(()));
+        assert_eq!(scene.scale.get(), 1.0);
+    }
+
+    #[test]
+    fn tile_statistics_follow_the_requests_and_buffers_of_the_tree() {
+        let root = layer(0.0, 0.0, 512.0, 512.0, 256);
+        let child = layer(0.0, 0.0, 100.0, 100.0, 256);
+        root.add_child(child.clone());
+        let mut scene = scene_with_root(root);
+        assert_eq!(scene.tile_statistics(), TileGridStatistics::default());
+
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!(scene.tile_statistics(),
+                   TileGridStatistics {
+                       tile_count: 5,
+                       tiles_with_pending_requests: 5,
+                       ..TileGridStatistics::default()
+                   });
+
+        fulfill(&scene, &requests);
+        let painted_memory = 4 * buffer(256, 256).get_mem() + buffer(100, 100).get_mem();
+        assert_eq!(scene.tile_statistics(),
+                   TileGridStatistics {
+                       tile_count: 5,
+                       tiles_with_buffers: 5,
+                       buffer_memory: painted_memory,
+                       ..TileGridStatistics::default()
+                   });
+
+        // The replaced buffer of the child waits to be collected, and still counts.
+        child.contents_changed();
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!(scene.tile_statistics().tiles_with_pending_requests, 1);
+        fulfill(&scene, &requests);
+        let statistics = scene.tile_statistics();
+        assert_eq!((statistics.unused_buffer_count, statistics.tiles_with_pending_requests),
+                   (1, 0));
+        assert_eq!(statistics.buffer_memory, painted_memory + buffer(100, 100).get_mem());
+        assert_eq!(child.tile_statistics().unused_buffer_count, 1);
+
+        let (_, unused_buffers) = request_pass(&mut scene);
+        assert_eq!(unused_buffers.len(), 1);
+        assert_eq!(scene.tile_statistics().buffer_memory, painted_memory);
+    }
+
+    /// Returns a 256x256 scene whose root holds a painted tile and an unused buffer, and a child
+    /// whose only painted tile has been scrolled out of view, along with the child.
+    fn scene_with_memory_to_release() -> (Scene<()>, Rc<Layer<()>>) {
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let child = layer(0.0, 0.0, 512.0, 256.0, 256);
+        root.add_child(child.clone());
+        let mut scene = scene_with_root(root.clone());
+        let (requests, _) = request_pass(&mut scene);
+        fulfill(&scene, &requests);
+        root.contents_changed();
+        let (requests, _) = request_pass(&mut scene);
+        fulfill(&scene, &requests);
+
+        child.set_transform(Matrix4D::identity().pre_translated(-256.0, 0.0, 0.0));
+        let statistics = scene.tile_statistics();
+        assert_eq!((statistics.tiles_with_buffers, statistics.unused_buffer_count), (2, 1));
+        (scene, child)
+    }
+
+    fn released_memory(buffers: &[Box<LayerBuffer>]) -> usize {
+        buffers.iter().map(|buffer| buffer.get_mem()).sum()
+    }
+
+    #[test]
+    fn releasing_unused_buffers_keeps_every_tile() {
+        let (mut scene, _) = scene_with_memory_to_release();
+        let buffers = scene.collect_memory(MemoryReleasePolicy::UnusedBuffersOnly);
+        assert_eq!(buffers.len(), 1);
+        assert_eq!(released_memory(&buffers), buffer(256, 256).get_mem());
+
+        let statistics = scene.tile_statistics();
+        assert_eq!((statistics.tiles_with_buffers, statistics.unused_buffer_count), (2, 0));
+        assert!(scene.collect_memory(MemoryReleasePolicy::UnusedBuffersOnly).is_empty());
+
+        // The offscreen tile is kept too, so nothing is requested but the tile scrolled in.
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!(requests.iter().map(|&(_, ref requests)| requests.len()).sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn releasing_offscreen_tiles_keeps_the_visible_ones() {
+        let (scene, child) = scene_with_memory_to_release();
+        let buffers = scene.collect_memory(MemoryReleasePolicy::OffscreenTiles);
+        assert_eq!(buffers.len(), 2);
+        assert_eq!(released_memory(&buffers), 2 * buffer(256, 256).get_mem());
+
+        let statistics = scene.tile_statistics();
+        assert_eq!((statistics.tiles_with_buffers, statistics.unused_buffer_count), (1, 0));
+        assert_eq!(child.tile_statistics().tiles_with_buffers, 0);
+    }
+
+    #[test]
+    fn releasing_everything_requests_the_scene_again() {
+        let (mut scene, _) = scene_with_memory_to_release();
+        let buffers = scene.collect_memory(MemoryReleasePolicy::Everything);
+        assert_eq!(buffers.len(), 3);
+        assert_eq!(released_memory(&buffers), 3 * buffer(256, 256).get_mem());
+        assert_eq!(scene.tile_statistics().tiles_with_buffers, 0);
+        assert_eq!(scene.get_memory_usage(), 0);
+
+        // Both visible tiles are painted again.
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!(requests.iter().map(|&(_, ref requests)| requests.len()).sum::<usize>(), 2);
+    }
+
+    #[test]
+    fn group_over_budget_drops_requests_but_still_evicts() {
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let child = layer(0.0, 0.0, 512.0, 256.0, 256);
+        child.set_group_id(Some(1));
+        root.add_child(child.clone());
+        let mut scene = scene_with_root(root);
+
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!(scene.requests_by_group(), &[(1, 1)]);
+        fulfill(&scene, &requests);
+        let usage = scene.memory_by_group()[0].1;
+        assert_eq!(usage.raster_area, 256 * 256);
+
+        // Over budget: the tile scrolled into view is not requested, but the one scrolled out is
+        // thrown out.
+        scene.group_raster_area_budgets.insert(1, 256 * 256 - 1);
+        child.set_transform(Matrix4D::identity().pre_translated(-300.0, 0.0, 0.0));
+        let (_, unused_buffers) = request_pass(&mut scene);
+        assert!(scene.requests_by_group().is_empty());
+        assert_eq!(unused_buffers.len(), 1);
+        assert_eq!(scene.memory_by_group()[0].1.raster_area, 0);
+        assert_eq!(child.tile_statistics().tiles_with_pending_requests, 0);
+
+        // Back under budget, the visible tile is requested.
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!(scene.requests_by_group(), &[(1, 1)]);
+        assert_eq!(requests[0].1[0].screen_rect.origin.x, 256);
+    }
+
+    #[test]
+    fn memory_budget_applies_to_its_group_only() {
+        let root = layer(0.0, 0.0, 256.0, 256.0, 128);
+        let first = layer(0.0, 0.0, 256.0, 256.0, 128);
+        let second = layer(0.0, 0.0, 256.0, 256.0, 128);
+        first.set_group_id(Some(1));
+        second.set_group_id(Some(2));
+        root.add_child(first.clone());
+        root.add_child(second.clone());
+        let mut scene = scene_with_root(root);
+
+        let (requests, _) = request_pass(&mut scene);
+        fulfill(&scene, &requests);
+        let memory_by_group = scene.memory_by_group();
+        assert_eq!(memory_by_group.len(), 2);
+        assert_eq!(memory_by_group[0].1, memory_by_group[1].1);
+        assert_eq!(memory_by_group[0].1.buffer_memory + memory_by_group[1].1.buffer_memory,
+                   first.get_memory_usage() + second.get_memory_usage());
+
+        scene.group_memory_budgets.insert(1, 0);
+        first.contents_changed();
+        second.contents_changed();
+        request_pass(&mut scene);
+        assert_eq!(scene.requests_by_group(), &[(2, 4)]);
+    }
+
+    /// Sets up a root with an opaque overlay, painted, in front of which nothing else is drawn,
+    /// and a page below it. Returns the scene and the page.
+    fn scene_with_painted_overlay(root: Rc<Layer<()>>, overlay_width: f32)
+                                  -> (Scene<()>, Rc<Layer<()>>) {
+        let overlay = layer(0.0, 0.0, overlay_width, 256.0, 256);
+        *overlay.contents_opaque.borrow_mut() = true;
+        root.add_child(overlay.clone());
+        let mut scene = scene_with_root(root.clone());
+        let (requests, _) = request_pass(&mut scene);
+        fulfill(&scene, &requests);
+        assert!(overlay.is_fully_painted());
+
+        let page = layer(0.0, 0.0, 256.0, 256.0, 256);
+        root.insert_child_at_index(page.clone(), 0);
+        (scene, page)
+    }
+
+    fn requests_for(requests: &[(Rc<Layer<()>>, Vec<BufferRequest>)], layer: &Rc<Layer<()>>)
+                    -> usize {
+        requests.iter().filter(|&&(ref requested, _)| {
+            &**requested as *const Layer<()> == &**layer as *const Layer<()>
+        }).map(|&(_, ref layer_requests)| layer_requests.len()).sum()
+    }
+
+    #[test]
+    fn tiles_behind_an_opaque_overlay_are_not_requested() {
+        let (mut scene, page) = scene_with_painted_overlay(layer(0.0, 0.0, 256.0, 256.0, 256),
+                                                           256.0);
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!(requests_for(&requests, &page), 0);
+    }
+
+    #[test]
+    fn partially_hidden_tiles_carry_their_visible_rect() {
+        let (mut scene, page) = scene_with_painted_overlay(layer(0.0, 0.0, 256.0, 256.0, 256),
+                                                           128.0);
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!(requests_for(&requests, &page), 1);
+        let request = &requests.iter().find(|&&(ref requested, _)| {
+            &**requested as *const Layer<()> == &*page as *const Layer<()>
+        }).unwrap().1[0];
+        let visible_rect = request.visible_rect.unwrap();
+        assert_eq!((visible_rect.origin.x, visible_rect.size.width), (128, 128));
+        assert_eq!((visible_rect.origin.y, visible_rect.size.height), (0, 256));
+    }
+
+    #[test]
+    fn occlusion_is_ignored_in_depth_sorted_trees() {
+        let transparent = Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 };
+        let root = Rc::new(Layer::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                     TypedSize2D::new(256.0, 256.0)),
+                                      256,
+                                      transparent,
+                                      1.0,
+                                      true,
+                                      ()));
+        let (mut scene, page) = scene_with_painted_overlay(root, 256.0);
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!(requests_for(&requests, &page), 1);
+    }
+
+    #[test]
+    fn occlusion_is_on_by_default_and_can_be_turned_off() {
+        let (mut scene, page) = scene_with_painted_overlay(layer(0.0, 0.0, 256.0, 256.0, 256),
+                                                           256.0);
+        assert!(scene.occlusion_aware_requests);
+        scene.occlusion_aware_requests = false;
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!(requests_for(&requests, &page), 1);
+    }
+
+    #[test]
+    fn cleared_group_id_follows_the_parent_again() {
+        let parent = layer(0.0, 0.0, 100.0, 100.0, 256);
+        let child = layer(0.0, 0.0, 100.0, 100.0, 256);
+        parent.set_group_id(Some(1));
+        child.set_group_id(Some(2));
+        parent.add_child(child.clone());
+        assert_eq!(child.group_id.get(), Some(2));
+
+        parent.set_group_id(Some(3));
+        assert_eq!(child.group_id.get(), Some(2));
+        child.set_group_id(None);
+        assert_eq!(child.group_id.get(), Some(3));
+        parent.set_group_id(Some(4));
+        assert_eq!(child.group_id.get(), Some(4));
+
+        assert!(parent.remove_child(&child));
+        child.set_group_id(None);
+        assert_eq!(child.group_id.get(), None);
+    }
+
+    fn viewport(width: f32, height: f32) -> TypedRect<f32, DevicePixel> {
+        TypedRect::new(TypedPoint2D::new(0.0, 0.0), TypedSize2D::new(width, height))
+    }
+
+    fn tile_clip_rects(layer: &Layer<()>) -> Vec<TypedRect<f32, LayerPixel>> {
+        let mut clip_rects = vec!();
+        layer.do_for_all_tiles(|tile| clip_rects.extend(tile.clip_rect));
+        clip_rects
+    }
+
+    #[test]
+    fn shrinking_the_viewport_keeps_and_clips_edge_tiles() {
+        let root = layer(0.0, 0.0, 400.0, 256.0, 256);
+        let mut scene = scene_with_root(root.clone());
+        let (requests, _) = request_pass(&mut scene);
+        fulfill(&scene, &requests);
+
+        scene.handle_viewport_resize(viewport(300.0, 256.0));
+        let (requests, unused_buffers) = request_pass(&mut scene);
+        assert!(requests.iter().all(|&(_, ref requests)| requests.is_empty()));
+        assert!(unused_buffers.is_empty());
+        assert_eq!(root.tile_statistics().tiles_with_buffers, 2);
+        assert_eq!(tile_clip_rects(&root),
+                   vec!(TypedRect::new(TypedPoint2D::new(256.0, 0.0),
+                                       TypedSize2D::new(44.0, 256.0))));
+
+        // Growing back within the buffer lifts the clip.
+        scene.handle_viewport_resize(viewport(400.0, 256.0));
+        assert!(tile_clip_rects(&root).is_empty());
+        assert_eq!(root.tile_statistics().tiles_with_buffers, 2);
+    }
+
+    #[test]
+    fn growing_the_viewport_requests_only_the_exposed_area() {
+        let root = layer(0.0, 0.0, 400.0, 256.0, 256);
+        let mut scene = scene_with_root(root.clone());
+        let (requests, _) = request_pass(&mut scene);
+        fulfill(&scene, &requests);
+        let content_age = *root.content_age.borrow();
+
+        scene.handle_viewport_resize(viewport(600.0, 256.0));
+        assert_eq!(*root.content_age.borrow(), content_age);
+        let (requests, unused_buffers) = request_pass(&mut scene);
+        let mut requested_rects: Vec<_> = requests[0].1.iter().map(|request| {
+            (request.screen_rect.origin.x, request.screen_rect.size.width)
+        }).collect();
+        requested_rects.sort();
+        assert_eq!(requested_rects, vec!((256, 256), (512, 88)));
+        assert_eq!(unused_buffers.len(), 1);
+        assert_eq!(root.tile_statistics().tiles_with_buffers, 1);
+    }
+
+    #[test]
+    fn buffers_requested_before_a_resize_are_requested_again() {
+        let root = layer(0.0, 0.0, 400.0, 256.0, 256);
+        let mut scene = scene_with_root(root.clone());
+        let (old_requests, _) = request_pass(&mut scene);
+
+        scene.handle_viewport_resize(viewport(300.0, 256.0));
+        fulfill(&scene, &old_requests);
+        assert_eq!(root.tile_statistics().tiles_with_buffers, 1);
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!(requests[0].1.len(), 1);
+        assert_eq!(requests[0].1[0].screen_rect.size.width, 44);
+    }
+
+    #[test]
+    fn layers_sized_to_the_viewport_follow_it() {
+        let root = layer(0.0, 0.0, 400.0, 300.0, 256);
+        let fixed = layer(0.0, 0.0, 400.0, 100.0, 256);
+        let other_fixed = layer(0.0, 0.0, 400.0, 100.0, 256);
+        fixed.set_fixed_to_viewport(Some(TypedPoint2D::new(0.0, 200.0)));
+        fixed.resizes_with_viewport.set(true);
+        other_fixed.set_fixed_to_viewport(Some(TypedPoint2D::new(0.0, 0.0)));
+        root.add_child(fixed.clone());
+        root.add_child(other_fixed.clone());
+        let mut scene = scene_with_root(root);
+
+        scene.handle_viewport_resize(viewport(300.0, 350.0));
+        assert_eq!(fixed.bounds().size, TypedSize2D::new(300.0, 150.0));
+        assert_eq!(other_fixed.bounds().size, TypedSize2D::new(400.0, 100.0));
+    }
+
+    #[test]
+    fn resizing_a_nine_patch_requests_no_buffers() {
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let patch = layer(10.0, 10.0, 40.0, 40.0, 256);
+        patch.set_content_mode(ContentMode::NinePatch {
+            buffer: buffer(20, 20),
+            insets: SideOffsets2D::new(5.0, 5.0, 5.0, 5.0),
+        });
+        root.add_child(patch.clone());
+        let mut scene = scene_with_root(root.clone());
+        let (requests, _) = request_pass(&mut scene);
+        fulfill(&scene, &requests);
+        assert_eq!(requests_for(&requests, &patch), 0);
+
+        patch.set_bounds(TypedRect::new(TypedPoint2D::new(10.0, 10.0),
+                                        TypedSize2D::new(200.0, 120.0)));
+        let (requests, unused_buffers) = request_pass(&mut scene);
+        assert!(requests.is_empty());
+        assert!(unused_buffers.is_empty());
+        assert!(patch.is_nine_patch());
+    }
+
+    /// Returns an up-to-date scene, its root, a masking layer at the given x position in the
+    /// root, and a child of the masking layer.
+    fn scene_with_masked_child(mask_x: f32)
+                               -> (Scene<()>, Rc<Layer<()>>, Rc<Layer<()>>, Rc<Layer<()>>) {
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let mask = layer(mask_x, 0.0, 100.0, 100.0, 256);
+        let child = layer(10.0, 10.0, 50.0, 50.0, 256);
+        mask.set_masks_to_bounds(true);
+        mask.add_child(child.clone());
+        root.add_child(mask.clone());
+        let scene = scene_with_root(root.clone());
+        scene.flush_transform_updates();
+        (scene, root, mask, child)
+    }
+
+    fn world_x(layer: &Layer<()>) -> f32 {
+        layer.transform_state.borrow().world_rect.origin.x
+    }
+
+    #[test]
+    fn culled_subtrees_dont_keep_the_tree_dirty() {
+        let (scene, root, mask, child) = scene_with_masked_child(512.0);
+        assert_eq!(world_x(&child), 522.0);
+
+        scene.begin_gesture();
+        mask.set_bounds(TypedRect::new(TypedPoint2D::new(502.0, 0.0),
+                                       TypedSize2D::new(100.0, 100.0)));
+        scene.flush_transform_updates();
+        assert_eq!(world_x(&mask), 502.0);
+        assert_eq!(world_x(&child), 522.0);
+        assert!(child.has_dirty_transform_state());
+        assert!(!root.subtree_has_dirty_transform_state());
+        match scene.explain_visibility(&child, None).blocker {
+            Some(VisibilityBlocker::StaleTransformState) => {}
+            blocker => panic!("unexpected visibility blocker {:?}", blocker),
+        }
+
+        scene.end_gesture();
+        assert_eq!(world_x(&child), 512.0);
+        assert!(!child.has_dirty_transform_state());
+    }
+
+    #[test]
+    fn culled_subtrees_are_updated_when_they_come_into_view() {
+        let (scene, _, mask, child) = scene_with_masked_child(512.0);
+        scene.begin_gesture();
+        mask.set_bounds(TypedRect::new(TypedPoint2D::new(600.0, 0.0),
+                                       TypedSize2D::new(100.0, 100.0)));
+        scene.flush_transform_updates();
+        assert!(child.has_dirty_transform_state());
+
+        mask.set_bounds(TypedRect::new(TypedPoint2D::new(50.0, 0.0),
+                                       TypedSize2D::new(100.0, 100.0)));
+        scene.flush_transform_updates();
+        assert_eq!(world_x(&child), 60.0);
+        assert!(!child.has_dirty_transform_state());
+        scene.end_gesture();
+        assert_eq!(world_x(&child), 60.0);
+    }
+
+    #[test]
+    fn mutations_between_flushes_are_coalesced_into_one_update() {
+        let (scene, root, mask, child) = scene_with_masked_child(0.0);
+        let updates = scene.transform_update_count();
+        scene.flush_transform_updates();
+        assert_eq!(scene.transform_update_count(), updates);
+
+        for frame in 1..4 {
+            for event in 0..10 {
+                let offset = -((frame * 10 + event) as f32);
+                root.set_transform(Matrix4D::identity().pre_translated(offset, 0.0, 0.0));
+                mask.set_opacity(0.5);
+                child.set_content_offset(TypedPoint2D::new(offset, 0.0));
+            }
+            scene.flush_transform_updates();
+            scene.flush_transform_updates();
+            assert_eq!(scene.transform_update_count(), updates + frame);
+            assert_eq!(world_x(&child), 10.0 - (frame * 10 + 9) as f32);
+        }
+    }
+
+    #[test]
+    fn direct_writes_are_noticed_by_the_next_flush() {
+        let (scene, _, mask, child) = scene_with_masked_child(0.0);
+        let updates = scene.transform_update_count();
+        *mask.bounds.borrow_mut() = TypedRect::new(TypedPoint2D::new(30.0, 0.0),
+                                                   TypedSize2D::new(100.0, 100.0));
+        assert!(mask.has_dirty_transform_state());
+        scene.flush_transform_updates();
+        assert_eq!(scene.transform_update_count(), updates + 1);
+        assert_eq!(world_x(&child), 40.0);
+
+        *child.opacity.borrow_mut() = 0.25;
+        scene.flush_transform_updates();
+        assert_eq!(child.transform_state.borrow().effective_opacity, 0.25);
+
+        // Writing back the flushed value isn't a change.
+        *mask.masks_to_bounds.borrow_mut() = true;
+        scene.flush_transform_updates();
+        assert_eq!(scene.transform_update_count(), updates + 2);
+    }
+
+    macro_rules! assert_blocked_by {
+        ($scene:expr, $layer:expr, $tile_index:expr, $blocker:pat) => {
+            match $scene.explain_visibility($layer, $tile_index).blocker {
+                Some($blocker) => {}
+                blocker => panic!("unexpected visibility blocker {:?}", blocker),
+            }
+        }
+    }
+
+    /// Returns an up-to-date scene whose root holds a 100x100 child at the given position.
+    fn scene_with_child(x: f32, y: f32) -> (Scene<()>, Rc<Layer<()>>) {
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let child = layer(x, y, 100.0, 100.0, 256);
+        root.add_child(child.clone());
+        let scene = scene_with_root(root);
+        scene.flush_transform_updates();
+        (scene, child)
+    }
+
+    #[test]
+    fn layers_outside_the_tree_are_not_in_the_scene() {
+        let (scene, _) = scene_with_child(0.0, 0.0);
+        assert_blocked_by!(scene, &layer(0.0, 0.0, 100.0, 100.0, 256), None,
+                           VisibilityBlocker::NotInScene);
+    }
+
+    #[test]
+    fn hidden_layers_and_subtrees_are_explained() {
+        let (scene, child) = scene_with_child(0.0, 0.0);
+        let grandchild = layer(0.0, 0.0, 50.0, 50.0, 256);
+        child.add_child(grandchild.clone());
+
+        child.set_visible(false, false);
+        scene.flush_transform_updates();
+        assert_blocked_by!(scene, &child, None, VisibilityBlocker::Hidden);
+        assert_blocked_by!(scene, &grandchild, None, VisibilityBlocker::NoDrawableTiles);
+
+        child.set_visible(false, true);
+        scene.flush_transform_updates();
+        assert_blocked_by!(scene, &grandchild, None,
+                           VisibilityBlocker::HiddenByAncestor { depth: 1 });
+    }
+
+    #[test]
+    fn layers_outside_the_root_are_outside_the_viewport() {
+        let (scene, child) = scene_with_child(300.0, 0.0);
+        assert_blocked_by!(scene, &child, None, VisibilityBlocker::OutsideViewport { .. });
+    }
+
+    #[test]
+    fn transparent_layers_are_explained() {
+        let (scene, child) = scene_with_child(0.0, 0.0);
+        child.set_opacity(0.0);
+        scene.flush_transform_updates();
+        assert_blocked_by!(scene, &child, None, VisibilityBlocker::Transparent { opacity: 0.0 });
+    }
+
+    #[test]
+    fn content_insets_covering_the_layer_leave_an_empty_content_rect() {
+        let (scene, child) = scene_with_child(0.0, 0.0);
+        child.set_content_inset(SideOffsets2D::new(50.0, 50.0, 50.0, 50.0));
+        scene.flush_transform_updates();
+        assert_blocked_by!(scene, &child, None, VisibilityBlocker::EmptyContentRect { .. });
+    }
+
+    /// Asserts that hit testing the scene at the given point finds `expected`, from front to
+    /// back, and that `hit_test` finds the first of them.
+    fn assert_hits(scene: &Scene<()>, x: f32, y: f32, expected: &[&Rc<Layer<()>>]) {
+        let point = TypedPoint2D::new(x, y);
+        let hits = scene.hit_test_all(point);
+        assert_eq!(hits.len(), expected.len(), "hits at ({}, {})", x, y);
+        for (hit, layer) in hits.iter().zip(expected.iter()) {
+            assert!(Rc::ptr_eq(hit, layer), "wrong layer hit at ({}, {})", x, y);
+        }
+        assert_eq!(scene.hit_test(point).is_some(), !expected.is_empty());
+        if let Some(top) = scene.hit_test(point) {
+            assert!(Rc::ptr_eq(&top, expected[0]));
+        }
+    }
+
+    #[test]
+    fn translated_and_scaled_layers_are_hit_where_they_are_drawn() {
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let child = layer(50.0, 50.0, 50.0, 50.0, 256);
+        child.set_transform(Matrix4D::identity().pre_translated(10.0, 0.0, 0.0)
+                                                .pre_scaled(2.0, 2.0, 1.0));
+        root.add_child(child.clone());
+        let mut scene = scene_with_root(root.clone());
+
+        assert_hits(&scene, 65.0, 55.0, &[&child, &root]);
+        assert_hits(&scene, 155.0, 145.0, &[&child, &root]);
+        assert_hits(&scene, 55.0, 55.0, &[&root]);
+        assert_hits(&scene, 165.0, 55.0, &[&root]);
+        assert_hits(&scene, 300.0, 55.0, &[]);
+
+        // Points are in device pixels.
+        scene.scale = ScaleFactor::new(2.0);
+        assert_hits(&scene, 310.0, 290.0, &[&child, &root]);
+        assert_hits(&scene, 110.0, 110.0, &[&root]);
+    }
+
+    #[test]
+    fn points_masked_away_by_an_ancestor_miss_its_descendants() {
+        // As when drawing, only layers establishing a 3D context clip their descendants.
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let transparent = Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 };
+        let mask = Rc::new(Layer::new(TypedRect::new(TypedPoint2D::new(0.0, 0.0),
+                                                     TypedSize2D::new(100.0, 100.0)),
+                                      256,
+                                      transparent,
+                                      1.0,
+                                      true,
+                                      ()));
+        let child = layer(70.0, 10.0, 50.0, 50.0, 256);
+        mask.set_masks_to_bounds(true);
+        mask.add_child(child.clone());
+        root.add_child(mask.clone());
+        let scene = scene_with_root(root.clone());
+
+        // The child covers 70..120 horizontally, but the mask ends at 100.
+        assert_hits(&scene, 80.0, 20.0, &[&child, &mask, &root]);
+        assert_hits(&scene, 110.0, 20.0, &[&root]);
+    }
+
+    #[test]
+    fn only_the_content_rect_of_a_layer_is_hit() {
+        let (scene, child) = scene_with_child(0.0, 0.0);
+        let root = scene.root.clone().unwrap();
+        child.set_content_inset(SideOffsets2D::new(10.0, 20.0, 30.0, 40.0));
+        assert_hits(&scene, 45.0, 15.0, &[&child, &root]);
+        assert_hits(&scene, 35.0, 15.0, &[&root]);
+        assert_hits(&scene, 45.0, 5.0, &[&root]);
+        assert_hits(&scene, 85.0, 15.0, &[&root]);
+        assert_hits(&scene, 45.0, 75.0, &[&root]);
+    }
+
+    #[test]
+    fn hidden_layers_are_not_hit() {
+        let (scene, child) = scene_with_child(0.0, 0.0);
+        let root = scene.root.clone().unwrap();
+        let grandchild = layer(0.0, 0.0, 50.0, 50.0, 256);
+        child.add_child(grandchild.clone());
+        assert_hits(&scene, 10.0, 10.0, &[&grandchild, &child, &root]);
+
+        // Descendants of a hidden layer are still hit, unless the whole subtree is hidden.
+        child.set_visible(false, false);
+        assert_hits(&scene, 10.0, 10.0, &[&grandchild, &root]);
+        child.set_visible(false, true);
+        assert_hits(&scene, 10.0, 10.0, &[&root]);
+    }
+
+    #[test]
+    fn layers_with_empty_inverted_or_nan_bounds_request_nothing() {
+        let (mut scene, child) = scene_with_child(0.0, 0.0);
+        let (requests, _) = request_pass(&mut scene);
+        fulfill(&scene, &requests);
+        let fresh = layer(0.0, 0.0, 100.0, 100.0, 256);
+        scene.root.as_ref().unwrap().add_child(fresh.clone());
+
+        // Painted layers keep their tiles until the next layout; new ones get none.
+        let nan = ::std::f32::NAN;
+        for &(width, height) in &[(0.0, 100.0), (100.0, 0.0), (-100.0, 100.0), (100.0, -50.0),
+                                  (nan, 100.0), (100.0, nan), (nan, nan)] {
+            let bounds = TypedRect::new(TypedPoint2D::new(10.0, 10.0),
+                                        TypedSize2D::new(width, height));
+            child.set_bounds(bounds);
+            child.contents_changed();
+            fresh.set_bounds(bounds);
+            let (requests, unused_buffers) = request_pass(&mut scene);
+            assert!(requests.is_empty());
+            assert!(unused_buffers.is_empty());
+            assert_eq!(child.tile_statistics().tile_count, 1);
+            assert_eq!(fresh.tile_statistics().tile_count, 0);
+
+            // Only inverted and NaN bounds leave the layer without a screen rect.
+            let projected = width >= 0.0 && height >= 0.0;
+            assert_eq!(child.transform_state.borrow().screen_rect.is_some(), projected);
+        }
+
+        child.set_bounds(TypedRect::new(TypedPoint2D::new(10.0, 10.0),
+                                        TypedSize2D::new(100.0, 100.0)));
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!((requests_for(&requests, &child), requests_for(&requests, &fresh)), (1, 0));
+    }
+
+    #[test]
+    fn hidden_layers_keep_their_tiles_without_requesting_new_ones() {
+        let (mut scene, child) = scene_with_child(0.0, 0.0);
+        let (requests, _) = request_pass(&mut scene);
+        fulfill(&scene, &requests);
+        child.contents_changed();
+        let (older, _) = request_pass(&mut scene);
+        child.contents_changed();
+        let (newer, _) = request_pass(&mut scene);
+        assert_eq!((older.len(), requests_for(&older, &child)), (1, 1));
+        assert_eq!((newer.len(), requests_for(&newer, &child)), (1, 1));
+        let content_age = *child.content_age.borrow();
+
+        // A hidden layer requests nothing, but still gives back the buffers it doesn't use: the
+        // one the newer buffer replaced, and the older buffer arriving last.
+        child.set_visible(false, false);
+        fulfill(&scene, &newer);
+        scene.add_buffer(&child, buffer_for_request(&older[0].1[0]), FrameIndex(0));
+        child.contents_changed();
+        let (requests, unused_buffers) = request_pass(&mut scene);
+        assert!(requests.is_empty());
+        assert_eq!(unused_buffers.len(), 2);
+
+        // Showing it again requests only the content that changed while it was hidden.
+        assert!(*child.content_age.borrow() > content_age);
+        child.set_visible(true, false);
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!((requests.len(), requests_for(&requests, &child)), (1, 1));
+        fulfill(&scene, &requests);
+        child.set_visible(false, false);
+        child.set_visible(true, false);
+        assert!(request_pass(&mut scene).0.is_empty());
+
+        // Descendants are requested unless the whole subtree is hidden.
+        let grandchild = layer(0.0, 0.0, 50.0, 50.0, 256);
+        child.add_child(grandchild.clone());
+        child.set_visible(false, true);
+        scene.flush_transform_updates();
+        assert!(request_pass(&mut scene).0.is_empty());
+        child.set_visible(false, false);
+        let (requests, _) = request_pass(&mut scene);
+        assert_eq!((requests.len(), requests_for(&requests, &grandchild)), (1, 1));
+    }
+
+    #[test]
+    fn tiles_are_explained_through_the_request_protocol() {
+        let (mut scene, child) = scene_with_child(0.0, 0.0);
+        let tile_index = Some(Point2D::new(0, 0));
+        assert_blocked_by!(scene, &child, tile_index, VisibilityBlocker::TileNotRequested);
+
+        let (requests, _) = request_pass(&mut scene);
+        assert_blocked_by!(scene, &child, tile_index, VisibilityBlocker::AwaitingBuffer { .. });
+        assert_blocked_by!(scene, &child, None, VisibilityBlocker::NoDrawableTiles);
+
+        // The buffer is only drawable once it is bound to a texture by the renderer.
+        fulfill(&scene, &requests);
+        assert_blocked_by!(scene, &child, tile_index, VisibilityBlocker::TextureNotCreated);
+    }
+
+    #[test]
+    fn tiles_behind_an_opaque_overlay_are_explained() {
+        let (mut scene, page) = scene_with_painted_overlay(layer(0.0, 0.0, 256.0, 256.0, 256),
+                                                           256.0);
+        request_pass(&mut scene);
+        assert_blocked_by!(scene, &page, Some(Point2D::new(0, 0)),
+                           VisibilityBlocker::TileOccluded);
+    }
+
+    /// Returns an up-to-date scene whose root holds siblings with the given bounds, as x, y,
+    /// width, and height, and opacities.
+    fn scene_with_siblings(siblings: &[(f32, f32, f32, f32, f32)]) -> Scene<()> {
+        let root = layer(0.0, 0.0, 512.0, 512.0, 256);
+        for &(x, y, width, height, opacity) in siblings {
+            let sibling = layer(x, y, width, height, 256);
+            sibling.set_opacity(opacity);
+            root.add_child(sibling);
+        }
+        let scene = scene_with_root(root);
+        scene.flush_transform_updates();
+        scene
+    }
+
+    /// Returns the x and y positions of the members of each batch of the scene.
+    fn batch_origins(scene: &Scene<()>) -> Vec<Vec<(f32, f32)>> {
+        scene.compute_layer_batches().iter().map(|batch| {
+            batch.iter().map(|member| (member.bounds().origin.x, member.bounds().origin.y))
+                 .collect()
+        }).collect()
+    }
+
+    #[test]
+    fn siblings_covering_one_region_are_batched() {
+        let scene = scene_with_siblings(&[(0.0, 0.0, 100.0, 100.0, 1.0),
+                                          (100.0, 0.0, 100.0, 100.0, 1.0),
+                                          (0.0, 100.0, 100.0, 50.0, 1.0)]);
+        assert_eq!(batch_origins(&scene), vec!(vec!((0.0, 0.0), (100.0, 0.0), (0.0, 100.0))));
+    }
+
+    #[test]
+    fn siblings_apart_are_not_batched() {
+        let scene = scene_with_siblings(&[(0.0, 0.0, 100.0, 100.0, 1.0),
+                                          (101.0, 0.0, 100.0, 100.0, 1.0)]);
+        assert!(batch_origins(&scene).is_empty());
+
+        // Meeting at a corner isn't enough either.
+        let scene = scene_with_siblings(&[(0.0, 0.0, 100.0, 100.0, 1.0),
+                                          (100.0, 100.0, 100.0, 100.0, 1.0)]);
+        assert!(batch_origins(&scene).is_empty());
+    }
+
+    #[test]
+    fn a_tiny_overlap_starts_a_new_batch() {
+        let scene = scene_with_siblings(&[(0.0, 0.0, 100.0, 100.0, 1.0),
+                                          (99.5, 0.0, 100.0, 100.0, 1.0),
+                                          (199.5, 0.0, 100.0, 100.0, 1.0)]);
+        assert_eq!(batch_origins(&scene), vec!(vec!((99.5, 0.0), (199.5, 0.0))));
+    }
+
+    #[test]
+    fn a_different_opacity_starts_a_new_batch() {
+        let scene = scene_with_siblings(&[(0.0, 0.0, 100.0, 100.0, 1.0),
+                                          (100.0, 0.0, 100.0, 100.0, 0.5),
+                                          (200.0, 0.0, 100.0, 100.0, 0.5)]);
+        assert_eq!(batch_origins(&scene), vec!(vec!((100.0, 0.0), (200.0, 0.0))));
+    }
+
+    fn device_rect(x: f32, y: f32, width: f32, height: f32) -> TypedRect<f32, DevicePixel> {
+        TypedRect::new(TypedPoint2D::new(x, y), TypedSize2D::new(width, height))
+    }
+
+    fn damage_rect(x: f32, y: f32, width: f32, height: f32) -> TypedRect<f32, LayerPixel> {
+        TypedRect::new(TypedPoint2D::new(x, y), TypedSize2D::new(width, height))
+    }
+
+    #[test]
+    fn damage_is_offset_by_the_position_of_the_layer_and_cleared_once_taken() {
+        let (scene, child) = scene_with_child(10.0, 20.0);
+        // New layers damage the whole viewport.
+        assert_eq!(scene.accumulate_damage(), Some(device_rect(0.0, 0.0, 256.0, 256.0)));
+        assert_eq!(scene.accumulate_damage(), None);
+
+        child.add_damage(damage_rect(5.0, 5.0, 10.0, 10.0));
+        child.add_damage(damage_rect(20.0, 5.0, 10.0, 10.0));
+        assert_eq!(scene.accumulate_damage(), Some(device_rect(15.0, 25.0, 25.0, 10.0)));
+        assert_eq!(child.take_damage(), None);
+        assert_eq!(scene.accumulate_damage(), None);
+
+        child.contents_changed();
+        assert_eq!(scene.accumulate_damage(), Some(device_rect(10.0, 20.0, 100.0, 100.0)));
+    }
+
+    #[test]
+    fn damage_is_transformed_by_the_layer_and_the_scene_scale() {
+        let (mut scene, child) = scene_with_child(10.0, 20.0);
+        child.set_transform(Matrix4D::create_scale(2.0, 2.0, 1.0));
+        scene.accumulate_damage();
+
+        child.add_damage(damage_rect(5.0, 5.0, 10.0, 10.0));
+        assert_eq!(scene.accumulate_damage(), Some(device_rect(20.0, 30.0, 20.0, 20.0)));
+
+        scene.scale = ScaleFactor::new(2.0);
+        child.add_damage(damage_rect(5.0, 5.0, 10.0, 10.0));
+        assert_eq!(scene.accumulate_damage(), Some(device_rect(40.0, 60.0, 40.0, 40.0)));
+    }
+
+    #[test]
+    fn damage_is_clipped_to_the_viewport() {
+        let (scene, child) = scene_with_child(200.0, 200.0);
+        scene.accumulate_damage();
+        child.contents_changed();
+        assert_eq!(scene.accumulate_damage(), Some(device_rect(200.0, 200.0, 56.0, 56.0)));
+    }
+
+    #[test]
+    fn layer_tree_dumps_round_trip_through_json() {
+        let root = layer(0.0, 0.0, 256.0, 256.0, 256);
+        let child = layer(10.0, 20.0, 30.0, 40.0, 256);
+        child.set_opacity(0.5);
+        *child.masks_to_bounds.borrow_mut() = true;
+        root.add_child(child);
+        let mut scene = scene_with_root(root);
+        let (requests, _) = request_pass(&mut scene);
+        fulfill(&scene, &requests);
+
+        let json = Json::from_str(&dump_layer_tree(&scene).to_json_string()).unwrap();
+        let number = |path: &[&str]| json.find_path(path).and_then(Json::as_f64);
+        assert_eq!(number(&["viewport", "size", "width"]), Some(256.0));
+        assert_eq!(number(&["scale"]), Some(1.0));
+        let children = json.find_path(&["root", "children"]).and_then(Json::as_array).unwrap();
+        assert_eq!(children.len(), 1);
+        let child = &children[0];
+        assert_eq!(child.find_path(&["bounds", "origin", "y"]).and_then(Json::as_f64),
+                   Some(20.0));
+        assert_eq!(child.find("opacity").and_then(Json::as_f64), Some(0.5));
+        assert_eq!(child.find("masks_to_bounds").and_then(Json::as_boolean), Some(true));
+        assert_eq!(child.find_path(&["transform_state", "effective_opacity"])
+                        .and_then(Json::as_f64),
+                   Some(0.5));
+        assert_eq!(child.find_path(&["tile_statistics", "tiles_with_buffers"])
+                        .and_then(Json::as_u64),
+                   Some(1));
+        assert_eq!(child.find("extra_data"), Some(&Json::Null));
+        assert!(child.find("children").and_then(Json::as_array).unwrap().is_empty());
+
+        let json = Json::from_str(&dump_layer_tree_with_extra_data(&scene).to_json_string());
+        assert_eq!(json.unwrap().find_path(&["root", "extra_data"]).and_then(Json::as_string),
+                   Some("()"));
+    }
+
+    /// Returns a layer at the given position, identified by `id`.
+    fn layer_with_id(id: u32, x: f32, y: f32) -> Rc<Layer<u32>> {
+        let bounds = TypedRect::new(TypedPoint2D::new(x, y), TypedSize2D::new(100.0, 100.0));
+        Rc::new(Layer::new(bounds, 256, Color::transparent(), 1.0, false, id))
+    }
+
+    /// Returns a tree of layers identified by ids, each given as its id and the id of its parent.
+    /// The first layer is the root.
+    fn tree(layers: &[(u32, u32)]) -> Rc<Layer<u32>> {
+        let mut built: Vec<Rc<Layer<u32>>> = vec!();
+        for &(id, parent_id) in layers {
+            let layer = layer_with_id(id, 0.0, 0.0);
+            if let Some(parent) = built.iter().rev().find(|layer| {
+                *layer.extra_data.borrow() == parent_id
+            }) {
+                parent.add_child(layer.clone());
+            }
+            built.push(layer);
+        }
+        built[0].clone()
+    }
+
+    fn find(layer: &Rc<Layer<u32>>, id: u32) -> Rc<Layer<u32>> {
+        find_descendant(layer, id).unwrap()
+    }
+
+    fn find_descendant(layer: &Rc<Layer<u32>>, id: u32) -> Option<Rc<Layer<u32>>> {
+        if *layer.extra_data.borrow() == id {
+            return Some(layer.clone());
+        }
+        layer.children().iter().filter_map(|kid| find_descendant(kid, id)).next()
+    }
+
+    fn is_same_layer(a: &Rc<Layer<u32>>, b: &Rc<Layer<u32>>) -> bool {
+        &**a as *const Layer<u32> == &**b as *const Layer<u32>
+    }
+
+    /// Describes each diff as its kind and the id of its layer.
+    fn summarize(diffs: &[LayerDiff<u32>]) -> Vec<(&'static str, u32)> {
+        diffs.iter().map(|diff| match *diff {
+            LayerDiff::Added(ref layer) => ("added", *layer.extra_data.borrow()),
+            LayerDiff::Removed(ref layer) => ("removed", *layer.extra_data.borrow()),
+            LayerDiff::BoundsChanged { ref layer, .. } => ("bounds", *layer.extra_data.borrow()),
+            LayerDiff::TransformChanged(ref layer) => ("transform", *layer.extra_data.borrow()),
+            LayerDiff::OpacityChanged(ref layer) => ("opacity", *layer.extra_data.borrow()),
+            LayerDiff::MaskChanged(ref layer) => ("mask", *layer.extra_data.borrow()),
+            LayerDiff::ContentOffsetChanged(ref layer) => {
+                ("content offset", *layer.extra_data.borrow())
+            }
+            LayerDiff::Reparented(ref layer) => ("reparented", *layer.extra_data.borrow()),
+        }).collect()
+    }
+
+    #[test]
+    fn identical_trees_and_reordered_siblings_have_no_diffs() {
+        let old = tree(&[(1, 0), (2, 1), (3, 1), (4, 3), (5, 3)]);
+        assert!(diff_layer_trees(&old, &tree(&[(1, 0), (2, 1), (3, 1), (4, 3), (5, 3)]))
+                    .is_empty());
+        assert!(diff_layer_trees(&old, &tree(&[(1, 0), (3, 1), (5, 3), (4, 3), (2, 1)]))
+                    .is_empty());
+    }
+
+    #[test]
+    fn a_moved_subtree_is_reparented_rather_than_removed_and_added() {
+        // The subtree of 3 and 4 moves from 2 to 5.
+        let old = tree(&[(1, 0), (2, 1), (3, 2), (4, 3), (5, 1)]);
+        let new = tree(&[(1, 0), (2, 1), (5, 1), (3, 5), (4, 3)]);
+        assert_eq!(summarize(&diff_layer_trees(&old, &new)), vec!(("reparented", 3)));
+
+        // Layers only in one of the trees are added or removed, with their descendants.
+        let new = tree(&[(1, 0), (2, 1), (6, 1), (7, 6)]);
+        assert_eq!(summarize(&diff_layer_trees(&old, &new)),
+                   vec!(("added", 6), ("added", 7), ("removed", 3), ("removed", 4),
+                        ("removed", 5)));
+    }
+
+    #[test]
+    fn changed_properties_are_reported_with_the_layer_of_the_new_tree() {
+        let old = tree(&[(1, 0), (2, 1), (3, 1)]);
+        let new = tree(&[(1, 0), (2, 1), (3, 1)]);
+        let changed = find(&new, 2);
+        let new_bounds = TypedRect::new(TypedPoint2D::new(10.0, 20.0),
+                                        TypedSize2D::new(30.0, 40.0));
+        changed.set_bounds(new_bounds);
+        changed.set_transform(Matrix4D::identity().pre_translated(1.0, 0.0, 0.0));
+        changed.set_opacity(0.5);
+        changed.set_masks_to_bounds(true);
+        changed.set_content_offset(TypedPoint2D::new(0.0, -5.0));
+
+        let diffs = diff_layer_trees(&old, &new);
+        assert_eq!(summarize(&diffs),
+                   vec!(("bounds", 2), ("transform", 2), ("opacity", 2), ("mask", 2),
+                        ("content offset", 2)));
+        match diffs[0] {
+            LayerDiff::BoundsChanged { ref layer, old, new } => {
+                assert!(is_same_layer(layer, &changed));
+                assert_eq!(old, layer_with_id(2, 0.0, 0.0).bounds());
+                assert_eq!(new, new_bounds);
+            }
+            _ => unreachable!(),
+        }
+
+        // Rounding the corners changes the mask too.
+        let newer = tree(&[(1, 0), (2, 1), (3, 1)]);
+        *find(&newer, 3).corner_radii.borrow_mut() = Some([4.0; 4]);
+        assert_eq!(summarize(&diff_layer_trees(&old, &newer)), vec!(("mask", 3)));
+    }
+
+    #[test]
+    fn layers_with_equal_ids_are_matched_in_tree_order() {
+        // The two layers identified by 2 are matched first to first and second to second, so
+        // only the second one changed.
+        let old = tree(&[(1, 0), (2, 1)]);
+        old.add_child(layer_with_id(2, 0.0, 0.0));
+        let new = tree(&[(1, 0), (2, 1)]);
+        new.add_child(layer_with_id(2, 50.0, 0.0));
+        assert_eq!(summarize(&diff_layer_trees(&old, &new)), vec!(("bounds", 2)));
+        match diff_layer_trees(&old, &new)[0] {
+            LayerDiff::BoundsChanged { ref layer, .. } => {
+                assert!(is_same_layer(layer, &new.children()[1]));
+            }
+            _ => unreachable!(),
+        }
+
+        // An extra layer with the same id is added.
+        new.add_child(layer_with_id(2, 0.0, 0.0));
+        assert_eq!(summarize(&diff_layer_trees(&old, &new)), vec!(("bounds", 2), ("added", 2)));
+    }
 }
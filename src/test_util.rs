@@ -0,0 +1,352 @@
+// Copyright 2016 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Helpers shared by the unit tests, which run headless: buffers are backed by memory surfaces
+//! and GL calls go to a `RecordingGl`.
+
+use color::Color;
+use euclid::point::TypedPoint2D;
+use euclid::rect::TypedRect;
+use euclid::size::TypedSize2D;
+use geometry::LayerPixel;
+use gltrace::RecordingGl;
+use layers::Layer;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::future::Future;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+#[cfg(target_os = "linux")]
+pub use self::buffers::{buffer, buffer_for_request, headless_display};
+#[cfg(all(target_os = "linux", feature = "egl-tests"))]
+pub use self::egl_context::make_headless_egl_context_current;
+#[cfg(all(target_os = "linux", feature = "x11-tests"))]
+pub use self::x11_context::{XErrorLog, make_glx_context_current};
+
+/// Creates a layer with the given bounds and tile size, with no content.
+pub fn layer(x: f32, y: f32, width: f32, height: f32, tile_size: usize) -> Rc<Layer<()>> {
+    let bounds: TypedRect<f32, LayerPixel> = TypedRect::new(TypedPoint2D::new(x, y),
+                                                            TypedSize2D::new(width, height));
+    let transparent = Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 };
+    Rc::new(Layer::new(bounds, tile_size, transparent, 1.0, false, ()))
+}
+
+/// Returns a new `RecordingGl`, to be handed to the render context or the textures whose GL
+/// calls a test checks.
+pub fn record_gl_calls() -> Rc<RecordingGl> {
+    Rc::new(RecordingGl::new())
+}
+
+/// The allocator of the test binary, which counts the allocations made by each thread so that
+/// tests running in parallel don't see each other's.
+struct CountingAllocator;
+
+thread_local!(static ALLOCATION_COUNT: Cell<usize> = const { Cell::new(0) });
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATION_COUNT.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATION_COUNT.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Returns the number of allocations and reallocations made by the current thread while running
+/// the given function.
+pub fn count_allocations<F>(f: F) -> usize where F: FnOnce() {
+    let before = ALLOCATION_COUNT.with(|count| count.get());
+    f();
+    ALLOCATION_COUNT.with(|count| count.get()) - before
+}
+
+/// A waker that unparks the thread that created it and counts how often it was woken.
+pub struct ThreadWaker {
+    thread: Thread,
+    pub wakes: AtomicUsize,
+}
+
+impl ThreadWaker {
+    pub fn for_current_thread() -> ThreadWaker {
+        ThreadWaker {
+            thread: thread::current(),
+            wakes: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wakes.fetch_add(1, Ordering::SeqCst);
+        self.thread.unpark();
+    }
+}
+
+/// Runs `future` to completion on the current thread, parking the thread while it is pending.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker::for_current_thread()));
+    let mut context = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output
+        }
+        thread::park();
+    }
+}
+
+/// Buffers backed by memory surfaces. Only Linux has a display that needs no window system.
+#[cfg(target_os = "linux")]
+mod buffers {
+    use euclid::point::Point2D;
+    use euclid::rect::Rect;
+    use euclid::size::Size2D;
+    use layers::{BufferRequest, ContentAge, LayerBuffer};
+    use platform::surface::{MemoryBufferNativeSurface, NativeDisplay, NativeSurface};
+    use std::ptr;
+    use texturegl::Rotation;
+
+    /// Returns a display without an X connection, for which only memory surfaces are allocated.
+    pub fn headless_display() -> NativeDisplay {
+        NativeDisplay::new(ptr::null_mut())
+    }
+
+    /// Creates a buffer fulfilling the given request, backed by a memory surface.
+    pub fn buffer_for_request(request: &BufferRequest) -> Box<LayerBuffer> {
+        let size = Size2D::new(request.screen_rect.size.width as i32,
+                               request.screen_rect.size.height as i32);
+        Box::new(LayerBuffer {
+            native_surface: NativeSurface::MemoryBuffer(
+                MemoryBufferNativeSurface::new(&headless_display(), size)),
+            rect: request.page_rect,
+            screen_pos: request.screen_rect,
+            resolution: request.resolution,
+            painted_with_cpu: true,
+            content_age: request.content_age,
+            rotation: Rotation::NoRotation,
+            mirrored: false,
+        })
+    }
+
+    /// Creates a buffer of the given size in device pixels at resolution 1.0, backed by a memory
+    /// surface.
+    pub fn buffer(width: usize, height: usize) -> Box<LayerBuffer> {
+        let request = BufferRequest::new(Rect::new(Point2D::zero(), Size2D::new(width, height)),
+                                         Rect::new(Point2D::zero(),
+                                                   Size2D::new(width as f32, height as f32)),
+                                         ContentAge::new());
+        buffer_for_request(&request)
+    }
+}
+
+/// A real GL context, for the tests that need one. Run them with `--features egl-tests` on a
+/// machine with an EGL implementation; Mesa needs `EGL_PLATFORM=surfaceless` when there is no
+/// window system.
+#[cfg(all(target_os = "linux", feature = "egl-tests"))]
+mod egl_context {
+    use egl::egl::{self, ChooseConfig, CreateContext, CreatePbufferSurface, EGLConfig, EGLint};
+    use egl::egl::{GetDisplay, Initialize, MakeCurrent};
+    use gltrace as gl;
+    use platform::linux::surface::EGLDisplayInfo;
+    use platform::surface::NativeDisplay;
+    use std::ffi::CString;
+    use std::os::raw::{c_char, c_void};
+    use std::ptr;
+
+    extern {
+        fn eglGetProcAddress(procname: *const c_char) -> *const c_void;
+    }
+
+    /// Makes a GLES 2 context on a pbuffer of the given size on the default EGL display current,
+    /// and loads the GL functions from it. Returns the display.
+    pub fn make_headless_egl_context_current(width: EGLint, height: EGLint) -> NativeDisplay {
+        let display = GetDisplay(ptr::null_mut());
+        let (mut major, mut minor) = (0, 0);
+        assert!(Initialize(display, &mut major, &mut minor) != 0, "No EGL display");
+        let config_attributes = [egl::EGL_SURFACE_TYPE as EGLint,
+                                 egl::EGL_PBUFFER_BIT as EGLint,
+                                 egl::EGL_RENDERABLE_TYPE as EGLint,
+                                 egl::EGL_OPENGL_ES2_BIT as EGLint,
+                                 egl::EGL_RED_SIZE as EGLint,
+                                 8,
+                                 egl::EGL_GREEN_SIZE as EGLint,
+                                 8,
+                                 egl::EGL_BLUE_SIZE as EGLint,
+                                 8,
+                                 egl::EGL_ALPHA_SIZE as EGLint,
+                                 8,
+                                 egl::EGL_NONE as EGLint];
+        let mut config: EGLConfig = ptr::null_mut();
+        let mut config_count = 0;
+        assert!(ChooseConfig(display,
+                             config_attributes.as_ptr(),
+                             &mut config,
+                             1,
+                             &mut config_count) != 0 && config_count == 1);
+        let context_attributes = [egl::EGL_CONTEXT_CLIENT_VERSION as EGLint,
+                                  2,
+                                  egl::EGL_NONE as EGLint];
+        let context = CreateContext(display,
+                                    config,
+                                    ptr::null_mut(),
+                                    context_attributes.as_ptr());
+        let mut surface_attributes = [egl::EGL_WIDTH as EGLint,
+                                      width,
+                                      egl::EGL_HEIGHT as EGLint,
+                                      height,
+                                      egl::EGL_NONE as EGLint];
+        let surface = CreatePbufferSurface(display, config, &mut surface_attributes[0]);
+        assert!(!context.is_null() && !surface.is_null());
+        assert!(MakeCurrent(display, surface, surface, context) != 0);
+
+        gl::load_with(|name| {
+            let name = CString::new(name).unwrap();
+            unsafe { eglGetProcAddress(name.as_ptr()) }
+        });
+        NativeDisplay::EGL(EGLDisplayInfo {
+            display: display,
+        })
+    }
+}
+
+/// A GLX context and a log of X errors, for the tests of X pixmaps. Run them with
+/// `--features x11-tests` on a machine with an X server that supports GLX, such as Xvfb, named by
+/// `DISPLAY`.
+#[cfg(all(target_os = "linux", feature = "x11-tests"))]
+mod x11_context {
+    use glx;
+    use gltrace as gl;
+    use libc::c_int;
+    use platform::surface::NativeDisplay;
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::raw::c_void;
+    use std::ptr;
+    use std::sync::{Mutex, MutexGuard};
+    use x11::xlib;
+
+    /// The errors reported while an `XErrorLog` is installed, as pairs of the major opcode of the
+    /// failed request and the error code.
+    static X_ERRORS: Mutex<Vec<(u8, u8)>> = Mutex::new(Vec::new());
+
+    /// Held by the installed `XErrorLog`. The error handler is process-wide, so tests that
+    /// install one must not run at the same time.
+    static X_ERROR_LOG_LOCK: Mutex<()> = Mutex::new(());
+
+    unsafe extern "C" fn log_x_error(_: *mut xlib::Display, event: *mut xlib::XErrorEvent)
+                                     -> c_int {
+        let mut errors = X_ERRORS.lock().unwrap_or_else(|error| error.into_inner());
+        errors.push(((*event).request_code, (*event).error_code));
+        0
+    }
+
+    /// Records the X errors of every display instead of exiting the process, until it is
+    /// dropped and the previous error handler is restored.
+    pub struct XErrorLog {
+        previous_error_handler: Option<unsafe extern "C" fn(*mut xlib::Display,
+                                                            *mut xlib::XErrorEvent)
+                                                            -> c_int>,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl XErrorLog {
+        pub fn install() -> XErrorLog {
+            let lock = X_ERROR_LOG_LOCK.lock().unwrap_or_else(|error| error.into_inner());
+            X_ERRORS.lock().unwrap_or_else(|error| error.into_inner()).clear();
+            XErrorLog {
+                previous_error_handler: unsafe { xlib::XSetErrorHandler(Some(log_x_error)) },
+                _lock: lock,
+            }
+        }
+
+        /// Waits for the server to process the requests sent to the display so far, and returns
+        /// the errors logged since the last call.
+        pub fn take_errors(&self, display: &NativeDisplay) -> Vec<(u8, u8)> {
+            if let NativeDisplay::GLX(info) = *display {
+                unsafe {
+                    xlib::XSync(info.display, xlib::False);
+                }
+            }
+            let mut errors = X_ERRORS.lock().unwrap_or_else(|error| error.into_inner());
+            mem::replace(&mut *errors, vec!())
+        }
+    }
+
+    impl Drop for XErrorLog {
+        fn drop(&mut self) {
+            unsafe {
+                xlib::XSetErrorHandler(self.previous_error_handler);
+            }
+        }
+    }
+
+    /// Opens a new connection to the X display, makes a GL context on a small pbuffer of it
+    /// current, and loads the GL functions from it. Returns the display, which is never closed.
+    pub fn make_glx_context_current() -> NativeDisplay {
+        unsafe {
+            let display = xlib::XOpenDisplay(ptr::null());
+            assert!(!display.is_null(), "No X display");
+            let config_attributes = [glx::DRAWABLE_TYPE as c_int,
+                                     glx::PBUFFER_BIT as c_int,
+                                     glx::RENDER_TYPE as c_int,
+                                     glx::RGBA_BIT as c_int,
+                                     0];
+            let mut config_count = 0;
+            let configs = glx::ChooseFBConfig(mem::transmute(display),
+                                              xlib::XDefaultScreen(display),
+                                              config_attributes.as_ptr(),
+                                              &mut config_count);
+            assert!(!configs.is_null() && config_count > 0, "No GLX configuration");
+            let config = *configs;
+            xlib::XFree(configs as *mut _);
+            let context = glx::CreateNewContext(mem::transmute(display),
+                                                config,
+                                                glx::RGBA_TYPE as c_int,
+                                                ptr::null(),
+                                                xlib::True);
+            let pbuffer_attributes = [glx::PBUFFER_WIDTH as c_int,
+                                      16,
+                                      glx::PBUFFER_HEIGHT as c_int,
+                                      16,
+                                      0];
+            let pbuffer = glx::CreatePbuffer(mem::transmute(display),
+                                             config,
+                                             pbuffer_attributes.as_ptr());
+            assert!(!context.is_null() && pbuffer != 0);
+            assert!(glx::MakeContextCurrent(mem::transmute(display),
+                                            pbuffer,
+                                            pbuffer,
+                                            context) != 0);
+
+            gl::load_with(|name| {
+                let name = CString::new(name).unwrap();
+                glx::GetProcAddress(name.as_ptr() as *const _) as *const c_void
+            });
+            NativeDisplay::new(display)
+        }
+    }
+}
@@ -8,17 +8,78 @@
 // except according to those terms.
 
 //! OpenGL-specific implementation of texturing.
-
+//!
+//! Dropping a texture does not delete it right away, since nothing guarantees that the GL context
+//! it belongs to is current at that point. Its id is queued on the `TextureReclaimQueue` of the
+//! thread that created it instead, and deleted by `RenderContext::purge_dead_textures` at the
+//! start of the next frame.
+//!
+//! Memory surfaces can be uploaded through a `PboPool` of pixel buffer objects, which lets the
+//! driver copy the pixels to the texture asynchronously.
+
+use capabilities::Capabilities;
+use completion::{CompletionSignal, CompletionToken};
 use layers::LayerBuffer;
+use platform::surface::SurfaceError;
 
 use euclid::size::Size2D;
-use gleam::gl;
-use gleam::gl::{GLenum, GLint, GLuint};
-
-#[derive(Copy, Clone)]
+use gltrace::{self as gl, GlApi, GleamGl};
+use gleam::gl::{GLenum, GLint, GLsizei, GLuint};
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The serial number of the last texture created.
+static LAST_TEXTURE_SERIAL: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The layout of the pixels of a surface.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, RustcDecodable, RustcEncodable)]
 pub enum Format {
+    /// Four bytes per pixel, in the native BGRA order, with premultiplied alpha.
     ARGB32Format,
-    RGB24Format
+    /// Three bytes per pixel, in RGB order, without alpha.
+    RGB24Format,
+    /// One byte per pixel holding only alpha, for masks such as glyphs. Drawn as black with that
+    /// alpha.
+    A8Format,
+}
+
+#[cfg(not(target_os="android"))]
+const ARGB32_INTERNAL_FORMAT: GLenum = gl::RGBA;
+#[cfg(not(target_os="android"))]
+const ARGB32_PIXEL_FORMAT: GLenum = gl::BGRA;
+
+// OpenGL ES requires the internal format to match the format.
+#[cfg(target_os="android")]
+const ARGB32_INTERNAL_FORMAT: GLenum = gl::BGRA_EXT;
+#[cfg(target_os="android")]
+const ARGB32_PIXEL_FORMAT: GLenum = gl::BGRA_EXT;
+
+impl Format {
+    /// Returns the number of bytes each pixel takes in this format.
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            Format::ARGB32Format => 4,
+            Format::RGB24Format => 3,
+            Format::A8Format => 1,
+        }
+    }
+
+    /// Returns the internal format, format, and unpack alignment used to upload pixels of this
+    /// format to a texture.
+    pub fn gl_formats(self) -> (GLenum, GLenum, GLint) {
+        match self {
+            Format::ARGB32Format => (ARGB32_INTERNAL_FORMAT, ARGB32_PIXEL_FORMAT, 4),
+            // Rows of three- and one-byte pixels need not be aligned to four bytes.
+            Format::RGB24Format => (gl::RGB, gl::RGB, 1),
+            // Alpha textures sample as black with the alpha of the pixel, which is already the
+            // premultiplied color of the mask.
+            Format::A8Format => (gl::ALPHA, gl::ALPHA, 1),
+        }
+    }
 }
 
 #[cfg(feature = "heapsize")]
@@ -33,8 +94,22 @@ pub enum FilterMode {
 #[cfg(feature = "heapsize")]
 known_heap_size!(0, FilterMode);
 
+/// What a texture samples outside of its edges.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WrapMode {
+    /// The edge pixels are repeated. This is the default.
+    ClampToEdge,
+    /// The texture is tiled.
+    Repeat,
+    /// The texture is tiled, with every other copy mirrored.
+    MirroredRepeat,
+}
+
+#[cfg(feature = "heapsize")]
+known_heap_size!(0, WrapMode);
+
 /// The texture target.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum TextureTarget {
     /// TEXTURE_2D.
     TextureTarget2D,
@@ -71,6 +146,10 @@ pub struct Texture {
     /// The OpenGL texture ID.
     id: GLuint,
 
+    /// A number identifying this texture among all textures created in the process, unlike the ID,
+    /// which GL may hand out again once the texture is deleted. 0 for the zero texture.
+    serial: usize,
+
     /// The texture target.
     pub target: TextureTarget,
 
@@ -81,14 +160,35 @@ pub struct Texture {
     // Whether or not this texture needs to be flipped upon display.
     pub flip: Flip,
 
+    // How this texture is rotated upon display. The rotation is applied before the flip.
+    pub rotation: Rotation,
+
     // The size of this texture in device pixels.
-    pub size: Size2D<usize>
+    pub size: Size2D<usize>,
+
+    /// Whether mipmaps were generated from the current contents of this texture.
+    has_mipmaps: Cell<bool>,
+
+    /// The size and format of the storage allocated by `ensure_storage`, or None if the storage
+    /// of this texture was never allocated that way or was since specified another way.
+    storage_allocated: Cell<Option<(Size2D<usize>, Format)>>,
+
+    /// The queue this texture is put on when it is dropped, which belongs to the thread that
+    /// created it. None for the zero texture.
+    reclaim_queue: Option<TextureReclaimQueue>,
+
+    /// The GL implementation this texture was created through, which it is bound and uploaded
+    /// through too.
+    gl: Rc<GlApi>,
 }
 
 impl Drop for Texture {
     fn drop(&mut self) {
-        if !self.weak {
-            gl::delete_textures(&[ self.id ])
+        if self.weak {
+            return;
+        }
+        if let Some(ref reclaim_queue) = self.reclaim_queue {
+            reclaim_queue.push(self.id);
         }
     }
 }
@@ -97,64 +197,388 @@ impl Texture {
     pub fn zero() -> Texture {
         Texture {
             id: 0,
+            serial: 0,
             target: TextureTarget::TextureTarget2D,
             weak: true,
             flip: Flip::NoFlip,
+            rotation: Rotation::NoRotation,
             size: Size2D::new(0, 0),
+            has_mipmaps: Cell::new(false),
+            storage_allocated: Cell::new(None),
+            reclaim_queue: None,
+            gl: Rc::new(GleamGl),
         }
     }
     pub fn is_zero(&self) -> bool {
         self.id == 0
     }
+
+    /// Marks this texture as weak, so that the destructor does not delete it. This is used when
+    /// the GL context owning the texture has already been lost.
+    pub fn mark_weak(&mut self) {
+        self.weak = true;
+    }
+}
+
+thread_local!(static RECLAIM_QUEUE: TextureReclaimQueue = TextureReclaimQueue::new());
+
+/// The ids of dropped textures waiting to be deleted on the thread that owns their GL context.
+/// Each thread has its own queue, and textures remember the queue of the thread that created
+/// them, so a texture dropped anywhere is deleted by the thread it was created on.
+#[derive(Clone)]
+pub struct TextureReclaimQueue {
+    dead_textures: Arc<Mutex<Vec<GLuint>>>,
+}
+
+impl TextureReclaimQueue {
+    fn new() -> TextureReclaimQueue {
+        TextureReclaimQueue {
+            dead_textures: Arc::new(Mutex::new(vec!())),
+        }
+    }
+
+    /// Returns the queue of the current thread.
+    pub fn for_current_thread() -> TextureReclaimQueue {
+        RECLAIM_QUEUE.with(|queue| queue.clone())
+    }
+
+    fn push(&self, id: GLuint) {
+        // Don't panic in a destructor; a poisoned queue only leaks the texture.
+        if let Ok(mut dead_textures) = self.dead_textures.lock() {
+            dead_textures.push(id);
+        }
+    }
+
+    /// Returns the number of textures waiting to be deleted.
+    pub fn len(&self) -> usize {
+        self.dead_textures.lock().map(|dead_textures| dead_textures.len()).unwrap_or(0)
+    }
+
+    /// Returns true if no textures are waiting to be deleted.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Deletes the queued textures through `gl` and returns how many there were. The GL context
+    /// the textures were created in must be current.
+    pub fn purge(&self, gl: &GlApi) -> usize {
+        let dead_textures = self.take();
+        if !dead_textures.is_empty() {
+            gl::delete_textures(gl, &dead_textures);
+        }
+        dead_textures.len()
+    }
+
+    /// Empties the queue without deleting the textures, for when their GL context is gone.
+    pub fn forget(&self) {
+        self.take();
+    }
+
+    fn take(&self) -> Vec<GLuint> {
+        match self.dead_textures.lock() {
+            Ok(mut dead_textures) => mem::replace(&mut *dead_textures, vec!()),
+            Err(_) => vec!(),
+        }
+    }
+}
+
+thread_local!(static PBO_POOL: RefCell<Option<PboPool>> = RefCell::new(None));
+thread_local!(static FENCE_QUEUE: RefCell<FenceQueue> = RefCell::new(FenceQueue::new()));
+
+/// Fences inserted after GL commands whose completion someone is waiting for, along with the
+/// signals to complete once the GPU has executed the commands. The queue is polled without
+/// blocking at the beginning of every frame, by `RenderContext::begin_frame`, so a token completes
+/// at most a frame after its fence was signaled.
+///
+/// Like the pixel buffer objects, fences belong to the GL context of the current thread, so the
+/// queue is per thread too. It must only be used if `Capabilities::fence_sync` is set.
+pub struct FenceQueue {
+    pending: Vec<(gl::GlSync, CompletionSignal)>,
+}
+
+impl FenceQueue {
+    fn new() -> FenceQueue {
+        FenceQueue {
+            pending: vec!(),
+        }
+    }
+
+    /// Inserts a fence after the GL commands issued so far on the current thread and returns a
+    /// token that completes once the GPU has executed them.
+    pub fn insert_for_current_thread(gl: &GlApi) -> CompletionToken {
+        let signal = CompletionSignal::new();
+        let token = signal.token();
+        let sync = gl::fence_sync(gl);
+        FENCE_QUEUE.with(|queue| queue.borrow_mut().pending.push((sync, signal)));
+        token
+    }
+
+    /// Completes the tokens of the fences of the current thread that have been signaled, and
+    /// returns the number of fences still pending.
+    pub fn poll_current_thread(gl: &GlApi) -> usize {
+        FENCE_QUEUE.with(|queue| {
+            let mut queue = queue.borrow_mut();
+            let pending = mem::replace(&mut queue.pending, vec!());
+            for (sync, signal) in pending {
+                match gl::client_wait_sync(gl, sync, 0) {
+                    gl::ALREADY_SIGNALED | gl::CONDITION_SATISFIED => {
+                        gl::delete_sync(gl, sync);
+                        signal.complete();
+                    }
+                    gl::WAIT_FAILED => {
+                        warn!("Waiting for a fence failed; cancelling its token.");
+                        gl::delete_sync(gl, sync);
+                    }
+                    _ => queue.pending.push((sync, signal)),
+                }
+            }
+            queue.pending.len()
+        })
+    }
+
+    /// Deletes the fences of the current thread, cancelling their tokens. The GL context they
+    /// were inserted in must be current.
+    pub fn cancel_current_thread(gl: &GlApi) {
+        let pending = FENCE_QUEUE.with(|queue| {
+            mem::replace(&mut queue.borrow_mut().pending, vec!())
+        });
+        for (sync, _) in pending {
+            gl::delete_sync(gl, sync);
+        }
+    }
+}
+
+/// Pixel buffer objects through which pixels in memory are uploaded to textures. Uploading
+/// straight from memory stalls the driver while it copies the pixels; copied into a buffer
+/// object first, they are transferred to the texture asynchronously. Uploads rotate through the
+/// buffers, so that one doesn't wait for the GPU to finish reading the buffer of the one before
+/// it, and each upload reallocates the storage of its buffer at the size of the upload, orphaning
+/// the previous storage if it is still in use.
+///
+/// `RenderContext` is cloned for each render pass, so it can't own the pool; like the texture
+/// reclaim queue, the pool belongs to the current thread. `RenderContext::set_use_pbo_uploads`
+/// installs it.
+///
+/// If the pool was created with fences, each upload is followed by one in the `FenceQueue`, and
+/// `last_upload` returns a token that completes once the GPU has read the pixels of the most
+/// recent upload, after which the memory surface they came from may be repainted.
+pub struct PboPool {
+    buffers: Vec<GLuint>,
+
+    /// The index of the buffer the next upload goes through.
+    next: usize,
+
+    /// Whether a fence is inserted after each upload.
+    fence_uploads: bool,
+
+    /// The token of the most recent upload, if fenced.
+    last_upload: Option<CompletionToken>,
+
+    /// The GL implementation the buffers were created through.
+    gl: Rc<GlApi>,
+}
+
+impl PboPool {
+    /// Creates a pool of `count` buffers, at least one. The GL implementation must support pixel
+    /// buffer objects; see `Capabilities::pixel_buffer_objects`.
+    pub fn new(gl: Rc<GlApi>, count: usize) -> PboPool {
+        PboPool {
+            buffers: gl::gen_buffers(&*gl, count.max(1) as GLsizei),
+            next: 0,
+            fence_uploads: false,
+            last_upload: None,
+            gl: gl,
+        }
+    }
+
+    /// Like `new`, but fences each upload. The GL implementation must support fence sync
+    /// objects too; see `Capabilities::fence_sync`.
+    pub fn new_with_fences(gl: Rc<GlApi>, count: usize) -> PboPool {
+        PboPool {
+            fence_uploads: true,
+            ..PboPool::new(gl, count)
+        }
+    }
+
+    /// Returns the token of the most recent upload, or None if there was none or the pool doesn't
+    /// fence its uploads.
+    pub fn last_upload(&self) -> Option<CompletionToken> {
+        self.last_upload.clone()
+    }
+
+    /// Returns the number of buffers in the pool.
+    pub fn len(&self) -> usize {
+        self.buffers.len()
+    }
+
+    /// Uploads the rows of the texture bound to `target` starting at row `y` from `bytes`, which
+    /// holds them tightly packed, through the next buffer of the pool.
+    pub fn upload(&mut self,
+                  target: GLenum,
+                  y: GLint,
+                  width: GLsizei,
+                  height: GLsizei,
+                  format: GLenum,
+                  bytes: &[u8]) {
+        let buffer = self.buffers[self.next];
+        self.next = (self.next + 1) % self.buffers.len();
+
+        let gl = &*self.gl;
+        gl::bind_buffer(gl, gl::PIXEL_UNPACK_BUFFER, buffer);
+        gl::buffer_data_uninitialized(gl, gl::PIXEL_UNPACK_BUFFER, bytes.len(), gl::STREAM_DRAW);
+        gl::buffer_sub_data(gl, gl::PIXEL_UNPACK_BUFFER, 0, bytes);
+        gl::tex_sub_image_2d_pbo(gl, target, 0, 0, y, width, height, format, gl::UNSIGNED_BYTE, 0);
+        gl::bind_buffer(gl, gl::PIXEL_UNPACK_BUFFER, 0);
+
+        if self.fence_uploads {
+            self.last_upload = Some(FenceQueue::insert_for_current_thread(gl));
+        }
+    }
+
+    /// Deletes the buffers of the pool. The GL context they were created in must be current.
+    pub fn destroy(self) {
+        gl::delete_buffers(&*self.gl, &self.buffers);
+    }
+
+    /// Sets the pool that memory surfaces are uploaded through on the current thread, or if None,
+    /// goes back to uploading them directly. Returns the previous pool, which the caller should
+    /// destroy.
+    pub fn set_for_current_thread(pool: Option<PboPool>) -> Option<PboPool> {
+        PBO_POOL.with(|current| mem::replace(&mut *current.borrow_mut(), pool))
+    }
+
+    /// Calls `f` with the pool of the current thread, if there is one.
+    pub fn with_current_thread<F, R>(f: F) -> R where F: FnOnce(Option<&mut PboPool>) -> R {
+        PBO_POOL.with(|current| f(current.borrow_mut().as_mut()))
+    }
+}
+
+fn check_texture_size(size: Size2D<usize>, capabilities: &Capabilities)
+                      -> Result<(), TextureTooLargeError> {
+    let max_texture_size = capabilities.max_texture_size;
+    if max_texture_size != 0 && (size.width > max_texture_size || size.height > max_texture_size) {
+        return Err(TextureTooLargeError {
+            size: size,
+            max_texture_size: max_texture_size,
+        });
+    }
+    Ok(())
 }
 
 /// Encapsulates a bound texture. This ensures that the texture is unbound
 /// properly.
 pub struct BoundTexture {
-    pub target: TextureTarget
+    pub target: TextureTarget,
+    gl: Rc<GlApi>,
 }
 
 impl Drop for BoundTexture {
     fn drop(&mut self) {
-        gl::bind_texture(self.target.as_gl_target(), 0);
+        gl::bind_texture(&*self.gl, self.target.as_gl_target(), 0);
+    }
+}
+
+/// Returned when a texture larger than the maximum texture size of the GL implementation is
+/// requested. GL would fail to allocate its storage without telling us.
+#[derive(Copy, Clone, Debug)]
+pub struct TextureTooLargeError {
+    /// The requested size.
+    pub size: Size2D<usize>,
+    /// The maximum width and height of a texture.
+    pub max_texture_size: usize,
+}
+
+impl fmt::Display for TextureTooLargeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Texture of {}x{} exceeds the maximum texture size of {}",
+               self.size.width, self.size.height, self.max_texture_size)
     }
 }
 
 impl Texture {
-    /// Creates a new blank texture.
+    /// Creates a new blank texture in the current GL context, through `GleamGl`.
     pub fn new(target: TextureTarget, size: Size2D<usize>) -> Texture {
+        Texture::new_with_gl(Rc::new(GleamGl), target, size)
+    }
+
+    /// Creates a new blank texture through the given GL implementation.
+    pub fn new_with_gl(gl: Rc<GlApi>, target: TextureTarget, size: Size2D<usize>) -> Texture {
         let this = Texture {
-            id: gl::gen_textures(1)[0],
+            id: gl::gen_textures(&*gl, 1)[0],
+            serial: LAST_TEXTURE_SERIAL.fetch_add(1, Ordering::Relaxed) + 1,
             target: target,
             weak: false,
             flip: Flip::NoFlip,
+            rotation: Rotation::NoRotation,
             size: size,
+            has_mipmaps: Cell::new(false),
+            storage_allocated: Cell::new(None),
+            reclaim_queue: Some(TextureReclaimQueue::for_current_thread()),
+            gl: gl,
         };
         this.set_default_params();
         this
     }
 
-    pub fn new_with_buffer(buffer: &Box<LayerBuffer>) -> Texture {
-        let (flip, target) = Texture::texture_flip_and_target(buffer.painted_with_cpu);
-        let mut texture = Texture::new(target, buffer.screen_pos.size);
-        texture.flip = flip;
+    /// Creates a new blank texture, or returns an error if it would be larger than the maximum
+    /// texture size in `capabilities`. A maximum of zero is treated as unknown.
+    pub fn try_new(gl: Rc<GlApi>,
+                   target: TextureTarget,
+                   size: Size2D<usize>,
+                   capabilities: &Capabilities)
+                   -> Result<Texture, TextureTooLargeError> {
+        try!(check_texture_size(size, capabilities));
+        Ok(Texture::new_with_gl(gl, target, size))
+    }
+
+    /// Like `new_with_buffer`, but returns an error if the buffer is larger than the maximum
+    /// texture size.
+    pub fn try_new_with_buffer(gl: Rc<GlApi>,
+                               buffer: &Box<LayerBuffer>,
+                               capabilities: &Capabilities)
+                               -> Result<Texture, TextureTooLargeError> {
+        try!(check_texture_size(buffer.screen_pos.size, capabilities));
+        Ok(Texture::new_with_buffer(gl, buffer, capabilities))
+    }
+
+    pub fn new_with_buffer(gl: Rc<GlApi>, buffer: &Box<LayerBuffer>, capabilities: &Capabilities)
+                           -> Texture {
+        let (flip, target) = Texture::texture_flip_and_target(buffer.painted_with_cpu,
+                                                              capabilities);
+        let mut texture = Texture::new_with_gl(gl, target, buffer.screen_pos.size);
+        texture.flip = if buffer.mirrored { flip.mirrored() } else { flip };
+        texture.rotation = buffer.rotation;
         texture
     }
 
-    // Returns whether the layer should be vertically flipped.
+    // Returns whether the layer should be vertically flipped, and the target its native surface
+    // is bound to. IOSurfaces are bound to rectangle textures where the GL implementation
+    // supports them.
     #[cfg(target_os="macos")]
-    pub fn texture_flip_and_target(cpu_painting: bool) -> (Flip, TextureTarget) {
+    pub fn texture_flip_and_target(cpu_painting: bool, capabilities: &Capabilities)
+                                   -> (Flip, TextureTarget) {
         let flip = if cpu_painting {
             Flip::NoFlip
         } else {
             Flip::VerticalFlip
         };
 
-        (flip, TextureTarget::TextureTargetRectangle)
+        (flip, Texture::native_surface_target(capabilities))
+    }
+
+    /// Returns the target a native surface should be bound to: a rectangle texture where the GL
+    /// implementation supports them, and a 2D texture otherwise.
+    pub fn native_surface_target(capabilities: &Capabilities) -> TextureTarget {
+        if capabilities.texture_rectangle {
+            TextureTarget::TextureTargetRectangle
+        } else {
+            TextureTarget::TextureTarget2D
+        }
     }
 
     #[cfg(target_os="android")]
-    pub fn texture_flip_and_target(cpu_painting: bool) -> (Flip, TextureTarget) {
+    pub fn texture_flip_and_target(cpu_painting: bool, _: &Capabilities)
+                                   -> (Flip, TextureTarget) {
         let flip = if cpu_painting {
             Flip::NoFlip
         } else {
@@ -165,12 +589,12 @@ impl Texture {
     }
 
     #[cfg(target_os="linux")]
-    pub fn texture_flip_and_target(_: bool) -> (Flip, TextureTarget) {
+    pub fn texture_flip_and_target(_: bool, _: &Capabilities) -> (Flip, TextureTarget) {
         (Flip::NoFlip, TextureTarget::TextureTarget2D)
     }
 
     #[cfg(target_os="windows")]
-    pub fn texture_flip_and_target(_: bool) -> (Flip, TextureTarget) {
+    pub fn texture_flip_and_target(_: bool, _: &Capabilities) -> (Flip, TextureTarget) {
         (Flip::NoFlip, TextureTarget::TextureTarget2D)
     }
 
@@ -179,13 +603,26 @@ impl Texture {
         self.id
     }
 
+    /// Returns the serial number of this texture, which, unlike its ID, is never reused.
+    pub fn serial(&self) -> usize {
+        self.serial
+    }
+
+    /// Returns the GL implementation this texture was created through. Code that specifies the
+    /// contents of the texture, such as platform surfaces binding themselves to it, must go
+    /// through it too.
+    pub fn gl(&self) -> &GlApi {
+        &*self.gl
+    }
+
     /// Sets default parameters for this texture.
     fn set_default_params(&self) {
         let _bound_texture = self.bind();
-        gl::tex_parameter_i(self.target.as_gl_target(), gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
-        gl::tex_parameter_i(self.target.as_gl_target(), gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
-        gl::tex_parameter_i(self.target.as_gl_target(), gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
-        gl::tex_parameter_i(self.target.as_gl_target(), gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
+        let (gl, target) = (&*self.gl, self.target.as_gl_target());
+        gl::tex_parameter_i(gl, target, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+        gl::tex_parameter_i(gl, target, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+        gl::tex_parameter_i(gl, target, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
+        gl::tex_parameter_i(gl, target, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
     }
 
     /// Sets the filter mode for this texture.
@@ -195,28 +632,299 @@ impl Texture {
             FilterMode::Nearest => gl::NEAREST,
             FilterMode::Linear => gl::LINEAR,
         } as GLint;
-        gl::tex_parameter_i(self.target.as_gl_target(), gl::TEXTURE_MAG_FILTER, gl_mode);
-        gl::tex_parameter_i(self.target.as_gl_target(), gl::TEXTURE_MIN_FILTER, gl_mode);
+        gl::tex_parameter_i(&*self.gl, self.target.as_gl_target(), gl::TEXTURE_MAG_FILTER, gl_mode);
+        gl::tex_parameter_i(&*self.gl, self.target.as_gl_target(), gl::TEXTURE_MIN_FILTER, gl_mode);
+    }
+
+    /// Sets what this texture samples outside of its edges, in both directions.
+    pub fn set_wrap_mode(&self, mode: WrapMode) {
+        let _bound_texture = self.bind();
+        let gl_mode = match mode {
+            WrapMode::ClampToEdge => gl::CLAMP_TO_EDGE,
+            WrapMode::Repeat => gl::REPEAT,
+            WrapMode::MirroredRepeat => gl::MIRRORED_REPEAT,
+        } as GLint;
+        gl::tex_parameter_i(&*self.gl, self.target.as_gl_target(), gl::TEXTURE_WRAP_S, gl_mode);
+        gl::tex_parameter_i(&*self.gl, self.target.as_gl_target(), gl::TEXTURE_WRAP_T, gl_mode);
+    }
+
+    /// Generates mipmaps from the current contents of this texture, for drawing it minified
+    /// without shimmering. They must be generated again whenever the contents change. Rectangle
+    /// textures can't have mipmaps, so this fails with `Unsupported` for them.
+    pub fn generate_mipmaps(&self) -> Result<(), SurfaceError> {
+        match self.target {
+            TextureTarget::TextureTarget2D => {}
+            TextureTarget::TextureTargetRectangle => return Err(SurfaceError::Unsupported),
+        }
+        let _bound_texture = self.bind();
+        gl::generate_mipmap(&*self.gl, self.target.as_gl_target());
+        self.has_mipmaps.set(true);
+        Ok(())
+    }
+
+    /// Returns true if mipmaps were generated for this texture.
+    pub fn has_mipmaps(&self) -> bool {
+        self.has_mipmaps.get()
+    }
+
+    /// Allocates storage of the given size and format for this texture, leaving its contents
+    /// undefined, unless `ensure_storage` already allocated storage of that size and format.
+    /// Pixels are then uploaded with `tex_sub_image_2d`, which lets the driver keep the storage
+    /// instead of reallocating it on every upload. The texture must be bound. Returns true if the
+    /// storage was allocated, in which case all of it must be uploaded to.
+    pub fn ensure_storage(&self, size: Size2D<usize>, format: Format) -> bool {
+        if self.storage_allocated.get() == Some((size, format)) {
+            return false;
+        }
+        let (internal_format, pixel_format, _) = format.gl_formats();
+        gl::tex_image_2d(&*self.gl,
+                         self.target.as_gl_target(),
+                         0,
+                         internal_format as GLint,
+                         size.width as GLint,
+                         size.height as GLint,
+                         0,
+                         pixel_format,
+                         gl::UNSIGNED_BYTE,
+                         None);
+        self.storage_allocated.set(Some((size, format)));
+        true
+    }
+
+    /// Makes the next `ensure_storage` allocate storage again. Code that specifies the storage of
+    /// a texture without `ensure_storage`, such as by binding a platform surface to it, must call
+    /// this.
+    pub fn forget_storage(&self) {
+        self.storage_allocated.set(None);
     }
 
     /// Binds the texture to the current context.
     pub fn bind(&self) -> BoundTexture {
-        gl::bind_texture(self.target.as_gl_target(), self.id);
+        gl::bind_texture(&*self.gl, self.target.as_gl_target(), self.id);
 
         BoundTexture {
             target: self.target,
+            gl: self.gl.clone(),
         }
     }
 }
 
 /// Whether a texture should be flipped.
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub enum Flip {
     /// The texture should not be flipped.
     NoFlip,
     /// The texture should be flipped vertically.
     VerticalFlip,
+    /// The texture should be flipped horizontally.
+    HorizontalFlip,
+    /// The texture should be flipped both horizontally and vertically.
+    HorizontalAndVerticalFlip,
+}
+
+impl Flip {
+    /// Returns this flip combined with a horizontal flip.
+    pub fn mirrored(self) -> Flip {
+        match self {
+            Flip::NoFlip => Flip::HorizontalFlip,
+            Flip::VerticalFlip => Flip::HorizontalAndVerticalFlip,
+            Flip::HorizontalFlip => Flip::NoFlip,
+            Flip::HorizontalAndVerticalFlip => Flip::VerticalFlip,
+        }
+    }
+
+    /// Returns true if the texture is flipped horizontally.
+    pub fn is_horizontal(self) -> bool {
+        self == Flip::HorizontalFlip || self == Flip::HorizontalAndVerticalFlip
+    }
+
+    /// Returns true if the texture is flipped vertically.
+    pub fn is_vertical(self) -> bool {
+        self == Flip::VerticalFlip || self == Flip::HorizontalAndVerticalFlip
+    }
 }
 
 #[cfg(feature = "heapsize")]
 known_heap_size!(0, Flip);
+
+/// How a texture should be rotated, clockwise, upon display. Camera and video frames are often
+/// delivered rotated, and are turned upright this way without an extra copy.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, RustcDecodable, RustcEncodable)]
+pub enum Rotation {
+    /// The texture should be displayed as is.
+    NoRotation,
+    /// The texture should be rotated by 90 degrees.
+    Rotate90,
+    /// The texture should be rotated by 180 degrees.
+    Rotate180,
+    /// The texture should be rotated by 270 degrees.
+    Rotate270,
+}
+
+#[cfg(feature = "heapsize")]
+known_heap_size!(0, Rotation);
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use capabilities::Capabilities;
+    use completion::Cancelled;
+    use euclid::size::Size2D;
+    use gltrace::{self as gl, GLint, GlCall, GlSync};
+    use platform::surface::SurfaceError;
+    use super::{FenceQueue, PboPool, Texture, TextureReclaimQueue, TextureTarget, WrapMode};
+    use test_util::{block_on, record_gl_calls};
+
+    #[test]
+    fn native_surfaces_use_rectangle_textures_only_where_supported() {
+        let with_rectangles = Capabilities::from_strings("2.1", "GL_ARB_texture_rectangle", 4096);
+        assert_eq!(Texture::native_surface_target(&with_rectangles),
+                   TextureTarget::TextureTargetRectangle);
+        let without_rectangles = Capabilities::from_strings("2.1", "", 4096);
+        assert_eq!(Texture::native_surface_target(&without_rectangles),
+                   TextureTarget::TextureTarget2D);
+    }
+
+    #[test]
+    fn textures_larger_than_the_maximum_texture_size_are_refused() {
+        let gl = record_gl_calls();
+        let capabilities = Capabilities::from_strings("2.1", "", 256);
+        let target = TextureTarget::TextureTarget2D;
+        assert!(Texture::try_new(gl.clone(), target, Size2D::new(256, 256), &capabilities).is_ok());
+        for &size in &[Size2D::new(257, 1), Size2D::new(1, 257)] {
+            let error = Texture::try_new(gl.clone(), target, size, &capabilities).err().unwrap();
+            assert_eq!((error.size, error.max_texture_size), (size, 256));
+        }
+
+        // A maximum of zero is unknown, and allows any size.
+        let unknown = Capabilities::from_strings("2.1", "", 0);
+        assert!(Texture::try_new(gl.clone(), target, Size2D::new(8192, 8192), &unknown).is_ok());
+    }
+
+    #[test]
+    fn fenced_uploads_complete_once_the_gpu_has_read_them() {
+        let gl = record_gl_calls();
+        let mut pool = PboPool::new_with_fences(gl.clone(), 2);
+        assert!(pool.last_upload().is_none());
+        pool.upload(gl::TEXTURE_2D, 0, 4, 4, gl::RGBA, &[0; 64]);
+        let token = pool.last_upload().unwrap();
+        assert_eq!(gl.take_calls().last(), Some(&GlCall::FenceSync(GlSync(1))));
+
+        assert_eq!(FenceQueue::poll_current_thread(&*gl), 1);
+        assert!(!token.is_complete());
+        gl.signal_fences();
+        assert_eq!(FenceQueue::poll_current_thread(&*gl), 0);
+        assert!(token.is_complete());
+        assert_eq!(block_on(token.future()), Ok(()));
+        assert_eq!(gl.take_calls(),
+                   vec!(GlCall::ClientWaitSync(GlSync(1), 0, gl::TIMEOUT_EXPIRED),
+                        GlCall::ClientWaitSync(GlSync(1), 0, gl::ALREADY_SIGNALED),
+                        GlCall::DeleteSync(GlSync(1))));
+        pool.destroy();
+    }
+
+    #[test]
+    fn unfenced_uploads_have_no_token() {
+        let gl = record_gl_calls();
+        let mut pool = PboPool::new(gl.clone(), 2);
+        pool.upload(gl::TEXTURE_2D, 0, 4, 4, gl::RGBA, &[0; 64]);
+        assert!(pool.last_upload().is_none());
+        assert_eq!(FenceQueue::poll_current_thread(&*gl), 0);
+    }
+
+    #[test]
+    fn cancelling_the_fences_of_a_thread_cancels_their_tokens() {
+        let gl = record_gl_calls();
+        let first = FenceQueue::insert_for_current_thread(&*gl);
+        let second = FenceQueue::insert_for_current_thread(&*gl);
+        gl.take_calls();
+        FenceQueue::cancel_current_thread(&*gl);
+        assert_eq!(gl.take_calls(),
+                   vec!(GlCall::DeleteSync(GlSync(1)), GlCall::DeleteSync(GlSync(2))));
+        assert_eq!(block_on(first.future()), Err(Cancelled));
+        assert_eq!(block_on(second.future()), Err(Cancelled));
+        assert_eq!(FenceQueue::poll_current_thread(&*gl), 0);
+    }
+
+    #[test]
+    fn dropped_textures_are_deleted_when_the_queue_is_purged() {
+        let gl = record_gl_calls();
+        let queue = TextureReclaimQueue::for_current_thread();
+        let first = Texture::new_with_gl(gl.clone(),
+                                         TextureTarget::TextureTarget2D,
+                                         Size2D::new(4, 4));
+        let second = Texture::new_with_gl(gl.clone(),
+                                          TextureTarget::TextureTarget2D,
+                                          Size2D::new(4, 4));
+        let ids = vec!(first.native_texture(), second.native_texture());
+        gl.take_calls();
+
+        drop(first);
+        drop(second);
+        drop(Texture::zero());
+        assert_eq!(queue.len(), 2);
+        assert_eq!(gl.take_calls(), vec!());
+
+        assert_eq!(queue.purge(&*gl), 2);
+        assert!(queue.is_empty());
+        assert_eq!(gl.take_calls(), vec!(GlCall::DeleteTextures(ids)));
+        assert_eq!(queue.purge(&*gl), 0);
+        assert_eq!(gl.take_calls(), vec!());
+    }
+
+    #[test]
+    fn forgotten_textures_are_not_deleted() {
+        let gl = record_gl_calls();
+        drop(Texture::new_with_gl(gl.clone(), TextureTarget::TextureTarget2D, Size2D::new(4, 4)));
+        gl.take_calls();
+        let queue = TextureReclaimQueue::for_current_thread();
+        queue.forget();
+        assert!(queue.is_empty());
+        assert_eq!(queue.purge(&*gl), 0);
+        assert_eq!(gl.take_calls(), vec!());
+    }
+
+    #[test]
+    fn only_2d_textures_have_mipmaps() {
+        let gl = record_gl_calls();
+        let texture = Texture::new_with_gl(gl.clone(),
+                                           TextureTarget::TextureTarget2D,
+                                           Size2D::new(64, 64));
+        assert!(!texture.has_mipmaps());
+        gl.take_calls();
+        assert!(texture.generate_mipmaps().is_ok());
+        assert!(texture.has_mipmaps());
+        assert!(gl.take_calls().contains(&GlCall::GenerateMipmap(gl::TEXTURE_2D)));
+
+        let rectangle = Texture::new_with_gl(gl.clone(),
+                                             TextureTarget::TextureTargetRectangle,
+                                             Size2D::new(64, 64));
+        gl.take_calls();
+        match rectangle.generate_mipmaps() {
+            Err(SurfaceError::Unsupported) => {}
+            result => panic!("Expected Unsupported, got {:?}", result),
+        }
+        assert!(!rectangle.has_mipmaps());
+        assert!(gl.take_calls().is_empty());
+    }
+
+    #[test]
+    fn wrap_modes_set_both_directions() {
+        let gl = record_gl_calls();
+        let texture = Texture::new_with_gl(gl.clone(),
+                                           TextureTarget::TextureTarget2D,
+                                           Size2D::new(64, 64));
+        for &(mode, gl_mode) in &[(WrapMode::Repeat, gl::REPEAT),
+                                  (WrapMode::MirroredRepeat, gl::MIRRORED_REPEAT),
+                                  (WrapMode::ClampToEdge, gl::CLAMP_TO_EDGE)] {
+            gl.take_calls();
+            texture.set_wrap_mode(mode);
+            let calls = gl.take_calls();
+            assert!(calls.contains(&GlCall::TexParameteri(gl::TEXTURE_2D,
+                                                          gl::TEXTURE_WRAP_S,
+                                                          gl_mode as GLint)));
+            assert!(calls.contains(&GlCall::TexParameteri(gl::TEXTURE_2D,
+                                                          gl::TEXTURE_WRAP_T,
+                                                          gl_mode as GLint)));
+        }
+    }
+}
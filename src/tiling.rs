@@ -7,42 +7,149 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use geometry::{DevicePixel, LayerPixel};
-use layers::{BufferRequest, ContentAge, LayerBuffer};
-use platform::surface::NativeDisplay;
-use texturegl::Texture;
-use util::project_rect_to_screen;
+//! Tiling of layers.
+//!
+//! Each tile follows a simple protocol with the painter, based on the content age of its layer:
+//!
+//! * A buffer is requested for a tile when its buffer is older than the current content age and
+//!   no request for the current content age is pending. The tile then remembers the content age
+//!   of the pending request.
+//! * When a buffer arrives, it replaces the buffer of the tile unless it is older than that
+//!   buffer. The pending marker is only cleared if the buffer satisfies the pending request,
+//!   that is, if it is at least as new as the pending content age and has the requested size.
+//!   An older buffer is shown in the meantime, but the pending request stays pending, so it is
+//!   neither lost nor issued twice.
+//! * A buffer whose size doesn't match its tile, because it was requested before the tile or
+//!   layer size changed, is never shown. It is put with the unused buffers, and a request for
+//!   the new size stays pending.
+//! * Discarding a tile's buffer also forgets any pending request, so that the tile is requested
+//!   again. A buffer arriving later for a discarded request is still accepted as an interim.
+//!
+//! The painter must eventually answer every request; a request that is dropped leaves its tile
+//! pending until the content age changes again.
+//!
+//! Content ages of a later epoch are newer than all ages of earlier epochs. Once the content age
+//! of a layer moves to a new epoch, for instance because the painter was restarted, all its
+//! tiles are requested again, and buffers painted by the previous painter that arrive later are
+//! only shown as interims.
+//!
+//! Tiles of double-buffered layers keep their previous buffer as a back buffer when a new one
+//! arrives, instead of giving it up. The next request for the tile hands the surface of the back
+//! buffer to the painter, via `BufferRequest::native_surface`. The compositor only ever binds the
+//! front buffer, and the front buffer only changes when a buffer arrives, so the painter never
+//! paints into a surface that is being sampled. Requests of other tiles are handed the surfaces
+//! of unused buffers of the same size and resolution, if there are any, so that scrolling
+//! recycles surfaces instead of allocating new ones.
+//!
+//! A buffer whose resolution doesn't match the scene scale can be rejected with
+//! `reject_buffer`, which clears the pending marker so that the tile is requested again at the
+//! right scale. If the painter keeps answering with the same mismatched resolution, the tile
+//! would be requested forever; so once a tile has been rejected more than
+//! `RESOLUTION_LOOP_REJECTION_LIMIT` times at the same content age within
+//! `RESOLUTION_LOOP_FRAME_WINDOW` frames, the buffer is accepted instead.
+//!
+//! When the scale changes, pending requests are forgotten so that the tiles are requested again
+//! at the new scale. Buffers painted for the forgotten requests are rejected without clearing
+//! the new requests or counting towards the limit.
+//!
+//! Requests carry the scale of the request pass that issued them. `add_buffer` can also check the
+//! resolution itself, against the scale of the last request pass; a mismatching buffer is then
+//! kept out of the tile the same way, but without loop detection.
+
+use capabilities::Capabilities;
+use frame::FrameIndex;
+use geometry::{DevicePixel, LayerPixel, screen_to_device_scale};
+use gltrace::GlApi;
+use layers::{BufferRequest, BufferRequestPriority, ContentAge, LayerBuffer, RequestOrdering};
+use layers::TileSnapshot;
+use platform::surface::{NativeDisplay, NativeSurface};
+use texturegl::{PboPool, Texture};
+use util::{inflate_rect, is_axis_aligned_2d_transform, project_rect_to_screen, subtract_rect};
 
 use euclid::length::Length;
-use euclid::{Matrix4D, Point2D, TypedPoint2D};
+use euclid::{Matrix2D, Matrix4D, Point2D, TypedPoint2D};
 use euclid::rect::{Rect, TypedRect};
+use euclid::scale_factor::ScaleFactor;
 use euclid::size::{Size2D, TypedSize2D};
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
 use std::mem;
+use std::rc::Rc;
+use std::usize;
+
+/// The number of times a buffer for a tile may be rejected because of its resolution, at the
+/// same content age, before it is accepted anyway.
+pub const RESOLUTION_LOOP_REJECTION_LIMIT: u32 = 3;
+
+/// The number of frames within which rejections count towards
+/// `RESOLUTION_LOOP_REJECTION_LIMIT`.
+pub const RESOLUTION_LOOP_FRAME_WINDOW: u32 = 60;
+
+/// The buffers of a tile rejected because of their resolution, at a single content age.
+#[derive(Clone, Copy)]
+struct ResolutionRejections {
+    content_age: ContentAge,
+    first_frame: FrameIndex,
+    count: u32,
+}
 
 pub struct Tile {
     /// The buffer displayed by this tile.
     buffer: Option<Box<LayerBuffer>>,
 
+    /// For double-buffered tiles, the previously displayed buffer, whose surface is handed to
+    /// the painter with the next request.
+    back_buffer: Option<Box<LayerBuffer>>,
+
     /// The content age of any pending buffer request to avoid re-requesting
     /// a buffer while waiting for it to come back from rendering.
     content_age_of_pending_buffer: Option<ContentAge>,
 
+    /// The size in device pixels of the buffer requested by the pending request, if any.
+    pending_buffer_size: Option<Size2D<usize>>,
+
     /// A handle to the GPU texture.
     pub texture: Texture,
 
     /// The tile boundaries in the parent layer coordinates.
     pub bounds: Option<TypedRect<f32, LayerPixel>>,
+
+    /// The part of `bounds` that is drawn, in the same coordinates, if the layer shrank into the
+    /// tile since its buffer was painted. Only the part of the buffer within the layer is drawn
+    /// until the tile is painted again.
+    pub clip_rect: Option<TypedRect<f32, LayerPixel>>,
+
+    /// The last frame in which this tile was drawn, if any.
+    pub last_drawn_frame: Cell<Option<FrameIndex>>,
+
+    /// The buffers rejected for this tile because of their resolution since the last buffer
+    /// was accepted.
+    resolution_rejections: Option<ResolutionRejections>,
+
+    /// The resolution of the request that was pending when the scale last changed, if any.
+    /// Buffers painted for it are rejected without counting as rejections.
+    superseded_resolution: Option<f32>,
+
+    /// The number of buffers this tile has displayed.
+    paint_count: u32,
 }
 
 impl Tile {
     fn new() -> Tile {
         Tile {
             buffer: None,
+            back_buffer: None,
             texture: Texture::zero(),
             content_age_of_pending_buffer: None,
+            pending_buffer_size: None,
             bounds: None,
+            clip_rect: None,
+            last_drawn_frame: Cell::new(None),
+            resolution_rejections: None,
+            superseded_resolution: None,
+            paint_count: 0,
         }
     }
 
@@ -53,37 +160,259 @@ impl Tile {
         }
     }
 
-    fn replace_buffer(&mut self, buffer: Box<LayerBuffer>) -> Option<Box<LayerBuffer>> {
+    /// Returns true if this tile displays a buffer painted for content older than the given
+    /// content age, which a repaint will replace.
+    pub fn is_stale(&self, current_age: ContentAge) -> bool {
+        self.buffer.as_ref().map_or(false, |buffer| buffer.content_age < current_age)
+    }
+
+    /// Returns how many content ages the buffer of this tile is behind the given current age of
+    /// its layer, or None if the tile has no buffer. A buffer of an earlier epoch is `usize::MAX`
+    /// ages behind.
+    pub fn content_ages_behind(&self, current_age: ContentAge) -> Option<usize> {
+        self.buffer.as_ref().map(|buffer| {
+            current_age.ages_since(buffer.content_age).unwrap_or(usize::MAX)
+        })
+    }
+
+    /// Returns the number of buffers this tile has displayed, which is how many times it was
+    /// painted.
+    pub fn paint_count(&self) -> u32 {
+        self.paint_count
+    }
+
+    /// Returns true if this tile waits for a buffer requested at the given content age or a
+    /// newer one.
+    fn is_awaiting_buffer_for(&self, current_age: ContentAge) -> bool {
+        self.content_age_of_pending_buffer.map_or(false, |pending| pending >= current_age)
+    }
+
+    /// Makes the given buffer the front buffer. Returns the buffer that is no longer used, if
+    /// any. When double buffered, the old front buffer becomes the back buffer instead.
+    fn replace_buffer(&mut self, buffer: Box<LayerBuffer>, double_buffered: bool)
+                      -> Option<Box<LayerBuffer>> {
         if !self.should_use_new_buffer(&buffer) {
             warn!("Layer received an old buffer.");
             return Some(buffer);
         }
 
+        // A buffer for an older request must not clear the marker of a newer pending request,
+        // or the newer request would never be issued again. Neither must a buffer of a
+        // different size, requested before the tile size or the layer size changed.
+        let satisfies_pending_request = match self.content_age_of_pending_buffer {
+            Some(pending_content_age) => {
+                buffer.content_age >= pending_content_age &&
+                self.pending_buffer_size.map_or(true, |size| size == buffer.screen_pos.size)
+            }
+            None => true,
+        };
+        if satisfies_pending_request {
+            self.content_age_of_pending_buffer = None;
+            self.pending_buffer_size = None;
+        }
+        self.resolution_rejections = None;
+        self.superseded_resolution = None;
+
         let old_buffer = self.buffer.take();
         self.buffer = Some(buffer);
-        self.texture = Texture::zero(); // The old texture is bound to the old buffer.
+        self.clip_rect = None;
+        self.paint_count = self.paint_count.saturating_add(1);
+        // The old texture is bound to the old buffer.
+        self.drop_texture(old_buffer.as_ref().map(|buffer| &**buffer));
+        if double_buffered {
+            mem::replace(&mut self.back_buffer, old_buffer)
+        } else {
+            old_buffer
+        }
+    }
+
+    /// Clears the pending marker if it is for the given content age or an older one, so that the
+    /// tile is requested again.
+    fn forget_pending_request(&mut self, content_age: ContentAge) {
+        if self.content_age_of_pending_buffer.map_or(true, |pending| content_age >= pending) {
+            self.content_age_of_pending_buffer = None;
+            self.pending_buffer_size = None;
+        }
+    }
+
+    /// Clears the pending marker if the given buffer, which can't be shown, answers the pending
+    /// request, so that the tile is requested again. A request for another size, issued after
+    /// the tile size or the layer size changed, stays pending.
+    fn forget_request_answered_by(&mut self, buffer: &LayerBuffer) {
+        if self.pending_buffer_size.map_or(true, |size| size == buffer.screen_pos.size) {
+            self.forget_pending_request(buffer.content_age);
+        }
+    }
+
+    /// Forgets the pending request, if any, because the scale changed from the given resolution,
+    /// so that the tile is requested again at the new scale.
+    fn forget_request_at_resolution(&mut self, resolution: f32) {
+        if self.content_age_of_pending_buffer.is_some() {
+            self.content_age_of_pending_buffer = None;
+            self.pending_buffer_size = None;
+            self.superseded_resolution = Some(resolution);
+        }
+    }
+
+    /// Records that the given buffer was rejected because of its resolution, and clears the
+    /// pending marker so that the tile is requested again. Returns false instead, without
+    /// clearing anything, if the tile has been rejected too often; see the module docs. A buffer
+    /// painted for a request superseded by a change of scale leaves the tile alone.
+    fn reject_buffer(&mut self, buffer: &LayerBuffer, frame: FrameIndex) -> bool {
+        if self.superseded_resolution == Some(buffer.resolution) {
+            return true;
+        }
+
+        let content_age = buffer.content_age;
+        let rejections = match self.resolution_rejections {
+            Some(rejections) if rejections.content_age == content_age &&
+                                frame.0.wrapping_sub(rejections.first_frame.0) <
+                                RESOLUTION_LOOP_FRAME_WINDOW => {
+                ResolutionRejections {
+                    count: rejections.count + 1,
+                    ..rejections
+                }
+            }
+            _ => {
+                ResolutionRejections {
+                    content_age: content_age,
+                    first_frame: frame,
+                    count: 1,
+                }
+            }
+        };
+        if rejections.count > RESOLUTION_LOOP_REJECTION_LIMIT {
+            return false;
+        }
+
+        self.resolution_rejections = Some(rejections);
+        self.forget_pending_request(content_age);
+        true
+    }
+
+    /// Throws away the buffers of this tile so that it is requested again.
+    fn discard_buffer(&mut self) -> Vec<Box<LayerBuffer>> {
         self.content_age_of_pending_buffer = None;
-        old_buffer
+        self.pending_buffer_size = None;
+        self.superseded_resolution = None;
+        self.bounds = None;
+        self.take_buffers()
+    }
+
+    /// Takes the front and back buffers of this tile, dropping its texture.
+    fn take_buffers(&mut self) -> Vec<Box<LayerBuffer>> {
+        let buffer = self.buffer.take();
+        self.clip_rect = None;
+        self.drop_texture(buffer.as_ref().map(|buffer| &**buffer));
+        buffer.into_iter().chain(self.back_buffer.take().into_iter()).collect()
+    }
+
+    /// Drops the texture of this tile, which was bound to the given buffer, and releases what the
+    /// binding holds on to.
+    fn drop_texture(&mut self, bound_buffer: Option<&LayerBuffer>) {
+        if !self.texture.is_zero() {
+            if let Some(buffer) = bound_buffer {
+                buffer.native_surface.release_texture_binding();
+            }
+        }
+        self.texture = Texture::zero();
     }
 
-    fn create_texture(&mut self, display: &NativeDisplay) {
+    /// Creates the texture of this tile with `gl` if it has a buffer but no texture yet. Mipmaps
+    /// are generated for buffers painted at a resolution below `mipmap_scale_threshold`, if given.
+    fn create_texture(&mut self,
+                      gl: &Rc<GlApi>,
+                      display: &NativeDisplay,
+                      capabilities: &Capabilities,
+                      mipmap_scale_threshold: Option<f32>) {
         if let Some(ref buffer) = self.buffer {
             // If we already have a texture it should still be valid.
             if !self.texture.is_zero() {
                 return;
             }
 
-            // Make a new texture and bind the LayerBuffer's surface to it.
-            self.texture = Texture::new_with_buffer(buffer);
+            // Make a new texture and bind the LayerBuffer's surface to it. A buffer that is too
+            // large for a texture would be drawn as garbage, so it isn't drawn at all.
+            self.texture = match Texture::try_new_with_buffer(gl.clone(), buffer, capabilities) {
+                Ok(texture) => texture,
+                Err(error) => {
+                    warn!("Not drawing tile: {}", error);
+                    return;
+                }
+            };
             debug!("Tile: binding to native surface {}",
                    buffer.native_surface.get_id() as isize);
-            buffer.native_surface.bind_to_texture(display, &self.texture);
+            // Memory surfaces are uploaded through the pixel buffer objects of the current thread
+            // if `RenderContext::set_use_pbo_uploads` turned them on.
+            let result = match buffer.native_surface {
+                NativeSurface::MemoryBuffer(ref surface) => {
+                    let texture = &self.texture;
+                    PboPool::with_current_thread(|pool| {
+                        match pool {
+                            Some(pool) => surface.bind_to_texture_via_pbo(pool, texture),
+                            None => surface.bind_to_texture(display, texture),
+                        }
+                    })
+                }
+                ref surface => surface.bind_to_texture(display, &self.texture),
+            };
+            if let Err(error) = result {
+                warn!("Not drawing tile: failed to bind its surface ({:?})", error);
+                buffer.native_surface.release_texture_binding();
+                self.texture = Texture::zero();
+                return;
+            }
+
+            if let Some(threshold) = mipmap_scale_threshold {
+                if buffer.resolution < threshold {
+                    if let Err(error) = self.texture.generate_mipmaps() {
+                        debug!("Tile: not generating mipmaps ({:?})", error);
+                    }
+                }
+            }
 
             // Set the layer's rect.
             self.bounds = Some(TypedRect::from_untyped(&buffer.rect));
         }
     }
 
+    /// Combines the given clip rect, in the world coordinates of the layer whose origin is given,
+    /// with `clip_rect` of this tile. Returns None if the tile isn't clipped at all, and an empty
+    /// rect if nothing of it may be drawn.
+    pub fn combined_clip_rect(&self, layer_origin: &Point2D<f32>, clip_rect: Option<Rect<f32>>)
+                              -> Option<Rect<f32>> {
+        let tile_clip_rect = match self.clip_rect {
+            Some(tile_clip_rect) => tile_clip_rect.to_untyped().translate(layer_origin),
+            None => return clip_rect,
+        };
+        match clip_rect {
+            Some(clip_rect) => {
+                Some(clip_rect.intersection(&tile_clip_rect).unwrap_or(Rect::zero()))
+            }
+            None => Some(tile_clip_rect),
+        }
+    }
+
+    /// Returns true if this tile has a texture that can be drawn.
+    pub fn is_drawable(&self) -> bool {
+        !self.texture.is_zero() && self.bounds.is_some()
+    }
+
+    /// Returns the buffer displayed by this tile, if it has one.
+    pub fn buffer(&self) -> Option<&LayerBuffer> {
+        self.buffer.as_ref().map(|buffer| &**buffer)
+    }
+
+    /// Returns the content age of the buffer displayed by this tile, if it has one.
+    pub fn buffer_content_age(&self) -> Option<ContentAge> {
+        self.buffer.as_ref().map(|buffer| buffer.content_age)
+    }
+
+    /// Returns the content age of the pending request for this tile, if any.
+    pub fn pending_content_age(&self) -> Option<ContentAge> {
+        self.content_age_of_pending_buffer
+    }
+
     fn should_request_buffer(&self, content_age: ContentAge) -> bool {
         // Don't resend a request if our buffer's content age matches the current content age.
         if let Some(ref buffer) = self.buffer {
@@ -100,14 +429,152 @@ impl Tile {
     }
 }
 
+/// Counters describing the tiles of a tile grid, or of several grids added together.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, RustcEncodable)]
+pub struct TileGridStatistics {
+    /// The number of tiles.
+    pub tile_count: usize,
+
+    /// The number of tiles that have a front buffer.
+    pub tiles_with_buffers: usize,
+
+    /// The number of tiles waiting for a buffer from the painter.
+    pub tiles_with_pending_requests: usize,
+
+    /// The number of buffers that are no longer used and wait to be collected.
+    pub unused_buffer_count: usize,
+
+    /// The memory used by all buffers held, including back buffers and unused buffers, in
+    /// bytes.
+    pub buffer_memory: usize,
+}
+
+impl TileGridStatistics {
+    /// Adds the counters of `other` to these.
+    pub fn accumulate(&mut self, other: &TileGridStatistics) {
+        self.tile_count += other.tile_count;
+        self.tiles_with_buffers += other.tiles_with_buffers;
+        self.tiles_with_pending_requests += other.tiles_with_pending_requests;
+        self.unused_buffer_count += other.unused_buffer_count;
+        self.buffer_memory += other.buffer_memory;
+    }
+}
+
 pub struct TileGrid {
     pub tiles: HashMap<Point2D<usize>, Tile>,
 
-    /// The size of tiles in this grid in device pixels.
+    /// The size of tiles in this grid in device pixels. This is the requested tile size, clamped
+    /// to `max_tile_size`.
     tile_size: Length<usize, DevicePixel>,
 
+    /// The tile size last passed to `new` or `set_tile_size`.
+    requested_tile_size: usize,
+
+    /// The largest tile size that the compositor can display, if known. See `set_max_tile_size`.
+    max_tile_size: Option<usize>,
+
     // Buffers that are currently unused.
     unused_buffers: Vec<Box<LayerBuffer>>,
+
+    /// If set, this grid holds a single tile covering the whole layer instead of tiling it, and
+    /// this is the largest width and height in device pixels that the tile may have.
+    single_buffer_max_size: Option<usize>,
+
+    /// Whether tiles keep a back buffer for the painter to draw into. See the module docs.
+    double_buffered: bool,
+
+    /// The tiles that were not requested in the last request pass because they were hidden
+    /// behind opaque layers.
+    occluded_tiles: Vec<Point2D<usize>>,
+
+    /// The scale of the last request pass, which buffers added to this grid must match.
+    resolution: Option<f32>,
+
+    /// The size of the layer in the last request pass with a valid layer size. Buffers added to
+    /// this grid must have the size of their tile at this layer size.
+    layer_size: Option<TypedSize2D<f32, DevicePixel>>,
+
+    /// Whether request passes throw out the tiles outside their viewport. See
+    /// `set_evicts_on_request`.
+    evicts_on_request: bool,
+
+    /// The emptied vectors of the last applied update, which the next update fills again so that
+    /// request passes over unchanged grids don't allocate.
+    spare_vectors: RefCell<SpareUpdateVectors>,
+}
+
+/// See `TileGrid::spare_vectors`.
+#[derive(Default)]
+struct SpareUpdateVectors {
+    occluders: Vec<TypedRect<f32, DevicePixel>>,
+    tile_requests: Vec<TileRequest>,
+    occluded_tiles: Vec<Point2D<usize>>,
+    unused_tiles: Vec<Point2D<usize>>,
+}
+
+/// Returned when a layer is too large to be displayed with a single buffer.
+#[derive(Debug)]
+pub struct LayerTooLargeError;
+
+/// The parameters of a request pass.
+struct RequestPass {
+    dirty_rect: TypedRect<f32, DevicePixel>,
+    viewport: TypedRect<f32, DevicePixel>,
+    current_layer_size: TypedSize2D<f32, DevicePixel>,
+    layer_world_origin: TypedPoint2D<f32, LayerPixel>,
+    scale: ScaleFactor<f32, LayerPixel, DevicePixel>,
+    layer_transform: Matrix4D<f32>,
+    occluders: Vec<TypedRect<f32, DevicePixel>>,
+    keep_margin: f32,
+    current_content_age: ContentAge,
+    ordering: RequestOrdering,
+}
+
+/// A tile visited by a request pass, which is requested unless its buffer is up to date.
+struct TileRequest {
+    tile_index: Point2D<usize>,
+
+    /// The part of the tile that is not hidden behind occluders, if that is not the whole tile.
+    visible_rect: Option<Rect<usize>>,
+
+    priority: BufferRequestPriority,
+
+    /// The squared distance from the center of the tile to the center of the viewport, in
+    /// screen pixels, used to sort requests by `RequestOrdering::ViewportDistance`.
+    viewport_distance: f32,
+}
+
+/// The outcome of a request pass over a tile grid, as computed by
+/// `TileGrid::compute_buffer_requests` without changing the grid, and applied with
+/// `TileGrid::apply_update`.
+pub struct TileGridUpdate {
+    pass: RequestPass,
+
+    /// Set if the layer has grown too large for a single buffer. Nothing else is computed then;
+    /// the grid falls back to tiling and computes the pass again when the update is applied.
+    too_large_for_single_buffer: bool,
+
+    /// The tiles visited by the pass, in request order.
+    tile_requests: Vec<TileRequest>,
+
+    /// The tiles skipped by the pass because they are hidden behind occluders.
+    occluded_tiles: Vec<Point2D<usize>>,
+
+    /// The tiles outside the viewport, which are thrown out.
+    unused_tiles: Vec<Point2D<usize>>,
+
+    /// Whether applying this update skips the requests, only throwing out tiles. See
+    /// `drop_requests`.
+    drops_requests: bool,
+}
+
+impl TileGridUpdate {
+    /// Makes this update throw out the tiles outside the viewport without requesting any tiles
+    /// when it is applied. No pending marker is set, so the tiles are requested by the first
+    /// pass that keeps its requests.
+    pub fn drop_requests(&mut self) {
+        self.drops_requests = true;
+    }
 }
 
 pub fn rect_uint_as_rect_f32(rect: Rect<usize>) -> Rect<f32> {
@@ -115,28 +582,160 @@ pub fn rect_uint_as_rect_f32(rect: Rect<usize>) -> Rect<f32> {
                    Size2D::new(rect.size.width as f32, rect.size.height as f32))
 }
 
+/// Returns the position of a tile index along a Z-order curve, by interleaving the bits of its
+/// coordinates.
+fn morton_code(tile_index: Point2D<usize>) -> u64 {
+    fn spread_bits(value: u32) -> u64 {
+        let mut value = value as u64;
+        value = (value | (value << 16)) & 0x0000ffff0000ffff;
+        value = (value | (value << 8)) & 0x00ff00ff00ff00ff;
+        value = (value | (value << 4)) & 0x0f0f0f0f0f0f0f0f;
+        value = (value | (value << 2)) & 0x3333333333333333;
+        value = (value | (value << 1)) & 0x5555555555555555;
+        value
+    }
+    spread_bits(tile_index.x as u32) | (spread_bits(tile_index.y as u32) << 1)
+}
+
+/// Returns the given tile size, clamped to the maximum tile size if there is one. A maximum of
+/// zero, which is what a broken GL implementation might report, is ignored.
+pub fn clamp_tile_size(tile_size: usize, max_tile_size: Option<usize>) -> usize {
+    match max_tile_size {
+        Some(max_tile_size) if max_tile_size != 0 && tile_size > max_tile_size => {
+            warn!("Clamping tile size {} to the maximum texture size {}.",
+                  tile_size,
+                  max_tile_size);
+            max_tile_size
+        }
+        _ => tile_size,
+    }
+}
+
 impl TileGrid {
     pub fn new(tile_size: usize) -> TileGrid {
         TileGrid {
             tiles: HashMap::new(),
             tile_size: Length::new(tile_size),
+            requested_tile_size: tile_size,
+            max_tile_size: None,
             unused_buffers: Vec::new(),
+            single_buffer_max_size: None,
+            double_buffered: false,
+            occluded_tiles: Vec::new(),
+            resolution: None,
+            layer_size: None,
+            evicts_on_request: true,
+            spare_vectors: RefCell::new(SpareUpdateVectors::default()),
+        }
+    }
+
+    /// Returns the size of the tiles in this grid in device pixels.
+    pub fn tile_size(&self) -> usize {
+        self.tile_size.get()
+    }
+
+    /// Changes the size of the tiles in this grid. All tiles are thrown out and their buffers
+    /// become unused, so the next request pass requests the whole layer again with the new tile
+    /// size. The content age is not changed. Buffers requested with the old tile size that
+    /// arrive later are put with the unused buffers.
+    ///
+    /// The tile size is clamped to the maximum set with `set_max_tile_size`.
+    pub fn set_tile_size(&mut self, new_size: usize) {
+        self.requested_tile_size = new_size;
+        let new_size = clamp_tile_size(new_size, self.max_tile_size);
+        if new_size == self.tile_size.get() {
+            return;
         }
+        let buffers: Vec<_> =
+            self.tiles.drain().flat_map(|(_, mut tile)| tile.take_buffers()).collect();
+        self.unused_buffers.extend(buffers.into_iter());
+        self.occluded_tiles.clear();
+        self.tile_size = Length::new(new_size);
+    }
+
+    /// Sets the largest tile size that can be displayed, usually the maximum texture size of the
+    /// GL implementation. Larger tile sizes are clamped to it, now and in later calls to
+    /// `set_tile_size`; if this changes the tile size, the tiles are thrown out as with
+    /// `set_tile_size`.
+    pub fn set_max_tile_size(&mut self, max_tile_size: Option<usize>) {
+        if max_tile_size == self.max_tile_size {
+            return;
+        }
+        self.max_tile_size = max_tile_size;
+        let requested_tile_size = self.requested_tile_size;
+        self.set_tile_size(requested_tile_size);
+    }
+
+    /// Switches between tiling the layer and displaying it with a single buffer no larger than
+    /// `max_size` device pixels in either dimension. When the mode changes, all existing tiles
+    /// are thrown out and their buffers become unused, so the new content is requested from
+    /// scratch. In single buffer mode, a request pass at a new layer size throws out the buffer
+    /// and requests one of the new size.
+    pub fn set_single_buffer_mode(&mut self, max_size: Option<usize>) {
+        if self.single_buffer_max_size.is_some() != max_size.is_some() {
+            let buffers: Vec<_> =
+                self.tiles.drain().flat_map(|(_, mut tile)| tile.take_buffers()).collect();
+            self.unused_buffers.extend(buffers.into_iter());
+        }
+        self.single_buffer_max_size = max_size;
+    }
+
+    /// Enables or disables double buffering of tiles. When disabled, the back buffers become
+    /// unused.
+    pub fn set_double_buffered(&mut self, double_buffered: bool) {
+        if !double_buffered {
+            for tile in self.tiles.values_mut() {
+                if let Some(back_buffer) = tile.back_buffer.take() {
+                    self.unused_buffers.push(back_buffer);
+                }
+            }
+        }
+        self.double_buffered = double_buffered;
+    }
+
+    /// Returns true if tiles of this grid are double buffered.
+    pub fn is_double_buffered(&self) -> bool {
+        self.double_buffered
+    }
+
+    /// Sets whether each request pass throws out the tiles outside its viewport and keep margin,
+    /// which is the default. When disabled, tiles are only thrown out by `evict_tiles_outside`,
+    /// so that a pass over a viewport that is only briefly valid, such as one past the content
+    /// edge while overscrolling, does not throw out tiles that are needed again right after.
+    pub fn set_evicts_on_request(&mut self, evicts_on_request: bool) {
+        self.evicts_on_request = evicts_on_request;
+    }
+
+    /// Returns true if request passes throw out the tiles outside their viewport.
+    pub fn evicts_on_request(&self) -> bool {
+        self.evicts_on_request
+    }
+
+    /// Returns true if this grid displays its layer with a single buffer.
+    pub fn is_single_buffer(&self) -> bool {
+        self.single_buffer_max_size.is_some()
     }
 
     pub fn get_rect_for_tile_index(&self,
                                    tile_index: Point2D<usize>,
                                    current_layer_size: TypedSize2D<f32, DevicePixel>)
                                    -> TypedRect<usize, DevicePixel> {
+        if self.is_single_buffer() {
+            debug_assert!(tile_index == Point2D::new(0, 0));
+            let size = TypedSize2D::new(current_layer_size.width.ceil().max(0.0) as usize,
+                                        current_layer_size.height.ceil().max(0.0) as usize);
+            return TypedRect::new(TypedPoint2D::zero(), size);
+        }
 
         let origin : TypedPoint2D<usize, DevicePixel> =
             TypedPoint2D::new(self.tile_size.get() * tile_index.x,
                               self.tile_size.get() * tile_index.y);
 
-        // Don't let tiles extend beyond the layer boundaries.
+        // Don't let tiles extend beyond the layer boundaries, and keep tiles past them, or in
+        // layers with invalid sizes, empty.
         let tile_size = self.tile_size.get() as f32;
-        let size = Size2D::new(tile_size.min(current_layer_size.width - origin.x as f32),
-                               tile_size.min(current_layer_size.height - origin.y as f32));
+        let size = Size2D::new(tile_size.min(current_layer_size.width - origin.x as f32).max(0.0),
+                               tile_size.min(current_layer_size.height - origin.y as f32).max(0.0));
 
         // Round up to texture pixels.
         let size = TypedSize2D::new(size.width.ceil() as usize, size.height.ceil() as usize);
@@ -156,41 +755,103 @@ impl TileGrid {
         }
     }
 
+    /// Returns the device-space bounding box of the given tile once transformed to the screen,
+    /// or None if it is entirely clipped.
+    fn get_transformed_rect_for_tile_index(&self,
+                                           tile_index: &Point2D<usize>,
+                                           current_layer_size: TypedSize2D<f32, DevicePixel>,
+                                           layer_world_origin: &TypedPoint2D<f32, LayerPixel>,
+                                           scale: ScaleFactor<f32, LayerPixel, DevicePixel>,
+                                           layer_transform: &Matrix4D<f32>)
+                                           -> Option<TypedRect<f32, DevicePixel>> {
+        let tile_rect = self.get_rect_for_tile_index(*tile_index,
+                                                     current_layer_size);
+        let tile_rect = (tile_rect.to_f32() / scale).translate(layer_world_origin);
+
+        project_rect_to_screen(&tile_rect, layer_transform).map(|screen_rect| {
+            screen_rect.rect * screen_to_device_scale(scale)
+        })
+    }
+
+    /// Returns true if the given tile, once transformed to the screen and scaled to device
+    /// pixels, intersects the given device-space rect.
     pub fn tile_intersects_rect(&self,
                                 tile_index: &Point2D<usize>,
-                                test_rect: &Rect<f32>,
+                                test_rect: &TypedRect<f32, DevicePixel>,
                                 current_layer_size: TypedSize2D<f32, DevicePixel>,
-                                layer_world_origin: &Point2D<f32>,
+                                layer_world_origin: &TypedPoint2D<f32, LayerPixel>,
+                                scale: ScaleFactor<f32, LayerPixel, DevicePixel>,
                                 layer_transform: &Matrix4D<f32>) -> bool {
-        let tile_rect = self.get_rect_for_tile_index(*tile_index,
-                                                     current_layer_size);
-        let tile_rect = tile_rect.to_f32()
-                                 .to_untyped()
-                                 .translate(layer_world_origin);
+        match self.get_transformed_rect_for_tile_index(tile_index,
+                                                       current_layer_size,
+                                                       layer_world_origin,
+                                                       scale,
+                                                       layer_transform) {
+            Some(device_rect) => device_rect.intersection(test_rect).is_some(),
+            None => false,
+        }
+    }
 
-        let screen_rect = project_rect_to_screen(&tile_rect, layer_transform);
+    /// Subtracts the occluders from the transformed rect of a tile. Returns None if the tile is
+    /// entirely hidden, or the part of the tile rect that is visible otherwise. The visible part
+    /// can only be mapped back to the tile for axis-aligned layer transforms; for other
+    /// transforms, the whole tile is considered visible unless it is entirely hidden.
+    fn get_visible_rect_for_tile(tile_rect: &TypedRect<usize, DevicePixel>,
+                                 transformed_tile_rect: &TypedRect<f32, DevicePixel>,
+                                 layer_transform: &Matrix4D<f32>,
+                                 occluders: &[TypedRect<f32, DevicePixel>])
+                                 -> Option<TypedRect<usize, DevicePixel>> {
+        let mut visible_rect = *transformed_tile_rect;
+        for occluder in occluders {
+            visible_rect = match subtract_rect(&visible_rect, occluder) {
+                Some(visible_rect) => visible_rect,
+                None => return None,
+            };
+        }
 
-        if let Some(screen_rect) = screen_rect {
-            if screen_rect.rect.intersection(&test_rect).is_some() {
-                return true;
-            }
+        if visible_rect == *transformed_tile_rect ||
+           !is_axis_aligned_2d_transform(layer_transform) {
+            return Some(*tile_rect);
         }
 
-        false
+        // Map the visible part back to the tile, rounding outwards.
+        let tile_rect_f32 = tile_rect.to_f32();
+        let x_scale = tile_rect_f32.size.width / transformed_tile_rect.size.width;
+        let y_scale = tile_rect_f32.size.height / transformed_tile_rect.size.height;
+        let min_x = tile_rect_f32.origin.x +
+            (visible_rect.min_x() - transformed_tile_rect.origin.x) * x_scale;
+        let min_y = tile_rect_f32.origin.y +
+            (visible_rect.min_y() - transformed_tile_rect.origin.y) * y_scale;
+        let max_x = tile_rect_f32.origin.x +
+            (visible_rect.max_x() - transformed_tile_rect.origin.x) * x_scale;
+        let max_y = tile_rect_f32.origin.y +
+            (visible_rect.max_y() - transformed_tile_rect.origin.y) * y_scale;
+
+        let min_x = (min_x.floor().max(0.0) as usize).max(tile_rect.origin.x);
+        let min_y = (min_y.floor().max(0.0) as usize).max(tile_rect.origin.y);
+        let max_x = (max_x.ceil().max(0.0) as usize).min(tile_rect.max_x());
+        let max_y = (max_y.ceil().max(0.0) as usize).min(tile_rect.max_y());
+        if max_x <= min_x || max_y <= min_y {
+            return None;
+        }
+        Some(TypedRect::new(TypedPoint2D::new(min_x, min_y),
+                            TypedSize2D::new(max_x - min_x, max_y - min_y)))
     }
 
     pub fn mark_tiles_outside_of_rect_as_unused(&mut self,
                                                 rect: TypedRect<f32, DevicePixel>,
-                                                layer_world_origin: &Point2D<f32>,
+                                                layer_world_origin: &TypedPoint2D<f32, LayerPixel>,
+                                                scale: ScaleFactor<f32, LayerPixel, DevicePixel>,
                                                 layer_transform: &Matrix4D<f32>,
                                                 current_layer_size: TypedSize2D<f32, DevicePixel>) {
         let mut tile_indexes_to_take = Vec::new();
 
         for tile_index in self.tiles.keys() {
             if !self.tile_intersects_rect(tile_index,
-                                          &rect.to_untyped(),
+                                          &rect,
                                           current_layer_size,
                                           layer_world_origin,
+                                          scale,
                                           layer_transform) {
                 tile_indexes_to_take.push(tile_index.clone());
             }
@@ -198,14 +859,125 @@ impl TileGrid {
 
         for tile_index in &tile_indexes_to_take {
             if let Some(ref mut tile) = self.tiles.remove(tile_index) {
-                self.add_unused_buffer(tile.buffer.take());
+                self.unused_buffers.extend(tile.take_buffers().into_iter());
             }
         }
     }
 
+    /// Throws out the tiles that don't intersect the given rect grown by `keep_margin` device
+    /// pixels, and puts their buffers with the unused buffers. This is what request passes do
+    /// unless `set_evicts_on_request` disabled it.
+    pub fn evict_tiles_outside(&mut self,
+                               rect: TypedRect<f32, DevicePixel>,
+                               keep_margin: f32,
+                               layer_world_origin: &TypedPoint2D<f32, LayerPixel>,
+                               scale: ScaleFactor<f32, LayerPixel, DevicePixel>,
+                               layer_transform: &Matrix4D<f32>,
+                               current_layer_size: TypedSize2D<f32, DevicePixel>) {
+        self.mark_tiles_outside_of_rect_as_unused(inflate_rect(&rect, keep_margin.max(0.0)),
+                                                  layer_world_origin,
+                                                  scale,
+                                                  layer_transform,
+                                                  current_layer_size);
+    }
+
+    /// Deletes the textures of the tiles that don't intersect the given rect, keeping their
+    /// buffers and bounds. The textures are created again by `create_textures` once the tiles
+    /// are drawn again, without asking the painter for anything. Until then the tiles are not
+    /// drawable.
+    pub fn evict_textures_outside_rect(&mut self,
+                                       rect: TypedRect<f32, DevicePixel>,
+                                       layer_world_origin: &TypedPoint2D<f32, LayerPixel>,
+                                       scale: ScaleFactor<f32, LayerPixel, DevicePixel>,
+                                       layer_transform: &Matrix4D<f32>,
+                                       current_layer_size: TypedSize2D<f32, DevicePixel>) {
+        let mut tile_indexes_to_evict = Vec::new();
+
+        for (tile_index, tile) in &self.tiles {
+            if !tile.texture.is_zero() &&
+               !self.tile_intersects_rect(tile_index,
+                                          &rect,
+                                          current_layer_size,
+                                          layer_world_origin,
+                                          scale,
+                                          layer_transform) {
+                tile_indexes_to_evict.push(tile_index.clone());
+            }
+        }
+
+        for tile_index in &tile_indexes_to_evict {
+            if let Some(tile) = self.tiles.get_mut(tile_index) {
+                let buffer = tile.buffer.take();
+                tile.drop_texture(buffer.as_ref().map(|buffer| &**buffer));
+                tile.buffer = buffer;
+            }
+        }
+    }
+
+    /// Updates the tiles for a change in layer size, given in device pixels at the given scale.
+    /// Tiles whose buffer still covers their rect at the new size are kept; if the layer shrank
+    /// into them, only the part of their buffer within the layer is drawn. Edge tiles that grew
+    /// beyond their buffer are discarded so that they are requested again, and tiles entirely
+    /// outside the new size are thrown out. Pending requests for the old size are forgotten, so
+    /// that the tiles are requested again at the new size.
+    pub fn handle_layer_size_change(&mut self,
+                                    new_layer_size: TypedSize2D<f32, DevicePixel>,
+                                    scale: ScaleFactor<f32, LayerPixel, DevicePixel>) {
+        let mut tile_indexes_to_take = Vec::new();
+        let mut tile_indexes_to_discard = Vec::new();
+
+        let new_rects: Vec<_> = self.tiles.keys().map(|tile_index| {
+            (*tile_index, self.get_rect_for_tile_index(*tile_index, new_layer_size))
+        }).collect();
+        for (tile_index, new_rect) in new_rects {
+            if new_rect.origin.x as f32 >= new_layer_size.width ||
+               new_rect.origin.y as f32 >= new_layer_size.height {
+                tile_indexes_to_take.push(tile_index);
+                continue;
+            }
+
+            let tile = self.tiles.get_mut(&tile_index).unwrap();
+
+            let new_size = new_rect.size.to_untyped();
+            if tile.pending_buffer_size.map_or(false, |size| size != new_size) {
+                tile.content_age_of_pending_buffer = None;
+                tile.pending_buffer_size = None;
+            }
+
+            let buffer_size = match tile.buffer {
+                Some(ref buffer) => buffer.screen_pos.size,
+                None => continue,
+            };
+            if buffer_size.width < new_size.width || buffer_size.height < new_size.height {
+                tile_indexes_to_discard.push(tile_index);
+            } else if buffer_size != new_size {
+                tile.clip_rect = Some(new_rect.to_f32() / scale);
+            } else {
+                tile.clip_rect = None;
+            }
+        }
+
+        for tile_index in &tile_indexes_to_take {
+            if let Some(ref mut tile) = self.tiles.remove(tile_index) {
+                self.unused_buffers.extend(tile.take_buffers().into_iter());
+            }
+        }
+
+        for tile_index in &tile_indexes_to_discard {
+            let buffers = self.tiles.get_mut(tile_index).unwrap().discard_buffer();
+            self.unused_buffers.extend(buffers.into_iter());
+        }
+
+        // Buffers requested at the old size don't fit any more.
+        if self.layer_size.is_some() {
+            self.layer_size = Some(new_layer_size);
+        }
+    }
+
     pub fn get_buffer_request_for_tile(&mut self,
                                        tile_index: Point2D<usize>,
                                        current_layer_size: TypedSize2D<f32, DevicePixel>,
+                                       scale: ScaleFactor<f32, LayerPixel, DevicePixel>,
                                        current_content_age: ContentAge)
                                        -> Option<BufferRequest> {
         let tile_rect = self.get_rect_for_tile_index(tile_index, current_layer_size);
@@ -223,76 +995,566 @@ impl TileGrid {
         }
 
         tile.content_age_of_pending_buffer = Some(current_content_age);
+        tile.pending_buffer_size = Some(tile_rect.size.to_untyped());
+
+        let mut request = BufferRequest::new(tile_rect.to_untyped(),
+                                             tile_rect.to_f32().to_untyped(),
+                                             current_content_age);
+        request.resolution = scale.get();
 
-        Some(BufferRequest::new(tile_rect.to_untyped(),
-                                tile_rect.to_f32().to_untyped(),
-                                current_content_age))
+        // Layer pixels are scaled and then shifted by the integral origin of the tile, the same
+        // origin the compositor draws the tile at, so the shared edge of neighbouring tiles maps
+        // to the same device pixel in both.
+        request.paint_transform = Matrix2D::row_major(scale.get(), 0.0,
+                                                      0.0, scale.get(),
+                                                      -(tile_rect.origin.x as f32),
+                                                      -(tile_rect.origin.y as f32));
+
+        // Hand the surface of the back buffer to the painter. It is not displayed, so painting
+        // into it can't tear.
+        if let Some(back_buffer) = tile.back_buffer.take() {
+            let fits = back_buffer.screen_pos.size == tile_rect.size.to_untyped();
+            if self.double_buffered && fits {
+                let mut native_surface = back_buffer.native_surface;
+                native_surface.mark_wont_leak();
+                request.native_surface = Some(native_surface);
+            } else {
+                self.unused_buffers.push(back_buffer);
+            }
+        }
+
+        Some(request)
     }
 
-    /// Returns buffer requests inside the given dirty rect, and simultaneously throws out tiles
-    /// outside the given viewport rect.
+    /// Appends buffer requests inside the given dirty rect to `buffer_requests`, and
+    /// simultaneously throws out tiles farther than `keep_margin` device pixels outside the given
+    /// viewport rect. Tiles entirely hidden behind the given device-space occluders are not
+    /// requested. Tiles within the viewport are requested first, followed by those close to it
+    /// and then the rest of the dirty rect.
+    ///
+    /// This is `compute_buffer_requests` followed by `apply_update_into`.
     pub fn get_buffer_requests_in_rect(&mut self,
                                        dirty_rect: TypedRect<f32, DevicePixel>,
                                        viewport: TypedRect<f32, DevicePixel>,
                                        current_layer_size: TypedSize2D<f32, DevicePixel>,
-                                       layer_world_origin: &Point2D<f32>,
+                                       layer_world_origin: &TypedPoint2D<f32, LayerPixel>,
+                                       scale: ScaleFactor<f32, LayerPixel, DevicePixel>,
                                        layer_transform: &Matrix4D<f32>,
-                                       current_content_age: ContentAge)
-                                       -> Vec<BufferRequest> {
-        let mut buffer_requests = Vec::new();
+                                       occluders: &[TypedRect<f32, DevicePixel>],
+                                       keep_margin: f32,
+                                       current_content_age: ContentAge,
+                                       ordering: RequestOrdering,
+                                       buffer_requests: &mut Vec<BufferRequest>) {
+        let update = self.compute_buffer_requests(dirty_rect,
+                                                  viewport,
+                                                  current_layer_size,
+                                                  layer_world_origin,
+                                                  scale,
+                                                  layer_transform,
+                                                  occluders.iter().cloned(),
+                                                  keep_margin,
+                                                  current_content_age,
+                                                  ordering);
+        self.apply_update_into(update, buffer_requests);
+    }
+
+    /// Works out, without changing the grid, which tiles a request pass with the given
+    /// parameters requests, in which order, and which tiles it throws out. The result is applied
+    /// with `apply_update`, which must be called before anything else changes the grid. The
+    /// update is `Send`, so the updates of several grids can be computed in parallel.
+    pub fn compute_buffer_requests<I>(&self,
+                                      dirty_rect: TypedRect<f32, DevicePixel>,
+                                      viewport: TypedRect<f32, DevicePixel>,
+                                      current_layer_size: TypedSize2D<f32, DevicePixel>,
+                                      layer_world_origin: &TypedPoint2D<f32, LayerPixel>,
+                                      scale: ScaleFactor<f32, LayerPixel, DevicePixel>,
+                                      layer_transform: &Matrix4D<f32>,
+                                      occluders: I,
+                                      keep_margin: f32,
+                                      current_content_age: ContentAge,
+                                      ordering: RequestOrdering)
+                                      -> TileGridUpdate
+                                      where I: IntoIterator<Item = TypedRect<f32, DevicePixel>> {
+        let mut occluders_vec = mem::replace(&mut self.spare_vectors.borrow_mut().occluders,
+                                             Vec::new());
+        occluders_vec.extend(occluders);
+        self.compute_update(RequestPass {
+            dirty_rect: dirty_rect,
+            viewport: viewport,
+            current_layer_size: current_layer_size,
+            layer_world_origin: *layer_world_origin,
+            scale: scale,
+            layer_transform: *layer_transform,
+            occluders: occluders_vec,
+            keep_margin: keep_margin,
+            current_content_age: current_content_age,
+            ordering: ordering,
+        })
+    }
 
-        // Get the range of tiles that can fit into the current layer size.
-        // Step through each, transform/clip them to 2d rect
-        // Check if visible against rect
+    fn compute_update(&self, pass: RequestPass) -> TileGridUpdate {
+        let mut spare_vectors = self.spare_vectors.borrow_mut();
+        let mut update = TileGridUpdate {
+            pass: pass,
+            too_large_for_single_buffer: false,
+            tile_requests: mem::replace(&mut spare_vectors.tile_requests, Vec::new()),
+            occluded_tiles: mem::replace(&mut spare_vectors.occluded_tiles, Vec::new()),
+            unused_tiles: mem::replace(&mut spare_vectors.unused_tiles, Vec::new()),
+            drops_requests: false,
+        };
+        drop(spare_vectors);
 
-        let tile_size = self.tile_size.get() as f32;
-        let x_tile_count = ((current_layer_size.to_untyped().width + tile_size - 1.0) / tile_size) as usize;
-        let y_tile_count = ((current_layer_size.to_untyped().height + tile_size - 1.0) / tile_size) as usize;
-
-        for x in 0..x_tile_count {
-            for y in 0..y_tile_count {
-                let tile_index = Point2D::new(x, y);
-                if self.tile_intersects_rect(&tile_index,
-                                             &dirty_rect.to_untyped(),
-                                             current_layer_size,
-                                             layer_world_origin,
-                                             layer_transform) {
-                    if let Some(buffer) = self.get_buffer_request_for_tile(tile_index,
-                                                                           current_layer_size,
-                                                                           current_content_age) {
-                        buffer_requests.push(buffer);
+        // A layer with empty, inverted, or NaN bounds has nothing to request. Its tiles are kept,
+        // since such bounds usually only last until the next layout.
+        let current_layer_size = update.pass.current_layer_size;
+        if !(current_layer_size.width > 0.0 && current_layer_size.height > 0.0) {
+            return update;
+        }
+
+        // Switching to tiling throws out all tiles, so leave the rest to `apply_update`.
+        if let Some(max_size) = self.single_buffer_max_size {
+            let current_layer_size = update.pass.current_layer_size;
+            if current_layer_size.width > max_size as f32 ||
+               current_layer_size.height > max_size as f32 {
+                update.too_large_for_single_buffer = true;
+                return update;
+            }
+        }
+
+        {
+            let pass = &update.pass;
+
+            // Tiles within a tile of the viewport are likely to be needed soon.
+            let margin = self.tile_size.get() as f32;
+            let viewport = pass.viewport;
+            let near_viewport =
+                TypedRect::new(TypedPoint2D::new(viewport.origin.x - margin,
+                                                 viewport.origin.y - margin),
+                               TypedSize2D::new(viewport.size.width + margin * 2.0,
+                                                viewport.size.height + margin * 2.0));
+            let viewport_center = viewport.origin +
+                TypedPoint2D::new(viewport.size.width * 0.5, viewport.size.height * 0.5);
+
+            // Get the range of tiles that can fit into the current layer size.
+            // Step through each, transform/clip them to 2d rect
+            // Check if visible against rect
+
+            let (x_tile_count, y_tile_count) = if self.is_single_buffer() {
+                (1, 1)
+            } else {
+                let tile_size = self.tile_size.get() as f32;
+                let layer_size = pass.current_layer_size.to_untyped();
+                (((layer_size.width + tile_size - 1.0) / tile_size) as usize,
+                 ((layer_size.height + tile_size - 1.0) / tile_size) as usize)
+            };
+
+            for x in 0..x_tile_count {
+                for y in 0..y_tile_count {
+                    let tile_index = Point2D::new(x, y);
+                    let transformed_tile_rect =
+                        match self.get_transformed_rect_for_tile_index(&tile_index,
+                                                                       pass.current_layer_size,
+                                                                       &pass.layer_world_origin,
+                                                                       pass.scale,
+                                                                       &pass.layer_transform) {
+                            Some(transformed_tile_rect) => transformed_tile_rect,
+                            None => continue,
+                        };
+                    if transformed_tile_rect.intersection(&pass.dirty_rect).is_none() {
+                        continue;
+                    }
+
+                    let tile_rect = self.get_rect_for_tile_index(tile_index,
+                                                                 pass.current_layer_size);
+                    let visible_rect =
+                        match TileGrid::get_visible_rect_for_tile(&tile_rect,
+                                                                  &transformed_tile_rect,
+                                                                  &pass.layer_transform,
+                                                                  &pass.occluders) {
+                            Some(visible_rect) => visible_rect,
+                            None => {
+                                // The tile is hidden behind opaque layers.
+                                update.occluded_tiles.push(tile_index);
+                                continue;
+                            }
+                        };
+
+                    let dx = transformed_tile_rect.origin.x +
+                        transformed_tile_rect.size.width * 0.5 - viewport_center.x;
+                    let dy = transformed_tile_rect.origin.y +
+                        transformed_tile_rect.size.height * 0.5 - viewport_center.y;
+                    update.tile_requests.push(TileRequest {
+                        tile_index: tile_index,
+                        visible_rect: if visible_rect != tile_rect {
+                            Some(visible_rect.to_untyped())
+                        } else {
+                            None
+                        },
+                        priority: if transformed_tile_rect.intersects(&viewport) {
+                            BufferRequestPriority::Visible
+                        } else if transformed_tile_rect.intersects(&near_viewport) {
+                            BufferRequestPriority::Near
+                        } else {
+                            BufferRequestPriority::Prefetch
+                        },
+                        viewport_distance: dx * dx + dy * dy,
+                    });
+                }
+            }
+
+            self.sort_tile_requests(&mut update.tile_requests, pass);
+
+            // The sort is stable, so each priority keeps the order chosen above.
+            update.tile_requests.sort_by_key(|tile_request| tile_request.priority);
+
+            // Tiles outside the viewport and the margin around it are thrown out, including those
+            // that the requests above create, unless eviction is left to `evict_tiles_outside`.
+            if self.evicts_on_request {
+                let keep_rect = inflate_rect(&pass.viewport, pass.keep_margin);
+                let new_tiles = update.tile_requests.iter().map(|tile_request| {
+                    &tile_request.tile_index
+                }).filter(|tile_index| !self.tiles.contains_key(*tile_index));
+                for tile_index in self.tiles.keys().chain(new_tiles) {
+                    if !self.tile_intersects_rect(tile_index,
+                                                  &keep_rect,
+                                                  pass.current_layer_size,
+                                                  &pass.layer_world_origin,
+                                                  pass.scale,
+                                                  &pass.layer_transform) {
+                        update.unused_tiles.push(*tile_index);
                     }
                 }
             }
         }
 
-        self.mark_tiles_outside_of_rect_as_unused(viewport,
-                                                  layer_world_origin,
-                                                  layer_transform,
-                                                  current_layer_size);
+        update
+    }
 
+    /// Applies an update computed by `compute_buffer_requests`: creates the tiles it visits,
+    /// records the content age of the requests it makes as pending, throws out the tiles outside
+    /// the viewport, and returns the requests.
+    pub fn apply_update(&mut self, update: TileGridUpdate) -> Vec<BufferRequest> {
+        let mut buffer_requests = Vec::new();
+        self.apply_update_into(update, &mut buffer_requests);
         buffer_requests
     }
 
-    pub fn get_tile_index_for_point(&self, point: Point2D<usize>) -> Point2D<usize> {
-        assert!(point.x % self.tile_size.get() == 0);
-        assert!(point.y % self.tile_size.get() == 0);
-        Point2D::new((point.x / self.tile_size.get()) as usize,
-                     (point.y / self.tile_size.get()) as usize)
+    /// Like `apply_update`, but appends the requests to a vector supplied by the caller.
+    pub fn apply_update_into(&mut self,
+                             mut update: TileGridUpdate,
+                             buffer_requests: &mut Vec<BufferRequest>) {
+        if update.too_large_for_single_buffer {
+            warn!("Layer is too large for a single buffer; falling back to tiling.");
+            self.set_single_buffer_mode(None);
+            let drops_requests = update.drops_requests;
+            let mut update = self.compute_update(update.pass);
+            update.drops_requests = drops_requests;
+            return self.apply_update_into(update, buffer_requests);
+        }
+
+        let first_request = buffer_requests.len();
+        let pass = update.pass;
+        mem::swap(&mut self.occluded_tiles, &mut update.occluded_tiles);
+
+        // Requests pending at the old scale would keep their tiles from being requested at the
+        // new one, possibly for good, since their buffers are rejected.
+        if let Some(previous_resolution) = self.resolution {
+            if previous_resolution != pass.scale.get() {
+                for tile in self.tiles.values_mut() {
+                    tile.forget_request_at_resolution(previous_resolution);
+                }
+            }
+        }
+        self.resolution = Some(pass.scale.get());
+        if pass.current_layer_size.width > 0.0 && pass.current_layer_size.height > 0.0 {
+            // A single buffer always covers the whole layer, so a resize asks for a new one.
+            if self.is_single_buffer() &&
+                    self.layer_size.map_or(false, |size| size != pass.current_layer_size) {
+                if let Some(tile) = self.tiles.get_mut(&Point2D::new(0, 0)) {
+                    self.unused_buffers.extend(tile.discard_buffer().into_iter());
+                }
+            }
+            self.layer_size = Some(pass.current_layer_size);
+        }
+
+        if update.drops_requests {
+            update.tile_requests.clear();
+        }
+        for tile_request in update.tile_requests.drain(..) {
+            if let Some(mut buffer) = self.get_buffer_request_for_tile(tile_request.tile_index,
+                                                                       pass.current_layer_size,
+                                                                       pass.scale,
+                                                                       pass.current_content_age) {
+                buffer.visible_rect = tile_request.visible_rect;
+                buffer.ordering = pass.ordering;
+                buffer.priority = tile_request.priority;
+                buffer_requests.push(buffer);
+            }
+        }
+
+        for tile_index in update.unused_tiles.drain(..) {
+            if let Some(ref mut tile) = self.tiles.remove(&tile_index) {
+                self.unused_buffers.extend(tile.take_buffers().into_iter());
+            }
+        }
+
+        self.recycle_unused_buffers(&mut buffer_requests[first_request..], pass.scale.get());
+
+        let mut occluders = pass.occluders;
+        occluders.clear();
+        update.occluded_tiles.clear();
+        *self.spare_vectors.borrow_mut() = SpareUpdateVectors {
+            occluders: occluders,
+            tile_requests: update.tile_requests,
+            occluded_tiles: update.occluded_tiles,
+            unused_tiles: update.unused_tiles,
+        };
+    }
+
+
+    /// Hands the surfaces of unused buffers to the given requests that don't carry one yet, so
+    /// that the painter can reuse them instead of allocating new ones. A surface is only handed
+    /// to a request of exactly its size, for a buffer painted at the given resolution; the
+    /// buffers that are not matched stay unused.
+    fn recycle_unused_buffers(&mut self, buffer_requests: &mut [BufferRequest], resolution: f32) {
+        if self.unused_buffers.is_empty() {
+            return;
+        }
+
+        let mut buffers_by_size: HashMap<(usize, usize), Vec<Box<LayerBuffer>>> = HashMap::new();
+        let mut unmatchable_buffers = Vec::new();
+        for buffer in mem::replace(&mut self.unused_buffers, Vec::new()) {
+            if buffer.resolution == resolution {
+                let size = (buffer.screen_pos.size.width, buffer.screen_pos.size.height);
+                buffers_by_size.entry(size).or_insert_with(Vec::new).push(buffer);
+            } else {
+                unmatchable_buffers.push(buffer);
+            }
+        }
+
+        for request in buffer_requests.iter_mut() {
+            if request.native_surface.is_some() {
+                continue;
+            }
+            let size = (request.screen_rect.size.width, request.screen_rect.size.height);
+            if let Some(buffer) = buffers_by_size.get_mut(&size).and_then(|buffers| buffers.pop()) {
+                let mut native_surface = buffer.native_surface;
+                native_surface.mark_wont_leak();
+                request.native_surface = Some(native_surface);
+            }
+        }
+
+        self.unused_buffers = unmatchable_buffers;
+        for (_, buffers) in buffers_by_size {
+            self.unused_buffers.extend(buffers.into_iter());
+        }
+    }
+
+    /// Sorts the tile requests of a request pass in its order. The requests are generated in
+    /// column-major order, so that order needs no sorting.
+    fn sort_tile_requests(&self, tile_requests: &mut [TileRequest], pass: &RequestPass) {
+        match pass.ordering {
+            RequestOrdering::ColumnMajor => {}
+            RequestOrdering::RowMajor => {
+                tile_requests.sort_by_key(|tile_request| {
+                    (tile_request.tile_index.y, tile_request.tile_index.x)
+                });
+            }
+            RequestOrdering::MortonOrder => {
+                tile_requests.sort_by_key(|tile_request| morton_code(tile_request.tile_index));
+            }
+            RequestOrdering::ViewportDistance => {
+                // The sort is stable, so tiles at the same distance stay in column-major order.
+                tile_requests.sort_by(|a, b| {
+                    a.viewport_distance.partial_cmp(&b.viewport_distance)
+                                       .unwrap_or(Ordering::Equal)
+                });
+            }
+        }
     }
 
-    pub fn add_buffer(&mut self, buffer: Box<LayerBuffer>) {
-        let index = self.get_tile_index_for_point(buffer.screen_pos.origin.clone());
+    /// Returns true if the given tile was not requested in the last request pass because it was
+    /// hidden behind opaque layers.
+    pub fn was_occluded(&self, tile_index: &Point2D<usize>) -> bool {
+        self.occluded_tiles.contains(tile_index)
+    }
+
+    /// Returns the index of the tile whose origin is the given point, or None if the point isn't
+    /// the origin of a tile.
+    pub fn get_tile_index_for_point(&self, point: Point2D<usize>) -> Option<Point2D<usize>> {
+        if self.is_single_buffer() {
+            return if point == Point2D::zero() { Some(Point2D::new(0, 0)) } else { None };
+        }
+
+        let tile_size = self.tile_size.get();
+        if point.x % tile_size != 0 || point.y % tile_size != 0 {
+            return None;
+        }
+        Some(Point2D::new(point.x / tile_size, point.y / tile_size))
+    }
+
+    /// Returns the index of the tile a buffer belongs to, or None if the buffer doesn't fit in
+    /// the grid, which happens when it was requested before the tile size changed.
+    fn get_tile_index_for_buffer(&self, buffer: &LayerBuffer) -> Option<Point2D<usize>> {
+        if self.is_single_buffer() {
+            return Some(Point2D::new(0, 0));
+        }
+
+        let tile_size = self.tile_size.get();
+        let rect = &buffer.screen_pos;
+        if rect.size.width > tile_size || rect.size.height > tile_size {
+            return None;
+        }
+        self.get_tile_index_for_point(rect.origin)
+    }
+
+    /// Adds a buffer sent by the painter to its tile. If the size of the buffer isn't that of its
+    /// tile at the layer size of the last request pass, or a tolerance is given and the resolution
+    /// of the buffer differs from the scale of the last request pass by more than that, which
+    /// happens when the layer is resized or the scale changes between a request and its answer,
+    /// the buffer is put with the unused buffers instead and its tile is requested again.
+    pub fn add_buffer(&mut self, buffer: Box<LayerBuffer>, resolution_epsilon: Option<f32>) {
+        let index = match self.get_tile_index_for_buffer(&buffer) {
+            Some(index) => index,
+            None => {
+                debug!("Received buffer that doesn't fit the tile grid.");
+                self.add_unused_buffer(Some(buffer));
+                return;
+            }
+        };
         if !self.tiles.contains_key(&index) {
             warn!("Received buffer for non-existent tile!");
             self.add_unused_buffer(Some(buffer));
             return;
         }
 
-        let replaced_buffer = self.tiles.get_mut(&index).unwrap().replace_buffer(buffer);
+        // A buffer requested before the layer was resized would be stretched over its tile.
+        if let Some(layer_size) = self.layer_size {
+            let expected_size = self.get_rect_for_tile_index(index, layer_size).size.to_untyped();
+            if buffer.screen_pos.size != expected_size {
+                warn!("Received buffer of size {:?} for a tile of size {:?}.",
+                      buffer.screen_pos.size,
+                      expected_size);
+                self.tiles.get_mut(&index).unwrap().forget_request_answered_by(&buffer);
+                self.add_unused_buffer(Some(buffer));
+                return;
+            }
+        }
+
+        if let (Some(resolution), Some(epsilon)) = (self.resolution, resolution_epsilon) {
+            if !buffer.is_valid_with_epsilon(resolution, epsilon) {
+                debug!("Received buffer painted at scale {} instead of {}.",
+                       buffer.resolution,
+                       resolution);
+                // The request of a tile whose scale changed since was forgotten already.
+                let tile = self.tiles.get_mut(&index).unwrap();
+                if tile.superseded_resolution != Some(buffer.resolution) {
+                    tile.forget_request_answered_by(&buffer);
+                }
+                self.add_unused_buffer(Some(buffer));
+                return;
+            }
+        }
+
+        let double_buffered = self.double_buffered;
+        let replaced_buffer =
+            self.tiles.get_mut(&index).unwrap().replace_buffer(buffer, double_buffered);
         self.add_unused_buffer(replaced_buffer);
     }
 
+    /// Puts each of the given buffers into its tile at once, without the checks of `add_buffer`,
+    /// and returns the buffers displaced from the tiles along with those whose tile doesn't
+    /// exist.
+    pub fn swap_in_buffers(&mut self, buffers: Vec<Box<LayerBuffer>>) -> Vec<Box<LayerBuffer>> {
+        let double_buffered = self.double_buffered;
+        let mut unused_buffers = vec!();
+        for buffer in buffers {
+            let index = match self.get_tile_index_for_buffer(&buffer) {
+                Some(index) if self.tiles.contains_key(&index) => index,
+                _ => {
+                    unused_buffers.push(buffer);
+                    continue;
+                }
+            };
+            let replaced_buffer =
+                self.tiles.get_mut(&index).unwrap().replace_buffer(buffer, double_buffered);
+            unused_buffers.extend(replaced_buffer.into_iter());
+        }
+        unused_buffers
+    }
+
+    /// Describes the buffers displayed by the tiles of this grid whose surfaces can be
+    /// serialized. See `Layer::export_tile_cache`.
+    pub fn export_tiles(&self) -> Vec<TileSnapshot> {
+        let mut snapshots = vec!();
+        for (index, tile) in &self.tiles {
+            let buffer = match tile.buffer {
+                Some(ref buffer) => buffer,
+                None => continue,
+            };
+            if let Some(native_surface) = buffer.native_surface.serialize() {
+                snapshots.push(TileSnapshot {
+                    tile_index: *index,
+                    native_surface: native_surface,
+                    rect: buffer.rect,
+                    screen_pos: buffer.screen_pos,
+                    resolution: buffer.resolution,
+                    painted_with_cpu: buffer.painted_with_cpu,
+                    content_age: buffer.content_age,
+                    rotation: buffer.rotation,
+                    mirrored: buffer.mirrored,
+                });
+            }
+        }
+        snapshots
+    }
+
+    /// Gives restored buffers to the tiles they were exported from, creating the tiles if
+    /// necessary. A buffer that doesn't fit its tile, or is older than the buffer its tile
+    /// displays, is put with the unused buffers. Returns the number of tiles given a buffer.
+    pub fn import_tiles(&mut self, buffers: Vec<(Point2D<usize>, Box<LayerBuffer>)>) -> usize {
+        let double_buffered = self.double_buffered;
+        let mut imported_count = 0;
+        for (index, buffer) in buffers {
+            if self.get_tile_index_for_buffer(&buffer) != Some(index) {
+                self.add_unused_buffer(Some(buffer));
+                continue;
+            }
+            let unused_buffer = {
+                let tile = self.tiles.entry(index).or_insert_with(Tile::new);
+                if !tile.should_use_new_buffer(&buffer) {
+                    Some(buffer)
+                } else {
+                    imported_count += 1;
+                    tile.replace_buffer(buffer, double_buffered)
+                }
+            };
+            self.add_unused_buffer(unused_buffer);
+        }
+        imported_count
+    }
+
+    /// Rejects a buffer whose resolution doesn't match the scene scale, so that its tile is
+    /// requested again. If the tile keeps being rejected, the buffer is handed back so that it
+    /// can be accepted anyway; see the module docs.
+    pub fn reject_buffer(&mut self, buffer: Box<LayerBuffer>, frame: FrameIndex)
+                         -> Option<Box<LayerBuffer>> {
+        let rejected = match self.get_tile_index_for_buffer(&buffer) {
+            Some(index) => {
+                match self.tiles.get_mut(&index) {
+                    Some(tile) => tile.reject_buffer(&buffer, frame),
+                    None => true,
+                }
+            }
+            None => true,
+        };
+        if !rejected {
+            return Some(buffer);
+        }
+        self.add_unused_buffer(Some(buffer));
+        None
+    }
+
     pub fn do_for_all_tiles<F>(&self, mut f: F) where F: FnMut(&Tile) {
         for tile in self.tiles.values() {
             f(tile);
@@ -301,26 +1563,90 @@ impl TileGrid {
 
     pub fn collect_buffers(&mut self) -> Vec<Box<LayerBuffer>> {
         let mut collected_buffers = self.take_unused_buffers();
-        collected_buffers.extend(self.tiles.drain().flat_map(|(_, mut tile)| tile.buffer.take()));
+        collected_buffers.extend(self.tiles.drain().flat_map(|(_, mut tile)| tile.take_buffers()));
         collected_buffers
     }
 
-    pub fn create_textures(&mut self, display: &NativeDisplay) {
+    /// Marks the textures of all tiles as weak, so that they are not deleted when the tiles are
+    /// dropped. Used when the GL context has been lost.
+    pub fn forget_textures(&mut self) {
+        for tile in self.tiles.values_mut() {
+            tile.texture.mark_weak();
+        }
+    }
+
+    /// Creates the textures of the tiles that have a buffer but no texture yet. See
+    /// `Layer::create_textures`.
+    pub fn create_textures(&mut self,
+                           gl: &Rc<GlApi>,
+                           display: &NativeDisplay,
+                           capabilities: &Capabilities,
+                           mipmap_scale_threshold: Option<f32>) {
         for (_, ref mut tile) in &mut self.tiles {
-            tile.create_texture(display);
+            tile.create_texture(gl, display, capabilities, mipmap_scale_threshold);
+        }
+    }
+
+    /// Returns counters describing the tiles of this grid. This neither copies buffers nor
+    /// creates textures.
+    pub fn statistics(&self) -> TileGridStatistics {
+        let mut statistics = TileGridStatistics {
+            tile_count: self.tiles.len(),
+            unused_buffer_count: self.unused_buffers.len(),
+            buffer_memory: self.get_memory_usage() +
+                           self.unused_buffers.iter().map(|buffer| buffer.get_mem()).sum::<usize>(),
+            ..TileGridStatistics::default()
+        };
+        for tile in self.tiles.values() {
+            if tile.buffer.is_some() {
+                statistics.tiles_with_buffers += 1;
+            }
+            if tile.content_age_of_pending_buffer.is_some() {
+                statistics.tiles_with_pending_requests += 1;
+            }
         }
+        statistics
+    }
+
+    /// Returns the number of tiles displaying a buffer older than the given content age.
+    pub fn stale_tile_count(&self, current_age: ContentAge) -> usize {
+        self.tiles.values().filter(|tile| tile.is_stale(current_age)).count()
+    }
+
+    /// Returns true if no tile displays a buffer older than the given content age or waits for
+    /// a buffer requested at it.
+    pub fn is_fully_painted(&self, current_age: ContentAge) -> bool {
+        self.tiles.values().all(|tile| {
+            !tile.is_stale(current_age) && !tile.is_awaiting_buffer_for(current_age)
+        })
     }
 
     /// Calculate the amount of memory used by all the tiles in the
-    /// tile grid. The memory may be allocated on the heap or in GPU memory.
+    /// tile grid, including back buffers. The memory may be allocated on the heap or in GPU
+    /// memory.
     pub fn get_memory_usage(&self) -> usize {
         self.tiles.values().map(|ref tile| {
             // We cannot use Option::map_or here because rust will
             // complain about moving out of borrowed content.
-            match tile.buffer {
+            let front = match tile.buffer {
                 Some(ref buffer) => buffer.get_mem(),
                 None => 0,
-            }
+            };
+            let back = match tile.back_buffer {
+                Some(ref buffer) => buffer.get_mem(),
+                None => 0,
+            };
+            front + back
+        }).sum()
+    }
+
+    /// Returns the area of the buffers held by the tiles in the tile grid, including back
+    /// buffers, in device pixels.
+    pub fn get_raster_area(&self) -> usize {
+        self.tiles.values().map(|tile| {
+            tile.buffer.iter().chain(tile.back_buffer.iter()).map(|buffer| {
+                buffer.screen_pos.size.width * buffer.screen_pos.size.height
+            }).sum::<usize>()
         }).sum()
     }
 }
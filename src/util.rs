@@ -10,15 +10,26 @@
 // Miscellaneous utilities.
 
 use std::iter::repeat;
-use euclid::{Matrix4D, Point2D, Point3D, Point4D, Rect, Size2D};
+use euclid::{Matrix4D, Point3D, Point4D};
+use euclid::point::{Point2D, TypedPoint2D};
+use euclid::rect::{Rect, TypedRect};
+use euclid::side_offsets::SideOffsets2D;
+use euclid::size::{Size2D, TypedSize2D};
+use geometry::{LayerPixel, ScreenPixel};
 use std::f32;
+use std::mem;
 
 const W_CLIPPING_PLANE: f32 = 0.00001;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct ScreenRect {
-    pub rect: Rect<f32>,
+    pub rect: TypedRect<f32, ScreenPixel>,
     pub z_center: f32,
+
+    /// The smallest and largest z of the corners of the rect after projection and clipping.
+    /// Larger values are nearer to the viewer.
+    pub z_min: f32,
+    pub z_max: f32,
 }
 
 #[cfg(feature = "heapsize")]
@@ -46,51 +57,351 @@ pub fn convert_rgb32_to_rgb24(buffer: &[u8]) -> Vec<u8> {
     }).collect()
 }
 
-// Sutherland-Hodgman clipping algorithm
-fn clip_polygon_to_near_plane(clip_space_vertices: &[Point4D<f32>; 4])
-                                  -> Option<Vec<Point4D<f32>>> {
-    let mut out_vertices = vec!();
+/// Shrinks a rect by the given side offsets. The result is never smaller than an empty rect.
+pub fn inset_rect<U>(rect: &TypedRect<f32, U>, inset: &SideOffsets2D<f32>) -> TypedRect<f32, U> {
+    let width = (rect.size.width - inset.left - inset.right).max(0.0);
+    let height = (rect.size.height - inset.top - inset.bottom).max(0.0);
+    TypedRect::new(TypedPoint2D::new(rect.origin.x + inset.left, rect.origin.y + inset.top),
+                   TypedSize2D::new(width, height))
+}
+
+/// Returns false if the width or height of a rect is negative or NaN, as happens briefly to the
+/// bounds of layers during incremental layout. Such rects are neither drawn nor projected.
+pub fn rect_has_valid_size<U>(rect: &TypedRect<f32, U>) -> bool {
+    rect.size.width >= 0.0 && rect.size.height >= 0.0
+}
+
+/// Grows a rect by `amount` on every side.
+pub fn inflate_rect<U>(rect: &TypedRect<f32, U>, amount: f32) -> TypedRect<f32, U> {
+    TypedRect::new(TypedPoint2D::new(rect.origin.x - amount, rect.origin.y - amount),
+                   TypedSize2D::new(rect.size.width + amount * 2.0,
+                                    rect.size.height + amount * 2.0))
+}
+
+/// Returns the part of `rect` not covered by `occluder`, or None if it is entirely covered. The
+/// result is conservative: it is only shrunk when the occluder covers a whole side of the rect,
+/// since otherwise the uncovered part is not a rect.
+pub fn subtract_rect<U>(rect: &TypedRect<f32, U>, occluder: &TypedRect<f32, U>)
+                        -> Option<TypedRect<f32, U>> {
+    if !rect.intersects(occluder) {
+        return Some(*rect);
+    }
+    if occluder.contains_rect(rect) {
+        return None;
+    }
+
+    let (mut min_x, mut max_x) = (rect.min_x(), rect.max_x());
+    let (mut min_y, mut max_y) = (rect.min_y(), rect.max_y());
+    if occluder.min_y() <= min_y && occluder.max_y() >= max_y {
+        if occluder.min_x() <= min_x {
+            min_x = occluder.max_x();
+        } else if occluder.max_x() >= max_x {
+            max_x = occluder.min_x();
+        }
+    } else if occluder.min_x() <= min_x && occluder.max_x() >= max_x {
+        if occluder.min_y() <= min_y {
+            min_y = occluder.max_y();
+        } else if occluder.max_y() >= max_y {
+            max_y = occluder.min_y();
+        }
+    }
+
+    Some(TypedRect::new(TypedPoint2D::new(min_x, min_y),
+                        TypedSize2D::new(max_x - min_x, max_y - min_y)))
+}
+
+/// Returns true if two rects touch along a part of an edge of non-zero length. Rects that only
+/// meet at a corner don't.
+pub fn rects_share_edge<U>(a: &TypedRect<f32, U>, b: &TypedRect<f32, U>) -> bool {
+    let overlaps = |a_min: f32, a_max: f32, b_min: f32, b_max: f32| {
+        a_min.max(b_min) < a_max.min(b_max)
+    };
+    let touch_horizontally = a.max_x() == b.min_x() || b.max_x() == a.min_x();
+    let touch_vertically = a.max_y() == b.min_y() || b.max_y() == a.min_y();
+    (touch_horizontally && overlaps(a.min_y(), a.max_y(), b.min_y(), b.max_y())) ||
+        (touch_vertically && overlaps(a.min_x(), a.max_x(), b.min_x(), b.max_x()))
+}
+
+/// Splits an axis for nine-patch rendering. Returns the boundaries of the three slices along the
+/// destination and the source axes, the latter normalized to [0, 1]. Insets that don't fit in
+/// their axis are shrunk proportionally, which collapses the middle slice to nothing.
+fn nine_patch_slices(length: f32, source_length: f32, start: f32, end: f32)
+                     -> ([f32; 4], [f32; 4]) {
+    let fit = |length: f32, start: f32, end: f32| {
+        let (start, end) = (start.max(0.0), end.max(0.0));
+        if start + end > length && start + end > 0.0 {
+            let factor = length.max(0.0) / (start + end);
+            (start * factor, end * factor)
+        } else {
+            (start, end)
+        }
+    };
+
+    let (source_start, source_end) = fit(source_length, start, end);
+    let (start, end) = fit(length, start, end);
+    let length = length.max(0.0);
+    ([0.0, start, length - end, length],
+     [0.0, source_start / source_length, 1.0 - source_end / source_length, 1.0])
+}
+
+/// Computes the quads of a nine-patch: a source image of `source_size` pixels, cut by `insets`
+/// into corners, edges, and a center, is drawn into a rect of `size` with the corners unscaled,
+/// the edges stretched along their length, and the center stretched both ways. Returns the
+/// destination rect, relative to the origin of the drawn rect, and the normalized source rect
+/// of each non-empty quad. If `size` is smaller than the insets, the corners are shrunk and the
+/// edges and center are left out.
+pub fn nine_patch_quads(size: &Size2D<f32>,
+                        source_size: &Size2D<f32>,
+                        insets: &SideOffsets2D<f32>)
+                        -> Vec<(Rect<f32>, Rect<f32>)> {
+    if source_size.width <= 0.0 || source_size.height <= 0.0 {
+        return vec!();
+    }
+
+    let (xs, source_xs) =
+        nine_patch_slices(size.width, source_size.width, insets.left, insets.right);
+    let (ys, source_ys) =
+        nine_patch_slices(size.height, source_size.height, insets.top, insets.bottom);
+
+    let mut quads = Vec::with_capacity(9);
+    for row in 0..3 {
+        for column in 0..3 {
+            let rect = Rect::new(Point2D::new(xs[column], ys[row]),
+                                 Size2D::new(xs[column + 1] - xs[column], ys[row + 1] - ys[row]));
+            if rect.size.width <= 0.0 || rect.size.height <= 0.0 {
+                continue;
+            }
+            let source_rect = Rect::new(Point2D::new(source_xs[column], source_ys[row]),
+                                        Size2D::new(source_xs[column + 1] - source_xs[column],
+                                                    source_ys[row + 1] - source_ys[row]));
+            quads.push((rect, source_rect));
+        }
+    }
+    quads
+}
+
+/// Returns true if the given transform is a 2D transform, that is, if it keeps layers in the
+/// z = 0 plane and involves no perspective. See https://drafts.csswg.org/css-transforms/#2d-matrix
+pub fn is_2d_transform(m: &Matrix4D<f32>) -> bool {
+    m.m31 == 0.0 && m.m32 == 0.0 &&
+    m.m13 == 0.0 && m.m23 == 0.0 &&
+    m.m43 == 0.0 && m.m14 == 0.0 &&
+    m.m24 == 0.0 && m.m34 == 0.0 &&
+    m.m33 == 1.0 && m.m44 == 1.0
+}
+
+/// Returns true if the given transform only translates and scales by positive factors in x and
+/// y, so that it maps axis-aligned rects to axis-aligned rects without flipping them.
+pub fn is_axis_aligned_2d_transform(m: &Matrix4D<f32>) -> bool {
+    m.m12 == 0.0 && m.m13 == 0.0 && m.m14 == 0.0 &&
+    m.m21 == 0.0 && m.m23 == 0.0 && m.m24 == 0.0 &&
+    m.m31 == 0.0 && m.m32 == 0.0 && m.m34 == 0.0 &&
+    m.m43 == 0.0 && m.m44 == 1.0 &&
+    m.m11 > 0.0 && m.m22 > 0.0
+}
+
+/// The maximum number of vertices produced by clipping a quad against a single plane.
+const MAX_CLIPPED_VERTICES: usize = 8;
+
+// Sutherland-Hodgman clipping algorithm, against the plane where the `w` of the vertices is
+// W_CLIPPING_PLANE. Writes the clipped polygon into `out_vertices`, interpolating vertices where
+// edges cross the plane, and returns the number of vertices written, or zero if the polygon is
+// entirely clipped.
+fn clip_to_near_plane<V, W, I>(vertices: &[V], out_vertices: &mut [V], w: W, interpolate: I)
+                               -> usize
+                               where V: Copy, W: Fn(&V) -> f32, I: Fn(&V, &V, f32) -> V {
+    let mut out_vertex_count = 0;
 
     // TODO(gw): Check for trivial accept / reject if all
     // input vertices are on the same side of the near plane.
 
-    for (i, current_vertex) in clip_space_vertices.iter().enumerate() {
+    for (i, current_vertex) in vertices.iter().enumerate() {
         let previous_vertex = if i == 0 {
-            clip_space_vertices.last().unwrap()
+            vertices.last().unwrap()
         } else {
-            &clip_space_vertices[i-1]
+            &vertices[i-1]
         };
 
-        let previous_dot = if previous_vertex.w < W_CLIPPING_PLANE { -1 } else { 1 };
-        let current_dot = if current_vertex.w < W_CLIPPING_PLANE { -1 } else { 1 };
+        let previous_w = w(previous_vertex);
+        let current_w = w(current_vertex);
+        let previous_dot = if previous_w < W_CLIPPING_PLANE { -1 } else { 1 };
+        let current_dot = if current_w < W_CLIPPING_PLANE { -1 } else { 1 };
 
         if previous_dot * current_dot < 0 {
-            let int_factor = (previous_vertex.w - W_CLIPPING_PLANE) / (previous_vertex.w - current_vertex.w);
-
-            // TODO(gw): Impl operators on Point4D for this
-            let int_point = Point4D::new(
-                previous_vertex.x + int_factor * (current_vertex.x - previous_vertex.x),
-                previous_vertex.y + int_factor * (current_vertex.y - previous_vertex.y),
-                previous_vertex.z + int_factor * (current_vertex.z - previous_vertex.z),
-                previous_vertex.w + int_factor * (current_vertex.w - previous_vertex.w),
-            );
-
-            out_vertices.push(int_point);
+            let int_factor = (previous_w - W_CLIPPING_PLANE) / (previous_w - current_w);
+            let int_point = interpolate(previous_vertex, current_vertex, int_factor);
+            out_vertices[out_vertex_count] = int_point;
+            out_vertex_count += 1;
         }
 
         if current_dot > 0 {
-            out_vertices.push(*current_vertex);
+            out_vertices[out_vertex_count] = *current_vertex;
+            out_vertex_count += 1;
         }
     }
 
-    if out_vertices.len() < 3 {
-        return None
+    if out_vertex_count < 3 {
+        return 0
     }
 
-    Some(out_vertices)
+    out_vertex_count
+}
+
+fn clip_polygon_to_near_plane(clip_space_vertices: &[Point4D<f32>; 4],
+                              out_vertices: &mut [Point4D<f32>; MAX_CLIPPED_VERTICES])
+                              -> usize {
+    clip_to_near_plane(clip_space_vertices, out_vertices, |vertex| vertex.w, |a, b, t| {
+        // TODO(gw): Impl operators on Point4D for this
+        Point4D::new(a.x + t * (b.x - a.x),
+                     a.y + t * (b.y - a.y),
+                     a.z + t * (b.z - a.z),
+                     a.w + t * (b.w - a.w))
+    })
+}
+
+/// Returns the `w` that a point in the z = 0 plane gets under the given transform.
+fn w_of_point(point: &Point2D<f32>, transform: &Matrix4D<f32>) -> f32 {
+    point.x * transform.m14 + point.y * transform.m24 + transform.m44
 }
 
-pub fn project_rect_to_screen(rect: &Rect<f32>,
+/// Returns true if the given transform puts some, but not all, of the corners of a rect behind
+/// the near plane, so that quads drawn in the rect must be clipped with
+/// `clip_textured_rect_to_near_plane`.
+pub fn rect_crosses_near_plane<U>(rect: &TypedRect<f32, U>, transform: &Matrix4D<f32>) -> bool {
+    let rect = rect.to_untyped();
+    let corners = [rect.origin, rect.top_right(), rect.bottom_right(), rect.bottom_left()];
+    let behind = corners.iter()
+                        .filter(|corner| w_of_point(corner, transform) < W_CLIPPING_PLANE)
+                        .count();
+    behind != 0 && behind != corners.len()
+}
+
+/// The magnitude below which a determinant is treated as zero. Rotating a layer by exactly 90
+/// degrees about the x or y axis leaves determinants on the order of 1e-8 rather than zero.
+const MIN_DETERMINANT: f32 = 1e-6;
+
+/// Returns the determinant of the part of a transform that maps the z = 0 plane of a layer.
+fn plane_determinant(m: &Matrix4D<f32>) -> f32 {
+    // Points in the z = 0 plane are mapped by the x, y, and w rows and columns alone.
+    m.m11 * (m.m22 * m.m44 - m.m24 * m.m42) -
+    m.m12 * (m.m21 * m.m44 - m.m24 * m.m41) +
+    m.m14 * (m.m21 * m.m42 - m.m22 * m.m41)
+}
+
+/// Returns true if all the entries of the given matrix are finite.
+pub fn matrix_is_finite(m: &Matrix4D<f32>) -> bool {
+    [m.m11, m.m12, m.m13, m.m14,
+     m.m21, m.m22, m.m23, m.m24,
+     m.m31, m.m32, m.m33, m.m34,
+     m.m41, m.m42, m.m43, m.m44].iter().all(|entry| entry.is_finite())
+}
+
+/// Returns true if the given layer transform flattens the z = 0 plane of the layer to exactly
+/// zero area, as a scale of 0 does, or isn't finite. Such a layer covers nothing on the screen.
+pub fn transform_collapses_layer(transform: &Matrix4D<f32>) -> bool {
+    !matrix_is_finite(transform) || plane_determinant(transform) == 0.0
+}
+
+/// Returns the inverse of the given layer transform, or None if the transform is singular or
+/// flattens the z = 0 plane of the layer to zero area on the screen, so that points on the screen
+/// can't be mapped back into the layer.
+pub fn invert_layer_transform(transform: &Matrix4D<f32>) -> Option<Matrix4D<f32>> {
+    let m = transform;
+    if !matrix_is_finite(m) || plane_determinant(m).abs() < MIN_DETERMINANT ||
+       m.determinant().abs() < MIN_DETERMINANT {
+        return None;
+    }
+    // The determinant can be large enough while the inverse still overflows.
+    m.inverse().and_then(|inverse| if matrix_is_finite(&inverse) { Some(inverse) } else { None })
+}
+
+/// Maps a point on the screen back to the z = 0 plane of a layer, given the inverse of the
+/// layer transform, by finding the depth at which the point lies in that plane. Returns None if
+/// the point in the plane is at or behind the eye, that is, if its `w` is not positive.
+pub fn unproject_point(inverse: &Matrix4D<f32>, point: &Point2D<f32>) -> Option<Point2D<f32>> {
+    let p = inverse.transform_point4d(&Point4D::new(point.x, point.y, 0.0, 1.0));
+
+    // Moving the screen point along z moves its preimage along the z row of the inverse.
+    let z = if inverse.m33 == 0.0 { 0.0 } else { -p.z / inverse.m33 };
+    let w = p.w + z * inverse.m34;
+    if w <= 0.0 {
+        return None;
+    }
+    Some(Point2D::new((p.x + z * inverse.m31) / w, (p.y + z * inverse.m32) / w))
+}
+
+/// A point along with its texture coordinates.
+pub type TexturedPoint = (Point2D<f32>, Point2D<f32>);
+
+/// Clips a rect in the z = 0 plane, along with the texture rect mapped onto it, to the part that
+/// the given transform puts in front of the near plane. Returns the corners of the resulting
+/// convex polygon in order, suitable for a triangle fan, each with its texture coordinates. The
+/// list is empty if the rect is entirely behind the near plane.
+pub fn clip_textured_rect_to_near_plane(rect: &Rect<f32>,
+                                        texture_rect: &Rect<f32>,
+                                        transform: &Matrix4D<f32>)
+                                        -> Vec<TexturedPoint> {
+    let vertices = [
+        (rect.origin, texture_rect.origin),
+        (rect.top_right(), texture_rect.top_right()),
+        (rect.bottom_right(), texture_rect.bottom_right()),
+        (rect.bottom_left(), texture_rect.bottom_left()),
+    ];
+
+    // Texture coordinates are affine in the plane of the layer, so they can be interpolated
+    // there along with the positions.
+    let w = |vertex: &TexturedPoint| w_of_point(&vertex.0, transform);
+    let interpolate = |a: &TexturedPoint, b: &TexturedPoint, t: f32| {
+        (Point2D::new(a.0.x + t * (b.0.x - a.0.x), a.0.y + t * (b.0.y - a.0.y)),
+         Point2D::new(a.1.x + t * (b.1.x - a.1.x), a.1.y + t * (b.1.y - a.1.y)))
+    };
+
+    let mut clipped_vertices = [vertices[0]; MAX_CLIPPED_VERTICES];
+    let clipped_vertex_count = clip_to_near_plane(&vertices, &mut clipped_vertices, w, interpolate);
+    clipped_vertices[..clipped_vertex_count].to_vec()
+}
+
+/// Clips a convex polygon to a rect with the Sutherland-Hodgman algorithm. Returns the corners of
+/// the clipped polygon in order, suitable for a triangle fan; the list is empty if nothing of the
+/// polygon is left.
+pub fn clip_polygon_to_rect(polygon: &[Point2D<f32>], rect: &Rect<f32>) -> Vec<Point2D<f32>> {
+    if polygon.len() < 3 {
+        return vec!();
+    }
+
+    // The signed distances of a point to the inside of each edge of the rect.
+    let edges: [&Fn(&Point2D<f32>) -> f32; 4] = [
+        &|point| point.x - rect.min_x(),
+        &|point| rect.max_x() - point.x,
+        &|point| point.y - rect.min_y(),
+        &|point| rect.max_y() - point.y,
+    ];
+
+    let mut vertices = polygon.to_vec();
+    for distance in &edges {
+        let input = mem::replace(&mut vertices, vec!());
+        for (i, current) in input.iter().enumerate() {
+            let previous = if i == 0 { input.last().unwrap() } else { &input[i - 1] };
+            let (previous_distance, current_distance) = (distance(previous), distance(current));
+            if (previous_distance < 0.0) != (current_distance < 0.0) {
+                let t = previous_distance / (previous_distance - current_distance);
+                vertices.push(Point2D::new(previous.x + t * (current.x - previous.x),
+                                           previous.y + t * (current.y - previous.y)));
+            }
+            if current_distance >= 0.0 {
+                vertices.push(*current);
+            }
+        }
+        if vertices.len() < 3 {
+            return vec!();
+        }
+    }
+    vertices
+}
+
+/// Projects a world-space rect to screen space with the given transform, returning its
+/// screen-space bounding box.
+pub fn project_rect_to_screen(rect: &TypedRect<f32, LayerPixel>,
                               transform: &Matrix4D<f32>) -> Option<ScreenRect> {
     let mut result = None;
 
@@ -105,11 +416,12 @@ pub fn project_rect_to_screen(rect: &Rect<f32>,
     let vc = Point4D::new(xc, yc, 0.0, 1.0);
     let vc = transform.transform_point4d(&vc);
 
+    // The corners go around the rect, so that clipping sees its edges.
     let vertices = [
         Point4D::new(x0, y0, 0.0, 1.0),
         Point4D::new(x1, y0, 0.0, 1.0),
-        Point4D::new(x0, y1, 0.0, 1.0),
-        Point4D::new(x1, y1, 0.0, 1.0)
+        Point4D::new(x1, y1, 0.0, 1.0),
+        Point4D::new(x0, y1, 0.0, 1.0)
     ];
 
     // Transform vertices to clip space
@@ -124,9 +436,11 @@ pub fn project_rect_to_screen(rect: &Rect<f32>,
     // There's no need to clip against other planes for correctness,
     // since as long as w > 0, we will get valid homogenous coords.
     // TODO(gw): Potential optimization to clip against other planes.
-    let clipped_vertices = clip_polygon_to_near_plane(&vertices_clip_space);
+    let mut clipped_vertices = [Point4D::new(0.0, 0.0, 0.0, 0.0); MAX_CLIPPED_VERTICES];
+    let clipped_vertex_count = clip_polygon_to_near_plane(&vertices_clip_space,
+                                                          &mut clipped_vertices);
 
-    if let Some(clipped_vertices) = clipped_vertices {
+    if clipped_vertex_count != 0 {
         // Perform perspective division on the clip space vertices
         // to get homogenous space vertices. Then calculate the
         // 2d AABB for this polygon in screen space.
@@ -134,7 +448,7 @@ pub fn project_rect_to_screen(rect: &Rect<f32>,
         let mut min_vertex = Point3D::new(f32::MAX, f32::MAX, f32::MAX);
         let mut max_vertex = Point3D::new(-f32::MAX, -f32::MAX, -f32::MAX);
 
-        for vertex_cs in &clipped_vertices {
+        for vertex_cs in &clipped_vertices[..clipped_vertex_count] {
             // This should be enforced by the clipper above
             debug_assert!(vertex_cs.w > 0.0);
             let inv_w = 1.0 / vertex_cs.w;
@@ -142,6 +456,12 @@ pub fn project_rect_to_screen(rect: &Rect<f32>,
             let y = vertex_cs.y * inv_w;
             let z = vertex_cs.z * inv_w;
 
+            // A transform that overflows or holds NaN would poison every rect derived from
+            // this one; such a layer can't be placed on the screen at all.
+            if !x.is_finite() || !y.is_finite() || !z.is_finite() {
+                return None;
+            }
+
             // Calculate the min/max z-depths of this layer.
             // This is used for simple depth sorting later.
             min_vertex.x = min_vertex.x.min(x);
@@ -152,15 +472,146 @@ pub fn project_rect_to_screen(rect: &Rect<f32>,
             max_vertex.z = max_vertex.z.max(z);
         }
 
-        let origin = Point2D::new(min_vertex.x, min_vertex.y);
-        let size = Size2D::new(max_vertex.x - min_vertex.x,
-                               max_vertex.y - min_vertex.y);
+        let origin = TypedPoint2D::new(min_vertex.x, min_vertex.y);
+        let size = TypedSize2D::new(max_vertex.x - min_vertex.x,
+                                    max_vertex.y - min_vertex.y);
 
         result = Some(ScreenRect {
-            rect: Rect::new(origin, size),
+            rect: TypedRect::new(origin, size),
             z_center: vc.z,
+            z_min: min_vertex.z,
+            z_max: max_vertex.z,
         });
     }
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use euclid::Matrix4D;
+    use euclid::point::Point2D;
+    use euclid::rect::Rect;
+    use euclid::side_offsets::SideOffsets2D;
+    use euclid::size::Size2D;
+    use super::{W_CLIPPING_PLANE, clip_textured_rect_to_near_plane, nine_patch_quads};
+    use super::{rect_crosses_near_plane, rects_share_edge, w_of_point};
+
+    fn rect(x: f32, y: f32, width: f32, height: f32) -> Rect<f32> {
+        Rect::new(Point2D::new(x, y), Size2D::new(width, height))
+    }
+
+    #[test]
+    fn rects_share_an_edge_only_along_a_segment() {
+        let a = rect(0.0, 0.0, 10.0, 10.0);
+        assert!(rects_share_edge(&a, &rect(10.0, 5.0, 10.0, 10.0)));
+        assert!(rects_share_edge(&rect(5.0, -10.0, 1.0, 10.0), &a));
+        assert!(!rects_share_edge(&a, &rect(10.0, 10.0, 10.0, 10.0)));
+        assert!(!rects_share_edge(&a, &rect(10.5, 0.0, 10.0, 10.0)));
+        assert!(!rects_share_edge(&a, &rect(10.0, 20.0, 10.0, 10.0)));
+    }
+
+    #[test]
+    fn nine_patches_keep_their_corners_and_stretch_the_rest() {
+        let quads = nine_patch_quads(&Size2D::new(100.0, 50.0),
+                                     &Size2D::new(20.0, 20.0),
+                                     &SideOffsets2D::new(4.0, 5.0, 6.0, 5.0));
+        assert_eq!(quads,
+                   vec!((rect(0.0, 0.0, 5.0, 4.0), rect(0.0, 0.0, 0.25, 0.2)),
+                        (rect(5.0, 0.0, 90.0, 4.0), rect(0.25, 0.0, 0.5, 0.2)),
+                        (rect(95.0, 0.0, 5.0, 4.0), rect(0.75, 0.0, 0.25, 0.2)),
+                        (rect(0.0, 4.0, 5.0, 40.0), rect(0.0, 0.2, 0.25, 0.5)),
+                        (rect(5.0, 4.0, 90.0, 40.0), rect(0.25, 0.2, 0.5, 0.5)),
+                        (rect(95.0, 4.0, 5.0, 40.0), rect(0.75, 0.2, 0.25, 0.5)),
+                        (rect(0.0, 44.0, 5.0, 6.0), rect(0.0, 0.7, 0.25, 0.3)),
+                        (rect(5.0, 44.0, 90.0, 6.0), rect(0.25, 0.7, 0.5, 0.3)),
+                        (rect(95.0, 44.0, 5.0, 6.0), rect(0.75, 0.7, 0.25, 0.3))));
+    }
+
+    #[test]
+    fn nine_patches_smaller_than_their_insets_shrink_the_corners() {
+        // Only the corners are left, shrunk to half the rect each, still sampling the whole
+        // corners of the source.
+        let quads = nine_patch_quads(&Size2D::new(6.0, 6.0),
+                                     &Size2D::new(20.0, 20.0),
+                                     &SideOffsets2D::new(5.0, 5.0, 5.0, 5.0));
+        assert_eq!(quads,
+                   vec!((rect(0.0, 0.0, 3.0, 3.0), rect(0.0, 0.0, 0.25, 0.25)),
+                        (rect(3.0, 0.0, 3.0, 3.0), rect(0.75, 0.0, 0.25, 0.25)),
+                        (rect(0.0, 3.0, 3.0, 3.0), rect(0.0, 0.75, 0.25, 0.25)),
+                        (rect(3.0, 3.0, 3.0, 3.0), rect(0.75, 0.75, 0.25, 0.25))));
+    }
+
+    #[test]
+    fn degenerate_nine_patches_have_no_quads() {
+        let insets = SideOffsets2D::new(5.0, 5.0, 5.0, 5.0);
+        assert!(nine_patch_quads(&Size2D::new(10.0, 10.0), &Size2D::new(0.0, 20.0), &insets)
+                    .is_empty());
+        assert!(nine_patch_quads(&Size2D::new(0.0, 10.0), &Size2D::new(20.0, 20.0), &insets)
+                    .is_empty());
+        assert!(nine_patch_quads(&Size2D::new(-5.0, 10.0), &Size2D::new(20.0, 20.0), &insets)
+                    .is_empty());
+
+        // Without insets the whole source is stretched over the rect.
+        assert_eq!(nine_patch_quads(&Size2D::new(10.0, 10.0),
+                                    &Size2D::new(20.0, 20.0),
+                                    &SideOffsets2D::zero()),
+                   vec!((rect(0.0, 0.0, 10.0, 10.0), rect(0.0, 0.0, 1.0, 1.0))));
+    }
+
+    /// Returns a transform under which the `w` of a point in the z = 0 plane is `w` at the
+    /// origin and falls by `dx` and `dy` for every unit along x and y.
+    fn perspective(w: f32, dx: f32, dy: f32) -> Matrix4D<f32> {
+        Matrix4D::row_major(1.0, 0.0, 0.0, -dx,
+                            0.0, 1.0, 0.0, -dy,
+                            0.0, 0.0, 1.0, 0.0,
+                            0.0, 0.0, 0.0, w)
+    }
+
+    fn clipped_points(transform: &Matrix4D<f32>) -> Vec<(Point2D<f32>, Point2D<f32>)> {
+        let clipped = clip_textured_rect_to_near_plane(&rect(0.0, 0.0, 100.0, 100.0),
+                                                       &rect(0.0, 0.0, 1.0, 1.0),
+                                                       transform);
+        for &(point, _) in &clipped {
+            assert!(w_of_point(&point, transform) >= W_CLIPPING_PLANE);
+        }
+        clipped
+    }
+
+    #[test]
+    fn quads_crossing_the_near_plane_keep_the_part_in_front_of_it() {
+        // The right half of the rect is behind the near plane.
+        let transform = perspective(1.0, 0.02, 0.0);
+        assert!(rect_crosses_near_plane(&rect(0.0, 0.0, 100.0, 100.0), &transform));
+        let clipped = clipped_points(&transform);
+        assert_eq!(clipped.len(), 4);
+        for &(point, texture_point) in &clipped {
+            assert!(point.x <= 50.0);
+            // Texture coordinates are interpolated along with the positions.
+            assert!((texture_point.x - point.x / 100.0).abs() < 1e-5);
+            assert!((texture_point.y - point.y / 100.0).abs() < 1e-5);
+        }
+
+        // Cutting off one corner leaves a pentagon.
+        assert_eq!(clipped_points(&perspective(1.0, 0.006, 0.006)).len(), 5);
+        // Keeping only one corner leaves a triangle.
+        assert_eq!(clipped_points(&perspective(1.0, 0.015, 0.015)).len(), 3);
+    }
+
+    #[test]
+    fn quads_entirely_on_one_side_of_the_near_plane_are_not_cut() {
+        let behind = perspective(-1.0, 0.0, 0.0);
+        assert!(!rect_crosses_near_plane(&rect(0.0, 0.0, 100.0, 100.0), &behind));
+        assert!(clip_textured_rect_to_near_plane(&rect(0.0, 0.0, 100.0, 100.0),
+                                                 &rect(0.0, 0.0, 1.0, 1.0),
+                                                 &behind).is_empty());
+
+        let in_front = perspective(1.0, 0.001, 0.001);
+        assert!(!rect_crosses_near_plane(&rect(0.0, 0.0, 100.0, 100.0), &in_front));
+        assert_eq!(clipped_points(&in_front),
+                   vec!((Point2D::new(0.0, 0.0), Point2D::new(0.0, 0.0)),
+                        (Point2D::new(100.0, 0.0), Point2D::new(1.0, 0.0)),
+                        (Point2D::new(100.0, 100.0), Point2D::new(1.0, 1.0)),
+                        (Point2D::new(0.0, 100.0), Point2D::new(0.0, 1.0))));
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright 2016 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Explanations of why a layer or tile is not visible, as produced by
+//! `Scene::explain_visibility`.
+//!
+//! The explanation walks the same checks as the render and buffer request paths, using the same
+//! predicates, and reports the first one that keeps the content from showing. It reflects the
+//! state after the last transform update and request pass.
+
+use geometry::{LayerPixel, ScreenPixel};
+use layers::ContentAge;
+
+use euclid::point::Point2D;
+use euclid::rect::{Rect, TypedRect};
+use std::fmt;
+
+/// The reason why a layer or tile is not visible.
+#[derive(Clone, Copy, Debug)]
+pub enum VisibilityBlocker {
+    /// The layer is not part of the layer tree of the scene.
+    NotInScene,
+    /// The transform state of the layer is out of date, for instance because an ancestor was
+    /// culled during a gesture, so the layer isn't placed where it is expected.
+    StaleTransformState,
+    /// An ancestor is hidden along with its descendants. `depth` is the depth of the ancestor in
+    /// the tree, with the root at depth 0.
+    HiddenByAncestor {
+        depth: usize,
+    },
+    /// An ancestor is entirely clipped and doesn't draw its descendants. `depth` is the depth of
+    /// the ancestor in the tree, with the root at depth 0.
+    AncestorEntirelyClipped {
+        depth: usize,
+    },
+    /// An ancestor that masks to its bounds clips away everything drawn within it.
+    ClippedByAncestor {
+        depth: usize,
+        clip_rect: TypedRect<f32, ScreenPixel>,
+    },
+    /// The layer is hidden with `Layer::set_visible`.
+    Hidden,
+    /// The layer cannot be projected to the screen, for instance because it is behind the
+    /// viewer.
+    EntirelyClipped,
+    /// The content rect of the layer is empty, because its bounds are empty or its content inset
+    /// covers them.
+    EmptyContentRect {
+        content_rect: TypedRect<f32, LayerPixel>,
+    },
+    /// The content rect of the layer lies outside of the clip rect it is drawn with. Both rects
+    /// are in world space.
+    ContentClipped {
+        content_rect: Rect<f32>,
+        clip_rect: Rect<f32>,
+    },
+    /// The layer lies outside of the root layer.
+    OutsideViewport {
+        screen_rect: TypedRect<f32, ScreenPixel>,
+        viewport: TypedRect<f32, ScreenPixel>,
+    },
+    /// The layer is fully transparent.
+    Transparent {
+        opacity: f32,
+    },
+    /// None of the tiles of the layer has a buffer that can be drawn yet.
+    NoDrawableTiles,
+    /// The tile lies outside of the root layer, so it is neither requested nor kept.
+    TileOutsideViewport {
+        viewport: TypedRect<f32, ScreenPixel>,
+    },
+    /// The tile was not requested because it is hidden behind opaque layers.
+    TileOccluded,
+    /// The layer belongs to a group that is over its memory budget, so its tiles are not
+    /// requested.
+    GroupOverBudget {
+        group_id: u64,
+    },
+    /// The tile has not been requested.
+    TileNotRequested,
+    /// The tile has been requested, but the painter hasn't sent a buffer yet.
+    AwaitingBuffer {
+        pending_content_age: ContentAge,
+    },
+    /// The tile, or the nine-patch of the layer, has a buffer, but it hasn't been bound to a
+    /// texture yet. This happens the next time the layer is rendered.
+    TextureNotCreated,
+    /// The tile shows a buffer that is older than the content of the layer.
+    StaleContent {
+        buffer_content_age: ContentAge,
+        content_age: ContentAge,
+    },
+}
+
+impl fmt::Display for VisibilityBlocker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VisibilityBlocker::NotInScene => {
+                write!(f, "the layer is not part of the layer tree of the scene")
+            }
+            VisibilityBlocker::StaleTransformState => {
+                write!(f, "the transform state of the layer is out of date; this happens when an \
+                           ancestor is culled during a gesture, and is fixed by ending the gesture")
+            }
+            VisibilityBlocker::HiddenByAncestor { depth } => {
+                write!(f, "the ancestor at depth {} is hidden along with its descendants", depth)
+            }
+            VisibilityBlocker::AncestorEntirelyClipped { depth } => {
+                write!(f, "the ancestor at depth {} cannot be projected to the screen, so none of \
+                           its descendants are drawn", depth)
+            }
+            VisibilityBlocker::ClippedByAncestor { depth, ref clip_rect } => {
+                write!(f, "the ancestor at depth {} masks to its bounds, which leaves the empty \
+                           clip rect {:?}", depth, clip_rect)
+            }
+            VisibilityBlocker::Hidden => {
+                write!(f, "the layer is hidden")
+            }
+            VisibilityBlocker::EntirelyClipped => {
+                write!(f, "the layer cannot be projected to the screen, for instance because it \
+                           is behind the viewer")
+            }
+            VisibilityBlocker::EmptyContentRect { ref content_rect } => {
+                write!(f, "the content rect {:?} of the layer is empty; check its bounds and its \
+                           content inset", content_rect)
+            }
+            VisibilityBlocker::ContentClipped { ref content_rect, ref clip_rect } => {
+                write!(f, "the content rect {:?} of the layer lies outside of the clip rect {:?} \
+                           of the masking ancestors it is drawn with", content_rect, clip_rect)
+            }
+            VisibilityBlocker::OutsideViewport { ref screen_rect, ref viewport } => {
+                write!(f, "the layer is at {:?} on the screen, which is outside of the viewport \
+                           {:?}", screen_rect, viewport)
+            }
+            VisibilityBlocker::Transparent { opacity } => {
+                write!(f, "the layer has an opacity of {}", opacity)
+            }
+            VisibilityBlocker::NoDrawableTiles => {
+                write!(f, "none of the tiles of the layer has been painted and bound to a texture \
+                           yet")
+            }
+            VisibilityBlocker::TileOutsideViewport { ref viewport } => {
+                write!(f, "the tile lies outside of the viewport {:?}, so it is neither requested \
+                           nor kept", viewport)
+            }
+            VisibilityBlocker::TileOccluded => {
+                write!(f, "the tile was not requested because it is hidden behind opaque layers")
+            }
+            VisibilityBlocker::GroupOverBudget { group_id } => {
+                write!(f, "the layer belongs to group {}, which is over its memory budget, so its \
+                           tiles are not requested", group_id)
+            }
+            VisibilityBlocker::TileNotRequested => {
+                write!(f, "the tile has not been requested yet; it will be in the next request \
+                           pass")
+            }
+            VisibilityBlocker::AwaitingBuffer { ref pending_content_age } => {
+                write!(f, "the tile was requested for content age {:?}, but the painter hasn't \
+                           sent a buffer yet", pending_content_age)
+            }
+            VisibilityBlocker::TextureNotCreated => {
+                write!(f, "the buffer has not been bound to a texture yet; this happens the next \
+                           time the layer is rendered")
+            }
+            VisibilityBlocker::StaleContent { ref buffer_content_age, ref content_age } => {
+                write!(f, "the tile shows a buffer painted for content age {:?}, but the layer is \
+                           at content age {:?}", buffer_content_age, content_age)
+            }
+        }
+    }
+}
+
+/// An explanation of why a layer, or one of its tiles, is or isn't visible.
+#[derive(Clone, Copy, Debug)]
+pub struct VisibilityExplanation {
+    /// The tile that was asked about, or None if the question was about the whole layer.
+    pub tile_index: Option<Point2D<usize>>,
+
+    /// The first reason found why the content is not visible, or None if it is.
+    pub blocker: Option<VisibilityBlocker>,
+}
+
+impl VisibilityExplanation {
+    /// Returns true if nothing was found that keeps the content from showing.
+    pub fn is_visible(&self) -> bool {
+        self.blocker.is_none()
+    }
+}
+
+impl fmt::Display for VisibilityExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.tile_index {
+            Some(tile_index) => try!(write!(f, "Tile ({}, {}) of the layer", tile_index.x,
+                                            tile_index.y)),
+            None => try!(write!(f, "The layer")),
+        }
+        match self.blocker {
+            Some(ref blocker) => write!(f, " is not visible: {}.", blocker),
+            None => write!(f, " is visible."),
+        }
+    }
+}